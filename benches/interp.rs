@@ -0,0 +1,187 @@
+//! interp.rs - Criterion benchmarks tracking interpreter throughput
+//
+// Each iteration runs its script with `do_string` on a fresh state, so
+// compilation is included in the timings. Needs `criterion` as a
+// dev-dependency and `harness = false` for this bench target. Run with
+// `cargo bench --bench interp`; add `--features jumptable_dispatch` to
+// compare the dispatch table against the default `match` in luaV_execute.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use skyla::ldo;
+use skyla::lobject::LuaValue;
+use skyla::lstate::{GlobalState, LuaState};
+use skyla::ltable::{Table, TableCursor};
+
+/// Call-heavy: recursive calls, comparisons and small-integer arithmetic
+const FIB: &str = r#"
+local function fib(n)
+  if n < 2 then return n end
+  return fib(n - 1) + fib(n - 2)
+end
+assert(fib(22) == 17711)
+"#;
+
+/// Float-heavy: field access on small tables and arithmetic (from the
+/// Benchmarks Game, five bodies, a few hundred steps)
+const NBODY: &str = r#"
+local sqrt = math.sqrt
+local PI = math.pi
+local SOLAR_MASS = 4 * PI * PI
+local DAYS_PER_YEAR = 365.24
+local bodies = {
+  {x = 0, y = 0, z = 0, vx = 0, vy = 0, vz = 0, mass = SOLAR_MASS},
+  {x = 4.84143144246472090e+00, y = -1.16032004402742839e+00, z = -1.03622044471123109e-01,
+   vx = 1.66007664274403694e-03 * DAYS_PER_YEAR, vy = 7.69901118419740425e-03 * DAYS_PER_YEAR,
+   vz = -6.90460016972063023e-05 * DAYS_PER_YEAR, mass = 9.54791938424326609e-04 * SOLAR_MASS},
+  {x = 8.34336671824457987e+00, y = 4.12479856412430479e+00, z = -4.03523417114321381e-01,
+   vx = -2.76742510726862411e-03 * DAYS_PER_YEAR, vy = 4.99852801234917238e-03 * DAYS_PER_YEAR,
+   vz = 2.30417297573763929e-05 * DAYS_PER_YEAR, mass = 2.85885980666130812e-04 * SOLAR_MASS},
+  {x = 1.28943695621391310e+01, y = -1.51111514016986312e+01, z = -2.23307578892655734e-01,
+   vx = 2.96460137564761618e-03 * DAYS_PER_YEAR, vy = 2.37847173959480950e-03 * DAYS_PER_YEAR,
+   vz = -2.96589568540237556e-05 * DAYS_PER_YEAR, mass = 4.36624404335156298e-05 * SOLAR_MASS},
+  {x = 1.53796971148509165e+01, y = -2.59193146099879641e+01, z = 1.79258772950371181e-01,
+   vx = 2.68067772490389322e-03 * DAYS_PER_YEAR, vy = 1.62824170038242295e-03 * DAYS_PER_YEAR,
+   vz = -9.51592254519715870e-05 * DAYS_PER_YEAR, mass = 5.15138902046611451e-05 * SOLAR_MASS},
+}
+
+local function advance(dt)
+  local n = #bodies
+  for i = 1, n do
+    local bi = bodies[i]
+    for j = i + 1, n do
+      local bj = bodies[j]
+      local dx, dy, dz = bi.x - bj.x, bi.y - bj.y, bi.z - bj.z
+      local d2 = dx * dx + dy * dy + dz * dz
+      local mag = dt / (d2 * sqrt(d2))
+      local bm, bjm = bi.mass * mag, bj.mass * mag
+      bi.vx, bi.vy, bi.vz = bi.vx - dx * bjm, bi.vy - dy * bjm, bi.vz - dz * bjm
+      bj.vx, bj.vy, bj.vz = bj.vx + dx * bm, bj.vy + dy * bm, bj.vz + dz * bm
+    end
+  end
+  for i = 1, n do
+    local b = bodies[i]
+    b.x, b.y, b.z = b.x + dt * b.vx, b.y + dt * b.vy, b.z + dt * b.vz
+  end
+end
+
+for _ = 1, 500 do advance(0.01) end
+"#;
+
+/// Table-heavy: array appends, hash inserts, lookups and removals
+const TABLE_STRESS: &str = r#"
+local t, h = {}, {}
+for i = 1, 20000 do
+  t[#t + 1] = i
+  h["k" .. i] = i
+end
+local sum = 0
+for i = 1, 20000 do
+  sum = sum + t[i] + h["k" .. i]
+end
+for i = 1, 20000, 2 do
+  h["k" .. i] = nil
+end
+assert(sum == 2 * (20000 * 20001 // 2))
+"#;
+
+/// Deep non-tail recursion: frame setup and teardown dominate
+const DEEP_RECURSION: &str = r#"
+local function depth(n)
+  if n == 0 then return 0 end
+  return 1 + depth(n - 1)
+end
+for _ = 1, 20 do assert(depth(5000) == 5000) end
+"#;
+
+/// Generic-for over builtin iterators: the TFORCALL fast paths
+const PAIRS: &str = r#"
+local t, a = {}, {}
+for i = 1, 10000 do
+  t["k" .. i] = i
+  a[i] = i
+end
+local sum = 0
+for _ = 1, 10 do
+  for _, v in pairs(t) do sum = sum + v end
+  for _, v in ipairs(a) do sum = sum + v end
+end
+assert(sum == 20 * (10000 * 10001 // 2))
+"#;
+
+fn run(script: &str) {
+    let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+    state.do_string(black_box(script)).expect("benchmark script failed");
+}
+
+fn bench_interp(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interp");
+    group.bench_function("fib", |b| b.iter(|| run(FIB)));
+    group.bench_function("nbody", |b| b.iter(|| run(NBODY)));
+    group.bench_function("table_stress", |b| b.iter(|| run(TABLE_STRESS)));
+    group.bench_function("deep_recursion", |b| b.iter(|| run(DEEP_RECURSION)));
+    group.bench_function("pairs", |b| b.iter(|| run(PAIRS)));
+    group.finish();
+}
+
+/// One traversal of a 2000-entry hash part: `next` from the previous key
+/// against the cursor TFORCALL keeps
+fn bench_traverse(c: &mut Criterion) {
+    let mut t = Table::new();
+    for i in 0..2000 {
+        t.set(&LuaValue::Str(format!("k{}", i)), LuaValue::Int(i));
+    }
+    let mut group = c.benchmark_group("traverse");
+    group.bench_function("next", |b| b.iter(|| {
+        let (mut n, mut last) = (0, None);
+        while let Some((k, _)) = t.next(last.as_ref()) {
+            last = Some(k);
+            n += 1;
+        }
+        black_box(n)
+    }));
+    group.bench_function("cursor", |b| b.iter(|| {
+        let (mut n, mut cursor) = (0, TableCursor::new());
+        while t.next_entry(&mut cursor).is_some() {
+            n += 1;
+        }
+        black_box(n)
+    }));
+    group.finish();
+}
+
+/// Rehash of a 100000-entry array part with a few integer keys past its
+/// end: only those move, the array part is not rebuilt
+fn bench_rehash(c: &mut Criterion) {
+    let mut t = Table::new();
+    for i in 1..=100_000 {
+        t.set(&LuaValue::Int(i), LuaValue::Int(i));
+    }
+    c.bench_function("table/rehash_100000", |b| b.iter(|| {
+        for i in 100_002..100_010 {
+            t.set(&LuaValue::Int(i), LuaValue::Int(i));
+        }
+        t.rehash();
+        black_box(t.len())
+    }));
+}
+
+/// Frame push/pop alone, 10000 levels deep: after the first descent every
+/// CallInfo slot is reused
+fn bench_callinfo(c: &mut Criterion) {
+    let mut state = ldo::lua_State::new(16);
+    c.bench_function("callinfo/push_pop_10000", |b| b.iter(|| {
+        for depth in 0..10_000 {
+            ldo::luaD_precall(&mut state, depth, 1);
+        }
+        for _ in 0..10_000 {
+            ldo::luaD_poscall(&mut state, 1);
+        }
+        black_box(state.callinfo.capacity())
+    }));
+}
+
+criterion_group!(benches, bench_interp, bench_callinfo, bench_traverse, bench_rehash);
+criterion_main!(benches);
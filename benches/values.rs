@@ -0,0 +1,65 @@
+//! values.rs - Criterion benchmarks comparing LObject and compact LValue storage
+//
+// The workload is table-shaped: many entries whose keys are drawn from a
+// small set of field names, as in arrays of records. Each representation is
+// timed on copying the entries and on keyed lookups, and the bytes each one
+// holds are printed once before the timings. Needs `criterion` as a
+// dev-dependency and `harness = false` for this bench target.
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use skyla::lobject::{LObject, LValue, ValueHeap};
+
+const ENTRIES: usize = 100_000;
+const FIELDS: usize = 64;
+
+fn entries() -> Vec<(LObject, LObject)> {
+    (0..ENTRIES)
+        .map(|i| (LObject::String(format!("field_{}", i % FIELDS)), LObject::Integer(i as _)))
+        .collect()
+}
+
+fn lobject_bytes(v: &[(LObject, LObject)]) -> usize {
+    std::mem::size_of_val(v)
+        + v.iter().map(|(k, _)| match k {
+            LObject::String(s) => s.capacity(),
+            _ => 0,
+        }).sum::<usize>()
+}
+
+fn bench_values(c: &mut Criterion) {
+    let objects = entries();
+    let mut heap = ValueHeap::new();
+    let compact: Vec<(LValue, LValue)> = objects.iter().map(|(k, v)| (heap.pack(k), heap.pack(v))).collect();
+    eprintln!(
+        "{} entries: LObject {} bytes, LValue {} bytes (+{} bytes of heap)",
+        ENTRIES,
+        lobject_bytes(&objects),
+        std::mem::size_of_val(compact.as_slice()),
+        heap.heap_size()
+    );
+
+    let mut group = c.benchmark_group("values");
+    group.bench_function("copy/lobject", |b| b.iter(|| black_box(objects.clone())));
+    group.bench_function("copy/lvalue", |b| b.iter(|| black_box(compact.clone())));
+
+    let by_name: HashMap<String, usize> = (0..FIELDS).map(|i| (format!("field_{}", i), i)).collect();
+    group.bench_function("lookup/lobject", |b| b.iter(|| {
+        objects.iter().filter_map(|(k, _)| match k {
+            LObject::String(s) => by_name.get(s),
+            _ => None,
+        }).sum::<usize>()
+    }));
+    let ids: HashMap<_, usize> = (0..FIELDS).map(|i| (heap.intern(&format!("field_{}", i)), i)).collect();
+    group.bench_function("lookup/lvalue", |b| b.iter(|| {
+        compact.iter().filter_map(|(k, _)| match k {
+            LValue::String(id) => ids.get(id),
+            _ => None,
+        }).sum::<usize>()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_values);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+//! Fuzz target: Lua source -> compile -> execute under an instruction budget
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use skyla::ltests::{fuzz_compile_execute, FUZZ_INSTRUCTION_BUDGET};
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_compile_execute(data, FUZZ_INSTRUCTION_BUDGET);
+});
@@ -0,0 +1,9 @@
+//! Fuzz target: Lua pattern matcher (input is `subject \0 pattern [\0 repl]`)
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use skyla::ltests::fuzz_pattern;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_pattern(data);
+});
@@ -0,0 +1,9 @@
+//! Fuzz target: VM operation sequences in the record_fuzz_session format
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use skyla::ltests::fuzz_session_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_session_bytes(data);
+});
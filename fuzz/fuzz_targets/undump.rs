@@ -0,0 +1,9 @@
+//! Fuzz target: load (undump + verify) arbitrary binary chunks
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use skyla::ltests::fuzz_undump;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz_undump(data);
+});
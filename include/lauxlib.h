@@ -0,0 +1,73 @@
+/* Generated by lcapi.rs (generate_lua_h / generate_lauxlib_h); do not edit. */
+#ifndef lauxlib_h
+#define lauxlib_h
+
+#include "lua.h"
+
+typedef struct luaL_Reg {
+  const char *name;
+  lua_CFunction func;
+} luaL_Reg;
+
+typedef struct luaL_Buffer luaL_Buffer;
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+int luaL_argerror(lua_State *L, int arg, const char *extramsg);
+int luaL_typeerror(lua_State *L, int arg, const char *tname);
+int luaL_getmetafield(lua_State *L, int obj, const char *e);
+int luaL_newmetatable(lua_State *L, const char *tname);
+void luaL_setmetatable(lua_State *L, const char *tname);
+void *luaL_testudata(lua_State *L, int ud, const char *tname);
+void *luaL_checkudata(lua_State *L, int ud, const char *tname);
+const char *luaL_checklstring(lua_State *L, int arg, size_t *l);
+const char *luaL_optlstring(lua_State *L, int arg, const char *def, size_t *l);
+lua_Number luaL_checknumber(lua_State *L, int arg);
+lua_Number luaL_optnumber(lua_State *L, int arg, lua_Number def);
+lua_Integer luaL_checkinteger(lua_State *L, int arg);
+lua_Integer luaL_optinteger(lua_State *L, int arg, lua_Integer def);
+void luaL_checkstack(lua_State *L, int sz, const char *msg);
+void luaL_checktype(lua_State *L, int arg, int t);
+void luaL_checkany(lua_State *L, int arg);
+int luaL_checkoption(lua_State *L, int arg, const char *def, const char *const lst[]);
+void luaL_where(lua_State *L, int lvl);
+void luaL_traceback(lua_State *L, lua_State *L1, const char *msg, int level);
+void luaL_addgsub(luaL_Buffer *b, const char *s, const char *p, const char *r);
+const char *luaL_gsub(lua_State *L, const char *s, const char *p, const char *r);
+int luaL_fileresult(lua_State *L, int stat, const char *fname);
+int luaL_execresult(lua_State *L, int stat);
+void luaL_setfuncs(lua_State *L, const luaL_Reg *l, int nup);
+int luaL_getsubtable(lua_State *L, int idx, const char *fname);
+void luaL_requiref(lua_State *L, const char *modname, lua_CFunction openf, int glb);
+void luaL_checkversion_(lua_State *L, lua_Number ver, size_t sz);
+int luaL_loadstring(lua_State *L, const char *s);
+int luaL_loadfilex(lua_State *L, const char *filename, const char *mode);
+
+#ifdef __cplusplus
+}
+#endif
+
+#define LUAL_NUMSIZES		(sizeof(lua_Integer)*16 + sizeof(lua_Number))
+#define luaL_checkversion(L)  \
+	luaL_checkversion_(L, LUA_VERSION_NUM, LUAL_NUMSIZES)
+#define luaL_newlibtable(L,l)	lua_createtable(L, 0, sizeof(l)/sizeof((l)[0]) - 1)
+#define luaL_newlib(L,l)  \
+  (luaL_checkversion(L), luaL_newlibtable(L,l), luaL_setfuncs(L,l,0))
+#define luaL_argcheck(L, cond,arg,extramsg) \
+	((void)((cond) || luaL_argerror(L, (arg), (extramsg))))
+#define luaL_argexpected(L,cond,arg,tname) \
+	((void)((cond) || luaL_typeerror(L, (arg), (tname))))
+#define luaL_checkstring(L,n)	(luaL_checklstring(L, (n), NULL))
+#define luaL_optstring(L,n,d)	(luaL_optlstring(L, (n), (d), NULL))
+#define luaL_typename(L,i)	lua_typename(L, lua_type(L,(i)))
+#define luaL_getmetatable(L,n)	(lua_getfield(L, LUA_REGISTRYINDEX, (n)))
+#define luaL_opt(L,f,n,d)	(lua_isnoneornil(L,(n)) ? (d) : f(L,(n)))
+#define luaL_loadfile(L,f)	luaL_loadfilex(L,f,NULL)
+#define luaL_dofile(L, fn) \
+	(luaL_loadfile(L, fn) || lua_pcall(L, 0, LUA_MULTRET, 0))
+#define luaL_dostring(L, s) \
+	(luaL_loadstring(L, s) || lua_pcall(L, 0, LUA_MULTRET, 0))
+
+#endif
@@ -0,0 +1,132 @@
+/* Generated by lcapi.rs (generate_lua_h / generate_lauxlib_h); do not edit. */
+#ifndef lua_h
+#define lua_h
+
+#include <stdarg.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#define LUA_VERSION_MAJOR	"5"
+#define LUA_VERSION_MINOR	"4"
+#define LUA_VERSION_NUM		504
+
+#define LUA_MULTRET	(-1)
+#define LUA_REGISTRYINDEX	(-1001000)
+
+/* thread status */
+#define LUA_OK		0
+#define LUA_YIELD	1
+#define LUA_ERRRUN	2
+#define LUA_ERRSYNTAX	3
+#define LUA_ERRMEM	4
+#define LUA_ERRERR	5
+
+/* basic types */
+#define LUA_TNONE		(-1)
+#define LUA_TNIL		0
+#define LUA_TBOOLEAN		1
+#define LUA_TLIGHTUSERDATA	2
+#define LUA_TNUMBER		3
+#define LUA_TSTRING		4
+#define LUA_TTABLE		5
+#define LUA_TFUNCTION		6
+#define LUA_TUSERDATA		7
+#define LUA_TTHREAD		8
+
+/* comparison functions */
+#define LUA_OPEQ	0
+#define LUA_OPLT	1
+#define LUA_OPLE	2
+
+typedef struct lua_State lua_State;
+
+typedef double lua_Number;
+typedef int64_t lua_Integer;
+typedef uint64_t lua_Unsigned;
+typedef intptr_t lua_KContext;
+
+#define LUA_INTEGER_FRMLEN	"ll"
+#define LUA_INTEGER_FMT		"%lld"
+#define LUAI_NUMFFORMAT		"%.14g"
+
+typedef int (*lua_CFunction) (lua_State *L);
+typedef int (*lua_KFunction) (lua_State *L, int status, lua_KContext ctx);
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+lua_State *lua_newthread(lua_State *L);
+int lua_checkstack(lua_State *L, int n);
+void lua_xmove(lua_State *from, lua_State *to, int n);
+int lua_gettop(lua_State *L);
+void lua_settop(lua_State *L, int idx);
+void lua_pushvalue(lua_State *L, int idx);
+void lua_rotate(lua_State *L, int idx, int n);
+void lua_copy(lua_State *L, int fromidx, int toidx);
+void lua_pushnil(lua_State *L);
+void lua_pushnumber(lua_State *L, lua_Number n);
+void lua_pushinteger(lua_State *L, lua_Integer n);
+const char *lua_pushlstring(lua_State *L, const char *s, size_t len);
+const char *lua_pushstring(lua_State *L, const char *s);
+void lua_pushcclosure(lua_State *L, lua_CFunction fn, int n);
+void lua_pushboolean(lua_State *L, int b);
+void lua_pushlightuserdata(lua_State *L, void *p);
+int lua_pushthread(lua_State *L);
+int lua_type(lua_State *L, int idx);
+const char *lua_typename(lua_State *L, int tp);
+lua_Number lua_tonumberx(lua_State *L, int idx, int *isnum);
+lua_Integer lua_tointegerx(lua_State *L, int idx, int *isnum);
+int lua_isinteger(lua_State *L, int idx);
+int lua_toboolean(lua_State *L, int idx);
+const char *lua_tolstring(lua_State *L, int idx, size_t *len);
+lua_CFunction lua_tocfunction(lua_State *L, int idx);
+lua_State *lua_tothread(lua_State *L, int idx);
+const void *lua_topointer(lua_State *L, int idx);
+int lua_rawequal(lua_State *L, int idx1, int idx2);
+int lua_compare(lua_State *L, int idx1, int idx2, int op);
+size_t lua_stringtonumber(lua_State *L, const char *s);
+lua_Number lua_version(lua_State *L);
+void lua_createtable(lua_State *L, int narr, int nrec);
+void *lua_newuserdatauv(lua_State *L, size_t sz, int nuvalue);
+int lua_getglobal(lua_State *L, const char *name);
+void lua_setglobal(lua_State *L, const char *name);
+int lua_getfield(lua_State *L, int idx, const char *k);
+void lua_setfield(lua_State *L, int idx, const char *k);
+int lua_pcallk(lua_State *L, int nargs, int nresults, int errfunc, lua_KContext ctx, lua_KFunction k);
+void lua_callk(lua_State *L, int nargs, int nresults, lua_KContext ctx, lua_KFunction k);
+int lua_yieldk(lua_State *L, int nresults, lua_KContext ctx, lua_KFunction k);
+int lua_resume(lua_State *L, lua_State *from, int narg, int *nres);
+int lua_status(lua_State *L);
+int lua_isyieldable(lua_State *L);
+int lua_error(lua_State *L);
+int lua_closethread(lua_State *L, lua_State *from);
+int lua_resetthread(lua_State *L);
+
+#ifdef __cplusplus
+}
+#endif
+
+#define lua_upvalueindex(i)	(LUA_REGISTRYINDEX - (i))
+#define lua_call(L,n,r)		lua_callk(L, (n), (r), 0, NULL)
+#define lua_pcall(L,n,r,f)	lua_pcallk(L, (n), (r), (f), 0, NULL)
+#define lua_yield(L,n)		lua_yieldk(L, (n), 0, NULL)
+#define lua_tonumber(L,i)	lua_tonumberx(L,(i),NULL)
+#define lua_tointeger(L,i)	lua_tointegerx(L,(i),NULL)
+#define lua_pop(L,n)		lua_settop(L, -(n)-1)
+#define lua_newtable(L)		lua_createtable(L, 0, 0)
+#define lua_newuserdata(L,s)	lua_newuserdatauv(L,s,1)
+#define lua_pushcfunction(L,f)	lua_pushcclosure(L, (f), 0)
+#define lua_isfunction(L,n)	(lua_type(L, (n)) == LUA_TFUNCTION)
+#define lua_istable(L,n)	(lua_type(L, (n)) == LUA_TTABLE)
+#define lua_isnil(L,n)		(lua_type(L, (n)) == LUA_TNIL)
+#define lua_isboolean(L,n)	(lua_type(L, (n)) == LUA_TBOOLEAN)
+#define lua_isnone(L,n)		(lua_type(L, (n)) == LUA_TNONE)
+#define lua_isnoneornil(L, n)	(lua_type(L, (n)) <= 0)
+#define lua_pushliteral(L, s)	lua_pushstring(L, "" s)
+#define lua_tostring(L,i)	lua_tolstring(L, (i), NULL)
+#define lua_insert(L,idx)	lua_rotate(L, (idx), 1)
+#define lua_remove(L,idx)	(lua_rotate(L, (idx), -1), lua_pop(L, 1))
+#define lua_replace(L,idx)	(lua_copy(L, -1, (idx)), lua_pop(L, 1))
+
+#endif
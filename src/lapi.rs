@@ -6,6 +6,8 @@
 pub mod lstate;
 pub mod lobject;
 pub mod ldo;
+pub mod lcontext;
+pub mod lerror;
 pub mod lstring;
 pub mod ltable;
 pub mod lmem;
@@ -15,10 +17,60 @@ pub mod ldebug;
 pub mod lapi;
 pub mod func;
 pub mod lcorolib;
-
+pub mod ltrace;
+pub mod lprofiler;
+pub mod lcoverage;
+pub mod ldebugger;
+#[cfg(feature = "dap")]
+pub mod ldap;
+pub mod lcapi;
+pub mod lplatform;
+pub mod lasync;
+pub mod lchannel;
+pub mod lclone;
+pub mod lmodule;
+pub mod lref;
+pub mod luserdata;
+pub mod ljson;
+pub mod lcolor;
+pub mod linspect;
+pub mod lrepl;
+pub mod lsourcemap;
+pub mod lsyntax;
+pub mod lpack;
+pub mod lvfs;
+pub mod loutput;
+pub mod lsandbox;
+pub mod lopt;
+pub mod lplugin;
+pub mod lbaselib;
+pub mod lmathlib;
+pub mod lbitlib;
+pub mod lutf8lib;
+pub mod lcompat;
+pub mod ldeterm;
+pub mod skylalib;
+#[cfg(not(feature = "minimal"))]
+pub mod liolib;
+#[cfg(feature = "regex")]
+pub mod lregex;
+#[cfg(feature = "skyla_ext")]
+pub mod lencoding;
+#[cfg(feature = "hash")]
+pub mod lhash;
+
+pub use lasync::AsyncLua;
+pub use lerror::Error;
+pub use lsyntax::{check_syntax, Diagnostic};
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
 use std::os::raw::{c_char, c_int, c_void};
 use std::ptr;
+use std::rc::Rc;
+
+use crate::lstate::ObjectId;
 
 // Type aliases and constants
 
@@ -28,33 +80,87 @@ pub struct lua_State {
     // Fill as per your internal implementation
 }
 
-pub struct TValue {
-    // Lua value representation
-}
-
-pub struct GlobalState {
-    pub nilvalue: TValue,
-    pub l_registry: TValue,
-    // other global Lua state fields
-}
+/// A stack value, as index2value returns it
+pub type TValue = crate::lobject::LuaValue;
 
 pub const LUA_REGISTRYINDEX: c_int = -1001000;
-pub const LUA_VERSION_NUM: f64 = 5.4;
 
-// Lua C function type
-pub type lua_CFunction = unsafe extern "C" fn(L: *mut lua_State) -> c_int;
+// Basic types (lua_type results)
+pub const LUA_TNONE: c_int = -1;
+pub const LUA_TNIL: c_int = 0;
+pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TLIGHTUSERDATA: c_int = 2;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
+pub const LUA_TUSERDATA: c_int = 7;
+pub const LUA_TTHREAD: c_int = 8;
+pub const LUA_VERSION_NUM: lua_Number = 504.0;
+
+// Comparison operators (lua_compare)
+pub const LUA_OPEQ: c_int = 0;
+pub const LUA_OPLT: c_int = 1;
+pub const LUA_OPLE: c_int = 2;
 
-// Placeholder global state getter
-fn G(_L: &lua_State) -> &'static GlobalState {
-    unimplemented!("Global state accessor")
+// Basic C API types; the numbers follow the build profile (skylaconf)
+pub type lua_Number = crate::skylaconf::LuaFloat;
+pub type lua_Integer = crate::skylaconf::LuaInteger;
+pub type lua_Unsigned = crate::skylaconf::LuaUnsigned;
+pub type lua_KContext = isize; // intptr_t
+
+// Lua C function type; lua_error unwinds out of it, hence "C-unwind"
+pub type lua_CFunction = unsafe extern "C-unwind" fn(L: *mut lua_State) -> c_int;
+
+// Continuation function type (lua_callk / lua_pcallk / lua_yieldk)
+pub type lua_KFunction = Option<unsafe extern "C-unwind" fn(L: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int>;
+
+/// Most upvalues a C closure can have
+const MAXUPVAL: c_int = 255;
+
+/// Global state of `L`
+unsafe fn G(L: *const lua_State) -> *mut crate::lstate::GlobalState {
+    (*(L as *const crate::lstate::LuaState)).l_G.as_ptr()
 }
 
 // Helper Macros converted to Rust inline macros/functions
 
+/// What a failed api_check does; per state, see
+/// LuaState::set_api_check_policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApiCheckPolicy {
+    /// Panic the host process
+    #[default]
+    Panic,
+    /// Raise a Lua error with the message, which the nearest protected
+    /// call catches
+    LuaError,
+    /// Write the message to stderr and go on with the bad argument, which
+    /// may still fail further on. Only release builds do this; debug builds
+    /// panic instead, so misuse is found in testing
+    LogAndContinue,
+}
+
+/// Handle a failed api_check in `L` as its policy says. Panic and
+/// LuaError unwind out of the lua_* function that made the check, which
+/// is why those are declared `extern "C-unwind"`.
+pub unsafe fn api_check_failed(L: *mut lua_State, msg: &str) {
+    let msg = format!("API check failed: {}", msg);
+    let L1 = L as *mut crate::lstate::LuaState;
+    let policy = if L1.is_null() { ApiCheckPolicy::Panic } else { (*L1).l_G.borrow().api_check_policy };
+    match policy {
+        ApiCheckPolicy::LuaError => (*L1).throw(crate::lobject::LuaValue::Str(msg)),
+        ApiCheckPolicy::LogAndContinue if !cfg!(debug_assertions) => {
+            crate::lplatform::lua_writestringerror(&format!("{}\n", msg));
+        }
+        _ => panic!("{}", msg),
+    }
+}
+
 macro_rules! api_check {
     ($L:expr, $cond:expr, $msg:expr) => {
         if !$cond {
-            panic!("API check failed: {}", $msg);
+            api_check_failed($L as *mut lua_State, $msg);
         }
     };
 }
@@ -79,9 +185,9 @@ macro_rules! api_checknelems {
 
 // Helper Functions
 
-/// Test if a TValue pointer is valid (not nil)
-pub fn isvalid(L: &lua_State, o: *const TValue) -> bool {
-    o != &G(L).nilvalue as *const _
+/// Test if a TValue pointer is valid (not the shared nil of a bad index)
+pub unsafe fn isvalid(L: &lua_State, o: *const TValue) -> bool {
+    o != ptr::addr_of!((*G(L)).nilvalue)
 }
 
 /// Test if an index is a pseudo-index
@@ -100,40 +206,211 @@ pub fn isupvalue(i: c_int) -> bool {
 ///
 /// Unsafe because of raw pointer dereferences, must ensure `L` is valid
 pub unsafe fn index2value(L: *mut lua_State, idx: c_int) -> *mut TValue {
-    // Rough translation outline from C:
-    // 1. Get current CallInfo
-    // 2. Handle positive index
-    // 3. Handle negative non-pseudo indices
-    // 4. Handle registry index
-    // 5. Handle upvalues and other pseudo-indices
-    
-    unimplemented!("index2value logic to convert stack index to TValue pointer")
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let func = L1.ci.borrow().func;
+    if idx > 0 {
+        match L1.stack.get_mut(func + idx as usize) {
+            Some(o) => o,
+            None => ptr::addr_of_mut!((*G(L)).nilvalue), // not in the stack
+        }
+    } else if !ispseudo(idx) {
+        // negative index
+        let top = L1.stack.len() - (func + 1);
+        api_check!(L, idx != 0 && -idx as usize <= top, "invalid index");
+        if idx == 0 || -idx as usize > top {
+            return ptr::addr_of_mut!((*G(L)).nilvalue);
+        }
+        let slot = (L1.stack.len() as c_int + idx) as usize;
+        &mut L1.stack[slot]
+    } else if idx == LUA_REGISTRYINDEX {
+        ptr::addr_of_mut!((*G(L)).registry)
+    } else {
+        // upvalues of the running C closure; Rust functions keep theirs in
+        // their own captures, so they have none to reach
+        let n = LUA_REGISTRYINDEX - idx;
+        api_check!(L, n <= MAXUPVAL + 1, "upvalue index too large");
+        let uv = C_CLOSURES.with(|c| {
+            let id = ObjectId::of(&L1.stack[func])?;
+            let upvalue = &mut *c.borrow().get(&id)?.upvalue;
+            upvalue.get_mut((n - 1) as usize).map(|uv| uv as *mut TValue)
+        });
+        uv.unwrap_or(ptr::addr_of_mut!((*G(L)).nilvalue))
+    }
+}
+
+/// C function and upvalues of a C closure (lua_pushcclosure)
+struct CClosure {
+    f: lua_CFunction,
+    upvalue: *mut [TValue],
+}
+
+thread_local! {
+    // C closure behind each function value made by lua_pushcclosure, by the
+    // identity of that value
+    static C_CLOSURES: RefCell<HashMap<ObjectId, CClosure>> = RefCell::new(HashMap::new());
+}
+
+/// Frees a C closure's entry in C_CLOSURES when its function value is
+/// dropped (set once the value exists and its identity is known)
+struct CClosureOwner(Cell<Option<ObjectId>>);
+
+impl Drop for CClosureOwner {
+    fn drop(&mut self) {
+        let Some(id) = self.0.get() else { return };
+        // other thread-locals may be gone already at thread exit
+        if let Ok(Some(cl)) = C_CLOSURES.try_with(|c| c.borrow_mut().remove(&id)) {
+            drop(unsafe { Box::from_raw(cl.upvalue) });
+        }
+    }
+}
+
+/// C closure of a function value made by lua_pushcclosure
+fn cclosure_of<R>(v: &TValue, f: impl FnOnce(&CClosure) -> R) -> Option<R> {
+    let id = ObjectId::of(v)?;
+    C_CLOSURES.with(|c| c.borrow().get(&id).map(f))
+}
+
+/// GC object of a collectable value (its identity)
+unsafe fn gcvalue(o: *const TValue) -> *const c_void {
+    ObjectId::of(&*o).map_or(ptr::null(), ObjectId::as_ptr)
+}
+
+/// Pointer stored in a light userdata value
+unsafe fn pvalue(o: *const TValue) -> *const c_void {
+    match &*o {
+        crate::lobject::LuaValue::Pointer(p) => *p as *const c_void,
+        _ => ptr::null(),
+    }
+}
+
+/// Memory block of a full userdata value
+unsafe fn getudatamem(o: *const TValue) -> *const c_void {
+    match &*o {
+        crate::lobject::LuaValue::UserData(u) => u.as_ptr() as *const c_void,
+        _ => ptr::null(),
+    }
+}
+
+/// Full userdata (lua_newuserdatauv): a zeroed memory block aligned for any
+/// C type, and its user values. The block never moves, so the pointer C
+/// code holds stays valid while the value is alive
+pub struct Udata {
+    mem: ptr::NonNull<u8>,
+    layout: std::alloc::Layout,
+    pub user_values: RefCell<Vec<TValue>>,
+}
+
+impl Udata {
+    /// Alignment of the block (LUAI_MAXALIGN)
+    const ALIGN: usize = 16;
+
+    pub fn new(size: usize, nuvalue: usize) -> Udata {
+        let layout = std::alloc::Layout::from_size_align(size.max(1), Self::ALIGN).expect("userdata too large");
+        // SAFETY: the layout has a nonzero size
+        let mem = ptr::NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) })
+            .unwrap_or_else(|| std::alloc::handle_alloc_error(layout));
+        let user_values = (0..nuvalue).map(|_| crate::lobject::LuaValue::Nil).collect();
+        Udata { mem, layout, user_values: RefCell::new(user_values) }
+    }
+
+    /// Start of the memory block
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.mem.as_ptr()
+    }
+}
+
+impl Drop for Udata {
+    fn drop(&mut self) {
+        // SAFETY: allocated in new with this layout
+        unsafe { std::alloc::dealloc(self.mem.as_ptr(), self.layout) }
+    }
+}
+
+thread_local! {
+    // Zero-terminated copies of the strings lua_tolstring has returned, by
+    // contents; they live as long as the thread, as VM_STRINGS do
+    static API_STRINGS: RefCell<HashMap<String, Box<[u8]>>> = RefCell::new(HashMap::new());
+}
+
+/// Zero-terminated C string with the contents of `s` (embedded zeros kept)
+fn api_string(s: &str) -> *const c_char {
+    API_STRINGS.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if let Some(c) = pool.get(s) {
+            return c.as_ptr() as *const c_char;
+        }
+        let c: Box<[u8]> = s.bytes().chain([0]).collect();
+        let p = c.as_ptr() as *const c_char;
+        pool.insert(s.to_string(), c);
+        p
+    })
+}
+
+/// Lua closure of a function value, or NULL if it is not one
+unsafe fn clLvalue(o: *const TValue) -> *mut crate::lvm::Closure {
+    crate::lvm::closure_of(&*o).map_or(ptr::null_mut(), |cl| cl.as_ptr())
+}
+
+/// Upvalues of a C closure value (empty for a light C function), or None
+/// if it is not a C function. Rust functions keep their state in their own
+/// captures, so they count as C functions without upvalues
+unsafe fn clCupvalues<'a>(o: *const TValue) -> Option<&'a mut [TValue]> {
+    if let Some(upvalue) = cclosure_of(&*o, |cl| cl.upvalue) {
+        return Some(&mut *upvalue);
+    }
+    match &*o {
+        crate::lobject::LuaValue::Function(_) if crate::lvm::closure_of(&*o).is_none() => Some(&mut []),
+        _ => None,
+    }
+}
+
+/// Register form of an API value, which upvalues store
+unsafe fn s2v(o: *const TValue) -> crate::lvm::TValue {
+    crate::lvm::TValue::from_lua(&*o)
+}
+
+/// Push a copy of a register value onto the stack
+unsafe fn setobj2s(L: *mut lua_State, v: *const crate::lvm::TValue) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    L1.push((*v).to_lua().unwrap_or(crate::lobject::LuaValue::Nil));
 }
 
 // --- Public API functions ---
 
 /// Check stack size, ensure `n` extra slots can be allocated
 #[no_mangle]
-pub unsafe extern "C" fn lua_checkstack(L: *mut lua_State, n: c_int) -> c_int {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_checkstack(L: *mut lua_State, n: c_int) -> c_int {
+    api_check!(L, n >= 0, "negative 'n'");
+    (*(L as *mut crate::lstate::LuaState)).check_stack(n.max(0) as usize) as c_int
 }
 
 /// Get the index of the top element in the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_gettop(L: *mut lua_State) -> c_int {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_gettop(L: *mut lua_State) -> c_int {
+    (*(L as *mut crate::lstate::LuaState)).get_top() as c_int
 }
 
 /// Set the stack top to the given index
 #[no_mangle]
-pub unsafe extern "C" fn lua_settop(L: *mut lua_State, idx: c_int) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_settop(L: *mut lua_State, idx: c_int) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let func = L1.ci.borrow().func;
+    let newtop = if idx >= 0 {
+        func + 1 + idx as usize
+    } else {
+        api_check!(L, -(idx + 1) <= L1.get_top(), "invalid new top");
+        L1.stack.len().saturating_sub(-(idx + 1) as usize).max(func + 1)
+    };
+    // close the to-be-closed slots being removed before dropping them
+    L1.close_tbc(newtop, None);
+    L1.stack.resize_with(newtop, || crate::lobject::LuaValue::Nil);
 }
 
 /// Push a copy of the element at the given index onto the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushvalue(L: *mut lua_State, idx: c_int) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushvalue(L: *mut lua_State, idx: c_int) {
+    let v = (*index2value(L, idx)).clone();
+    (*(L as *mut crate::lstate::LuaState)).push(v);
 }
 
 /// Pop `n` elements from the stack
@@ -142,306 +419,916 @@ pub unsafe fn lua_pop(L: *mut lua_State, n: c_int) {
     lua_settop(L, -n - 1)
 }
 
-/// Insert element at top into given index, shifting others up
+/// Rotate the elements between `idx` and the top `n` positions towards the
+/// top (away from it for a negative `n`)
 #[no_mangle]
-pub unsafe extern "C" fn lua_insert(L: *mut lua_State, idx: c_int) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_rotate(L: *mut lua_State, idx: c_int, n: c_int) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let slot = index2slot(L1, idx);
+    let segment = &mut L1.stack[slot..];
+    api_check!(L, n.unsigned_abs() as usize <= segment.len(), "invalid 'n'");
+    let m = n.unsigned_abs() as usize % segment.len();
+    if n >= 0 {
+        segment.rotate_right(m);
+    } else {
+        segment.rotate_left(m);
+    }
+}
+
+/// Insert element at top into given index, shifting others up
+#[inline(always)]
+pub unsafe fn lua_insert(L: *mut lua_State, idx: c_int) {
+    lua_rotate(L, idx, 1)
 }
 
 /// Remove element at given index, shifting others down
-#[no_mangle]
-pub unsafe extern "C" fn lua_remove(L: *mut lua_State, idx: c_int) {
-    unimplemented!()
+#[inline(always)]
+pub unsafe fn lua_remove(L: *mut lua_State, idx: c_int) {
+    lua_rotate(L, idx, -1);
+    lua_pop(L, 1)
 }
 
 /// Replace element at given index with top of stack, then pop
-#[no_mangle]
-pub unsafe extern "C" fn lua_replace(L: *mut lua_State, idx: c_int) {
-    unimplemented!()
+#[inline(always)]
+pub unsafe fn lua_replace(L: *mut lua_State, idx: c_int) {
+    lua_copy(L, -1, idx);
+    lua_pop(L, 1)
 }
 
 /// Copy element from one index to another without changing stack size
 #[no_mangle]
-pub unsafe extern "C" fn lua_copy(L: *mut lua_State, fromidx: c_int, toidx: c_int) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_copy(L: *mut lua_State, fromidx: c_int, toidx: c_int) {
+    let fr = (*index2value(L, fromidx)).clone();
+    let to = index2value(L, toidx);
+    api_check!(L, isvalid(&*L, to), "invalid index");
+    if isvalid(&*L, to) {
+        *to = fr;
+    }
 }
 
 /// Push a nil value onto the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushnil(L: *mut lua_State) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushnil(L: *mut lua_State) {
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::Nil);
 }
 
 /// Push a number value onto the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushnumber(L: *mut lua_State, n: f64) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushnumber(L: *mut lua_State, n: lua_Number) {
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::Float(n));
 }
 
 /// Push an integer value onto the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushinteger(L: *mut lua_State, n: isize) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushinteger(L: *mut lua_State, n: lua_Integer) {
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::Int(n));
 }
 
-/// Push a string of given length onto the stack
+/// Push the `len` bytes at `s` as a string and return its internal copy.
+/// Strings are UTF-8 here: invalid sequences become U+FFFD
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushlstring(L: *mut lua_State, s: *const c_char, len: usize) -> *const c_char {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushlstring(L: *mut lua_State, s: *const c_char, len: usize) -> *const c_char {
+    let bytes = if len == 0 { &[][..] } else { std::slice::from_raw_parts(s as *const u8, len) };
+    let s = String::from_utf8_lossy(bytes).into_owned();
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::Str(s));
+    lua_tolstring(L, -1, ptr::null_mut())
 }
 
-/// Push a null-terminated string onto the stack
+/// Push the zero-terminated string `s` and return its internal copy; a
+/// NULL `s` pushes nil and returns NULL
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushstring(L: *mut lua_State, s: *const c_char) -> *const c_char {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushstring(L: *mut lua_State, s: *const c_char) -> *const c_char {
+    if s.is_null() {
+        lua_pushnil(L);
+        return ptr::null();
+    }
+    lua_pushlstring(L, s, CStr::from_ptr(s).to_bytes().len())
 }
 
-/// Push a C closure with `n` upvalues onto the stack
+/// Pop `n` values and push a C closure of `f` with them as its upvalues,
+/// which `f` reaches at lua_upvalueindex(1..n)
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushcclosure(L: *mut lua_State, f: lua_CFunction, n: c_int) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushcclosure(L: *mut lua_State, f: lua_CFunction, n: c_int) {
+    api_checknelems!(L, n);
+    api_check!(L, (0..=MAXUPVAL).contains(&n), "upvalue index too large");
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let first = L1.stack.len() - n.clamp(0, MAXUPVAL) as usize;
+    let upvalue: Box<[TValue]> = L1.stack.split_off(first).into_boxed_slice();
+    let owner = Rc::new(CClosureOwner(Cell::new(None)));
+    let v = crate::lobject::LuaValue::Function(Box::new({
+        let owner = owner.clone();
+        move |L1: &mut crate::lstate::LuaState| {
+            let _ = &owner;
+            L1.call_rust(|L1| unsafe { f(L1 as *mut crate::lstate::LuaState as *mut lua_State) })
+        }
+    }));
+    let id = ObjectId::of(&v).expect("functions have an identity");
+    C_CLOSURES.with(|c| c.borrow_mut().insert(id, CClosure { f, upvalue: Box::into_raw(upvalue) }));
+    owner.0.set(Some(id));
+    L1.push(v);
 }
 
-/// Push a boolean value onto the stack
+/// Push a boolean value onto the stack (any nonzero `b` is true)
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushboolean(L: *mut lua_State, b: c_int) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushboolean(L: *mut lua_State, b: c_int) {
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::Bool(b != 0));
 }
 
 /// Push a light userdata pointer onto the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_pushlightuserdata(L: *mut lua_State, p: *mut c_void) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_pushlightuserdata(L: *mut lua_State, p: *mut c_void) {
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::Pointer(p as *const ()));
 }
 
 /// Get the type of the value at the given stack index
 #[no_mangle]
-pub unsafe extern "C" fn lua_type(L: *mut lua_State, idx: c_int) -> c_int {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_type(L: *mut lua_State, idx: c_int) -> c_int {
+    use crate::lobject::LuaValue;
+    let o = index2value(L, idx);
+    if !isvalid(&*L, o) {
+        return LUA_TNONE;
+    }
+    match &*o {
+        LuaValue::Bool(_) => LUA_TBOOLEAN,
+        LuaValue::Pointer(_) => LUA_TLIGHTUSERDATA,
+        LuaValue::Int(_) | LuaValue::Float(_) => LUA_TNUMBER,
+        LuaValue::Str(_) => LUA_TSTRING,
+        LuaValue::Table(_) => LUA_TTABLE,
+        LuaValue::Function(_) => LUA_TFUNCTION,
+        LuaValue::UserData(_) => LUA_TUSERDATA,
+        LuaValue::Thread(_) => LUA_TTHREAD,
+        _ => LUA_TNIL,
+    }
+}
+
+/// Names of the basic types, from LUA_TNONE on (luaT_typenames_)
+static TYPENAMES: [&[u8]; 10] = [
+    b"no value\0", b"nil\0", b"boolean\0", b"userdata\0", b"number\0",
+    b"string\0", b"table\0", b"function\0", b"userdata\0", b"thread\0",
+];
+
+/// Get the name of type `tp` (a lua_type result)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_typename(L: *mut lua_State, tp: c_int) -> *const c_char {
+    api_check!(L, (LUA_TNONE..=LUA_TTHREAD).contains(&tp), "invalid type");
+    TYPENAMES.get((tp + 1) as usize).map_or(ptr::null(), |name| name.as_ptr() as *const c_char)
 }
 
-/// Get the name of the type at the given stack index
+/// Check if the value at the given index is an integer (number with integer subtype)
 #[no_mangle]
-pub unsafe extern "C" fn lua_typename(L: *mut lua_State, tp: c_int) -> *const c_char {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_isinteger(L: *mut lua_State, idx: c_int) -> c_int {
+    matches!(*index2value(L, idx), crate::lobject::LuaValue::Int(_)) as c_int
 }
 
-/// Check if the value at the given index is a number and return it
+/// Number `o` converts to: numbers and numeric strings
+fn tonumeral(o: &TValue) -> Option<crate::lobject::Numeral> {
+    use crate::lobject::{LuaValue, Numeral};
+    match o {
+        LuaValue::Int(i) => Some(Numeral::Int(*i)),
+        LuaValue::Float(f) => Some(Numeral::Float(*f)),
+        LuaValue::Str(s) => crate::lobject::luaO_str2number(s),
+        _ => None,
+    }
+}
+
+/// Convert the value at the given index to a number (numbers and numeric
+/// strings; 0 otherwise), setting `*isnum` to whether that succeeded
 #[no_mangle]
-pub unsafe extern "C" fn lua_tonumberx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> f64 {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_tonumberx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> lua_Number {
+    use crate::lobject::Numeral;
+    let n = match tonumeral(&*index2value(L, idx)) {
+        Some(Numeral::Int(i)) => Some(i as lua_Number),
+        Some(Numeral::Float(f)) => Some(f),
+        None => None,
+    };
+    if !isnum.is_null() {
+        *isnum = n.is_some() as c_int;
+    }
+    n.unwrap_or(0.0)
+}
+
+/// Convert the value at the given index to an integer (integers, floats
+/// with an exact integer value and strings of either; 0 otherwise), setting
+/// `*isnum` to whether that succeeded
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tointegerx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> lua_Integer {
+    use crate::lobject::Numeral;
+    let i = match tonumeral(&*index2value(L, idx)) {
+        Some(Numeral::Int(i)) => Some(i),
+        Some(Numeral::Float(f)) => crate::skylaconf::float_to_integer(f),
+        None => None,
+    };
+    if !isnum.is_null() {
+        *isnum = i.is_some() as c_int;
+    }
+    i.unwrap_or(0)
+}
+
+/// Truth value of the value at the given index: 0 for nil and false (and
+/// an invalid index), 1 for everything else
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_toboolean(L: *mut lua_State, idx: c_int) -> c_int {
+    use crate::lobject::LuaValue;
+    !matches!(*index2value(L, idx), LuaValue::Nil | LuaValue::Bool(false)) as c_int
+}
+
+/// Contents of the string at the given index, zero-terminated, with its
+/// length in `*len` (if not NULL). A number is converted in place, as in
+/// C Lua, which confuses lua_next if done to a key; other values give NULL
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tolstring(L: *mut lua_State, idx: c_int, len: *mut usize) -> *const c_char {
+    use crate::lobject::LuaValue;
+    let o = index2value(L, idx);
+    let s = match &*o {
+        LuaValue::Str(s) => s.clone(),
+        LuaValue::Int(i) => crate::lobject::luaO_int2str(*i),
+        LuaValue::Float(f) => crate::lobject::luaO_num2str_dot(*f),
+        _ => {
+            if !len.is_null() {
+                *len = 0;
+            }
+            return ptr::null();
+        }
+    };
+    if !len.is_null() {
+        *len = s.len();
+    }
+    let p = api_string(&s);
+    if !matches!(*o, LuaValue::Str(_)) {
+        *o = LuaValue::Str(s);
+    }
+    p
+}
+
+/// The C function of the C closure at the given index, or NULL for any
+/// other value (Rust functions included)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tocfunction(L: *mut lua_State, idx: c_int) -> Option<lua_CFunction> {
+    cclosure_of(&*index2value(L, idx), |cl| cl.f)
+}
+
+/// Identity pointer of the value at the given index: the GC object for
+/// tables, functions and threads, the memory block for full userdata, the
+/// pointer itself for light userdata, and NULL for everything else. The
+/// pointer is stable while the object is alive (see lstate::ObjectId)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_topointer(L: *mut lua_State, idx: c_int) -> *const c_void {
+    let o = index2value(L, idx);
+    match lua_type(L, idx) {
+        LUA_TLIGHTUSERDATA => pvalue(o),
+        LUA_TUSERDATA => getudatamem(o),
+        LUA_TTABLE | LUA_TFUNCTION | LUA_TTHREAD => gcvalue(o),
+        _ => ptr::null(),
+    }
+}
+
+/// Are the two values primitively equal (no __eq)? 0 if either index is invalid
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_rawequal(L: *mut lua_State, index1: c_int, index2: c_int) -> c_int {
+    let o1 = index2value(L, index1);
+    let o2 = index2value(L, index2);
+    if !isvalid(&*L, o1) || !isvalid(&*L, o2) {
+        return 0;
+    }
+    let t1 = lua_type(L, index1);
+    if t1 != lua_type(L, index2) {
+        return 0;
+    }
+    let eq = match t1 {
+        LUA_TNIL => true,
+        LUA_TBOOLEAN => lua_toboolean(L, index1) == lua_toboolean(L, index2),
+        LUA_TNUMBER => {
+            // mixed subtypes compare exactly, not through a float conversion
+            let number = |idx| if lua_isinteger(L, idx) != 0 {
+                crate::lobject::LuaValue::Int(lua_tointegerx(L, idx, ptr::null_mut()))
+            } else {
+                crate::lobject::LuaValue::Float(lua_tonumberx(L, idx, ptr::null_mut()))
+            };
+            crate::lstate::raw_equal(&number(index1), &number(index2))
+        }
+        LUA_TSTRING => {
+            let (mut l1, mut l2) = (0, 0);
+            let s1 = lua_tolstring(L, index1, &mut l1);
+            let s2 = lua_tolstring(L, index2, &mut l2);
+            std::slice::from_raw_parts(s1 as *const u8, l1) == std::slice::from_raw_parts(s2 as *const u8, l2)
+        }
+        _ => lua_topointer(L, index1) == lua_topointer(L, index2),
+    };
+    eq as c_int
+}
+
+/// Compare the values at two indices as ==, < or <= do in Lua (`op` is
+/// LUA_OPEQ, LUA_OPLT or LUA_OPLE), metamethods included. 0 if either
+/// index is invalid
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_compare(L: *mut lua_State, index1: c_int, index2: c_int, op: c_int) -> c_int {
+    if lua_type(L, index1) == LUA_TNONE || lua_type(L, index2) == LUA_TNONE {
+        return 0;
+    }
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    lua_pushvalue(L, index1);
+    lua_pushvalue(L, index2);
+    let b = L1.pop().unwrap();
+    let a = L1.pop().unwrap();
+    let res = match op {
+        LUA_OPEQ => crate::lvm::luaV_equalobj(L1, &a, &b),
+        LUA_OPLT => crate::lvm::luaV_lessthan(L1, &a, &b),
+        LUA_OPLE => crate::lvm::luaV_lessequal(L1, &a, &b),
+        _ => {
+            api_check!(L, false, "invalid option");
+            false
+        }
+    };
+    res as c_int
 }
 
-/// Check if the value at the given index is an integer and return it
+/// Pop `n` values and push their concatenation, as `..` does (metamethods
+/// included); with `n` 1 the value stays as it is, with `n` 0 the empty
+/// string is pushed
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_concat(L: *mut lua_State, n: c_int) {
+    api_checknelems!(L, n);
+    api_check!(L, n >= 0, "invalid number of values to concatenate");
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let mut vals: Vec<crate::lobject::LuaValue> = (0..n).map(|_| L1.pop().unwrap()).collect();
+    vals.reverse();
+    let res = crate::lvm::luaV_concat(L1, vals);
+    L1.push(res);
+}
+
+/// Push the length of the value at the given index, as `#` does (__len
+/// included)
 #[no_mangle]
-pub unsafe extern "C" fn lua_tointegerx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> isize {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_len(L: *mut lua_State, idx: c_int) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    lua_pushvalue(L, idx);
+    let v = L1.pop().unwrap();
+    let res = crate::lvm::luaV_objlen(L1, &v);
+    L1.push(res);
+}
+
+/// Stack slot (0-based) of the valid, non-pseudo index `idx`
+unsafe fn index2slot(L1: &crate::lstate::LuaState, idx: c_int) -> usize {
+    let L = L1 as *const crate::lstate::LuaState as *mut lua_State;
+    let slot = if idx > 0 {
+        L1.ci.borrow().func + idx as usize
+    } else {
+        api_check!(L, idx != 0 && -idx as usize <= L1.stack.len(), "invalid index");
+        (L1.stack.len() as c_int + idx) as usize
+    };
+    api_check!(L, slot < L1.stack.len(), "invalid index");
+    slot
 }
 
-/// Check if the value at the given index is a boolean and return it
+/// Mark the slot at the given index as to-be-closed: its value's __close
+/// runs when the slot is closed with lua_closeslot, when the running
+/// function returns, or when an error unwinds past it. The slot must be
+/// above every other to-be-closed slot; nil and false need no closing
 #[no_mangle]
-pub unsafe extern "C" fn lua_toboolean(L: *mut lua_State, idx: c_int) -> c_int {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_toclose(L: *mut lua_State, idx: c_int) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let slot = index2slot(L1, idx);
+    L1.to_close(slot);
 }
 
-/// Check if the value at the given index is a string and return it
+/// Close the to-be-closed slot at the given index and set it to nil
 #[no_mangle]
-pub unsafe extern "C" fn lua_tolstring(L: *mut lua_State, idx: c_int, len: *mut usize) -> *const c_char {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_closeslot(L: *mut lua_State, idx: c_int) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let slot = index2slot(L1, idx);
+    L1.close_slot(slot);
 }
 
-/// Check if the value at the given index is a C function and return it
+/// Version number of this core (LUA_VERSION_NUM), as checked by
+/// luaL_checkversion
 #[no_mangle]
-pub unsafe extern "C" fn lua_tocfunction(L: *mut lua_State, idx: c_int) -> lua_CFunction {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_version(L: *mut lua_State) -> lua_Number {
+    LUA_VERSION_NUM
 }
 
-/// Check if the value at the given index is a pointer and return it
+/// Convert the zero-terminated string `s` to a number, push it and return
+/// the string's size plus one; return 0 and push nothing if `s` is not a
+/// numeral. Integers keep their subtype, as with luaO_str2number.
 #[no_mangle]
-pub unsafe extern "C" fn lua_topointer(L: *mut lua_State, idx: c_int) -> *const c_void {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_stringtonumber(L: *mut lua_State, s: *const c_char) -> usize {
+    let s = CStr::from_ptr(s);
+    let n = match std::str::from_utf8(s.to_bytes()).ok().and_then(crate::lobject::luaO_str2number) {
+        Some(n) => n,
+        None => return 0,
+    };
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    L1.push(match n {
+        crate::lobject::Numeral::Int(i) => crate::lobject::LuaValue::Int(i),
+        crate::lobject::Numeral::Float(f) => crate::lobject::LuaValue::Float(f),
+    });
+    s.to_bytes().len() + 1
 }
 
-/// Create a new table and push it onto the stack
+/// Create a new table with preallocated array/hash parts and push it onto the stack
 #[no_mangle]
-pub unsafe extern "C" fn lua_newtable(L: *mut lua_State) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_createtable(L: *mut lua_State, narr: c_int, nrec: c_int) {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let t = crate::ltable::Table::with_capacity(narr.max(0) as usize, nrec.max(0) as usize);
+    L1.push(crate::lobject::LuaValue::Table(std::rc::Rc::new(std::cell::RefCell::new(t))));
 }
 
-/// Create a new userdata block and push it onto the stack
+/// Create a new empty table and push it onto the stack
+#[inline(always)]
+pub unsafe fn lua_newtable(L: *mut lua_State) {
+    lua_createtable(L, 0, 0)
+}
+
+/// Create a new userdata block with `nuvalue` user values and push it onto
+/// the stack. The block is zeroed and aligned for any C type
 #[no_mangle]
-pub unsafe extern "C" fn lua_newuserdata(L: *mut lua_State, size: usize) -> *mut c_void {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_newuserdatauv(L: *mut lua_State, size: usize, nuvalue: c_int) -> *mut c_void {
+    api_check!(L, (0..u16::MAX as c_int).contains(&nuvalue), "invalid value");
+    let u = Rc::new(Udata::new(size, nuvalue.max(0) as usize));
+    let p = u.as_ptr() as *mut c_void;
+    (*(L as *mut crate::lstate::LuaState)).push(crate::lobject::LuaValue::UserData(u));
+    p
+}
+
+/// Create a new userdata block (one user value) and push it onto the stack
+#[inline(always)]
+pub unsafe fn lua_newuserdata(L: *mut lua_State, size: usize) -> *mut c_void {
+    lua_newuserdatauv(L, size, 1)
 }
 
-/// Get a global variable and push it onto the stack
+/// String key of a field name given to the API
+unsafe fn field_key(k: *const c_char) -> TValue {
+    crate::lobject::LuaValue::Str(CStr::from_ptr(k).to_string_lossy().into_owned())
+}
+
+/// Push t[k] (__index included) and return its type
+unsafe fn auxgetstr(L: *mut lua_State, t: TValue, k: *const c_char) -> c_int {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let v = crate::lvm::luaV_finishget(L1, &t, &field_key(k));
+    L1.push(v);
+    api_incr_top!(L);
+    lua_type(L, -1)
+}
+
+/// Pop a value into t[k] (__newindex included)
+unsafe fn auxsetstr(L: *mut lua_State, t: TValue, k: *const c_char) {
+    api_checknelems!(L, 1);
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let v = L1.pop().unwrap_or(crate::lobject::LuaValue::Nil);
+    crate::lvm::luaV_finishset(L1, &t, &field_key(k), &v);
+}
+
+/// Push the value of global `name` and return its type
 #[no_mangle]
-pub unsafe extern "C" fn lua_getglobal(L: *mut lua_State, name: *const c_char) -> c_int {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_getglobal(L: *mut lua_State, name: *const c_char) -> c_int {
+    let gt = (*(L as *mut crate::lstate::LuaState)).globals_table();
+    auxgetstr(L, crate::lobject::LuaValue::Table(gt), name)
 }
 
-/// Set a global variable from the value at the top of the stack
+/// Pop a value into global `name`
 #[no_mangle]
-pub unsafe extern "C" fn lua_setglobal(L: *mut lua_State, name: *const c_char) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_setglobal(L: *mut lua_State, name: *const c_char) {
+    let gt = (*(L as *mut crate::lstate::LuaState)).globals_table();
+    auxsetstr(L, crate::lobject::LuaValue::Table(gt), name)
 }
 
-/// Get a table field by key and push it onto the stack
+/// Push t[k], where t is the value at the given index, and return its type
 #[no_mangle]
-pub unsafe extern "C" fn lua_getfield(L: *mut lua_State, idx: c_int, k: *const c_char) -> c_int {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_getfield(L: *mut lua_State, idx: c_int, k: *const c_char) -> c_int {
+    auxgetstr(L, (*index2value(L, idx)).clone(), k)
 }
 
-/// Set a table field by key from the value at the top of the stack
+/// Pop a value into t[k], where t is the value at the given index
 #[no_mangle]
-pub unsafe extern "C" fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const c_char) {
-    unimplemented!()
+pub unsafe extern "C-unwind" fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const c_char) {
+    auxsetstr(L, (*index2value(L, idx)).clone(), k)
 }
 
-/// Call a function in protected mode
+/// Call a function in protected mode. On error the function and its
+/// arguments are replaced by the error object, whatever value it is, after
+/// the message handler at `errfunc` (if not 0) has transformed it.
 #[no_mangle]
-pub unsafe extern "C" fn lua_pcallk(
+pub unsafe extern "C-unwind" fn lua_pcallk(
     L: *mut lua_State,
     nargs: c_int,
     nresults: c_int,
     errfunc: c_int,
-    ctx: isize,
-    k: Option<unsafe extern "C" fn(L: *mut lua_State) -> c_int>,
+    ctx: lua_KContext,
+    k: lua_KFunction,
 ) -> c_int {
-    unimplemented!()
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let handler = if errfunc == 0 {
+        None
+    } else {
+        lua_pushvalue(L, errfunc);
+        L1.pop()
+    };
+    let func = L1.stack.len() - nargs as usize - 1;
+    let olderrfunc = std::mem::replace(&mut L1.errfunc, handler);
+    let result = L1.pcall(|L1| lua_callk(L1 as *mut _ as *mut lua_State, nargs, nresults, ctx, k));
+    L1.errfunc = olderrfunc;
+    match result {
+        Ok(()) => LUA_OK,
+        Err(e) => {
+            let status = e.status();
+            L1.stack.truncate(func);
+            L1.push(e.into_value());
+            status
+        }
+    }
 }
 
-/// Call a function (not protected)
+/// Call a function (not protected): the function below the top `nargs`
+/// values and those arguments are replaced by its results, adjusted to
+/// `nresults` unless that is LUA_MULTRET. Errors unwind to the nearest
+/// protected call.
+///
+/// Without a continuation `k` the called function cannot yield. With one
+/// it can: the coroutine keeps this frame on its own stack while
+/// suspended, so once it is resumed and the function returns, lua_callk
+/// returns normally and `k` is not needed (nor called).
 #[no_mangle]
-pub unsafe extern "C" fn lua_callk(
+pub unsafe extern "C-unwind" fn lua_callk(
     L: *mut lua_State,
     nargs: c_int,
     nresults: c_int,
-    ctx: isize,
-    k: Option<unsafe extern "C" fn(L: *mut lua_State) -> c_int>,
+    ctx: lua_KContext,
+    k: lua_KFunction,
 ) {
-    unimplemented!()
+    api_checknelems!(L, nargs + 1);
+    api_check!(L, nargs >= 0 && (nresults >= 0 || nresults == LUA_MULTRET), "invalid call arguments");
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let _ = ctx;
+    if k.is_some() {
+        L1.call(nargs as usize, nresults);
+    } else {
+        // an error unwinds past dec_nyci; the protected call that catches
+        // it restores the count
+        L1.inc_nyci();
+        L1.call(nargs as usize, nresults);
+        L1.dec_nyci();
+    }
+}
+
+/// Where an upvalue lives: C closures hold API values, Lua closures
+/// register values
+enum UpvalSlot {
+    C(*mut TValue),
+    Lua(*mut crate::lvm::TValue),
+}
+
+/// Name and value slot of upvalue `n` of the function value `fi`. Lua
+/// closures name their upvalues after the captured variables; C closures
+/// have nameless ones, reported as ""
+unsafe fn aux_upvalue(fi: *const TValue, n: c_int) -> Option<(*const c_char, UpvalSlot)> {
+    if let Some(upvals) = clCupvalues(fi) {
+        let uv = upvals.get_mut((n as usize).wrapping_sub(1))?;
+        return Some((b"\0".as_ptr() as *const c_char, UpvalSlot::C(uv)));
+    }
+    let f = clLvalue(fi);
+    if f.is_null() {
+        return None; // not a closure
+    }
+    let (name, val) = (*f).upvalue(n as usize)?;
+    Some((crate::lvm::vm_string(name), UpvalSlot::Lua(val)))
+}
+
+/// Push the value of upvalue `n` of the function at `funcindex` and return
+/// its name, or return NULL (pushing nothing) if there is no such upvalue
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_getupvalue(L: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char {
+    match aux_upvalue(index2value(L, funcindex), n) {
+        Some((name, val)) => {
+            match val {
+                UpvalSlot::C(v) => (*(L as *mut crate::lstate::LuaState)).push((*v).clone()),
+                UpvalSlot::Lua(v) => setobj2s(L, v),
+            }
+            api_incr_top!(L);
+            name
+        }
+        None => ptr::null(),
+    }
 }
-/// Load a Lua chunk from a string
-pub unsafe extern "C" fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int {
-    unimplemented!()
-}     
 
-
-/// Load a Lua chunk from a file
-pub unsafe extern "C" fn luaL_loadfile(L: *mut lua_State, filename: *const c_char) -> c_int {
-    unimplemented!()
+/// Pop a value into upvalue `n` of the function at `funcindex` and return
+/// the upvalue's name, or return NULL (popping nothing) if there is no such
+/// upvalue. Every closure sharing the upvalue sees the new value
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_setupvalue(L: *mut lua_State, funcindex: c_int, n: c_int) -> *const c_char {
+    api_checknelems!(L, 1);
+    match aux_upvalue(index2value(L, funcindex), n) {
+        Some((name, val)) => {
+            match val {
+                UpvalSlot::C(v) => *v = (*index2value(L, -1)).clone(),
+                UpvalSlot::Lua(v) => *v = s2v(index2value(L, -1)),
+            }
+            lua_pop(L, 1);
+            name
+        }
+        None => ptr::null(),
+    }
 }
 
-use std::os::raw::{c_int, c_void};
-use std::ffi::CStr;
-use crate::lstate::lua_State;
-use crate::lvm;
-
-/// Coroutine-related constants from Lua
-pub const LUA_OK: c_int = 0;
-pub const LUA_YIELD: c_int = 1;
-pub const LUA_ERRRUN: c_int = 2;
-
-/// Create a new coroutine thread.
-/// Pushes the new thread onto the stack.
-pub unsafe fn lua_newthread(L: *mut lua_State) -> *mut lua_State {
-    // Your implementation here: create new lua_State as a coroutine thread,
-    // link to main state, setup stack, etc.
-    unimplemented!()
+/// Lua closure at `fidx`, whose upvalues can be joined
+unsafe fn getlclosure(L: *mut lua_State, fidx: c_int) -> *mut crate::lvm::Closure {
+    let f = clLvalue(index2value(L, fidx));
+    api_check!(L, !f.is_null(), "Lua function expected");
+    f
 }
 
-/// Push a copy of the value at index `idx` onto the stack.
-pub unsafe fn lua_pushvalue(L: *mut lua_State, idx: c_int) {
-    // Copy value from idx to top of stack.
-    unimplemented!()
+/// Identity of upvalue `n` of the function at `funcindex`: two closures get
+/// the same id exactly when they share that upvalue, e.g. when they
+/// captured the same local or were joined by lua_upvaluejoin. NULL if `n`
+/// is out of range or the function is a light C function
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_upvalueid(L: *mut lua_State, funcindex: c_int, n: c_int) -> *mut c_void {
+    let fi = index2value(L, funcindex);
+    if let Some(upvals) = clCupvalues(fi) {
+        // C closures never share upvalues, each slot is its own
+        return upvals.get_mut((n as usize).wrapping_sub(1)).map_or(ptr::null_mut(), |uv| uv as *mut _ as *mut c_void);
+    }
+    api_check!(L, lua_type(L, funcindex) == LUA_TFUNCTION, "function expected");
+    (*getlclosure(L, funcindex)).upvalue_id(n as usize).map_or(ptr::null_mut(), |uv| uv.as_ptr() as *mut c_void)
+}
+
+/// Make upvalue `n1` of the Lua closure at `funcindex1` refer to upvalue
+/// `n2` of the Lua closure at `funcindex2`. The two closures then share
+/// that variable, as hot-reload libraries need when they rebind a new
+/// function to the state of the old one
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_upvaluejoin(L: *mut lua_State, funcindex1: c_int, n1: c_int, funcindex2: c_int, n2: c_int) {
+    let f1 = getlclosure(L, funcindex1);
+    let f2 = getlclosure(L, funcindex2);
+    let up2 = (*f2).upvalue_id(n2 as usize);
+    api_check!(L, up2.is_some_and(|uv| (*f1).join_upvalue(n1 as usize, uv)), "invalid upvalue index");
+    // luaC_objbarrier(L, f1, up): the upvalue may now be reachable from a black closure
 }
 
-/// Move `n` values from thread `from` to `to`.
-pub unsafe fn lua_xmove(from: *mut lua_State, to: *mut lua_State, n: c_int) {
-    // Move values from one lua_State stack to another.
-    unimplemented!()
+/// Load the zero-terminated string `s` as a Lua chunk named after itself,
+/// text or binary. Pushes the chunk, or the error message on failure.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let s = CStr::from_ptr(s).to_bytes();
+    let opts = crate::lsourcemap::LoadOptions { chunkname: String::from_utf8_lossy(s).into_owned(), ..Default::default() };
+    match L1.load_buffer_with(s, &opts) {
+        Ok(()) => LUA_OK,
+        Err(e) => {
+            let status = e.status();
+            L1.push(e.into_value());
+            status
+        }
+    }
 }
 
-/// Convert the value at given index to a coroutine thread.
-/// Returns null if value is not a thread.
-pub unsafe fn lua_tothread(L: *mut lua_State, idx: c_int) -> *mut lua_State {
-    // Return lua_State pointer if value at idx is thread, else null.
-    unimplemented!()
+/// Load a Lua chunk from a file ("b", "t" or "bt" mode; NULL means "bt"),
+/// or from standard input if `filename` is NULL. The file is read through
+/// the state's Vfs. Pushes the chunk, or the error message on failure.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_loadfilex(L: *mut lua_State, filename: *const c_char, mode: *const c_char) -> c_int {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let fname = (!filename.is_null()).then(|| CStr::from_ptr(filename).to_string_lossy().into_owned());
+    let mode = if mode.is_null() { "bt".to_string() } else { CStr::from_ptr(mode).to_string_lossy().into_owned() };
+    let mut opts = crate::lsourcemap::LoadOptions { mode, ..Default::default() };
+    match crate::lbaselib::load_file(L1, fname.as_deref(), &mut opts) {
+        Ok(()) => LUA_OK,
+        Err(e) => {
+            let status = e.status();
+            L1.push(e.into_value());
+            status
+        }
+    }
 }
 
-/// Resume a coroutine `co` with `nargs` arguments, using `L` as the caller state.
-/// Returns status code: LUA_OK, LUA_YIELD, or error.
-pub unsafe fn lua_resume(co: *mut lua_State, from: *mut lua_State, nargs: c_int) -> c_int {
-    // Run coroutine, resume execution.
-    // Update states accordingly.
-    unimplemented!()
+/// Load a Lua chunk from a file
+#[inline(always)]
+pub unsafe fn luaL_loadfile(L: *mut lua_State, filename: *const c_char) -> c_int {
+    luaL_loadfilex(L, filename, ptr::null())
 }
 
-/// Yield the current coroutine, returning `nresults` values.
-pub unsafe fn lua_yield(L: *mut lua_State, nresults: c_int) -> c_int {
-    // Suspend current coroutine, return to caller.
-    unimplemented!()
-}
+/// Coroutine-related constants from Lua
+pub const LUA_OK: c_int = 0;
+pub const LUA_YIELD: c_int = 1;
+pub const LUA_ERRRUN: c_int = 2;
+pub const LUA_ERRSYNTAX: c_int = 3;
+pub const LUA_ERRMEM: c_int = 4;
+pub const LUA_ERRERR: c_int = 5;
 
-/// Return the status of a coroutine thread.
-pub unsafe fn lua_status(L: *mut lua_State) -> c_int {
-    // Return LUA_OK, LUA_YIELD, or error code.
-    unimplemented!()
+/// Create a new thread sharing the global state of `L`, push it and return
+/// it. The thread starts with an empty stack and the hook of `L`; like any
+/// value it lives as long as it is referenced.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_newthread(L: *mut lua_State) -> *mut lua_State {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let th = crate::lstate::LuaThread::new(L1.l_G.clone());
+    (*th.state()).hook = L1.hook;
+    let L2 = th.state() as *mut lua_State;
+    L1.push(crate::lobject::LuaValue::Thread(th));
+    L2
 }
 
-/// Return the number of values on the stack.
-pub unsafe fn lua_gettop(L: *mut lua_State) -> c_int {
-    // Return stack top index.
-    unimplemented!()
+/// Pop `n` values from `from` and push them onto `to`, in order. Both must
+/// be threads of the same state.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_xmove(from: *mut lua_State, to: *mut lua_State, n: c_int) {
+    if from == to {
+        return;
+    }
+    api_checknelems!(from, n);
+    let (F, T) = (&mut *(from as *mut crate::lstate::LuaState), &mut *(to as *mut crate::lstate::LuaState));
+    api_check!(from, Rc::ptr_eq(&F.l_G, &T.l_G), "moving among independent states");
+    let first = F.stack.len() - n.max(0) as usize;
+    let values = F.stack.split_off(first);
+    T.stack.extend(values);
+}
+
+/// The thread at the given index, or NULL if the value is not a thread
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_tothread(L: *mut lua_State, idx: c_int) -> *mut lua_State {
+    match &*index2value(L, idx) {
+        crate::lobject::LuaValue::Thread(th) => th.state() as *mut lua_State,
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Pop `nargs` values from `L`, the resumer, and leave the error message in
+/// their place (resume_error in ldo.c)
+unsafe fn resume_error(L: *mut lua_State, msg: &str, nargs: c_int) -> c_int {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    L1.stack.truncate(L1.stack.len() - nargs as usize);
+    L1.push(crate::lobject::LuaValue::Str(msg.to_string()));
+    LUA_ERRRUN
+}
+
+/// Start or continue coroutine `L` with the `nargs` values on top of its
+/// stack: as arguments of its function (below them) on the first resume,
+/// as the results of its pending yield afterwards. `from` is the coroutine
+/// doing the resume, or NULL. Returns LUA_YIELD when it yields and LUA_OK
+/// when its function returns, with `*nres` values on top of its stack, or
+/// an error status with the error object on top (the coroutine is then
+/// dead, its frames kept for a traceback until lua_closethread).
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_resume(L: *mut lua_State, from: *mut lua_State, nargs: c_int, nres: *mut c_int) -> c_int {
+    use crate::lstate::TStatus;
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    api_checknelems!(L, nargs);
+    // held for the whole resume, so the coroutine outlives it even if every
+    // value referencing it goes away meanwhile
+    let Some(th) = L1.thread.as_ref().and_then(std::rc::Weak::upgrade) else {
+        return resume_error(L, "cannot resume non-suspended coroutine", nargs);
+    };
+    match L1.status {
+        TStatus::LUA_YIELD => {}
+        TStatus::LUA_OK if th.coroutine().is_some() => {
+            return resume_error(L, "cannot resume non-suspended coroutine", nargs);
+        }
+        TStatus::LUA_OK if L1.get_top() > nargs => {}
+        _ => return resume_error(L, "cannot resume dead coroutine", nargs),
+    }
+    let depth = if from.is_null() {
+        1
+    } else {
+        let caller = (*(from as *mut crate::lstate::LuaState)).thread.as_ref().and_then(std::rc::Weak::upgrade);
+        caller.map_or(1, |caller| caller.depth.get() + 1)
+    };
+    if depth >= crate::llimits::LUAI_MAXCCALLS {
+        return resume_error(L, "C stack overflow", nargs);
+    }
+    th.depth.set(depth);
+    th.transfer.set(nargs as usize);
+    if th.coroutine().is_none() {
+        let state = th.state();
+        let body = move || (*state).call(nargs as usize, LUA_MULTRET);
+        match crate::lcontext::Coroutine::new(body) {
+            Ok(co) => th.set_coroutine(Some(co)),
+            Err(msg) => return resume_error(L, msg, nargs),
+        }
+    }
+    L1.status = TStatus::LUA_OK;
+    let result = th.coroutine().expect("the coroutine was just started").resume();
+    let status = match result {
+        Ok(false) => LUA_YIELD,
+        Ok(true) => {
+            th.set_coroutine(None);
+            LUA_OK
+        }
+        Err(payload) => {
+            th.set_coroutine(None);
+            let (status, tstatus) = if let Some(&crate::ldo::LuaThrow(status)) = payload.downcast_ref::<crate::ldo::LuaThrow>() {
+                if status == crate::ldo::LuaStatus::MemoryError {
+                    L1.push(crate::lobject::LuaValue::Str("not enough memory".to_string()));
+                    (LUA_ERRMEM, TStatus::LUA_ERRMEM)
+                } else {
+                    (LUA_ERRRUN, TStatus::LUA_ERRRUN) // the error object is on the stack
+                }
+            } else {
+                std::panic::resume_unwind(payload)
+            };
+            L1.status = tstatus;
+            status
+        }
+    };
+    *nres = if status == LUA_YIELD { th.transfer.get() as c_int } else { L1.get_top() };
+    status
 }
 
-/// Push boolean onto stack.
-pub unsafe fn lua_pushboolean(L: *mut lua_State, b: c_int) {
-    // Push boolean true/false
-    unimplemented!()
+/// Yield the running coroutine, handing the top `nresults` values to its
+/// resumer. This returns once the coroutine is resumed, with the values
+/// passed to lua_resume on top of the stack: through continuation `k`
+/// with LUA_YIELD and `ctx` if there is one, else by returning their
+/// number, so `return lua_yield(L, n)` passes them on as the results of
+/// the yielding function.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_yieldk(L: *mut lua_State, nresults: c_int, ctx: lua_KContext, k: lua_KFunction) -> c_int {
+    api_checknelems!(L, nresults);
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    // the resumer holds the thread alive; no strong reference may stay on
+    // this stack while it is suspended, or dropping it could never free it
+    let th = match L1.thread.as_ref().and_then(std::rc::Weak::upgrade) {
+        Some(th) if th.coroutine().is_some() => Rc::as_ptr(&th),
+        _ => L1.error("attempt to yield from outside a coroutine"),
+    };
+    if !L1.yieldable() {
+        L1.error("attempt to yield across a C-call boundary");
+    }
+    L1.status = crate::lstate::TStatus::LUA_YIELD;
+    (*th).transfer.set(nresults as usize);
+    (*th).coroutine().expect("a running coroutine has its stack").suspend();
+    match k {
+        Some(k) => k(L, LUA_YIELD, ctx),
+        None => (*th).transfer.get() as c_int,
+    }
 }
 
-/// Push a string onto the stack.
-pub unsafe fn lua_pushstring(L: *mut lua_State, s: *const i8) {
-    // Push null-terminated C string.
-    unimplemented!()
+/// Yield the current coroutine, returning `nresults` values.
+#[inline(always)]
+pub unsafe fn lua_yield(L: *mut lua_State, nresults: c_int) -> c_int {
+    lua_yieldk(L, nresults, 0, None)
 }
 
-/// Raise a Lua error (longjmp).
-pub unsafe fn lua_error(L: *mut lua_State) -> ! {
-    // Raise error, never returns.
-    unimplemented!()
+/// Whether the running function may yield: it runs in a coroutine, not
+/// under a lua_callk or lua_pcallk without continuation
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_isyieldable(L: *mut lua_State) -> c_int {
+    (*(L as *mut crate::lstate::LuaState)).is_yieldable() as c_int
 }
 
-/// Register a C function on top of the stack with a name in the table at the given index.
-pub unsafe fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const i8) {
-    // Set field k in table at idx with value at top of stack.
-    unimplemented!()
+/// Reset thread `L` so it can run a new function: close its pending
+/// upvalues, unwind its calls and empty its stack. Returns LUA_OK, or the
+/// error status the thread had died with, leaving the error object as the
+/// only value on its stack. A suspended coroutine is unwound first, so
+/// the Rust frames it was in drop what they hold. `from` is the thread
+/// doing the reset (for the C-call count), or NULL.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_closethread(L: *mut lua_State, from: *mut lua_State) -> c_int {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    let th = L1.thread.as_ref().and_then(std::rc::Weak::upgrade);
+    if let Some(th) = &th {
+        let running = th.coroutine().is_some_and(|co| co.status() == crate::lcontext::CoStatus::Running);
+        api_check!(L, !running, "cannot close a running coroutine");
+        th.set_coroutine(None);
+    }
+    L1.nci = if from.is_null() { 0 } else { (*(from as *mut crate::lstate::LuaState)).get_ccalls() };
+    let status = L1.status;
+    let status = crate::lstate::luaE_resetthread(L1, status) as c_int;
+    if th.is_some() {
+        L1.stack.insert(0, crate::lobject::LuaValue::Nil); // the base function slot
+    }
+    status
+}
+
+/// Same as lua_closethread with a NULL `from` (deprecated in Lua 5.4.6)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_resetthread(L: *mut lua_State) -> c_int {
+    lua_closethread(L, ptr::null_mut())
 }
 
-/// Push a new empty table onto the stack.
-pub unsafe fn lua_newtable(L: *mut lua_State) {
-    // Push new table.
-    unimplemented!()
+/// Status of thread `L`: LUA_OK while it runs or can start a function,
+/// LUA_YIELD while suspended, or the error status it died with
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_status(L: *mut lua_State) -> c_int {
+    (*(L as *mut crate::lstate::LuaState)).status as c_int
 }
 
-/// Push a C function onto the stack.
-pub unsafe fn lua_pushcfunction(L: *mut lua_State, f: Option<extern "C" fn(*mut lua_State) -> c_int>) {
-    // Push C function as a Lua callable.
-    unimplemented!()
+/// Raise the value on top of the stack as a Lua error. It unwinds to the
+/// nearest protected call, which receives it as the error object.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_error(L: *mut lua_State) -> ! {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    // throw runs the message handler
+    let errobj = L1.pop().unwrap_or(crate::lobject::LuaValue::Nil);
+    L1.throw(errobj)
 }
 
-/// Check argument at given stack index is of expected type.
-pub unsafe fn luaL_checktype(L: *mut lua_State, arg: c_int, t: c_int) {
-    // Panic or raise error if type mismatch.
-    unimplemented!()
+/// Push a C function (a closure without upvalues) onto the stack
+#[inline(always)]
+pub unsafe fn lua_pushcfunction(L: *mut lua_State, f: lua_CFunction) {
+    lua_pushcclosure(L, f, 0)
 }
 
-/// Throw a Lua error with formatted message.
+/// Raise `msg` as an error located at the calling Lua function. Rust
+/// callers with format arguments use the luaL_error! macro instead.
 pub unsafe fn luaL_error(L: *mut lua_State, msg: *const i8) -> ! {
-    // Raise error.
-    unimplemented!()
+    crate::lauxlib::luaL_errorat(L.cast(), 1, &CStr::from_ptr(msg).to_string_lossy())
 }
 
 /// Returns the stack index for the upvalue.
@@ -450,14 +1337,385 @@ pub unsafe fn lua_upvalueindex(i: c_int) -> c_int {
     -1001000 - i
 }
 
-/// Push the current coroutine thread.
-pub unsafe fn lua_pushthread(L: *mut lua_State) -> c_int {
-    // Push thread on stack and return 1 if main thread.
-    unimplemented!()
+/// Push thread `L` onto its own stack and return 1 if it is the main
+/// thread. The main thread is not a value of its own: nil stands for it.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn lua_pushthread(L: *mut lua_State) -> c_int {
+    let L1 = &mut *(L as *mut crate::lstate::LuaState);
+    match L1.thread.as_ref().and_then(std::rc::Weak::upgrade) {
+        Some(th) => {
+            L1.push(crate::lobject::LuaValue::Thread(th));
+            0
+        }
+        None => {
+            L1.push(crate::lobject::LuaValue::Nil);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobject::LuaValue;
+    use crate::lstate::{GlobalState, LuaState};
+    use crate::lvm::{closure_value, Closure, ClosureType, Proto, UpVal};
+    use std::cell::RefCell;
+    use std::ptr::NonNull;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_upvalue_api() {
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: vec!["_ENV".to_string(), "count".to_string()],
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let p: *mut Proto = &mut p;
+        let mut f = Closure { cl: ClosureType { p }, upvals: vec![UpVal::closed(crate::lvm::TValue::nil()), UpVal::closed(crate::lvm::TValue::from_integer(1))] };
+        let mut g = Closure { cl: ClosureType { p }, upvals: vec![UpVal::closed(crate::lvm::TValue::nil()), UpVal::closed(crate::lvm::TValue::from_integer(2))] };
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        state.push(closure_value(NonNull::from(&mut f)));
+        state.push(closure_value(NonNull::from(&mut g)));
+        state.push(LuaValue::Function(Box::new(|_: &mut LuaState| 0)));
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            let name = lua_getupvalue(l, 1, 2);
+            assert_eq!(CStr::from_ptr(name).to_str().unwrap(), "count");
+            assert_eq!(state.pop(), Some(LuaValue::Int(1)));
+            assert!(lua_getupvalue(l, 1, 3).is_null() && lua_gettop(l) == 3);
+            // Rust functions have no upvalues the API can reach
+            assert!(lua_getupvalue(l, 3, 1).is_null());
+            assert_eq!(lua_type(l, 3), LUA_TFUNCTION);
+            assert_eq!(lua_type(l, 4), LUA_TNONE);
+
+            state.push(LuaValue::Str("x".to_string()));
+            let name = lua_setupvalue(l, 1, 1);
+            assert_eq!(CStr::from_ptr(name).to_str().unwrap(), "_ENV");
+            assert_eq!(lua_gettop(l), 3);
+            lua_getupvalue(l, 1, 1);
+            assert_eq!(state.pop(), Some(LuaValue::Str("x".to_string())));
+
+            assert_ne!(lua_upvalueid(l, 1, 2), lua_upvalueid(l, 2, 2));
+            lua_upvaluejoin(l, 2, 2, 1, 2);
+            assert_eq!(lua_upvalueid(l, 1, 2), lua_upvalueid(l, 2, 2));
+            // a write through one closure is seen by the other
+            state.push(LuaValue::Int(10));
+            lua_setupvalue(l, 2, 2);
+            lua_getupvalue(l, 1, 2);
+            assert_eq!(state.pop(), Some(LuaValue::Int(10)));
+        }
+    }
+    #[test]
+    fn test_rawequal_mixed_numbers() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Int((1 << 53) + 1));
+        state.push(LuaValue::Float((1u64 << 53) as f64));
+        state.push(LuaValue::Int(1 << 53));
+        state.push(LuaValue::Int(i64::MAX));
+        state.push(LuaValue::Float(9223372036854775808.0));
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            // 2^53 + 1 rounds to 2^53 as a float, but the values differ
+            assert_eq!(lua_rawequal(l, 1, 2), 0);
+            assert_eq!(lua_rawequal(l, 2, 3), 1);
+            assert_eq!(lua_rawequal(l, 4, 5), 0);
+            assert_eq!(lua_rawequal(l, 1, 1), 1);
+        }
+    }
+    #[test]
+    fn test_loadfilex_reads_through_the_vfs() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let vfs = crate::lvfs::MemoryVfs::new();
+        vfs.insert("main.lua", "return 1");
+        state.set_vfs(std::sync::Arc::new(vfs));
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            assert_eq!(luaL_loadfilex(l, b"missing.lua\0".as_ptr() as *const c_char, ptr::null()), crate::lauxlib::LUA_ERRFILE);
+            assert!(matches!(state.pop(), Some(LuaValue::Str(m)) if m.contains("cannot open missing.lua")));
+            // a text chunk is refused in binary-only mode
+            assert_eq!(luaL_loadfilex(l, b"main.lua\0".as_ptr() as *const c_char, b"b\0".as_ptr() as *const c_char), LUA_ERRSYNTAX);
+            state.pop();
+            assert_eq!(luaL_loadfile(l, b"main.lua\0".as_ptr() as *const c_char), LUA_OK);
+            assert_eq!(lua_type(l, -1), LUA_TFUNCTION);
+        }
+    }
+    #[test]
+    fn test_yield_inside_a_metamethod() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            let co = lua_newthread(l);
+            let co1 = &mut *(co as *mut LuaState);
+            // __index yields the key and returns what the coroutine is
+            // resumed with
+            let t = LuaValue::Table(Rc::new(RefCell::new(crate::ltable::Table::new())));
+            let mt = Rc::new(RefCell::new(crate::ltable::Table::new()));
+            mt.borrow_mut().set(&LuaValue::Str("__index".to_string()), LuaValue::Function(Box::new(|L: &mut LuaState| unsafe {
+                lua_yield(L as *mut LuaState as *mut lua_State, 1)
+            })));
+            state.set_value_metatable(&t, LuaValue::Table(mt));
+            co1.push(LuaValue::Function(Box::new(|L: &mut LuaState| unsafe {
+                lua_getfield(L as *mut LuaState as *mut lua_State, 1, b"answer\0".as_ptr() as *const c_char);
+                1
+            })));
+            co1.push(t);
+            let mut nres = 0;
+            assert_eq!(lua_resume(co, l, 1, &mut nres), LUA_YIELD);
+            assert_eq!((nres, co1.pop()), (1, Some(LuaValue::Str("answer".to_string()))));
+            assert_eq!(lua_status(co), LUA_YIELD);
+            co1.push(LuaValue::Int(42));
+            assert_eq!(lua_resume(co, l, 1, &mut nres), LUA_OK);
+            assert_eq!((nres, co1.pop()), (1, Some(LuaValue::Int(42))));
+            assert_eq!((lua_status(co), lua_gettop(co)), (LUA_OK, 0));
+        }
+    }
+    #[test]
+    fn test_yield_errors() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        let yielder = || LuaValue::Function(Box::new(|L: &mut LuaState| unsafe { lua_yield(L as *mut LuaState as *mut lua_State, 0) }));
+        unsafe {
+            state.push(yielder());
+            assert_eq!(lua_pcallk(l, 0, 0, 0, 0, None), LUA_ERRRUN);
+            assert_eq!(state.pop(), Some(LuaValue::Str("attempt to yield from outside a coroutine".to_string())));
+            assert_eq!((lua_isyieldable(l), lua_pushthread(l)), (0, 1));
+            state.pop();
+
+            // a call without continuation cannot be yielded across
+            let co = lua_newthread(l);
+            let co1 = &mut *(co as *mut LuaState);
+            co1.push(LuaValue::Function(Box::new(move |L: &mut LuaState| unsafe {
+                let l = L as *mut LuaState as *mut lua_State;
+                assert_eq!(lua_isyieldable(l), 1);
+                L.push(yielder());
+                lua_callk(l, 0, 0, 0, None);
+                0
+            })));
+            let mut nres = 0;
+            assert_eq!(lua_resume(co, l, 0, &mut nres), LUA_ERRRUN);
+            assert_eq!(co1.pop(), Some(LuaValue::Str("attempt to yield across a C-call boundary".to_string())));
+            assert_eq!(lua_status(co), LUA_ERRRUN);
+            assert_eq!(lua_resume(co, l, 0, &mut nres), LUA_ERRRUN);
+            assert_eq!(co1.pop(), Some(LuaValue::Str("cannot resume dead coroutine".to_string())));
+            // nor can the main thread be resumed
+            assert_eq!(lua_resume(l, ptr::null_mut(), 0, &mut nres), LUA_ERRRUN);
+            assert_eq!(state.pop(), Some(LuaValue::Str("cannot resume non-suspended coroutine".to_string())));
+            // closing the dead coroutine reports how it died and leaves it
+            // ready for a new function
+            assert_eq!(lua_closethread(co, l), LUA_ERRRUN);
+            assert_eq!(lua_status(co), LUA_OK);
+        }
+    }
+    #[test]
+    fn test_yield_with_continuation() {
+        unsafe extern "C-unwind" fn finish(L: *mut lua_State, status: c_int, ctx: lua_KContext) -> c_int {
+            lua_pushinteger(L, ctx as lua_Integer * 10 + status as lua_Integer);
+            lua_gettop(L)
+        }
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            let co = lua_newthread(l);
+            let co1 = &mut *(co as *mut LuaState);
+            // a call with a continuation can be yielded across; the yielding
+            // function finishes through its own continuation
+            co1.push(LuaValue::Function(Box::new(|L: &mut LuaState| unsafe {
+                let l = L as *mut LuaState as *mut lua_State;
+                L.push(LuaValue::Function(Box::new(|L: &mut LuaState| unsafe {
+                    lua_yieldk(L as *mut LuaState as *mut lua_State, 0, 7, Some(finish))
+                })));
+                lua_callk(l, 0, LUA_MULTRET, 0, Some(finish));
+                lua_gettop(l)
+            })));
+            let mut nres = 0;
+            assert_eq!(lua_resume(co, l, 0, &mut nres), LUA_YIELD);
+            assert_eq!(nres, 0);
+            co1.push(LuaValue::Int(1));
+            assert_eq!(lua_resume(co, l, 1, &mut nres), LUA_OK);
+            assert_eq!(nres, 2);
+            assert_eq!(co1.pop(), Some(LuaValue::Int(7 * 10 + LUA_YIELD as i64)));
+            assert_eq!(co1.pop(), Some(LuaValue::Int(1)));
+        }
+    }
+    #[test]
+    fn test_pcall_through_the_c_api() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            // results replace the function and its arguments
+            state.push(LuaValue::Function(Box::new(|L: &mut LuaState| {
+                let Some(LuaValue::Int(n)) = L.pop() else { return 0 };
+                L.push(LuaValue::Int(n * 2));
+                1
+            })));
+            state.push(LuaValue::Int(21));
+            assert_eq!(lua_pcallk(l, 1, 2, 0, 0, None), LUA_OK);
+            assert_eq!(lua_gettop(l), 2);
+            assert_eq!(state.pop(), Some(LuaValue::Nil));
+            assert_eq!(state.pop(), Some(LuaValue::Int(42)));
+            // the error object comes back as it was raised
+            state.push(LuaValue::Function(Box::new(|L: &mut LuaState| {
+                L.push(LuaValue::Int(7));
+                lua_error(L as *mut LuaState as *mut lua_State)
+            })));
+            assert_eq!(lua_pcallk(l, 0, 0, 0, 0, None), LUA_ERRRUN);
+            assert_eq!(lua_gettop(l), 1);
+            assert_eq!(state.pop(), Some(LuaValue::Int(7)));
+            // the message handler at index 1 transforms it
+            state.push(LuaValue::Function(Box::new(|L: &mut LuaState| {
+                L.push(LuaValue::Str("handled".to_string()));
+                1
+            })));
+            state.push(LuaValue::Function(Box::new(|L: &mut LuaState| {
+                L.push(LuaValue::Int(7));
+                lua_error(L as *mut LuaState as *mut lua_State)
+            })));
+            assert_eq!(lua_pcallk(l, 0, 0, 1, 0, None), LUA_ERRRUN);
+            assert_eq!(state.pop(), Some(LuaValue::Str("handled".to_string())));
+            assert!(state.errfunc.is_none());
+            // an unprotected call runs in place
+            state.push(LuaValue::Function(Box::new(|L: &mut LuaState| {
+                L.push(LuaValue::Bool(true));
+                1
+            })));
+            lua_callk(l, 0, LUA_MULTRET, 0, None);
+            assert_eq!(state.pop(), Some(LuaValue::Bool(true)));
+            assert_eq!(lua_gettop(l), 1);
+        }
+    }
+    #[test]
+    fn test_push_convert_and_move_values() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            assert_eq!(lua_checkstack(l, 10), 1);
+            assert_eq!(lua_checkstack(l, crate::llimits::LUAI_MAXSTACK as c_int), 0);
+            lua_pushinteger(l, 10);
+            lua_pushnumber(l, 2.5);
+            let p = lua_pushstring(l, b"0x10\0".as_ptr() as *const c_char);
+            assert_eq!(CStr::from_ptr(p).to_str().unwrap(), "0x10");
+            lua_pushlstring(l, b"a\0b".as_ptr() as *const c_char, 3);
+            lua_pushboolean(l, 2);
+            lua_pushlightuserdata(l, 8 as *mut c_void);
+            assert!(lua_pushstring(l, ptr::null()).is_null());
+            assert_eq!(lua_type(l, 7), LUA_TNIL);
+            assert_eq!(CStr::from_ptr(lua_typename(l, lua_type(l, 6))).to_str().unwrap(), "userdata");
+            assert_eq!(CStr::from_ptr(lua_typename(l, LUA_TNONE)).to_str().unwrap(), "no value");
+
+            let mut isnum = 0;
+            assert_eq!((lua_isinteger(l, 1), lua_isinteger(l, 2)), (1, 0));
+            assert_eq!(lua_tointegerx(l, 3, &mut isnum), 16);
+            assert_eq!(isnum, 1);
+            assert_eq!(lua_tointegerx(l, 2, &mut isnum), 0);
+            assert_eq!(isnum, 0);
+            assert_eq!(lua_tonumberx(l, 1, ptr::null_mut()), 10.0);
+            lua_tonumberx(l, 4, &mut isnum);
+            assert_eq!(isnum, 0);
+            assert_eq!((lua_toboolean(l, 5), lua_toboolean(l, 7), lua_toboolean(l, 1)), (1, 0, 1));
+            assert_eq!(lua_topointer(l, 6), 8 as *const c_void);
+
+            // embedded zeros are kept; numbers are converted in place
+            let mut len = 0;
+            let s = lua_tolstring(l, 4, &mut len);
+            assert_eq!(std::slice::from_raw_parts(s as *const u8, len + 1), b"a\0b\0");
+            assert_eq!(CStr::from_ptr(lua_tolstring(l, 2, &mut len)).to_str().unwrap(), "2.5");
+            assert_eq!(lua_type(l, 2), LUA_TSTRING);
+            lua_pushnumber(l, 3.0);
+            assert_eq!(CStr::from_ptr(lua_tolstring(l, -1, ptr::null_mut())).to_str().unwrap(), "3.0");
+            assert!(lua_tolstring(l, 5, &mut len).is_null() && len == 0);
+            lua_settop(l, 3);
+
+            // 10 "2.5" "0x10" -> "0x10" 10 "2.5"
+            lua_rotate(l, 1, 1);
+            assert_eq!(state.stack[1..], [LuaValue::Str("0x10".to_string()), LuaValue::Int(10), LuaValue::Str("2.5".to_string())]);
+            lua_rotate(l, 1, -1);
+            assert_eq!(lua_tointegerx(l, 1, ptr::null_mut()), 10);
+            lua_copy(l, 1, -1);
+            assert_eq!(state.stack[3], LuaValue::Int(10));
+            lua_remove(l, 2);
+            assert_eq!(lua_gettop(l), 2);
+
+            let block = lua_newuserdatauv(l, 24, 1) as *mut u64;
+            assert_eq!(block as usize % 16, 0);
+            assert_eq!(*block.add(2), 0);
+            *block.add(2) = 7;
+            assert_eq!(lua_type(l, -1), LUA_TUSERDATA);
+            assert_eq!(lua_topointer(l, -1), block as *const c_void);
+        }
+    }
+
+    unsafe extern "C-unwind" fn counter(L: *mut lua_State) -> c_int {
+        let n = lua_tointegerx(L, lua_upvalueindex(1), ptr::null_mut()) + 1;
+        lua_pushinteger(L, n);
+        lua_copy(L, -1, lua_upvalueindex(1));
+        1
+    }
+
+    #[test]
+    fn test_c_closures_keep_their_upvalues() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            lua_pushinteger(l, 0);
+            lua_pushcclosure(l, counter, 1);
+            assert_eq!(lua_gettop(l), 1);
+            assert_eq!(lua_tocfunction(l, 1).map(|f| f as usize), Some(counter as usize));
+            for expected in 1..=3 {
+                lua_pushvalue(l, 1);
+                lua_callk(l, 0, 1, 0, None);
+                assert_eq!(state.pop(), Some(LuaValue::Int(expected)));
+            }
+            // the debug API sees the same slot
+            let name = lua_getupvalue(l, 1, 1);
+            assert_eq!(CStr::from_ptr(name).to_str().unwrap(), "");
+            assert_eq!(state.pop(), Some(LuaValue::Int(3)));
+            assert!(lua_getupvalue(l, 1, 2).is_null());
+            // Rust functions are not C functions
+            state.push(LuaValue::Function(Box::new(|_: &mut LuaState| 0)));
+            assert!(lua_tocfunction(l, -1).is_none());
+        }
+    }
+
+    #[test]
+    fn test_fields_globals_and_loadstring() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut lua_State;
+        unsafe {
+            lua_newtable(l);
+            lua_pushinteger(l, 5);
+            lua_setfield(l, 1, b"x\0".as_ptr() as *const c_char);
+            assert_eq!(lua_getfield(l, 1, b"x\0".as_ptr() as *const c_char), LUA_TNUMBER);
+            assert_eq!(state.pop(), Some(LuaValue::Int(5)));
+            assert_eq!(lua_getfield(l, 1, b"y\0".as_ptr() as *const c_char), LUA_TNIL);
+            lua_pop(l, 1);
+            lua_setglobal(l, b"t\0".as_ptr() as *const c_char);
+            assert_eq!(lua_gettop(l), 0);
+            assert_eq!(lua_getglobal(l, b"t\0".as_ptr() as *const c_char), LUA_TTABLE);
+            lua_pop(l, 1);
+
+            assert_eq!(luaL_loadstring(l, b"return 1 +\0".as_ptr() as *const c_char), LUA_ERRSYNTAX);
+            assert!(matches!(state.pop(), Some(LuaValue::Str(m)) if m.contains("return 1 +")));
+            assert_eq!(luaL_loadstring(l, b"return t.x * 2\0".as_ptr() as *const c_char), LUA_OK);
+            lua_callk(l, 0, 1, 0, None);
+            assert_eq!(state.pop(), Some(LuaValue::Int(10)));
+        }
+    }
 }
-
-
-#[link(name = "dapi")]
-extern "C" {
-    pub fn lua_gettop(L: *mut std::ffi::c_void) -> i32;
-}
\ No newline at end of file
@@ -0,0 +1,367 @@
+//! lasync.rs - Await bridge between Lua coroutines and Rust futures
+//
+// A Rust function registered with `register_async` returns a Future. When Lua
+// calls it, the C trampoline stores the future, and yields the running
+// coroutine (lua_yieldk) with an await request: the marker light userdata
+// followed by the request id. `AsyncLua` drives the coroutine from an async
+// context: on an await request it polls the stored future on the host
+// executor, then resumes the coroutine with `true, results...` or
+// `false, message`; the continuation returns the results to the Lua caller
+// or raises the message as a Lua error.
+//
+// From the safe API, LuaState::register_async registers the function and
+// CThread::new creates the coroutine for the driver.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::os::raw::{c_char, c_int, c_void};
+use std::pin::Pin;
+use std::rc::Rc;
+
+use crate::lapi::*;
+use crate::lobject::LuaValue;
+use crate::lstate::{LuaState, LuaThread};
+
+/// Result of an async Rust function: the values returned to Lua, or an error message
+pub type LuaFuture = Pin<Box<dyn Future<Output = Result<Vec<LuaValue>, String>>>>;
+
+/// An async Rust function callable from Lua
+pub type AsyncFn = Box<dyn Fn(Vec<LuaValue>) -> LuaFuture>;
+
+/// Marker whose address tags await requests yielded to the driver
+static AWAIT_MARKER: u8 = 0;
+
+fn await_marker() -> *const () {
+    &AWAIT_MARKER as *const u8 as *const ()
+}
+
+thread_local! {
+    static ASYNC_FNS: RefCell<Vec<AsyncFn>> = RefCell::new(Vec::new());
+    static PENDING: RefCell<HashMap<u64, LuaFuture>> = RefCell::new(HashMap::new());
+    static NEXT_REQUEST: RefCell<u64> = const { RefCell::new(1) };
+}
+
+/// Store a future and return the request id that the coroutine yields
+pub fn submit_future(fut: LuaFuture) -> u64 {
+    let id = NEXT_REQUEST.with(|n| {
+        let mut n = n.borrow_mut();
+        let id = *n;
+        *n += 1;
+        id
+    });
+    PENDING.with(|p| p.borrow_mut().insert(id, fut));
+    id
+}
+
+/// The values a coroutine yields to await request `id`
+pub fn await_request(id: u64) -> Vec<LuaValue> {
+    vec![LuaValue::Pointer(await_marker()), LuaValue::Int(id as i64)]
+}
+
+/// If `yielded` is an await request, its id
+pub fn as_await_request(yielded: &[LuaValue]) -> Option<u64> {
+    match yielded {
+        [LuaValue::Pointer(p), LuaValue::Int(id)] if *p == await_marker() => Some(*id as u64),
+        _ => None,
+    }
+}
+
+// --- Stack <-> LuaValue conversion for the C side of the bridge ---
+
+/// Push a plain value (nil, boolean, number, string, light userdata)
+pub unsafe fn push_value(L: *mut lua_State, v: &LuaValue) {
+    match v {
+        LuaValue::Nil => lua_pushnil(L),
+        LuaValue::Bool(b) => lua_pushboolean(L, *b as c_int),
+        LuaValue::Int(i) => lua_pushinteger(L, *i),
+        LuaValue::Float(n) => lua_pushnumber(L, *n),
+        LuaValue::Str(s) => { lua_pushlstring(L, s.as_ptr() as *const c_char, s.len()); }
+        LuaValue::Pointer(p) => lua_pushlightuserdata(L, *p as *mut c_void),
+        _ => lua_pushnil(L), // tables/functions do not cross the bridge
+    }
+}
+
+/// Read the value at `idx` (tables/functions come back as nil)
+pub unsafe fn to_value(L: *mut lua_State, idx: c_int) -> LuaValue {
+    match lua_type(L, idx) {
+        LUA_TBOOLEAN => LuaValue::Bool(lua_toboolean(L, idx) != 0),
+        LUA_TNUMBER if lua_isinteger(L, idx) != 0 => LuaValue::Int(lua_tointegerx(L, idx, std::ptr::null_mut())),
+        LUA_TNUMBER => LuaValue::Float(lua_tonumberx(L, idx, std::ptr::null_mut())),
+        LUA_TSTRING => {
+            let mut len = 0;
+            let s = lua_tolstring(L, idx, &mut len);
+            let bytes = std::slice::from_raw_parts(s as *const u8, len);
+            LuaValue::Str(String::from_utf8_lossy(bytes).into_owned())
+        }
+        LUA_TLIGHTUSERDATA => LuaValue::Pointer(lua_topointer(L, idx) as *const ()),
+        _ => LuaValue::Nil,
+    }
+}
+
+// Continuation after the driver resumed us: stack holds `ok, results...`
+unsafe extern "C-unwind" fn async_continue(L: *mut lua_State, _status: c_int, _ctx: lua_KContext) -> c_int {
+    if lua_toboolean(L, 1) == 0 {
+        lua_settop(L, 2);
+        lua_error(L);
+    }
+    lua_gettop(L) - 1
+}
+
+// C closure behind every registered async function; upvalue 1 is its index
+unsafe extern "C-unwind" fn async_trampoline(L: *mut lua_State) -> c_int {
+    let idx = lua_tointegerx(L, lua_upvalueindex(1), std::ptr::null_mut()) as usize;
+    let args: Vec<LuaValue> = (1..=lua_gettop(L)).map(|i| to_value(L, i)).collect();
+    let fut = ASYNC_FNS.with(|f| (f.borrow()[idx])(args));
+    let id = submit_future(fut);
+    lua_settop(L, 0);
+    for v in await_request(id) {
+        push_value(L, &v);
+    }
+    lua_yieldk(L, 2, id as lua_KContext, Some(async_continue))
+}
+
+/// Register `f` as global `name`; calling it from a coroutine driven by
+/// `AsyncLua` suspends the coroutine until the future completes
+pub unsafe fn register_async(L: *mut lua_State, name: &str, f: AsyncFn) {
+    let idx = ASYNC_FNS.with(|fns| {
+        let mut fns = fns.borrow_mut();
+        fns.push(f);
+        fns.len() - 1
+    });
+    lua_pushinteger(L, idx as lua_Integer);
+    lua_pushcclosure(L, async_trampoline, 1);
+    let cname = std::ffi::CString::new(name).unwrap();
+    lua_setglobal(L, cname.as_ptr());
+}
+
+impl LuaState {
+    /// Register `f` as global `name` of this state (register_async)
+    pub fn register_async(&mut self, name: &str, f: AsyncFn) {
+        unsafe { register_async(self as *mut LuaState as *mut lua_State, name, f) }
+    }
+}
+
+/// One step of a resumable Lua thread
+#[derive(Debug)]
+pub enum ThreadStep {
+    Yielded(Vec<LuaValue>),
+    Finished(Vec<LuaValue>),
+}
+
+/// A coroutine the driver can resume
+pub trait ResumableThread {
+    fn resume(&mut self, args: Vec<LuaValue>) -> Result<ThreadStep, String>;
+}
+
+/// A coroutine of the C API (`co` holds the function to run on first resume)
+pub struct CThread {
+    pub co: *mut lua_State,
+    pub from: *mut lua_State,
+    // keeps `co` alive when CThread::new created it
+    _thread: Option<Rc<LuaThread>>,
+}
+
+impl CThread {
+    /// A new coroutine of `state` that runs `f` on first resume. `state`
+    /// must outlive it: it is the resumer of every step.
+    pub fn new(state: &mut LuaState, f: LuaValue) -> Self {
+        let from = state as *mut LuaState as *mut lua_State;
+        unsafe {
+            let co = lua_newthread(from);
+            let Some(LuaValue::Thread(thread)) = state.pop() else { unreachable!() };
+            (*thread.state()).push(f);
+            CThread { co, from, _thread: Some(thread) }
+        }
+    }
+
+    /// Drive the coroutine `co` of the C API, which `from` resumes; the
+    /// caller keeps `co` alive
+    pub fn from_raw(co: *mut lua_State, from: *mut lua_State) -> Self {
+        CThread { co, from, _thread: None }
+    }
+}
+
+impl ResumableThread for CThread {
+    fn resume(&mut self, args: Vec<LuaValue>) -> Result<ThreadStep, String> {
+        unsafe {
+            for v in &args {
+                push_value(self.co, v);
+            }
+            let mut nres = 0;
+            let status = lua_resume(self.co, self.from, args.len() as c_int, &mut nres);
+            // only the values handed over are ours: below them is the
+            // stack of the suspended calls
+            let n = lua_gettop(self.co);
+            let vals: Vec<LuaValue> = (n - nres + 1..=n).map(|i| to_value(self.co, i)).collect();
+            lua_settop(self.co, -nres - 1);
+            match status {
+                LUA_OK => Ok(ThreadStep::Finished(vals)),
+                LUA_YIELD => Ok(ThreadStep::Yielded(vals)),
+                _ => Err(match vals.last() {
+                    Some(LuaValue::Str(s)) => s.clone(),
+                    _ => "error in coroutine".to_string(),
+                }),
+            }
+        }
+    }
+}
+
+/// Drives a coroutine to completion, awaiting the futures it requests
+pub struct AsyncLua<T: ResumableThread> {
+    thread: T,
+}
+
+impl<T: ResumableThread> AsyncLua<T> {
+    pub fn new(thread: T) -> Self {
+        AsyncLua { thread }
+    }
+
+    /// Run the coroutine with `args` until it returns. A plain
+    /// coroutine.yield outside any await is an error here, as there is no
+    /// Lua resumer to receive the values.
+    pub async fn run(mut self, args: Vec<LuaValue>) -> Result<Vec<LuaValue>, String> {
+        let mut args = args;
+        loop {
+            match self.thread.resume(args)? {
+                ThreadStep::Finished(results) => return Ok(results),
+                ThreadStep::Yielded(vals) => {
+                    let Some(id) = as_await_request(&vals) else {
+                        return Err("attempt to yield across the async driver".to_string());
+                    };
+                    let fut = PENDING.with(|p| p.borrow_mut().remove(&id))
+                        .ok_or_else(|| format!("unknown await request {}", id))?;
+                    args = match fut.await {
+                        Ok(mut results) => {
+                            results.insert(0, LuaValue::Bool(true));
+                            results
+                        }
+                        Err(msg) => vec![LuaValue::Bool(false), LuaValue::Str(msg)],
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    // Minimal executor: poll until ready (the test futures never park)
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        fn noop_raw() -> RawWaker {
+            fn clone(_: *const ()) -> RawWaker { noop_raw() }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        let waker = unsafe { Waker::from_raw(noop_raw()) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+        loop {
+            if let Poll::Ready(v) = fut.as_mut().poll(&mut cx) {
+                return v;
+            }
+        }
+    }
+
+    /// Yields once after `pending` polls, then completes
+    struct Delayed(u32, Result<Vec<LuaValue>, String>);
+
+    impl Future for Delayed {
+        type Output = Result<Vec<LuaValue>, String>;
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            if self.0 == 0 {
+                Poll::Ready(self.1.clone())
+            } else {
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Scripted coroutine: `x = fetch(); return x + 1`
+    struct Script {
+        step: u32,
+        result: Result<Vec<LuaValue>, String>,
+    }
+
+    impl ResumableThread for Script {
+        fn resume(&mut self, args: Vec<LuaValue>) -> Result<ThreadStep, String> {
+            self.step += 1;
+            match self.step {
+                1 => Ok(ThreadStep::Yielded(await_request(submit_future(Box::pin(Delayed(2, self.result.clone())))))),
+                _ => match args.as_slice() {
+                    [LuaValue::Bool(true), LuaValue::Int(x)] => Ok(ThreadStep::Finished(vec![LuaValue::Int(x + 1)])),
+                    [LuaValue::Bool(false), LuaValue::Str(e)] => Err(e.clone()),
+                    _ => Err("unexpected resume values".to_string()),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_await_resumes_with_future_result() {
+        let script = Script { step: 0, result: Ok(vec![LuaValue::Int(41)]) };
+        let out = block_on(AsyncLua::new(script).run(vec![]));
+        assert!(matches!(out.as_deref(), Ok([LuaValue::Int(42)])));
+    }
+
+    #[test]
+    fn test_await_error_is_raised_in_coroutine() {
+        let script = Script { step: 0, result: Err("timeout".to_string()) };
+        assert_eq!(block_on(AsyncLua::new(script).run(vec![])).err().as_deref(), Some("timeout"));
+    }
+
+    /// A state with global `fetch`, an async function whose future
+    /// completes with its arguments or `err` after two polls
+    fn fetch_state(err: Option<&'static str>) -> LuaState {
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        state.register_async("fetch", Box::new(move |args: Vec<LuaValue>| -> LuaFuture {
+            Box::pin(Delayed(2, err.map_or(Ok(args), |e| Err(e.to_string()))))
+        }));
+        state
+    }
+
+    /// `x = fetch(41); return x + 1` on a real coroutine
+    fn fetch_plus_one() -> LuaValue {
+        LuaValue::Function(Box::new(|L: &mut LuaState| {
+            let fetch = L.get_global("fetch").unwrap();
+            L.push(fetch);
+            L.push(LuaValue::Int(41));
+            L.call(1, 1);
+            let Some(LuaValue::Int(x)) = L.pop() else { panic!("fetch returned no integer") };
+            L.push(LuaValue::Int(x + 1));
+            1
+        }))
+    }
+
+    #[test]
+    fn test_await_in_a_real_coroutine() {
+        let mut state = fetch_state(None);
+        let co = CThread::new(&mut state, fetch_plus_one());
+        let out = block_on(AsyncLua::new(co).run(vec![]));
+        assert!(matches!(out.as_deref(), Ok([LuaValue::Int(42)])));
+        assert_eq!(state.stack.len(), 1);
+
+        let mut state = fetch_state(Some("timeout"));
+        let co = CThread::new(&mut state, fetch_plus_one());
+        assert_eq!(block_on(AsyncLua::new(co).run(vec![])).err().as_deref(), Some("timeout"));
+    }
+
+    #[test]
+    fn test_plain_yield_is_rejected() {
+        struct Yielder;
+        impl ResumableThread for Yielder {
+            fn resume(&mut self, _: Vec<LuaValue>) -> Result<ThreadStep, String> {
+                Ok(ThreadStep::Yielded(vec![LuaValue::Int(1)]))
+            }
+        }
+        assert!(block_on(AsyncLua::new(Yielder).run(vec![])).is_err());
+        assert_eq!(as_await_request(&await_request(7)), Some(7));
+    }
+}
@@ -6,17 +6,18 @@ use std::ptr;
 use std::mem;
 use std::slice;
 use std::time::SystemTime;
-use std::fs::File;
 use std::io::{self, Read, BufReader};
 use std::collections::HashMap;
 
 // --- Type aliases and constants ---
 
 pub type lua_State = c_void;
-pub type lua_CFunction = unsafe extern "C" fn(*mut lua_State) -> c_int;
-pub type lua_Integer = isize;
-pub type lua_Unsigned = usize;
-pub type lua_Number = f64;
+// Functions registered through luaL_Reg run on the API state and may raise
+// Lua errors, so they unwind
+pub type lua_CFunction = crate::lapi::lua_CFunction;
+pub type lua_Integer = crate::skylaconf::LuaInteger;
+pub type lua_Unsigned = crate::skylaconf::LuaUnsigned;
+pub type lua_Number = crate::skylaconf::LuaFloat;
 pub type size_t = usize;
 
 pub const LUA_GNAME: &str = "_G";
@@ -37,6 +38,9 @@ pub struct luaL_Reg {
     pub func: Option<lua_CFunction>,
 }
 
+// Registration arrays are plain static data
+unsafe impl Sync for luaL_Reg {}
+
 #[repr(C)]
 pub struct luaL_Buffer {
     pub b: *mut c_char,
@@ -54,12 +58,57 @@ pub union luaL_BufferInit {
 
 pub const LUAL_BUFFERSIZE: usize = 8192; // adjust as needed
 
+/// C's FILE, only ever handled through pointers
+pub type FILE = c_void;
+
+/// What a LUA_FILEHANDLE value holds for C code: `closef` is null once
+/// the file is closed
 #[repr(C)]
 pub struct luaL_Stream {
-    pub f: *mut File,
+    pub f: *mut FILE,
     pub closef: Option<lua_CFunction>,
 }
 
+pub const LUA_IDSIZE: usize = 60;
+
+/// Activation record filled by lua_getstack/lua_getinfo (lua.h layout)
+#[repr(C)]
+pub struct lua_Debug {
+    pub event: c_int,
+    pub name: *const c_char,
+    pub namewhat: *const c_char,
+    pub what: *const c_char,
+    pub source: *const c_char,
+    pub srclen: size_t,
+    pub currentline: c_int,
+    pub linedefined: c_int,
+    pub lastlinedefined: c_int,
+    pub nups: u8,
+    pub nparams: u8,
+    pub isvararg: c_char,
+    pub istailcall: c_char,
+    pub ftransfer: u16,
+    pub ntransfer: u16,
+    pub short_src: [c_char; LUA_IDSIZE],
+    pub(crate) i_ci: *mut c_void,
+}
+
+impl lua_Debug {
+    pub fn new() -> Self {
+        // SAFETY: all-zero is a valid lua_Debug (null pointers, zero counts)
+        unsafe { mem::zeroed() }
+    }
+}
+
+impl Default for lua_Debug {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The debug interface is implemented in ldebug
+pub use crate::ldebug::{lua_getinfo, lua_getlocal, lua_getstack};
+
 // --- Function stubs (to be implemented) ---
 
 extern "C" {
@@ -88,53 +137,38 @@ extern "C" {
     pub fn lua_isnoneornil(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_istable(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_toboolean(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_isinteger(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_checkstack(L: *mut lua_State, n: c_int) -> c_int;
     pub fn lua_topointer(L: *mut lua_State, idx: c_int) -> *const c_void;
+    pub fn lua_touserdata(L: *mut lua_State, idx: c_int) -> *mut c_void;
+    pub fn lua_rawequal(L: *mut lua_State, idx1: c_int, idx2: c_int) -> c_int;
     pub fn lua_getfield(L: *mut lua_State, idx: c_int, k: *const c_char) -> c_int;
+    pub fn lua_setglobal(L: *mut lua_State, name: *const c_char);
     pub fn lua_setfield(L: *mut lua_State, idx: c_int, k: *const c_char);
     pub fn lua_getmetatable(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_setmetatable(L: *mut lua_State, idx: c_int) -> c_int;
     pub fn lua_createtable(L: *mut lua_State, narr: c_int, nrec: c_int);
     pub fn lua_newuserdatauv(L: *mut lua_State, sz: size_t, nuvalue: c_int) -> *mut c_void;
     pub fn lua_rawget(L: *mut lua_State, idx: c_int) -> c_int;
+    pub fn lua_rawset(L: *mut lua_State, idx: c_int);
     pub fn lua_rawgeti(L: *mut lua_State, idx: c_int, n: lua_Integer) -> c_int;
     pub fn lua_rawseti(L: *mut lua_State, idx: c_int, n: lua_Integer);
     pub fn lua_rawlen(L: *mut lua_State, idx: c_int) -> size_t;
+    pub fn lua_len(L: *mut lua_State, idx: c_int);
     pub fn lua_remove(L: *mut lua_State, idx: c_int);
     pub fn lua_pop(L: *mut lua_State, n: c_int);
     pub fn lua_concat(L: *mut lua_State, n: c_int);
+    pub fn lua_toclose(L: *mut lua_State, idx: c_int);
+    pub fn lua_closeslot(L: *mut lua_State, idx: c_int);
     pub fn lua_call(L: *mut lua_State, nargs: c_int, nresults: c_int);
-    pub fn lua_error(L: *mut lua_State) -> c_int;
     pub fn luaL_error(L: *mut lua_State, fmt: *const c_char, ...) -> c_int;
-    pub fn luaL_checkstack(L: *mut lua_State, sz: c_int, msg: *const c_char);
-    pub fn luaL_checktype(L: *mut lua_State, arg: c_int, t: c_int);
-    pub fn luaL_checkany(L: *mut lua_State, arg: c_int);
-    pub fn luaL_checklstring(L: *mut lua_State, arg: c_int, l: *mut size_t) -> *const c_char;
-    pub fn luaL_optlstring(L: *mut lua_State, arg: c_int, def: *const c_char, l: *mut size_t) -> *const c_char;
-    pub fn luaL_checknumber(L: *mut lua_State, arg: c_int) -> lua_Number;
-    pub fn luaL_optnumber(L: *mut lua_State, arg: c_int, def: lua_Number) -> lua_Number;
-    pub fn luaL_checkinteger(L: *mut lua_State, arg: c_int) -> lua_Integer;
-    pub fn luaL_optinteger(L: *mut lua_State, arg: c_int, def: lua_Integer) -> lua_Integer;
-    pub fn luaL_newmetatable(L: *mut lua_State, tname: *const c_char) -> c_int;
-    pub fn luaL_setmetatable(L: *mut lua_State, tname: *const c_char);
-    pub fn luaL_testudata(L: *mut lua_State, ud: c_int, tname: *const c_char) -> *mut c_void;
-    pub fn luaL_checkudata(L: *mut lua_State, ud: c_int, tname: *const c_char) -> *mut c_void;
-    pub fn luaL_where(L: *mut lua_State, lvl: c_int);
-    pub fn luaL_fileresult(L: *mut lua_State, stat: c_int, fname: *const c_char) -> c_int;
-    pub fn luaL_execresult(L: *mut lua_State, stat: c_int) -> c_int;
     pub fn luaL_ref(L: *mut lua_State, t: c_int) -> c_int;
     pub fn luaL_unref(L: *mut lua_State, t: c_int, r: c_int);
     pub fn luaL_loadfilex(L: *mut lua_State, filename: *const c_char, mode: *const c_char) -> c_int;
     pub fn luaL_loadbufferx(L: *mut lua_State, buff: *const c_char, sz: size_t, name: *const c_char, mode: *const c_char) -> c_int;
     pub fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int;
     pub fn luaL_newstate() -> *mut lua_State;
-    pub fn luaL_makeseed(L: *mut lua_State) -> u32;
     pub fn luaL_len(L: *mut lua_State, idx: c_int) -> lua_Integer;
-    pub fn luaL_addgsub(b: *mut luaL_Buffer, s: *const c_char, p: *const c_char, r: *const c_char);
-    pub fn luaL_gsub(L: *mut lua_State, s: *const c_char, p: *const c_char, r: *const c_char) -> *const c_char;
-    pub fn luaL_setfuncs(L: *mut lua_State, l: *const luaL_Reg, nup: c_int);
-    pub fn luaL_getsubtable(L: *mut lua_State, idx: c_int, fname: *const c_char) -> c_int;
-    pub fn luaL_traceback(L: *mut lua_State, L1: *mut lua_State, msg: *const c_char, level: c_int);
-    pub fn luaL_requiref(L: *mut lua_State, modname: *const c_char, openf: lua_CFunction, glb: c_int);
     pub fn luaL_buffinit(L: *mut lua_State, B: *mut luaL_Buffer);
     pub fn luaL_prepbuffsize(B: *mut luaL_Buffer, sz: size_t) -> *mut c_char;
     pub fn luaL_addlstring(B: *mut luaL_Buffer, s: *const c_char, l: size_t);
@@ -172,6 +206,21 @@ pub fn luaL_argexpected(L: *mut lua_State, cond: bool, arg: c_int, tname: &str)
     }
 }
 
+/// Create a table sized for the functions in `l` (which ends with the
+/// {NULL, NULL} sentinel, as in C)
+#[inline]
+pub unsafe fn luaL_newlibtable(L: *mut lua_State, l: &[luaL_Reg]) {
+    lua_createtable(L, 0, l.len().saturating_sub(1) as c_int);
+}
+
+/// Create a table and register the functions in `l` into it
+#[inline]
+pub unsafe fn luaL_newlib(L: *mut lua_State, l: &[luaL_Reg]) {
+    luaL_checkversion(L);
+    luaL_newlibtable(L, l);
+    luaL_setfuncs(L, l.as_ptr(), 0);
+}
+
 // ...more macro helpers as needed...
 
 // --- Buffer helpers ---
@@ -188,28 +237,1164 @@ pub fn luaL_buffaddr(bf: &luaL_Buffer) -> *mut c_char {
 
 // ...implement more helpers as needed...
 
-// --- Main function implementations go here ---
-// (Translate each C function to Rust, using the above types and helpers.)
+// --- Plain substitution ---
+
+/// Replace every occurrence of `p` in `s` with `r`, scanning left to right;
+/// text coming from `r` is never searched again. An empty `p` matches
+/// nothing (the C version would loop forever).
+pub fn gsub(s: &str, p: &str, r: &str) -> String {
+    if p.is_empty() {
+        return s.to_string();
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(p) {
+        out.push_str(&rest[..i]);
+        out.push_str(r);
+        rest = &rest[i + p.len()..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Add `s` to the buffer with every occurrence of `p` replaced by `r`
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_addgsub(b: *mut luaL_Buffer, s: *const c_char, p: *const c_char, r: *const c_char) {
+    let pat = CStr::from_ptr(p).to_bytes();
+    let mut s = s;
+    if !pat.is_empty() {
+        while let Some(i) = CStr::from_ptr(s).to_bytes().windows(pat.len()).position(|w| w == pat) {
+            luaL_addlstring(b, s, i);
+            luaL_addstring(b, r);
+            s = s.add(i + pat.len());
+        }
+    }
+    luaL_addstring(b, s);
+}
+
+/// Push `s` with every occurrence of `p` replaced by `r` and return it
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_gsub(L: *mut lua_State, s: *const c_char, p: *const c_char, r: *const c_char) -> *const c_char {
+    let mut b: luaL_Buffer = mem::zeroed();
+    luaL_buffinit(L, &mut b);
+    luaL_addgsub(&mut b, s, p, r);
+    luaL_pushresult(&mut b);
+    lua_tostring(L, -1)
+}
+
+// --- Error results ---
+//
+// luaL_fileresult/luaL_execresult: the (true) or (nil, message, code)
+// results of io and os functions. Codes are errno values; errors that carry
+// no OS code (a Vfs, a closed pipe, ...) are mapped from their io::ErrorKind
+// so scripts see the same numbers on every platform.
+
+/// How a command ended (os.execute, closing an io.popen file)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecStatus {
+    Exit(i32),
+    Signal(i32),
+}
+
+impl ExecStatus {
+    #[cfg(not(feature = "minimal"))]
+    pub fn from_status(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if let Some(sig) = status.signal() {
+                return ExecStatus::Signal(sig);
+            }
+        }
+        ExecStatus::Exit(status.code().unwrap_or(-1))
+    }
+
+    /// Decode a status as returned by system() (l_inspectstat)
+    pub fn from_wait(stat: c_int) -> Self {
+        if cfg!(unix) {
+            if stat & 0x7f == 0 {
+                ExecStatus::Exit((stat >> 8) & 0xff)
+            } else {
+                ExecStatus::Signal(stat & 0x7f)
+            }
+        } else {
+            ExecStatus::Exit(stat)
+        }
+    }
+
+    pub fn success(self) -> bool {
+        self == ExecStatus::Exit(0)
+    }
+}
+
+/// The errno value for `e`
+pub fn io_errno(e: &io::Error) -> i32 {
+    #[cfg(unix)]
+    {
+        if let Some(code) = e.raw_os_error() {
+            return code;
+        }
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => 2,          // ENOENT
+        io::ErrorKind::Interrupted => 4,       // EINTR
+        io::ErrorKind::WouldBlock => 11,       // EAGAIN
+        io::ErrorKind::OutOfMemory => 12,      // ENOMEM
+        io::ErrorKind::PermissionDenied => 13, // EACCES
+        io::ErrorKind::AlreadyExists => 17,    // EEXIST
+        io::ErrorKind::InvalidInput => 22,     // EINVAL
+        io::ErrorKind::BrokenPipe => 32,       // EPIPE
+        io::ErrorKind::Unsupported => 38,      // ENOSYS
+        _ => 5,                                // EIO
+    }
+}
+
+/// The message for `e` as strerror gives it (without " (os error N)")
+pub fn io_strerror(e: &io::Error) -> String {
+    let msg = e.to_string();
+    match msg.rfind(" (os error ") {
+        Some(i) if msg.ends_with(')') => msg[..i].to_string(),
+        _ => msg,
+    }
+}
+
+/// Push `true` if `stat` is nonzero, else nil, "fname: message" (just the
+/// message without `fname`) and errno; errno is read on entry.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_fileresult(L: *mut lua_State, stat: c_int, fname: *const c_char) -> c_int {
+    let e = io::Error::last_os_error();
+    if stat != 0 {
+        lua_pushboolean(L, 1);
+        return 1;
+    }
+    lua_pushnil(L);
+    let msg = CString::new(io_strerror(&e)).unwrap_or_default();
+    if fname.is_null() {
+        lua_pushstring(L, msg.as_ptr());
+    } else {
+        lua_pushfstring(L, b"%s: %s\0".as_ptr() as *const c_char, fname, msg.as_ptr());
+    }
+    lua_pushinteger(L, io_errno(&e) as lua_Integer);
+    3
+}
+
+/// Push the results of os.execute for the system() status `stat`: true or
+/// nil, then "exit" or "signal" and the code. A failed call with errno set
+/// is reported as by luaL_fileresult.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_execresult(L: *mut lua_State, stat: c_int) -> c_int {
+    if stat != 0 && io::Error::last_os_error().raw_os_error().unwrap_or(0) != 0 {
+        return luaL_fileresult(L, 0, ptr::null());
+    }
+    let status = ExecStatus::from_wait(stat);
+    let (what, code): (&[u8], c_int) = match status {
+        ExecStatus::Exit(c) => (b"exit\0", c),
+        ExecStatus::Signal(s) => (b"signal\0", s),
+    };
+    if status.success() { lua_pushboolean(L, 1) } else { lua_pushnil(L) }
+    lua_pushstring(L, what.as_ptr() as *const c_char);
+    lua_pushinteger(L, code as lua_Integer);
+    3
+}
+
+// --- Argument checking ---
+//
+// Native versions of the luaL_check*/luaL_opt* family. Errors are raised
+// with the messages of the reference implementation, prefixed by luaL_where:
+//   "file.lua:3: bad argument #1 to 'sub' (number expected, got nil)"
+
+use crate::lapi::{LUA_REGISTRYINDEX, LUA_TLIGHTUSERDATA, LUA_TNIL, LUA_TNONE, LUA_TNUMBER, LUA_TSTRING, LUA_TTABLE, LUA_VERSION_NUM};
+
+/// "bad argument #n to 'fname' (extramsg)". For methods (namewhat "method")
+/// the self argument is not counted, and a bad self is reported as such.
+pub fn argerror_message(arg: c_int, fname: Option<&str>, namewhat: &str, extramsg: &str) -> String {
+    let fname = fname.unwrap_or("?");
+    let mut arg = arg;
+    if namewhat == "method" {
+        arg -= 1;
+        if arg == 0 {
+            return format!("calling '{}' on bad self ({})", fname, extramsg);
+        }
+    }
+    format!("bad argument #{} to '{}' ({})", arg, fname, extramsg)
+}
+
+/// "<expected> expected, got <actual>"
+pub fn typeerror_message(expected: &str, actual: &str) -> String {
+    format!("{} expected, got {}", expected, actual)
+}
+
+/// Index of `name` in `lst` (luaL_checkoption's lookup)
+pub fn find_option(name: &str, lst: &[&str]) -> Option<usize> {
+    lst.iter().position(|o| *o == name)
+}
+
+unsafe fn cstr_opt<'a>(p: *const c_char) -> Option<&'a str> {
+    if p.is_null() { None } else { CStr::from_ptr(p).to_str().ok() }
+}
+
+/// Push msg prefixed with the current position and raise it
+unsafe fn aux_raise(L: *mut lua_State, msg: &str) -> ! {
+    luaL_errorat(L, 1, msg)
+}
+
+/// Raise `msg` as a runtime error, prefixed with the position of the
+/// function at `level` as luaL_where gives it ("file.lua:3: msg"). The
+/// string is the error object seen by the enclosing protected call.
+pub unsafe fn luaL_errorat(L: *mut lua_State, level: c_int, msg: &str) -> ! {
+    luaL_where(L, level);
+    let L1 = &mut *(L as *mut LuaState);
+    let mut err = match L1.pop() {
+        Some(LuaValue::Str(pos)) => pos,
+        _ => String::new(),
+    };
+    err.push_str(msg);
+    L1.push(LuaValue::Str(err));
+    crate::lapi::lua_error(L.cast())
+}
+
+/// Why a library built for core version `ver` with numeric signature `sz`
+/// (its LUAL_NUMSIZES) cannot run on a core of version `core`, if so
+fn checkversion_message(ver: lua_Number, sz: size_t, core: lua_Number) -> Option<String> {
+    if sz != LUAL_NUMSIZES {
+        Some("core and library have incompatible numeric types".to_string())
+    } else if ver != core {
+        Some(format!("version mismatch: app. needs {}, Lua core provides {}", ver, core))
+    } else {
+        None
+    }
+}
+
+/// Raise an error unless the running core has version `ver` and the
+/// integer and float sizes `sz` the caller was compiled with, so a module
+/// built against another core fails on load instead of corrupting the
+/// state (luaL_checkversion passes the caller's own constants)
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkversion_(L: *mut lua_State, ver: lua_Number, sz: size_t) {
+    if let Some(msg) = checkversion_message(ver, sz, crate::lapi::lua_version(L.cast())) {
+        luaL_errorat(L, 1, &msg);
+    }
+}
+
+/// luaL_error with Rust format arguments:
+/// `luaL_error!(L, "invalid size {}", n)` raises "file.lua:3: invalid size 7"
+/// for the Lua function that called the running one.
+#[macro_export]
+macro_rules! luaL_error {
+    ($L:expr, $($arg:tt)*) => {
+        $crate::lauxlib::luaL_errorat($L, 1, &format!($($arg)*))
+    };
+}
+
+/// short_src and `line` of the frame in `ar` (filled with "S"), or the
+/// original file and line when its chunk was loaded with a source map
+unsafe fn mapped_position(L: *mut lua_State, ar: &lua_Debug, line: c_int) -> (String, c_int) {
+    if let Some(source) = cstr_opt(ar.source) {
+        if let Some(pos) = (*(L as *mut LuaState)).map_source_line(source, line) {
+            return pos;
+        }
+    }
+    (CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().into_owned(), line)
+}
+
+/// Push "chunkname:currentline: " for the function at `level`, or "" when
+/// that level has no line information (C functions). Chunks loaded with a
+/// source map report the original file and line.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_where(L: *mut lua_State, level: c_int) {
+    let mut ar = lua_Debug::new();
+    if lua_getstack(L, level, &mut ar) != 0 {
+        lua_getinfo(L, b"Sl\0".as_ptr() as *const c_char, &mut ar);
+        if ar.currentline > 0 {
+            let (src, line) = mapped_position(L, &ar, ar.currentline);
+            let prefix = format!("{}:{}: ", src, line);
+            lua_pushlstring(L, prefix.as_ptr() as *const c_char, prefix.len());
+            return;
+        }
+    }
+    lua_pushlstring(L, ptr::null(), 0);
+}
+
+/// Levels shown from the top and from the bottom of a long traceback
+const LEVELS1: c_int = 10;
+const LEVELS2: c_int = 11;
+
+/// How luaL_traceback renders a state's stacks (LuaState::set_traceback_options)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracebackOptions {
+    /// Levels shown from the top and from the bottom; deeper stacks skip
+    /// the ones in the middle
+    pub levels1: c_int,
+    pub levels2: c_int,
+    /// Show the current source line under each frame when the chunk's
+    /// source is at hand: its file for "@file" chunks, or the chunk
+    /// itself for strings loaded without a chunk name
+    pub source_lines: bool,
+}
+
+impl Default for TracebackOptions {
+    fn default() -> Self {
+        TracebackOptions { levels1: LEVELS1, levels2: LEVELS2, source_lines: false }
+    }
+}
+
+/// Line `line` (from 1) of the chunk whose source is `source`, trimmed;
+/// `read_file` gets the file name of "@file" sources. None for "=name"
+/// sources, missing files and lines, and blank lines.
+pub fn source_line(source: &str, line: c_int, read_file: impl FnOnce(&str) -> Option<String>) -> Option<String> {
+    if line <= 0 {
+        return None;
+    }
+    let text = match source.as_bytes().first() {
+        Some(b'=') => return None,
+        Some(b'@') => read_file(&source[1..])?,
+        _ => source.to_string(),
+    };
+    let l = text.lines().nth(line as usize - 1)?.trim();
+    (!l.is_empty()).then(|| l.to_string())
+}
+
+impl LuaState {
+    /// Traceback rendering for this state (shared by all its threads)
+    pub fn set_traceback_options(&mut self, opts: TracebackOptions) {
+        self.l_G.borrow_mut().traceback = opts;
+    }
+}
+
+/// How a traceback line names the function of a frame: "function 'f'",
+/// "method 'm'", "main chunk", "function <file:12>" or "?"
+pub fn traceback_funcname(namewhat: &str, name: Option<&str>, what: &str, short_src: &str, linedefined: c_int) -> String {
+    if !namewhat.is_empty() {
+        let namewhat = if namewhat == "global" { "function" } else { namewhat };
+        format!("{} '{}'", namewhat, name.unwrap_or("?"))
+    } else if what == "main" {
+        "main chunk".to_string()
+    } else if what != "C" {
+        format!("function <{}:{}>", short_src, linedefined)
+    } else {
+        "?".to_string()
+    }
+}
+
+// Level of the outermost active function of L1 (binary search on lua_getstack)
+unsafe fn lastlevel(L1: *mut lua_State) -> c_int {
+    let mut ar = lua_Debug::new();
+    let (mut li, mut le) = (1, 1);
+    while lua_getstack(L1, le, &mut ar) != 0 {
+        li = le;
+        le *= 2;
+    }
+    while li < le {
+        let m = (li + le) / 2;
+        if lua_getstack(L1, m, &mut ar) != 0 { li = m + 1 } else { le = m }
+    }
+    le - 1
+}
+
+/// Push a traceback of the stack of `L1` from `level` up, preceded by `msg`
+/// if it is not NULL. Works on a coroutine that died with an error: its
+/// frames are kept until it is reset, so they still show where it failed.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_traceback(L: *mut lua_State, L1: *mut lua_State, msg: *const c_char, level: c_int) {
+    let mut tb = String::new();
+    if let Some(msg) = cstr_opt(msg) {
+        tb.push_str(msg);
+        tb.push('\n');
+    }
+    tb.push_str("stack traceback:");
+    let state = &*(L1 as *mut LuaState);
+    let opts = state.l_G.borrow().traceback.clone();
+    let last = lastlevel(L1);
+    let mut limit2show = if last - level > opts.levels1 + opts.levels2 { opts.levels1 } else { -1 };
+    let mut level = level;
+    let mut ar = lua_Debug::new();
+    while lua_getstack(L1, level, &mut ar) != 0 {
+        level += 1;
+        if limit2show == 0 {
+            let n = last - level - opts.levels2 + 1;
+            tb.push_str(&format!("\n\t...\t(skipping {} levels)", n));
+            level += n;
+        } else {
+            lua_getinfo(L1, b"Slnt\0".as_ptr() as *const c_char, &mut ar);
+            let (src, line) = mapped_position(L1, &ar, ar.currentline);
+            if line <= 0 {
+                tb.push_str(&format!("\n\t{}: in ", src));
+            } else {
+                tb.push_str(&format!("\n\t{}:{}: in ", src, line));
+            }
+            let (defsrc, linedefined) = mapped_position(L1, &ar, ar.linedefined);
+            tb.push_str(&traceback_funcname(cstr_opt(ar.namewhat).unwrap_or(""), cstr_opt(ar.name),
+                cstr_opt(ar.what).unwrap_or("?"), &defsrc, linedefined));
+            if opts.source_lines && !ar.source.is_null() {
+                let source = String::from_utf8_lossy(std::slice::from_raw_parts(ar.source as *const u8, ar.srclen));
+                let read = |path: &str| state.vfs().read(path).ok().map(|b| String::from_utf8_lossy(&b).into_owned());
+                if let Some(l) = source_line(&source, ar.currentline, read) {
+                    tb.push_str(&format!("\n\t\t{}", l));
+                }
+            }
+            if ar.istailcall != 0 {
+                tb.push_str("\n\t(...tail calls...)");
+            }
+        }
+        limit2show -= 1;
+    }
+    lua_pushlstring(L, tb.as_ptr() as *const c_char, tb.len());
+}
+
+/// Raise "bad argument #arg to 'fname' (extramsg)"
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_argerror(L: *mut lua_State, arg: c_int, extramsg: *const c_char) -> c_int {
+    let extra = cstr_opt(extramsg).unwrap_or("");
+    let mut ar = lua_Debug::new();
+    if lua_getstack(L, 0, &mut ar) == 0 {
+        // no stack frame: called directly from the host
+        aux_raise(L, &format!("bad argument #{} ({})", arg, extra));
+    }
+    lua_getinfo(L, b"n\0".as_ptr() as *const c_char, &mut ar);
+    let msg = argerror_message(arg, cstr_opt(ar.name), cstr_opt(ar.namewhat).unwrap_or(""), extra);
+    aux_raise(L, &msg)
+}
+
+/// Name of the value's type for error messages (__name wins for userdata)
+unsafe fn typename_at(L: *mut lua_State, arg: c_int) -> String {
+    if luaL_getmetafield(L, arg, b"__name\0".as_ptr() as *const c_char) == LUA_TSTRING {
+        let name = CStr::from_ptr(lua_tolstring(L, -1, ptr::null_mut())).to_string_lossy().into_owned();
+        lua_settop(L, -2);
+        return name;
+    }
+    let t = lua_type(L, arg);
+    if t == LUA_TLIGHTUSERDATA {
+        return "light userdata".to_string();
+    }
+    CStr::from_ptr(lua_typename(L, t)).to_string_lossy().into_owned()
+}
+
+/// Raise "bad argument #arg to 'fname' (tname expected, got <type>)"
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_typeerror(L: *mut lua_State, arg: c_int, tname: *const c_char) -> c_int {
+    let msg = typeerror_message(cstr_opt(tname).unwrap_or("?"), &typename_at(L, arg));
+    let msg = CString::new(msg).unwrap();
+    luaL_argerror(L, arg, msg.as_ptr())
+}
+
+unsafe fn tag_error(L: *mut lua_State, arg: c_int, tag: c_int) -> ! {
+    luaL_typeerror(L, arg, lua_typename(L, tag));
+    unreachable!("luaL_typeerror returned")
+}
+
+/// Push field `e` of the metatable of the value at `obj` and return its
+/// type; push nothing and return LUA_TNIL if there is no such field
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_getmetafield(L: *mut lua_State, obj: c_int, e: *const c_char) -> c_int {
+    if lua_getmetatable(L, obj) == 0 {
+        return LUA_TNIL;
+    }
+    lua_pushstring(L, e);
+    let tt = lua_rawget(L, -2);
+    if tt == LUA_TNIL {
+        lua_settop(L, -3); // the nil and the metatable
+    } else {
+        lua_remove(L, -2); // the metatable
+    }
+    tt
+}
+
+/// Push registry[tname], first creating it with __name = tname if absent;
+/// returns 1 if it was created. The __name is what argument errors call
+/// values with this metatable.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_newmetatable(L: *mut lua_State, tname: *const c_char) -> c_int {
+    if lua_getfield(L, LUA_REGISTRYINDEX, tname) != LUA_TNIL {
+        return 0;
+    }
+    lua_settop(L, -2);
+    lua_createtable(L, 0, 2);
+    lua_pushstring(L, tname);
+    lua_setfield(L, -2, b"__name\0".as_ptr() as *const c_char);
+    lua_pushvalue(L, -1);
+    lua_setfield(L, LUA_REGISTRYINDEX, tname);
+    1
+}
+
+/// Set the metatable of the value on top to registry[tname]
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setmetatable(L: *mut lua_State, tname: *const c_char) {
+    lua_getfield(L, LUA_REGISTRYINDEX, tname);
+    lua_setmetatable(L, -2);
+}
+
+/// The block of the userdata at `ud` if its metatable is registry[tname],
+/// else null
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_testudata(L: *mut lua_State, ud: c_int, tname: *const c_char) -> *mut c_void {
+    if cstr_opt(tname) == Some(LUA_FILEHANDLE) {
+        // io files are not userdata, but they carry a luaL_Stream
+        return crate::liolib::tofilestream(L, ud).cast();
+    }
+    let p = lua_touserdata(L, ud);
+    if p.is_null() || lua_getmetatable(L, ud) == 0 {
+        return ptr::null_mut();
+    }
+    lua_getfield(L, LUA_REGISTRYINDEX, tname);
+    let same = lua_rawequal(L, -1, -2) != 0;
+    lua_settop(L, -3);
+    if same { p } else { ptr::null_mut() }
+}
 
-// For example:
-pub unsafe fn luaL_checklstring_rs(L: *mut lua_State, arg: c_int, len: *mut size_t) -> *const c_char {
-    // Example translation of luaL_checklstring
+/// luaL_testudata, raising "tname expected, got <type>" on a mismatch
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkudata(L: *mut lua_State, ud: c_int, tname: *const c_char) -> *mut c_void {
+    let p = luaL_testudata(L, ud, tname);
+    if p.is_null() {
+        luaL_typeerror(L, ud, tname);
+    }
+    p
+}
+
+/// Grow the stack by `sz` slots or raise "stack overflow (msg)"
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkstack(L: *mut lua_State, sz: c_int, msg: *const c_char) {
+    if lua_checkstack(L, sz) == 0 {
+        match cstr_opt(msg) {
+            Some(m) => aux_raise(L, &format!("stack overflow ({})", m)),
+            None => aux_raise(L, "stack overflow"),
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checktype(L: *mut lua_State, arg: c_int, t: c_int) {
+    if lua_type(L, arg) != t {
+        tag_error(L, arg, t);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkany(L: *mut lua_State, arg: c_int) {
+    if lua_type(L, arg) == LUA_TNONE {
+        let msg = b"value expected\0";
+        luaL_argerror(L, arg, msg.as_ptr() as *const c_char);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checklstring(L: *mut lua_State, arg: c_int, len: *mut size_t) -> *const c_char {
     let s = lua_tolstring(L, arg, len);
     if s.is_null() {
-        // tag_error(L, arg, LUA_TSTRING);
-        // (implement tag_error in Rust)
+        tag_error(L, arg, LUA_TSTRING);
     }
     s
 }
 
-pub unsafe fn luaL_checkinteger_rs(L: *mut lua_State, arg: c_int) -> lua_Integer {
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optlstring(L: *mut lua_State, arg: c_int, def: *const c_char, len: *mut size_t) -> *const c_char {
+    if lua_type(L, arg) <= LUA_TNIL {
+        if !len.is_null() {
+            *len = if def.is_null() { 0 } else { CStr::from_ptr(def).to_bytes().len() };
+        }
+        return def;
+    }
+    luaL_checklstring(L, arg, len)
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checknumber(L: *mut lua_State, arg: c_int) -> lua_Number {
+    let mut isnum = 0;
+    let n = lua_tonumberx(L, arg, &mut isnum);
+    if isnum == 0 {
+        tag_error(L, arg, LUA_TNUMBER);
+    }
+    n
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optnumber(L: *mut lua_State, arg: c_int, def: lua_Number) -> lua_Number {
+    if lua_type(L, arg) <= LUA_TNIL { def } else { luaL_checknumber(L, arg) }
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkinteger(L: *mut lua_State, arg: c_int) -> lua_Integer {
     let mut isnum = 0;
     let n = lua_tointegerx(L, arg, &mut isnum);
     if isnum == 0 {
-        // tag_error(L, arg, LUA_TNUMBER);
-        // (implement tag_error in Rust)
+        // A float without an exact integer value is a different error than a non-number
+        if lua_isnumber(L, arg) != 0 {
+            let msg = b"number has no integer representation\0";
+            luaL_argerror(L, arg, msg.as_ptr() as *const c_char);
+        }
+        tag_error(L, arg, LUA_TNUMBER);
     }
     n
 }
 
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_optinteger(L: *mut lua_State, arg: c_int, def: lua_Integer) -> lua_Integer {
+    if lua_type(L, arg) <= LUA_TNIL { def } else { luaL_checkinteger(L, arg) }
+}
+
+/// Seed for the string hashes of a new state: the platform's entropy
+/// folded to 32 bits, so hosts without entropy get a fixed one
+pub fn makeseed() -> u32 {
+    let s = crate::lplatform::with_platform(|p| p.seed());
+    (s ^ (s >> 32)) as u32
+}
+
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_makeseed(_L: *mut lua_State) -> u32 {
+    makeseed()
+}
+
+// Unsigned casts of the 5.3 API (COMPAT_APIINTCASTS); macros in luaconf.h
+
+#[cfg(feature = "compat_apiintcasts")]
+pub unsafe fn lua_pushunsigned(L: *mut lua_State, n: lua_Unsigned) {
+    lua_pushinteger(L, n as lua_Integer)
+}
+
+#[cfg(feature = "compat_apiintcasts")]
+pub unsafe fn lua_tounsignedx(L: *mut lua_State, idx: c_int, isnum: *mut c_int) -> lua_Unsigned {
+    lua_tointegerx(L, idx, isnum) as lua_Unsigned
+}
+
+#[cfg(feature = "compat_apiintcasts")]
+pub unsafe fn lua_tounsigned(L: *mut lua_State, idx: c_int) -> lua_Unsigned {
+    lua_tounsignedx(L, idx, ptr::null_mut())
+}
+
+#[cfg(feature = "compat_apiintcasts")]
+pub unsafe fn luaL_checkunsigned(L: *mut lua_State, arg: c_int) -> lua_Unsigned {
+    luaL_checkinteger(L, arg) as lua_Unsigned
+}
+
+#[cfg(feature = "compat_apiintcasts")]
+pub unsafe fn luaL_optunsigned(L: *mut lua_State, arg: c_int, def: lua_Unsigned) -> lua_Unsigned {
+    luaL_optinteger(L, arg, def as lua_Integer) as lua_Unsigned
+}
+
+/// Index in the NULL-terminated `lst` of the string argument (or `def` when
+/// the argument is absent); raises "invalid option 'x'" otherwise
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_checkoption(L: *mut lua_State, arg: c_int, def: *const c_char, lst: *const *const c_char) -> c_int {
+    let name = if def.is_null() {
+        luaL_checklstring(L, arg, ptr::null_mut())
+    } else {
+        luaL_optlstring(L, arg, def, ptr::null_mut())
+    };
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    let mut opts = Vec::new();
+    let mut p = lst;
+    while !(*p).is_null() {
+        opts.push(CStr::from_ptr(*p).to_string_lossy().into_owned());
+        p = p.add(1);
+    }
+    let refs: Vec<&str> = opts.iter().map(|s| s.as_str()).collect();
+    match find_option(&name, &refs) {
+        Some(i) => i as c_int,
+        None => {
+            let msg = CString::new(format!("invalid option '{}'", name)).unwrap();
+            luaL_argerror(L, arg, msg.as_ptr())
+        }
+    }
+}
+
+// --- Library registration ---
+
+/// Register the functions of the NULL-terminated array `l` into the table
+/// below the `nup` upvalues on the stack; every function gets its own copy
+/// of the upvalues, which are popped afterwards. A NULL `func` stores a
+/// `false` placeholder.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_setfuncs(L: *mut lua_State, l: *const luaL_Reg, nup: c_int) {
+    luaL_checkstack(L, nup, b"too many upvalues\0".as_ptr() as *const c_char);
+    let mut l = l;
+    while !(*l).name.is_null() {
+        match (*l).func {
+            None => lua_pushboolean(L, 0),
+            Some(f) => {
+                for _ in 0..nup {
+                    lua_pushvalue(L, -nup);
+                }
+                lua_pushcclosure(L, f, nup);
+            }
+        }
+        lua_setfield(L, -(nup + 2), (*l).name);
+        l = l.add(1);
+    }
+    lua_pop(L, nup);
+}
+
+/// Push t[fname] where t is the table at `idx`, creating it if it is not a
+/// table. Returns 1 if the table already existed.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_getsubtable(L: *mut lua_State, idx: c_int, fname: *const c_char) -> c_int {
+    if lua_getfield(L, idx, fname) == LUA_TTABLE {
+        return 1;
+    }
+    lua_pop(L, 1);
+    // make idx absolute: the new table is about to shift relative indices
+    let idx = if idx > 0 || idx <= LUA_REGISTRYINDEX { idx } else { lua_gettop(L) + idx + 1 };
+    lua_createtable(L, 0, 0);
+    lua_pushvalue(L, -1);
+    lua_setfield(L, idx, fname);
+    0
+}
+
+/// Call `openf` with `modname` unless package.loaded[modname] is already
+/// set, store its result there, and leave the module on the stack. With
+/// `glb` the module is also stored in the global `modname`.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaL_requiref(L: *mut lua_State, modname: *const c_char, openf: lua_CFunction, glb: c_int) {
+    let loaded = CString::new(LUA_LOADED_TABLE).unwrap();
+    luaL_getsubtable(L, LUA_REGISTRYINDEX, loaded.as_ptr());
+    lua_getfield(L, -1, modname);
+    if lua_toboolean(L, -1) == 0 {
+        lua_pop(L, 1);
+        lua_pushcfunction(L, openf);
+        lua_pushstring(L, modname);
+        lua_call(L, 1, 1);
+        lua_pushvalue(L, -1);
+        lua_setfield(L, -3, modname);
+    }
+    lua_remove(L, -2);
+    if glb != 0 {
+        lua_pushvalue(L, -1);
+        lua_setglobal(L, modname);
+    }
+}
+
+// --- Argument checking for the safe API ---
+//
+// The same checks for library functions written against LuaState
+// (ltablib, lstrlib, loslib, ...). Arguments are numbered from 1 relative
+// to the running function (CallInfo::func). Failed checks record the error
+// with LuaState::error and return a neutral value.
+
+use crate::lobject::{LuaValue, Numeral};
+use crate::lstate::LuaState;
+use crate::ltm::{get_metafield, obj_typename};
+use crate::skylaconf::{float_to_integer, LuaFloat, LuaInteger};
+
+/// Type name of `v` in error messages: a string __name in its metatable
+/// if it has one ("MyType expected, got OtherType"), else "light userdata"
+/// or the basic type name (luaL_typeerror)
+pub fn error_typename(v: &LuaValue) -> String {
+    match get_metafield(v, "__name") {
+        Some(LuaValue::Str(name)) => name,
+        _ if matches!(v, LuaValue::Pointer(_)) => "light userdata".to_string(),
+        _ => obj_typename(v).to_string(),
+    }
+}
+
+impl LuaState {
+    /// Argument `arg` of the running function (None if absent)
+    pub fn arg(&self, arg: i32) -> Option<&LuaValue> {
+        if arg < 1 {
+            return None;
+        }
+        self.stack.get(self.ci.borrow().func + arg as usize)
+    }
+
+    /// Number of arguments of the running function
+    pub fn get_top(&self) -> i32 {
+        self.stack.len().saturating_sub(self.ci.borrow().func + 1) as i32
+    }
+
+    /// Whether `n` more values fit on the stack (lua_checkstack)
+    pub fn check_stack(&self, n: usize) -> bool {
+        n <= crate::llimits::LUAI_MAXSTACK.saturating_sub(self.stack.len())
+    }
+
+    /// A new table with room for `narr` array and `nrec` other entries
+    /// (lua_createtable); it keeps insertion order if the state does
+    pub fn create_table(&mut self, narr: usize, nrec: usize) -> Rc<RefCell<Table>> {
+        let mut t = Table::with_capacity(narr, nrec);
+        if self.insertion_order() {
+            t.set_ordered(true);
+        }
+        Rc::new(RefCell::new(t))
+    }
+
+    /// Argument `arg`, or nil if absent
+    pub fn to_value(&self, arg: i32) -> LuaValue {
+        self.arg(arg).cloned().unwrap_or(LuaValue::Nil)
+    }
+
+    pub fn is_none_or_nil(&self, arg: i32) -> bool {
+        matches!(self.arg(arg), None | Some(LuaValue::Nil))
+    }
+
+    /// Raise "bad argument #arg to 'fname' (extramsg)"
+    pub fn arg_error(&mut self, arg: i32, extramsg: &str) -> ! {
+        let msg = {
+            let ci = self.ci.borrow();
+            argerror_message(arg, ci.name.as_deref(), if ci.is_method { "method" } else { "" }, extramsg)
+        };
+        self.error(&msg)
+    }
+
+    /// Raise "bad argument #arg to 'fname' (expected expected, got <type>)",
+    /// naming the argument's type as error_typename does
+    pub fn type_error(&mut self, arg: i32, expected: &str) -> ! {
+        let actual = self.arg(arg).map(error_typename).unwrap_or_else(|| "no value".to_string());
+        self.arg_error(arg, &typeerror_message(expected, &actual))
+    }
+
+    pub fn check_any(&mut self, arg: i32) {
+        if self.arg(arg).is_none() {
+            self.arg_error(arg, "value expected");
+        }
+    }
+
+    pub fn check_number(&mut self, arg: i32) -> LuaFloat {
+        match self.arg(arg) {
+            Some(LuaValue::Int(i)) => *i as LuaFloat,
+            Some(LuaValue::Float(f)) => *f,
+            Some(LuaValue::Str(s)) => match crate::lobject::luaO_str2number(s) {
+                Some(Numeral::Int(i)) => i as LuaFloat,
+                Some(Numeral::Float(f)) => f,
+                None => self.type_error(arg, "number"),
+            },
+            _ => self.type_error(arg, "number"),
+        }
+    }
+
+    pub fn check_integer(&mut self, arg: i32) -> LuaInteger {
+        let f = match self.arg(arg) {
+            Some(LuaValue::Int(i)) => return *i,
+            Some(LuaValue::Float(f)) => Some(*f),
+            Some(LuaValue::Str(s)) => match crate::lobject::luaO_str2number(s) {
+                Some(Numeral::Int(i)) => return i,
+                Some(Numeral::Float(f)) => Some(f),
+                None => None,
+            },
+            _ => None,
+        };
+        match f.map(float_to_integer) {
+            Some(Some(i)) => i,
+            Some(None) => self.arg_error(arg, "number has no integer representation"),
+            None => self.type_error(arg, "number"),
+        }
+    }
+
+    /// String argument; numbers are converted as by tostring
+    pub fn check_string(&mut self, arg: i32) -> String {
+        match self.arg(arg) {
+            Some(LuaValue::Str(s)) => s.clone(),
+            Some(LuaValue::Int(i)) => crate::lobject::luaO_int2str(*i),
+            Some(LuaValue::Float(f)) => crate::lobject::luaO_num2str_dot(*f),
+            _ => self.type_error(arg, "string"),
+        }
+    }
+
+    /// Index of the string argument in `lst` (`def` when absent)
+    pub fn check_option(&mut self, arg: i32, def: Option<&str>, lst: &[&str]) -> usize {
+        let name = match def {
+            Some(d) if self.is_none_or_nil(arg) => d.to_string(),
+            _ => self.check_string(arg),
+        };
+        match find_option(&name, lst) {
+            Some(i) => i,
+            None => self.arg_error(arg, &format!("invalid option '{}'", name)),
+        }
+    }
 
+    pub fn opt_number(&mut self, arg: i32, def: LuaFloat) -> LuaFloat {
+        if self.is_none_or_nil(arg) { def } else { self.check_number(arg) }
+    }
+
+    pub fn opt_integer(&mut self, arg: i32, def: LuaInteger) -> LuaInteger {
+        if self.is_none_or_nil(arg) { def } else { self.check_integer(arg) }
+    }
+
+    pub fn opt_string(&mut self, arg: i32, def: &str) -> String {
+        if self.is_none_or_nil(arg) { def.to_string() } else { self.check_string(arg) }
+    }
+
+    /// Boolean argument with Lua truthiness (nil and false are false)
+    pub fn opt_boolean(&mut self, arg: i32, def: bool) -> bool {
+        match self.arg(arg) {
+            None => def,
+            Some(LuaValue::Nil) | Some(LuaValue::Bool(false)) => false,
+            Some(_) => true,
+        }
+    }
+}
+
+// --- Error results for the safe API ---
+
+impl LuaState {
+    /// Push nil, "fname: message" (or just the message) and the errno of `e`
+    pub fn file_error(&mut self, e: &io::Error, fname: Option<&str>) -> i32 {
+        let msg = io_strerror(e);
+        self.push(LuaValue::Nil);
+        self.push(LuaValue::Str(match fname {
+            Some(n) => format!("{}: {}", n, msg),
+            None => msg,
+        }));
+        self.push(LuaValue::Int(io_errno(e) as LuaInteger));
+        3
+    }
+
+    /// `true` on success, otherwise the failure triple (luaL_fileresult)
+    pub fn file_result(&mut self, res: io::Result<()>, fname: Option<&str>) -> i32 {
+        match res {
+            Ok(()) => {
+                self.push(LuaValue::Bool(true));
+                1
+            }
+            Err(e) => self.file_error(&e, fname),
+        }
+    }
+
+    /// Push Lua's (true|nil, "exit"|"signal", code) for a finished command,
+    /// or the failure triple if it could not run (luaL_execresult)
+    pub fn exec_result(&mut self, res: io::Result<ExecStatus>) -> i32 {
+        let status = match res {
+            Ok(status) => status,
+            Err(e) => return self.file_error(&e, None),
+        };
+        let (what, code) = match status {
+            ExecStatus::Exit(c) => ("exit", c),
+            ExecStatus::Signal(s) => ("signal", s),
+        };
+        self.push(if status.success() { LuaValue::Bool(true) } else { LuaValue::Nil });
+        self.push(LuaValue::Str(what.to_string()));
+        self.push(LuaValue::Int(code as LuaInteger));
+        3
+    }
+}
+
+// --- Library registration for the safe API ---
+//
+// Libraries live in package.loaded (registry._LOADED) under their name, as
+// with luaL_requiref; register_lib_function fills those tables.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::ltable::Table;
+
+/// A library function of the safe API
+pub type LibFunction = fn(&mut LuaState) -> i32;
+
+/// Call library function `f` as the VM does, with `args` above the slot
+/// of the running function, and return its results (library tests)
+#[cfg(test)]
+pub(crate) fn call_lib(state: &mut LuaState, f: LibFunction, args: Vec<LuaValue>) -> Vec<LuaValue> {
+    state.stack.truncate(0);
+    state.push(LuaValue::Nil); // the running function's slot
+    for a in args {
+        state.push(a);
+    }
+    let n = f(state) as usize;
+    state.stack.split_off(state.stack.len() - n)
+}
+
+/// t[fname] if it is a table, otherwise a new table stored there; the flag
+/// tells whether the table already existed (luaL_getsubtable)
+pub fn get_subtable(t: &Rc<RefCell<Table>>, fname: &str) -> (Rc<RefCell<Table>>, bool) {
+    let key = LuaValue::Str(fname.to_string());
+    if let Some(LuaValue::Table(sub)) = t.borrow().get(&key) {
+        return (sub.clone(), true);
+    }
+    let sub = Rc::new(RefCell::new(Table::new()));
+    t.borrow_mut().rawset(&key, LuaValue::Table(sub.clone()));
+    (sub, false)
+}
+
+impl LuaState {
+    /// The registry table, created on first use
+    pub fn registry_table(&mut self) -> Rc<RefCell<Table>> {
+        let mut g = self.l_G.borrow_mut();
+        if let LuaValue::Table(t) = &g.registry {
+            return t.clone();
+        }
+        let t = Rc::new(RefCell::new(Table::new()));
+        g.registry = LuaValue::Table(t.clone());
+        t
+    }
+
+    /// package.loaded
+    pub fn loaded_table(&mut self) -> Rc<RefCell<Table>> {
+        let registry = self.registry_table();
+        get_subtable(&registry, LUA_LOADED_TABLE).0
+    }
+
+    /// The globals table: package.loaded._G, which the base library fills
+    /// and get_global/set_global read and write
+    pub fn globals_table(&mut self) -> Rc<RefCell<Table>> {
+        self.lib_table(LUA_GNAME)
+    }
+
+    /// The table of library `libname` in package.loaded, created on first use
+    pub fn lib_table(&mut self, libname: &str) -> Rc<RefCell<Table>> {
+        let loaded = self.loaded_table();
+        get_subtable(&loaded, libname).0
+    }
+
+    /// Store every function of `funcs` in `lib` (luaL_setfuncs). Each one
+    /// runs behind LuaState::call_rust, so a panic in it becomes a Lua error.
+    pub fn set_funcs(&mut self, lib: &Rc<RefCell<Table>>, funcs: &[(&str, LibFunction)]) {
+        let mut t = lib.borrow_mut();
+        for &(name, f) in funcs {
+            let f = move |L: &mut LuaState| L.call_rust(f);
+            t.rawset(&LuaValue::Str(name.to_string()), LuaValue::Function(Box::new(f)));
+        }
+    }
+
+    /// Add function `name` to library `libname`
+    pub fn register_lib_function(&mut self, libname: &str, name: &str, f: LibFunction) {
+        let lib = self.lib_table(libname);
+        self.set_funcs(&lib, &[(name, f)]);
+    }
+
+    /// Add a non-function field (a constant or sentinel) to library `libname`
+    pub fn register_lib_value(&mut self, libname: &str, name: &str, v: LuaValue) {
+        let lib = self.lib_table(libname);
+        lib.borrow_mut().rawset(&LuaValue::Str(name.to_string()), v);
+    }
+
+    /// Open library `modname` with `openf` unless package.loaded already has
+    /// it, and return its table; with `global` it is also stored in the
+    /// global `modname` (luaL_requiref)
+    pub fn require_lib(&mut self, modname: &str, openf: fn(&mut LuaState), global: bool) -> Rc<RefCell<Table>> {
+        let loaded = self.loaded_table();
+        let (lib, existed) = get_subtable(&loaded, modname);
+        if !existed {
+            openf(self);
+        }
+        if global {
+            self.set_global(modname, LuaValue::Table(lib.clone()));
+        }
+        lib
+    }
+
+    /// luaL_checkversion for Rust extensions, given the LUA_VERSION_NUM and
+    /// LUAL_NUMSIZES they were built with: raises "version mismatch" (or
+    /// the numeric-types error) if this core differs
+    pub fn check_version(&mut self, ver: lua_Number, numsizes: usize) {
+        if let Some(msg) = checkversion_message(ver, numsizes, LUA_VERSION_NUM) {
+            self.throw(LuaValue::Str(msg));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argerror_messages() {
+        assert_eq!(argerror_message(2, Some("sub"), "global", &typeerror_message("number", "nil")),
+            "bad argument #2 to 'sub' (number expected, got nil)");
+        assert_eq!(argerror_message(2, Some("find"), "method", "x"), "bad argument #1 to 'find' (x)");
+        assert_eq!(argerror_message(1, Some("close"), "method", "closed file"), "calling 'close' on bad self (closed file)");
+        assert_eq!(argerror_message(1, None, "", "value expected"), "bad argument #1 to '?' (value expected)");
+    }
+
+    #[test]
+    fn test_error_typename() {
+        assert_eq!(error_typename(&LuaValue::Int(1)), "number");
+        assert_eq!(error_typename(&LuaValue::Pointer(std::ptr::null())), "light userdata");
+        assert_eq!(typeerror_message("FILE*", &error_typename(&LuaValue::Nil)), "FILE* expected, got nil");
+    }
+
+    #[test]
+    fn test_checkversion_message() {
+        assert_eq!(checkversion_message(LUA_VERSION_NUM, LUAL_NUMSIZES, LUA_VERSION_NUM), None);
+        assert_eq!(checkversion_message(503.0, LUAL_NUMSIZES, 504.0).unwrap(),
+            "version mismatch: app. needs 503, Lua core provides 504");
+        // a library built with 32-bit integers and floats
+        assert_eq!(checkversion_message(504.0, 4 * 16 + 4, 504.0).unwrap(),
+            "core and library have incompatible numeric types");
+    }
+
+    #[test]
+    fn test_source_line() {
+        let none = |_: &str| -> Option<String> { None };
+        assert_eq!(source_line("local x = 1\n  error('boom')\n", 2, none), Some("error('boom')".to_string()));
+        assert_eq!(source_line("=stdin", 1, none), None);
+        assert_eq!(source_line("@t.lua", 1, |p: &str| Some(format!("-- {}", p))), Some("-- t.lua".to_string()));
+        assert_eq!(source_line("@t.lua", 1, none), None);
+        assert_eq!(source_line("a\n\nb", 2, none), None);
+        assert_eq!(source_line("a", 0, none), None);
+        assert_eq!(TracebackOptions::default().levels1, LEVELS1);
+    }
+
+    #[test]
+    fn test_traceback_funcname() {
+        assert_eq!(traceback_funcname("global", Some("f"), "Lua", "t.lua", 3), "function 'f'");
+        assert_eq!(traceback_funcname("method", Some("m"), "Lua", "t.lua", 3), "method 'm'");
+        assert_eq!(traceback_funcname("", None, "main", "t.lua", 0), "main chunk");
+        assert_eq!(traceback_funcname("", None, "Lua", "t.lua", 12), "function <t.lua:12>");
+        assert_eq!(traceback_funcname("", None, "C", "[C]", -1), "?");
+    }
+
+    #[test]
+    fn test_get_subtable() {
+        let t = Rc::new(RefCell::new(Table::new()));
+        let (sub, existed) = get_subtable(&t, "_LOADED");
+        assert!(!existed);
+        sub.borrow_mut().rawset(&LuaValue::Str("x".to_string()), LuaValue::Int(1));
+        let (again, existed) = get_subtable(&t, "_LOADED");
+        assert!(existed && Rc::ptr_eq(&sub, &again));
+        // a non-table value is replaced
+        t.borrow_mut().rawset(&LuaValue::Str("n".to_string()), LuaValue::Int(3));
+        assert!(!get_subtable(&t, "n").1);
+    }
+
+    #[test]
+    fn test_gsub() {
+        assert_eq!(gsub("./?.lua;./?/init.lua", "?", "a/b"), "./a/b.lua;./a/b/init.lua");
+        // the replacement is not rescanned, even when it contains the pattern
+        assert_eq!(gsub("x?y", "?", "??"), "x??y");
+        assert_eq!(gsub("a.b.c", ".", "/"), "a/b/c");
+        assert_eq!(gsub("abc", "", "-"), "abc");
+    }
+
+    #[test]
+    fn test_io_error_results() {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        assert_eq!(io_errno(&e), 2);
+        assert_eq!(io_strerror(&e), "no such file");
+        #[cfg(unix)]
+        {
+            let e = io::Error::from_raw_os_error(13);
+            assert_eq!(io_errno(&e), 13);
+            assert!(!io_strerror(&e).contains("os error"));
+        }
+        assert_eq!(ExecStatus::from_wait(0), ExecStatus::Exit(0));
+        #[cfg(unix)]
+        {
+            assert_eq!(ExecStatus::from_wait(3 << 8), ExecStatus::Exit(3));
+            assert_eq!(ExecStatus::from_wait(9), ExecStatus::Signal(9));
+        }
+    }
+
+    #[test]
+    fn test_find_option() {
+        let cats = ["all", "collate", "ctype"];
+        assert_eq!(find_option("ctype", &cats), Some(2));
+        assert_eq!(find_option("bogus", &cats), None);
+    }
+
+    #[test]
+    fn test_check_stack() {
+        let state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        assert!(state.check_stack(1000));
+        assert!(!state.check_stack(crate::llimits::LUAI_MAXSTACK + 1));
+        assert!(!state.check_stack(usize::MAX));
+    }
+
+    #[test]
+    fn test_check_string_and_type() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        state.push(LuaValue::Nil); // the running function's slot
+        state.push(LuaValue::Float(1.0));
+        state.push(LuaValue::Int(2));
+        assert_eq!(state.check_string(1), "1.0");
+        assert_eq!(state.check_string(2), "2");
+        // a failed check unwinds out of the C function as a Lua error
+        let err = state.pcall(|L| unsafe { luaL_checktype(L as *mut LuaState as *mut lua_State, 1, LUA_TSTRING) });
+        assert!(err.unwrap_err().to_string().contains("string expected, got number"));
+    }
+}
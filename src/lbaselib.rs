@@ -1,558 +1,631 @@
-//! Basic library for Lua, translated from lbaselib.c
-
-use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int, c_void};
-use std::ptr;
-
-use crate::lua::*;
-use crate::lauxlib::*;
-use crate::lualib::*;
+//! lbaselib.rs - Lua basic library for Rust-based Lua VM
+// Ported and adapted from lbaselib.c
+/*
+** $Id: lbaselib.c $
+** Basic library
+** See Copyright Notice in lua.h
+*/
 
-// Helper macro for error checking
-macro_rules! l_unlikely {
-    ($e:expr) => { $e }
-}
-macro_rules! l_likely {
-    ($e:expr) => { $e }
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::lobject::{LuaValue, Numeral};
+use crate::lsourcemap::LoadOptions;
+use crate::lstate::{raw_equal, LuaState, ObjectId};
+use crate::ltable::Table;
+use crate::ltm::{get_metafield, obj_typename};
+
+/// Option for multiple returns (lua_call)
+const LUA_MULTRET: i32 = crate::lvm::LUA_MULTRET;
+
+/// Value of the global _VERSION
+pub const LUA_VERSION: &str = "Lua 5.4";
+
+// Basic library function list (name, implementation)
+const BASE_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("assert", base_assert),
+    ("collectgarbage", base_collectgarbage),
+    ("dofile", base_dofile),
+    ("error", base_error),
+    ("getmetatable", base_getmetatable),
+    ("ipairs", base_ipairs),
+    ("loadfile", base_loadfile),
+    ("load", base_load),
+    ("next", base_next),
+    ("pairs", base_pairs),
+    ("pcall", base_pcall),
+    ("print", base_print),
+    ("warn", base_warn),
+    ("rawequal", base_rawequal),
+    ("rawlen", base_rawlen),
+    ("rawget", base_rawget),
+    ("rawset", base_rawset),
+    ("select", base_select),
+    ("setmetatable", base_setmetatable),
+    ("tonumber", base_tonumber),
+    ("tostring", base_tostring),
+    ("type", base_type),
+    ("xpcall", base_xpcall),
+];
+
+// Register the basic functions, _G and _VERSION in the table `_G` of
+// package.loaded
+pub fn open_base_lib(state: &mut LuaState) {
+    let g = state.lib_table(crate::lauxlib::LUA_GNAME);
+    state.set_funcs(&g, BASE_FUNCS);
+    let mut t = g.borrow_mut();
+    t.rawset(&LuaValue::Str(crate::lauxlib::LUA_GNAME.to_string()), LuaValue::Table(g.clone()));
+    t.rawset(&LuaValue::Str("_VERSION".to_string()), LuaValue::Str(LUA_VERSION.to_string()));
+}
+
+// Set the stack top to argument `n` (lua_settop), padding with nil
+fn set_top(state: &mut LuaState, n: usize) {
+    let base = state.ci.borrow().func + 1;
+    state.stack.resize_with(base + n, || LuaValue::Nil);
+}
+
+fn is_truthy(v: &LuaValue) -> bool {
+    !matches!(v, LuaValue::Nil | LuaValue::Bool(false))
+}
+
+/// `v` as tostring shows it (luaL_tolstring): the result of __tostring,
+/// which must be a string, else "name: 0x..." for values whose metatable
+/// has a string __name, else the value itself or "type: 0x..."
+pub fn tostring_value(state: &mut LuaState, v: &LuaValue) -> String {
+    if let Some(f) = get_metafield(v, "__tostring") {
+        return match crate::ltm::call_tm_vm(state, &f, std::slice::from_ref(v)) {
+            Some(LuaValue::Str(s)) => s,
+            _ => state.error("'__tostring' must return a string"),
+        };
+    }
+    match v {
+        LuaValue::Nil => "nil".to_string(),
+        LuaValue::Bool(b) => b.to_string(),
+        LuaValue::Int(i) => crate::lobject::luaO_int2str(*i),
+        LuaValue::Float(f) => crate::lobject::luaO_num2str_dot(*f),
+        LuaValue::Str(s) => s.clone(),
+        LuaValue::Pointer(p) => format!("userdata: {:p}", *p),
+        _ => {
+            let kind = match get_metafield(v, "__name") {
+                Some(LuaValue::Str(name)) => name,
+                _ => obj_typename(v).to_string(),
+            };
+            let p = ObjectId::of(v).map_or(std::ptr::null(), ObjectId::as_ptr);
+            format!("{}: {:p}", kind, p)
+        }
+    }
 }
 
-// print implementation
-unsafe extern "C" fn luaB_print(L: *mut lua_State) -> c_int {
-    let n = lua_gettop(L);
-    for i in 1..=n {
-        let mut l = 0;
-        let s = luaL_tolstring(L, i, &mut l);
+// print(...)
+// One write of the whole line to the state's output
+pub fn base_print(state: &mut LuaState) -> i32 {
+    let mut line = Vec::new();
+    for i in 1..=state.get_top() {
+        let v = state.to_value(i);
         if i > 1 {
-            lua_writestring(b"\t".as_ptr() as *const c_char, 1);
+            line.push(b'\t');
         }
-        lua_writestring(s, l);
-        lua_pop(L, 1);
+        line.extend_from_slice(tostring_value(state, &v).as_bytes());
     }
-    lua_writeline();
+    line.push(b'\n');
+    let _ = state.write_output(&line);
     0
 }
 
-// warn implementation
-unsafe extern "C" fn luaB_warn(L: *mut lua_State) -> c_int {
-    let n = lua_gettop(L);
-    luaL_checkstring(L, 1);
+// warn(msg1, ...)
+pub fn base_warn(state: &mut LuaState) -> i32 {
+    let n = state.get_top();
+    let mut msg = state.check_string(1);
     for i in 2..=n {
-        luaL_checkstring(L, i);
-    }
-    for i in 1..n {
-        lua_warning(L, lua_tostring(L, i), 1);
+        msg.push_str(&state.check_string(i));
     }
-    lua_warning(L, lua_tostring(L, n), 0);
+    crate::lstate::luaE_warning(state, &msg, false);
     0
 }
 
-
-#define SPACECHARS	" \f\n\r\t\v"
-
-static const char *b_str2int (const char *s, unsigned base, lua_Integer *pn) {
-  lua_Unsigned n = 0;
-  int neg = 0;
-  s += strspn(s, SPACECHARS);  /* skip initial spaces */
-  if (*s == '-') { s++; neg = 1; }  /* handle sign */
-  else if (*s == '+') s++;
-  if (!isalnum(cast_uchar(*s)))  /* no digit? */
-    return NULL;
-  do {
-    unsigned digit = cast_uint(isdigit(cast_uchar(*s))
-                               ? *s - '0'
-                               : (toupper(cast_uchar(*s)) - 'A') + 10);
-    if (digit >= base) return NULL;  /* invalid numeral */
-    n = n * base + digit;
-    s++;
-  } while (isalnum(cast_uchar(*s)));
-  s += strspn(s, SPACECHARS);  /* skip trailing spaces */
-  *pn = (lua_Integer)((neg) ? (0u - n) : n);
-  return s;
-}
-
-
-static int luaB_tonumber (lua_State *L) {
-  if (lua_isnoneornil(L, 2)) {  /* standard conversion? */
-    if (lua_type(L, 1) == LUA_TNUMBER) {  /* already a number? */
-      lua_settop(L, 1);  /* yes; return it */
-      return 1;
+// tonumber(e [, base])
+// Standard conversion (decimal/hex numerals, including hex floats), or
+// integer conversion in base 2..36
+pub fn base_tonumber(state: &mut LuaState) -> i32 {
+    if state.is_none_or_nil(2) {
+        // standard conversion?
+        let n = match state.arg(1) {
+            Some(v @ (LuaValue::Int(_) | LuaValue::Float(_))) => Some(v.clone()), // already a number
+            Some(LuaValue::Str(s)) => crate::lobject::luaO_str2number(s).map(|n| match n {
+                Numeral::Int(i) => LuaValue::Int(i),
+                Numeral::Float(f) => LuaValue::Float(f),
+            }),
+            Some(_) => None,
+            None => state.arg_error(1, "value expected"), // (but there must be some parameter)
+        };
+        state.push(n.unwrap_or(LuaValue::Nil));
+        return 1;
     }
-    else {
-      size_t l;
-      const char *s = lua_tolstring(L, 1, &l);
-      if (s != NULL && lua_stringtonumber(L, s) == l + 1)
-        return 1;  /* successful conversion to number */
-      /* else not a number */
-      luaL_checkany(L, 1);  /* (but there must be some parameter) */
+    let base = state.check_integer(2);
+    let Some(LuaValue::Str(s)) = state.arg(1).cloned() else {
+        state.type_error(1, "string") // no numbers as strings
+    };
+    if !(2..=36).contains(&base) {
+        state.arg_error(2, "base out of range");
+    }
+    let n = crate::lobject::luaO_str2int_base(&s, base as u32);
+    state.push(n.map_or(LuaValue::Nil, LuaValue::Int)); // fail: not a number
+    1
+}
+
+// error(message [, level])
+// A string message gets the position of level `level` (1, the function
+// that called error, by default; 0 for none)
+pub fn base_error(state: &mut LuaState) -> i32 {
+    let level = state.opt_integer(2, 1);
+    let msg = state.to_value(1);
+    match msg {
+        LuaValue::Str(s) if level > 0 => {
+            let L = state as *mut LuaState as *mut crate::lauxlib::lua_State;
+            unsafe { crate::lauxlib::luaL_where(L, level as i32) };
+            let pos = match state.pop() {
+                Some(LuaValue::Str(pos)) => pos,
+                _ => String::new(),
+            };
+            state.throw(LuaValue::Str(pos + &s))
+        }
+        msg => state.throw(msg),
     }
-  }
-  else {
-    size_t l;
-    const char *s;
-    lua_Integer n = 0;  /* to avoid warnings */
-    lua_Integer base = luaL_checkinteger(L, 2);
-    luaL_checktype(L, 1, LUA_TSTRING);  /* no numbers as strings */
-    s = lua_tolstring(L, 1, &l);
-    luaL_argcheck(L, 2 <= base && base <= 36, 2, "base out of range");
-    if (b_str2int(s, cast_uint(base), &n) == s + l) {
-      lua_pushinteger(L, n);
-      return 1;
-    }  /* else not a number */
-  }  /* else not a number */
-  luaL_pushfail(L);  /* not a number */
-  return 1;
-}
-
-
-static int luaB_error (lua_State *L) {
-  int level = (int)luaL_optinteger(L, 2, 1);
-  lua_settop(L, 1);
-  if (lua_type(L, 1) == LUA_TSTRING && level > 0) {
-    luaL_where(L, level);   /* add extra information */
-    lua_pushvalue(L, 1);
-    lua_concat(L, 2);
-  }
-  return lua_error(L);
-}
-
-
-static int luaB_getmetatable (lua_State *L) {
-  luaL_checkany(L, 1);
-  if (!lua_getmetatable(L, 1)) {
-    lua_pushnil(L);
-    return 1;  /* no metatable */
-  }
-  luaL_getmetafield(L, 1, "__metatable");
-  return 1;  /* returns either __metatable field (if present) or metatable */
-}
-
-
-static int luaB_setmetatable (lua_State *L) {
-  int t = lua_type(L, 2);
-  luaL_checktype(L, 1, LUA_TTABLE);
-  luaL_argexpected(L, t == LUA_TNIL || t == LUA_TTABLE, 2, "nil or table");
-  if (l_unlikely(luaL_getmetafield(L, 1, "__metatable") != LUA_TNIL))
-    return luaL_error(L, "cannot change a protected metatable");
-  lua_settop(L, 2);
-  lua_setmetatable(L, 1);
-  return 1;
-}
-
-
-static int luaB_rawequal (lua_State *L) {
-  luaL_checkany(L, 1);
-  luaL_checkany(L, 2);
-  lua_pushboolean(L, lua_rawequal(L, 1, 2));
-  return 1;
-}
-
-
-static int luaB_rawlen (lua_State *L) {
-  int t = lua_type(L, 1);
-  luaL_argexpected(L, t == LUA_TTABLE || t == LUA_TSTRING, 1,
-                      "table or string");
-  lua_pushinteger(L, l_castU2S(lua_rawlen(L, 1)));
-  return 1;
-}
-
-
-static int luaB_rawget (lua_State *L) {
-  luaL_checktype(L, 1, LUA_TTABLE);
-  luaL_checkany(L, 2);
-  lua_settop(L, 2);
-  lua_rawget(L, 1);
-  return 1;
-}
-
-static int luaB_rawset (lua_State *L) {
-  luaL_checktype(L, 1, LUA_TTABLE);
-  luaL_checkany(L, 2);
-  luaL_checkany(L, 3);
-  lua_settop(L, 3);
-  lua_rawset(L, 1);
-  return 1;
-}
-
-
-static int pushmode (lua_State *L, int oldmode) {
-  if (oldmode == -1)
-    luaL_pushfail(L);  /* invalid call to 'lua_gc' */
-  else
-    lua_pushstring(L, (oldmode == LUA_GCINC) ? "incremental"
-                                             : "generational");
-  return 1;
 }
 
-
-/*
-** check whether call to 'lua_gc' was valid (not inside a finalizer)
-*/
-#define checkvalres(res) { if (res == -1) break; }
-
-static int luaB_collectgarbage (lua_State *L) {
-  static const char *const opts[] = {"stop", "restart", "collect",
-    "count", "step", "isrunning", "generational", "incremental",
-    "param", NULL};
-  static const char optsnum[] = {LUA_GCSTOP, LUA_GCRESTART, LUA_GCCOLLECT,
-    LUA_GCCOUNT, LUA_GCSTEP, LUA_GCISRUNNING, LUA_GCGEN, LUA_GCINC,
-    LUA_GCPARAM};
-  int o = optsnum[luaL_checkoption(L, 1, "collect", opts)];
-  switch (o) {
-    case LUA_GCCOUNT: {
-      int k = lua_gc(L, o);
-      int b = lua_gc(L, LUA_GCCOUNTB);
-      checkvalres(k);
-      lua_pushnumber(L, (lua_Number)k + ((lua_Number)b/1024));
-      return 1;
+// getmetatable(object)
+// The __metatable field of the metatable if present, else the metatable
+pub fn base_getmetatable(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    let v = state.to_value(1);
+    let mt = match get_metafield(&v, "__metatable") {
+        Some(protected) => protected,
+        None => state.get_value_metatable(&v).cloned().unwrap_or(LuaValue::Nil), // nil: no metatable
+    };
+    state.push(mt);
+    1
+}
+
+// setmetatable(table, metatable)
+pub fn base_setmetatable(state: &mut LuaState) -> i32 {
+    let t = state.to_value(1);
+    if !matches!(t, LuaValue::Table(_)) {
+        state.type_error(1, "table");
     }
-    case LUA_GCSTEP: {
-      lua_Integer n = luaL_optinteger(L, 2, 0);
-      int res = lua_gc(L, o, cast_sizet(n));
-      checkvalres(res);
-      lua_pushboolean(L, res);
-      return 1;
+    let mt = state.to_value(2);
+    if !matches!(mt, LuaValue::Nil | LuaValue::Table(_)) {
+        state.type_error(2, "nil or table");
     }
-    case LUA_GCISRUNNING: {
-      int res = lua_gc(L, o);
-      checkvalres(res);
-      lua_pushboolean(L, res);
-      return 1;
+    if get_metafield(&t, "__metatable").is_some() {
+        state.error("cannot change a protected metatable");
     }
-    case LUA_GCGEN: {
-      return pushmode(L, lua_gc(L, o));
+    state.set_value_metatable(&t, mt);
+    state.push(t);
+    1
+}
+
+// rawequal(v1, v2)
+pub fn base_rawequal(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    state.check_any(2);
+    let eq = raw_equal(&state.to_value(1), &state.to_value(2));
+    state.push(LuaValue::Bool(eq));
+    1
+}
+
+// rawlen(v)
+pub fn base_rawlen(state: &mut LuaState) -> i32 {
+    let n = match state.arg(1) {
+        Some(LuaValue::Table(t)) => t.borrow().len(),
+        Some(LuaValue::Str(s)) => s.len(),
+        _ => state.arg_error(1, "table or string expected"),
+    };
+    state.push(LuaValue::Int(n as i64));
+    1
+}
+
+fn check_table(state: &mut LuaState, arg: i32) -> Rc<RefCell<Table>> {
+    match state.arg(arg) {
+        Some(LuaValue::Table(t)) => t.clone(),
+        _ => state.type_error(arg, "table"),
     }
-    case LUA_GCINC: {
-      return pushmode(L, lua_gc(L, o));
+}
+
+// rawget(table, index)
+pub fn base_rawget(state: &mut LuaState) -> i32 {
+    let t = check_table(state, 1);
+    state.check_any(2);
+    let v = t.borrow().get(&state.to_value(2)).cloned().unwrap_or(LuaValue::Nil);
+    state.push(v);
+    1
+}
+
+// rawset(table, index, value)
+pub fn base_rawset(state: &mut LuaState) -> i32 {
+    let t = check_table(state, 1);
+    state.check_any(2);
+    state.check_any(3);
+    let (k, v) = (state.to_value(2), state.to_value(3));
+    let writable = t.borrow().check_writable();
+    if let Err(msg) = writable {
+        state.error(msg);
     }
-    case LUA_GCPARAM: {
-      static const char *const params[] = {
-        "minormul", "majorminor", "minormajor",
-        "pause", "stepmul", "stepsize", NULL};
-      static const char pnum[] = {
-        LUA_GCPMINORMUL, LUA_GCPMAJORMINOR, LUA_GCPMINORMAJOR,
-        LUA_GCPPAUSE, LUA_GCPSTEPMUL, LUA_GCPSTEPSIZE};
-      int p = pnum[luaL_checkoption(L, 2, NULL, params)];
-      lua_Integer value = luaL_optinteger(L, 3, -1);
-      lua_pushinteger(L, lua_gc(L, o, p, (int)value));
-      return 1;
+    match k {
+        LuaValue::Nil => state.error("index is nil"),
+        LuaValue::Float(f) if f.is_nan() => state.error("index is NaN"),
+        _ => t.borrow_mut().rawset(&k, v),
     }
-    default: {
-      int res = lua_gc(L, o);
-      checkvalres(res);
-      lua_pushinteger(L, res);
-      return 1;
+    state.push(LuaValue::Table(t));
+    1
+}
+
+// collectgarbage([opt [, arg]])
+pub fn base_collectgarbage(state: &mut LuaState) -> i32 {
+    const OPTS: &[&str] = &["stop", "restart", "collect", "count", "step", "isrunning", "generational", "incremental"];
+    let o = state.check_option(1, Some("collect"), OPTS);
+    let res = match OPTS[o] {
+        "stop" | "restart" => {
+            state.l_G.borrow_mut().gc_paused = OPTS[o] == "stop";
+            LuaValue::Int(0)
+        }
+        "count" => LuaValue::Float(state.l_G.borrow().total_bytes() as f64 / 1024.0),
+        "step" => {
+            state.l_G.borrow_mut().gc_collect();
+            LuaValue::Bool(true) // a step always finishes a cycle here
+        }
+        "isrunning" => LuaValue::Bool(!state.l_G.borrow().gc_paused),
+        // only the incremental mode exists; report it as the previous mode
+        "generational" | "incremental" => LuaValue::Str("incremental".to_string()),
+        _ => {
+            state.l_G.borrow_mut().gc_collect();
+            LuaValue::Int(0)
+        }
+    };
+    state.push(res);
+    1
+}
+
+// type(v)
+pub fn base_type(state: &mut LuaState) -> i32 {
+    let Some(v) = state.arg(1) else { state.arg_error(1, "value expected") };
+    let name = obj_typename(v).to_string();
+    state.push(LuaValue::Str(name));
+    1
+}
+
+// next(table [, index])
+pub fn base_next(state: &mut LuaState) -> i32 {
+    let t = check_table(state, 1);
+    let k = state.to_value(2);
+    let last = if matches!(k, LuaValue::Nil) { None } else { Some(&k) };
+    let entry = t.borrow().next(last).map(|(k, v)| (k, v.clone()));
+    match entry {
+        Some((k, v)) => {
+            state.push(k);
+            state.push(v);
+            2
+        }
+        None => {
+            state.push(LuaValue::Nil);
+            1
+        }
     }
-  }
-  luaL_pushfail(L);  /* invalid call (inside a finalizer) */
-  return 1;
 }
 
-
-static int luaB_type (lua_State *L) {
-  int t = lua_type(L, 1);
-  luaL_argcheck(L, t != LUA_TNONE, 1, "value expected");
-  lua_pushstring(L, lua_typename(L, t));
-  return 1;
+fn lib_function(f: fn(&mut LuaState) -> i32) -> LuaValue {
+    LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(f)))
 }
 
-
-static int luaB_next (lua_State *L) {
-  luaL_checktype(L, 1, LUA_TTABLE);
-  lua_settop(L, 2);  /* create a 2nd argument if there isn't one */
-  if (lua_next(L, 1))
-    return 2;
-  else {
-    lua_pushnil(L);
-    return 1;
-  }
+// pairs(t)
+// Honours the '__pairs' metamethod (userdata, proxy tables), otherwise
+// returns the raw 'next' generator
+pub fn base_pairs(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    let t = state.to_value(1);
+    match get_metafield(&t, "__pairs") {
+        None => {
+            // no metamethod: return generator, state and initial value
+            state.push(lib_function(base_next));
+            state.push(t);
+            state.push(LuaValue::Nil);
+        }
+        Some(h) => {
+            state.push(h);
+            state.push(t); // argument 'self' to metamethod
+            state.call(1, 3); // get 3 values from metamethod
+        }
+    }
+    3
+}
+
+// Traversal function for 'ipairs'. Reads through __index and stops at the
+// first nil
+fn ipairs_aux(state: &mut LuaState) -> i32 {
+    let i = state.check_integer(2).wrapping_add(1);
+    let t = state.to_value(1);
+    let v = crate::lvm::luaV_finishget(state, &t, &LuaValue::Int(i));
+    if matches!(v, LuaValue::Nil) {
+        state.push(LuaValue::Nil);
+        return 1;
+    }
+    state.push(LuaValue::Int(i));
+    state.push(v);
+    2
+}
+
+// ipairs(t)
+// Returns 'ipairs_aux', the given value, and 0. (The given value may not
+// be a table.)
+pub fn base_ipairs(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    let t = state.to_value(1);
+    state.push(lib_function(ipairs_aux)); // iteration function
+    state.push(t); // state
+    state.push(LuaValue::Int(0)); // initial value
+    3
+}
+
+// Result of load/loadfile: the chunk, or fail plus the error message
+fn load_aux(state: &mut LuaState, res: crate::lerror::Result<()>) -> i32 {
+    match res {
+        Ok(()) => 1,
+        Err(e) => {
+            state.push(LuaValue::Nil);
+            state.push(LuaValue::Str(e.to_string()));
+            2 // return fail plus error message
+        }
+    }
 }
 
-
-static int pairscont (lua_State *L, int status, lua_KContext k) {
-  (void)L; (void)status; (void)k;  /* unused */
-  return 3;
+// 'mode' argument of load/loadfile ("bt" by default)
+fn get_mode(state: &mut LuaState, arg: i32) -> String {
+    let mode = state.opt_string(arg, "bt");
+    if mode.contains('B') {
+        // Lua code cannot use fixed buffers
+        state.arg_error(arg, "invalid mode");
+    }
+    mode
 }
 
-static int luaB_pairs (lua_State *L) {
-  luaL_checkany(L, 1);
-  if (luaL_getmetafield(L, 1, "__pairs") == LUA_TNIL) {  /* no metamethod? */
-    lua_pushcfunction(L, luaB_next);  /* will return generator, */
-    lua_pushvalue(L, 1);  /* state, */
-    lua_pushnil(L);  /* and initial value */
-  }
-  else {
-    lua_pushvalue(L, 1);  /* argument 'self' to metamethod */
-    lua_callk(L, 1, 3, 0, pairscont);  /* get 3 values from metamethod */
-  }
-  return 3;
+// 'env' argument of load/loadfile: the chunk's _ENV when given, even as nil
+fn get_env(state: &LuaState, arg: i32) -> Option<LuaValue> {
+    state.arg(arg).cloned()
 }
 
-
-/*
-** Traversal function for 'ipairs'
-*/
-static int ipairsaux (lua_State *L) {
-  lua_Integer i = luaL_checkinteger(L, 2);
-  i = luaL_intop(+, i, 1);
-  lua_pushinteger(L, i);
-  return (lua_geti(L, 1, i) == LUA_TNIL) ? 1 : 2;
+// Chunk in file `fname` (standard input if None), loaded with `opts`
+// (luaL_loadfilex). Files are read through the state's Vfs
+pub(crate) fn load_file(state: &mut LuaState, fname: Option<&str>, opts: &mut LoadOptions) -> crate::lerror::Result<()> {
+    let data = match fname {
+        Some(f) => {
+            opts.chunkname = format!("@{}", f);
+            state.vfs().read(f).map_err(|e| crate::lerror::Error::File(format!("cannot open {}: {}", f, e)))?
+        }
+        None => {
+            use std::io::Read;
+            opts.chunkname = "=stdin".to_string();
+            let mut buf = Vec::new();
+            std::io::stdin().read_to_end(&mut buf).map_err(|e| crate::lerror::Error::File(format!("cannot read stdin: {}", e)))?;
+            buf
+        }
+    };
+    // skip a first line starting with '#' (a Unix exec line), keeping
+    // the newline so line numbers stay right
+    let start = if data.first() == Some(&b'#') { data.iter().position(|&c| c == b'\n').unwrap_or(data.len()) } else { 0 };
+    state.load_buffer_with(&data[start..], opts)
+}
+
+// loadfile([filename [, mode [, env]]])
+pub fn base_loadfile(state: &mut LuaState) -> i32 {
+    let fname = if state.is_none_or_nil(1) { None } else { Some(state.check_string(1)) };
+    let mut opts = LoadOptions { mode: get_mode(state, 2), env: get_env(state, 3), ..LoadOptions::default() };
+    let res = load_file(state, fname.as_deref(), &mut opts);
+    load_aux(state, res)
+}
+
+// Pieces of a chunk from the reader function at argument 1, called until
+// it returns nil or an empty string
+fn read_chunk(state: &mut LuaState) -> Vec<u8> {
+    let reader = state.to_value(1);
+    let mut data = Vec::new();
+    loop {
+        state.push(reader.clone());
+        state.call(0, 1);
+        match state.pop() {
+            Some(LuaValue::Str(s)) if !s.is_empty() => data.extend_from_slice(s.as_bytes()),
+            Some(LuaValue::Str(_)) | Some(LuaValue::Nil) | None => return data,
+            Some(_) => state.error("reader function must return a string"),
+        }
+    }
 }
 
-
-/*
-** 'ipairs' function. Returns 'ipairsaux', given "table", 0.
-** (The given "table" may not be a table.)
-*/
-static int luaB_ipairs (lua_State *L) {
-  luaL_checkany(L, 1);
-  lua_pushcfunction(L, ipairsaux);  /* iteration function */
-  lua_pushvalue(L, 1);  /* state */
-  lua_pushinteger(L, 0);  /* initial value */
-  return 3;
+// load(chunk [, chunkname [, mode [, env]]])
+// `chunk` is a string or a function returning its pieces; with `env`, the
+// chunk runs with it as _ENV instead of the global table
+pub fn base_load(state: &mut LuaState) -> i32 {
+    let mode = get_mode(state, 3);
+    let env = get_env(state, 4);
+    let (data, chunkname) = match state.to_value(1) {
+        LuaValue::Str(s) => {
+            // loading a string?
+            let chunkname = state.opt_string(2, &s);
+            (s.into_bytes(), chunkname)
+        }
+        LuaValue::Function(_) => {
+            // loading from a reader function
+            let chunkname = state.opt_string(2, "=(load)");
+            (read_chunk(state), chunkname)
+        }
+        _ => state.type_error(1, "string or function"),
+    };
+    let opts = LoadOptions { chunkname, mode, source_map: None, env };
+    let res = state.load_buffer_with(&data, &opts);
+    load_aux(state, res)
+}
+
+// dofile([filename])
+pub fn base_dofile(state: &mut LuaState) -> i32 {
+    let fname = if state.is_none_or_nil(1) { None } else { Some(state.check_string(1)) };
+    set_top(state, 1);
+    let mut opts = LoadOptions::default();
+    if let Err(e) = load_file(state, fname.as_deref(), &mut opts) {
+        state.raise(e);
+    }
+    state.call(0, LUA_MULTRET);
+    state.get_top() - 1
 }
 
-
-static int load_aux (lua_State *L, int status, int envidx) {
-  if (l_likely(status == LUA_OK)) {
-    if (envidx != 0) {  /* 'env' parameter? */
-      lua_pushvalue(L, envidx);  /* environment for loaded function */
-      if (!lua_setupvalue(L, -2, 1))  /* set it as 1st upvalue */
-        lua_pop(L, 1);  /* remove 'env' if not used by previous call */
+// assert(v [, message])
+pub fn base_assert(state: &mut LuaState) -> i32 {
+    if state.arg(1).is_some_and(is_truthy) {
+        return state.get_top(); // return all arguments
+    }
+    state.check_any(1); // there must be a condition
+    let msg = state.arg(2).cloned().unwrap_or_else(|| LuaValue::Str("assertion failed!".to_string()));
+    set_top(state, 0);
+    state.push(msg); // leave only message (default if no other one)
+    base_error(state) // call 'error'
+}
+
+// select(n, ...)
+// select('#', ...) returns the vararg count, select(n, ...) returns all
+// arguments after the n-th (negative n counts from the end)
+pub fn base_select(state: &mut LuaState) -> i32 {
+    let n = state.get_top() as i64;
+    if matches!(state.arg(1), Some(LuaValue::Str(s)) if s.starts_with('#')) {
+        state.push(LuaValue::Int(n - 1));
+        return 1;
+    }
+    let mut i = state.check_integer(1);
+    if i < 0 {
+        i += n;
+    } else if i > n {
+        i = n;
+    }
+    if i < 1 {
+        state.arg_error(1, "index out of range");
+    }
+    (n - i) as i32
+}
+
+// Call the function at argument `first` with the arguments above it in
+// protected mode; on success `true` goes before its results, on error
+// the results are false and the error object
+fn finish_pcall(state: &mut LuaState, first: usize, msgh: Option<LuaValue>) -> i32 {
+    let base = state.ci.borrow().func + first;
+    let nargs = state.stack.len() - base - 1;
+    // the handler of an enclosing xpcall does not see errors caught here
+    let old = std::mem::replace(&mut state.errfunc, msgh);
+    let result = state.pcall(|L| L.call(nargs, LUA_MULTRET));
+    state.errfunc = old;
+    match result {
+        Ok(()) => {
+            state.stack.insert(base, LuaValue::Bool(true)); // first result
+            (state.stack.len() - base) as i32
+        }
+        Err(e) => {
+            state.stack.truncate(base);
+            state.push(LuaValue::Bool(false));
+            state.push(e.into_value());
+            2 // return false, msg
+        }
     }
-    return 1;
-  }
-  else {  /* error (message is on top of the stack) */
-    luaL_pushfail(L);
-    lua_insert(L, -2);  /* put before error message */
-    return 2;  /* return fail plus error message */
-  }
 }
 
-
-static const char *getMode (lua_State *L, int idx) {
-  const char *mode = luaL_optstring(L, idx, "bt");
-  if (strchr(mode, 'B') != NULL)  /* Lua code cannot use fixed buffers */
-    luaL_argerror(L, idx, "invalid mode");
-  return mode;
+// pcall(f, ...)
+pub fn base_pcall(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    finish_pcall(state, 1, None)
 }
 
-
-static int luaB_loadfile (lua_State *L) {
-  const char *fname = luaL_optstring(L, 1, NULL);
-  const char *mode = getMode(L, 2);
-  int env = (!lua_isnone(L, 3) ? 3 : 0);  /* 'env' index or 0 if no 'env' */
-  int status = luaL_loadfilex(L, fname, mode);
-  return load_aux(L, status, env);
+// xpcall(f, msgh, ...)
+// The error object goes through `msgh` before it is returned
+pub fn base_xpcall(state: &mut LuaState) -> i32 {
+    if !matches!(state.arg(2), Some(LuaValue::Function(_))) {
+        state.type_error(2, "function"); // check error function
+    }
+    let msgh = state.stack.remove(state.ci.borrow().func + 2);
+    finish_pcall(state, 1, Some(msgh))
 }
 
-
-/*
-** {======================================================
-** Generic Read function
-** =======================================================
-*/
-
-
-/*
-** reserved slot, above all arguments, to hold a copy of the returned
-** string to avoid it being collected while parsed. 'load' has four
-** optional arguments (chunk, source name, mode, and environment).
-*/
-#define RESERVEDSLOT	5
-
-
-/*
-** Reader for generic 'load' function: 'lua_load' uses the
-** stack for internal stuff, so the reader cannot change the
-** stack top. Instead, it keeps its resulting string in a
-** reserved slot inside the stack.
-*/
-static const char *generic_reader (lua_State *L, void *ud, size_t *size) {
-  (void)(ud);  /* not used */
-  luaL_checkstack(L, 2, "too many nested functions");
-  lua_pushvalue(L, 1);  /* get function */
-  lua_call(L, 0, 1);  /* call it */
-  if (lua_isnil(L, -1)) {
-    lua_pop(L, 1);  /* pop result */
-    *size = 0;
-    return NULL;
-  }
-  else if (l_unlikely(!lua_isstring(L, -1)))
-    luaL_error(L, "reader function must return a string");
-  lua_replace(L, RESERVEDSLOT);  /* save string in reserved slot */
-  return lua_tolstring(L, RESERVEDSLOT, size);
-}
-
-
-static int luaB_load (lua_State *L) {
-  int status;
-  size_t l;
-  const char *s = lua_tolstring(L, 1, &l);
-  const char *mode = getMode(L, 3);
-  int env = (!lua_isnone(L, 4) ? 4 : 0);  /* 'env' index or 0 if no 'env' */
-  if (s != NULL) {  /* loading a string? */
-    const char *chunkname = luaL_optstring(L, 2, s);
-    status = luaL_loadbufferx(L, s, l, chunkname, mode);
-  }
-  else {  /* loading from a reader function */
-    const char *chunkname = luaL_optstring(L, 2, "=(load)");
-    luaL_checktype(L, 1, LUA_TFUNCTION);
-    lua_settop(L, RESERVEDSLOT);  /* create reserved slot */
-    status = lua_load(L, generic_reader, NULL, chunkname, mode);
-  }
-  return load_aux(L, status, env);
-}
-
-/* }====================================================== */
-
-
-static int dofilecont (lua_State *L, int d1, lua_KContext d2) {
-  (void)d1;  (void)d2;  /* only to match 'lua_Kfunction' prototype */
-  return lua_gettop(L) - 1;
-}
-
-
-static int luaB_dofile (lua_State *L) {
-  const char *fname = luaL_optstring(L, 1, NULL);
-  lua_settop(L, 1);
-  if (l_unlikely(luaL_loadfile(L, fname) != LUA_OK))
-    return lua_error(L);
-  lua_callk(L, 0, LUA_MULTRET, 0, dofilecont);
-  return dofilecont(L, 0, 0);
-}
-
-
-static int luaB_assert (lua_State *L) {
-  if (l_likely(lua_toboolean(L, 1)))  /* condition is true? */
-    return lua_gettop(L);  /* return all arguments */
-  else {  /* error */
-    luaL_checkany(L, 1);  /* there must be a condition */
-    lua_remove(L, 1);  /* remove it */
-    lua_pushliteral(L, "assertion failed!");  /* default message */
-    lua_settop(L, 1);  /* leave only message (default if no other one) */
-    return luaB_error(L);  /* call 'error' */
-  }
-}
-
-
-static int luaB_select (lua_State *L) {
-  int n = lua_gettop(L);
-  if (lua_type(L, 1) == LUA_TSTRING && *lua_tostring(L, 1) == '#') {
-    lua_pushinteger(L, n-1);
-    return 1;
-  }
-  else {
-    lua_Integer i = luaL_checkinteger(L, 1);
-    if (i < 0) i = n + i;
-    else if (i > n) i = n;
-    luaL_argcheck(L, 1 <= i, 1, "index out of range");
-    return n - (int)i;
-  }
+// tostring(v)
+pub fn base_tostring(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    let v = state.to_value(1);
+    let s = tostring_value(state, &v);
+    state.push(LuaValue::Str(s));
+    1
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lauxlib::call_lib;
+    use crate::lstate::GlobalState;
 
-/*
-** Continuation function for 'pcall' and 'xpcall'. Both functions
-** already pushed a 'true' before doing the call, so in case of success
-** 'finishpcall' only has to return everything in the stack minus
-** 'extra' values (where 'extra' is exactly the number of items to be
-** ignored).
-*/
-static int finishpcall (lua_State *L, int status, lua_KContext extra) {
-  if (l_unlikely(status != LUA_OK && status != LUA_YIELD)) {  /* error? */
-    lua_pushboolean(L, 0);  /* first result (false) */
-    lua_pushvalue(L, -2);  /* error message */
-    return 2;  /* return false, msg */
-  }
-  else
-    return lua_gettop(L) - (int)extra;  /* return all results */
-}
+    fn s(x: &str) -> LuaValue {
+        LuaValue::Str(x.to_string())
+    }
 
+    #[test]
+    fn test_select_tonumber_tostring() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let args = vec![s("#"), LuaValue::Nil, LuaValue::Nil];
+        assert_eq!(call_lib(&mut state, base_select, args), vec![LuaValue::Int(2)]);
+        assert_eq!(call_lib(&mut state, base_select, vec![LuaValue::Int(-1), s("a"), s("b")]), vec![s("b")]);
+        assert_eq!(call_lib(&mut state, base_tonumber, vec![s("0x10")]), vec![LuaValue::Int(16)]);
+        assert_eq!(call_lib(&mut state, base_tonumber, vec![s("z"), LuaValue::Int(36)]), vec![LuaValue::Int(35)]);
+        assert_eq!(call_lib(&mut state, base_tonumber, vec![s("1e")]), vec![LuaValue::Nil]);
+        assert_eq!(call_lib(&mut state, base_tostring, vec![LuaValue::Float(1.0)]), vec![s("1.0")]);
+    }
 
-static int luaB_pcall (lua_State *L) {
-  int status;
-  luaL_checkany(L, 1);
-  lua_pushboolean(L, 1);  /* first result if no errors */
-  lua_insert(L, 1);  /* put it in place */
-  status = lua_pcallk(L, lua_gettop(L) - 2, LUA_MULTRET, 0, 0, finishpcall);
-  return finishpcall(L, status, 0);
-}
+    #[test]
+    fn test_pcall_and_load_reader() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let r = call_lib(&mut state, base_pcall, vec![lib_function(base_error), s("boom"), LuaValue::Int(0)]);
+        assert_eq!(r, vec![LuaValue::Bool(false), s("boom")]);
+        let r = call_lib(&mut state, base_pcall, vec![lib_function(base_select), s("#"), LuaValue::Nil]);
+        assert_eq!(r, vec![LuaValue::Bool(true), LuaValue::Int(1)]);
+        // a reader returning a non-string is an error, not a chunk
+        let reader = LuaValue::Function(Box::new(|L: &mut LuaState| {
+            L.push(LuaValue::Bool(true));
+            1
+        }));
+        let r = call_lib(&mut state, base_pcall, vec![lib_function(base_load), reader]);
+        assert_eq!(r, vec![LuaValue::Bool(false), s("reader function must return a string")]);
+    }
 
+    #[test]
+    fn test_xpcall_runs_the_message_handler() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let handler = || LuaValue::Function(Box::new(|L: &mut LuaState| {
+            let msg = L.to_value(1);
+            L.push(LuaValue::Str(format!("handled: {}", tostring_value(L, &msg))));
+            1
+        }));
+        let r = call_lib(&mut state, base_xpcall, vec![lib_function(base_error), handler(), s("x"), LuaValue::Int(0)]);
+        assert_eq!(r, vec![LuaValue::Bool(false), s("handled: x")]);
+        assert!(state.errfunc.is_none());
+        // a pcall inside the protected call catches its errors unhandled
+        let f = LuaValue::Function(Box::new(|L: &mut LuaState| {
+            L.push(lib_function(base_pcall));
+            L.push(lib_function(base_error));
+            L.push(LuaValue::Str("inner".to_string()));
+            L.push(LuaValue::Int(0));
+            L.call(3, LUA_MULTRET);
+            2
+        }));
+        let r = call_lib(&mut state, base_xpcall, vec![f, handler()]);
+        assert_eq!(r, vec![LuaValue::Bool(true), LuaValue::Bool(false), s("inner")]);
+    }
 
-/*
-** Do a protected call with error handling. After 'lua_rotate', the
-** stack will have <f, err, true, f, [args...]>; so, the function passes
-** 2 to 'finishpcall' to skip the 2 first values when returning results.
-*/
-static int luaB_xpcall (lua_State *L) {
-  int status;
-  int n = lua_gettop(L);
-  luaL_checktype(L, 2, LUA_TFUNCTION);  /* check error function */
-  lua_pushboolean(L, 1);  /* first result */
-  lua_pushvalue(L, 1);  /* function */
-  lua_rotate(L, 3, 2);  /* move them below function's arguments */
-  status = lua_pcallk(L, n - 2, LUA_MULTRET, 2, 2, finishpcall);
-  return finishpcall(L, status, 2);
-}
-
-
-static int luaB_tostring (lua_State *L) {
-  luaL_checkany(L, 1);
-  luaL_tolstring(L, 1, NULL);
-  return 1;
-}
-
-
-static const luaL_Reg base_funcs[] = {
-  {"assert", luaB_assert},
-  {"collectgarbage", luaB_collectgarbage},
-  {"dofile", luaB_dofile},
-  {"error", luaB_error},
-  {"getmetatable", luaB_getmetatable},
-  {"ipairs", luaB_ipairs},
-  {"loadfile", luaB_loadfile},
-  {"load", luaB_load},
-  {"next", luaB_next},
-  {"pairs", luaB_pairs},
-  {"pcall", luaB_pcall},
-  {"print", luaB_print},
-  {"warn", luaB_warn},
-  {"rawequal", luaB_rawequal},
-  {"rawlen", luaB_rawlen},
-  {"rawget", luaB_rawget},
-  {"rawset", luaB_rawset},
-  {"select", luaB_select},
-  {"setmetatable", luaB_setmetatable},
-  {"tonumber", luaB_tonumber},
-  {"tostring", luaB_tostring},
-  {"type", luaB_type},
-  {"xpcall", luaB_xpcall},
-  /* placeholders */
-  {LUA_GNAME, NULL},
-  {"_VERSION", NULL},
-  {NULL, NULL}
-};
-
-
-LUAMOD_API int luaopen_base (lua_State *L) {
-  /* open lib into global table */
-  lua_pushglobaltable(L);
-  luaL_setfuncs(L, base_funcs, 0);
-  /* set global _G */
-  lua_pushvalue(L, -1);
-  lua_setfield(L, -2, LUA_GNAME);
-  /* set global _VERSION */
-  lua_pushliteral(L, LUA_VERSION);
-  lua_setfield(L, -2, "_VERSION");
-  return 1;
-}
-  luaL_setfuncs(L, base_funcs, 0);
-  /* set global _G */
-  lua_pushvalue(L, -1);
-  lua_setfield(L, -2, LUA_GNAME);
-  /* set global _VERSION */
-  lua_pushliteral(L, LUA_VERSION);
-  lua_setfield(L, -2, "_VERSION");
-  return 1;
+    #[test]
+    fn test_rawset_on_a_frozen_table() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let t = Rc::new(RefCell::new(Table::new()));
+        t.borrow_mut().freeze();
+        let args = vec![lib_function(base_rawset), LuaValue::Table(t.clone()), s("k"), LuaValue::Int(1)];
+        let r = call_lib(&mut state, base_pcall, args);
+        assert_eq!(r, vec![LuaValue::Bool(false), s(crate::ltable::READONLY_TABLE_MSG)]);
+        assert!(t.borrow().is_empty());
+    }
 }
-
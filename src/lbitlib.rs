@@ -0,0 +1,208 @@
+//! lbitlib.rs - The Lua 5.2 bit32 library
+// Ported from lbitlib.c; opened by open_libs when COMPAT_BITLIB is on, for
+// code written before the 5.3 bitwise operators. Results are integers in
+// [0, 2^32), and arguments are taken modulo 2^32 as by luaL_checkunsigned,
+// so -1 reads as 0xFFFFFFFF.
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::lualib::LUA_BITLIBNAME;
+use crate::skylaconf::{LuaFloat, LuaInteger};
+
+/// Number of bits to consider in a number
+const LUA_NBITS: u32 = 32;
+
+const ALLONES: u32 = u32::MAX;
+
+/// Mask with `n` ones (1 <= n <= LUA_NBITS)
+fn mask(n: u32) -> u32 {
+    ALLONES >> (LUA_NBITS - n)
+}
+
+/// Argument `arg` modulo 2^32 (luaL_checkunsigned in 5.2)
+fn check_unsigned(state: &mut LuaState, arg: i32) -> u32 {
+    if let Some(LuaValue::Int(i)) = state.arg(arg) {
+        return *i as u32;
+    }
+    let f = state.check_number(arg).floor();
+    let two32 = 4294967296.0 as LuaFloat;
+    (f - (f / two32).floor() * two32) as u32
+}
+
+fn push_unsigned(state: &mut LuaState, r: u32) -> i32 {
+    state.push(LuaValue::Int(r as LuaInteger));
+    1
+}
+
+/// `r` shifted left by `disp` (right if negative); shifts of 32 or more
+/// give 0
+pub fn shift(r: u32, disp: LuaInteger) -> u32 {
+    if disp < 0 {
+        r.checked_shr(disp.unsigned_abs().min(LUA_NBITS as u64) as u32).unwrap_or(0)
+    } else {
+        r.checked_shl(disp.min(LUA_NBITS as LuaInteger) as u32).unwrap_or(0)
+    }
+}
+
+/// `r` shifted right by `disp`, filling with copies of the top bit
+pub fn arshift(r: u32, disp: LuaInteger) -> u32 {
+    if disp < 0 || r & (1 << (LUA_NBITS - 1)) == 0 {
+        shift(r, -disp)
+    } else if disp >= LUA_NBITS as LuaInteger {
+        ALLONES
+    } else {
+        ((r as i32) >> disp) as u32
+    }
+}
+
+/// `r` rotated left by `disp` (right if negative)
+pub fn rotate(r: u32, disp: LuaInteger) -> u32 {
+    r.rotate_left((disp & (LUA_NBITS as LuaInteger - 1)) as u32)
+}
+
+/// Field and width arguments of extract and replace
+fn field_args(state: &mut LuaState, farg: i32) -> (u32, u32) {
+    let f = state.check_integer(farg);
+    let w = state.opt_integer(farg + 1, 1);
+    if f < 0 {
+        state.arg_error(farg, "field cannot be negative");
+    }
+    if w <= 0 {
+        state.arg_error(farg + 1, "width must be positive");
+    }
+    if f + w > LUA_NBITS as LuaInteger {
+        state.error("trying to access non-existent bits");
+    }
+    (f as u32, w as u32)
+}
+
+/// All arguments folded with `op`, starting from `init`
+fn fold_args(state: &mut LuaState, init: u32, op: fn(u32, u32) -> u32) -> u32 {
+    let n = state.get_top();
+    (1..=n).fold(init, |r, i| op(r, check_unsigned(state, i)))
+}
+
+// bit32.band(...)
+fn b_and(state: &mut LuaState) -> i32 {
+    let r = fold_args(state, ALLONES, |a, b| a & b);
+    push_unsigned(state, r)
+}
+
+// bit32.btest(...) -> band(...) ~= 0
+fn b_test(state: &mut LuaState) -> i32 {
+    let r = fold_args(state, ALLONES, |a, b| a & b);
+    state.push(LuaValue::Bool(r != 0));
+    1
+}
+
+// bit32.bor(...)
+fn b_or(state: &mut LuaState) -> i32 {
+    let r = fold_args(state, 0, |a, b| a | b);
+    push_unsigned(state, r)
+}
+
+// bit32.bxor(...)
+fn b_xor(state: &mut LuaState) -> i32 {
+    let r = fold_args(state, 0, |a, b| a ^ b);
+    push_unsigned(state, r)
+}
+
+// bit32.bnot(x)
+fn b_not(state: &mut LuaState) -> i32 {
+    let r = !check_unsigned(state, 1);
+    push_unsigned(state, r)
+}
+
+// bit32.lshift(x, disp)
+fn b_lshift(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let disp = state.check_integer(2);
+    push_unsigned(state, shift(r, disp))
+}
+
+// bit32.rshift(x, disp)
+fn b_rshift(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let disp = state.check_integer(2);
+    push_unsigned(state, shift(r, disp.saturating_neg()))
+}
+
+// bit32.arshift(x, disp)
+fn b_arshift(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let disp = state.check_integer(2);
+    push_unsigned(state, arshift(r, disp.max(-(LUA_NBITS as LuaInteger))))
+}
+
+// bit32.lrotate(x, disp)
+fn b_lrot(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let disp = state.check_integer(2);
+    push_unsigned(state, rotate(r, disp))
+}
+
+// bit32.rrotate(x, disp)
+fn b_rrot(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let disp = state.check_integer(2);
+    push_unsigned(state, rotate(r, disp.wrapping_neg()))
+}
+
+// bit32.extract(n, field [, width])
+fn b_extract(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let (f, w) = field_args(state, 2);
+    push_unsigned(state, (r >> f) & mask(w))
+}
+
+// bit32.replace(n, v, field [, width])
+fn b_replace(state: &mut LuaState) -> i32 {
+    let r = check_unsigned(state, 1);
+    let v = check_unsigned(state, 2);
+    let (f, w) = field_args(state, 3);
+    let m = mask(w);
+    push_unsigned(state, (r & !(m << f)) | ((v & m) << f))
+}
+
+const BIT_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("arshift", b_arshift),
+    ("band", b_and),
+    ("bnot", b_not),
+    ("bor", b_or),
+    ("btest", b_test),
+    ("bxor", b_xor),
+    ("extract", b_extract),
+    ("lrotate", b_lrot),
+    ("lshift", b_lshift),
+    ("replace", b_replace),
+    ("rrotate", b_rrot),
+    ("rshift", b_rshift),
+];
+
+pub fn open_bit32(state: &mut LuaState) {
+    for &(name, f) in BIT_FUNCS {
+        state.register_lib_function(LUA_BITLIBNAME, name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shifts_and_rotates() {
+        assert_eq!(shift(1, 31), 0x8000_0000);
+        assert_eq!(shift(1, 32), 0);
+        assert_eq!(shift(0x8000_0000, -31), 1);
+        assert_eq!(shift(0x8000_0000, -40), 0);
+        assert_eq!(arshift(0x8000_0000, 4), 0xF800_0000);
+        assert_eq!(arshift(0x8000_0000, 32), ALLONES);
+        assert_eq!(arshift(0x4000_0000, 30), 1);
+        assert_eq!(arshift(1, -4), 16); // negative displacement shifts left
+        assert_eq!(rotate(0x8000_0001, 1), 3);
+        assert_eq!(rotate(3, -1), 0x8000_0001);
+        assert_eq!(rotate(0x1234_5678, 32), 0x1234_5678);
+        assert_eq!(mask(1), 1);
+        assert_eq!(mask(32), ALLONES);
+    }
+}
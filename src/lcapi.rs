@@ -0,0 +1,271 @@
+//! lcapi.rs - C-ABI surface of liblua-skyla and generation of its headers
+//
+// The lua_* / luaL_* functions in lapi.rs and lauxlib.rs are exported
+// unmangled with the signatures of lua.h (Lua 5.4 layout: lua_Integer is
+// 64-bit, lua_KContext is intptr_t), so the crate can be built as a shared
+// library and dropped into existing C embeddings and luarocks modules.
+// The int32/float32 profiles change lua_Integer/lua_Number, and the
+// generated typedefs with them; the checked-in headers are the default ones.
+//
+//     cargo rustc --lib --release --crate-type cdylib
+//
+// The headers shipped in include/ are generated from the tables below by
+// `generate_lua_h` / `generate_lauxlib_h` (the tests keep them in sync with
+// both the checked-in files and the #[no_mangle] functions in lapi.rs and
+// lauxlib.rs).
+// Functions that are macros in lua.h (lua_pop, lua_newtable, lua_insert, ...)
+// are macros here too and have no symbol.
+
+/// Prototypes of the functions exported from lapi.rs that belong in lua.h
+pub const LUA_H_EXPORTS: &[&str] = &[
+    "lua_State *lua_newthread(lua_State *L)",
+    "int lua_checkstack(lua_State *L, int n)",
+    "void lua_xmove(lua_State *from, lua_State *to, int n)",
+    "int lua_gettop(lua_State *L)",
+    "void lua_settop(lua_State *L, int idx)",
+    "void lua_pushvalue(lua_State *L, int idx)",
+    "void lua_rotate(lua_State *L, int idx, int n)",
+    "void lua_copy(lua_State *L, int fromidx, int toidx)",
+    "void lua_pushnil(lua_State *L)",
+    "void lua_pushnumber(lua_State *L, lua_Number n)",
+    "void lua_pushinteger(lua_State *L, lua_Integer n)",
+    "const char *lua_pushlstring(lua_State *L, const char *s, size_t len)",
+    "const char *lua_pushstring(lua_State *L, const char *s)",
+    "void lua_pushcclosure(lua_State *L, lua_CFunction fn, int n)",
+    "void lua_pushboolean(lua_State *L, int b)",
+    "void lua_pushlightuserdata(lua_State *L, void *p)",
+    "int lua_pushthread(lua_State *L)",
+    "int lua_type(lua_State *L, int idx)",
+    "const char *lua_typename(lua_State *L, int tp)",
+    "lua_Number lua_tonumberx(lua_State *L, int idx, int *isnum)",
+    "lua_Integer lua_tointegerx(lua_State *L, int idx, int *isnum)",
+    "int lua_isinteger(lua_State *L, int idx)",
+    "int lua_toboolean(lua_State *L, int idx)",
+    "const char *lua_tolstring(lua_State *L, int idx, size_t *len)",
+    "lua_CFunction lua_tocfunction(lua_State *L, int idx)",
+    "lua_State *lua_tothread(lua_State *L, int idx)",
+    "const void *lua_topointer(lua_State *L, int idx)",
+    "int lua_rawequal(lua_State *L, int idx1, int idx2)",
+    "int lua_compare(lua_State *L, int idx1, int idx2, int op)",
+    "size_t lua_stringtonumber(lua_State *L, const char *s)",
+    "lua_Number lua_version(lua_State *L)",
+    "void lua_createtable(lua_State *L, int narr, int nrec)",
+    "void *lua_newuserdatauv(lua_State *L, size_t sz, int nuvalue)",
+    "int lua_getglobal(lua_State *L, const char *name)",
+    "void lua_setglobal(lua_State *L, const char *name)",
+    "int lua_getfield(lua_State *L, int idx, const char *k)",
+    "void lua_setfield(lua_State *L, int idx, const char *k)",
+    "int lua_pcallk(lua_State *L, int nargs, int nresults, int errfunc, lua_KContext ctx, lua_KFunction k)",
+    "void lua_callk(lua_State *L, int nargs, int nresults, lua_KContext ctx, lua_KFunction k)",
+    "int lua_yieldk(lua_State *L, int nresults, lua_KContext ctx, lua_KFunction k)",
+    "int lua_resume(lua_State *L, lua_State *from, int narg, int *nres)",
+    "int lua_status(lua_State *L)",
+    "int lua_isyieldable(lua_State *L)",
+    "int lua_error(lua_State *L)",
+    "int lua_closethread(lua_State *L, lua_State *from)",
+    "int lua_resetthread(lua_State *L)",
+];
+
+/// Prototypes of the exported auxiliary functions (lauxlib.h)
+pub const LAUXLIB_H_EXPORTS: &[&str] = &[
+    "int luaL_argerror(lua_State *L, int arg, const char *extramsg)",
+    "int luaL_typeerror(lua_State *L, int arg, const char *tname)",
+    "int luaL_getmetafield(lua_State *L, int obj, const char *e)",
+    "int luaL_newmetatable(lua_State *L, const char *tname)",
+    "void luaL_setmetatable(lua_State *L, const char *tname)",
+    "void *luaL_testudata(lua_State *L, int ud, const char *tname)",
+    "void *luaL_checkudata(lua_State *L, int ud, const char *tname)",
+    "const char *luaL_checklstring(lua_State *L, int arg, size_t *l)",
+    "const char *luaL_optlstring(lua_State *L, int arg, const char *def, size_t *l)",
+    "lua_Number luaL_checknumber(lua_State *L, int arg)",
+    "lua_Number luaL_optnumber(lua_State *L, int arg, lua_Number def)",
+    "lua_Integer luaL_checkinteger(lua_State *L, int arg)",
+    "lua_Integer luaL_optinteger(lua_State *L, int arg, lua_Integer def)",
+    "void luaL_checkstack(lua_State *L, int sz, const char *msg)",
+    "void luaL_checktype(lua_State *L, int arg, int t)",
+    "void luaL_checkany(lua_State *L, int arg)",
+    "int luaL_checkoption(lua_State *L, int arg, const char *def, const char *const lst[])",
+    "void luaL_where(lua_State *L, int lvl)",
+    "void luaL_traceback(lua_State *L, lua_State *L1, const char *msg, int level)",
+    "void luaL_addgsub(luaL_Buffer *b, const char *s, const char *p, const char *r)",
+    "const char *luaL_gsub(lua_State *L, const char *s, const char *p, const char *r)",
+    "int luaL_fileresult(lua_State *L, int stat, const char *fname)",
+    "int luaL_execresult(lua_State *L, int stat)",
+    "void luaL_setfuncs(lua_State *L, const luaL_Reg *l, int nup)",
+    "int luaL_getsubtable(lua_State *L, int idx, const char *fname)",
+    "void luaL_requiref(lua_State *L, const char *modname, lua_CFunction openf, int glb)",
+    "void luaL_checkversion_(lua_State *L, lua_Number ver, size_t sz)",
+    "int luaL_loadstring(lua_State *L, const char *s)",
+    "int luaL_loadfilex(lua_State *L, const char *filename, const char *mode)",
+];
+
+/// lua.h macros over the exported functions
+const LUA_H_MACROS: &[&str] = &[
+    "#define lua_upvalueindex(i)\t(LUA_REGISTRYINDEX - (i))",
+    "#define lua_call(L,n,r)\t\tlua_callk(L, (n), (r), 0, NULL)",
+    "#define lua_pcall(L,n,r,f)\tlua_pcallk(L, (n), (r), (f), 0, NULL)",
+    "#define lua_yield(L,n)\t\tlua_yieldk(L, (n), 0, NULL)",
+    "#define lua_tonumber(L,i)\tlua_tonumberx(L,(i),NULL)",
+    "#define lua_tointeger(L,i)\tlua_tointegerx(L,(i),NULL)",
+    "#define lua_pop(L,n)\t\tlua_settop(L, -(n)-1)",
+    "#define lua_newtable(L)\t\tlua_createtable(L, 0, 0)",
+    "#define lua_newuserdata(L,s)\tlua_newuserdatauv(L,s,1)",
+    "#define lua_pushcfunction(L,f)\tlua_pushcclosure(L, (f), 0)",
+    "#define lua_isfunction(L,n)\t(lua_type(L, (n)) == LUA_TFUNCTION)",
+    "#define lua_istable(L,n)\t(lua_type(L, (n)) == LUA_TTABLE)",
+    "#define lua_isnil(L,n)\t\t(lua_type(L, (n)) == LUA_TNIL)",
+    "#define lua_isboolean(L,n)\t(lua_type(L, (n)) == LUA_TBOOLEAN)",
+    "#define lua_isnone(L,n)\t\t(lua_type(L, (n)) == LUA_TNONE)",
+    "#define lua_isnoneornil(L, n)\t(lua_type(L, (n)) <= 0)",
+    "#define lua_pushliteral(L, s)\tlua_pushstring(L, \"\" s)",
+    "#define lua_tostring(L,i)\tlua_tolstring(L, (i), NULL)",
+    "#define lua_insert(L,idx)\tlua_rotate(L, (idx), 1)",
+    "#define lua_remove(L,idx)\t(lua_rotate(L, (idx), -1), lua_pop(L, 1))",
+    "#define lua_replace(L,idx)\t(lua_copy(L, -1, (idx)), lua_pop(L, 1))",
+];
+
+/// lauxlib.h macros over the exported functions
+const LAUXLIB_H_MACROS: &[&str] = &[
+    "#define LUAL_NUMSIZES\t\t(sizeof(lua_Integer)*16 + sizeof(lua_Number))",
+    "#define luaL_checkversion(L)  \\\n\tluaL_checkversion_(L, LUA_VERSION_NUM, LUAL_NUMSIZES)",
+    "#define luaL_newlibtable(L,l)\tlua_createtable(L, 0, sizeof(l)/sizeof((l)[0]) - 1)",
+    "#define luaL_newlib(L,l)  \\\n  (luaL_checkversion(L), luaL_newlibtable(L,l), luaL_setfuncs(L,l,0))",
+    "#define luaL_argcheck(L, cond,arg,extramsg) \\\n\t((void)((cond) || luaL_argerror(L, (arg), (extramsg))))",
+    "#define luaL_argexpected(L,cond,arg,tname) \\\n\t((void)((cond) || luaL_typeerror(L, (arg), (tname))))",
+    "#define luaL_checkstring(L,n)\t(luaL_checklstring(L, (n), NULL))",
+    "#define luaL_optstring(L,n,d)\t(luaL_optlstring(L, (n), (d), NULL))",
+    "#define luaL_typename(L,i)\tlua_typename(L, lua_type(L,(i)))",
+    "#define luaL_getmetatable(L,n)\t(lua_getfield(L, LUA_REGISTRYINDEX, (n)))",
+    "#define luaL_opt(L,f,n,d)\t(lua_isnoneornil(L,(n)) ? (d) : f(L,(n)))",
+    "#define luaL_loadfile(L,f)\tluaL_loadfilex(L,f,NULL)",
+    "#define luaL_dofile(L, fn) \\\n\t(luaL_loadfile(L, fn) || lua_pcall(L, 0, LUA_MULTRET, 0))",
+    "#define luaL_dostring(L, s) \\\n\t(luaL_loadstring(L, s) || lua_pcall(L, 0, LUA_MULTRET, 0))",
+];
+
+const GENERATED_NOTE: &str = "/* Generated by lcapi.rs (generate_lua_h / generate_lauxlib_h); do not edit. */";
+
+/// Text of include/lua.h
+pub fn generate_lua_h() -> String {
+    let mut h = String::new();
+    h.push_str(GENERATED_NOTE);
+    h.push_str("\n#ifndef lua_h\n#define lua_h\n\n#include <stdarg.h>\n#include <stddef.h>\n#include <stdint.h>\n\n");
+    h.push_str("#define LUA_VERSION_MAJOR\t\"5\"\n#define LUA_VERSION_MINOR\t\"4\"\n#define LUA_VERSION_NUM\t\t504\n\n");
+    h.push_str("#define LUA_MULTRET\t(-1)\n");
+    h.push_str(&format!("#define LUA_REGISTRYINDEX\t({})\n\n", crate::lapi::LUA_REGISTRYINDEX));
+    h.push_str("/* thread status */\n#define LUA_OK\t\t0\n#define LUA_YIELD\t1\n#define LUA_ERRRUN\t2\n#define LUA_ERRSYNTAX\t3\n#define LUA_ERRMEM\t4\n#define LUA_ERRERR\t5\n\n");
+    h.push_str("/* basic types */\n#define LUA_TNONE\t\t(-1)\n#define LUA_TNIL\t\t0\n#define LUA_TBOOLEAN\t\t1\n#define LUA_TLIGHTUSERDATA\t2\n#define LUA_TNUMBER\t\t3\n#define LUA_TSTRING\t\t4\n#define LUA_TTABLE\t\t5\n#define LUA_TFUNCTION\t\t6\n#define LUA_TUSERDATA\t\t7\n#define LUA_TTHREAD\t\t8\n\n");
+    h.push_str("/* comparison functions */\n#define LUA_OPEQ\t0\n#define LUA_OPLT\t1\n#define LUA_OPLE\t2\n\n");
+    h.push_str("typedef struct lua_State lua_State;\n\n");
+    let (int, uint) = crate::skylaconf::LUA_INTEGER_CTYPE;
+    h.push_str(&format!("typedef {} lua_Number;\ntypedef {} lua_Integer;\ntypedef {} lua_Unsigned;\ntypedef intptr_t lua_KContext;\n\n",
+        crate::skylaconf::LUA_NUMBER_CTYPE, int, uint));
+    h.push_str(&format!("#define LUA_INTEGER_FRMLEN\t\"{}\"\n#define LUA_INTEGER_FMT\t\t\"{}\"\n#define LUAI_NUMFFORMAT\t\t\"%.{}g\"\n\n",
+        crate::skylaconf::LUA_INTEGER_FRMLEN, crate::skylaconf::LUA_INTEGER_FMT, crate::skylaconf::LUAI_NUMFFORMAT_PREC));
+    h.push_str("typedef int (*lua_CFunction) (lua_State *L);\ntypedef int (*lua_KFunction) (lua_State *L, int status, lua_KContext ctx);\n\n");
+    h.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+    for proto in LUA_H_EXPORTS {
+        h.push_str(&format!("{};\n", proto));
+    }
+    h.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n");
+    for m in LUA_H_MACROS {
+        h.push_str(m);
+        h.push('\n');
+    }
+    h.push_str("\n#endif\n");
+    h
+}
+
+/// Text of include/lauxlib.h
+pub fn generate_lauxlib_h() -> String {
+    let mut h = String::new();
+    h.push_str(GENERATED_NOTE);
+    h.push_str("\n#ifndef lauxlib_h\n#define lauxlib_h\n\n#include \"lua.h\"\n\n");
+    h.push_str("typedef struct luaL_Reg {\n  const char *name;\n  lua_CFunction func;\n} luaL_Reg;\n\n");
+    // Buffers are only handled through pointers from C
+    h.push_str("typedef struct luaL_Buffer luaL_Buffer;\n\n");
+    h.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+    for proto in LAUXLIB_H_EXPORTS {
+        h.push_str(&format!("{};\n", proto));
+    }
+    h.push_str("\n#ifdef __cplusplus\n}\n#endif\n\n");
+    for m in LAUXLIB_H_MACROS {
+        h.push_str(m);
+        h.push('\n');
+    }
+    h.push_str("\n#endif\n");
+    h
+}
+
+/// Write the generated headers into `dir` (normally include/)
+pub fn write_headers(dir: &std::path::Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    std::fs::write(dir.join("lua.h"), generate_lua_h())?;
+    std::fs::write(dir.join("lauxlib.h"), generate_lauxlib_h())
+}
+
+/// Symbol name of a C prototype ("const char *lua_typename(...)" -> "lua_typename")
+pub fn prototype_name(proto: &str) -> &str {
+    let head = &proto[..proto.find('(').unwrap_or(proto.len())];
+    head.rsplit(|c: char| c == ' ' || c == '*').next().unwrap_or(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Names and bodies of the `#[no_mangle] extern "C"` (or "C-unwind")
+    // functions defined in lapi.rs and lauxlib.rs
+    fn lapi_exports() -> Vec<(String, String)> {
+        let src = [include_str!("lapi.rs"), include_str!("lauxlib.rs")].concat();
+        let mut exports = Vec::new();
+        let mut exported = false;
+        let mut lines = src.lines();
+        while let Some(line) = lines.next() {
+            let line = line.trim();
+            if line == "#[no_mangle]" {
+                exported = true;
+            } else if exported && !line.starts_with("#[") && !line.starts_with("///") {
+                let rest = line.split("extern \"C\" fn ").nth(1).or_else(|| line.split("extern \"C-unwind\" fn ").nth(1));
+                if let Some(rest) = rest {
+                    // the body ends at the first unindented closing brace
+                    let body: Vec<&str> = lines.by_ref().take_while(|l| *l != "}").collect();
+                    exports.push((rest[..rest.find('(').unwrap()].to_string(), body.join("\n")));
+                }
+                exported = false;
+            }
+        }
+        exports
+    }
+
+    #[test]
+    fn test_headers_declare_exactly_the_exported_symbols() {
+        let mut declared: Vec<&str> = LUA_H_EXPORTS.iter().chain(LAUXLIB_H_EXPORTS).map(|p| prototype_name(p)).collect();
+        let mut exported: Vec<String> = lapi_exports().into_iter().map(|(name, _)| name).collect();
+        declared.sort();
+        exported.sort();
+        assert_eq!(declared, exported);
+    }
+
+    #[test]
+    fn test_exported_symbols_are_implemented() {
+        for (name, body) in lapi_exports() {
+            assert!(!body.contains("unimplemented!"), "{} is exported but not implemented", name);
+        }
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "int32", feature = "float32")))]
+    fn test_checked_in_headers_are_up_to_date() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("include");
+        assert_eq!(std::fs::read_to_string(dir.join("lua.h")).unwrap(), generate_lua_h(),
+            "include/lua.h is stale; regenerate with lcapi::write_headers");
+        assert_eq!(std::fs::read_to_string(dir.join("lauxlib.h")).unwrap(), generate_lauxlib_h(),
+            "include/lauxlib.h is stale; regenerate with lcapi::write_headers");
+    }
+
+    #[test]
+    fn test_prototype_name() {
+        assert_eq!(prototype_name("const char *lua_typename(lua_State *L, int tp)"), "lua_typename");
+        assert_eq!(prototype_name("void *lua_newuserdatauv(lua_State *L, size_t sz, int nuvalue)"), "lua_newuserdatauv");
+    }
+}
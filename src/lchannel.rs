@@ -0,0 +1,612 @@
+//! lchannel.rs - Channels for message passing between LuaStates (skyla.channel)
+//
+// Each LuaState is confined to one thread, so states cooperate by sending
+// copies of plain values (nil, booleans, numbers, strings and tables of
+// those) over channels. A channel is created from Rust or from Lua; in Lua
+// it is a `skyla.channel` object (see luserdata) that only the channel
+// functions can see into, so a script cannot name a channel it was not
+// given. Hosts hand channels to scripts as values (`channel_value`) or
+// publish them under a name (`register_channel`) for scripts to `open`.
+// A channel lives as long as some state, thread or name still holds it.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+use crate::ltm::obj_typename;
+use crate::luserdata::{userdata_ref, UserData, UserDataMethods};
+
+/// Maximum table nesting accepted by `SendValue::from_lua`; this only
+/// bounds the copy's recursion, tables reached again are found by identity
+pub const MAX_SEND_DEPTH: usize = 200;
+
+/// A thread-safe copy of a plain Lua value. The tables of a value are
+/// numbered in the order the copy first reaches them (keys before values);
+/// a table is a Table the first time and a TableRef to its number after
+/// that, so shared and cyclic tables arrive with the same shape.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Table(Vec<(SendValue, SendValue)>),
+    TableRef(usize),
+}
+
+impl SendValue {
+    /// Copy `v`; functions, userdata and threads cannot be sent
+    pub fn from_lua(v: &LuaValue) -> Result<SendValue, String> {
+        Self::copy(v, &mut HashMap::new(), 0)
+    }
+
+    // `seen` numbers the tables copied so far; `depth` is the number of
+    // tables being copied from the root down to `v`
+    fn copy(v: &LuaValue, seen: &mut HashMap<*const RefCell<Table>, usize>, depth: usize) -> Result<SendValue, String> {
+        Ok(match v {
+            LuaValue::Nil => SendValue::Nil,
+            LuaValue::Bool(b) => SendValue::Bool(*b),
+            LuaValue::Int(i) => SendValue::Int(*i),
+            LuaValue::Float(f) => SendValue::Float(*f),
+            LuaValue::Str(s) => SendValue::Str(s.clone()),
+            LuaValue::Table(t) => {
+                let id = Rc::as_ptr(t);
+                if let Some(&n) = seen.get(&id) {
+                    return Ok(SendValue::TableRef(n));
+                }
+                if depth >= MAX_SEND_DEPTH {
+                    return Err("table nested too deeply to send".to_string());
+                }
+                seen.insert(id, seen.len());
+                let t = t.borrow();
+                let mut pairs = Vec::with_capacity(t.len_total());
+                for (k, v) in t.pairs() {
+                    pairs.push((Self::copy(&k, seen, depth + 1)?, Self::copy(v, seen, depth + 1)?));
+                }
+                SendValue::Table(pairs)
+            }
+            other => return Err(format!("cannot send a {} value", obj_typename(other))),
+        })
+    }
+
+    /// Build a fresh Lua value (tables are new tables in the receiving
+    /// state); a TableRef to no table before it is nil
+    pub fn to_lua(&self) -> LuaValue {
+        self.build(&mut Vec::new())
+    }
+
+    // `tables` holds the tables built so far, by number
+    fn build(&self, tables: &mut Vec<Rc<RefCell<Table>>>) -> LuaValue {
+        match self {
+            SendValue::Nil => LuaValue::Nil,
+            SendValue::Bool(b) => LuaValue::Bool(*b),
+            SendValue::Int(i) => LuaValue::Int(*i),
+            SendValue::Float(f) => LuaValue::Float(*f),
+            SendValue::Str(s) => LuaValue::Str(s.clone()),
+            SendValue::Table(pairs) => {
+                let t = Rc::new(RefCell::new(Table::with_capacity(0, pairs.len())));
+                tables.push(t.clone());
+                for (k, v) in pairs {
+                    let (k, v) = (k.build(tables), v.build(tables));
+                    t.borrow_mut().rawset(&k, v);
+                }
+                LuaValue::Table(t)
+            }
+            SendValue::TableRef(n) => tables.get(*n).map_or(LuaValue::Nil, |t| LuaValue::Table(t.clone())),
+        }
+    }
+}
+
+/// Error from a send
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendError {
+    /// The channel was closed; the value is returned
+    Closed(SendValue),
+    /// A bounded channel is full (non-blocking send only)
+    Full(SendValue),
+}
+
+struct ChannelState {
+    queue: VecDeque<SendValue>,
+    capacity: Option<usize>,
+    closed: bool,
+}
+
+struct ChannelInner {
+    state: Mutex<ChannelState>,
+    cond: Condvar,
+}
+
+/// A multi-producer, multi-consumer queue of `SendValue`s
+#[derive(Clone)]
+pub struct Channel {
+    inner: Arc<ChannelInner>,
+}
+
+// Bumped on every channel event so `select` can sleep on all channels at once
+lazy_static::lazy_static! {
+    static ref SELECT_SIGNAL: (Mutex<u64>, Condvar) = (Mutex::new(0), Condvar::new());
+}
+
+fn notify_select() {
+    let (gen, cond) = &*SELECT_SIGNAL;
+    *gen.lock().unwrap() += 1;
+    cond.notify_all();
+}
+
+impl Channel {
+    /// A channel holding at most `capacity` values (None: unbounded)
+    pub fn new(capacity: Option<usize>) -> Self {
+        Channel {
+            inner: Arc::new(ChannelInner {
+                state: Mutex::new(ChannelState { queue: VecDeque::new(), capacity, closed: false }),
+                cond: Condvar::new(),
+            }),
+        }
+    }
+
+    fn wake(&self) {
+        self.inner.cond.notify_all();
+        notify_select();
+    }
+
+    /// Send, blocking while a bounded channel is full
+    pub fn send(&self, v: SendValue) -> Result<(), SendError> {
+        let mut st = self.inner.state.lock().unwrap();
+        while !st.closed && st.capacity.is_some_and(|c| st.queue.len() >= c) {
+            st = self.inner.cond.wait(st).unwrap();
+        }
+        if st.closed {
+            return Err(SendError::Closed(v));
+        }
+        st.queue.push_back(v);
+        drop(st);
+        self.wake();
+        Ok(())
+    }
+
+    /// Send without blocking
+    pub fn try_send(&self, v: SendValue) -> Result<(), SendError> {
+        let mut st = self.inner.state.lock().unwrap();
+        if st.closed {
+            return Err(SendError::Closed(v));
+        }
+        if st.capacity.is_some_and(|c| st.queue.len() >= c) {
+            return Err(SendError::Full(v));
+        }
+        st.queue.push_back(v);
+        drop(st);
+        self.wake();
+        Ok(())
+    }
+
+    /// Receive, waiting up to `timeout` (None: forever). Returns None on
+    /// timeout or once the channel is closed and drained.
+    pub fn recv(&self, timeout: Option<Duration>) -> Option<SendValue> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t)); // too far off: forever
+        let mut st = self.inner.state.lock().unwrap();
+        loop {
+            if let Some(v) = st.queue.pop_front() {
+                drop(st);
+                self.wake(); // room for blocked senders
+                return Some(v);
+            }
+            if st.closed {
+                return None;
+            }
+            st = match deadline {
+                None => self.inner.cond.wait(st).unwrap(),
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return None;
+                    }
+                    self.inner.cond.wait_timeout(st, d - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Receive without blocking
+    pub fn try_recv(&self) -> Option<SendValue> {
+        let v = self.inner.state.lock().unwrap().queue.pop_front();
+        if v.is_some() {
+            self.wake();
+        }
+        v
+    }
+
+    /// Close the channel: senders fail, receivers drain what is queued
+    pub fn close(&self) {
+        self.inner.state.lock().unwrap().closed = true;
+        self.wake();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.state.lock().unwrap().closed
+    }
+
+    /// Number of queued values
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Receive from whichever of `channels` has a value first, waiting up to
+/// `timeout` (None: forever). Returns the channel's position and the value,
+/// or None on timeout or when every channel is closed and drained.
+pub fn select(channels: &[Channel], timeout: Option<Duration>) -> Option<(usize, SendValue)> {
+    let deadline = timeout.and_then(|t| Instant::now().checked_add(t)); // too far off: forever
+    let (gen, cond) = &*SELECT_SIGNAL;
+    loop {
+        // Read the generation before polling so no event is missed in between
+        let seen = *gen.lock().unwrap();
+        for (i, ch) in channels.iter().enumerate() {
+            if let Some(v) = ch.try_recv() {
+                return Some((i, v));
+            }
+        }
+        if channels.iter().all(|ch| ch.is_closed() && ch.is_empty()) {
+            return None;
+        }
+        let mut g = gen.lock().unwrap();
+        while *g == seen {
+            g = match deadline {
+                None => cond.wait(g).unwrap(),
+                Some(d) => {
+                    let now = Instant::now();
+                    if now >= d {
+                        return None;
+                    }
+                    cond.wait_timeout(g, d - now).unwrap().0
+                }
+            };
+        }
+    }
+}
+
+// --- Channels published by name, shared by all states ---
+
+lazy_static::lazy_static! {
+    static ref CHANNELS: Mutex<HashMap<String, Channel>> = Mutex::new(HashMap::new());
+}
+
+/// Publish `ch` under `name` for `skyla.channel.open(name)`, replacing
+/// any channel published under it before
+pub fn register_channel(name: &str, ch: &Channel) {
+    CHANNELS.lock().unwrap().insert(name.to_string(), ch.clone());
+}
+
+/// Withdraw the channel published under `name`; the channel itself stays
+/// usable by whoever holds it, and is freed with its last holder
+pub fn unregister_channel(name: &str) -> Option<Channel> {
+    CHANNELS.lock().unwrap().remove(name)
+}
+
+/// The channel published under `name`
+pub fn open_channel(name: &str) -> Option<Channel> {
+    CHANNELS.lock().unwrap().get(name).cloned()
+}
+
+/// A channel as seen by one state
+struct LuaChannel(Channel);
+
+impl UserData for LuaChannel {
+    const NAME: &'static str = "skyla.channel";
+
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method("send", |L, ch| channel_send(L, &ch.0));
+        methods.add_method("try_send", |L, ch| channel_try_send(L, &ch.0));
+        methods.add_method("recv", |L, ch| channel_recv(L, &ch.0));
+        methods.add_method("try_recv", |L, ch| channel_try_recv(L, &ch.0));
+        methods.add_method("close", |_, ch| {
+            ch.0.close();
+            0
+        });
+        methods.add_method("len", |L, ch| {
+            L.push(LuaValue::Int(ch.0.len() as i64));
+            1
+        });
+    }
+}
+
+impl LuaState {
+    /// `ch` as a Lua value of this state
+    pub fn channel_value(&mut self, ch: &Channel) -> LuaValue {
+        self.create_userdata(LuaChannel(ch.clone()))
+    }
+}
+
+/// The channel behind Lua value `v`, if it is a channel object
+pub fn channel_from_value(v: &LuaValue) -> Option<Channel> {
+    userdata_ref::<LuaChannel>(v).map(|ch| ch.borrow().0.clone())
+}
+
+fn check_channel(state: &mut LuaState, arg: i32) -> Channel {
+    match channel_from_value(&state.to_value(arg)) {
+        Some(ch) => ch,
+        None => state.type_error(arg, LuaChannel::NAME),
+    }
+}
+
+// Timeout argument in seconds; nil means wait forever
+fn opt_timeout(state: &mut LuaState, arg: i32) -> Option<Duration> {
+    match state.to_value(arg) {
+        LuaValue::Int(n) => Some(Duration::from_secs(n.max(0) as u64)),
+        LuaValue::Float(f) if f <= 0.0 => Some(Duration::ZERO),
+        LuaValue::Float(f) => match Duration::try_from_secs_f64(f) {
+            Ok(d) => Some(d),
+            Err(_) => state.arg_error(arg, "timeout out of range"),
+        },
+        _ => None,
+    }
+}
+
+// skyla.channel.new([capacity]) -> channel
+fn channel_lua_new(state: &mut LuaState) -> i32 {
+    let cap = state.opt_integer(1, 0);
+    let ch = Channel::new(if cap > 0 { Some(cap as usize) } else { None });
+    let v = state.channel_value(&ch);
+    state.push(v);
+    1
+}
+
+// skyla.channel.open(name) -> channel | nil
+fn channel_lua_open(state: &mut LuaState) -> i32 {
+    let name = state.check_string(1);
+    let v = match open_channel(&name) {
+        Some(ch) => state.channel_value(&ch),
+        None => LuaValue::Nil,
+    };
+    state.push(v);
+    1
+}
+
+fn send_value(state: &mut LuaState, arg: i32) -> SendValue {
+    let v = state.to_value(arg);
+    match SendValue::from_lua(&v) {
+        Ok(v) => v,
+        Err(msg) => state.arg_error(arg, &msg),
+    }
+}
+
+// The channel functions read the channel from argument 1, both as
+// skyla.channel.f(ch, ...) and as ch:f(...)
+
+// send(ch, value): blocks while the channel is full
+fn channel_send(state: &mut LuaState, ch: &Channel) -> i32 {
+    let v = send_value(state, 2);
+    if ch.send(v).is_err() {
+        state.error("send on closed channel");
+    }
+    0
+}
+
+// try_send(ch, value) -> boolean
+fn channel_try_send(state: &mut LuaState, ch: &Channel) -> i32 {
+    let v = send_value(state, 2);
+    state.push(LuaValue::Bool(ch.try_send(v).is_ok()));
+    1
+}
+
+// recv(ch[, timeout]) -> ok, value
+fn channel_recv(state: &mut LuaState, ch: &Channel) -> i32 {
+    let timeout = opt_timeout(state, 2);
+    push_received(state, ch.recv(timeout))
+}
+
+// try_recv(ch) -> ok, value
+fn channel_try_recv(state: &mut LuaState, ch: &Channel) -> i32 {
+    push_received(state, ch.try_recv())
+}
+
+fn push_received(state: &mut LuaState, v: Option<SendValue>) -> i32 {
+    match v {
+        Some(v) => {
+            state.push(LuaValue::Bool(true));
+            state.push(v.to_lua());
+        }
+        None => {
+            state.push(LuaValue::Bool(false));
+            state.push(LuaValue::Nil);
+        }
+    }
+    2
+}
+
+// skyla.channel.select({ch1, ch2, ...}[, timeout]) -> index, value | nil
+fn channel_lua_select(state: &mut LuaState) -> i32 {
+    let list = state.check_table(1);
+    let n = state.len(1);
+    let mut channels = Vec::new();
+    for i in 1..=n {
+        match channel_from_value(&list.get(i as usize)) {
+            Some(ch) => channels.push(ch),
+            None => state.arg_error(1, &format!("invalid channel at index {}", i)),
+        }
+    }
+    let timeout = opt_timeout(state, 2);
+    match select(&channels, timeout) {
+        Some((i, v)) => {
+            state.push(LuaValue::Int(i as i64 + 1));
+            state.push(v.to_lua());
+            2
+        }
+        None => {
+            state.push(LuaValue::Nil);
+            1
+        }
+    }
+}
+
+fn channel_lua_send(state: &mut LuaState) -> i32 {
+    let ch = check_channel(state, 1);
+    channel_send(state, &ch)
+}
+
+fn channel_lua_try_send(state: &mut LuaState) -> i32 {
+    let ch = check_channel(state, 1);
+    channel_try_send(state, &ch)
+}
+
+fn channel_lua_recv(state: &mut LuaState) -> i32 {
+    let ch = check_channel(state, 1);
+    channel_recv(state, &ch)
+}
+
+fn channel_lua_try_recv(state: &mut LuaState) -> i32 {
+    let ch = check_channel(state, 1);
+    channel_try_recv(state, &ch)
+}
+
+// skyla.channel.close(ch)
+fn channel_lua_close(state: &mut LuaState) -> i32 {
+    check_channel(state, 1).close();
+    0
+}
+
+// skyla.channel.len(ch) -> number of queued values
+fn channel_lua_len(state: &mut LuaState) -> i32 {
+    let ch = check_channel(state, 1);
+    state.push(LuaValue::Int(ch.len() as i64));
+    1
+}
+
+const CHANNEL_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("new", channel_lua_new),
+    ("open", channel_lua_open),
+    ("send", channel_lua_send),
+    ("try_send", channel_lua_try_send),
+    ("recv", channel_lua_recv),
+    ("try_recv", channel_lua_try_recv),
+    ("select", channel_lua_select),
+    ("close", channel_lua_close),
+    ("len", channel_lua_len),
+];
+
+/// Register the `skyla.channel` module
+pub fn open_channel_lib(state: &mut LuaState) {
+    for &(name, f) in CHANNEL_FUNCS {
+        state.register_lib_function("skyla.channel", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::lstate::GlobalState;
+
+    #[test]
+    fn test_send_recv_across_threads() {
+        let ch = Channel::new(Some(1));
+        let tx = ch.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..3 {
+                tx.send(SendValue::Int(i)).unwrap();
+            }
+            tx.close();
+        });
+        let mut got = Vec::new();
+        while let Some(v) = ch.recv(None) {
+            got.push(v);
+        }
+        producer.join().unwrap();
+        assert_eq!(got, vec![SendValue::Int(0), SendValue::Int(1), SendValue::Int(2)]);
+        assert_eq!(ch.try_send(SendValue::Nil), Err(SendError::Closed(SendValue::Nil)));
+    }
+
+    #[test]
+    fn test_try_send_full_and_select() {
+        let a = Channel::new(Some(1));
+        let b = Channel::new(None);
+        a.try_send(SendValue::Bool(true)).unwrap();
+        assert!(matches!(a.try_send(SendValue::Nil), Err(SendError::Full(_))));
+        assert_eq!(a.try_recv(), Some(SendValue::Bool(true)));
+        assert_eq!(select(&[a.clone(), b.clone()], Some(Duration::from_millis(10))), None);
+        let tx = b.clone();
+        let t = thread::spawn(move || tx.send(SendValue::Str("hi".to_string())).unwrap());
+        assert_eq!(select(&[a, b], None), Some((1, SendValue::Str("hi".to_string()))));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_channel_values() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let ch = Channel::new(None);
+        register_channel("test_channel_values", &ch);
+        let v = state.channel_value(&open_channel("test_channel_values").unwrap());
+        channel_from_value(&v).unwrap().try_send(SendValue::Float(1.5)).unwrap();
+        assert_eq!(ch.recv(Some(Duration::ZERO)), Some(SendValue::Float(1.5)));
+        // a table that merely looks like a channel is not one
+        let forged = Table::new();
+        assert!(channel_from_value(&LuaValue::Table(Rc::new(RefCell::new(forged)))).is_none());
+        assert!(channel_from_value(&LuaValue::Int(1)).is_none());
+        // the name and the state's object are the only holders
+        let weak = Arc::downgrade(&ch.inner);
+        drop(ch);
+        assert!(unregister_channel("test_channel_values").is_some());
+        assert!(weak.upgrade().is_some());
+        drop(v);
+        state.stack.clear();
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_send_shared_and_cyclic_tables() {
+        let inner = Rc::new(RefCell::new(Table::new()));
+        let outer = Rc::new(RefCell::new(Table::new()));
+        outer.borrow_mut().rawset(&LuaValue::Int(1), LuaValue::Table(inner.clone()));
+        outer.borrow_mut().rawset(&LuaValue::Int(2), LuaValue::Table(inner.clone()));
+        inner.borrow_mut().rawset(&LuaValue::Int(1), LuaValue::Table(outer.clone()));
+        let sent = SendValue::from_lua(&LuaValue::Table(outer.clone())).unwrap();
+        inner.borrow_mut().rawset(&LuaValue::Int(1), LuaValue::Nil); // break the cycle
+        let LuaValue::Table(t) = sent.to_lua() else { panic!("not a table") };
+        let entry = |t: &Rc<RefCell<Table>>, i| match t.borrow().get(&LuaValue::Int(i)) {
+            Some(LuaValue::Table(e)) => e.clone(),
+            other => panic!("entry {} is {:?}", i, other),
+        };
+        // the shared table is received once, and its cycle leads back to the root
+        let (a, b) = (entry(&t, 1), entry(&t, 2));
+        assert!(Rc::ptr_eq(&a, &b) && Rc::ptr_eq(&entry(&a, 1), &t));
+        a.borrow_mut().rawset(&LuaValue::Int(1), LuaValue::Nil);
+        // a wide DAG is copied in linear size
+        let mut v = LuaValue::Table(Rc::new(RefCell::new(Table::new())));
+        for _ in 0..64 {
+            let t = Rc::new(RefCell::new(Table::new()));
+            t.borrow_mut().rawset(&LuaValue::Int(1), v.clone());
+            t.borrow_mut().rawset(&LuaValue::Int(2), v);
+            v = LuaValue::Table(t);
+        }
+        assert!(matches!(SendValue::from_lua(&v), Ok(SendValue::Table(_))));
+        assert_eq!(SendValue::TableRef(3).to_lua(), LuaValue::Nil);
+    }
+
+    #[test]
+    fn test_recv_timeout_argument() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let ch = Channel::new(None);
+        let v = state.channel_value(&ch);
+        for t in [f64::INFINITY, f64::NAN, 1e300] {
+            let args = vec![v.clone(), LuaValue::Float(t)];
+            let r = state.pcall(|s| crate::lauxlib::call_lib(s, channel_lua_recv, args));
+            assert!(matches!(r, Err(crate::lerror::Error::Runtime(LuaValue::Str(ref m))) if m.contains("timeout out of range")), "{:?}", r);
+        }
+        // negative timeouts do not wait, and neither do huge integer ones once a value is queued
+        let r = crate::lauxlib::call_lib(&mut state, channel_lua_recv, vec![v.clone(), LuaValue::Float(-1.0)]);
+        assert_eq!(r, vec![LuaValue::Bool(false), LuaValue::Nil]);
+        ch.try_send(SendValue::Int(7)).unwrap();
+        let r = crate::lauxlib::call_lib(&mut state, channel_lua_recv, vec![v, LuaValue::Int(i64::MAX)]);
+        assert_eq!(r, vec![LuaValue::Bool(true), LuaValue::Int(7)]);
+    }
+}
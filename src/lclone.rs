@@ -0,0 +1,120 @@
+//! lclone.rs - Copying values between independent states
+//
+// Every LuaState owns its tables, so a worker state cannot share a
+// template state's configuration: LuaState::clone_value_to copies it.
+// Nil, booleans, numbers and strings are copied as they are; tables become
+// new tables of the destination, and a table reached twice (shared or
+// cyclic) is copied once, so the copy has the same shape. Metatables are
+// not copied. Functions, userdata and threads belong to their state: the
+// first converter of the source state (add_clone_converter) that accepts
+// one makes its copy, and without one the clone fails. Both states must be
+// on the current thread; across threads, values go through channels
+// (lchannel).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::lerror::{Error, Result};
+use crate::lobject::LuaValue;
+use crate::lstate::{LuaState, ObjectId};
+use crate::ltable::Table;
+use crate::ltm::obj_typename;
+
+/// Copies a function, userdata or thread into the destination state, or
+/// returns None to leave it to the next converter
+pub type CloneConverter = fn(&LuaValue, &mut LuaState) -> Option<LuaValue>;
+
+/// One clone_value_to: the copies made so far, by source table
+struct Cloner {
+    converters: Vec<CloneConverter>,
+    copies: HashMap<ObjectId, Rc<RefCell<Table>>>,
+}
+
+impl Cloner {
+    fn copy(&mut self, dst: &mut LuaState, v: &LuaValue) -> Result<LuaValue> {
+        let LuaValue::Table(t) = v else {
+            return match v {
+                LuaValue::Nil | LuaValue::Bool(_) | LuaValue::Int(_) | LuaValue::Float(_) | LuaValue::Str(_) => Ok(v.clone()),
+                other => self.converters.iter().find_map(|f| f(other, dst)).ok_or_else(|| {
+                    Error::Runtime(LuaValue::Str(format!("cannot clone a {} value", obj_typename(other))))
+                }),
+            };
+        };
+        let id = ObjectId::of(v).expect("tables have an identity");
+        if let Some(copy) = self.copies.get(&id) {
+            return Ok(LuaValue::Table(copy.clone()));
+        }
+        let copy = Rc::new(RefCell::new(Table::new()));
+        self.copies.insert(id, copy.clone());
+        // no borrow of the source is held while converters run
+        let entries: Vec<(LuaValue, LuaValue)> = t.borrow().pairs().map(|(k, v)| (k, v.clone())).collect();
+        for (k, v) in entries {
+            let k = self.copy(dst, &k)?;
+            let v = self.copy(dst, &v)?;
+            copy.borrow_mut().rawset(&k, v);
+        }
+        Ok(LuaValue::Table(copy))
+    }
+}
+
+impl LuaState {
+    /// Let clone_value_to copy values of this state that are not plain
+    /// data; converters are tried in the order they were added
+    pub fn add_clone_converter(&mut self, f: CloneConverter) {
+        self.l_G.borrow_mut().clone_converters.push(f);
+    }
+
+    /// A copy of `v`, a value of this state, made of `other`'s objects
+    /// (see the module comment)
+    pub fn clone_value_to(&self, other: &mut LuaState, v: &LuaValue) -> Result<LuaValue> {
+        let converters = self.l_G.borrow().clone_converters.clone();
+        Cloner { converters, copies: HashMap::new() }.copy(other, v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lstate::GlobalState;
+
+    fn new_state() -> LuaState {
+        LuaState::new(Rc::new(RefCell::new(GlobalState::new())))
+    }
+
+    fn field(t: &LuaValue, k: &str) -> LuaValue {
+        let LuaValue::Table(t) = t else { panic!("not a table") };
+        t.borrow().get(&LuaValue::Str(k.to_string())).cloned().unwrap_or(LuaValue::Nil)
+    }
+
+    #[test]
+    fn test_clone_keeps_shape() {
+        let (template, mut worker) = (new_state(), new_state());
+        let shared = Rc::new(RefCell::new(Table::new()));
+        shared.borrow_mut().rawset(&LuaValue::Str("port".to_string()), LuaValue::Int(8080));
+        let config = Rc::new(RefCell::new(Table::new()));
+        for k in ["a", "b"] {
+            config.borrow_mut().rawset(&LuaValue::Str(k.to_string()), LuaValue::Table(shared.clone()));
+        }
+        config.borrow_mut().rawset(&LuaValue::Str("self".to_string()), LuaValue::Table(config.clone()));
+        let src = LuaValue::Table(config);
+
+        let copy = template.clone_value_to(&mut worker, &src).unwrap();
+        assert_ne!(ObjectId::of(&copy), ObjectId::of(&src));
+        assert_eq!(ObjectId::of(&field(&copy, "self")), ObjectId::of(&copy));
+        assert_eq!(ObjectId::of(&field(&copy, "a")), ObjectId::of(&field(&copy, "b")));
+        assert_eq!(field(&field(&copy, "a"), "port"), LuaValue::Int(8080));
+        shared.borrow_mut().rawset(&LuaValue::Str("port".to_string()), LuaValue::Int(1));
+        assert_eq!(field(&field(&copy, "b"), "port"), LuaValue::Int(8080));
+    }
+
+    #[test]
+    fn test_clone_functions_need_a_converter() {
+        let (mut template, mut worker) = (new_state(), new_state());
+        let f = LuaValue::Function(Box::new(|_: &mut LuaState| 0));
+        let err = template.clone_value_to(&mut worker, &f).unwrap_err();
+        assert_eq!(err.to_string(), "cannot clone a function value");
+        template.add_clone_converter(|v, _| matches!(v, LuaValue::Function(_)).then_some(LuaValue::Bool(true)));
+        assert_eq!(template.clone_value_to(&mut worker, &f).unwrap(), LuaValue::Bool(true));
+    }
+}
@@ -6,7 +6,8 @@ use std::os::raw::c_int;
 
 use crate::lparser::{FuncState, expdesc};
 use crate::lopcodes::{OpCode, Instruction};
-use crate::lobject::{NO_JUMP};
+use crate::lobject::{NO_JUMP, luaO_codesize};
+use crate::lvm::BITRK;
 
 /// Mark that the given list is empty (no jump).
 pub const NO_JUMP: c_int = -1;
@@ -28,7 +29,7 @@ pub fn code(fs: &mut FuncState, i: Instruction) -> c_int {
 /// Emit an ABC-format instruction.
 /// A, B, and C are operands (signed integers).
 pub fn code_abc(fs: &mut FuncState, op: OpCode, a: c_int, b: c_int, c: c_int) -> c_int {
-    let i = Instruction::encode_abc(op, a as u8, b as u8, c as u8);
+    let i = Instruction::encode_abc(op, a as u8, b as u16, c as u16);
     code(fs, i)
 }
 
@@ -184,6 +185,94 @@ pub fn luaK_isconstant(e: &expdesc) -> bool {
     matches!(e.k, expdesc::VKNUM | expdesc::VKSTR | expdesc::VTRUE | expdesc::VFALSE)
 }
 
+/// Option for multiple returns ('...' or a call as last expression).
+pub use crate::lvm::LUA_MULTRET;
+
+/// Returns true if expression may produce multiple values (call or '...').
+#[inline(always)]
+pub fn hasmultret(e: &expdesc) -> bool {
+    e.k == expdesc::VCALL || e.k == expdesc::VVARARG
+}
+
+/// Fix an open call or vararg expression to return 'nresults' values
+/// (LUA_MULTRET keeps them all; the VM then uses 'top').
+pub fn luaK_setreturns(fs: &mut FuncState, e: &mut expdesc, nresults: c_int) {
+    let pc = e.info as usize;
+    match e.k {
+        expdesc::VCALL => {
+            // C = nresults + 1
+            fs.f.code[pc] = Instruction::set_arg_c(fs.f.code[pc], nresults + 1);
+        }
+        expdesc::VVARARG => {
+            // B = nresults + 1, A = first free register
+            fs.f.code[pc] = Instruction::set_arg_b(fs.f.code[pc], nresults + 1);
+            fs.f.code[pc] = Instruction::set_arg_a(fs.f.code[pc], fs.freereg);
+            luaK_reserveregs(fs, 1);
+        }
+        _ => {}
+    }
+}
+
+/// Keep all values produced by a call or '...' (used in calls, returns
+/// and table constructors when the expression is the last one).
+#[inline(always)]
+pub fn luaK_setmultret(fs: &mut FuncState, e: &mut expdesc) {
+    luaK_setreturns(fs, e, LUA_MULTRET);
+}
+
+/// Adjust a call or '...' used as a single value.
+pub fn luaK_setoneret(fs: &mut FuncState, e: &mut expdesc) {
+    match e.k {
+        expdesc::VCALL => {
+            // result is already in R(A)
+            e.k = expdesc::VNONRELOC;
+            e.info = Instruction::get_arg_a(&fs.f.code[e.info as usize]) as c_int;
+        }
+        expdesc::VVARARG => {
+            let pc = e.info as usize;
+            fs.f.code[pc] = Instruction::set_arg_b(fs.f.code[pc], 2);
+            e.k = expdesc::VRELOCABLE;
+        }
+        _ => {}
+    }
+}
+
+/// Emit the '...' expression; B is patched later by setreturns/setoneret.
+pub fn luaK_vararg(fs: &mut FuncState, e: &mut expdesc) {
+    e.info = code_abc(fs, OpCode::VARARG, 0, 1, 0);
+    e.k = expdesc::VVARARG;
+}
+
+/// Patch the NEWTABLE at 'pc' with the constructor's final sizes, now
+/// that the parser has counted its list and record items
+pub fn luaK_settablesize(fs: &mut FuncState, pc: c_int, ra: c_int, asize: c_int, hsize: c_int) {
+    let b = luaO_codesize(asize.max(0) as u32) as c_int;
+    let c = luaO_codesize(hsize.max(0) as u32) as c_int;
+    fs.f.code[pc as usize] = Instruction::encode_abc(OpCode::NEWTABLE, ra as u8, b as u16, c as u16);
+}
+
+/// Emit SELF for the method call 'e:key(...)': the method goes to a new
+/// register and 'e' to the one above it, the first argument, so 'e' is
+/// evaluated once
+pub fn luaK_self(fs: &mut FuncState, e: &mut expdesc, key: &str) {
+    let ereg = exp2anyreg(fs, e);
+    luaK_freereg(fs, ereg);
+    let func = fs.freereg;
+    luaK_reserveregs(fs, 2); // function and 'self'
+    let k = addk_string(fs, key);
+    code_abc(fs, OpCode::SELF, func, ereg, k | BITRK as c_int);
+    e.info = func;
+    e.k = expdesc::VNONRELOC;
+}
+
+/// Emit SETLIST for a table constructor; 'tostore' == LUA_MULTRET means
+/// the last item was a call or '...', so B = 0 and the VM stores up to 'top'.
+pub fn luaK_setlist(fs: &mut FuncState, base: c_int, nelems: c_int, tostore: c_int) {
+    let b = if tostore == LUA_MULTRET { 0 } else { tostore };
+    code_abc(fs, OpCode::SETLIST, base, b, nelems);
+    fs.freereg = base + 1; // free registers with list values
+}
+
 /// Jumps if expression is true.
 pub fn luaK_goiftrue(fs: &mut FuncState, e: &mut expdesc) -> c_int {
     // Implementation of conditional jump if expression evaluates to true
@@ -0,0 +1,141 @@
+//! lcolor.rs - ANSI colors for the standalone interpreter
+//
+// The REPL colors the values it prints (through linspect), underlines the
+// token a syntax error points at and dims the library frames of a
+// traceback. --color=auto (the default) colors only when writing to a
+// terminal and NO_COLOR is unset; --color=always and --color=never
+// override both.
+
+/// When to color output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorMode {
+    /// The value of --color=
+    pub fn parse(s: &str) -> Option<ColorMode> {
+        match s {
+            "auto" => Some(ColorMode::Auto),
+            "always" => Some(ColorMode::Always),
+            "never" => Some(ColorMode::Never),
+            _ => None,
+        }
+    }
+
+    /// Whether to color, given if the output is a terminal and if NO_COLOR
+    /// is set to a non-empty value
+    pub fn enabled(self, is_terminal: bool, no_color: bool) -> bool {
+        match self {
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+            ColorMode::Auto => is_terminal && !no_color,
+        }
+    }
+}
+
+pub const RESET: &str = "\x1b[0m";
+pub const STRING: &str = "\x1b[32m";
+pub const NUMBER: &str = "\x1b[36m";
+/// nil, true, false
+pub const KEYWORD: &str = "\x1b[35m";
+pub const KEY: &str = "\x1b[34m";
+pub const DIM: &str = "\x1b[2m";
+pub const ERROR: &str = "\x1b[1;31m";
+pub const UNDERLINE: &str = "\x1b[4;31m";
+
+/// `text` in `style` if `on`
+pub fn paint(on: bool, style: &str, text: &str) -> String {
+    if on {
+        format!("{}{}{}", style, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// The token a syntax error message points at ("... near 'x'"), or None
+/// for one at the end of the chunk
+fn near_token(msg: &str) -> Option<&str> {
+    let start = msg.rfind("near '")? + "near '".len();
+    let len = msg[start..].rfind('\'')?;
+    Some(&msg[start..start + len])
+}
+
+/// A syntax error for people: the message, then line `line` (1-based) of
+/// `src` with the offending token underlined, or marked with carets when
+/// not coloring. The parser only reports lines, so the token is found by
+/// its text (its first occurrence on the line); a message without one
+/// marks the end of the line.
+pub fn highlight_syntax_error(src: &str, line: u32, msg: &str, color: bool) -> String {
+    let mut out = paint(color, ERROR, msg);
+    let Some(text) = src.lines().nth(line.saturating_sub(1) as usize) else { return out };
+    let text = text.trim_end();
+    let (col, len) = match near_token(msg) {
+        Some(tok) => match text.find(tok) {
+            Some(col) => (col, tok.len()),
+            None => (text.len(), 0),
+        },
+        None => (text.len(), 0),
+    };
+    out.push('\n');
+    if color {
+        out.push_str(&text[..col]);
+        out.push_str(&paint(true, UNDERLINE, &text[col..col + len]));
+        out.push_str(&text[col + len..]);
+    } else {
+        out.push_str(text);
+        out.push('\n');
+        let pad: String = text[..col].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        out.push_str(&pad);
+        out.push_str(&"^".repeat(len.max(1)));
+    }
+    out
+}
+
+/// An error message with its traceback's library frames ([C] functions)
+/// dimmed
+pub fn dim_library_frames(msg: &str, color: bool) -> String {
+    if !color {
+        return msg.to_string();
+    }
+    msg.split('\n')
+        .map(|l| if l.trim_start().starts_with("[C]:") { paint(true, DIM, l) } else { l.to_string() })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_mode() {
+        assert_eq!(ColorMode::parse("always"), Some(ColorMode::Always));
+        assert_eq!(ColorMode::parse("yes"), None);
+        assert!(ColorMode::Auto.enabled(true, false));
+        assert!(!ColorMode::Auto.enabled(true, true));
+        assert!(!ColorMode::Auto.enabled(false, false));
+        assert!(ColorMode::Always.enabled(false, true));
+        assert!(!ColorMode::Never.enabled(true, false));
+    }
+
+    #[test]
+    fn test_highlight_syntax_error() {
+        let msg = "stdin:2: unexpected symbol near ')'";
+        assert_eq!(highlight_syntax_error("y = 1\nx = 1)", 2, msg, false), format!("{}\nx = 1)\n     ^", msg));
+        assert_eq!(highlight_syntax_error("local end = 1", 1, "'<name>' expected near 'end'", true),
+            format!("{}'<name>' expected near 'end'{}\nlocal {}end{} = 1", ERROR, RESET, UNDERLINE, RESET));
+        assert_eq!(highlight_syntax_error("f(", 1, "unexpected symbol near <eof>", false),
+            "unexpected symbol near <eof>\nf(\n  ^");
+    }
+
+    #[test]
+    fn test_dim_library_frames() {
+        let tb = "boom\nstack traceback:\n\t[C]: in function 'error'\n\tstdin:1: in main chunk";
+        assert_eq!(dim_library_frames(tb, false), tb);
+        assert_eq!(dim_library_frames(tb, true),
+            format!("boom\nstack traceback:\n{}\t[C]: in function 'error'{}\n\tstdin:1: in main chunk", DIM, RESET));
+    }
+}
@@ -0,0 +1,125 @@
+//! lcompat.rs - Lua 5.1 globals kept for old code (COMPAT_GLOBAL)
+//
+// unpack and loadstring are the 5.1 names of table.unpack and load.
+// module(name, ...) creates or reuses the module table in package.loaded,
+// fills in _NAME, _M and _PACKAGE and applies the option functions, as in
+// 5.1; but there is no setfenv any more, so it cannot make the table the
+// caller's environment: it returns it, for `local M = module(...)`.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::lobject::LuaValue;
+use crate::lsourcemap::LoadOptions;
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+
+// loadstring(s [, chunkname]) -> function | nil, message
+fn compat_loadstring(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let chunkname = state.opt_string(2, &s);
+    let opts = LoadOptions { chunkname, ..LoadOptions::default() };
+    match state.load_buffer_with(s.as_bytes(), &opts) {
+        Ok(()) => 1,
+        Err(e) => {
+            state.push(LuaValue::Nil);
+            state.push(LuaValue::Str(e.to_string()));
+            2
+        }
+    }
+}
+
+/// The package part of a module name: "a.b.c" -> "a.b."
+fn package_name(name: &str) -> &str {
+    name.rfind('.').map_or("", |i| &name[..=i])
+}
+
+// module(name [, option...]) -> module table
+fn compat_module(state: &mut LuaState) -> i32 {
+    let name = state.check_string(1);
+    let key = LuaValue::Str(name.clone());
+    let loaded = state.loaded_table();
+    let existing = match loaded.borrow().get(&key) {
+        Some(LuaValue::Table(t)) => Some(t.clone()),
+        _ => None,
+    };
+    let m = existing.unwrap_or_else(|| {
+        let t = Rc::new(RefCell::new(Table::new()));
+        loaded.borrow_mut().rawset(&key, LuaValue::Table(t.clone()));
+        t
+    });
+    let field = |f: &str| LuaValue::Str(f.to_string());
+    if matches!(m.borrow().get(&field("_NAME")), None | Some(LuaValue::Nil)) {
+        let mut t = m.borrow_mut();
+        t.rawset(&field("_M"), LuaValue::Table(m.clone()));
+        t.rawset(&field("_NAME"), key.clone());
+        t.rawset(&field("_PACKAGE"), LuaValue::Str(package_name(&name).to_string()));
+    }
+    if !name.contains('.') {
+        state.set_global(&name, LuaValue::Table(m.clone()));
+    }
+    // each option is called with the module; its errors propagate
+    for i in 2..=state.get_top() {
+        let option = state.to_value(i);
+        state.push(option);
+        state.push(LuaValue::Table(m.clone()));
+        state.call(1, 0);
+    }
+    state.push(LuaValue::Table(m));
+    1
+}
+
+const COMPAT_GLOBALS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("loadstring", compat_loadstring),
+    ("module", compat_module),
+    ("unpack", crate::ltablib::table_unpack),
+];
+
+/// Set the 5.1 globals
+pub fn open_compat_globals(state: &mut LuaState) {
+    for &(name, f) in COMPAT_GLOBALS {
+        let f = move |L: &mut LuaState| L.call_rust(f);
+        state.set_global(name, LuaValue::Function(Box::new(f)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(package_name("a.b.c"), "a.b.");
+        assert_eq!(package_name("mod"), "");
+    }
+
+    #[test]
+    fn test_globals_after_open_libs() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        crate::skylalib::open_libs(&mut state);
+        state.stack.truncate(0);
+        // unpack({1, 2}) -> 1, 2
+        let t = Rc::new(RefCell::new(Table::new()));
+        t.borrow_mut().rawset(&LuaValue::Int(1), LuaValue::Int(1));
+        t.borrow_mut().rawset(&LuaValue::Int(2), LuaValue::Int(2));
+        let f = state.get_global("unpack").unwrap();
+        state.push(f);
+        state.push(LuaValue::Table(t));
+        state.call(1, -1);
+        assert_eq!(state.stack, vec![LuaValue::Int(1), LuaValue::Int(2)]);
+        // loadstring("return 7")() -> 7
+        state.stack.truncate(0);
+        let f = state.get_global("loadstring").unwrap();
+        state.push(f);
+        state.push(LuaValue::Str("return 7".to_string()));
+        state.call(1, 1);
+        state.call(0, 1);
+        assert_eq!(state.stack, vec![LuaValue::Int(7)]);
+        // module stores the new module in the real globals
+        let f = state.get_global("module").unwrap();
+        state.push(f);
+        state.push(LuaValue::Str("m".to_string()));
+        state.call(1, 1);
+        assert!(matches!(state.get_global("m"), Some(LuaValue::Table(_))));
+    }
+}
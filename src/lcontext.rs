@@ -0,0 +1,429 @@
+//! lcontext.rs - Native stacks for coroutines
+//
+// Every coroutine runs on a machine stack of its own. lua_resume switches
+// to it and lua_yieldk switches back, so a yield from any depth (inside a
+// metamethod, a for iterator, a Rust function called through the API)
+// suspends all the frames between the resume and the yield at once, and
+// the next resume continues them where they stopped. A switch pushes the
+// callee-saved registers on the running stack, saves its stack pointer and
+// loads the other one (skyla_switch_context); that and the first entry
+// into a coroutine (skyla_coroutine_start) are the only assembly.
+//
+// Lua errors and panics never unwind across a switch: coroutine_main
+// catches them at the base of the coroutine stack and resume hands the
+// payload to the resumer. Dropping a suspended coroutine resumes it once
+// more and raises Cancel from its suspend, so the Rust frames on its stack
+// unwind and drop what they own.
+
+use std::any::Any;
+use std::cell::Cell;
+use std::ptr;
+
+/// Usable size of a coroutine stack, what std gives spawned threads
+pub const STACK_SIZE: usize = 2 << 20;
+
+/// Inaccessible region below every stack, so an overflow faults instead of
+/// writing over other memory (a multiple of every supported page size)
+const GUARD_SIZE: usize = 64 << 10;
+
+/// Panic payload that unwinds a suspended coroutine being dropped; it
+/// passes through LuaState::call_rust and protected calls untouched
+#[derive(Debug)]
+pub struct Cancel;
+
+/// Where a coroutine is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoStatus {
+    /// Not started yet, or stopped in suspend
+    Suspended,
+    /// On its stack now, or resuming another coroutine from it
+    Running,
+    /// Its body returned or panicked
+    Finished,
+}
+
+/// A body running on a stack of its own
+pub struct Coroutine {
+    stack: Stack,
+    /// Stack pointer of the coroutine while it is suspended
+    sp: Cell<*mut u8>,
+    /// Stack pointer of the resumer while the coroutine runs
+    caller_sp: Cell<*mut u8>,
+    body: Cell<Option<Box<dyn FnOnce()>>>,
+    status: Cell<CoStatus>,
+    /// Panic that ended the body, until resume hands it over
+    panic: Cell<Option<Box<dyn Any + Send>>>,
+    /// Set by drop: suspend raises Cancel when it returns
+    cancel: Cell<bool>,
+}
+
+impl Coroutine {
+    /// A suspended coroutine that runs `body` when first resumed. Fails if
+    /// no stack can be mapped, or on targets without stack switching.
+    pub fn new(body: impl FnOnce() + 'static) -> Result<Box<Coroutine>, &'static str> {
+        let co = Box::new(Coroutine {
+            stack: Stack::new()?,
+            sp: Cell::new(ptr::null_mut()),
+            caller_sp: Cell::new(ptr::null_mut()),
+            body: Cell::new(Some(Box::new(body))),
+            status: Cell::new(CoStatus::Suspended),
+            panic: Cell::new(None),
+            cancel: Cell::new(false),
+        });
+        let arg = &*co as *const Coroutine as usize;
+        co.sp.set(unsafe { arch::init_stack(co.stack.top(), coroutine_main as usize, arg) });
+        Ok(co)
+    }
+
+    pub fn status(&self) -> CoStatus {
+        self.status.get()
+    }
+
+    /// Run the coroutine until it suspends (Ok(false)) or its body returns
+    /// (Ok(true)). A panic that ended the body comes back as Err with its
+    /// payload, already caught, so the caller decides whether to go on
+    /// unwinding.
+    pub fn resume(&self) -> Result<bool, Box<dyn Any + Send>> {
+        assert_eq!(self.status.get(), CoStatus::Suspended, "resuming a coroutine that is not suspended");
+        self.status.set(CoStatus::Running);
+        unsafe { arch::skyla_switch_context(self.caller_sp.as_ptr(), self.sp.get()) };
+        match self.status.get() {
+            CoStatus::Finished => self.panic.take().map_or(Ok(true), Err),
+            _ => Ok(false),
+        }
+    }
+
+    /// Switch back to the resumer; returns when the coroutine is resumed
+    /// again. Only the coroutine itself may call this, on its own stack.
+    pub fn suspend(&self) {
+        debug_assert_eq!(self.status.get(), CoStatus::Running);
+        self.status.set(CoStatus::Suspended);
+        unsafe { arch::skyla_switch_context(self.sp.as_ptr(), self.caller_sp.get()) };
+        if self.cancel.get() {
+            std::panic::resume_unwind(Box::new(Cancel));
+        }
+    }
+}
+
+impl Drop for Coroutine {
+    fn drop(&mut self) {
+        debug_assert_ne!(self.status.get(), CoStatus::Running, "dropping a running coroutine");
+        // a body that never ran is simply dropped; one stopped in suspend
+        // is unwound from there
+        if self.status.get() == CoStatus::Suspended && self.body.take().is_none() {
+            self.cancel.set(true);
+            let _ = self.resume();
+            if self.status.get() != CoStatus::Finished {
+                // the body caught Cancel and suspended again: its frames
+                // still live on the stack, which must stay mapped
+                std::mem::forget(std::mem::replace(&mut self.stack, Stack::EMPTY));
+            }
+        }
+    }
+}
+
+/// First code on a coroutine stack (entered from skyla_coroutine_start)
+extern "C" fn coroutine_main(co: *const Coroutine) -> ! {
+    let co = unsafe { &*co };
+    let body = co.body.take().expect("coroutine started twice");
+    if let Err(payload) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(body)) {
+        co.panic.set(Some(payload));
+    }
+    co.status.set(CoStatus::Finished);
+    let mut dead = ptr::null_mut();
+    unsafe { arch::skyla_switch_context(&mut dead, co.caller_sp.get()) };
+    // a finished coroutine is never switched to again
+    std::process::abort()
+}
+
+/// A mapped stack with its guard region
+struct Stack {
+    base: *mut u8,
+    len: usize,
+}
+
+impl Stack {
+    const EMPTY: Stack = Stack { base: ptr::null_mut(), len: 0 };
+
+    /// Highest address of the stack (stacks grow down), 16-aligned
+    fn top(&self) -> *mut u8 {
+        unsafe { self.base.add(self.len) }
+    }
+}
+
+#[cfg(all(unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod os {
+    use std::os::raw::{c_int, c_long, c_void};
+
+    pub const PROT_NONE: c_int = 0;
+    pub const PROT_READ: c_int = 1;
+    pub const PROT_WRITE: c_int = 2;
+    pub const MAP_PRIVATE: c_int = 2;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub const MAP_ANONYMOUS: c_int = 0x20;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub const MAP_ANONYMOUS: c_int = 0x1000;
+    pub const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+    extern "C" {
+        pub fn mmap(addr: *mut c_void, len: usize, prot: c_int, flags: c_int, fd: c_int, off: c_long) -> *mut c_void;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        pub fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+}
+
+#[cfg(all(unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl Stack {
+    fn new() -> Result<Stack, &'static str> {
+        let len = GUARD_SIZE + STACK_SIZE;
+        unsafe {
+            let p = os::mmap(ptr::null_mut(), len, os::PROT_READ | os::PROT_WRITE, os::MAP_PRIVATE | os::MAP_ANONYMOUS, -1, 0);
+            if p == os::MAP_FAILED {
+                return Err("not enough memory");
+            }
+            if os::mprotect(p, GUARD_SIZE, os::PROT_NONE) != 0 {
+                os::munmap(p, len);
+                return Err("not enough memory");
+            }
+            Ok(Stack { base: p as *mut u8, len })
+        }
+    }
+}
+
+#[cfg(all(unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe { os::munmap(self.base as *mut _, self.len) };
+        }
+    }
+}
+
+#[cfg(not(all(unix, any(target_arch = "x86_64", target_arch = "aarch64"))))]
+impl Stack {
+    fn new() -> Result<Stack, &'static str> {
+        Err("coroutines are not supported on this target")
+    }
+}
+
+// Assembly symbols carry a leading underscore on Apple targets
+#[cfg(target_vendor = "apple")]
+macro_rules! asm_symbol {
+    ($name:literal) => {
+        concat!("_", $name)
+    };
+}
+#[cfg(not(target_vendor = "apple"))]
+macro_rules! asm_symbol {
+    ($name:literal) => {
+        $name
+    };
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+mod arch {
+    use std::ptr;
+
+    // skyla_switch_context(save_sp: rdi, new_sp: rsi): push the System V
+    // callee-saved registers and the SSE/x87 control words, store rsp in
+    // *save_sp, load new_sp and pop the same frame off it. A new stack
+    // starts with such a frame whose return address is
+    // skyla_coroutine_start, r13 the entry function and r12 its argument.
+    std::arch::global_asm!(
+        ".text",
+        ".p2align 4",
+        concat!(".globl ", asm_symbol!("skyla_switch_context")),
+        concat!(asm_symbol!("skyla_switch_context"), ":"),
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "sub rsp, 8",
+        "stmxcsr dword ptr [rsp]",
+        "fnstcw word ptr [rsp + 4]",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "ldmxcsr dword ptr [rsp]",
+        "fldcw word ptr [rsp + 4]",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        ".p2align 4",
+        concat!(".globl ", asm_symbol!("skyla_coroutine_start")),
+        concat!(asm_symbol!("skyla_coroutine_start"), ":"),
+        "mov rdi, r12",
+        "call r13",
+        "ud2",
+    );
+
+    extern "C" {
+        pub fn skyla_switch_context(save_sp: *mut *mut u8, new_sp: *mut u8);
+        fn skyla_coroutine_start();
+    }
+
+    /// Lay out the first frame skyla_switch_context pops on a new stack
+    pub unsafe fn init_stack(top: *mut u8, entry: usize, arg: usize) -> *mut u8 {
+        let sp = top.sub(64) as *mut usize;
+        let frame = [
+            (0x037F << 32) | 0x1F80, // default MXCSR, x87 control word
+            0,                       // r15
+            0,                       // r14
+            entry,                   // r13
+            arg,                     // r12
+            0,                       // rbx
+            0,                       // rbp
+            skyla_coroutine_start as usize,
+        ];
+        ptr::copy_nonoverlapping(frame.as_ptr(), sp, frame.len());
+        sp as *mut u8
+    }
+}
+
+#[cfg(all(unix, target_arch = "aarch64"))]
+mod arch {
+    use std::ptr;
+
+    // skyla_switch_context(save_sp: x0, new_sp: x1): store the AAPCS64
+    // callee-saved registers (x19-x30, d8-d15) and FPCR below sp, store sp
+    // in *save_sp, load new_sp and restore the same frame from it. A new
+    // stack starts with such a frame whose x30 is skyla_coroutine_start,
+    // x20 the entry function and x19 its argument.
+    std::arch::global_asm!(
+        ".text",
+        ".p2align 4",
+        concat!(".globl ", asm_symbol!("skyla_switch_context")),
+        concat!(asm_symbol!("skyla_switch_context"), ":"),
+        "sub sp, sp, #176",
+        "stp x19, x20, [sp, #0]",
+        "stp x21, x22, [sp, #16]",
+        "stp x23, x24, [sp, #32]",
+        "stp x25, x26, [sp, #48]",
+        "stp x27, x28, [sp, #64]",
+        "stp x29, x30, [sp, #80]",
+        "stp d8, d9, [sp, #96]",
+        "stp d10, d11, [sp, #112]",
+        "stp d12, d13, [sp, #128]",
+        "stp d14, d15, [sp, #144]",
+        "mrs x9, fpcr",
+        "str x9, [sp, #160]",
+        "mov x9, sp",
+        "str x9, [x0]",
+        "mov sp, x1",
+        "ldr x9, [sp, #160]",
+        "msr fpcr, x9",
+        "ldp x19, x20, [sp, #0]",
+        "ldp x21, x22, [sp, #16]",
+        "ldp x23, x24, [sp, #32]",
+        "ldp x25, x26, [sp, #48]",
+        "ldp x27, x28, [sp, #64]",
+        "ldp x29, x30, [sp, #80]",
+        "ldp d8, d9, [sp, #96]",
+        "ldp d10, d11, [sp, #112]",
+        "ldp d12, d13, [sp, #128]",
+        "ldp d14, d15, [sp, #144]",
+        "add sp, sp, #176",
+        "ret",
+        ".p2align 4",
+        concat!(".globl ", asm_symbol!("skyla_coroutine_start")),
+        concat!(asm_symbol!("skyla_coroutine_start"), ":"),
+        "mov x0, x19",
+        "blr x20",
+        "brk #0",
+    );
+
+    extern "C" {
+        pub fn skyla_switch_context(save_sp: *mut *mut u8, new_sp: *mut u8);
+        fn skyla_coroutine_start();
+    }
+
+    /// Lay out the first frame skyla_switch_context restores on a new stack
+    pub unsafe fn init_stack(top: *mut u8, entry: usize, arg: usize) -> *mut u8 {
+        let sp = top.sub(176) as *mut usize;
+        ptr::write_bytes(sp, 0, 22); // everything else, FPCR included, starts at 0
+        *sp = arg; // x19
+        *sp.add(1) = entry; // x20
+        *sp.add(11) = skyla_coroutine_start as usize; // x30
+        sp as *mut u8
+    }
+}
+
+#[cfg(not(all(unix, any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod arch {
+    // Stack::new fails first on these targets, so nothing here runs
+    pub unsafe fn skyla_switch_context(_save_sp: *mut *mut u8, _new_sp: *mut u8) {
+        unreachable!("coroutines are not supported on this target")
+    }
+
+    pub unsafe fn init_stack(_top: *mut u8, _entry: usize, _arg: usize) -> *mut u8 {
+        unreachable!("coroutines are not supported on this target")
+    }
+}
+
+#[cfg(all(test, unix, any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_switch_and_resume() {
+        let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let slot: Rc<Cell<*const Coroutine>> = Rc::new(Cell::new(ptr::null()));
+        let co = Coroutine::new({
+            let (log, slot) = (log.clone(), slot.clone());
+            move || {
+                let co = unsafe { &*slot.get() };
+                for i in 0..3 {
+                    log.borrow_mut().push(i);
+                    co.suspend();
+                }
+                // floating point state survives the switches
+                log.borrow_mut().push((0.1f64 + 0.2 * 10.0) as i32);
+            }
+        }).unwrap();
+        slot.set(&*co);
+        assert_eq!(co.resume().ok(), Some(false));
+        assert_eq!(co.resume().ok(), Some(false));
+        assert_eq!(co.resume().ok(), Some(false));
+        assert_eq!(co.status(), CoStatus::Suspended);
+        assert_eq!(co.resume().ok(), Some(true));
+        assert_eq!(co.status(), CoStatus::Finished);
+        assert_eq!(*log.borrow(), vec![0, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_panics_and_cancellation_unwind_the_stack() {
+        let co = Coroutine::new(|| std::panic::resume_unwind(Box::new(7i32))).unwrap();
+        let payload = co.resume().unwrap_err();
+        assert_eq!(payload.downcast_ref::<i32>(), Some(&7));
+
+        // dropping a suspended coroutine drops the values its frames hold
+        struct Flag(Rc<Cell<bool>>);
+        impl Drop for Flag {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+        let dropped = Rc::new(Cell::new(false));
+        let slot: Rc<Cell<*const Coroutine>> = Rc::new(Cell::new(ptr::null()));
+        let co = Coroutine::new({
+            let (flag, slot) = (Flag(dropped.clone()), slot.clone());
+            move || {
+                let _flag = flag;
+                unsafe { &*slot.get() }.suspend();
+                unreachable!("a cancelled coroutine does not continue");
+            }
+        }).unwrap();
+        slot.set(&*co);
+        assert_eq!(co.resume().ok(), Some(false));
+        assert!(!dropped.get());
+        drop(co);
+        assert!(dropped.get());
+    }
+}
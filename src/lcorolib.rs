@@ -1,11 +1,16 @@
 //! lcorolib.rs
 //! Coroutine library for Lua Skylet (Rust version).
-//! Provides coroutine.create, coroutine.resume, coroutine.yield, coroutine.status, coroutine.wrap, coroutine.yieldable.
+//! Provides coroutine.create, coroutine.resume, coroutine.yield, coroutine.status, coroutine.wrap,
+//! coroutine.running, coroutine.isyieldable (also as coroutine.yieldable) and coroutine.close
+//! (and coroutine.reset with the `skyla_ext` feature).
 
 use crate::lapi::*;
 use crate::lobject::*;
 use crate::lstate::*;
-use std::os::raw::{c_int, c_void};
+use crate::lauxlib::{luaL_Reg, luaL_newlib, luaL_getsubtable, luaL_traceback, luaL_typeerror, lua_rawget, lua_rawset, lua_remove, lua_setmetatable};
+#[cfg(feature = "skyla_ext")]
+use crate::lauxlib::luaL_setfuncs;
+use std::os::raw::{c_char, c_int, c_void};
 
 /// Coroutine status codes modeled after Lua's
 #[repr(i32)]
@@ -16,11 +21,54 @@ pub enum CoroutineStatus {
     Error = 2,
 }
 
+/// Registry field holding the tracebacks of coroutines that died with an
+/// error, keyed by thread (weak keys, so entries go with their coroutine)
+pub const LUA_COTRACEBACKS: &[u8] = b"_COTRACEBACKS\0";
+
+// Capture the traceback of `co` (the value at `coidx` in L), which just
+// failed with its error object on top, before the error is moved out
+unsafe fn save_traceback(L: *mut lua_State, co: *mut lua_State, coidx: c_int) {
+    let msg = if lua_type(co, -1) == LUA_TSTRING { lua_tolstring(co, -1, std::ptr::null_mut()) } else { std::ptr::null() };
+    if luaL_getsubtable(L, LUA_REGISTRYINDEX, LUA_COTRACEBACKS.as_ptr() as *const c_char) == 0 {
+        lua_createtable(L, 0, 1); // new table: make its keys weak
+        lua_pushstring(L, cstr!("k"));
+        lua_setfield(L, -2, cstr!("__mode"));
+        lua_setmetatable(L, -2);
+    }
+    lua_pushvalue(L, coidx);
+    luaL_traceback(L, co, msg, 0);
+    lua_rawset(L, -3);
+    lua_pop(L, 1);
+}
+
+/// Push the traceback captured when the coroutine at `idx` failed, or nil
+/// if it has not failed (since its last reset). Returns the pushed type.
+pub unsafe fn lua_gettracebackof(L: *mut lua_State, idx: c_int) -> c_int {
+    let idx = if idx > 0 || idx <= LUA_REGISTRYINDEX { idx } else { lua_gettop(L) + idx + 1 };
+    if lua_getfield(L, LUA_REGISTRYINDEX, LUA_COTRACEBACKS.as_ptr() as *const c_char) != LUA_TTABLE {
+        return LUA_TNIL; // no coroutine has failed yet: the nil is the answer
+    }
+    lua_pushvalue(L, idx);
+    let t = lua_rawget(L, -2);
+    lua_remove(L, -2);
+    t
+}
+
+/// The coroutine at argument 1, or "bad argument #1 to 'f' (coroutine
+/// expected, got <type>)" (getco)
+unsafe fn getco(L: *mut lua_State) -> *mut lua_State {
+    let co = lua_tothread(L, 1);
+    if co.is_null() {
+        luaL_typeerror(L, 1, cstr!("coroutine"));
+    }
+    co
+}
+
 /// coroutine.create(f)
 /// Creates a new coroutine running function `f`.
 /// Returns the new coroutine thread.
 #[no_mangle]
-pub unsafe extern "C" fn luaB_cocreate(L: *mut lua_State) -> c_int {
+pub unsafe extern "C-unwind" fn luaB_cocreate(L: *mut lua_State) -> c_int {
     luaL_checktype(L, 1, LUA_TFUNCTION); // ensure argument is function
     let co = lua_newthread(L);           // create new coroutine thread
     lua_pushvalue(L, 1);                 // push function onto stack
@@ -29,145 +77,245 @@ pub unsafe extern "C" fn luaB_cocreate(L: *mut lua_State) -> c_int {
     1
 }
 
+/// Resume `co` (the value at `coidx` in L) with the top `narg` values of L
+/// and move what it yields or returns to L, returning how many; on an
+/// error, move the error object and return -1 (auxresume)
+unsafe fn auxresume(L: *mut lua_State, co: *mut lua_State, narg: c_int, coidx: c_int) -> c_int {
+    if lua_checkstack(co, narg) == 0 {
+        lua_pushstring(L, cstr!("too many arguments to resume"));
+        return -1;
+    }
+    let before = lua_status(co);
+    lua_xmove(L, co, narg);
+    let mut nres = 0;
+    let status = lua_resume(co, L, narg, &mut nres);
+    if status == LUA_OK || status == LUA_YIELD {
+        if lua_checkstack(L, nres + 1) == 0 {
+            lua_pop(co, nres); // remove results anyway
+            lua_pushstring(L, cstr!("too many results to resume"));
+            return -1;
+        }
+        lua_xmove(co, L, nres); // move yielded values
+        nres
+    } else {
+        // only a coroutine that dies now has frames worth a traceback; the
+        // error of resuming a dead or running one is not its own
+        if before <= LUA_YIELD && lua_status(co) > LUA_YIELD {
+            save_traceback(L, co, coidx);
+        }
+        lua_xmove(co, L, 1); // move error message
+        -1
+    }
+}
+
 /// coroutine.resume(co, ...)
 /// Resumes coroutine `co` with arguments.
 /// Returns: true + results on success, false + error message on failure.
 #[no_mangle]
-pub unsafe extern "C" fn luaB_coresume(L: *mut lua_State) -> c_int {
-    let co = lua_tothread(L, 1);
-    if co.is_null() {
-        lua_pushboolean(L, 0);
-        lua_pushstring(L, cstr!("bad argument #1 (coroutine expected)"));
-        return 2;
-    }
-    let status = lua_status(co);
-    if status != LUA_YIELD && status != LUA_OK {
+pub unsafe extern "C-unwind" fn luaB_coresume(L: *mut lua_State) -> c_int {
+    let co = getco(L);
+    let r = auxresume(L, co, lua_gettop(L) - 1, 1);
+    if r < 0 {
         lua_pushboolean(L, 0);
-        lua_pushstring(L, cstr!("cannot resume dead coroutine"));
-        return 2;
-    }
-    let nargs = lua_gettop(L) - 1;
-    lua_xmove(L, co, nargs);
-    let status = lua_resume(co, L, nargs);
-    if status == LUA_OK || status == LUA_YIELD {
-        lua_pushboolean(L, 1);
-        let nresults = lua_gettop(co);
-        lua_xmove(co, L, nresults);
-        return (nresults + 1) as c_int;
+        lua_insert(L, -2);
+        2 // return false + error message
     } else {
-        lua_pushboolean(L, 0);
-        // Push error message from coroutine stack
-        if lua_gettop(co) > 0 {
-            lua_xmove(co, L, 1);
-        } else {
-            lua_pushstring(L, cstr!("coroutine error"));
-        }
-        return 2;
+        lua_pushboolean(L, 1);
+        lua_insert(L, -(r + 1));
+        r + 1 // return true + 'resume' returns
     }
 }
 
 /// coroutine.yield(...)
 /// Yields the running coroutine, returning values to the resumer.
 #[no_mangle]
-pub unsafe extern "C" fn luaB_yield(L: *mut lua_State) -> c_int {
+pub unsafe extern "C-unwind" fn luaB_yield(L: *mut lua_State) -> c_int {
     let n = lua_gettop(L);
     lua_yield(L, n)
 }
 
-/// coroutine.status(co)
-/// Returns the status string of a coroutine: "running", "suspended", "normal", or "dead".
-#[no_mangle]
-pub unsafe extern "C" fn luaB_costatus(L: *mut lua_State) -> c_int {
-    let co = lua_tothread(L, 1);
-    if co.is_null() {
-        luaL_error(L, cstr!("bad argument #1 (coroutine expected)"));
-        return 0; // unreachable
-    }
-    let status = lua_status(co);
-    let status_str = if co == lua_pushthread(L) {
-        // running coroutine
-        lua_pop(L, 1);
+/// Status of coroutine `co` as seen from `L`: "running", "suspended",
+/// "normal" or "dead" (auxstatus)
+unsafe fn auxstatus(L: *mut lua_State, co: *mut lua_State) -> &'static str {
+    if L == co {
         "running"
     } else {
-        match status {
+        match lua_status(co) {
             LUA_YIELD => "suspended",
             LUA_OK => {
-                // If stack is empty, coroutine is dead
-                if lua_gettop(co) == 0 {
+                let mut ar = crate::lauxlib::lua_Debug::new();
+                if crate::ldebug::lua_getstack(co.cast(), 0, &mut ar) != 0 {
+                    "normal" // it has frames: it resumed the running one
+                } else if lua_gettop(co) == 0 {
                     "dead"
                 } else {
-                    "normal"
+                    "suspended" // initial state
                 }
             }
-            _ => "dead",
+            _ => "dead", // some error occurred
         }
-    };
-    lua_pushstring(L, cstr!(status_str));
+    }
+}
+
+/// coroutine.status(co)
+/// Returns the status string of a coroutine: "running", "suspended", "normal", or "dead".
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaB_costatus(L: *mut lua_State) -> c_int {
+    let co = getco(L);
+    lua_pushstring(L, cstr!(auxstatus(L, co)));
     1
 }
 
+/// coroutine.running()
+/// Returns the running coroutine and whether it is the main thread.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaB_corunning(L: *mut lua_State) -> c_int {
+    let ismain = lua_pushthread(L);
+    lua_pushboolean(L, ismain);
+    2
+}
+
+/// coroutine.close(co)
+/// Closes a suspended or dead coroutine, closing its pending to-be-closed
+/// variables. Returns true, or false and the error object if the
+/// coroutine had died with an error or one was raised while closing.
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaB_close(L: *mut lua_State) -> c_int {
+    let co = getco(L);
+    match auxstatus(L, co) {
+        "suspended" | "dead" => {
+            if lua_closethread(co, L) == LUA_OK {
+                lua_pushboolean(L, 1);
+                1
+            } else {
+                lua_pushboolean(L, 0);
+                lua_xmove(co, L, 1); // error object
+                2
+            }
+        }
+        status => crate::luaL_error!(L.cast(), "cannot close a {} coroutine", status),
+    }
+}
+
 /// coroutine.wrap(f)
 /// Returns a function that resumes the coroutine created from `f`.
 #[no_mangle]
-pub unsafe extern "C" fn luaB_cowrap(L: *mut lua_State) -> c_int {
+pub unsafe extern "C-unwind" fn luaB_cowrap(L: *mut lua_State) -> c_int {
     luaB_cocreate(L); // pushes coroutine thread
-    lua_pushcclosure(L, Some(luaB_auxwrap), 1); // closure with coroutine as upvalue
+    lua_pushcclosure(L, luaB_auxwrap, 1); // closure with coroutine as upvalue
     1
 }
 
-/// Auxiliary function used by `coroutine.wrap`.
-unsafe extern "C" fn luaB_auxwrap(L: *mut lua_State) -> c_int {
+/// Auxiliary function used by `coroutine.wrap`: errors propagate to the caller.
+unsafe extern "C-unwind" fn luaB_auxwrap(L: *mut lua_State) -> c_int {
     let co = lua_tothread(L, lua_upvalueindex(1));
-    let nargs = lua_gettop(L);
-    lua_xmove(L, co, nargs);
-    let status = lua_resume(co, L, nargs);
-    if status == LUA_OK || status == LUA_YIELD {
-        let nresults = lua_gettop(co);
-        lua_xmove(co, L, nresults);
-        return nresults as c_int;
-    } else {
-        // propagate error as Lua error
-        if lua_gettop(co) > 0 {
-            lua_xmove(co, L, 1);
-        } else {
-            lua_pushstring(L, cstr!("error in coroutine wrap"));
-        }
-        lua_error(L);
-        unreachable!();
+    let r = auxresume(L, co, lua_gettop(L), lua_upvalueindex(1));
+    if r < 0 {
+        lua_error(L); // propagate error
     }
+    r
 }
 
-/// coroutine.yieldable()
-/// Returns true if the running coroutine can yield.
+/// coroutine.isyieldable([co])
+/// Returns true if coroutine `co` (by default the running one) can yield.
 #[no_mangle]
-pub unsafe extern "C" fn lua_yieldable(L: *mut lua_State) -> c_int {
-    let yieldable = lua_isyieldable(L);
-    lua_pushboolean(L, if yieldable != 0 { 1 } else { 0 });
+pub unsafe extern "C-unwind" fn lua_yieldable(L: *mut lua_State) -> c_int {
+    let co = if lua_type(L, 1) == LUA_TNONE { L } else { getco(L) };
+    lua_pushboolean(L, lua_isyieldable(co));
     1
 }
 
-/// Creates the coroutine library table and registers functions.
-pub unsafe fn luaopen_coroutine(L: *mut lua_State) -> c_int {
-    lua_newtable(L);
-
-    // Register coroutine functions
-    lua_pushcfunction(L, Some(luaB_cocreate));
-    lua_setfield(L, -2, cstr!("create"));
-
-    lua_pushcfunction(L, Some(luaB_coresume));
-    lua_setfield(L, -2, cstr!("resume"));
+/// coroutine.reset(co [, f]) [skyla_ext]
+/// Closes a suspended or dead coroutine and resets it for reuse, so thread
+/// objects can be pooled. With `f`, the coroutine is left ready to run `f`
+/// as if just created by coroutine.create(f). Returns true, or false and
+/// the error object if the coroutine had died with an error.
+#[cfg(feature = "skyla_ext")]
+#[no_mangle]
+pub unsafe extern "C-unwind" fn luaB_coreset(L: *mut lua_State) -> c_int {
+    let co = getco(L);
+    if co == L {
+        luaL_error(L, cstr!("cannot reset a running coroutine"));
+        return 0; // unreachable
+    }
+    let status = lua_closethread(co, L);
+    if lua_getfield(L, LUA_REGISTRYINDEX, LUA_COTRACEBACKS.as_ptr() as *const c_char) == LUA_TTABLE {
+        lua_pushvalue(L, 1);
+        lua_pushnil(L);
+        lua_rawset(L, -3); // a reused coroutine starts without a traceback
+    }
+    lua_pop(L, 1);
+    if lua_type(L, 2) > LUA_TNIL {
+        luaL_checktype(L, 2, LUA_TFUNCTION);
+        lua_settop(co, 0); // drop the error object, if any
+        lua_pushvalue(L, 2);
+        lua_xmove(L, co, 1);
+    }
+    if status == LUA_OK {
+        lua_pushboolean(L, 1);
+        1
+    } else {
+        lua_pushboolean(L, 0);
+        if lua_gettop(co) > 0 {
+            lua_xmove(co, L, 1); // error object
+        } else {
+            lua_pushstring(L, cstr!("coroutine error"));
+        }
+        2
+    }
+}
 
-    lua_pushcfunction(L, Some(luaB_yield));
-    lua_setfield(L, -2, cstr!("yield"));
+// Functions of the coroutine library (mimics luaL_Reg co_funcs[])
+static CO_FUNCS: &[luaL_Reg] = &[
+    luaL_Reg { name: b"create\0".as_ptr() as *const c_char, func: Some(luaB_cocreate) },
+    luaL_Reg { name: b"resume\0".as_ptr() as *const c_char, func: Some(luaB_coresume) },
+    luaL_Reg { name: b"yield\0".as_ptr() as *const c_char, func: Some(luaB_yield) },
+    luaL_Reg { name: b"status\0".as_ptr() as *const c_char, func: Some(luaB_costatus) },
+    luaL_Reg { name: b"wrap\0".as_ptr() as *const c_char, func: Some(luaB_cowrap) },
+    luaL_Reg { name: b"yieldable\0".as_ptr() as *const c_char, func: Some(lua_yieldable) },
+    luaL_Reg { name: b"isyieldable\0".as_ptr() as *const c_char, func: Some(lua_yieldable) },
+    luaL_Reg { name: b"running\0".as_ptr() as *const c_char, func: Some(luaB_corunning) },
+    luaL_Reg { name: b"close\0".as_ptr() as *const c_char, func: Some(luaB_close) },
+    luaL_Reg { name: std::ptr::null(), func: None },
+];
 
-    lua_pushcfunction(L, Some(luaB_costatus));
-    lua_setfield(L, -2, cstr!("status"));
+// Skyla extensions to the coroutine library (enabled with the `skyla_ext` feature)
+#[cfg(feature = "skyla_ext")]
+static CO_EXT_FUNCS: &[luaL_Reg] = &[
+    luaL_Reg { name: b"reset\0".as_ptr() as *const c_char, func: Some(luaB_coreset) },
+    luaL_Reg { name: std::ptr::null(), func: None },
+];
 
-    lua_pushcfunction(L, Some(luaB_cowrap));
-    lua_setfield(L, -2, cstr!("wrap"));
+/// Creates the coroutine library table and registers functions.
+pub unsafe fn luaopen_coroutine(L: *mut lua_State) -> c_int {
+    luaL_newlib(L, CO_FUNCS);
+    #[cfg(feature = "skyla_ext")]
+    luaL_setfuncs(L, CO_EXT_FUNCS.as_ptr(), 0);
+    1
+}
+// The coroutine functions for the safe API. They run the C functions above
+// on the state, the same bridge ldblib::require_debug uses.
+const CO_LIB: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("create", |L| unsafe { luaB_cocreate((L as *mut LuaState).cast()) }),
+    ("resume", |L| unsafe { luaB_coresume((L as *mut LuaState).cast()) }),
+    ("yield", |L| unsafe { luaB_yield((L as *mut LuaState).cast()) }),
+    ("status", |L| unsafe { luaB_costatus((L as *mut LuaState).cast()) }),
+    ("wrap", |L| unsafe { luaB_cowrap((L as *mut LuaState).cast()) }),
+    ("yieldable", |L| unsafe { lua_yieldable((L as *mut LuaState).cast()) }),
+    ("isyieldable", |L| unsafe { lua_yieldable((L as *mut LuaState).cast()) }),
+    ("running", |L| unsafe { luaB_corunning((L as *mut LuaState).cast()) }),
+    ("close", |L| unsafe { luaB_close((L as *mut LuaState).cast()) }),
+];
 
-    lua_pushcfunction(L, Some(lua_yieldable));
-    lua_setfield(L, -2, cstr!("yieldable"));
+#[cfg(feature = "skyla_ext")]
+const CO_EXT_LIB: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("reset", |L| unsafe { luaB_coreset((L as *mut LuaState).cast()) }),
+];
 
-    1
-}
\ No newline at end of file
+/// Register the coroutine functions in the coroutine library table
+pub fn open_coroutine_lib(state: &mut LuaState) {
+    let lib = state.lib_table(crate::skylalib::LUA_COLIBNAME);
+    state.set_funcs(&lib, CO_LIB);
+    #[cfg(feature = "skyla_ext")]
+    state.set_funcs(&lib, CO_EXT_LIB);
+}
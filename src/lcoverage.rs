@@ -0,0 +1,218 @@
+//! lcoverage.rs - Line coverage for Lua code run by the VM
+//
+// When enabled, luaV_execute reports the (source, line) of every instruction
+// through the `skyla_coverage!` macro (compiled in with `--features coverage`).
+// A line is counted once each time execution enters it; all lines that carry
+// code in a prototype are registered with a zero count the first time that
+// prototype runs, so unexecuted lines show up in the report. Results can be
+// merged across runs and written as an lcov tracefile (genhtml, Codecov, ...).
+// The stand-alone interpreter writes SKYLA_COVERAGE=<file> at exit.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// Executed lines per source: source -> line -> hit count
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LineCoverage {
+    pub files: BTreeMap<String, BTreeMap<u32, u64>>,
+}
+
+/// Strip the chunkname prefix ('@' for files, '=' for literal names)
+fn source_name(chunkname: &str) -> &str {
+    chunkname.strip_prefix('@').or_else(|| chunkname.strip_prefix('=')).unwrap_or(chunkname)
+}
+
+impl LineCoverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a line that carries code without counting a hit
+    pub fn add_line(&mut self, source: &str, line: u32) {
+        if line == 0 { return; }
+        self.files.entry(source_name(source).to_string()).or_default().entry(line).or_insert(0);
+    }
+
+    /// Count one execution of (source, line)
+    pub fn hit(&mut self, source: &str, line: u32) {
+        if line == 0 { return; }
+        *self.files.entry(source_name(source).to_string()).or_default().entry(line).or_insert(0) += 1;
+    }
+
+    /// Add the counts of another run into this one
+    pub fn merge(&mut self, other: &LineCoverage) {
+        for (file, lines) in &other.files {
+            let dst = self.files.entry(file.clone()).or_default();
+            for (&line, &n) in lines {
+                *dst.entry(line).or_insert(0) += n;
+            }
+        }
+    }
+
+    /// (lines found, lines hit) for one source
+    pub fn summary(&self, source: &str) -> (usize, usize) {
+        self.files.get(source_name(source))
+            .map(|l| (l.len(), l.values().filter(|&&n| n > 0).count()))
+            .unwrap_or((0, 0))
+    }
+
+    /// Render as an lcov tracefile
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        for (file, lines) in &self.files {
+            out.push_str("TN:\n");
+            out.push_str(&format!("SF:{}\n", file));
+            for (line, n) in lines {
+                out.push_str(&format!("DA:{},{}\n", line, n));
+            }
+            out.push_str(&format!("LF:{}\n", lines.len()));
+            out.push_str(&format!("LH:{}\n", lines.values().filter(|&&n| n > 0).count()));
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+
+    /// Parse the SF/DA records of an lcov tracefile (other records are ignored)
+    pub fn from_lcov(text: &str) -> LineCoverage {
+        let mut cov = LineCoverage::new();
+        let mut current: Option<String> = None;
+        for line in text.lines() {
+            if let Some(sf) = line.strip_prefix("SF:") {
+                current = Some(sf.to_string());
+            } else if let Some(da) = line.strip_prefix("DA:") {
+                let (Some(file), Some((l, n))) = (current.as_ref(), da.split_once(',')) else { continue };
+                // DA may carry a trailing checksum field: DA:<line>,<count>[,<sum>]
+                let n = n.split(',').next().unwrap_or(n);
+                if let (Ok(l), Ok(n)) = (l.parse::<u32>(), n.parse::<u64>()) {
+                    *cov.files.entry(file.clone()).or_default().entry(l).or_insert(0) += n;
+                }
+            } else if line == "end_of_record" {
+                current = None;
+            }
+        }
+        cov
+    }
+
+    /// Write the lcov report to `path`, adding the counts already in the
+    /// file when `merge_existing` is set
+    pub fn write_lcov(&self, path: &str, merge_existing: bool) -> std::io::Result<()> {
+        let mut all = self.clone();
+        if merge_existing {
+            if let Ok(old) = std::fs::read_to_string(path) {
+                all.merge(&LineCoverage::from_lcov(&old));
+            }
+        }
+        std::fs::write(path, all.to_lcov())
+    }
+}
+
+static COVERAGE_ON: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref LINE_COVERAGE: Mutex<LineCoverage> = Mutex::new(LineCoverage::new());
+}
+
+thread_local! {
+    /// Last (prototype, line) seen, so a line is counted once per entry
+    static LAST_LINE: RefCell<(usize, u32)> = const { RefCell::new((0, 0)) };
+    /// Prototypes whose lines were already registered
+    static SEEN_PROTOS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new());
+}
+
+/// Turn collection on or off
+pub fn set_coverage_enabled(on: bool) {
+    COVERAGE_ON.store(on, Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub fn coverage_enabled() -> bool {
+    COVERAGE_ON.load(Ordering::Relaxed)
+}
+
+/// VM line hook: `proto` identifies the running prototype, `lineinfo` gives
+/// the line of each of its instructions and `line` the line of the current
+/// instruction
+pub fn coverage_hit(proto: usize, source: &str, lineinfo: impl IntoIterator<Item = u32>, line: u32) {
+    let first = SEEN_PROTOS.with(|s| s.borrow_mut().insert(proto));
+    let new_line = LAST_LINE.with(|l| {
+        let mut l = l.borrow_mut();
+        let changed = *l != (proto, line);
+        *l = (proto, line);
+        changed
+    });
+    if !first && !new_line {
+        return;
+    }
+    let mut cov = LINE_COVERAGE.lock().unwrap();
+    if first {
+        for l in lineinfo {
+            cov.add_line(source, l);
+        }
+    }
+    if new_line {
+        cov.hit(source, line);
+    }
+}
+
+/// Copy of the coverage collected so far
+pub fn coverage_snapshot() -> LineCoverage {
+    LINE_COVERAGE.lock().unwrap().clone()
+}
+
+/// Discard collected coverage
+pub fn coverage_reset() {
+    *LINE_COVERAGE.lock().unwrap() = LineCoverage::new();
+    LAST_LINE.with(|l| *l.borrow_mut() = (0, 0));
+    SEEN_PROTOS.with(|s| s.borrow_mut().clear());
+}
+
+/// Line hook used by the VM. Expands to nothing without the `coverage` feature.
+#[macro_export]
+macro_rules! skyla_coverage {
+    ($proto:expr, $source:expr, $lineinfo:expr, $line:expr) => {{
+        #[cfg(feature = "coverage")]
+        {
+            if $crate::lcoverage::coverage_enabled() {
+                $crate::lcoverage::coverage_hit($proto, $source, $lineinfo, $line);
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage_counts_line_entries_and_unexecuted_lines() {
+        coverage_reset();
+        let lineinfo = [1, 1, 2, 4];
+        coverage_hit(1, "@a.lua", lineinfo, 1);
+        coverage_hit(1, "@a.lua", lineinfo, 1); // same line, not a new entry
+        coverage_hit(1, "@a.lua", lineinfo, 2);
+        coverage_hit(1, "@a.lua", lineinfo, 1);
+        let cov = coverage_snapshot();
+        let a = &cov.files["a.lua"];
+        assert_eq!(a[&1], 2);
+        assert_eq!(a[&2], 1);
+        assert_eq!(a[&4], 0);
+        assert_eq!(cov.summary("a.lua"), (3, 2));
+        coverage_reset();
+    }
+
+    #[test]
+    fn test_lcov_roundtrip_and_merge() {
+        let mut run1 = LineCoverage::new();
+        run1.hit("a.lua", 1);
+        run1.add_line("a.lua", 3);
+        let mut run2 = LineCoverage::new();
+        run2.hit("a.lua", 3);
+        run2.hit("b.lua", 7);
+        run1.merge(&run2);
+        let text = run1.to_lcov();
+        assert!(text.contains("SF:a.lua\nDA:1,1\nDA:3,1\nLF:2\nLH:2\nend_of_record\n"));
+        assert_eq!(LineCoverage::from_lcov(&text), run1);
+    }
+}
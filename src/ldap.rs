@@ -0,0 +1,523 @@
+//! ldap.rs - Debug Adapter Protocol server (`skyla --dap[=port] script.lua`)
+//
+// Speaks DAP over stdio or a TCP connection so editors (VS Code, ...) can
+// debug Skyla scripts. Breakpoints, stepping and variable inspection map onto
+// the same machinery as the command-line debugger (ldebugger): the server
+// installs a line hook and, while the script is stopped, serves requests from
+// the client until it resumes. Over stdio the script's own output is sent to
+// the client as output events, since stdout carries the protocol. Only built
+// with `--features dap`.
+
+use std::cell::{Cell, RefCell};
+use std::ffi::{c_char, CStr};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use crate::lauxlib::lua_Debug;
+use crate::ldebug::{lua_getinfo, lua_getstack, luaG_sethook, DebugFrame, DebugValue, HookEvent, LUA_MASKLINE};
+use crate::ldebugger::{CommandResult, DebugCommand, Debugger, StepMode};
+use crate::loutput::{OutputSink, StdoutSink};
+use crate::lstate::LuaState;
+
+/// The only thread a Skyla script runs on, as reported to the client
+const DAP_THREAD_ID: i64 = 1;
+/// Variable references for the two scopes of the stopped frame
+const LOCALS_REF: i64 = 1;
+const UPVALUES_REF: i64 = 2;
+
+/// Where the client is connected
+pub enum DapTransport {
+    Stdio,
+    /// Listen on this port and serve the first connection
+    Tcp(u16),
+}
+
+/// Read one base-protocol message ("Content-Length: N\r\n\r\n<json>")
+pub fn read_message(input: &mut dyn BufRead) -> std::io::Result<Option<Value>> {
+    let mut len = None;
+    let mut header = String::new();
+    loop {
+        header.clear();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let h = header.trim_end();
+        if h.is_empty() {
+            break;
+        }
+        if let Some(n) = h.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(len) = len else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "missing Content-Length"));
+    };
+    let mut body = vec![0u8; len];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// Write one base-protocol message
+pub fn write_message(out: &mut dyn Write, msg: &Value) -> std::io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+/// The connection to the client: sessions and the script's output sink
+/// share it, each message taking the next sequence number
+#[derive(Clone)]
+pub struct DapWriter {
+    out: Rc<RefCell<Box<dyn Write>>>,
+    seq: Rc<Cell<i64>>,
+}
+
+impl DapWriter {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        DapWriter { out: Rc::new(RefCell::new(out)), seq: Rc::new(Cell::new(0)) }
+    }
+
+    fn send(&self, mut msg: Value) {
+        self.seq.set(self.seq.get() + 1);
+        msg["seq"] = json!(self.seq.get());
+        if let Err(e) = write_message(&mut **self.out.borrow_mut(), &msg) {
+            eprintln!("[ldap] write failed: {}", e);
+        }
+    }
+
+    /// Send an event to the client
+    pub fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+}
+
+/// A script's print and io.write output, sent as "output" events
+pub struct DapOutputSink(pub DapWriter);
+
+impl OutputSink for DapOutputSink {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let text = String::from_utf8_lossy(data);
+        self.0.event("output", json!({ "category": "stdout", "output": text }));
+        Ok(())
+    }
+}
+
+/// What the server should do after handling a request
+#[derive(Debug, PartialEq)]
+pub enum DapAction {
+    None,
+    /// Leave the stopped state and run the script
+    Resume,
+    Disconnect,
+}
+
+/// Protocol state: the debugger, the stopped frame and the outgoing sequence
+pub struct DapSession {
+    pub debugger: Debugger,
+    pub program: Option<String>,
+    pub configured: bool,
+    stopped: Option<DebugFrame>,
+    writer: DapWriter,
+}
+
+fn vars_json(vars: &[(String, DebugValue)]) -> Value {
+    Value::Array(vars.iter().map(|(n, v)| json!({
+        "name": n,
+        "value": v.to_string(),
+        "variablesReference": 0,
+    })).collect())
+}
+
+impl DapSession {
+    pub fn new(writer: DapWriter) -> Self {
+        DapSession { debugger: Debugger::new(), program: None, configured: false, stopped: None, writer }
+    }
+
+    fn send(&mut self, msg: Value) {
+        self.writer.send(msg);
+    }
+
+    /// Send an event to the client
+    pub fn event(&mut self, event: &str, body: Value) {
+        self.writer.event(event, body);
+    }
+
+    fn respond(&mut self, req: &Value, success: bool, body: Value) {
+        let msg = json!({
+            "type": "response",
+            "request_seq": req["seq"],
+            "command": req["command"],
+            "success": success,
+            "body": body,
+        });
+        self.send(msg);
+    }
+
+    fn error(&mut self, req: &Value, message: &str) {
+        let mut msg = json!({
+            "type": "response",
+            "request_seq": req["seq"],
+            "command": req["command"],
+            "success": false,
+        });
+        msg["message"] = json!(message);
+        self.send(msg);
+    }
+
+    /// Report that execution stopped at `frame`
+    pub fn stop_at(&mut self, frame: DebugFrame) {
+        let reason = if self.debugger.at_breakpoint(&frame) { "breakpoint" } else { "step" };
+        self.stopped = Some(frame);
+        self.event("stopped", json!({ "reason": reason, "threadId": DAP_THREAD_ID, "allThreadsStopped": true }));
+    }
+
+    fn resume_with(&mut self, state: &mut LuaState, req: &Value, cmd: DebugCommand) -> DapAction {
+        let frame = self.stopped.take().unwrap_or_default();
+        self.debugger.execute(state, cmd, &frame);
+        self.respond(req, true, json!({ "allThreadsContinued": true }));
+        DapAction::Resume
+    }
+
+    /// Handle one request from the client; expressions are evaluated in
+    /// the paused frame of `state`
+    pub fn handle(&mut self, state: &mut LuaState, req: &Value) -> DapAction {
+        let args = &req["arguments"];
+        match req["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.respond(req, true, json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsEvaluateForHovers": true,
+                }));
+                self.event("initialized", json!({}));
+            }
+            "launch" => {
+                self.program = args["program"].as_str().map(str::to_string);
+                if !args["stopOnEntry"].as_bool().unwrap_or(false) {
+                    self.debugger.mode = StepMode::Continue;
+                }
+                if self.program.is_some() {
+                    self.respond(req, true, json!({}));
+                } else {
+                    self.error(req, "launch needs a 'program'");
+                }
+            }
+            "setBreakpoints" => {
+                let path = args["source"]["path"].as_str().unwrap_or("").to_string();
+                self.debugger.breakpoints.retain(|(f, _)| *f != path);
+                let mut verified = Vec::new();
+                for bp in args["breakpoints"].as_array().into_iter().flatten() {
+                    if let Some(line) = bp["line"].as_u64() {
+                        self.debugger.breakpoints.insert((path.clone(), line as u32));
+                        verified.push(json!({ "verified": true, "line": line }));
+                    }
+                }
+                self.respond(req, true, json!({ "breakpoints": verified }));
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(req, true, json!({}));
+                return DapAction::Resume;
+            }
+            "threads" => {
+                self.respond(req, true, json!({ "threads": [{ "id": DAP_THREAD_ID, "name": "main" }] }));
+            }
+            "stackTrace" => {
+                let frames = if self.stopped.is_some() { stack_frames(state) } else { Vec::new() };
+                let total = frames.len();
+                self.respond(req, true, json!({ "stackFrames": frames, "totalFrames": total }));
+            }
+            "scopes" => {
+                self.respond(req, true, json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": LOCALS_REF, "expensive": false },
+                    { "name": "Upvalues", "variablesReference": UPVALUES_REF, "expensive": false },
+                ]}));
+            }
+            "variables" => {
+                let vars = match (&self.stopped, args["variablesReference"].as_i64()) {
+                    (Some(f), Some(LOCALS_REF)) => vars_json(&f.locals),
+                    (Some(f), Some(UPVALUES_REF)) => vars_json(&f.upvalues),
+                    _ => json!([]),
+                };
+                self.respond(req, true, json!({ "variables": vars }));
+            }
+            "evaluate" => {
+                let expr = args["expression"].as_str().unwrap_or("").to_string();
+                let frame = self.stopped.clone().unwrap_or_default();
+                match self.debugger.execute(state, DebugCommand::Print(expr), &frame) {
+                    CommandResult::Stay(v) => self.respond(req, true, json!({ "result": v, "variablesReference": 0 })),
+                    _ => self.error(req, "cannot evaluate"),
+                }
+            }
+            "continue" => return self.resume_with(state, req, DebugCommand::Continue),
+            "next" => return self.resume_with(state, req, DebugCommand::Next),
+            "stepIn" => return self.resume_with(state, req, DebugCommand::Step),
+            "stepOut" => return self.resume_with(state, req, DebugCommand::Finish),
+            "pause" => {
+                self.debugger.mode = StepMode::Step;
+                self.respond(req, true, json!({}));
+            }
+            "disconnect" | "terminate" => {
+                self.respond(req, true, json!({}));
+                return DapAction::Disconnect;
+            }
+            other => {
+                let msg = format!("unsupported request '{}'", other);
+                self.error(req, &msg);
+            }
+        }
+        DapAction::None
+    }
+}
+
+/// Absolute form of a chunk's file name, which the client needs to open it
+fn absolute_path(path: &str) -> String {
+    match std::env::current_dir() {
+        Ok(cwd) if std::path::Path::new(path).is_relative() => cwd.join(path).to_string_lossy().into_owned(),
+        _ => path.to_string(),
+    }
+}
+
+/// The paused call stack, innermost first, read through lua_getstack and
+/// lua_getinfo; frames without Lua code have no source
+fn stack_frames(state: &mut LuaState) -> Vec<Value> {
+    let L = state as *mut LuaState as *mut std::ffi::c_void;
+    let cstr = |p: *const c_char| (!p.is_null()).then(|| unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned());
+    let mut frames = Vec::new();
+    let mut ar = lua_Debug::new();
+    let mut level = 0;
+    unsafe {
+        while lua_getstack(L, level, &mut ar) != 0 {
+            lua_getinfo(L, b"Sln\0".as_ptr().cast(), &mut ar);
+            let what = cstr(ar.what).unwrap_or_default();
+            let short_src = cstr(ar.short_src.as_ptr()).unwrap_or_default();
+            let name = match cstr(ar.name) {
+                Some(n) => n,
+                None if what == "main" => "main chunk".to_string(),
+                None if what == "C" => "?".to_string(),
+                None => format!("function <{}:{}>", short_src, ar.linedefined),
+            };
+            let mut frame = json!({ "id": level, "name": name, "line": ar.currentline.max(0), "column": 1 });
+            if let Some(path) = cstr(ar.source).as_deref().and_then(|s| s.strip_prefix('@')) {
+                frame["source"] = json!({ "path": absolute_path(path) });
+            }
+            frames.push(frame);
+            level += 1;
+        }
+    }
+    frames
+}
+
+// Serve requests until the client resumes execution (or disconnects)
+fn serve(session: &Rc<RefCell<DapSession>>, input: &Rc<RefCell<Box<dyn BufRead>>>, state: &mut LuaState) -> DapAction {
+    loop {
+        let msg = match read_message(&mut **input.borrow_mut()) {
+            Ok(Some(m)) => m,
+            Ok(None) => return DapAction::Disconnect,
+            Err(e) => {
+                eprintln!("[ldap] bad message: {}", e);
+                continue;
+            }
+        };
+        match session.borrow_mut().handle(state, &msg) {
+            DapAction::None => {}
+            action => return action,
+        }
+    }
+}
+
+/// Run a debug session: wait for launch/configuration, run the script in
+/// `state` via `run_program` with the line hook installed, then report its
+/// exit.
+pub fn run_dap_server(
+    transport: DapTransport,
+    state: &mut LuaState,
+    run_program: impl FnOnce(&mut LuaState, &str) -> bool,
+) -> std::io::Result<()> {
+    let stdio = matches!(transport, DapTransport::Stdio);
+    let (input, output): (Box<dyn BufRead>, Box<dyn Write>) = match transport {
+        DapTransport::Stdio => (Box::new(BufReader::new(std::io::stdin())), Box::new(std::io::stdout())),
+        DapTransport::Tcp(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            eprintln!("[ldap] listening on 127.0.0.1:{}", port);
+            let (stream, _) = listener.accept()?;
+            (Box::new(BufReader::new(stream.try_clone()?)), Box::new(stream))
+        }
+    };
+    let input = Rc::new(RefCell::new(input));
+    let writer = DapWriter::new(output);
+    let session = Rc::new(RefCell::new(DapSession::new(writer.clone())));
+
+    // Handshake: initialize, launch, setBreakpoints..., configurationDone
+    if serve(&session, &input, state) == DapAction::Disconnect {
+        return Ok(());
+    }
+    let Some(program) = session.borrow().program.clone() else {
+        return Ok(());
+    };
+
+    let (hook_session, hook_input) = (session.clone(), input.clone());
+    luaG_sethook(Some(Box::new(move |state, ev| {
+        if let HookEvent::Line(frame) = ev {
+            if hook_session.borrow().debugger.should_stop(frame) {
+                hook_session.borrow_mut().stop_at(frame.clone());
+                if serve(&hook_session, &hook_input, state) == DapAction::Disconnect {
+                    std::process::exit(0);
+                }
+            }
+        }
+    })), LUA_MASKLINE);
+    if stdio {
+        state.set_output(Box::new(DapOutputSink(writer)));
+    }
+    let ok = run_program(state, &program);
+    luaG_sethook(None, 0);
+    if stdio {
+        state.set_output(Box::new(StdoutSink::default()));
+    }
+
+    let mut s = session.borrow_mut();
+    s.event("exited", json!({ "exitCode": if ok { 0 } else { 1 } }));
+    s.event("terminated", json!({}));
+    drop(s);
+    // Let the client finish with its disconnect request
+    serve(&session, &input, state);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output sink the test can inspect after the session wrote to it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn messages(buf: &SharedBuf) -> Vec<Value> {
+        let data = buf.0.borrow().clone();
+        let mut input = &data[..];
+        let mut out = Vec::new();
+        while let Ok(Some(m)) = read_message(&mut input) {
+            out.push(m);
+        }
+        out
+    }
+
+    #[test]
+    fn test_message_framing_roundtrip() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &json!({ "seq": 1, "type": "request", "command": "threads" })).unwrap();
+        let msg = read_message(&mut &buf[..]).unwrap().unwrap();
+        assert_eq!(msg["command"], "threads");
+    }
+
+    #[test]
+    fn test_session_breakpoints_stop_and_variables() {
+        let buf = SharedBuf::default();
+        let mut s = DapSession::new(DapWriter::new(Box::new(buf.clone())));
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        s.handle(&mut state, &json!({ "seq": 1, "command": "initialize", "arguments": {} }));
+        s.handle(&mut state, &json!({ "seq": 2, "command": "launch", "arguments": { "program": "a.lua" } }));
+        s.handle(&mut state, &json!({ "seq": 3, "command": "setBreakpoints",
+            "arguments": { "source": { "path": "a.lua" }, "breakpoints": [{ "line": 4 }] } }));
+        assert_eq!(s.handle(&mut state, &json!({ "seq": 4, "command": "configurationDone" })), DapAction::Resume);
+
+        let frame = DebugFrame {
+            source: "@a.lua".to_string(),
+            line: 4,
+            depth: 0,
+            locals: vec![("n".to_string(), DebugValue::Number(3.0))],
+            upvalues: vec![],
+        };
+        assert!(s.debugger.should_stop(&frame));
+        s.stop_at(frame);
+        s.handle(&mut state, &json!({ "seq": 5, "command": "variables", "arguments": { "variablesReference": LOCALS_REF } }));
+        assert_eq!(s.handle(&mut state, &json!({ "seq": 6, "command": "next" })), DapAction::Resume);
+
+        let msgs = messages(&buf);
+        assert!(msgs.iter().any(|m| m["event"] == "initialized"));
+        let stopped = msgs.iter().find(|m| m["event"] == "stopped").unwrap();
+        assert_eq!(stopped["body"]["reason"], "breakpoint");
+        let vars = msgs.iter().find(|m| m["command"] == "variables").unwrap();
+        assert_eq!(vars["body"]["variables"][0]["name"], "n");
+        assert_eq!(vars["body"]["variables"][0]["value"], "3");
+        assert_eq!(s.debugger.mode, StepMode::Next(0));
+    }
+
+    #[test]
+    fn test_script_output_becomes_output_events() {
+        let buf = SharedBuf::default();
+        let writer = DapWriter::new(Box::new(buf.clone()));
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        state.set_output(Box::new(DapOutputSink(writer.clone())));
+        state.write_output(b"hello\n").unwrap();
+        writer.event("terminated", json!({}));
+        let msgs = messages(&buf);
+        assert_eq!(msgs[0]["event"], "output");
+        assert_eq!(msgs[0]["body"]["category"], "stdout");
+        assert_eq!(msgs[0]["body"]["output"], "hello\n");
+        // the sink and the session number their messages in one sequence
+        assert_eq!((msgs[0]["seq"].clone(), msgs[1]["seq"].clone()), (json!(1), json!(2)));
+    }
+
+    #[test]
+    fn test_stack_trace_lists_every_frame() {
+        use crate::lobject::LuaValue;
+        use crate::lstate::CallInfo;
+        use crate::lvm::{closure_value, Closure, ClosureType, Proto};
+        use std::ptr::NonNull;
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: [4, 5, 5, 8].into_iter().collect(),
+            numparams: 0,
+            is_vararg: false,
+            source: "@scripts/game.lua".to_string(),
+            linedefined: 3,
+            lastlinedefined: 9,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let mut cl = Closure { cl: ClosureType { p: &mut p }, upvals: Vec::new() };
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        state.stack.extend([closure_value(NonNull::from(&mut cl)), LuaValue::Nil]);
+        // the Lua function, stopped on its third instruction, called string.rep
+        for ci in [
+            CallInfo { func: 0, savedpc: 3, ..CallInfo::default() },
+            CallInfo { func: 1, name: Some("string.rep".to_string()), ..CallInfo::default() },
+        ] {
+            let ci = Rc::new(RefCell::new(ci));
+            ci.borrow_mut().previous = Some(state.ci.clone());
+            state.ci = ci;
+        }
+
+        let buf = SharedBuf::default();
+        let mut s = DapSession::new(DapWriter::new(Box::new(buf.clone())));
+        s.stop_at(DebugFrame::default());
+        s.handle(&mut state, &json!({ "seq": 1, "command": "stackTrace", "arguments": { "threadId": DAP_THREAD_ID } }));
+        let msgs = messages(&buf);
+        let body = &msgs.iter().find(|m| m["command"] == "stackTrace").unwrap()["body"];
+        assert_eq!(body["totalFrames"], 2);
+        let frames = body["stackFrames"].as_array().unwrap();
+        assert_eq!((frames[0]["id"].clone(), frames[0]["name"].clone()), (json!(0), json!("string.rep")));
+        assert!(frames[0].get("source").is_none());
+        assert_eq!(frames[1]["name"], "function <scripts/game.lua:3>");
+        assert_eq!(frames[1]["line"], 5);
+        let path = frames[1]["source"]["path"].as_str().unwrap();
+        assert!(std::path::Path::new(path).is_absolute() && path.ends_with("scripts/game.lua"));
+    }
+}
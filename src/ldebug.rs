@@ -40,6 +40,486 @@ pub fn log_debug_message(msg: &str) {
     }
 }
 
+// --- VM debug hooks (debug.sethook machinery) ---
+
+use std::cell::{Cell, RefCell};
+
+use crate::lobject::LuaValue;
+
+/// Hook event masks (as in lua.h)
+pub const LUA_MASKCALL: u8 = 1 << 0;
+pub const LUA_MASKRET: u8 = 1 << 1;
+pub const LUA_MASKLINE: u8 = 1 << 2;
+
+/// A value as seen by a hook (registers are copied out of the VM)
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugValue {
+    Nil,
+    Bool(bool),
+    Integer(crate::skylaconf::LuaInteger),
+    Number(crate::skylaconf::LuaFloat),
+    Str(String),
+    /// Tables, functions, ...: only their "type: address" form
+    Other(String),
+}
+
+impl std::fmt::Display for DebugValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DebugValue::Nil => write!(f, "nil"),
+            DebugValue::Bool(b) => write!(f, "{}", b),
+            DebugValue::Integer(i) => write!(f, "{}", crate::lobject::luaO_int2str(*i)),
+            DebugValue::Number(n) => write!(f, "{}", crate::lobject::luaO_num2str(*n)),
+            DebugValue::Str(s) => write!(f, "{:?}", s),
+            DebugValue::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// The running Lua frame at a line event
+#[derive(Debug, Clone, Default)]
+pub struct DebugFrame {
+    pub source: String,
+    pub line: u32,
+    /// Lua call depth (0 = main chunk)
+    pub depth: usize,
+    /// Active locals in declaration order
+    pub locals: Vec<(String, DebugValue)>,
+    pub upvalues: Vec<(String, DebugValue)>,
+}
+
+/// What a hook is called for
+pub enum HookEvent<'a> {
+    Call,
+    Return,
+    Line(&'a DebugFrame),
+}
+
+/// A hook gets the state that raised the event, to inspect the paused
+/// frame through lua_getinfo/lua_getlocal (lua_Hook)
+pub type HookFn = Box<dyn FnMut(&mut crate::lstate::LuaState, &HookEvent)>;
+
+thread_local! {
+    static HOOK: RefCell<Option<HookFn>> = RefCell::new(None);
+    static HOOK_MASK: Cell<u8> = const { Cell::new(0) };
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// (prototype, line) of the last line event, so each line fires once per entry
+    static OLD_LINE: Cell<(usize, u32)> = const { Cell::new((0, 0)) };
+}
+
+/// Install (or remove, with `None`) the hook for this thread
+pub fn luaG_sethook(hook: Option<HookFn>, mask: u8) {
+    let mask = if hook.is_some() { mask } else { 0 };
+    HOOK.with(|h| *h.borrow_mut() = hook);
+    HOOK_MASK.with(|m| m.set(mask));
+    CALL_DEPTH.with(|d| d.set(0));
+    OLD_LINE.with(|l| l.set((0, 0)));
+}
+
+#[inline(always)]
+pub fn luaG_hookmask() -> u8 {
+    HOOK_MASK.with(|m| m.get())
+}
+
+/// Current Lua call depth as tracked by the call/return hooks
+pub fn luaG_calldepth() -> usize {
+    CALL_DEPTH.with(|d| d.get())
+}
+
+// Run the hook with it taken out of its slot, so hooks do not re-enter
+// themselves (the equivalent of 'allowhook' in ldo.c)
+fn callhook(state: &mut crate::lstate::LuaState, ev: &HookEvent) {
+    let Some(mut hook) = HOOK.with(|h| h.borrow_mut().take()) else { return };
+    hook(state, ev);
+    HOOK.with(|h| {
+        let mut slot = h.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(hook);
+        }
+    });
+}
+
+/// Called by the VM before a function call
+pub fn luaG_callhook(state: &mut crate::lstate::LuaState) {
+    CALL_DEPTH.with(|d| d.set(d.get() + 1));
+    if luaG_hookmask() & LUA_MASKCALL != 0 {
+        callhook(state, &HookEvent::Call);
+    }
+}
+
+/// Called by the VM after a function call returns
+pub fn luaG_rethook(state: &mut crate::lstate::LuaState) {
+    CALL_DEPTH.with(|d| d.set(d.get().saturating_sub(1)));
+    OLD_LINE.with(|l| l.set((0, 0)));
+    if luaG_hookmask() & LUA_MASKRET != 0 {
+        callhook(state, &HookEvent::Return);
+    }
+}
+
+/// Called by the VM for each instruction while a line hook is set; the
+/// frame is only built when execution enters a new line
+pub fn luaG_traceexec(state: &mut crate::lstate::LuaState, proto: usize, line: u32, frame: impl FnOnce() -> DebugFrame) {
+    if luaG_hookmask() & LUA_MASKLINE == 0 || line == 0 {
+        return;
+    }
+    if OLD_LINE.with(|l| l.replace((proto, line))) == (proto, line) {
+        return;
+    }
+    let mut frame = frame();
+    frame.line = line;
+    frame.depth = luaG_calldepth();
+    callhook(state, &HookEvent::Line(&frame));
+}
+
+// --- Line information of prototypes ---
+//
+// Proto::lineinfo keeps one byte per instruction, the difference between
+// its line and the previous instruction's, as Lua 5.4 does. An instruction
+// whose difference does not fit, or that comes MAXIWTHABS instructions
+// after the last absolute entry, gets an entry in `abslineinfo` with its
+// line instead, so finding the line of any pc decodes at most MAXIWTHABS
+// bytes from the anchor before it.
+
+/// Most instructions between two absolute line entries (MAXIWTHABS)
+pub const MAXIWTHABS: usize = 128;
+
+/// lineinfo byte of an instruction whose line is in abslineinfo
+const ABSLINEINFO: i8 = i8::MIN;
+
+/// Line differences stored in lineinfo are below this (LIMLINEDIFF)
+const LIMLINEDIFF: i64 = 0x80;
+
+/// Line of the instruction at `pc`, stored in full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbsLineInfo {
+    pub pc: usize,
+    pub line: u32,
+}
+
+/// Compressed instruction-to-line map of a prototype (see above)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineInfo {
+    lineinfo: Vec<i8>,
+    abslineinfo: Vec<AbsLineInfo>,
+    previousline: u32, // line of the last instruction added
+    iwthabs: usize,    // instructions added since the last absolute entry
+}
+
+impl LineInfo {
+    /// Record the line of the next instruction (savelineinfo)
+    pub fn push(&mut self, line: u32) {
+        let pc = self.lineinfo.len();
+        let linedif = line as i64 - self.previousline as i64;
+        let far = linedif.abs() >= LIMLINEDIFF || {
+            self.iwthabs += 1;
+            self.iwthabs > MAXIWTHABS
+        };
+        if far {
+            self.abslineinfo.push(AbsLineInfo { pc, line });
+            self.lineinfo.push(ABSLINEINFO);
+            self.iwthabs = 1;
+        } else {
+            self.lineinfo.push(linedif as i8);
+        }
+        self.previousline = line;
+    }
+
+    /// Number of instructions described
+    pub fn len(&self) -> usize {
+        self.lineinfo.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lineinfo.is_empty()
+    }
+
+    /// Pc and line of the absolute entry at or before `pc`, searched from
+    /// the estimate pc / MAXIWTHABS - 1 (getbaseline); (None, 0) if there
+    /// is none
+    fn baseline(&self, pc: usize) -> (Option<usize>, u32) {
+        let abs = &self.abslineinfo;
+        if abs.first().is_none_or(|a| pc < a.pc) {
+            return (None, 0);
+        }
+        let mut i = (pc / MAXIWTHABS).saturating_sub(1).min(abs.len() - 1);
+        while i > 0 && abs[i].pc > pc {
+            i -= 1;
+        }
+        while i + 1 < abs.len() && pc >= abs[i + 1].pc {
+            i += 1;
+        }
+        (Some(abs[i].pc), abs[i].line)
+    }
+
+    /// Line of the instruction at `pc` (luaG_getfuncline)
+    pub fn get(&self, pc: usize) -> Option<u32> {
+        if pc >= self.lineinfo.len() {
+            return None;
+        }
+        let (basepc, mut line) = self.baseline(pc);
+        let from = basepc.map_or(0, |b| b + 1);
+        for &d in &self.lineinfo[from..=pc] {
+            line = (line as i64 + d as i64) as u32;
+        }
+        Some(line)
+    }
+
+    /// Lines of all instructions, in pc order
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        let mut abs = self.abslineinfo.iter();
+        let mut line = 0u32;
+        self.lineinfo.iter().map(move |&d| {
+            line = if d == ABSLINEINFO {
+                abs.next().expect("an absolute entry for each marker").line
+            } else {
+                (line as i64 + d as i64) as u32
+            };
+            line
+        })
+    }
+
+    /// Lines of all instructions, in pc order, uncompressed
+    pub fn lines(&self) -> Vec<u32> {
+        self.iter().collect()
+    }
+}
+
+impl FromIterator<u32> for LineInfo {
+    fn from_iter<I: IntoIterator<Item = u32>>(lines: I) -> Self {
+        let mut li = LineInfo::default();
+        for line in lines {
+            li.push(line);
+        }
+        li
+    }
+}
+
+/// Lines of `p` that have code, ascending (debug.getinfo's 'L'): the only
+/// lines a line hook stops on, so the only places a breakpoint can hit
+pub fn luaG_activelines(p: &crate::lvm::Proto) -> Vec<u32> {
+    let mut lines: Vec<u32> = p.lineinfo.iter().filter(|&l| l > 0).collect();
+    lines.sort_unstable();
+    lines.dedup();
+    lines
+}
+
+/// Where a breakpoint set on `line` takes effect: the first active line
+/// at or after it, None past the last one
+pub fn luaG_breakpointline(activelines: &[u32], line: u32) -> Option<u32> {
+    activelines.get(activelines.partition_point(|&l| l < line)).copied()
+}
+
+// --- Debug interface of the C API (lua_getstack, lua_getinfo, lua_getlocal) ---
+//
+// For native profilers and debuggers written against lua.h: lua_Debug
+// (lauxlib) has the lua.h layout, and these functions walk the frames of
+// the LuaState behind `L`. The frames are those of functions called through
+// the safe API, which have no Lua source: they report what = "C", like C
+// functions in the reference implementation, with the name they were called
+// by, and their stack slots as "(C temporary)" locals. A lua_Debug filled by
+// lua_getstack keeps the level of its frame (its private i_ci field), so it
+// is valid until the stack changes.
+
+use std::collections::HashSet;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::rc::Rc;
+
+use crate::lauxlib::{lua_Debug, LUA_IDSIZE};
+use crate::lstate::{CallInfo, LuaState};
+
+thread_local! {
+    // Names handed out in lua_Debug::name: they must outlive the frame, as
+    // the strings of a C Lua state do, so each distinct name is kept
+    static DEBUG_NAMES: RefCell<HashSet<Box<CStr>>> = RefCell::new(HashSet::new());
+}
+
+fn debug_name(name: &str) -> *const c_char {
+    let name = CString::new(name.replace('\0', "?")).expect("NULs replaced").into_boxed_c_str();
+    DEBUG_NAMES.with(|n| {
+        let mut n = n.borrow_mut();
+        if let Some(b) = n.get(&name) {
+            return b.as_ptr();
+        }
+        let ptr = name.as_ptr();
+        n.insert(name);
+        ptr
+    })
+}
+
+type CallInfoRef = Rc<RefCell<CallInfo>>;
+
+/// The frame `level` calls below the running one, and the frame it called
+/// (None for the running one); None past the outermost frame
+fn frame_at(state: &LuaState, level: usize) -> Option<(CallInfoRef, Option<CallInfoRef>)> {
+    let mut callee = None;
+    let mut ci = state.ci.clone();
+    for _ in 0..level {
+        let prev = ci.borrow().previous.clone()?;
+        callee = Some(std::mem::replace(&mut ci, prev));
+    }
+    // the first CallInfo of a thread is the host's, not a function's
+    let is_function = ci.borrow().previous.is_some();
+    is_function.then_some((ci, callee))
+}
+
+/// Fill `ar` so lua_getinfo can describe the function running `level`
+/// calls below the current one (0 is the current one); 0 if there is no
+/// such level
+#[no_mangle]
+pub unsafe extern "C" fn lua_getstack(L: *mut c_void, level: c_int, ar: *mut lua_Debug) -> c_int {
+    let state = &*(L as *mut LuaState);
+    if level < 0 || frame_at(state, level as usize).is_none() {
+        return 0;
+    }
+    (*ar).i_ci = (level as usize + 1) as *mut c_void;
+    1
+}
+
+fn set_short_src(ar: &mut lua_Debug, src: &[u8]) {
+    let n = src.len().min(LUA_IDSIZE - 1);
+    for (d, &c) in ar.short_src.iter_mut().zip(&src[..n]) {
+        *d = c as c_char;
+    }
+    ar.short_src[n] = 0;
+}
+
+/// Fill the fields of `ar` that `what` asks for ('S', 'l', 'u', 'n', 'r',
+/// 't'), and push the function ('f') and then its active lines ('L', nil
+/// for functions without Lua code). With a leading '>' the function is
+/// popped from the stack instead of taken from `ar`. 0 for an invalid option.
+#[no_mangle]
+pub unsafe extern "C" fn lua_getinfo(L: *mut c_void, what: *const c_char, ar: *mut lua_Debug) -> c_int {
+    let state = &mut *(L as *mut LuaState);
+    let ar = &mut *ar;
+    let mut what = CStr::from_ptr(what).to_bytes();
+    let (func, ci) = if what.first() == Some(&b'>') {
+        what = &what[1..];
+        (state.pop().unwrap_or(LuaValue::Nil), None)
+    } else {
+        let Some((ci, _)) = frame_at(state, (ar.i_ci as usize).saturating_sub(1)) else { return 0 };
+        let func = state.stack.get(ci.borrow().func).cloned().unwrap_or(LuaValue::Nil);
+        (func, Some(ci))
+    };
+    // a Lua function describes itself through its prototype; a Rust one
+    // has no source, lines or parameters to report
+    let cl = crate::lvm::closure_of(&func);
+    let proto = cl.map(|cl| &*(*cl.as_ptr()).cl.p);
+    let nups = cl.map_or(0, |cl| (*cl.as_ptr()).upvals.len());
+    let mut status = 1;
+    for &c in what {
+        match c {
+            b'S' => match proto {
+                Some(p) => {
+                    ar.source = debug_name(&p.source);
+                    ar.srclen = p.source.len();
+                    ar.linedefined = p.linedefined as c_int;
+                    ar.lastlinedefined = p.lastlinedefined as c_int;
+                    ar.what = if p.linedefined == 0 { b"main\0".as_ptr() } else { b"Lua\0".as_ptr() } as *const c_char;
+                    set_short_src(ar, crate::lobject::luaO_chunkid(&p.source, LUA_IDSIZE - 1).as_bytes());
+                }
+                None => {
+                    ar.source = b"=[C]\0".as_ptr() as *const c_char;
+                    ar.srclen = 4;
+                    ar.linedefined = -1;
+                    ar.lastlinedefined = -1;
+                    ar.what = b"C\0".as_ptr() as *const c_char;
+                    set_short_src(ar, b"[C]");
+                }
+            },
+            b'l' => {
+                // the line of the instruction the frame is running, as the
+                // VM saved it when the frame last called out
+                let line = proto.zip(ci.as_ref()).and_then(|(p, ci)| {
+                    p.lineinfo.get(ci.borrow().savedpc.saturating_sub(1))
+                });
+                ar.currentline = line.map_or(-1, |l| l as c_int);
+            }
+            b'u' => {
+                ar.nups = nups as u8;
+                ar.nparams = proto.map_or(0, |p| p.numparams);
+                ar.isvararg = proto.is_none_or(|p| p.is_vararg) as c_char;
+            }
+            b'n' => {
+                let named = ci.as_ref().and_then(|ci| {
+                    let ci = ci.borrow();
+                    let name = ci.name.clone()?;
+                    let namewhat: &[u8] = if ci.is_method {
+                        b"method\0"
+                    } else if name.contains('.') {
+                        b"field\0"
+                    } else {
+                        b"global\0"
+                    };
+                    Some((debug_name(&name), namewhat))
+                });
+                let (name, namewhat) = named.unwrap_or((std::ptr::null(), b"\0"));
+                ar.name = name;
+                ar.namewhat = namewhat.as_ptr() as *const c_char;
+            }
+            b'r' => {
+                ar.ftransfer = 0;
+                ar.ntransfer = 0;
+            }
+            b't' => ar.istailcall = 0,
+            b'f' | b'L' => {}
+            _ => status = 0,
+        }
+    }
+    if what.contains(&b'f') {
+        state.push(func);
+    }
+    if what.contains(&b'L') {
+        match proto {
+            // the set of lines with code: {[line] = true, ...}
+            Some(p) => {
+                let lines = state.create_table(0, 0);
+                for line in luaG_activelines(p) {
+                    lines.borrow_mut().rawset(&LuaValue::Int(line as i64), LuaValue::Bool(true));
+                }
+                state.push(LuaValue::Table(lines));
+            }
+            None => state.push(LuaValue::Nil), // no Lua code, so no active lines
+        }
+    }
+    status
+}
+
+/// Push local `n` (from 1) of the frame in `ar` and return its name, or
+/// return NULL (pushing nothing) if it has no such local. A Lua frame names
+/// its active locals, read from its registers; a Rust frame's slots are
+/// "(C temporary)". Without `ar`, names the parameters of the function on
+/// top, which has none here.
+#[no_mangle]
+pub unsafe extern "C" fn lua_getlocal(L: *mut c_void, ar: *const lua_Debug, n: c_int) -> *const c_char {
+    let state = &mut *(L as *mut LuaState);
+    if ar.is_null() || n <= 0 {
+        return std::ptr::null(); // no parameter names, no varargs
+    }
+    let Some((ci, callee)) = frame_at(state, ((*ar).i_ci as usize).saturating_sub(1)) else {
+        return std::ptr::null();
+    };
+    let func = state.stack.get(ci.borrow().func).cloned().unwrap_or(LuaValue::Nil);
+    if let (Some(cl), Some(base)) = (crate::lvm::closure_of(&func), ci.borrow().base) {
+        let p = &*(*cl.as_ptr()).cl.p;
+        // savedpc is past the instruction being run
+        let Some(name) = p.getlocalname(n as usize, ci.borrow().savedpc.saturating_sub(1)) else {
+            return std::ptr::null();
+        };
+        let v = (*base.as_ptr().add(n as usize - 1)).to_lua().unwrap_or(LuaValue::Nil);
+        state.push(v);
+        return crate::lvm::vm_string(name);
+    }
+    let base = ci.borrow().func + 1;
+    let limit = callee.map_or(state.stack.len(), |c| c.borrow().func);
+    let slot = base + n as usize - 1;
+    if slot >= limit {
+        return std::ptr::null();
+    }
+    let v = state.stack[slot].clone();
+    state.push(v);
+    b"(C temporary)\0".as_ptr() as *const c_char
+}
+
 // Add more internal debug helpers as needed...
 
 #[cfg(test)]
@@ -70,6 +550,171 @@ mod tests {
         print_register_value(3, "0xDEADBEEF");
     }
 
+    #[test]
+    fn test_line_hook_fires_once_per_line_with_depth() {
+        let lines = std::rc::Rc::new(RefCell::new(Vec::new()));
+        let seen = lines.clone();
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        luaG_sethook(Some(Box::new(move |_, ev| {
+            if let HookEvent::Line(f) = ev {
+                seen.borrow_mut().push((f.line, f.depth));
+            }
+        })), LUA_MASKLINE);
+        luaG_traceexec(&mut state, 1, 1, DebugFrame::default);
+        luaG_traceexec(&mut state, 1, 1, DebugFrame::default);
+        luaG_callhook(&mut state);
+        luaG_traceexec(&mut state, 2, 5, DebugFrame::default);
+        luaG_rethook(&mut state);
+        luaG_traceexec(&mut state, 1, 2, DebugFrame::default);
+        luaG_sethook(None, 0);
+        luaG_traceexec(&mut state, 1, 3, DebugFrame::default);
+        assert_eq!(*lines.borrow(), vec![(1, 0), (5, 1), (2, 0)]);
+    }
+
+    #[test]
+    fn test_lineinfo_roundtrip() {
+        // small steps, a big jump back and forth, and a run long enough to
+        // need anchors every MAXIWTHABS instructions
+        let mut lines = vec![1, 2, 2, 5, 400, 3, 3];
+        lines.extend((0..3 * MAXIWTHABS as u32).map(|i| 10 + i / 7));
+        let li: LineInfo = lines.iter().copied().collect();
+        assert_eq!(li.lines(), lines);
+        for (pc, &l) in lines.iter().enumerate() {
+            assert_eq!(li.get(pc), Some(l));
+        }
+        assert_eq!(li.get(lines.len()), None);
+        assert!(li.abslineinfo.len() >= 5);
+        assert!(li.abslineinfo.windows(2).all(|w| w[1].pc - w[0].pc <= MAXIWTHABS));
+    }
+
+    #[test]
+    fn test_getstack_getinfo_getlocal() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        // a frame for `string.rep`, called from the host with two arguments
+        let ci = Rc::new(RefCell::new(CallInfo { func: 0, name: Some("string.rep".to_string()), ..CallInfo::default() }));
+        ci.borrow_mut().previous = Some(state.ci.clone());
+        state.ci = ci;
+        state.stack.extend([LuaValue::Nil, LuaValue::Str("ab".to_string()), LuaValue::Int(3)]);
+        let l = &mut state as *mut LuaState as *mut c_void;
+        let mut ar = lua_Debug::new();
+        unsafe {
+            assert_eq!(lua_getstack(l, 1, &mut ar), 0);
+            assert_eq!(lua_getstack(l, 0, &mut ar), 1);
+            assert_eq!(lua_getinfo(l, b"Snl\0".as_ptr() as *const c_char, &mut ar), 1);
+            assert_eq!(CStr::from_ptr(ar.what).to_bytes(), b"C");
+            assert_eq!(CStr::from_ptr(ar.short_src.as_ptr()).to_bytes(), b"[C]");
+            assert_eq!(CStr::from_ptr(ar.name).to_bytes(), b"string.rep");
+            assert_eq!(CStr::from_ptr(ar.namewhat).to_bytes(), b"field");
+            assert_eq!(ar.currentline, -1);
+            assert_eq!(lua_getinfo(l, b"x\0".as_ptr() as *const c_char, &mut ar), 0);
+            let name = lua_getlocal(l, &ar, 2);
+            assert_eq!(CStr::from_ptr(name).to_bytes(), b"(C temporary)");
+            assert_eq!(state.pop(), Some(LuaValue::Int(3)));
+            assert!(lua_getlocal(l, &ar, 3).is_null());
+        }
+    }
+
+    #[test]
+    fn test_getinfo_of_a_lua_function() {
+        use crate::lvm::{closure_value, Closure, ClosureType, Proto};
+        use std::ptr::NonNull;
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: [4, 5, 5, 8].into_iter().collect(),
+            numparams: 2,
+            is_vararg: false,
+            source: "@scripts/game.lua".to_string(),
+            linedefined: 3,
+            lastlinedefined: 9,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let mut cl = Closure { cl: ClosureType { p: &mut p }, upvals: Vec::new() };
+        let f = closure_value(NonNull::from(&mut cl));
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        // the closure is running its third instruction
+        let ci = Rc::new(RefCell::new(CallInfo { func: 0, savedpc: 3, ..CallInfo::default() }));
+        ci.borrow_mut().previous = Some(state.ci.clone());
+        state.ci = ci;
+        state.stack.push(f.clone());
+        let l = &mut state as *mut LuaState as *mut c_void;
+        let mut ar = lua_Debug::new();
+        unsafe {
+            assert_eq!(lua_getstack(l, 0, &mut ar), 1);
+            assert_eq!(lua_getinfo(l, b"SluL\0".as_ptr() as *const c_char, &mut ar), 1);
+            assert_eq!(CStr::from_ptr(ar.what).to_bytes(), b"Lua");
+            assert_eq!(CStr::from_ptr(ar.source).to_bytes(), b"@scripts/game.lua");
+            assert_eq!(CStr::from_ptr(ar.short_src.as_ptr()).to_bytes(), b"scripts/game.lua");
+            assert_eq!((ar.linedefined, ar.lastlinedefined, ar.currentline), (3, 9, 5));
+            assert_eq!((ar.nparams, ar.isvararg), (2, 0));
+            let Some(LuaValue::Table(lines)) = state.pop() else { panic!("no activelines table") };
+            let mut active: Vec<LuaValue> = lines.borrow().keys().collect();
+            active.sort_by_key(|k| if let LuaValue::Int(i) = k { *i } else { 0 });
+            assert_eq!(active, [4, 5, 8].map(LuaValue::Int));
+            // a function outside any frame has no current line
+            state.push(f);
+            assert_eq!(lua_getinfo(l, b">Sl\0".as_ptr() as *const c_char, &mut ar), 1);
+            assert_eq!((ar.linedefined, ar.currentline), (3, -1));
+        }
+        p.linedefined = 0;
+        state.push(closure_value(NonNull::from(&mut cl)));
+        unsafe {
+            assert_eq!(lua_getinfo(l, b">S\0".as_ptr() as *const c_char, &mut ar), 1);
+            assert_eq!(CStr::from_ptr(ar.what).to_bytes(), b"main");
+        }
+    }
+
+    #[test]
+    fn test_getlocal_of_a_lua_frame() {
+        use crate::lvm::{closure_value, Closure, ClosureType, LocVar, Proto, TValue};
+        use std::ptr::NonNull;
+        let var = |name: &str, startpc, endpc| LocVar { varname: name.to_string(), startpc, endpc };
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 1,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 1,
+            lastlinedefined: 4,
+            locvars: vec![var("n", 0, 6), var("tmp", 0, 2), var("total", 2, 6)],
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let mut cl = Closure { cl: ClosureType { p: &mut p }, upvals: Vec::new() };
+        let mut regs = [TValue::from_integer(4), TValue::from_integer(10)];
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        // running its fourth instruction, where 'tmp' is dead and 'total' took its register
+        let ci = Rc::new(RefCell::new(CallInfo { func: 0, savedpc: 4, base: NonNull::new(regs.as_mut_ptr()), ..CallInfo::default() }));
+        ci.borrow_mut().previous = Some(state.ci.clone());
+        state.ci = ci;
+        state.stack.push(closure_value(NonNull::from(&mut cl)));
+        let l = &mut state as *mut LuaState as *mut c_void;
+        let mut ar = lua_Debug::new();
+        unsafe {
+            assert_eq!(lua_getstack(l, 0, &mut ar), 1);
+            assert_eq!(CStr::from_ptr(lua_getlocal(l, &ar, 1)).to_bytes(), b"n");
+            assert_eq!(state.pop(), Some(LuaValue::Int(4)));
+            assert_eq!(CStr::from_ptr(lua_getlocal(l, &ar, 2)).to_bytes(), b"total");
+            assert_eq!(state.pop(), Some(LuaValue::Int(10)));
+            assert!(lua_getlocal(l, &ar, 3).is_null());
+        }
+    }
+
+    #[test]
+    fn test_breakpointline() {
+        let lines = [2, 3, 7];
+        assert_eq!(luaG_breakpointline(&lines, 1), Some(2));
+        assert_eq!(luaG_breakpointline(&lines, 3), Some(3));
+        assert_eq!(luaG_breakpointline(&lines, 4), Some(7));
+        assert_eq!(luaG_breakpointline(&lines, 8), None);
+    }
+
     #[test]
     fn test_enable_disable_debug() {
         enable_debug();
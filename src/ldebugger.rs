@@ -0,0 +1,440 @@
+//! ldebugger.rs - Interactive command-line debugger (`skyla -d script.lua`)
+//
+// Built on the line/call/return hooks in ldebug: the debugger installs a line
+// hook and, whenever execution reaches a breakpoint or finishes a step, stops
+// and reads commands from the terminal. Expressions are evaluated in the
+// paused frame: its locals (lua_getlocal) and upvalues (lua_getupvalue) are
+// in scope with their live values, and globals resolve through its _ENV.
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+use crate::ldebug::{lua_getinfo, lua_getlocal, lua_getstack, luaG_sethook, DebugFrame, HookEvent, LUA_MASKLINE};
+use crate::lauxlib::lua_Debug;
+use crate::lobject::LuaValue;
+use crate::lsourcemap::LoadOptions;
+use crate::lstate::LuaState;
+
+const DEBUGGER_HELP: &str = "\
+commands:
+  b file:line   set a breakpoint          d file:line   delete a breakpoint
+  bl            list breakpoints          w             show current position
+  s             step (into calls)         n             next (over calls)
+  f             finish current function   c             continue
+  l             print locals              u             print upvalues
+  p expr        evaluate expression       q             quit
+  h             this help";
+
+/// A parsed debugger command
+#[derive(Debug, Clone, PartialEq)]
+pub enum DebugCommand {
+    Break(String, u32),
+    Delete(String, u32),
+    ListBreaks,
+    Step,
+    Next,
+    Finish,
+    Continue,
+    Locals,
+    Upvalues,
+    Print(String),
+    Where,
+    Help,
+    Quit,
+    Unknown(String),
+}
+
+/// How execution resumes after the prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepMode {
+    /// Run until a breakpoint
+    Continue,
+    /// Stop at the next line, entering calls
+    Step,
+    /// Stop at the next line at this depth or shallower
+    Next(usize),
+    /// Stop at the next line shallower than this depth
+    Finish(usize),
+}
+
+/// Outcome of one command
+#[derive(Debug, PartialEq)]
+pub enum CommandResult {
+    /// Stay at the prompt (output to show)
+    Stay(String),
+    /// Leave the prompt and resume execution
+    Resume,
+    Quit,
+}
+
+/// Evaluates an expression in the paused frame of a state
+pub type Evaluator = Box<dyn FnMut(&mut LuaState, &str) -> Result<String, String>>;
+
+fn parse_location(arg: &str) -> Option<(String, u32)> {
+    let (file, line) = arg.rsplit_once(':')?;
+    Some((file.to_string(), line.trim().parse().ok()?))
+}
+
+/// Parse one line typed at the debugger prompt
+pub fn parse_command(input: &str) -> DebugCommand {
+    let input = input.trim();
+    let (cmd, arg) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    let arg = arg.trim();
+    match cmd {
+        "b" | "break" => parse_location(arg)
+            .map(|(f, l)| DebugCommand::Break(f, l))
+            .unwrap_or_else(|| DebugCommand::Unknown(input.to_string())),
+        "d" | "delete" => parse_location(arg)
+            .map(|(f, l)| DebugCommand::Delete(f, l))
+            .unwrap_or_else(|| DebugCommand::Unknown(input.to_string())),
+        "bl" => DebugCommand::ListBreaks,
+        "s" | "step" => DebugCommand::Step,
+        "n" | "next" => DebugCommand::Next,
+        "f" | "finish" => DebugCommand::Finish,
+        "c" | "continue" => DebugCommand::Continue,
+        "l" | "locals" => DebugCommand::Locals,
+        "u" | "upvalues" => DebugCommand::Upvalues,
+        "p" | "print" if !arg.is_empty() => DebugCommand::Print(arg.to_string()),
+        "w" | "where" => DebugCommand::Where,
+        "h" | "help" => DebugCommand::Help,
+        "q" | "quit" => DebugCommand::Quit,
+        _ => DebugCommand::Unknown(input.to_string()),
+    }
+}
+
+/// Does breakpoint file `bp` name the chunk `source`? ("@dir/a.lua"
+/// matches "a.lua", "dir/a.lua" and the full path). An absolute `bp`, as
+/// editors send, matches a chunk named relative to the working directory
+/// when both lead to the same file.
+fn source_matches(source: &str, bp: &str) -> bool {
+    let src = source.strip_prefix('@').unwrap_or(source);
+    if src == bp || src.ends_with(&format!("/{}", bp)) {
+        return true;
+    }
+    let (src, bp) = (std::path::Path::new(src), std::path::Path::new(bp));
+    if !bp.is_absolute() || src.is_absolute() {
+        return false;
+    }
+    matches!((std::fs::canonicalize(src), std::fs::canonicalize(bp)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Names and live values of the variables visible in the frame `level`
+/// calls down: its function's upvalues, then its active locals, so that a
+/// later entry shadows an earlier one of the same name
+fn frame_vars(state: &mut LuaState, level: i32) -> Option<Vec<(String, LuaValue)>> {
+    let L = state as *mut LuaState;
+    let mut ar = lua_Debug::new();
+    let mut vars = Vec::new();
+    unsafe {
+        if lua_getstack(L.cast(), level, &mut ar) == 0 {
+            return None;
+        }
+        lua_getinfo(L.cast(), b"f\0".as_ptr().cast(), &mut ar); // pushes the function
+        for n in 1.. {
+            let name = crate::lapi::lua_getupvalue(L.cast(), -1, n);
+            if name.is_null() {
+                break;
+            }
+            let v = state.pop().unwrap_or(LuaValue::Nil);
+            vars.push((std::ffi::CStr::from_ptr(name).to_string_lossy().into_owned(), v));
+        }
+        state.pop();
+        for n in 1.. {
+            let name = lua_getlocal(L.cast(), &ar, n);
+            if name.is_null() {
+                break;
+            }
+            let v = state.pop().unwrap_or(LuaValue::Nil);
+            vars.push((std::ffi::CStr::from_ptr(name).to_string_lossy().into_owned(), v));
+        }
+    }
+    Some(vars)
+}
+
+/// Evaluate `expr` in the state's running frame and convert the result as
+/// tostring does. The expression runs as a chunk whose locals are the
+/// frame's variables, passed in as arguments, with the frame's _ENV (the
+/// globals if it has none) as its environment.
+pub fn eval_in_frame(state: &mut LuaState, expr: &str) -> Result<String, String> {
+    let vars = frame_vars(state, 0).ok_or("no active frame")?;
+    let env = vars.iter().rev().find(|(n, _)| n == "_ENV").map(|(_, v)| v.clone());
+    // temporaries such as "(for state)" and _ENV itself are not locals
+    let vars: Vec<_> = vars.into_iter()
+        .filter(|(n, _)| n != "_ENV" && crate::linspect::is_identifier(n))
+        .collect();
+    let mut chunk = String::new();
+    if !vars.is_empty() {
+        let names: Vec<&str> = vars.iter().map(|(n, _)| n.as_str()).collect();
+        chunk.push_str(&format!("local {} = ...\n", names.join(", ")));
+    }
+    chunk.push_str(&format!("return {}", expr));
+    let env = env.unwrap_or_else(|| LuaValue::Table(state.globals_table()));
+    let opts = LoadOptions { chunkname: "=(debug)".to_string(), env: Some(env), ..LoadOptions::default() };
+    let res = state.pcall(|L| {
+        if let Err(e) = L.load_buffer_with(chunk.as_bytes(), &opts) {
+            L.throw(e.into_value());
+        }
+        let nargs = vars.len();
+        for (_, v) in vars {
+            L.push(v);
+        }
+        L.call(nargs, 1);
+        let v = L.pop().unwrap_or(LuaValue::Nil);
+        crate::lbaselib::tostring_value(L, &v)
+    });
+    res.map_err(|e| e.to_string())
+}
+
+fn format_vars(vars: &[(String, crate::ldebug::DebugValue)]) -> String {
+    if vars.is_empty() {
+        return "(none)".to_string();
+    }
+    vars.iter().map(|(n, v)| format!("{} = {}", n, v)).collect::<Vec<_>>().join("\n")
+}
+
+/// Debugger state: breakpoints and stepping mode
+pub struct Debugger {
+    pub breakpoints: BTreeSet<(String, u32)>,
+    pub mode: StepMode,
+    eval: Option<Evaluator>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    /// A debugger that stops at the first line executed
+    pub fn new() -> Self {
+        Debugger { breakpoints: BTreeSet::new(), mode: StepMode::Step, eval: None }
+    }
+
+    /// Replace the evaluator used by `p expr` for anything but a plain
+    /// variable name (eval_in_frame by default)
+    pub fn set_evaluator(&mut self, eval: Evaluator) {
+        self.eval = Some(eval);
+    }
+
+    /// Should execution stop at this line event?
+    pub fn should_stop(&self, frame: &DebugFrame) -> bool {
+        let stepped = match self.mode {
+            StepMode::Continue => false,
+            StepMode::Step => true,
+            StepMode::Next(depth) => frame.depth <= depth,
+            StepMode::Finish(depth) => frame.depth < depth,
+        };
+        stepped || self.at_breakpoint(frame)
+    }
+
+    /// Is there a breakpoint on this frame's line?
+    pub fn at_breakpoint(&self, frame: &DebugFrame) -> bool {
+        self.breakpoints.iter().any(|(f, l)| *l == frame.line && source_matches(&frame.source, f))
+    }
+
+    /// Execute one command against the stopped frame
+    pub fn execute(&mut self, state: &mut LuaState, cmd: DebugCommand, frame: &DebugFrame) -> CommandResult {
+        match cmd {
+            DebugCommand::Break(f, l) => {
+                let msg = format!("breakpoint set at {}:{}", f, l);
+                self.breakpoints.insert((f, l));
+                CommandResult::Stay(msg)
+            }
+            DebugCommand::Delete(f, l) => {
+                if self.breakpoints.remove(&(f.clone(), l)) {
+                    CommandResult::Stay(format!("breakpoint {}:{} deleted", f, l))
+                } else {
+                    CommandResult::Stay(format!("no breakpoint at {}:{}", f, l))
+                }
+            }
+            DebugCommand::ListBreaks => CommandResult::Stay(if self.breakpoints.is_empty() {
+                "no breakpoints".to_string()
+            } else {
+                self.breakpoints.iter().map(|(f, l)| format!("{}:{}", f, l)).collect::<Vec<_>>().join("\n")
+            }),
+            DebugCommand::Step => { self.mode = StepMode::Step; CommandResult::Resume }
+            DebugCommand::Next => { self.mode = StepMode::Next(frame.depth); CommandResult::Resume }
+            DebugCommand::Finish => { self.mode = StepMode::Finish(frame.depth); CommandResult::Resume }
+            DebugCommand::Continue => { self.mode = StepMode::Continue; CommandResult::Resume }
+            DebugCommand::Locals => CommandResult::Stay(format_vars(&frame.locals)),
+            DebugCommand::Upvalues => CommandResult::Stay(format_vars(&frame.upvalues)),
+            DebugCommand::Print(expr) => {
+                // Innermost binding wins: later locals shadow earlier ones and upvalues
+                let var = frame.locals.iter().rev().chain(frame.upvalues.iter())
+                    .find(|(n, _)| *n == expr);
+                if let Some((_, v)) = var {
+                    return CommandResult::Stay(v.to_string());
+                }
+                let res = match self.eval.as_mut() {
+                    Some(eval) => eval(state, &expr),
+                    None => eval_in_frame(state, &expr),
+                };
+                CommandResult::Stay(res.unwrap_or_else(|e| format!("error: {}", e)))
+            }
+            DebugCommand::Where => CommandResult::Stay(format!("{}:{}", frame.source, frame.line)),
+            DebugCommand::Help => CommandResult::Stay(DEBUGGER_HELP.to_string()),
+            DebugCommand::Quit => CommandResult::Quit,
+            DebugCommand::Unknown(s) => CommandResult::Stay(format!("unknown command '{}' (h for help)", s)),
+        }
+    }
+
+    /// Stop at `frame` and run commands from `input` until execution resumes.
+    /// Returns false if the user quit (or input ended).
+    pub fn prompt(&mut self, state: &mut LuaState, frame: &DebugFrame, input: &mut dyn BufRead, out: &mut dyn Write) -> bool {
+        let _ = writeln!(out, "{}:{}", frame.source.strip_prefix('@').unwrap_or(&frame.source), frame.line);
+        let mut line = String::new();
+        loop {
+            let _ = write!(out, "(sdb) ");
+            let _ = out.flush();
+            line.clear();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                return false;
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            match self.execute(state, parse_command(&line), frame) {
+                CommandResult::Stay(msg) => { let _ = writeln!(out, "{}", msg); }
+                CommandResult::Resume => return true,
+                CommandResult::Quit => return false,
+            }
+        }
+    }
+}
+
+/// Install `debugger` as this thread's line hook, reading commands from stdin.
+/// Quitting the debugger exits the process.
+pub fn debugger_attach(mut debugger: Debugger) {
+    luaG_sethook(Some(Box::new(move |state, ev| {
+        if let HookEvent::Line(frame) = ev {
+            if debugger.should_stop(frame) {
+                let stdin = std::io::stdin();
+                if !debugger.prompt(state, frame, &mut stdin.lock(), &mut std::io::stdout()) {
+                    std::process::exit(0);
+                }
+            }
+        }
+    })), LUA_MASKLINE);
+}
+
+/// Remove the debugger hook
+pub fn debugger_detach() {
+    luaG_sethook(None, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ldebug::DebugValue;
+    use crate::lstate::{CallInfo, GlobalState};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn new_state() -> LuaState {
+        LuaState::new(Rc::new(RefCell::new(GlobalState::new())))
+    }
+
+    fn frame(line: u32, depth: usize) -> DebugFrame {
+        DebugFrame {
+            source: "@scripts/a.lua".to_string(),
+            line,
+            depth,
+            locals: vec![("x".to_string(), DebugValue::Number(1.0)), ("x".to_string(), DebugValue::Str("in".to_string()))],
+            upvalues: vec![("y".to_string(), DebugValue::Bool(true))],
+        }
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("b a.lua:12"), DebugCommand::Break("a.lua".to_string(), 12));
+        assert_eq!(parse_command("d a.lua:12"), DebugCommand::Delete("a.lua".to_string(), 12));
+        assert_eq!(parse_command("p x + 1"), DebugCommand::Print("x + 1".to_string()));
+        assert_eq!(parse_command("n"), DebugCommand::Next);
+        assert_eq!(parse_command("b nowhere"), DebugCommand::Unknown("b nowhere".to_string()));
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut dbg = Debugger::new();
+        let mut state = new_state();
+        dbg.execute(&mut state, parse_command("b a.lua:7"), &frame(1, 0));
+        dbg.execute(&mut state, DebugCommand::Continue, &frame(1, 0));
+        assert!(!dbg.should_stop(&frame(6, 0)));
+        assert!(dbg.should_stop(&frame(7, 2)));
+        dbg.execute(&mut state, DebugCommand::Next, &frame(7, 2));
+        assert!(!dbg.should_stop(&frame(3, 3)));
+        assert!(dbg.should_stop(&frame(8, 2)));
+        dbg.execute(&mut state, DebugCommand::Finish, &frame(8, 2));
+        assert!(!dbg.should_stop(&frame(9, 2)));
+        assert!(dbg.should_stop(&frame(12, 1)));
+    }
+
+    #[test]
+    fn test_print_locals_and_prompt() {
+        let mut dbg = Debugger::new();
+        let mut state = new_state();
+        let f = frame(3, 0);
+        assert_eq!(dbg.execute(&mut state, parse_command("p x"), &f), CommandResult::Stay("\"in\"".to_string()));
+        assert_eq!(dbg.execute(&mut state, parse_command("p y"), &f), CommandResult::Stay("true".to_string()));
+        // the default evaluator needs a paused frame
+        assert_eq!(dbg.execute(&mut state, parse_command("p x+1"), &f), CommandResult::Stay("error: no active frame".to_string()));
+        dbg.set_evaluator(Box::new(|_, e| Err(format!("no eval for {}", e))));
+        assert_eq!(dbg.execute(&mut state, parse_command("p x+1"), &f), CommandResult::Stay("error: no eval for x+1".to_string()));
+        let mut out = Vec::new();
+        assert!(dbg.prompt(&mut state, &f, &mut "l\nc\n".as_bytes(), &mut out));
+        assert!(String::from_utf8(out).unwrap().contains("x = 1\nx = \"in\""));
+        assert_eq!(dbg.mode, StepMode::Continue);
+    }
+
+    #[test]
+    fn test_frame_vars_read_the_paused_frame() {
+        use crate::lvm::{closure_value, Closure, ClosureType, LocVar, Proto, TValue, UpVal};
+        use std::ptr::NonNull;
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 1,
+            is_vararg: false,
+            source: "@scripts/a.lua".to_string(),
+            linedefined: 1,
+            lastlinedefined: 4,
+            locvars: vec![LocVar { varname: "limit".to_string(), startpc: 0, endpc: 4 }],
+            upvalnames: vec!["_ENV".to_string(), "limit".to_string()],
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let mut cl = Closure {
+            cl: ClosureType { p: &mut p },
+            upvals: vec![UpVal::closed(TValue::nil()), UpVal::closed(TValue::from_integer(3))],
+        };
+        let mut regs = [TValue::from_integer(7)];
+        let mut state = new_state();
+        let ci = Rc::new(RefCell::new(CallInfo { func: 0, savedpc: 2, base: NonNull::new(regs.as_mut_ptr()), ..CallInfo::default() }));
+        ci.borrow_mut().previous = Some(state.ci.clone());
+        state.ci = ci;
+        state.stack.push(closure_value(NonNull::from(&mut cl)));
+        let vars = frame_vars(&mut state, 0).unwrap();
+        // the local comes last, so it shadows the upvalue of the same name
+        let expect = [("_ENV", LuaValue::Nil), ("limit", LuaValue::Int(3)), ("limit", LuaValue::Int(7))];
+        assert_eq!(vars, expect.map(|(n, v)| (n.to_string(), v)));
+        assert_eq!(state.stack.len(), 1);
+        assert!(frame_vars(&mut state, 1).is_none());
+    }
+
+    #[test]
+    fn test_absolute_breakpoint_matches_relative_chunk() {
+        // tests run from the crate root, which holds this file
+        let abs = std::fs::canonicalize("src/ldebugger.rs").unwrap();
+        let abs = abs.to_str().unwrap();
+        assert!(source_matches("@src/ldebugger.rs", abs));
+        assert!(source_matches("@./src/ldebugger.rs", abs));
+        assert!(!source_matches("@src/ldap.rs", abs));
+        assert!(!source_matches("=stdin", abs));
+        let mut dbg = Debugger::new();
+        dbg.breakpoints.insert((abs.to_string(), 2));
+        let f = DebugFrame { source: "@src/ldebugger.rs".to_string(), line: 2, ..DebugFrame::default() };
+        assert!(dbg.at_breakpoint(&f));
+    }
+}
@@ -0,0 +1,168 @@
+//! ldeterm.rs - Deterministic execution for replay and lockstep simulation
+//
+// A state created with `LuaState::new_deterministic` computes the same
+// results on every run and every machine, given the same inputs:
+//   - the string-hash seed, the key of table hash parts and math.random's
+//     generator start from the options' seed instead of platform entropy
+//     (math.randomseed() without arguments goes back to it);
+//   - tables the state creates (constructors, library results) traverse
+//     their hash part (pairs, next) in insertion order instead of hash
+//     order, see LuaState::set_insertion_order;
+//   - os.time, os.clock, os.hrtime and os.date without a time read the
+//     host's VirtualClock, which a lockstep simulation advances once per
+//     tick, instead of the platform clocks.
+// The mode is chosen when the state is created and cannot be turned off:
+// switching halfway would leave tables and seeds of both kinds.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::lstate::{GlobalState, LuaState};
+
+/// Time as a deterministic state sees it, supplied by the host
+pub trait VirtualClock: fmt::Debug {
+    /// Seconds since the Unix epoch (os.time)
+    fn time(&self) -> i64;
+    /// Seconds of processor time used so far (os.clock)
+    fn clock(&self) -> f64;
+}
+
+/// A clock that only moves when the host says so: start it at a date and
+/// advance it by the length of each simulation tick
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    time: Cell<i64>,
+    clock: Cell<f64>,
+}
+
+impl ManualClock {
+    /// Clock at Unix time `time`, with no processor time used
+    pub fn new(time: i64) -> Self {
+        ManualClock { time: Cell::new(time), clock: Cell::new(0.0) }
+    }
+
+    /// Move both times forward by `secs` seconds
+    pub fn advance(&self, secs: f64) {
+        let clock = self.clock.get() + secs;
+        self.clock.set(clock);
+        self.time.set(self.time.get() + (clock.floor() - (clock - secs).floor()) as i64);
+    }
+}
+
+impl VirtualClock for ManualClock {
+    fn time(&self) -> i64 {
+        self.time.get()
+    }
+    fn clock(&self) -> f64 {
+        self.clock.get()
+    }
+}
+
+/// How a deterministic state is set up
+#[derive(Debug, Clone)]
+pub struct DeterministicOptions {
+    /// Seed of the string hashes and of math.random
+    pub seed: u64,
+    /// Source of os.time and os.clock; by default a ManualClock at 0
+    pub clock: Rc<dyn VirtualClock>,
+}
+
+impl DeterministicOptions {
+    pub fn new(seed: u64) -> Self {
+        DeterministicOptions { seed, clock: Rc::new(ManualClock::default()) }
+    }
+
+    pub fn with_clock(mut self, clock: Rc<dyn VirtualClock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl LuaState {
+    /// New state in deterministic mode (see the module comment)
+    pub fn new_deterministic(opts: DeterministicOptions) -> LuaState {
+        let mut g = GlobalState::new();
+        g.set_seed(opts.seed as u32);
+        crate::ltable::set_hash_key(Some((opts.seed, opts.seed.rotate_left(32) ^ 0x9e37_79b9_7f4a_7c15)));
+        g.rng = crate::lmathlib::RanState::new(opts.seed, 0);
+        g.deterministic = Some(opts);
+        g.ordered_tables = true;
+        LuaState::new(Rc::new(RefCell::new(g)))
+    }
+
+    /// Do tables created by this state keep insertion order?
+    pub fn insertion_order(&self) -> bool {
+        self.l_G.borrow().ordered_tables
+    }
+
+    /// Make tables created from now on by this state traverse their hash
+    /// part in insertion order (or not); tables it already made keep theirs
+    pub fn set_insertion_order(&mut self, on: bool) {
+        self.l_G.borrow_mut().ordered_tables = on;
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.l_G.borrow().deterministic.is_some()
+    }
+
+    /// The fixed seed of a deterministic state
+    pub fn deterministic_seed(&self) -> Option<u64> {
+        self.l_G.borrow().deterministic.as_ref().map(|d| d.seed)
+    }
+
+    /// os.time() of a deterministic state, from its virtual clock
+    pub fn virtual_time(&self) -> Option<i64> {
+        self.l_G.borrow().deterministic.as_ref().map(|d| d.clock.time())
+    }
+
+    /// os.clock() of a deterministic state, from its virtual clock
+    pub fn virtual_clock(&self) -> Option<f64> {
+        self.l_G.borrow().deterministic.as_ref().map(|d| d.clock.clock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lobject::LuaValue;
+    use crate::ltable::Table;
+
+    #[test]
+    fn test_deterministic_state() {
+        let clock = Rc::new(ManualClock::new(1_700_000_000));
+        let opts = DeterministicOptions::new(99).with_clock(clock.clone());
+        let a = LuaState::new_deterministic(opts.clone());
+        let b = LuaState::new_deterministic(opts);
+        assert!(a.is_deterministic() && a.deterministic_seed() == Some(99));
+        assert_eq!(a.l_G.borrow().seed, b.l_G.borrow().seed);
+        assert_eq!(a.l_G.borrow_mut().rng.next(), b.l_G.borrow_mut().rng.next());
+
+        clock.advance(0.75);
+        assert_eq!((a.virtual_time(), a.virtual_clock()), (Some(1_700_000_000), Some(0.75)));
+        clock.advance(0.5);
+        assert_eq!((b.virtual_time(), b.virtual_clock()), (Some(1_700_000_001), Some(1.25)));
+
+        let plain = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        assert!(!plain.is_deterministic() && plain.virtual_time().is_none());
+    }
+
+    #[test]
+    fn test_tables_of_a_deterministic_state_keep_insertion_order() {
+        let mut det = LuaState::new_deterministic(DeterministicOptions::new(1));
+        let t = det.create_table(0, 0);
+        let keys: Vec<LuaValue> = ["x", "b", "k", "a"].iter().map(|k| LuaValue::Str(k.to_string())).collect();
+        for k in &keys {
+            t.borrow_mut().set(k, LuaValue::Bool(true));
+        }
+        assert_eq!(t.borrow().keys().collect::<Vec<_>>(), keys);
+        // the option belongs to the state: states and tables made on the
+        // same thread afterwards are not affected
+        let mut plain = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        assert!(!plain.insertion_order() && !plain.create_table(0, 0).borrow().is_ordered());
+        assert!(!Table::new().is_ordered());
+        assert!(det.create_table(0, 0).borrow().is_ordered());
+        plain.set_insertion_order(true);
+        assert!(plain.create_table(0, 0).borrow().is_ordered());
+    }
+}
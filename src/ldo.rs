@@ -16,6 +16,16 @@ pub enum LuaStatus {
     // Add more as needed
 }
 
+/// Panic payload used to raise a Lua error with a specific status
+/// (e.g. LUA_ERRMEM from the allocator); protected calls map it back.
+#[derive(Debug, Clone, Copy)]
+pub struct LuaThrow(pub LuaStatus);
+
+/// Map a caught panic payload to the status it represents.
+pub fn luaD_statusof(payload: &(dyn std::any::Any + Send)) -> LuaStatus {
+    payload.downcast_ref::<LuaThrow>().map(|t| t.0).unwrap_or(LuaStatus::RuntimeError)
+}
+
 /// Calls a Lua function in protected mode.
 /// In a real implementation, this would set up error handling and call the function.
 pub unsafe fn luaD_pcall(
@@ -32,15 +42,14 @@ pub unsafe fn luaD_pcall(
     }
 }
 
-/// Represents a Lua stack frame (CallInfo).
+/// Represents a Lua stack frame (CallInfo). Frames live in a CallInfoStack
+/// indexed by call depth; the caller of the frame at depth `d` is at `d - 1`.
 #[derive(Debug, Clone)]
 pub struct CallInfo {
     pub func_index: usize,
     pub base: usize,
     pub top: usize,
     pub nresults: i32,
-    pub previous: Option<Box<CallInfo>>,
-    pub next: Option<Box<CallInfo>>,
     pub status: LuaStatus,
 }
 
@@ -51,19 +60,79 @@ impl CallInfo {
             base,
             top,
             nresults,
-            previous: None,
-            next: None,
             status: LuaStatus::Ok,
         }
     }
 }
 
+/// The chain of active frames, as a vector indexed by call depth. Entries
+/// past `depth` are kept after a return and overwritten by the next call
+/// (the C luaE_extendCI/next_ci reuse), so calls and returns only allocate
+/// when the stack of frames grows deeper than it has ever been.
+#[derive(Debug, Default)]
+pub struct CallInfoStack {
+    cis: Vec<CallInfo>,
+    depth: usize,
+}
+
+impl CallInfoStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enter a frame: reuse the slot at the current depth if there is one
+    pub fn push(&mut self, ci: CallInfo) {
+        if self.depth < self.cis.len() {
+            self.cis[self.depth] = ci;
+        } else {
+            self.cis.push(ci);
+        }
+        self.depth += 1;
+    }
+
+    /// Leave the current frame; its slot stays allocated for reuse
+    pub fn pop(&mut self) -> Option<&CallInfo> {
+        self.depth = self.depth.checked_sub(1)?;
+        self.cis.get(self.depth)
+    }
+
+    /// The running frame
+    pub fn current(&self) -> Option<&CallInfo> {
+        self.depth.checked_sub(1).map(|d| &self.cis[d])
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut CallInfo> {
+        self.depth.checked_sub(1).map(move |d| &mut self.cis[d])
+    }
+
+    /// The frame `level` calls above the running one (0 is the running one)
+    pub fn get(&self, level: usize) -> Option<&CallInfo> {
+        self.depth.checked_sub(level + 1).map(|d| &self.cis[d])
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Number of frame slots allocated, in use or not
+    pub fn capacity(&self) -> usize {
+        self.cis.len()
+    }
+
+    /// luaE_shrinkCI: free half of the unused slots
+    pub fn shrink(&mut self) {
+        let keep = self.depth + (self.cis.len() - self.depth) / 2;
+        self.cis.truncate(keep);
+        self.cis.shrink_to(keep);
+    }
+}
+
 /// Represents a Lua value (simplified).
 #[derive(Debug, Clone)]
 pub enum LuaValue {
     Nil,
     Boolean(bool),
-    Number(f64),
+    Number(crate::skylaconf::LuaFloat),
     String(String),
     Function(fn(*mut lua_State) -> i32),
     // Add more as needed
@@ -129,7 +198,7 @@ impl ErrorContext {
 /// Simulate the lua_State structure.
 pub struct lua_State {
     pub stack: LuaStack,
-    pub callinfo: Option<Box<CallInfo>>,
+    pub callinfo: CallInfoStack,
     pub status: LuaStatus,
     pub error_ctx: Option<ErrorContext>,
 }
@@ -138,22 +207,18 @@ impl lua_State {
     pub fn new(stack_size: usize) -> Self {
         lua_State {
             stack: LuaStack::new(stack_size),
-            callinfo: None,
+            callinfo: CallInfoStack::new(),
             status: LuaStatus::Ok,
             error_ctx: None,
         }
     }
 
     pub fn push_callinfo(&mut self, ci: CallInfo) {
-        let mut boxed = Box::new(ci);
-        boxed.previous = self.callinfo.take();
-        self.callinfo = Some(boxed);
+        self.callinfo.push(ci);
     }
 
     pub fn pop_callinfo(&mut self) {
-        if let Some(mut ci) = self.callinfo.take() {
-            self.callinfo = ci.previous.take();
-        }
+        self.callinfo.pop();
     }
 }
 
@@ -176,7 +241,7 @@ pub fn luaD_rawrunprotected(
     }));
     match result {
         Ok(_) => LuaStatus::Ok,
-        Err(_) => LuaStatus::RuntimeError,
+        Err(e) => luaD_statusof(&*e),
     }
 }
 
@@ -249,7 +314,7 @@ pub fn luaD_runprotected_chunk(L: &mut lua_State, chunk: fn(&mut lua_State)) ->
     }));
     match result {
         Ok(_) => LuaStatus::Ok,
-        Err(_) => LuaStatus::RuntimeError,
+        Err(e) => luaD_statusof(&*e),
     }
 }
 
@@ -284,6 +349,7 @@ pub fn luaD_reallocstack(L: &mut lua_State, newsize: usize) {
 pub fn luaD_shrinkstack(L: &mut lua_State) {
     let used = L.stack.top;
     L.stack.values.truncate(used + 10);
+    L.callinfo.shrink();
 }
 
 /// Simulate function call with error handler.
@@ -301,7 +367,7 @@ pub fn luaD_call_with_errfunc(
     L.error_ctx = old_ctx;
     match status {
         Ok(_) => LuaStatus::Ok,
-        Err(_) => LuaStatus::RuntimeError,
+        Err(e) => luaD_statusof(&*e),
     }
 }
 
@@ -471,4 +537,31 @@ pub fn luaD_moverange(L: &mut lua_State, from: usize, to: usize, n: usize) {
             L.stack.values[from + i] = LuaValue::Nil;
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_callinfo_slots_are_reused() {
+        let mut L = lua_State::new(16);
+        for depth in 0..100 {
+            luaD_precall(&mut L, depth, 1);
+        }
+        assert_eq!(L.callinfo.current().map(|ci| ci.func_index), Some(99));
+        assert_eq!(L.callinfo.get(1).map(|ci| ci.func_index), Some(98));
+        for _ in 0..100 {
+            luaD_poscall(&mut L, 1);
+        }
+        assert!(L.callinfo.current().is_none());
+        assert_eq!(L.callinfo.capacity(), 100);
+        // a second descent reuses the slots instead of allocating
+        for depth in 0..50 {
+            luaD_precall(&mut L, depth, 1);
+        }
+        assert_eq!(L.callinfo.depth(), 50);
+        assert_eq!(L.callinfo.capacity(), 100);
+        luaD_shrinkstack(&mut L);
+        assert_eq!(L.callinfo.capacity(), 75);
+        assert_eq!(L.callinfo.current().map(|ci| ci.func_index), Some(49));
+    }
+}
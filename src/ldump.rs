@@ -3,6 +3,21 @@ use std::mem;
 use std::slice;
 use std::os::raw::{c_void, c_int};
 use std::cell::RefCell;
+use crate::lapi::{lua_Integer, lua_Number};
+use crate::lvm::Instruction;
+use crate::skylaconf::{LUAC_INT, LUAC_NUM};
+
+/// Mark of a precompiled chunk ("<esc>Lua")
+pub const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+/// Version byte: major * 16 + minor
+pub const LUAC_VERSION: u8 = 0x54;
+/// 0 is the official format
+pub const LUAC_FORMAT: u8 = 0;
+/// Bytes that catch text-mode conversions of the chunk
+pub const LUAC_DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+/// Length of the chunk header
+pub const LUAC_HEADERSIZE: usize = LUA_SIGNATURE.len() + 2 + LUAC_DATA.len() + 3
+    + mem::size_of::<lua_Integer>() + mem::size_of::<lua_Number>();
 
 // Placeholder imports for Lua types
 // use crate::{lua_State, lua_Writer, Proto, TValue, TString, Table, Instruction, lua_Number, lua_Integer, LUAC_VERSION, LUAC_FORMAT, LUA_SIGNATURE, LUAC_DATA, LUAC_INT, LUAC_INST, LUAC_NUM, LUA_VNUMFLT, LUA_VNUMINT, LUA_VSHRSTR, LUA_VLNGSTR, LUA_VNIL, LUA_VFALSE, LUA_VTRUE};
@@ -170,12 +185,30 @@ fn dump_function(D: &mut DumpState, f: &Proto) {
 }
 
 
+/*
+** The chunk header, in the 5.4 layout: signature, version, format and
+** LUAC_DATA, then the sizes of Instruction, lua_Integer and lua_Number,
+** then the samples LUAC_INT and LUAC_NUM, so that a loader built with
+** other number types (int32/float32 profiles) or endianness rejects the
+** chunk.
+*/
+pub fn luaU_header() -> Vec<u8> {
+    let mut h = Vec::with_capacity(LUAC_HEADERSIZE);
+    h.extend_from_slice(LUA_SIGNATURE);
+    h.push(LUAC_VERSION);
+    h.push(LUAC_FORMAT);
+    h.extend_from_slice(LUAC_DATA);
+    h.push(mem::size_of::<Instruction>() as u8);
+    h.push(mem::size_of::<lua_Integer>() as u8);
+    h.push(mem::size_of::<lua_Number>() as u8);
+    h.extend_from_slice(&(LUAC_INT as lua_Integer).to_ne_bytes());
+    h.extend_from_slice(&(LUAC_NUM as lua_Number).to_ne_bytes());
+    h
+}
+
+
 fn dump_header(D: &mut DumpState) {
-    dump_block(D, Some(LUA_SIGNATURE));
-    dump_byte(D, LUAC_VERSION);
-    dump_byte(D, LUAC_FORMAT);
-    dump_block(D, Some(LUAC_DATA));
-    // ...existing code...
+    dump_block(D, Some(&luaU_header()));
 }
 
 
@@ -301,3 +334,21 @@ int luaU_dump (lua_State *L, const Proto *f, lua_Writer w, void *data,
   return D.status;
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_layout() {
+        let h = luaU_header();
+        assert_eq!(h.len(), LUAC_HEADERSIZE);
+        assert_eq!(&h[..4], b"\x1bLua");
+        assert_eq!((h[4], h[5]), (0x54, 0));
+        assert_eq!(&h[6..12], LUAC_DATA);
+        let (isz, nsz) = (mem::size_of::<lua_Integer>(), mem::size_of::<lua_Number>());
+        assert_eq!(&h[12..15], &[mem::size_of::<Instruction>() as u8, isz as u8, nsz as u8]);
+        assert_eq!(&h[15..15 + isz], &(LUAC_INT as lua_Integer).to_ne_bytes());
+        assert_eq!(&h[15 + isz..], &(LUAC_NUM as lua_Number).to_ne_bytes());
+    }
+}
@@ -0,0 +1,417 @@
+//! lencoding.rs - Base64, hex and checksums (skyla.encoding)
+//
+// Built with the `skyla_ext` feature. The one-shot functions take and
+// return whole strings; for data that arrives in pieces, encoding.encoder
+// and encoding.decoder make objects whose update(chunk) returns what can
+// be produced so far and whose finish() returns the rest, and crc32 and
+// adler32 take the checksum of the data before as a second argument, as in
+// zlib. Strings are UTF-8 in this port: decoded bytes that are not valid
+// UTF-8 are replaced.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+use crate::skylaconf::LuaInteger;
+
+const B64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn b64_value(c: u8) -> Option<u8> {
+    B64_ALPHABET.iter().position(|&a| a == c).map(|v| v as u8)
+}
+
+/// Base64 of `data`, padded with '=' if the last group is short
+fn encode_groups(data: &[u8], out: &mut String) {
+    for group in data.chunks(3) {
+        let mut acc = [0u8; 3];
+        acc[..group.len()].copy_from_slice(group);
+        let n = u32::from_be_bytes([0, acc[0], acc[1], acc[2]]);
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(B64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+/// Decode a group of 2 to 4 base64 characters, '=' padding included;
+/// true if it was padded
+fn decode_group(q: &[u8], out: &mut Vec<u8>) -> Result<bool, String> {
+    let n = q.iter().position(|&c| c == b'=').unwrap_or(q.len());
+    if n < 2 || q[n..].iter().any(|&c| c != b'=') {
+        return Err("invalid base64 padding".to_string());
+    }
+    let mut acc = 0u32;
+    for (i, &c) in q[..n].iter().enumerate() {
+        acc |= (b64_value(c).expect("checked by update") as u32) << (18 - 6 * i);
+    }
+    out.extend_from_slice(&acc.to_be_bytes()[1..n]);
+    Ok(n < q.len())
+}
+
+/// Incremental base64 encoder
+#[derive(Debug, Default)]
+pub struct Base64Encoder {
+    pending: Vec<u8>,
+}
+
+impl Base64Encoder {
+    /// Base64 of the complete 3-byte groups so far
+    pub fn update(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        let whole = self.pending.len() / 3 * 3;
+        let mut out = String::with_capacity(whole / 3 * 4);
+        encode_groups(&self.pending[..whole], &mut out);
+        self.pending.drain(..whole);
+        out
+    }
+
+    /// The last, padded group
+    pub fn finish(&mut self) -> String {
+        let mut out = String::new();
+        encode_groups(&std::mem::take(&mut self.pending), &mut out);
+        out
+    }
+}
+
+/// Incremental base64 decoder; whitespace (line breaks) is skipped and
+/// the final padding may be left out
+#[derive(Debug, Default)]
+pub struct Base64Decoder {
+    pending: Vec<u8>,
+    padded: bool,
+}
+
+impl Base64Decoder {
+    /// The bytes of the complete 4-character groups so far
+    pub fn update(&mut self, text: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = Vec::with_capacity(text.len() / 4 * 3);
+        for &c in text.iter().filter(|c| !c.is_ascii_whitespace()) {
+            if self.padded {
+                return Err("invalid base64 data (data after padding)".to_string());
+            }
+            if c != b'=' && b64_value(c).is_none() {
+                return Err(format!("invalid base64 character '{}'", c.escape_ascii()));
+            }
+            self.pending.push(c);
+            if self.pending.len() == 4 {
+                self.padded = decode_group(&self.pending, &mut out)?;
+                self.pending.clear();
+            }
+        }
+        Ok(out)
+    }
+
+    /// The bytes of an unpadded last group
+    pub fn finish(&mut self) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        match std::mem::take(&mut self.pending).as_slice() {
+            [] => {}
+            [_] => return Err("truncated base64 data".to_string()),
+            tail => {
+                decode_group(tail, &mut out)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut e = Base64Encoder::default();
+    let mut out = e.update(data);
+    out.push_str(&e.finish());
+    out
+}
+
+pub fn base64_decode(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut d = Base64Decoder::default();
+    let mut out = d.update(text)?;
+    out.extend(d.finish()?);
+    Ok(out)
+}
+
+/// Lower-case hex of `data`
+pub fn hex_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() * 2);
+    for b in data {
+        out.push_str(&format!("{:02x}", b));
+    }
+    out
+}
+
+/// Incremental hex decoder; whitespace is skipped, case does not matter
+#[derive(Debug, Default)]
+pub struct HexDecoder {
+    high: Option<u8>,
+}
+
+impl HexDecoder {
+    /// The bytes of the complete digit pairs so far
+    pub fn update(&mut self, text: &[u8]) -> Result<Vec<u8>, String> {
+        let mut out = Vec::with_capacity(text.len() / 2);
+        for &c in text.iter().filter(|c| !c.is_ascii_whitespace()) {
+            let d = (c as char).to_digit(16).ok_or_else(|| format!("invalid hex digit '{}'", c.escape_ascii()))? as u8;
+            match self.high.take() {
+                Some(h) => out.push(h << 4 | d),
+                None => self.high = Some(d),
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>, String> {
+        match self.high.take() {
+            Some(_) => Err("odd number of hex digits".to_string()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+pub fn hex_decode(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut d = HexDecoder::default();
+    let mut out = d.update(text)?;
+    out.extend(d.finish()?);
+    Ok(out)
+}
+
+/// Table of the reflected CRC-32 polynomial (as zlib, PNG, gzip)
+const CRC_TABLE: [u32; 256] = {
+    let mut t = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        t[i] = c;
+        i += 1;
+    }
+    t
+};
+
+/// CRC-32 of `data` continuing from `crc` (0 to start)
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for &b in data {
+        c = CRC_TABLE[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+/// Largest prime below 2^16
+const ADLER_MOD: u32 = 65521;
+/// Most bytes summed before the sums could overflow 32 bits
+const ADLER_NMAX: usize = 5552;
+
+/// Adler-32 of `data` continuing from `adler` (1 to start)
+pub fn adler32(adler: u32, data: &[u8]) -> u32 {
+    let (mut a, mut b) = (adler & 0xffff, adler >> 16);
+    for chunk in data.chunks(ADLER_NMAX) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+    b << 16 | a
+}
+
+/// Push a decoding result: the string, or nil and the message
+fn push_decoded(state: &mut LuaState, r: Result<Vec<u8>, String>) -> i32 {
+    match r {
+        Ok(bytes) => {
+            state.push(LuaValue::Str(String::from_utf8_lossy(&bytes).into_owned()));
+            1
+        }
+        Err(e) => {
+            state.push(LuaValue::Nil);
+            state.push(LuaValue::Str(e));
+            2
+        }
+    }
+}
+
+// encoding.base64encode(s)
+fn enc_base64encode(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Str(base64_encode(s.as_bytes())));
+    1
+}
+
+// encoding.base64decode(s) -> string | nil, message
+fn enc_base64decode(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    push_decoded(state, base64_decode(s.as_bytes()))
+}
+
+// encoding.hexencode(s)
+fn enc_hexencode(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Str(hex_encode(s.as_bytes())));
+    1
+}
+
+// encoding.hexdecode(s) -> string | nil, message
+fn enc_hexdecode(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    push_decoded(state, hex_decode(s.as_bytes()))
+}
+
+// encoding.crc32(s [, crc]) -> integer
+fn enc_crc32(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let crc = state.opt_integer(2, 0) as u32;
+    state.push(LuaValue::Int(crc32(crc, s.as_bytes()) as LuaInteger));
+    1
+}
+
+// encoding.adler32(s [, adler]) -> integer
+fn enc_adler32(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let adler = state.opt_integer(2, 1) as u32;
+    state.push(LuaValue::Int(adler32(adler, s.as_bytes()) as LuaInteger));
+    1
+}
+
+const CODECS: &[&str] = &["base64", "hex"];
+
+/// One streaming direction of a codec
+enum Stream {
+    Base64Encode(Base64Encoder),
+    Base64Decode(Base64Decoder),
+    HexEncode,
+    HexDecode(HexDecoder),
+}
+
+impl Stream {
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Stream::Base64Encode(e) => Ok(e.update(data).into_bytes()),
+            Stream::Base64Decode(d) => d.update(data),
+            Stream::HexEncode => Ok(hex_encode(data).into_bytes()),
+            Stream::HexDecode(d) => d.update(data),
+        }
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>, String> {
+        match self {
+            Stream::Base64Encode(e) => Ok(e.finish().into_bytes()),
+            Stream::Base64Decode(d) => d.finish(),
+            Stream::HexEncode => Ok(Vec::new()),
+            Stream::HexDecode(d) => d.finish(),
+        }
+    }
+}
+
+/// Object with update(chunk) and finish() methods over `stream`; each
+/// returns a string, or nil and a message for bad input
+fn stream_object(stream: Stream) -> LuaValue {
+    let stream = Rc::new(RefCell::new(stream));
+    let s = stream.clone();
+    let update = move |state: &mut LuaState| {
+        let chunk = state.check_string(2);
+        let r = s.borrow_mut().update(chunk.as_bytes());
+        push_decoded(state, r)
+    };
+    let finish = move |state: &mut LuaState| {
+        let r = stream.borrow_mut().finish();
+        push_decoded(state, r)
+    };
+    let mut t = Table::new();
+    t.rawset(&LuaValue::Str("update".to_string()), LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(&update))));
+    t.rawset(&LuaValue::Str("finish".to_string()), LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(&finish))));
+    LuaValue::Table(Rc::new(RefCell::new(t)))
+}
+
+// encoding.encoder(codec) -> object with :update(chunk) and :finish()
+fn enc_encoder(state: &mut LuaState) -> i32 {
+    let stream = match state.check_option(1, None, CODECS) {
+        0 => Stream::Base64Encode(Base64Encoder::default()),
+        _ => Stream::HexEncode,
+    };
+    state.push(stream_object(stream));
+    1
+}
+
+// encoding.decoder(codec) -> object with :update(chunk) and :finish()
+fn enc_decoder(state: &mut LuaState) -> i32 {
+    let stream = match state.check_option(1, None, CODECS) {
+        0 => Stream::Base64Decode(Base64Decoder::default()),
+        _ => Stream::HexDecode(HexDecoder::default()),
+    };
+    state.push(stream_object(stream));
+    1
+}
+
+const ENCODING_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("adler32", enc_adler32),
+    ("base64decode", enc_base64decode),
+    ("base64encode", enc_base64encode),
+    ("crc32", enc_crc32),
+    ("decoder", enc_decoder),
+    ("encoder", enc_encoder),
+    ("hexdecode", enc_hexdecode),
+    ("hexencode", enc_hexencode),
+];
+
+/// Register the `skyla.encoding` module
+pub fn open_encoding_lib(state: &mut LuaState) {
+    for &(name, f) in ENCODING_FUNCS {
+        state.register_lib_function("skyla.encoding", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_and_hex() {
+        for (plain, coded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(base64_encode(plain.as_bytes()), coded);
+            assert_eq!(base64_decode(coded.as_bytes()).unwrap(), plain.as_bytes());
+        }
+        assert_eq!(base64_decode(b"Zm9v\nYmE").unwrap(), b"fooba"); // line break, no padding
+        assert!(base64_decode(b"Zg==Zg==").is_err());
+        assert!(base64_decode(b"Z").is_err() && base64_decode(b"Z=g=").is_err() && base64_decode(b"Zm!v").is_err());
+        assert_eq!(hex_encode(b"\x00\xffA"), "00ff41");
+        assert_eq!(hex_decode(b"00 FF 41").unwrap(), b"\x00\xffA");
+        assert!(hex_decode(b"abc").is_err() && hex_decode(b"zz").is_err());
+    }
+
+    #[test]
+    fn test_streaming_matches_one_shot() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut enc = Base64Encoder::default();
+        let mut coded = String::new();
+        for chunk in data.chunks(7) {
+            coded.push_str(&enc.update(chunk));
+        }
+        coded.push_str(&enc.finish());
+        assert_eq!(coded, base64_encode(&data));
+        let mut dec = Base64Decoder::default();
+        let mut back = Vec::new();
+        for chunk in coded.as_bytes().chunks(5) {
+            back.extend(dec.update(chunk).unwrap());
+        }
+        back.extend(dec.finish().unwrap());
+        assert_eq!(back, data);
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(1, b"Wikipedia"), 0x11E6_0398);
+        // continuing from the checksum of the first part
+        let data = vec![0xa5u8; 20000];
+        assert_eq!(crc32(crc32(0, &data[..333]), &data[333..]), crc32(0, &data));
+        assert_eq!(adler32(adler32(1, &data[..9999]), &data[9999..]), adler32(1, &data));
+    }
+}
@@ -0,0 +1,134 @@
+//! lerror.rs - Errors of the safe API (skyla::Error)
+//
+// Inside the VM an error is any Lua value: error({code = 42}) raises the
+// table itself, and it reaches the nearest protected call unchanged
+// (LuaState::throw / LuaState::pcall, lua_error / lua_pcall). At the Rust
+// boundary it becomes an Error; host errors go back into Lua as values.
+
+use std::fmt;
+use std::os::raw::c_int;
+
+use crate::lapi::{LUA_ERRMEM, LUA_ERRRUN, LUA_ERRSYNTAX};
+use crate::lauxlib::LUA_ERRFILE;
+use crate::lobject::LuaValue;
+use crate::ltm::obj_typename;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A runtime error and its error object, as given to error()
+    Runtime(LuaValue),
+    /// Memory allocation failed
+    Memory,
+    /// A chunk failed to compile
+    Syntax { line: u32, msg: String },
+    /// A chunk could not be opened or read
+    File(String),
+    /// An error raised by host code
+    Custom(Box<dyn std::error::Error>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Wrap a host error
+    pub fn custom(e: impl std::error::Error + 'static) -> Error {
+        Error::Custom(Box::new(e))
+    }
+
+    /// The error object of a runtime error
+    pub fn value(&self) -> Option<&LuaValue> {
+        match self {
+            Error::Runtime(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The error object seen by Lua code: the original value for a runtime
+    /// error, the message for the others
+    pub fn into_value(self) -> LuaValue {
+        match self {
+            Error::Runtime(v) => v,
+            e => LuaValue::Str(e.to_string()),
+        }
+    }
+
+    /// Status code returned by lua_pcall/lua_load for this error
+    pub fn status(&self) -> c_int {
+        match self {
+            Error::Runtime(_) | Error::Custom(_) => LUA_ERRRUN,
+            Error::Memory => LUA_ERRMEM,
+            Error::Syntax { .. } => LUA_ERRSYNTAX,
+            Error::File(_) => LUA_ERRFILE,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Runtime(LuaValue::Str(s)) => f.write_str(s),
+            Error::Runtime(LuaValue::Int(i)) => write!(f, "{}", i),
+            Error::Runtime(LuaValue::Float(n)) => write!(f, "{}", n),
+            Error::Runtime(v) => write!(f, "(error object is a {} value)", obj_typename(v)),
+            Error::Memory => f.write_str("not enough memory"),
+            Error::Syntax { line, msg } => write!(f, "line {}: {}", line, msg),
+            Error::File(msg) => f.write_str(msg),
+            Error::Custom(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Custom(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<LuaValue> for Error {
+    fn from(v: LuaValue) -> Self {
+        Error::Runtime(v)
+    }
+}
+
+impl From<Error> for LuaValue {
+    fn from(e: Error) -> Self {
+        e.into_value()
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::File(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::ltable::Table;
+
+    #[test]
+    fn test_table_error_object_round_trip() {
+        let t = LuaValue::Table(Rc::new(RefCell::new(Table::new())));
+        let e = Error::from(t.clone());
+        assert_eq!(e.status(), LUA_ERRRUN);
+        assert_eq!(e.to_string(), "(error object is a table value)");
+        assert!(matches!(e.into_value(), LuaValue::Table(ref r) if matches!(&t, LuaValue::Table(t) if Rc::ptr_eq(r, t))));
+    }
+
+    #[test]
+    fn test_host_errors_become_messages() {
+        let io = std::io::Error::new(std::io::ErrorKind::NotFound, "cannot open x.lua");
+        let e = Error::custom(io);
+        assert!(std::error::Error::source(&e).is_some());
+        assert!(matches!(LuaValue::from(e), LuaValue::Str(ref m) if m == "cannot open x.lua"));
+        let e = Error::Syntax { line: 3, msg: "unexpected symbol near 'x'".to_string() };
+        assert_eq!(e.status(), LUA_ERRSYNTAX);
+        assert_eq!(e.to_string(), "line 3: unexpected symbol near 'x'");
+    }
+}
@@ -12,13 +12,15 @@ mod lfunc;
 mod ltm;
 // ...existing code...
 
-use crate::lstate::{lua_State, GlobalState};
+use crate::lstate::{lua_State, GlobalState, LuaState};
 use crate::lobject::{GCObject, TValue, GCType};
 use crate::ltable::Table;
-use crate::lstring::TString;
+use crate::lstring::{luaS_stats, luaS_sweep, StringStats, TString};
 use crate::lfunc::{LClosure, CClosure, Proto, UpVal};
 use std::ptr;
 use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 /// Maximum number of elements to sweep in each single step.
 pub const GCSWEEPMAX: usize = 20;
@@ -53,6 +55,22 @@ pub enum GCState {
     // Add more as needed
 }
 
+impl GCState {
+    /// State name as reported by T.gcstate()
+    pub fn name(&self) -> &'static str {
+        match self {
+            GCState::Pause => "pause",
+            GCState::Propagate => "propagate",
+            GCState::Atomic => "atomic",
+            GCState::SweepAllGC => "sweepallgc",
+            GCState::SweepFinObj => "sweepfinobj",
+            GCState::SweepToBeFNZ => "sweeptobefnz",
+            GCState::SweepEnd => "sweepend",
+            GCState::CallFin => "callfin",
+        }
+    }
+}
+
 /// Mark an object as white
 pub fn makewhite(_g: &GlobalState, o: &mut GCObject) {
     o.marked = (o.marked & !MASKCOLORS) | WHITE0BIT; // Example: set to WHITE0
@@ -83,8 +101,79 @@ pub fn isgray(o: &GCObject) -> bool {
     !iswhite(o) && !isblack(o)
 }
 
-/// Main GC step
+/// Main GC step; does nothing while the host keeps the collector paused
 pub fn luaC_step(L: &mut lua_State) {
+    if !L.global.gc_paused {
+        gc_step(L);
+    }
+}
+
+/// Run collector work for up to `budget` (at least one step), stopping
+/// early when the cycle completes; returns the debt left (gc_debt)
+pub fn luaC_stepbudget(L: &mut lua_State, budget: Duration) -> usize {
+    let start = Instant::now();
+    loop {
+        gc_step(L);
+        if L.global.gcstate == GCState::Pause || start.elapsed() >= budget {
+            break;
+        }
+    }
+    gc_debt(&L.global)
+}
+
+/// Work left in the current cycle: the objects still to be marked or
+/// swept, plus its final step; 0 between cycles
+pub fn gc_debt(g: &GlobalState) -> usize {
+    let unswept = |lists: &[&VecDeque<GCObject>]| lists.iter().map(|l| l.len()).sum::<usize>();
+    match g.gcstate {
+        GCState::Pause => 0,
+        GCState::Propagate | GCState::Atomic => 1 + g.gray.len() + unswept(&[&g.allgc, &g.finobj, &g.tobefnz]),
+        GCState::SweepAllGC => 1 + g.sweep_list.len() + unswept(&[&g.finobj, &g.tobefnz]),
+        GCState::SweepFinObj => 1 + g.sweep_list.len() + g.tobefnz.len(),
+        GCState::SweepToBeFNZ => 1 + g.sweep_list.len(),
+        GCState::SweepEnd | GCState::CallFin => 1,
+    }
+}
+
+impl lua_State {
+    /// Frame-budgeted collection: run collector work for at most
+    /// `max_micros` microseconds and return the debt left (gc_debt)
+    pub fn gc_step(&mut self, max_micros: u64) -> usize {
+        luaC_stepbudget(self, Duration::from_micros(max_micros))
+    }
+
+    /// Stop (or resume) automatic collection; gc_step still runs
+    pub fn gc_set_paused(&mut self, paused: bool) {
+        self.global.gc_paused = paused;
+    }
+}
+
+/// One step, with its events and trace
+fn gc_step(L: &mut lua_State) {
+    let from = L.global.gcstate;
+    if from == GCState::Pause {
+        let total = L.global.total_bytes;
+        L.global.gc_events.cycle_start(total);
+    }
+    let start = Instant::now();
+    gc_singlestep(L);
+    let g = &mut L.global;
+    g.gc_events.add_pause(start.elapsed());
+    let to = g.gcstate;
+    if from != GCState::Pause && to == GCState::Pause {
+        g.gc_events.cycle_end(g.total_bytes);
+    }
+    report_pressure(g);
+    #[cfg(feature = "trace")]
+    {
+        if from != to {
+            crate::skyla_trace!(crate::ltrace::TRACE_GC, crate::ltrace::TraceEvent::GcPhase { from: from.name(), to: to.name() });
+        }
+    }
+}
+
+/// One step of the GC state machine
+fn gc_singlestep(L: &mut lua_State) {
     let g = &mut L.global;
     match g.gcstate {
         GCState::Pause => {
@@ -144,7 +233,9 @@ pub fn luaC_step(L: &mut lua_State) {
 
 /// Full GC cycle (stub)
 pub fn luaC_fullgc(L: &mut lua_State, _isemergency: bool) {
+    let start = Instant::now();
     let g = &mut L.global;
+    g.gc_events.cycle_start(g.total_bytes);
     g.gcstate = GCState::Pause;
     // Mark everything
     mark_roots(L);
@@ -158,7 +249,142 @@ pub fn luaC_fullgc(L: &mut lua_State, _isemergency: bool) {
     sweep_list(&mut g.allgc, usize::MAX);
     sweep_list(&mut g.finobj, usize::MAX);
     sweep_list(&mut g.tobefnz, usize::MAX);
+    luaS_sweep();
     g.gcstate = GCState::Pause;
+    g.gc_events.add_pause(start.elapsed());
+    g.gc_events.cycle_end(g.total_bytes);
+    report_pressure(g);
+}
+
+/// What the collector reports to the host (LuaState::set_gc_callback)
+#[derive(Debug, Clone, PartialEq)]
+pub enum GcEvent {
+    /// A cycle begins with `total_bytes` in use
+    CycleStart { total_bytes: usize },
+    /// A cycle ended; `pause` is the time spent in its steps
+    CycleEnd { freed_bytes: usize, pause: Duration },
+    /// Memory in use went above `threshold` (once per crossing)
+    MemoryPressure { total_bytes: usize, threshold: usize },
+}
+
+/// Receives the GcEvents of a state. It runs inside the collector, so it
+/// must not use the state; a game would note the event and react at the
+/// end of its frame.
+pub type GcCallback = Box<dyn FnMut(&GcEvent)>;
+
+/// GC event reporting of a state: the callback, the memory-pressure
+/// thresholds and the figures of the running cycle
+#[derive(Default)]
+pub struct GcEvents {
+    callback: Option<GcCallback>,
+    thresholds: Vec<usize>, // ascending
+    level: usize,           // number of thresholds exceeded
+    cycle_bytes: usize,     // total_bytes at the start of the cycle
+    pause: Duration,        // time spent in the steps of the cycle
+}
+
+impl fmt::Debug for GcEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcEvents")
+            .field("callback", &self.callback.is_some())
+            .field("thresholds", &self.thresholds)
+            .field("level", &self.level)
+            .finish()
+    }
+}
+
+impl GcEvents {
+    pub fn set_callback(&mut self, f: Option<GcCallback>) {
+        self.callback = f;
+    }
+
+    /// Warn when memory in use goes above each of `thresholds`
+    pub fn set_thresholds(&mut self, thresholds: &[usize]) {
+        self.thresholds = thresholds.to_vec();
+        self.thresholds.sort_unstable();
+        self.thresholds.dedup();
+        self.level = 0;
+    }
+
+    fn emit(&mut self, e: GcEvent) {
+        if let Some(f) = &mut self.callback {
+            f(&e);
+        }
+    }
+
+    pub fn cycle_start(&mut self, total_bytes: usize) {
+        self.cycle_bytes = total_bytes;
+        self.pause = Duration::ZERO;
+        self.emit(GcEvent::CycleStart { total_bytes });
+    }
+
+    pub fn add_pause(&mut self, d: Duration) {
+        self.pause += d;
+    }
+
+    pub fn cycle_end(&mut self, total_bytes: usize) {
+        let freed_bytes = self.cycle_bytes.saturating_sub(total_bytes);
+        let pause = std::mem::take(&mut self.pause);
+        self.emit(GcEvent::CycleEnd { freed_bytes, pause });
+    }
+
+    /// Report the highest threshold `total_bytes` went above since the
+    /// last check, if any; falling back below a threshold re-arms it
+    pub fn check_pressure(&mut self, total_bytes: usize) -> Option<usize> {
+        let level = self.thresholds.partition_point(|&t| t < total_bytes);
+        let crossed = level > self.level;
+        self.level = level;
+        if !crossed {
+            return None;
+        }
+        let threshold = self.thresholds[level - 1];
+        self.emit(GcEvent::MemoryPressure { total_bytes, threshold });
+        Some(threshold)
+    }
+}
+
+/// Check the memory-pressure thresholds, also warning through the
+/// state's warning function
+fn report_pressure(g: &mut GlobalState) {
+    if let Some(threshold) = g.gc_events.check_pressure(g.total_bytes) {
+        if let Some(warn) = g.warning_func {
+            warn(&format!("memory pressure: {} bytes in use (threshold {})", g.total_bytes, threshold));
+        }
+    }
+}
+
+/// Memory figures of a state (LuaState::gc_stats)
+#[derive(Debug, Clone, PartialEq)]
+pub struct GcStats {
+    /// Bytes allocated by the state
+    pub total_bytes: usize,
+    /// Automatic collection stopped by the host
+    pub paused: bool,
+    /// Interned and long strings
+    pub strings: StringStats,
+}
+
+impl LuaState {
+    pub fn gc_stats(&self) -> GcStats {
+        let g = self.l_G.borrow();
+        GcStats { total_bytes: g.total_bytes, paused: g.gc_paused, strings: luaS_stats() }
+    }
+
+    /// Call `f` on the GC events of this state (see GcCallback)
+    pub fn set_gc_callback(&mut self, f: impl FnMut(&GcEvent) + 'static) {
+        self.l_G.borrow_mut().gc_events.set_callback(Some(Box::new(f)));
+    }
+
+    /// Report a MemoryPressure event and a warning whenever memory in use
+    /// goes above one of `thresholds` (in bytes)
+    pub fn set_memory_pressure_thresholds(&mut self, thresholds: &[usize]) {
+        self.l_G.borrow_mut().gc_events.set_thresholds(thresholds);
+    }
+
+    /// Stop (or resume) automatic collection, leaving it to explicit steps
+    pub fn gc_set_paused(&mut self, paused: bool) {
+        self.l_G.borrow_mut().gc_paused = paused;
+    }
 }
 
 /// Barrier (stub)
@@ -327,6 +553,8 @@ impl Default for GlobalState {
             metatables: Vec::new(),
             weak_tables: Vec::new(),
             current_white: WHITE0BIT,
+            gc_events: GcEvents::default(),
+            gc_paused: false,
             // ...other fields...
         }
     }
@@ -361,6 +589,24 @@ mod tests {
         assert_eq!(L.global.gcstate, GCState::Pause);
     }
 
+    #[test]
+    fn test_gc_step_budget() {
+        let mut L = lua_State::default();
+        for _ in 0..50 {
+            L.global.allgc.push_back(GCObject::default());
+        }
+        L.gc_set_paused(true);
+        luaC_step(&mut L);
+        assert_eq!(L.global.gcstate, GCState::Pause);
+        // a zero budget still does one step
+        let debt = L.gc_step(0);
+        assert_eq!(L.global.gcstate, GCState::Propagate);
+        assert_eq!(debt, gc_debt(&L.global));
+        assert!(debt > 50);
+        assert_eq!(L.gc_step(1_000_000), 0);
+        assert_eq!(L.global.gcstate, GCState::Pause);
+    }
+
     #[test]
     fn test_mark_and_sweep() {
         let mut g = GlobalState::default();
@@ -375,6 +621,31 @@ mod tests {
         assert!(isblack(&g.allgc[0]));
     }
 
+    #[test]
+    fn test_gc_events() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut ev = GcEvents::default();
+        let log = seen.clone();
+        ev.set_callback(Some(Box::new(move |e: &GcEvent| log.borrow_mut().push(e.clone()))));
+        ev.set_thresholds(&[2000, 1000]);
+        ev.cycle_start(1500);
+        ev.add_pause(Duration::from_millis(2));
+        ev.cycle_end(600);
+        assert_eq!(ev.check_pressure(1500), Some(1000));
+        assert_eq!(ev.check_pressure(1800), None);
+        assert_eq!(ev.check_pressure(2500), Some(2000));
+        assert_eq!(ev.check_pressure(500), None);
+        assert_eq!(ev.check_pressure(1001), Some(1000));
+        assert_eq!(seen.borrow()[..2], [
+            GcEvent::CycleStart { total_bytes: 1500 },
+            GcEvent::CycleEnd { freed_bytes: 900, pause: Duration::from_millis(2) },
+        ]);
+        assert_eq!(seen.borrow().len(), 5);
+    }
+
     #[test]
     fn test_barrier() {
         let mut o1 = GCObject::default();
@@ -0,0 +1,275 @@
+//! lhash.rs - Message digests and HMAC (skyla.hash)
+//
+// Built with the `hash` feature; MD5, SHA-1 and SHA-256 are implemented
+// here, with no C code and no dependencies. Digests are returned as
+// lower-case hex, since strings in this port are UTF-8 and cannot carry raw
+// digest bytes. MD5 and SHA-1 are broken against deliberate collisions:
+// they are for checking downloads against published sums, not for
+// signatures.
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+
+/// A supported digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+}
+
+/// All three take 64-byte blocks
+const BLOCK_SIZE: usize = 64;
+
+impl Algorithm {
+    pub const NAMES: &'static [&'static str] = &["md5", "sha1", "sha256"];
+
+    /// The algorithm named `name` (as in NAMES)
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "md5" => Some(Algorithm::Md5),
+            "sha1" => Some(Algorithm::Sha1),
+            "sha256" => Some(Algorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Md5 => md5(data).to_vec(),
+            Algorithm::Sha1 => sha1(data).to_vec(),
+            Algorithm::Sha256 => sha256(data).to_vec(),
+        }
+    }
+}
+
+/// `data` with the Merkle-Damgard padding: 0x80, zeros, and the bit length
+/// in 8 bytes (little-endian for MD5, big-endian for SHA)
+fn pad(data: &[u8], little_endian: bool) -> Vec<u8> {
+    let bits = (data.len() as u64).wrapping_mul(8);
+    let mut m = Vec::with_capacity(data.len() + 2 * BLOCK_SIZE);
+    m.extend_from_slice(data);
+    m.push(0x80);
+    while m.len() % BLOCK_SIZE != BLOCK_SIZE - 8 {
+        m.push(0);
+    }
+    m.extend_from_slice(&if little_endian { bits.to_le_bytes() } else { bits.to_be_bytes() });
+    m
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// floor(abs(sin(i + 1)) * 2^32)
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+pub fn md5(data: &[u8]) -> [u8; 16] {
+    let mut h: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in pad(data, true).chunks(BLOCK_SIZE) {
+        let w: Vec<u32> = block.chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = h;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(MD5_K[i]).wrapping_add(w[g]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated.rotate_left(MD5_SHIFTS[i / 16 * 4 + i % 4]));
+        }
+        for (x, v) in h.iter_mut().zip([a, b, c, d]) {
+            *x = x.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 16];
+    for (o, x) in out.chunks_mut(4).zip(h) {
+        o.copy_from_slice(&x.to_le_bytes());
+    }
+    out
+}
+
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    for block in pad(data, false).chunks(BLOCK_SIZE) {
+        let mut w = [0u32; 80];
+        for (i, b) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5a827999),
+                1 => (b ^ c ^ d, 0x6ed9eba1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(wi);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t);
+        }
+        for (x, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *x = x.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 20];
+    for (o, x) in out.chunks_mut(4).zip(h) {
+        o.copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+/// First 32 bits of the fractional parts of the cube roots of the first
+/// 64 primes
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+    for block in pad(data, false).chunks(BLOCK_SIZE) {
+        let mut w = [0u32; 64];
+        for (i, b) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([b[0], b[1], b[2], b[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for (&k, &wi) in SHA256_K.iter().zip(w.iter()) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(k).wrapping_add(wi);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (hh, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (x, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+            *x = x.wrapping_add(v);
+        }
+    }
+    let mut out = [0u8; 32];
+    for (o, x) in out.chunks_mut(4).zip(h) {
+        o.copy_from_slice(&x.to_be_bytes());
+    }
+    out
+}
+
+/// HMAC (RFC 2104) of `msg` under `key`
+pub fn hmac(alg: Algorithm, key: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut k = if key.len() > BLOCK_SIZE { alg.digest(key) } else { key.to_vec() };
+    k.resize(BLOCK_SIZE, 0);
+    let mut inner: Vec<u8> = k.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(msg);
+    let mut outer: Vec<u8> = k.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend(alg.digest(&inner));
+    alg.digest(&outer)
+}
+
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn push_digest(state: &mut LuaState, alg: Algorithm) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Str(to_hex(&alg.digest(s.as_bytes()))));
+    1
+}
+
+// hash.md5(s) -> hex digest
+fn hash_md5(state: &mut LuaState) -> i32 {
+    push_digest(state, Algorithm::Md5)
+}
+
+// hash.sha1(s) -> hex digest
+fn hash_sha1(state: &mut LuaState) -> i32 {
+    push_digest(state, Algorithm::Sha1)
+}
+
+// hash.sha256(s) -> hex digest
+fn hash_sha256(state: &mut LuaState) -> i32 {
+    push_digest(state, Algorithm::Sha256)
+}
+
+// hash.hmac(algorithm, key, message) -> hex digest
+fn hash_hmac(state: &mut LuaState) -> i32 {
+    let alg = Algorithm::from_name(Algorithm::NAMES[state.check_option(1, None, Algorithm::NAMES)])
+        .expect("check_option returns an index into NAMES");
+    let key = state.check_string(2);
+    let msg = state.check_string(3);
+    state.push(LuaValue::Str(to_hex(&hmac(alg, key.as_bytes(), msg.as_bytes()))));
+    1
+}
+
+const HASH_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("hmac", hash_hmac),
+    ("md5", hash_md5),
+    ("sha1", hash_sha1),
+    ("sha256", hash_sha256),
+];
+
+/// Register the `skyla.hash` module
+pub fn open_hash_lib(state: &mut LuaState) {
+    for &(name, f) in HASH_FUNCS {
+        state.register_lib_function("skyla.hash", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_digests() {
+        assert_eq!(to_hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(to_hex(&md5(b"The quick brown fox jumps over the lazy dog")), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(to_hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(to_hex(&sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        // two blocks once padded
+        assert_eq!(
+            to_hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_hmac() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            to_hex(&hmac(Algorithm::Sha256, b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // RFC 2202: a key longer than a block is hashed first
+        assert_eq!(
+            to_hex(&hmac(Algorithm::Md5, &[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "6b1ab7fe4bd7bf8f0b62e6ce61b9d0cd"
+        );
+        assert_eq!(
+            to_hex(&hmac(Algorithm::Sha1, b"Jefe", b"what do ya want for nothing?")),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+    }
+}
@@ -8,6 +8,7 @@ use crate::lmathlib::luaopen_math;
 use crate::ldblib::luaopen_debug;
 use crate::loslib::luaopen_os;
 use crate::lcorolib::luaopen_coroutine;
+#[cfg(not(feature = "minimal"))]
 use crate::liolib::luaopen_io;
 use crate::lutf8lib::luaopen_utf8;
 // Add more library modules as needed
@@ -21,6 +22,8 @@ const LUA_LIBS: &[(&str, fn(*mut lua_State) -> i32)] = &[
     ("debug", luaopen_debug),
     ("os", luaopen_os),
     ("coroutine", luaopen_coroutine),
+    // io needs a file system; `minimal` builds leave it out
+    #[cfg(not(feature = "minimal"))]
     ("io", luaopen_io),
     ("utf8", luaopen_utf8),
     // Add more libraries here
@@ -65,6 +68,12 @@ pub unsafe fn luaL_openlibs(L: *mut lua_State) {
         crate::lapi::luaL_requiref(L, name, Some(openf), 1);
         crate::lapi::lua_pop(L, 1);
     }
+    // Internal test library 'T' (ltests), only in test builds of the VM
+    #[cfg(feature = "internal_tests")]
+    {
+        crate::lapi::luaL_requiref(L, "T", Some(crate::ltests::luaB_opentests), 1);
+        crate::lapi::lua_pop(L, 1);
+    }
 }
 
 /// Optionally, allow registering custom libraries at runtime
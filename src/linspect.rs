@@ -0,0 +1,298 @@
+//! linspect.rs - Human-readable rendering of Lua values (skyla.inspect)
+//
+// Used by the REPL to show expression results, and by skyla.inspect(v
+// [, options]). Tables print as constructors: the sequence 1..n first,
+// then the other keys sorted (numbers, then strings, then the rest by
+// type), identifiers bare and other keys in brackets. A table already on
+// the path from the root prints as <cycle>, one below the depth limit as
+// {...}. A value whose metatable has __tostring prints as what it returns.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt::Write as _;
+use std::rc::Rc;
+
+use crate::lauxlib::error_typename;
+use crate::lcolor;
+use crate::lobject::{luaO_num2str_dot, LuaValue};
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+use crate::ltm::{call_tm_vm, get_metafield};
+
+/// Options for `LuaValue::inspect`
+#[derive(Debug, Clone)]
+pub struct InspectOptions {
+    /// Tables nested deeper than this print as {...}
+    pub depth: usize,
+    /// One entry per line with this many spaces per level; None for one line
+    pub indent: Option<usize>,
+    /// Color strings, numbers and keys with ANSI escapes (lcolor)
+    pub color: bool,
+}
+
+impl Default for InspectOptions {
+    fn default() -> Self {
+        InspectOptions { depth: 8, indent: None, color: false }
+    }
+}
+
+/// The text for a value, if it has one of its own (__tostring)
+type TostringHook<'a> = &'a mut dyn FnMut(&LuaValue) -> Option<String>;
+
+const RESERVED: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+    "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// A name that can be written bare, as a field or local name
+pub(crate) fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !RESERVED.contains(&s)
+}
+
+/// Order of the non-sequence keys: numbers, strings, booleans, others
+fn key_order(a: &LuaValue, b: &LuaValue) -> Ordering {
+    fn rank(v: &LuaValue) -> u8 {
+        match v {
+            LuaValue::Int(_) | LuaValue::Float(_) => 0,
+            LuaValue::Str(_) => 1,
+            LuaValue::Bool(_) => 2,
+            _ => 3,
+        }
+    }
+    let num = |v: &LuaValue| match v {
+        LuaValue::Int(i) => *i as f64,
+        LuaValue::Float(f) => *f,
+        _ => 0.0,
+    };
+    match (a, b) {
+        (LuaValue::Str(x), LuaValue::Str(y)) => x.cmp(y),
+        (LuaValue::Bool(x), LuaValue::Bool(y)) => x.cmp(y),
+        _ if rank(a) == 0 && rank(b) == 0 => num(a).total_cmp(&num(b)),
+        _ => rank(a).cmp(&rank(b)).then_with(|| error_typename(a).cmp(&error_typename(b))),
+    }
+}
+
+struct Inspector<'a> {
+    opts: &'a InspectOptions,
+    out: String,
+    /// Tables on the path from the root, for cycle detection
+    path: Vec<*const RefCell<Table>>,
+    tostring: Option<TostringHook<'a>>,
+}
+
+impl Inspector<'_> {
+    fn separator(&mut self, depth: usize) {
+        match self.opts.indent {
+            Some(n) => {
+                self.out.push('\n');
+                self.out.push_str(&" ".repeat(n * depth));
+            }
+            None => self.out.push(' '),
+        }
+    }
+
+    fn value(&mut self, v: &LuaValue) {
+        if let Some(tostring) = self.tostring.as_mut() {
+            if let Some(s) = tostring(v) {
+                self.out.push_str(&s);
+                return;
+            }
+        }
+        let (style, text) = match v {
+            LuaValue::Nil => (lcolor::KEYWORD, "nil".to_string()),
+            LuaValue::Bool(b) => (lcolor::KEYWORD, b.to_string()),
+            LuaValue::Int(i) => (lcolor::NUMBER, i.to_string()),
+            LuaValue::Float(f) => (lcolor::NUMBER, luaO_num2str_dot(*f)),
+            LuaValue::Str(s) => (lcolor::STRING, format!("{:?}", s)),
+            LuaValue::Table(t) => return self.table(t),
+            other => (lcolor::DIM, format!("<{}>", error_typename(other))),
+        };
+        self.paint(style, &text);
+    }
+
+    fn paint(&mut self, style: &str, text: &str) {
+        self.out.push_str(&lcolor::paint(self.opts.color, style, text));
+    }
+
+    fn key(&mut self, k: &LuaValue) {
+        match k {
+            LuaValue::Str(s) if is_identifier(s) => self.paint(lcolor::KEY, s),
+            k => {
+                self.out.push('[');
+                self.value(k);
+                self.out.push(']');
+            }
+        }
+    }
+
+    fn table(&mut self, t: &Rc<RefCell<Table>>) {
+        let ptr = Rc::as_ptr(t);
+        if self.path.contains(&ptr) {
+            self.paint(lcolor::DIM, "<cycle>");
+            return;
+        }
+        let mut pairs: Vec<(LuaValue, LuaValue)> = t.borrow().pairs().map(|(k, v)| (k, v.clone())).collect();
+        if pairs.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        if self.path.len() >= self.opts.depth {
+            self.paint(lcolor::DIM, "{...}");
+            return;
+        }
+        // the sequence 1..n prints positionally, in order
+        let mut n = 0;
+        while pairs.iter().any(|(k, _)| matches!(k, LuaValue::Int(i) if *i == n + 1)) {
+            n += 1;
+        }
+        let (mut seq, mut rest): (Vec<_>, Vec<_>) = pairs.drain(..).partition(|(k, _)| matches!(k, LuaValue::Int(i) if (1..=n).contains(i)));
+        seq.sort_by_key(|(k, _)| match k { LuaValue::Int(i) => *i, _ => 0 });
+        rest.sort_by(|a, b| key_order(&a.0, &b.0));
+        self.path.push(ptr);
+        let depth = self.path.len();
+        self.out.push('{');
+        let entries = seq.iter().map(|(_, v)| (None, v)).chain(rest.iter().map(|(k, v)| (Some(k), v)));
+        for (i, (k, v)) in entries.enumerate() {
+            if i > 0 {
+                self.out.push(',');
+            }
+            self.separator(depth);
+            if let Some(k) = k {
+                self.key(k);
+                self.out.push_str(" = ");
+            }
+            self.value(v);
+        }
+        self.separator(depth - 1);
+        self.out.push('}');
+        self.path.pop();
+    }
+}
+
+impl LuaValue {
+    /// Render for people to read (no metamethods are consulted)
+    pub fn inspect(&self, opts: &InspectOptions) -> String {
+        let mut ins = Inspector { opts, out: String::new(), path: Vec::new(), tostring: None };
+        ins.value(self);
+        ins.out
+    }
+
+    /// Render for people to read, each value printing as `tostring(value)`
+    /// when that returns text (the REPL and skyla.inspect pass __tostring)
+    pub fn inspect_with(&self, opts: &InspectOptions, tostring: &mut dyn FnMut(&LuaValue) -> Option<String>) -> String {
+        let mut ins = Inspector { opts, out: String::new(), path: Vec::new(), tostring: Some(tostring) };
+        ins.value(self);
+        ins.out
+    }
+}
+
+/// The __tostring text of `v`, if its metatable has a __tostring that
+/// returns a string
+pub fn tostring_hook(state: &mut LuaState, v: &LuaValue) -> Option<String> {
+    let f = get_metafield(v, "__tostring")?;
+    match call_tm_vm(state, &f, std::slice::from_ref(v))? {
+        LuaValue::Str(s) => Some(s),
+        _ => None,
+    }
+}
+
+/// `v` as the REPL shows it
+pub fn inspect_value(state: &mut LuaState, v: &LuaValue, opts: &InspectOptions) -> String {
+    v.inspect_with(opts, &mut |v| tostring_hook(state, v))
+}
+
+/// Read the options table at `arg`, if any
+fn inspect_options(state: &mut LuaState, arg: i32) -> InspectOptions {
+    let mut opts = InspectOptions::default();
+    let LuaValue::Table(t) = state.to_value(arg) else { return opts };
+    let t = t.borrow();
+    let field = |name: &str| t.get(&LuaValue::Str(name.to_string())).cloned();
+    if let Some(LuaValue::Int(n)) = field("depth") {
+        opts.depth = n.max(0) as usize;
+    }
+    match field("indent") {
+        Some(LuaValue::Bool(true)) => opts.indent = Some(2),
+        Some(LuaValue::Int(n)) if n >= 0 => opts.indent = Some(n as usize),
+        _ => {}
+    }
+    opts.color = matches!(field("color"), Some(LuaValue::Bool(true)));
+    opts
+}
+
+// skyla.inspect(value [, options]) -> string
+fn skyla_inspect(state: &mut LuaState) -> i32 {
+    let opts = inspect_options(state, 2);
+    let v = state.to_value(1);
+    let s = inspect_value(state, &v, &opts);
+    state.push(LuaValue::Str(s));
+    1
+}
+
+/// Register `inspect` in the `skyla` module
+pub fn open_inspect_lib(state: &mut LuaState) {
+    state.register_lib_function("skyla", "inspect", skyla_inspect);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(pairs: Vec<(LuaValue, LuaValue)>) -> LuaValue {
+        let mut t = Table::new();
+        for (k, v) in pairs {
+            t.rawset(&k, v);
+        }
+        LuaValue::Table(Rc::new(RefCell::new(t)))
+    }
+
+    fn s(x: &str) -> LuaValue {
+        LuaValue::Str(x.to_string())
+    }
+
+    #[test]
+    fn test_sequence_then_sorted_keys() {
+        let v = table(vec![
+            (s("b"), LuaValue::Float(2.0)),
+            (LuaValue::Int(2), s("two")),
+            (s("a b"), LuaValue::Bool(true)),
+            (LuaValue::Int(1), LuaValue::Int(1)),
+            (LuaValue::Int(10), s("ten")),
+            (s("end"), LuaValue::Int(0)),
+            (s("a"), table(vec![])),
+        ]);
+        let opts = InspectOptions::default();
+        assert_eq!(v.inspect(&opts), r#"{ 1, "two", [10] = "ten", a = {}, ["a b"] = true, b = 2.0, ["end"] = 0 }"#);
+        let pretty = InspectOptions { indent: Some(2), ..opts };
+        assert_eq!(table(vec![(s("k"), table(vec![(LuaValue::Int(1), s("x"))]))]).inspect(&pretty),
+            "{\n  k = {\n    \"x\"\n  }\n}");
+    }
+
+    #[test]
+    fn test_cycles_and_depth() {
+        let t = Rc::new(RefCell::new(Table::new()));
+        t.borrow_mut().rawset(&s("self"), LuaValue::Table(t.clone()));
+        assert_eq!(LuaValue::Table(t.clone()).inspect(&InspectOptions::default()), "{ self = <cycle> }");
+        let deep = table(vec![(LuaValue::Int(1), table(vec![(LuaValue::Int(1), table(vec![(LuaValue::Int(1), LuaValue::Int(3))]))]))]);
+        assert_eq!(deep.inspect(&InspectOptions { depth: 2, ..Default::default() }), "{ { {...} } }");
+    }
+
+    #[test]
+    fn test_color() {
+        let v = table(vec![(s("k"), s("v")), (LuaValue::Int(1), LuaValue::Int(2))]);
+        let opts = InspectOptions { color: true, ..Default::default() };
+        assert_eq!(v.inspect(&opts), format!("{{ {n}2{r}, {k}k{r} = {s}\"v\"{r} }}",
+            n = lcolor::NUMBER, k = lcolor::KEY, s = lcolor::STRING, r = lcolor::RESET));
+    }
+
+    #[test]
+    fn test_tostring_hook() {
+        let point = table(vec![(s("x"), LuaValue::Int(1))]);
+        let v = table(vec![(LuaValue::Int(1), point.clone())]);
+        let is_point = |t: &LuaValue| matches!((t, &point), (LuaValue::Table(a), LuaValue::Table(b)) if Rc::ptr_eq(a, b));
+        let mut hook = |t: &LuaValue| is_point(t).then(|| "Point(1)".to_string());
+        assert_eq!(v.inspect_with(&InspectOptions::default(), &mut hook), "{ Point(1) }");
+    }
+}
@@ -0,0 +1,1292 @@
+//! liolib.rs - Standard I/O library (Rust port of liolib.c)
+//
+// Files are opened through the state's Vfs (lvfs), so a sandboxed host
+// decides what io.open can reach; io.popen runs a command through the shell
+// with std::process, subject to the sandbox policy. A file reaches Lua as a
+// "FILE*" object (luserdata) carrying the file methods, so `f:read()` and
+// friends work and a script cannot make up a file it was not given. When
+// the object is collected the file is closed (__gc), and a pipe's command
+// is waited for. Strings are UTF-8: bytes read that are not valid UTF-8
+// are replaced.
+//
+// io.read, io.write and io.lines without a file name use the state's
+// default files (io.input, io.output). Until a script or the host picks
+// others they are the standard files: stdin, and the state's output sink
+// (loutput), which cannot be closed. A host can make any Read or Write the
+// default with set_default_input and set_default_output, e.g. to run a
+// script against an embedded console.
+//
+// C modules see a file as the luaL_Stream that luaL_checkudata(L, i,
+// LUA_FILEHANDLE) returns: `closef` closes it like io.close and is null
+// once it is closed, and on Unix `f` is a FILE* on a duplicate of the
+// descriptor of stdin and pipes (Vfs files have no descriptor, so theirs
+// is null).
+
+use std::cell::RefCell;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
+use std::rc::Rc;
+
+use crate::lauxlib::{lua_CFunction, luaL_Stream, ExecStatus, LibFunction, FILE};
+use crate::lobject::{luaO_str2number, LuaValue, Numeral};
+use crate::loslib::shell_command;
+use crate::lplatform::EnvOverlay;
+use crate::loutput::OutputHandle;
+use crate::lstate::LuaState;
+use crate::luserdata::{userdata_ref, UserData, UserDataMethods};
+use crate::lvfs::{OpenMode, VfsFile};
+
+/// Size of the read-ahead buffer
+const IO_BUFSIZE: usize = 8192;
+
+enum Stream {
+    File(Box<dyn VfsFile>),
+    PipeRead(Child, ChildStdout),
+    PipeWrite(Child, ChildStdin),
+    /// The process's stdin (the standard input file)
+    Stdin,
+    /// The state's output sink (the standard output file)
+    Stdout(OutputHandle),
+    /// Host-supplied default input
+    Reader(Box<dyn Read>),
+    /// Host-supplied default output
+    Writer(Box<dyn Write>),
+}
+
+/// Longest numeral file:read("n") accepts (L_MAXLENNUM)
+const L_MAXLENNUM: usize = 200;
+
+/// Buffering of writes (file:setvbuf)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufMode {
+    /// Every write goes straight to the stream (the default)
+    No,
+    /// Writes are kept until the buffer is full
+    Full,
+    /// Writes are kept until a newline is written or the buffer is full
+    Line,
+}
+
+/// An open (or closed) Lua file
+pub struct LuaFile {
+    stream: Option<Stream>,
+    rbuf: Vec<u8>,
+    rpos: usize,
+    wbuf: Vec<u8>,
+    vbuf: (BufMode, usize),
+    /// What C modules see of the file, made by c_stream
+    cstream: Option<Box<luaL_Stream>>,
+}
+
+impl LuaFile {
+    pub fn from_vfs(f: Box<dyn VfsFile>) -> Self {
+        Self::new(Stream::File(f))
+    }
+
+    fn new(stream: Stream) -> Self {
+        LuaFile { stream: Some(stream), rbuf: Vec::new(), rpos: 0, wbuf: Vec::new(), vbuf: (BufMode::No, IO_BUFSIZE), cstream: None }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.stream.is_none()
+    }
+
+    fn closed_error() -> io::Error {
+        io::Error::other("attempt to use a closed file")
+    }
+
+    fn raw_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.as_mut() {
+            Some(Stream::File(f)) => f.read(buf),
+            Some(Stream::PipeRead(_, out)) => out.read(buf),
+            Some(Stream::Stdin) => io::stdin().read(buf),
+            Some(Stream::Reader(r)) => r.read(buf),
+            Some(Stream::PipeWrite(..) | Stream::Stdout(_) | Stream::Writer(_)) => {
+                Err(io::Error::other("file not open for reading"))
+            }
+            None => Err(Self::closed_error()),
+        }
+    }
+
+    /// Make sure some unread bytes are buffered; false at end of file
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.rpos < self.rbuf.len() {
+            return Ok(true);
+        }
+        self.flush_writes()?;
+        let mut buf = std::mem::take(&mut self.rbuf);
+        buf.resize(IO_BUFSIZE, 0);
+        self.rpos = 0;
+        let r = self.raw_read(&mut buf);
+        buf.truncate(*r.as_ref().unwrap_or(&0));
+        self.rbuf = buf;
+        Ok(r? > 0)
+    }
+
+    fn unread(&self) -> &[u8] {
+        &self.rbuf[self.rpos..]
+    }
+
+    /// Next line, with its newline when `keep_nl`; None at end of file
+    pub fn read_line(&mut self, keep_nl: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let mut any = false;
+        while self.fill()? {
+            any = true;
+            let chunk = self.unread();
+            match chunk.iter().position(|&c| c == b'\n') {
+                Some(i) => {
+                    line.extend_from_slice(&chunk[..if keep_nl { i + 1 } else { i }]);
+                    self.rpos += i + 1;
+                    return Ok(Some(line));
+                }
+                None => {
+                    line.extend_from_slice(chunk);
+                    self.rpos = self.rbuf.len();
+                }
+            }
+        }
+        Ok(if any { Some(line) } else { None })
+    }
+
+    /// Up to `n` bytes, fewer only at end of file; None at end of file
+    /// (read(0) tests for it). Once the buffer is drained, long reads go
+    /// straight into the result instead of through the buffer.
+    pub fn read_bytes(&mut self, n: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut out = Vec::with_capacity(n.min(IO_BUFSIZE));
+        if !self.fill()? {
+            return Ok(None);
+        }
+        let take = n.min(self.unread().len());
+        out.extend_from_slice(&self.unread()[..take]);
+        self.rpos += take;
+        while n - out.len() >= IO_BUFSIZE {
+            let len = out.len();
+            out.resize(len + (n - len).min(1 << 20), 0);
+            let got = self.raw_read(&mut out[len..]);
+            out.truncate(len + *got.as_ref().unwrap_or(&0));
+            if got? == 0 {
+                return Ok(Some(out));
+            }
+        }
+        while out.len() < n && self.fill()? {
+            let take = (n - out.len()).min(self.unread().len());
+            out.extend_from_slice(&self.unread()[..take]);
+            self.rpos += take;
+        }
+        Ok(Some(out))
+    }
+
+    /// Next unread byte, without consuming it
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill()? { Some(self.rbuf[self.rpos]) } else { None })
+    }
+
+    /// Consume the next byte if it is one of `set` and add it to `buf`
+    fn accept(&mut self, buf: &mut Vec<u8>, set: &[u8]) -> io::Result<bool> {
+        match self.peek()? {
+            Some(c) if set.contains(&c) && buf.len() < L_MAXLENNUM => {
+                buf.push(c);
+                self.rpos += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Consume digits (hex digits with `hex`) into `buf`; how many
+    fn accept_digits(&mut self, buf: &mut Vec<u8>, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(c) = self.peek()? {
+            let digit = if hex { c.is_ascii_hexdigit() } else { c.is_ascii_digit() };
+            if !digit || buf.len() >= L_MAXLENNUM {
+                break;
+            }
+            buf.push(c);
+            self.rpos += 1;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// A numeral, read as the lexer reads one (l_getn): leading spaces,
+    /// a sign, "0x" for hex, digits, a point and more digits, then an
+    /// exponent ('e', or 'p' for hex) with its own sign. Only the longest
+    /// prefix that can start a numeral is consumed; None if it is not one
+    /// (or is longer than L_MAXLENNUM)
+    pub fn read_number(&mut self) -> io::Result<Option<Numeral>> {
+        while matches!(self.peek()?, Some(b' ' | b'\t'..=b'\r')) {
+            self.rpos += 1;
+        }
+        let mut buf = Vec::new();
+        let mut count = 0;
+        let mut hex = false;
+        self.accept(&mut buf, b"-+")?;
+        if self.accept(&mut buf, b"0")? {
+            if self.accept(&mut buf, b"xX")? {
+                hex = true;
+            } else {
+                count = 1; // the initial '0' is a digit
+            }
+        }
+        count += self.accept_digits(&mut buf, hex)?;
+        if self.accept(&mut buf, b".")? {
+            count += self.accept_digits(&mut buf, hex)?;
+        }
+        if count > 0 && self.accept(&mut buf, if hex { b"pP" } else { b"eE" })? {
+            self.accept(&mut buf, b"-+")?;
+            self.accept_digits(&mut buf, false)?;
+        }
+        if buf.len() >= L_MAXLENNUM {
+            return Ok(None);
+        }
+        Ok(std::str::from_utf8(&buf).ok().and_then(luaO_str2number))
+    }
+
+    /// The rest of the file (possibly empty)
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        self.flush_writes()?;
+        let mut out = self.unread().to_vec();
+        self.rpos = self.rbuf.len();
+        let mut buf = [0u8; IO_BUFSIZE];
+        loop {
+            let n = self.raw_read(&mut buf)?;
+            if n == 0 {
+                return Ok(out);
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// Move to `pos` (file:seek); returns the new position from the start.
+    /// Offsets are 64-bit, so files past 4 GB work where the Vfs allows.
+    pub fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.flush_writes()?;
+        let pos = match pos {
+            // the stream is ahead of the logical position by the unread bytes
+            SeekFrom::Current(off) => SeekFrom::Current(off - self.unread().len() as i64),
+            pos => pos,
+        };
+        self.rbuf.clear();
+        self.rpos = 0;
+        match self.stream.as_mut() {
+            Some(Stream::File(f)) => f.seek(pos),
+            Some(_) => Err(io::Error::from_raw_os_error(29)), // ESPIPE: "Illegal seek"
+            None => Err(Self::closed_error()),
+        }
+    }
+
+    /// Set how writes are buffered (file:setvbuf); pending writes go out first
+    pub fn setvbuf(&mut self, mode: BufMode, size: usize) -> io::Result<()> {
+        self.flush_writes()?;
+        self.vbuf = (mode, size.max(1));
+        Ok(())
+    }
+
+    /// Write out the buffered writes
+    fn flush_writes(&mut self) -> io::Result<()> {
+        if self.wbuf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.wbuf);
+        self.write_stream(&data)
+    }
+
+    fn write_stream(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.stream.as_mut() {
+            Some(Stream::File(f)) => f.write_all(data),
+            Some(Stream::PipeWrite(_, input)) => input.write_all(data),
+            Some(Stream::Stdout(out)) => out.0.borrow_mut().write(data),
+            Some(Stream::Writer(w)) => w.write_all(data),
+            Some(Stream::PipeRead(..) | Stream::Stdin | Stream::Reader(_)) => {
+                Err(io::Error::other("file not open for writing"))
+            }
+            None => Err(Self::closed_error()),
+        }
+    }
+
+    /// Give back read-ahead bytes so the stream position is the logical one
+    fn drop_read_buffer(&mut self) -> io::Result<()> {
+        let unread = self.unread().len() as i64;
+        self.rbuf.clear();
+        self.rpos = 0;
+        if let (Some(Stream::File(f)), true) = (self.stream.as_mut(), unread > 0) {
+            f.seek(SeekFrom::Current(-unread))?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.drop_read_buffer()?;
+        if self.stream.is_none() {
+            return Err(Self::closed_error());
+        }
+        let (mode, size) = self.vbuf;
+        if mode == BufMode::No {
+            return self.write_stream(data);
+        }
+        self.wbuf.extend_from_slice(data);
+        if self.wbuf.len() >= size || (mode == BufMode::Line && data.contains(&b'\n')) {
+            self.flush_writes()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.flush_writes()?;
+        match self.stream.as_mut() {
+            Some(Stream::File(f)) => f.flush(),
+            Some(Stream::PipeWrite(_, input)) => input.flush(),
+            Some(Stream::Stdout(out)) => out.0.borrow_mut().flush(),
+            Some(Stream::Writer(w)) => w.flush(),
+            Some(Stream::PipeRead(..) | Stream::Stdin | Stream::Reader(_)) => Ok(()),
+            None => Err(Self::closed_error()),
+        }
+    }
+
+    /// Close the file; for a pipe, wait for the command and return how it
+    /// ended. The standard files stay open (io_noclose).
+    pub fn close(&mut self) -> io::Result<Option<ExecStatus>> {
+        self.flush()?;
+        if matches!(self.stream, Some(Stream::Stdin | Stream::Stdout(_))) {
+            return Err(io::Error::other("cannot close standard file"));
+        }
+        self.close_c_stream(); // first, or a pipe's command would not see EOF
+        match self.stream.take() {
+            Some(Stream::File(_) | Stream::Reader(_) | Stream::Writer(_)) => Ok(None),
+            Some(Stream::Stdin | Stream::Stdout(_)) => unreachable!("standard files stay open"),
+            Some(Stream::PipeRead(mut child, out)) => {
+                drop(out);
+                child.wait().map(|s| Some(ExecStatus::from_status(s)))
+            }
+            Some(Stream::PipeWrite(mut child, input)) => {
+                drop(input); // EOF for the command
+                child.wait().map(|s| Some(ExecStatus::from_status(s)))
+            }
+            None => Err(Self::closed_error()),
+        }
+    }
+}
+
+impl LuaFile {
+    /// The luaL_Stream of this file, made on first use. Its FILE* shares
+    /// the descriptor but not the buffers, so writes are flushed first.
+    pub fn c_stream(&mut self) -> *mut luaL_Stream {
+        if self.cstream.is_none() {
+            let _ = self.flush();
+            let closef = if self.is_closed() { None } else { Some(io_fclose as lua_CFunction) };
+            self.cstream = Some(Box::new(luaL_Stream { f: self.os_file(), closef }));
+        }
+        self.cstream.as_deref_mut().map_or(ptr::null_mut(), |p| p as *mut luaL_Stream)
+    }
+
+    #[cfg(unix)]
+    fn os_file(&self) -> *mut FILE {
+        use std::os::unix::io::AsRawFd;
+        let (fd, mode) = match self.stream.as_ref() {
+            Some(Stream::Stdin) => (0, b"r\0"),
+            Some(Stream::PipeRead(_, out)) => (out.as_raw_fd(), b"r\0"),
+            Some(Stream::PipeWrite(_, input)) => (input.as_raw_fd(), b"w\0"),
+            _ => return ptr::null_mut(),
+        };
+        unsafe {
+            let fd = dup(fd);
+            if fd < 0 {
+                return ptr::null_mut();
+            }
+            let f = fdopen(fd, mode.as_ptr() as *const c_char);
+            if f.is_null() {
+                close(fd);
+            }
+            f
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn os_file(&self) -> *mut FILE {
+        ptr::null_mut()
+    }
+
+    /// Mark the luaL_Stream closed and close its FILE*
+    fn close_c_stream(&mut self) {
+        if let Some(p) = self.cstream.as_mut() {
+            #[cfg(unix)]
+            if !p.f.is_null() {
+                unsafe { fclose(p.f) };
+            }
+            p.f = ptr::null_mut();
+            p.closef = None;
+        }
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn dup(fd: c_int) -> c_int;
+    fn close(fd: c_int) -> c_int;
+    fn fdopen(fd: c_int, mode: *const c_char) -> *mut FILE;
+    fn fclose(f: *mut FILE) -> c_int;
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        if matches!(self.stream, Some(Stream::PipeRead(..) | Stream::PipeWrite(..))) {
+            // nobody closed the pipe: still reap its command
+            let _ = self.close();
+            return;
+        }
+        let _ = self.flush_writes();
+        self.close_c_stream();
+    }
+}
+
+/// Run `cmd` with its output ("r") or input ("w") connected to the file,
+/// and the environment `env`
+pub fn popen(cmd: &str, mode: &str, env: &EnvOverlay) -> io::Result<LuaFile> {
+    let mut command = shell_command(cmd);
+    env.apply(&mut command);
+    if mode == "w" {
+        let mut child = command.stdin(Stdio::piped()).spawn()?;
+        let input = child.stdin.take().expect("piped stdin");
+        Ok(LuaFile::new(Stream::PipeWrite(child, input)))
+    } else {
+        let mut child = command.stdout(Stdio::piped()).spawn()?;
+        let out = child.stdout.take().expect("piped stdout");
+        Ok(LuaFile::new(Stream::PipeRead(child, out)))
+    }
+}
+
+// --- Lua bindings ---
+
+/// A file as the io functions hold it
+type FileRef = Rc<RefCell<LuaFile>>;
+
+impl UserData for LuaFile {
+    const NAME: &'static str = "FILE*";
+
+    // The methods reading arguments themselves (FILE_METHODS) are set on
+    // each object by new_file_object
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_meta_method("__tostring", |L, f| {
+            let s = if f.is_closed() { "file (closed)".to_string() } else { format!("file ({:p})", f) };
+            L.push(LuaValue::Str(s));
+            1
+        });
+        methods.add_meta_method_mut("__gc", io_gc);
+        methods.add_meta_method_mut("__close", io_gc);
+    }
+}
+
+/// Finalizer of a file object: close it unless it is closed already (or
+/// a standard file, which stays open)
+fn io_gc(_state: &mut LuaState, f: &mut LuaFile) -> i32 {
+    if !f.is_closed() {
+        let _ = f.close();
+    }
+    0
+}
+
+fn bytes_to_lua(b: &[u8]) -> LuaValue {
+    LuaValue::Str(String::from_utf8_lossy(b).into_owned())
+}
+
+/// The Lua object for `f`
+pub fn new_file_object(state: &mut LuaState, f: LuaFile) -> LuaValue {
+    let obj = state.create_userdata(f);
+    if let LuaValue::Table(t) = &obj {
+        state.set_funcs(t, FILE_METHODS);
+    }
+    obj
+}
+
+/// The file behind object `v`
+fn file_handle(v: &LuaValue) -> Option<FileRef> {
+    userdata_ref::<LuaFile>(v)
+}
+
+fn with_file<R>(file: &FileRef, f: impl FnOnce(&mut LuaFile) -> R) -> R {
+    f(&mut file.borrow_mut())
+}
+
+/// The open file at `arg` (tofile)
+fn to_file(state: &mut LuaState, arg: i32) -> Option<FileRef> {
+    let Some(file) = file_handle(&state.to_value(arg)) else {
+        state.type_error(arg, "FILE*");
+    };
+    if with_file(&file, |f| f.is_closed()) {
+        state.error("attempt to use a closed file");
+    }
+    Some(file)
+}
+
+/// Which default file (IO_INPUT, IO_OUTPUT)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IoDefault {
+    Input,
+    Output,
+}
+
+/// The default files of a state (the IO_INPUT and IO_OUTPUT registry
+/// entries of liolib.c); None until first used or set
+#[derive(Debug, Default)]
+pub struct IoDefaults {
+    input: Option<LuaValue>,
+    output: Option<LuaValue>,
+}
+
+impl IoDefaults {
+    fn slot(&mut self, which: IoDefault) -> &mut Option<LuaValue> {
+        match which {
+            IoDefault::Input => &mut self.input,
+            IoDefault::Output => &mut self.output,
+        }
+    }
+}
+
+/// A new object for the standard file `which` stands for: stdin, or the
+/// state's output sink
+fn std_file(state: &mut LuaState, which: IoDefault) -> LuaValue {
+    let stream = match which {
+        IoDefault::Input => Stream::Stdin,
+        IoDefault::Output => Stream::Stdout(state.l_G.borrow().output.clone()),
+    };
+    new_file_object(state, LuaFile::new(stream))
+}
+
+/// The default file object, creating the standard file on first use
+fn default_file(state: &mut LuaState, which: IoDefault) -> LuaValue {
+    if let Some(obj) = state.l_G.borrow_mut().io_defaults.slot(which).clone() {
+        return obj;
+    }
+    let obj = std_file(state, which);
+    set_default_file(state, which, obj.clone());
+    obj
+}
+
+fn set_default_file(state: &mut LuaState, which: IoDefault, obj: LuaValue) {
+    *state.l_G.borrow_mut().io_defaults.slot(which) = Some(obj);
+}
+
+/// The default file, which must be open (getiofile)
+fn default_handle(state: &mut LuaState, which: IoDefault) -> FileRef {
+    let obj = default_file(state, which);
+    match file_handle(&obj) {
+        Some(file) if !with_file(&file, |f| f.is_closed()) => file,
+        _ => {
+            let name = if which == IoDefault::Input { "input" } else { "output" };
+            state.throw(LuaValue::Str(format!("default {} file is closed", name)))
+        }
+    }
+}
+
+impl LuaState {
+    /// Make `r` the default input of io.read and io.lines, as io.input(file)
+    /// would; the file it replaces is left open
+    pub fn set_default_input(&mut self, r: Box<dyn Read>) {
+        let obj = new_file_object(self, LuaFile::new(Stream::Reader(r)));
+        set_default_file(self, IoDefault::Input, obj);
+    }
+
+    /// Make `w` the default output of io.write, as io.output(file) would;
+    /// print still goes to the output sink (set_output)
+    pub fn set_default_output(&mut self, w: Box<dyn Write>) {
+        let obj = new_file_object(self, LuaFile::new(Stream::Writer(w)));
+        set_default_file(self, IoDefault::Output, obj);
+    }
+}
+
+/// io.input and io.output (g_iofile): a file name opens that file in `mode`,
+/// a file becomes the default; either way the current default is returned
+fn io_file(state: &mut LuaState, which: IoDefault, mode: &str) -> i32 {
+    if !state.is_none_or_nil(1) {
+        let obj = match state.to_value(1) {
+            LuaValue::Str(filename) => {
+                let m = OpenMode::parse(mode).expect("valid mode");
+                match state.vfs().open(&filename, m) {
+                    Ok(f) => new_file_object(state, LuaFile::from_vfs(f)),
+                    Err(e) => {
+                        let msg = format!("cannot open file '{}' ({})", filename, crate::lauxlib::io_strerror(&e));
+                        state.throw(LuaValue::Str(msg))
+                    }
+                }
+            }
+            v => {
+                to_file(state, 1);
+                v
+            }
+        };
+        set_default_file(state, which, obj);
+    }
+    let obj = default_file(state, which);
+    state.push(obj);
+    1
+}
+
+// io.input([file | filename])
+fn io_input(state: &mut LuaState) -> i32 {
+    io_file(state, IoDefault::Input, "r")
+}
+
+// io.output([file | filename])
+fn io_output(state: &mut LuaState) -> i32 {
+    io_file(state, IoDefault::Output, "w")
+}
+
+/// closef of every file's luaL_Stream: io.close on the file at index 1
+unsafe extern "C-unwind" fn io_fclose(L: *mut crate::lapi::lua_State) -> c_int {
+    let state = &mut *(L as *mut LuaState);
+    f_close(state)
+}
+
+/// The luaL_Stream of the file at stack index `idx`, or null if the value
+/// there is not a file (luaL_testudata with LUA_FILEHANDLE)
+pub unsafe fn tofilestream(L: *mut crate::lauxlib::lua_State, idx: c_int) -> *mut luaL_Stream {
+    let state = &*(L as *mut LuaState);
+    let v = if idx > 0 {
+        state.arg(idx)
+    } else {
+        state.stack.len().checked_sub(idx.unsigned_abs() as usize).and_then(|i| state.stack.get(i))
+    };
+    match v.and_then(file_handle) {
+        Some(file) => with_file(&file, |f| f.c_stream()),
+        None => ptr::null_mut(),
+    }
+}
+
+// io.open(filename [, mode])
+fn io_open(state: &mut LuaState) -> i32 {
+    let filename = state.check_string(1);
+    let mode = state.opt_string(2, "r");
+    let Some(m) = OpenMode::parse(&mode) else {
+        state.arg_error(2, "invalid mode");
+    };
+    match state.vfs().open(&filename, m) {
+        Ok(f) => {
+            let obj = new_file_object(state, LuaFile::from_vfs(f));
+            state.push(obj);
+            1
+        }
+        Err(e) => state.file_error(&e, Some(&filename)),
+    }
+}
+
+// io.popen(prog [, mode])
+fn io_popen(state: &mut LuaState) -> i32 {
+    let prog = state.check_string(1);
+    let mode = state.opt_string(2, "r");
+    if mode != "r" && mode != "w" {
+        state.arg_error(2, "invalid mode");
+    }
+    let allowed = state.sandbox().allow_process;
+    if !state.check_sandbox(allowed, "popen") {
+        return 0;
+    }
+    let res = popen(&prog, &mode, &state.l_G.borrow().env);
+    match res {
+        Ok(f) => {
+            let obj = new_file_object(state, f);
+            state.push(obj);
+            1
+        }
+        Err(e) => state.file_error(&e, Some(&prog)),
+    }
+}
+
+// io.tmpfile(): a new temporary file opened for update. On Unix its name
+// is removed at once, so nothing else can reach it and it disappears when
+// closed; elsewhere the file is removed when the state is dropped.
+fn io_tmpfile(state: &mut LuaState) -> i32 {
+    match crate::loslib::make_temp_file() {
+        Ok((path, f)) => {
+            if cfg!(unix) {
+                let _ = std::fs::remove_file(&path);
+            } else {
+                state.l_G.borrow_mut().temp_files.register(path);
+            }
+            let obj = new_file_object(state, LuaFile::from_vfs(Box::new(f)));
+            state.push(obj);
+            1
+        }
+        Err(e) => state.file_error(&e, None),
+    }
+}
+
+// io.type(obj)
+fn io_type(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    let v = match file_handle(&state.to_value(1)) {
+        Some(file) if with_file(&file, |f| f.is_closed()) => LuaValue::Str("closed file".to_string()),
+        Some(_) => LuaValue::Str("file".to_string()),
+        None => LuaValue::Nil,
+    };
+    state.push(v);
+    1
+}
+
+// file:close()
+fn f_close(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    close_file(state, &file)
+}
+
+// io.close([file]): without a file, closes the default output
+fn io_close(state: &mut LuaState) -> i32 {
+    if state.is_none_or_nil(1) {
+        let file = default_handle(state, IoDefault::Output);
+        return close_file(state, &file);
+    }
+    f_close(state)
+}
+
+fn close_file(state: &mut LuaState, file: &FileRef) -> i32 {
+    match with_file(file, |f| f.close()) {
+        Ok(Some(status)) => state.exec_result(Ok(status)),
+        Ok(None) => {
+            state.push(LuaValue::Bool(true));
+            1
+        }
+        Err(e) => state.file_error(&e, None),
+    }
+}
+
+// file:flush()
+fn f_flush(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    match with_file(&file, |f| f.flush()) {
+        Ok(()) => {
+            state.push(state.to_value(1));
+            1
+        }
+        Err(e) => state.file_error(&e, None),
+    }
+}
+
+// io.flush(): flush the default output
+fn io_flush(state: &mut LuaState) -> i32 {
+    let file = default_handle(state, IoDefault::Output);
+    let res = with_file(&file, |f| f.flush());
+    state.file_result(res, None)
+}
+
+/// Read formats given as arguments `first..`, or "l" if there are none
+fn formats_from(state: &mut LuaState, first: i32) -> Vec<LuaValue> {
+    let nargs = state.get_top();
+    if nargs < first {
+        vec![LuaValue::Str("l".to_string())]
+    } else {
+        (first..=nargs).map(|i| state.to_value(i)).collect()
+    }
+}
+
+// file:seek([whence [, offset]]) -> position from the start of the file
+fn f_seek(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    let whence = state.opt_string(2, "cur");
+    let offset = match state.to_value(3) {
+        LuaValue::Nil => 0,
+        LuaValue::Int(i) => i,
+        LuaValue::Float(x) if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 => x as i64,
+        _ => {
+            state.arg_error(3, "not an integer in proper range");
+        }
+    };
+    let pos = match whence.as_str() {
+        "set" if offset < 0 => {
+            return state.file_error(&io::Error::from(io::ErrorKind::InvalidInput), None);
+        }
+        "set" => SeekFrom::Start(offset as u64),
+        "cur" => SeekFrom::Current(offset),
+        "end" => SeekFrom::End(offset),
+        _ => {
+            state.arg_error(2, &format!("invalid option '{}'", whence));
+        }
+    };
+    match with_file(&file, |f| f.seek(pos)) {
+        Ok(p) => {
+            state.push(LuaValue::Int(p as i64));
+            1
+        }
+        Err(e) => state.file_error(&e, None),
+    }
+}
+
+// file:setvbuf(mode [, size]): mode is "no", "full" or "line"
+fn f_setvbuf(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    let mode = match state.check_string(2).as_str() {
+        "no" => BufMode::No,
+        "full" => BufMode::Full,
+        "line" => BufMode::Line,
+        other => {
+            state.arg_error(2, &format!("invalid option '{}'", other));
+        }
+    };
+    let size = state.opt_integer(3, IO_BUFSIZE as i64).max(1) as usize;
+    let res = with_file(&file, |f| f.setvbuf(mode, size));
+    state.file_result(res, None)
+}
+
+// file:read(...): formats "n", "l", "L", "a" (a leading '*' is ignored) or a byte count
+fn f_read(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    let formats = formats_from(state, 2);
+    match read_formats(state, &file, &formats, 2) {
+        Ok(n) => n,
+        Err(e) => state.file_error(&e, None),
+    }
+}
+
+// io.read(...): file:read on the default input
+fn io_read(state: &mut LuaState) -> i32 {
+    let file = default_handle(state, IoDefault::Input);
+    let formats = formats_from(state, 1);
+    match read_formats(state, &file, &formats, 1) {
+        Ok(n) => n,
+        Err(e) => state.file_error(&e, None),
+    }
+}
+
+/// Push one value per format read from `file` (g_read); at end of
+/// file that value is nil and the remaining formats are skipped. Returns
+/// how many values were pushed. `first_arg` is the argument of the first
+/// format, for errors about an invalid one.
+fn read_formats(state: &mut LuaState, file: &FileRef, formats: &[LuaValue], first_arg: i32) -> io::Result<i32> {
+    let mut n = 0;
+    for (i, fmt) in formats.iter().enumerate() {
+        let arg = i as i32 + first_arg;
+        let bytes = |r: io::Result<Option<Vec<u8>>>| r.map(|d| d.map(|d| bytes_to_lua(&d)));
+        let r = match fmt {
+            LuaValue::Int(count) => bytes(with_file(file, |f| f.read_bytes((*count).max(0) as usize))),
+            LuaValue::Float(count) if count.fract() == 0.0 => bytes(with_file(file, |f| f.read_bytes(count.max(0.0) as usize))),
+            LuaValue::Str(s) => match s.trim_start_matches('*').chars().next() {
+                Some('n') => with_file(file, |f| f.read_number()).map(|n| n.map(|n| match n {
+                    Numeral::Int(i) => LuaValue::Int(i),
+                    Numeral::Float(x) => LuaValue::Float(x),
+                })),
+                Some('l') => bytes(with_file(file, |f| f.read_line(false))),
+                Some('L') => bytes(with_file(file, |f| f.read_line(true))),
+                Some('a') => bytes(with_file(file, |f| f.read_all().map(Some))),
+                _ => {
+                    state.arg_error(arg, "invalid format");
+                }
+            },
+            _ => {
+                state.arg_error(arg, "invalid format");
+            }
+        };
+        match r? {
+            Some(v) => state.push(v),
+            None => {
+                // end of file (or no numeral): this result is nil and the rest are skipped
+                state.push(LuaValue::Nil);
+                return Ok(n + 1);
+            }
+        }
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Most formats a lines iterator takes (MAXARGLINE)
+const MAXARGLINE: usize = 250;
+
+/// Iterator of file:lines and io.lines over `file`, reading `formats`
+/// on each call (io_readline). For io.lines, `fname` is the file's name:
+/// the iterator owns the file then, closing it at end of file and before
+/// raising a read error, which names the file.
+fn lines_iterator(file: FileRef, formats: Vec<LuaValue>, fname: Option<String>) -> LuaValue {
+    let f = move |state: &mut LuaState| {
+        if with_file(&file, |f| f.is_closed()) {
+            state.throw(LuaValue::Str("file is already closed".to_string()));
+        }
+        let base = state.stack_size();
+        let r = read_formats(state, &file, &formats, 1);
+        if matches!(r, Ok(_)) && !matches!(state.stack.get(base), None | Some(LuaValue::Nil)) {
+            return r.unwrap_or(0);
+        }
+        state.stack.truncate(base);
+        if fname.is_some() {
+            let _ = with_file(&file, |f| f.close());
+        }
+        if let Err(e) = r {
+            let msg = crate::lauxlib::io_strerror(&e);
+            state.throw(LuaValue::Str(match &fname {
+                Some(name) => format!("{}: {}", name, msg),
+                None => msg,
+            }));
+        }
+        state.push(LuaValue::Nil);
+        1
+    };
+    LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(&f)))
+}
+
+// file:lines(...) -> iterator reading the given formats ("l" by default)
+fn f_lines(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    let formats = formats_from(state, 2);
+    if formats.len() > MAXARGLINE {
+        state.arg_error(MAXARGLINE as i32 + 2, "too many arguments");
+    }
+    state.push(lines_iterator(file, formats, None));
+    1
+}
+
+// io.lines([filename, ...]) -> iterator, nil, nil, file: like file:lines on
+// the opened file, which the iterator closes at end of file or on error.
+// Without a file name it reads the default input and leaves it open.
+fn io_lines(state: &mut LuaState) -> i32 {
+    let formats = formats_from(state, 2);
+    if formats.len() > MAXARGLINE {
+        state.arg_error(MAXARGLINE as i32 + 2, "too many arguments");
+    }
+    if state.is_none_or_nil(1) {
+        let file = default_handle(state, IoDefault::Input);
+        state.push(lines_iterator(file, formats, None));
+        return 1;
+    }
+    let filename = state.check_string(1);
+    let mode = OpenMode::parse("r").expect("valid mode");
+    let f = match state.vfs().open(&filename, mode) {
+        Ok(f) => f,
+        Err(e) => {
+            let msg = format!("cannot open file '{}' ({})", filename, crate::lauxlib::io_strerror(&e));
+            state.throw(LuaValue::Str(msg))
+        }
+    };
+    let obj = new_file_object(state, LuaFile::from_vfs(f));
+    let file = file_handle(&obj).expect("new file object");
+    state.push(lines_iterator(file, formats, Some(filename)));
+    state.push(LuaValue::Nil);
+    state.push(LuaValue::Nil);
+    state.push(obj);
+    4
+}
+
+/// Argument `arg` of a write: a string, or a number written as tostring does
+fn write_arg(state: &mut LuaState, arg: i32) -> Option<String> {
+    match state.to_value(arg) {
+        LuaValue::Str(s) => Some(s),
+        LuaValue::Int(i) => Some(i.to_string()),
+        LuaValue::Float(f) => Some(crate::lobject::luaO_num2str_dot(f)),
+        _ => {
+            state.type_error(arg, "string")
+        }
+    }
+}
+
+// file:write(...)
+fn f_write(state: &mut LuaState) -> i32 {
+    let Some(file) = to_file(state, 1) else { return 0 };
+    for arg in 2..=state.get_top() {
+        let Some(data) = write_arg(state, arg) else { return 0 };
+        if let Err(e) = with_file(&file, |f| f.write(data.as_bytes())) {
+            return state.file_error(&e, None);
+        }
+    }
+    state.push(state.to_value(1));
+    1
+}
+
+// io.write(...) -> default output: file:write on the default output, which
+// is the state's output sink, like print, until io.output changes it
+fn io_write(state: &mut LuaState) -> i32 {
+    let file = default_handle(state, IoDefault::Output);
+    for arg in 1..=state.get_top() {
+        let Some(data) = write_arg(state, arg) else { return 0 };
+        if let Err(e) = with_file(&file, |f| f.write(data.as_bytes())) {
+            return state.file_error(&e, None);
+        }
+    }
+    let obj = default_file(state, IoDefault::Output);
+    state.push(obj);
+    1
+}
+
+const FILE_METHODS: &[(&str, LibFunction)] = &[
+    ("close", f_close),
+    ("flush", f_flush),
+    ("lines", f_lines),
+    ("read", f_read),
+    ("seek", f_seek),
+    ("setvbuf", f_setvbuf),
+    ("write", f_write),
+];
+
+const IO_FUNCS: &[(&str, LibFunction)] = &[
+    ("close", io_close),
+    ("flush", io_flush),
+    ("input", io_input),
+    ("lines", io_lines),
+    ("open", io_open),
+    ("output", io_output),
+    ("popen", io_popen),
+    ("read", io_read),
+    ("tmpfile", io_tmpfile),
+    ("type", io_type),
+    ("write", io_write),
+];
+
+/// Register the io library functions, and io.stdin and io.stdout
+/// (createstdfile), which are also the default files unless the host has
+/// set others
+pub fn open_io_lib(state: &mut LuaState) {
+    for &(name, f) in IO_FUNCS {
+        state.register_lib_function("io", name, f);
+    }
+    for (which, name) in [(IoDefault::Input, "stdin"), (IoDefault::Output, "stdout")] {
+        let obj = std_file(state, which);
+        state.l_G.borrow_mut().io_defaults.slot(which).get_or_insert_with(|| obj.clone());
+        state.register_lib_value("io", name, obj);
+    }
+}
+
+// luaopen_io: entry point used by linit
+pub fn luaopen_io(L: *mut crate::lstate::lua_State) -> i32 {
+    let state = unsafe { &mut *(L as *mut LuaState) };
+    open_io_lib(state);
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::ltable::Table;
+
+    fn mem_file(data: &[u8]) -> LuaFile {
+        LuaFile::from_vfs(Box::new(Cursor::new(data.to_vec())))
+    }
+
+    #[test]
+    fn test_read_formats() {
+        let mut f = mem_file(b"one\ntwo\n\nlast");
+        assert_eq!(f.read_line(false).unwrap().as_deref(), Some(&b"one"[..]));
+        assert_eq!(f.read_line(true).unwrap().as_deref(), Some(&b"two\n"[..]));
+        assert_eq!(f.read_line(false).unwrap().as_deref(), Some(&b""[..]));
+        assert_eq!(f.read_bytes(2).unwrap().as_deref(), Some(&b"la"[..]));
+        assert_eq!(f.read_all().unwrap(), b"st");
+        assert_eq!(f.read_line(false).unwrap(), None);
+        assert_eq!(f.read_bytes(0).unwrap(), None);
+        assert_eq!(f.read_all().unwrap(), b"");
+    }
+
+    #[test]
+    fn test_write_after_read_keeps_position() {
+        let mut f = mem_file(b"abcdef");
+        assert_eq!(f.read_bytes(2).unwrap().as_deref(), Some(&b"ab"[..]));
+        f.write(b"XY").unwrap();
+        assert_eq!(f.read_all().unwrap(), b"ef");
+        assert!(f.close().unwrap().is_none());
+        assert!(f.is_closed() && f.read_all().is_err());
+    }
+
+    #[test]
+    fn test_read_number() {
+        let mut f = mem_file(b"  0x1Fp1 -3.5e2 12abc 0x .5 99");
+        assert_eq!(f.read_number().unwrap(), Some(Numeral::Float(62.0)));
+        assert_eq!(f.read_number().unwrap(), Some(Numeral::Float(-350.0)));
+        assert_eq!(f.read_number().unwrap(), Some(Numeral::Int(12)));
+        // the numeral stops at the first byte that cannot continue it
+        assert_eq!(f.read_bytes(3).unwrap().as_deref(), Some(&b"abc"[..]));
+        assert_eq!(f.read_number().unwrap(), None); // "0x" has no digits
+        assert_eq!(f.read_number().unwrap(), Some(Numeral::Float(0.5)));
+        assert_eq!(f.read_number().unwrap(), Some(Numeral::Int(99)));
+        assert_eq!(f.read_number().unwrap(), None);
+        let mut long = mem_file(&[b'1'; L_MAXLENNUM + 10]);
+        assert_eq!(long.read_number().unwrap(), None);
+    }
+
+    #[test]
+    fn test_seek_and_buffered_writes() {
+        let mut f = mem_file(b"0123456789");
+        assert_eq!(f.read_bytes(3).unwrap().as_deref(), Some(&b"012"[..]));
+        // "cur" is the logical position, not the read-ahead one
+        assert_eq!(f.seek(SeekFrom::Current(0)).unwrap(), 3);
+        assert_eq!(f.seek(SeekFrom::End(-2)).unwrap(), 8);
+        assert_eq!(f.read_all().unwrap(), b"89");
+        let big = (5u64 << 30) + 7; // past 4 GB
+        assert_eq!(f.seek(SeekFrom::Start(big)).unwrap(), big);
+
+        let mut f = mem_file(b"");
+        f.setvbuf(BufMode::Line, 64).unwrap();
+        f.write(b"no newline yet").unwrap();
+        assert_eq!(f.wbuf.len(), 14);
+        f.write(b"\n").unwrap();
+        assert!(f.wbuf.is_empty());
+        f.setvbuf(BufMode::Full, 4).unwrap();
+        f.write(b"ab").unwrap();
+        assert_eq!(f.seek(SeekFrom::Start(0)).unwrap(), 0); // flushes first
+        assert_eq!(f.read_all().unwrap(), b"no newline yet\nab");
+        f.close().unwrap();
+        assert!(f.seek(SeekFrom::Start(0)).is_err());
+    }
+
+    #[test]
+    fn test_lines_iterator_closes_its_file() {
+        let g = Rc::new(RefCell::new(crate::lstate::GlobalState::new()));
+        let mut state = LuaState::new(g);
+        let obj = new_file_object(&mut state, mem_file(b"10 apples\n20 pears\n"));
+        let file = file_handle(&obj).unwrap();
+        let formats = vec![LuaValue::Int(2), LuaValue::Str("l".to_string())];
+        let LuaValue::Function(iter) = lines_iterator(file.clone(), formats, Some("fruit.txt".to_string())) else { unreachable!() };
+        let mut rows = Vec::new();
+        loop {
+            let n = iter(&mut state) as usize;
+            let vals = state.stack.split_off(state.stack_size() - n);
+            if vals[0] == LuaValue::Nil {
+                break;
+            }
+            rows.push(vals);
+        }
+        let s = |x: &str| LuaValue::Str(x.to_string());
+        assert_eq!(rows, vec![vec![s("10"), s(" apples")], vec![s("20"), s(" pears")]]);
+        // end of file closed the file; calling again is an error
+        assert!(with_file(&file, |f| f.is_closed()));
+        assert!(state.pcall(|L| iter(L)).is_err());
+    }
+
+    /// A Write whose bytes the test can still see
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_host_default_files() {
+        let g = Rc::new(RefCell::new(crate::lstate::GlobalState::new()));
+        let mut state = LuaState::new(g);
+        let console = crate::loutput::CaptureSink::default();
+        state.set_output(Box::new(console.clone()));
+        state.push(LuaValue::Nil); // the running function's slot
+        let s = |x: &str| LuaValue::Str(x.to_string());
+
+        // the standard output is the sink, and cannot be closed
+        state.push(s("to the sink"));
+        assert_eq!(io_write(&mut state), 1);
+        assert_eq!(console.take_string(), "to the sink");
+        state.stack.truncate(1);
+        io_close(&mut state);
+        assert_eq!(state.stack[1], LuaValue::Nil);
+        state.stack.truncate(1);
+
+        let out = Shared::default();
+        state.set_default_input(Box::new(Cursor::new(b"first\n42\n".to_vec())));
+        state.set_default_output(Box::new(out.clone()));
+        assert_eq!(io_read(&mut state), 1);
+        assert_eq!(state.pop(), Some(s("first")));
+        state.push(s("n"));
+        assert_eq!(io_read(&mut state), 1);
+        assert_eq!(state.pop(), Some(LuaValue::Int(42)));
+        state.stack.truncate(1);
+
+        state.push(s("to the host"));
+        state.push(LuaValue::Int(7));
+        state.push(LuaValue::Float(1.0));
+        io_write(&mut state);
+        assert_eq!(&out.0.borrow()[..], b"to the host71.0");
+        assert!(console.take().is_empty());
+
+        // io.output() hands back the host's file; closing it makes io.write fail
+        state.stack.truncate(1);
+        assert_eq!(io_output(&mut state), 1);
+        let file = file_handle(&state.pop().unwrap()).unwrap();
+        assert!(Rc::ptr_eq(&file, &default_handle(&mut state, IoDefault::Output)));
+        assert_eq!(io_close(&mut state), 1);
+        state.stack.truncate(1);
+        state.push(s("lost"));
+        assert!(state.pcall(io_write).is_err());
+    }
+
+    #[test]
+    fn test_tmpfile() {
+        let g = Rc::new(RefCell::new(crate::lstate::GlobalState::new()));
+        let mut state = LuaState::new(g);
+        assert_eq!(io_tmpfile(&mut state), 1);
+        let file = file_handle(&state.pop().unwrap()).unwrap();
+        with_file(&file, |f| {
+            f.write(b"scratch").unwrap();
+            f.seek(SeekFrom::Start(0)).unwrap();
+            assert_eq!(f.read_all().unwrap(), b"scratch");
+            assert!(f.close().is_ok());
+        });
+    }
+
+    #[test]
+    fn test_c_stream() {
+        let g = Rc::new(RefCell::new(crate::lstate::GlobalState::new()));
+        let mut state = LuaState::new(g);
+        let obj = new_file_object(&mut state, mem_file(b"data"));
+        state.push(LuaValue::Nil); // the running function's slot
+        state.push(obj.clone());
+        let L = &mut state as *mut LuaState as *mut crate::lauxlib::lua_State;
+        unsafe {
+            let p = tofilestream(L, 1);
+            assert!(!p.is_null() && (*p).f.is_null()); // a Vfs file has no FILE*
+            assert_eq!(tofilestream(L, -1), p);
+            assert!(tofilestream(L, 2).is_null());
+            let closef = (*p).closef.expect("open file");
+            assert_eq!(closef(L), 1);
+            assert!((*p).closef.is_none());
+        }
+        assert!(with_file(&file_handle(&obj).unwrap(), |f| f.is_closed()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_popen() {
+        let mut f = popen("echo hello; exit 2", "r", &EnvOverlay::default()).unwrap();
+        assert_eq!(f.read_line(false).unwrap().as_deref(), Some(&b"hello"[..]));
+        assert_eq!(f.close().unwrap(), Some(ExecStatus::Exit(2)));
+        let mut w = popen("cat > /dev/null", "w", &EnvOverlay::default()).unwrap();
+        let p = w.c_stream();
+        assert!(unsafe { !(*p).f.is_null() });
+        w.write(b"data").unwrap();
+        assert!(w.close().unwrap().unwrap().success());
+    }
+
+    #[test]
+    fn test_file_objects() {
+        let g = Rc::new(RefCell::new(crate::lstate::GlobalState::new()));
+        let mut state = LuaState::new(g);
+        state.push(LuaValue::Nil); // the running function's slot
+        // a table that only looks like a file is not one
+        let forged = Rc::new(RefCell::new(Table::new()));
+        forged.borrow_mut().rawset(&LuaValue::Str("__file".to_string()), LuaValue::Int(1));
+        state.push(LuaValue::Table(forged));
+        assert_eq!(io_type(&mut state), 1);
+        assert_eq!(state.pop(), Some(LuaValue::Nil));
+        assert!(state.pcall(f_close).is_err());
+        state.stack.truncate(1);
+
+        // the file goes away with its object
+        let obj = new_file_object(&mut state, mem_file(b"data"));
+        let weak = Rc::downgrade(&file_handle(&obj).unwrap());
+        drop(obj);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unclosed_pipe_is_waited_for() {
+        let g = Rc::new(RefCell::new(crate::lstate::GlobalState::new()));
+        let mut state = LuaState::new(g);
+        let path = std::env::temp_dir().join(format!("skyla_popen_{}", std::process::id()));
+        let obj = new_file_object(&mut state, popen(&format!("cat > '{}'", path.display()), "w", &EnvOverlay::default()).unwrap());
+        with_file(&file_handle(&obj).unwrap(), |f| f.write(b"piped").unwrap());
+        drop(obj);
+        // dropping the object closed the pipe and waited for the command
+        assert_eq!(std::fs::read(&path).unwrap(), b"piped");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,712 @@
+//! ljson.rs - JSON encoding and decoding of Lua values (skyla.json)
+//
+// Tables whose keys are exactly 1..n encode as arrays, other tables as
+// objects. JSON null decodes to the `json.null` sentinel (a light userdata)
+// so that it survives in tables; `null = "nil"` decodes it to nil instead.
+// Integers and floats keep their subtype across a round trip: 1 encodes as
+// `1` and 1.0 as `1.0`. json.encode encodes a table whose metatable has
+// __serialize (ltm's extension metamethods) as the value it returns.
+
+use std::cell::RefCell;
+use std::fmt::Write as _;
+use std::rc::Rc;
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+use crate::ltm::{call_tm_vm, get_extension_tm, obj_typename, TM_SERIALIZE};
+
+/// Nesting limit for both encoding and decoding
+pub const JSON_MAX_DEPTH: usize = 1000;
+
+/// Integer-keyed tables whose largest key is at most this are always arrays
+pub const JSON_SPARSE_SAFE: i64 = 10;
+
+/// Most slots per element `SparseArrays::Null` fills with null; sparser
+/// tables are still an error, so `{[2^40] = 1}` cannot exhaust memory
+pub const JSON_NULL_FILL_RATIO: i64 = 1000;
+
+/// Most holes an array is encoded with, whatever `sparse_ratio` says; a
+/// table with more is too sparse, so `{[1e9] = 1}` is never filled either
+pub const JSON_MAX_HOLES: i64 = 1 << 16;
+
+/// How to encode a table with integer keys 1..max that has holes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SparseArrays {
+    /// Too-sparse arrays are an error (more than `sparse_ratio` slots per element)
+    Error,
+    /// Fill the holes with null (up to JSON_NULL_FILL_RATIO slots per element
+    /// and JSON_MAX_HOLES holes)
+    Null,
+    /// Encode too-sparse arrays as objects with string keys
+    Object,
+}
+
+/// Options for `LuaValue::to_json` / `LuaValue::from_json`
+#[derive(Debug, Clone)]
+pub struct JsonOptions {
+    pub sparse: SparseArrays,
+    /// Largest allowed max_key / element_count before an array is "too sparse"
+    pub sparse_ratio: i64,
+    /// Sort object keys (stable output for diffs and tests)
+    pub sort_keys: bool,
+    /// Pretty-print with this many spaces per level
+    pub indent: Option<usize>,
+    /// Encode empty tables as `[]` instead of `{}`
+    pub empty_table_as_array: bool,
+    /// Decode null as nil instead of the `json.null` sentinel
+    pub null_as_nil: bool,
+}
+
+impl Default for JsonOptions {
+    fn default() -> Self {
+        JsonOptions {
+            sparse: SparseArrays::Error,
+            sparse_ratio: 2,
+            sort_keys: false,
+            indent: None,
+            empty_table_as_array: false,
+            null_as_nil: false,
+        }
+    }
+}
+
+/// JSON encode/decode error
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonError {
+    /// A table contains itself
+    Cycle,
+    TooDeep,
+    /// Value of this type has no JSON form
+    Unsupported(&'static str),
+    /// Table key that is neither a string nor a number
+    BadKey(&'static str),
+    SparseArray,
+    /// NaN or infinity
+    NonFinite,
+    /// Decoding failed at this byte offset
+    Syntax(usize, String),
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JsonError::Cycle => write!(f, "cannot encode a table that contains itself"),
+            JsonError::TooDeep => write!(f, "nesting too deep"),
+            JsonError::Unsupported(t) => write!(f, "cannot encode a {} value", t),
+            JsonError::BadKey(t) => write!(f, "cannot encode a table key of type {}", t),
+            JsonError::SparseArray => write!(f, "cannot encode an excessively sparse array"),
+            JsonError::NonFinite => write!(f, "cannot encode NaN or infinity"),
+            JsonError::Syntax(pos, msg) => write!(f, "{} at character {}", msg, pos + 1),
+        }
+    }
+}
+
+static JSON_NULL_MARKER: u8 = 0;
+
+/// The `json.null` sentinel
+pub fn json_null() -> LuaValue {
+    LuaValue::Pointer(&JSON_NULL_MARKER as *const u8 as *const ())
+}
+
+fn is_json_null(v: &LuaValue) -> bool {
+    matches!(v, LuaValue::Pointer(p) if *p == &JSON_NULL_MARKER as *const u8 as *const ())
+}
+
+fn write_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_float(out: &mut String, f: f64) -> Result<(), JsonError> {
+    if !f.is_finite() {
+        return Err(JsonError::NonFinite);
+    }
+    let s = f.to_string();
+    out.push_str(&s);
+    if !s.contains(['.', 'e', 'E']) {
+        out.push_str(".0");
+    }
+    Ok(())
+}
+
+/// Key text for an object member
+fn object_key(k: &LuaValue) -> Result<String, JsonError> {
+    match k {
+        LuaValue::Str(s) => Ok(s.clone()),
+        LuaValue::Int(i) => Ok(i.to_string()),
+        LuaValue::Float(f) if f.is_finite() => Ok(f.to_string()),
+        other => Err(JsonError::BadKey(obj_typename(other))),
+    }
+}
+
+/// Replacement value for a table, if any (json.encode: __serialize)
+type SerializeHook<'a> = &'a mut dyn FnMut(&LuaValue) -> Option<LuaValue>;
+
+struct Encoder<'a> {
+    opts: &'a JsonOptions,
+    out: String,
+    /// Tables on the path from the root, for cycle detection
+    path: Vec<*const RefCell<Table>>,
+    serialize: Option<SerializeHook<'a>>,
+}
+
+impl Encoder<'_> {
+    fn newline(&mut self, depth: usize) {
+        if let Some(n) = self.opts.indent {
+            self.out.push('\n');
+            self.out.push_str(&" ".repeat(n * depth));
+        }
+    }
+
+    fn value(&mut self, v: &LuaValue) -> Result<(), JsonError> {
+        if let (LuaValue::Table(_), Some(serialize)) = (v, self.serialize.as_mut()) {
+            if let Some(r) = serialize(v) {
+                return self.plain_value(&r);
+            }
+        }
+        self.plain_value(v)
+    }
+
+    fn plain_value(&mut self, v: &LuaValue) -> Result<(), JsonError> {
+        match v {
+            LuaValue::Nil => self.out.push_str("null"),
+            v if is_json_null(v) => self.out.push_str("null"),
+            LuaValue::Bool(b) => self.out.push_str(if *b { "true" } else { "false" }),
+            LuaValue::Int(i) => { let _ = write!(self.out, "{}", i); }
+            LuaValue::Float(f) => write_float(&mut self.out, *f)?,
+            LuaValue::Str(s) => write_json_string(&mut self.out, s),
+            LuaValue::Table(t) => self.table(t)?,
+            other => return Err(JsonError::Unsupported(obj_typename(other))),
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, t: &Rc<RefCell<Table>>) -> Result<(), JsonError> {
+        let ptr = Rc::as_ptr(t);
+        if self.path.contains(&ptr) {
+            return Err(JsonError::Cycle);
+        }
+        if self.path.len() >= JSON_MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+        self.path.push(ptr);
+        let pairs: Vec<(LuaValue, LuaValue)> = t.borrow().pairs().map(|(k, v)| (k, v.clone())).collect();
+        let r = match self.array_len(&pairs)? {
+            Some(n) => self.array(&pairs, n),
+            None => self.object(pairs),
+        };
+        self.path.pop();
+        r
+    }
+
+    /// Some(n) if the table should encode as an array of n elements
+    fn array_len(&self, pairs: &[(LuaValue, LuaValue)]) -> Result<Option<i64>, JsonError> {
+        if pairs.is_empty() {
+            return Ok(self.opts.empty_table_as_array.then_some(0));
+        }
+        let mut max = 0;
+        for (k, _) in pairs {
+            match k {
+                LuaValue::Int(i) if *i >= 1 => max = max.max(*i),
+                _ => return Ok(None),
+            }
+        }
+        let count = pairs.len() as i64;
+        let holes = max - count;
+        if holes > JSON_MAX_HOLES || (max > JSON_SPARSE_SAFE && max > count.saturating_mul(self.opts.sparse_ratio)) {
+            return match self.opts.sparse {
+                SparseArrays::Object => Ok(None),
+                SparseArrays::Null if holes <= JSON_MAX_HOLES && max <= count.saturating_mul(JSON_NULL_FILL_RATIO) => Ok(Some(max)),
+                _ => Err(JsonError::SparseArray),
+            };
+        }
+        Ok(Some(max))
+    }
+
+    fn array(&mut self, pairs: &[(LuaValue, LuaValue)], n: i64) -> Result<(), JsonError> {
+        let mut items = vec![LuaValue::Nil; n as usize];
+        for (k, v) in pairs {
+            if let LuaValue::Int(i) = k {
+                items[*i as usize - 1] = v.clone();
+            }
+        }
+        let depth = self.path.len();
+        self.out.push('[');
+        for (i, v) in items.iter().enumerate() {
+            if i > 0 {
+                self.out.push(',');
+            }
+            self.newline(depth);
+            self.value(v)?;
+        }
+        if n > 0 {
+            self.newline(depth - 1);
+        }
+        self.out.push(']');
+        Ok(())
+    }
+
+    fn object(&mut self, pairs: Vec<(LuaValue, LuaValue)>) -> Result<(), JsonError> {
+        let mut members = Vec::with_capacity(pairs.len());
+        for (k, v) in pairs {
+            members.push((object_key(&k)?, v));
+        }
+        if self.opts.sort_keys {
+            members.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+        let depth = self.path.len();
+        self.out.push('{');
+        for (i, (k, v)) in members.iter().enumerate() {
+            if i > 0 {
+                self.out.push(',');
+            }
+            self.newline(depth);
+            write_json_string(&mut self.out, k);
+            self.out.push(':');
+            if self.opts.indent.is_some() {
+                self.out.push(' ');
+            }
+            self.value(v)?;
+        }
+        if !members.is_empty() {
+            self.newline(depth - 1);
+        }
+        self.out.push('}');
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize,
+    opts: &'a JsonOptions,
+}
+
+impl Decoder<'_> {
+    fn err<T>(&self, msg: &str) -> Result<T, JsonError> {
+        Err(JsonError::Syntax(self.pos, msg.to_string()))
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.src.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, lit: &str) -> Result<(), JsonError> {
+        if self.src[self.pos..].starts_with(lit.as_bytes()) {
+            self.pos += lit.len();
+            Ok(())
+        } else {
+            self.err("unexpected token")
+        }
+    }
+
+    fn value(&mut self) -> Result<LuaValue, JsonError> {
+        self.skip_ws();
+        match self.src.get(self.pos) {
+            None => self.err("unexpected end of input"),
+            Some(b'n') => {
+                self.expect("null")?;
+                Ok(if self.opts.null_as_nil { LuaValue::Nil } else { json_null() })
+            }
+            Some(b't') => self.expect("true").map(|_| LuaValue::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| LuaValue::Bool(false)),
+            Some(b'"') => self.string().map(LuaValue::Str),
+            Some(b'[') => self.nested(|d| d.array()),
+            Some(b'{') => self.nested(|d| d.object()),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => self.err("unexpected character"),
+        }
+    }
+
+    fn nested(&mut self, f: impl FnOnce(&mut Self) -> Result<LuaValue, JsonError>) -> Result<LuaValue, JsonError> {
+        if self.depth >= JSON_MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+        self.depth += 1;
+        let r = f(self);
+        self.depth -= 1;
+        r
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+        while matches!(self.src.get(self.pos), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+        self.pos - start
+    }
+
+    // -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+    fn number(&mut self) -> Result<LuaValue, JsonError> {
+        let start = self.pos;
+        if self.src.get(self.pos) == Some(&b'-') {
+            self.pos += 1;
+        }
+        let int_start = self.pos;
+        match self.digits() {
+            0 => { self.pos = start; return self.err("invalid number"); }
+            n if n > 1 && self.src[int_start] == b'0' => { self.pos = start; return self.err("leading zero in number"); }
+            _ => {}
+        }
+        let mut is_float = false;
+        if self.src.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            is_float = true;
+            if self.digits() == 0 {
+                self.pos = start;
+                return self.err("invalid number");
+            }
+        }
+        if matches!(self.src.get(self.pos), Some(b'e' | b'E')) {
+            self.pos += 1;
+            is_float = true;
+            if matches!(self.src.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if self.digits() == 0 {
+                self.pos = start;
+                return self.err("invalid number");
+            }
+        }
+        let text = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+        if !is_float {
+            if let Ok(i) = text.parse::<i64>() {
+                return Ok(LuaValue::Int(i));
+            }
+        }
+        match text.parse::<f64>() {
+            Ok(f) => Ok(LuaValue::Float(f)),
+            Err(_) => { self.pos = start; self.err("invalid number") }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        // exactly four hex digits: from_str_radix alone would take a sign
+        let digits = self.src.get(self.pos..self.pos + 4).filter(|d| d.iter().all(u8::is_ascii_hexdigit));
+        match digits.and_then(|d| u32::from_str_radix(std::str::from_utf8(d).ok()?, 16).ok()) {
+            Some(n) => { self.pos += 4; Ok(n) }
+            None => self.err("invalid \\u escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        self.pos += 1; // opening quote
+        let mut out = Vec::new();
+        loop {
+            let Some(&c) = self.src.get(self.pos) else { return self.err("unterminated string") };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&e) = self.src.get(self.pos) else { return self.err("unterminated string") };
+                    self.pos += 1;
+                    match e {
+                        b'"' | b'\\' | b'/' => out.push(e),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'b' => out.push(8),
+                        b'f' => out.push(12),
+                        b'u' => {
+                            let mut cp = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&cp) && self.src[self.pos..].starts_with(b"\\u") {
+                                // a high surrogate pairs only with a low one; any
+                                // other escape is left to decode on its own
+                                let save = self.pos;
+                                self.pos += 2;
+                                match self.hex4()? {
+                                    lo @ 0xdc00..=0xdfff => cp = 0x10000 + ((cp - 0xd800) << 10) + (lo - 0xdc00),
+                                    _ => self.pos = save,
+                                }
+                            }
+                            // unpaired surrogates become U+FFFD
+                            let ch = char::from_u32(cp).unwrap_or('\u{fffd}');
+                            out.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => { self.pos -= 1; return self.err("invalid escape"); }
+                    }
+                }
+                c if c < 0x20 => { self.pos -= 1; return self.err("control character in string"); }
+                c => out.push(c),
+            }
+        }
+        String::from_utf8(out).or_else(|_| self.err("invalid UTF-8 in string"))
+    }
+
+    fn array(&mut self) -> Result<LuaValue, JsonError> {
+        self.pos += 1;
+        let mut t = Table::new();
+        self.skip_ws();
+        if self.src.get(self.pos) == Some(&b']') {
+            self.pos += 1;
+            return Ok(LuaValue::Table(Rc::new(RefCell::new(t))));
+        }
+        let mut i = 1;
+        loop {
+            let v = self.value()?;
+            t.rawset(&LuaValue::Int(i), v);
+            i += 1;
+            self.skip_ws();
+            match self.src.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => { self.pos += 1; break; }
+                _ => return self.err("expected ',' or ']'"),
+            }
+        }
+        Ok(LuaValue::Table(Rc::new(RefCell::new(t))))
+    }
+
+    fn object(&mut self) -> Result<LuaValue, JsonError> {
+        self.pos += 1;
+        let mut t = Table::new();
+        self.skip_ws();
+        if self.src.get(self.pos) == Some(&b'}') {
+            self.pos += 1;
+            return Ok(LuaValue::Table(Rc::new(RefCell::new(t))));
+        }
+        loop {
+            self.skip_ws();
+            if self.src.get(self.pos) != Some(&b'"') {
+                return self.err("expected string key");
+            }
+            let k = self.string()?;
+            self.skip_ws();
+            if self.src.get(self.pos) != Some(&b':') {
+                return self.err("expected ':'");
+            }
+            self.pos += 1;
+            let v = self.value()?;
+            t.rawset(&LuaValue::Str(k), v);
+            self.skip_ws();
+            match self.src.get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => { self.pos += 1; break; }
+                _ => return self.err("expected ',' or '}'"),
+            }
+        }
+        Ok(LuaValue::Table(Rc::new(RefCell::new(t))))
+    }
+}
+
+impl LuaValue {
+    /// Encode as JSON text
+    pub fn to_json(&self, opts: &JsonOptions) -> Result<String, JsonError> {
+        let mut enc = Encoder { opts, out: String::new(), path: Vec::new(), serialize: None };
+        enc.value(self)?;
+        Ok(enc.out)
+    }
+
+    /// Encode as JSON text, each table encoding as `serialize(table)` when
+    /// that returns a value (json.encode passes the __serialize hook)
+    pub fn to_json_with(&self, opts: &JsonOptions, serialize: &mut dyn FnMut(&LuaValue) -> Option<LuaValue>) -> Result<String, JsonError> {
+        let mut enc = Encoder { opts, out: String::new(), path: Vec::new(), serialize: Some(serialize) };
+        enc.value(self)?;
+        Ok(enc.out)
+    }
+
+    /// Decode JSON text
+    pub fn from_json(text: &str, opts: &JsonOptions) -> Result<LuaValue, JsonError> {
+        let mut dec = Decoder { src: text.as_bytes(), pos: 0, depth: 0, opts };
+        let v = dec.value()?;
+        dec.skip_ws();
+        if dec.pos < dec.src.len() {
+            return dec.err("trailing garbage");
+        }
+        Ok(v)
+    }
+}
+
+/// Read the options table at `arg`, if any
+fn json_options(state: &mut LuaState, arg: i32) -> JsonOptions {
+    let mut opts = JsonOptions::default();
+    let LuaValue::Table(t) = state.to_value(arg) else { return opts };
+    let t = t.borrow();
+    let field = |name: &str| t.get(&LuaValue::Str(name.to_string())).cloned();
+    match field("pretty") {
+        Some(LuaValue::Bool(true)) => opts.indent = Some(2),
+        Some(LuaValue::Int(n)) if n > 0 => opts.indent = Some(n as usize),
+        _ => {}
+    }
+    opts.sort_keys = matches!(field("sort_keys"), Some(LuaValue::Bool(true)));
+    opts.empty_table_as_array = matches!(field("empty_array"), Some(LuaValue::Bool(true)));
+    opts.null_as_nil = matches!(field("null"), Some(LuaValue::Str(s)) if s == "nil");
+    match field("sparse") {
+        Some(LuaValue::Str(s)) if s == "null" => opts.sparse = SparseArrays::Null,
+        Some(LuaValue::Str(s)) if s == "object" => opts.sparse = SparseArrays::Object,
+        _ => {}
+    }
+    if let Some(LuaValue::Int(n)) = field("sparse_ratio") {
+        opts.sparse_ratio = n.max(1);
+    }
+    opts
+}
+
+// json.encode(value [, options]) -> string | nil, message
+fn json_lua_encode(state: &mut LuaState) -> i32 {
+    let opts = json_options(state, 2);
+    let v = state.to_value(1);
+    let mut serialize = |t: &LuaValue| get_extension_tm(t, TM_SERIALIZE).and_then(|f| call_tm_vm(state, &f, std::slice::from_ref(t)));
+    match v.to_json_with(&opts, &mut serialize) {
+        Ok(s) => { state.push(LuaValue::Str(s)); 1 }
+        Err(e) => { state.push(LuaValue::Nil); state.push(LuaValue::Str(e.to_string())); 2 }
+    }
+}
+
+// json.decode(string [, options]) -> value | nil, message
+fn json_lua_decode(state: &mut LuaState) -> i32 {
+    let text = state.opt_string(1, "");
+    let opts = json_options(state, 2);
+    match LuaValue::from_json(&text, &opts) {
+        Ok(v) => { state.push(v); 1 }
+        Err(e) => { state.push(LuaValue::Nil); state.push(LuaValue::Str(e.to_string())); 2 }
+    }
+}
+
+const JSON_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("encode", json_lua_encode),
+    ("decode", json_lua_decode),
+];
+
+/// Register the `skyla.json` module
+pub fn open_json_lib(state: &mut LuaState) {
+    for &(name, f) in JSON_FUNCS {
+        state.register_lib_function("skyla.json", name, f);
+    }
+    state.register_lib_value("skyla.json", "null", json_null());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(pairs: Vec<(LuaValue, LuaValue)>) -> LuaValue {
+        let mut t = Table::new();
+        for (k, v) in pairs {
+            t.rawset(&k, v);
+        }
+        LuaValue::Table(Rc::new(RefCell::new(t)))
+    }
+
+    fn s(x: &str) -> LuaValue {
+        LuaValue::Str(x.to_string())
+    }
+
+    #[test]
+    fn test_encode_sorted_pretty_and_sparse() {
+        let opts = JsonOptions { sort_keys: true, ..Default::default() };
+        let v = table(vec![
+            (s("b"), table(vec![(LuaValue::Int(1), LuaValue::Int(1)), (LuaValue::Int(2), LuaValue::Float(2.0))])),
+            (s("a"), s("x\"\n")),
+            (s("n"), json_null()),
+        ]);
+        assert_eq!(v.to_json(&opts).unwrap(), r#"{"a":"x\"\n","b":[1,2.0],"n":null}"#);
+        let pretty = JsonOptions { indent: Some(2), ..opts };
+        assert_eq!(table(vec![(s("k"), table(vec![(LuaValue::Int(1), LuaValue::Bool(true))]))]).to_json(&pretty).unwrap(),
+            "{\n  \"k\": [\n    true\n  ]\n}");
+
+        let sparse = table(vec![(LuaValue::Int(1), LuaValue::Int(1)), (LuaValue::Int(50), LuaValue::Int(2))]);
+        assert_eq!(sparse.to_json(&JsonOptions::default()), Err(JsonError::SparseArray));
+        let as_object = JsonOptions { sparse: SparseArrays::Object, sort_keys: true, ..Default::default() };
+        assert_eq!(sparse.to_json(&as_object).unwrap(), r#"{"1":1,"50":2}"#);
+        let small = table(vec![(LuaValue::Int(3), LuaValue::Int(3))]);
+        assert_eq!(small.to_json(&JsonOptions::default()).unwrap(), "[null,null,3]");
+    }
+
+    #[test]
+    fn test_cycles_are_errors() {
+        let t = Rc::new(RefCell::new(Table::new()));
+        t.borrow_mut().rawset(&s("self"), LuaValue::Table(t.clone()));
+        assert_eq!(LuaValue::Table(t.clone()).to_json(&JsonOptions::default()), Err(JsonError::Cycle));
+        // A table reached twice without a cycle is fine
+        let shared = table(vec![(LuaValue::Int(1), LuaValue::Int(7))]);
+        let v = table(vec![(LuaValue::Int(1), shared.clone()), (LuaValue::Int(2), shared)]);
+        assert_eq!(v.to_json(&JsonOptions::default()).unwrap(), "[[7],[7]]");
+    }
+
+    #[test]
+    fn test_serialize_hook() {
+        // the hook replaces a table once; the tables in its result are hooked again
+        let point = table(vec![(s("x"), LuaValue::Int(1)), (s("y"), LuaValue::Int(2))]);
+        let v = table(vec![(LuaValue::Int(1), point.clone()), (LuaValue::Int(2), s("p"))]);
+        let is_point = |t: &LuaValue| matches!((t, &point), (LuaValue::Table(a), LuaValue::Table(b)) if Rc::ptr_eq(a, b));
+        let mut serialize = |t: &LuaValue| is_point(t).then(|| s("(1, 2)"));
+        assert_eq!(v.to_json_with(&JsonOptions::default(), &mut serialize).unwrap(), r#"["(1, 2)","p"]"#);
+        let mut wrap = |t: &LuaValue| is_point(t).then(|| table(vec![(LuaValue::Int(1), point.clone())]));
+        assert_eq!(point.to_json_with(&JsonOptions::default(), &mut wrap), Err(JsonError::TooDeep));
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        let opts = JsonOptions { sort_keys: true, ..Default::default() };
+        let text = r#"{"a":[1,2.5,-3e2,"é😀"],"b":null,"c":{"d":false}}"#;
+        let v = LuaValue::from_json(text, &opts).unwrap();
+        assert_eq!(v.to_json(&opts).unwrap(), r#"{"a":[1,2.5,-300.0,"é😀"],"b":null,"c":{"d":false}}"#);
+        let nil_opts = JsonOptions { null_as_nil: true, ..Default::default() };
+        assert!(matches!(LuaValue::from_json(" null ", &nil_opts), Ok(LuaValue::Nil)));
+        assert!(matches!(LuaValue::from_json("[1,]", &opts), Err(JsonError::Syntax(3, _))));
+        assert!(matches!(LuaValue::from_json("{} x", &opts), Err(JsonError::Syntax(3, _))));
+    }
+
+    #[test]
+    fn test_sparse_null_fill_is_bounded() {
+        let fill = JsonOptions { sparse: SparseArrays::Null, ..Default::default() };
+        let holes = table(vec![(LuaValue::Int(1), LuaValue::Int(1)), (LuaValue::Int(20), LuaValue::Int(2))]);
+        assert!(holes.to_json(&fill).unwrap().starts_with("[1,null,"));
+        let huge = table(vec![(LuaValue::Int(1 << 40), LuaValue::Int(1))]);
+        assert_eq!(huge.to_json(&fill), Err(JsonError::SparseArray));
+        let lax = JsonOptions { sparse_ratio: i64::MAX, ..fill };
+        assert_eq!(huge.to_json(&lax), Err(JsonError::SparseArray));
+        // past JSON_MAX_HOLES a table is too sparse whatever the ratio
+        let far = table(vec![(LuaValue::Int(1_000_000_000), LuaValue::Int(1))]);
+        for sparse in [SparseArrays::Error, SparseArrays::Null] {
+            assert_eq!(far.to_json(&JsonOptions { sparse, sparse_ratio: i64::MAX, ..Default::default() }), Err(JsonError::SparseArray));
+        }
+        let as_object = JsonOptions { sparse: SparseArrays::Object, sparse_ratio: i64::MAX, ..Default::default() };
+        assert_eq!(far.to_json(&as_object).unwrap(), r#"{"1000000000":1}"#);
+    }
+
+    #[test]
+    fn test_decode_number_grammar() {
+        let opts = JsonOptions::default();
+        assert!(matches!(LuaValue::from_json("0", &opts), Ok(LuaValue::Int(0))));
+        assert!(matches!(LuaValue::from_json("-0.5e+1", &opts), Ok(LuaValue::Float(f)) if f == -5.0));
+        for bad in ["01", "-01", "1.", ".5", "+1", "1e", "1-2", "-"] {
+            assert!(matches!(LuaValue::from_json(bad, &opts), Err(JsonError::Syntax(..))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_decode_surrogates() {
+        let opts = JsonOptions::default();
+        let decode = |text: &str| match LuaValue::from_json(text, &opts) {
+            Ok(LuaValue::Str(s)) => s,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(decode(r#""\ud83d\ude00""#), "😀");
+        // a high surrogate followed by another escape: both decode on their own
+        assert_eq!(decode(r#""\ud83d\u0041""#), "\u{fffd}A");
+        assert_eq!(decode(r#""\ud83d\ud83d\ude00""#), "\u{fffd}😀");
+        assert_eq!(decode(r#""\ude00x""#), "\u{fffd}x");
+        assert_eq!(decode(r#""\u0041""#), "A");
+        for bad in [r#""\u+041""#, r#""\u-041""#, r#""\u 041""#, r#""\u04""#] {
+            assert!(matches!(LuaValue::from_json(bad, &opts), Err(JsonError::Syntax(..))), "{}", bad);
+        }
+    }
+}
@@ -1,14 +1,14 @@
 //! llimits.rs - Lua limits and compile-time constants (Rust translation of llimits.h)
 
-// Integer and floating-point types
-pub type LuaInt = i32;
-pub type LuaNum = f64;
+// Integer and floating-point types (chosen by the build profile, see skylaconf)
+pub type LuaInt = crate::skylaconf::LuaInteger;
+pub type LuaNum = crate::skylaconf::LuaFloat;
 
 // Maximum values for Lua integers and numbers
-pub const LUA_MAXINTEGER: LuaInt = std::i32::MAX;
-pub const LUA_MININTEGER: LuaInt = std::i32::MIN;
-pub const LUA_MAXNUMBER: LuaNum = std::f64::MAX;
-pub const LUA_MINNUMBER: LuaNum = std::f64::MIN;
+pub const LUA_MAXINTEGER: LuaInt = LuaInt::MAX;
+pub const LUA_MININTEGER: LuaInt = LuaInt::MIN;
+pub const LUA_MAXNUMBER: LuaNum = LuaNum::MAX;
+pub const LUA_MINNUMBER: LuaNum = LuaNum::MIN;
 
 // Stack and call limits
 pub const LUAI_MAXSTACK: usize = 1000000;
@@ -0,0 +1,647 @@
+//! lmathlib.rs - The math library (from lmathlib.c): math.random and
+//! math.randomseed, the functions whose results depend on the integer
+//! subtype, the float functions and the 5.2 compatibility functions
+//
+// The generator is xoshiro256**, as in Lua 5.4, with one state per
+// GlobalState. A new state seeds it with luaL_makeseed and the time, or
+// with the fixed seed of a deterministic state (ldeterm), so scripts that
+// never call math.randomseed still replay identically there.
+
+use crate::lobject::{luaO_str2number, LuaValue, Numeral};
+use crate::lstate::LuaState;
+use crate::lvm::luaV_lessthan;
+use crate::skylaconf::{float_to_integer, LuaFloat, LuaInteger};
+
+/// State of the xoshiro256** generator
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RanState {
+    s: [u64; 4],
+}
+
+impl RanState {
+    /// Generator seeded with `n1` and `n2` (setseed): the state is spread
+    /// out and the first values, which still show the seed, are discarded
+    pub fn new(n1: u64, n2: u64) -> Self {
+        let mut r = RanState { s: [n1, 0xff, n2, 0] };
+        for _ in 0..16 {
+            r.next();
+        }
+        r
+    }
+
+    /// Next 64 random bits
+    pub fn next(&mut self) -> u64 {
+        let s = &mut self.s;
+        let state0 = s[0];
+        let state1 = s[1];
+        let state2 = s[2] ^ state0;
+        let state3 = s[3] ^ state1;
+        let res = state1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        s[0] = state0 ^ state3;
+        s[1] = state1 ^ state2;
+        s[2] = state2 ^ (state1 << 17);
+        s[3] = state3.rotate_left(45);
+        res
+    }
+
+    /// A float in [0, 1) from the next value
+    pub fn next_float(&mut self) -> LuaFloat {
+        i2d(self.next())
+    }
+
+    /// A random integer in [0, n] (project): mask the bits above n and
+    /// draw again while the result is still larger than n
+    pub fn project(&mut self, mut ran: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            return ran & n; // n + 1 is a power of 2
+        }
+        let mut lim = n;
+        // the smallest 2^b - 1 not smaller than n
+        lim |= lim >> 1;
+        lim |= lim >> 2;
+        lim |= lim >> 4;
+        lim |= lim >> 8;
+        lim |= lim >> 16;
+        lim |= lim >> 32;
+        loop {
+            ran &= lim;
+            if ran <= n {
+                return ran;
+            }
+            ran = self.next();
+        }
+    }
+}
+
+/// The 53 high bits of `x` as a float in [0, 1)
+fn i2d(x: u64) -> LuaFloat {
+    ((x >> 11) as f64 * 0.5f64.powi(53)) as LuaFloat
+}
+
+/// The seed of a generator nobody seeded (randseed): luaL_makeseed and
+/// the time
+fn randseed() -> (u64, u64) {
+    (crate::lauxlib::makeseed() as u64, crate::lplatform::with_platform(|p| p.time()) as u64)
+}
+
+impl Default for RanState {
+    fn default() -> Self {
+        let (n1, n2) = randseed();
+        RanState::new(n1, n2)
+    }
+}
+
+// math.random([m [, n]])
+fn math_random(state: &mut LuaState) -> i32 {
+    let rv = state.l_G.borrow_mut().rng.next();
+    let (low, up) = match state.get_top() {
+        0 => {
+            state.push(LuaValue::Float(i2d(rv))); // float in [0, 1)
+            return 1;
+        }
+        1 => {
+            let up = state.check_integer(1);
+            if up == 0 {
+                // math.random(0): all 64 bits
+                state.push(LuaValue::Int(rv as LuaInteger));
+                return 1;
+            }
+            (1, up)
+        }
+        2 => (state.check_integer(1), state.check_integer(2)),
+        _ => {
+            state.error("wrong number of arguments");
+        }
+    };
+    if low > up {
+        state.arg_error(1, "interval is empty");
+    }
+    let n = (up as u64).wrapping_sub(low as u64);
+    let r = state.l_G.borrow_mut().rng.project(rv, n);
+    state.push(LuaValue::Int(r.wrapping_add(low as u64) as LuaInteger));
+    1
+}
+
+// math.randomseed([n1 [, n2]]) -> n1, n2. Without arguments the seed is
+// a fresh one (randseed), or the state's fixed seed when it is
+// deterministic.
+fn math_randomseed(state: &mut LuaState) -> i32 {
+    let (n1, n2) = if state.arg(1).is_none() {
+        match state.deterministic_seed() {
+            Some(seed) => (seed, 0),
+            None => randseed(),
+        }
+    } else {
+        (state.check_integer(1) as u64, state.opt_integer(2, 0) as u64)
+    };
+    state.l_G.borrow_mut().rng = RanState::new(n1, n2);
+    state.push(LuaValue::Int(n1 as LuaInteger));
+    state.push(LuaValue::Int(n2 as LuaInteger));
+    2
+}
+
+/// Register the random functions in the `math` library
+pub fn open_math_random(state: &mut LuaState) {
+    state.register_lib_function("math", "random", math_random);
+    state.register_lib_function("math", "randomseed", math_randomseed);
+}
+
+// Functions that keep integers integers
+
+/// Push `d` as an integer if it has an exact one, else as a float
+/// (pushnumint)
+fn push_numint(state: &mut LuaState, d: LuaFloat) -> i32 {
+    match float_to_integer(d) {
+        Some(n) => state.push(LuaValue::Int(n)),
+        None => state.push(LuaValue::Float(d)),
+    }
+    1
+}
+
+// math.floor(x)
+fn math_floor(state: &mut LuaState) -> i32 {
+    if let Some(LuaValue::Int(n)) = state.arg(1) {
+        let n = *n; // already an integer: itself
+        state.push(LuaValue::Int(n));
+        return 1;
+    }
+    let d = state.check_number(1).floor();
+    push_numint(state, d)
+}
+
+// math.ceil(x)
+fn math_ceil(state: &mut LuaState) -> i32 {
+    if let Some(LuaValue::Int(n)) = state.arg(1) {
+        let n = *n;
+        state.push(LuaValue::Int(n));
+        return 1;
+    }
+    let d = state.check_number(1).ceil();
+    push_numint(state, d)
+}
+
+/// Integer remainder with the sign of `a`, as C's `%`; None for a zero
+/// divisor
+pub fn fmod_integer(a: LuaInteger, b: LuaInteger) -> Option<LuaInteger> {
+    match b {
+        0 => None,
+        -1 => Some(0), // avoids the overflow of LuaInteger::MIN % -1
+        _ => Some(a % b),
+    }
+}
+
+// math.fmod(a, b)
+fn math_fmod(state: &mut LuaState) -> i32 {
+    if let (Some(&LuaValue::Int(a)), Some(&LuaValue::Int(b))) = (state.arg(1), state.arg(2)) {
+        match fmod_integer(a, b) {
+            Some(r) => state.push(LuaValue::Int(r)),
+            None => state.arg_error(2, "zero"),
+        }
+        return 1;
+    }
+    let a = state.check_number(1);
+    let b = state.check_number(2);
+    state.push(LuaValue::Float(a % b)); // C fmod
+    1
+}
+
+// math.type(x) -> "integer" | "float" | fail
+fn math_type(state: &mut LuaState) -> i32 {
+    state.check_any(1);
+    let t = match state.arg(1) {
+        Some(LuaValue::Int(_)) => LuaValue::Str("integer".to_string()),
+        Some(LuaValue::Float(_)) => LuaValue::Str("float".to_string()),
+        _ => LuaValue::Nil,
+    };
+    state.push(t);
+    1
+}
+
+// math.abs(x)
+fn math_abs(state: &mut LuaState) -> i32 {
+    if let Some(&LuaValue::Int(n)) = state.arg(1) {
+        state.push(LuaValue::Int(n.wrapping_abs())); // MIN stays MIN, as in C
+        return 1;
+    }
+    let x = state.check_number(1).abs();
+    state.push(LuaValue::Float(x));
+    1
+}
+
+// math.tointeger(x) -> integer | fail
+fn math_tointeger(state: &mut LuaState) -> i32 {
+    let n = match state.arg(1) {
+        Some(&LuaValue::Int(n)) => Some(n),
+        Some(&LuaValue::Float(f)) => float_to_integer(f),
+        Some(LuaValue::Str(s)) => match luaO_str2number(s) {
+            Some(Numeral::Int(n)) => Some(n),
+            Some(Numeral::Float(f)) => float_to_integer(f),
+            None => None,
+        },
+        _ => None,
+    };
+    match n {
+        Some(n) => state.push(LuaValue::Int(n)),
+        None => {
+            state.check_any(1);
+            state.push(LuaValue::Nil);
+        }
+    }
+    1
+}
+
+// math.ult(m, n)
+fn math_ult(state: &mut LuaState) -> i32 {
+    let a = state.check_integer(1);
+    let b = state.check_integer(2);
+    state.push(LuaValue::Bool((a as u64) < (b as u64)));
+    1
+}
+
+/// The greatest argument (the least unless `max`), compared as `<` does;
+/// the winner is returned as it was passed, keeping its subtype
+fn min_max(state: &mut LuaState, max: bool) -> i32 {
+    let n = state.get_top();
+    if n < 1 {
+        state.arg_error(1, "value expected");
+    }
+    state.check_number(1);
+    let mut best = state.to_value(1);
+    for i in 2..=n {
+        state.check_number(i);
+        let v = state.to_value(i);
+        let wins = if max { luaV_lessthan(state, &best, &v) } else { luaV_lessthan(state, &v, &best) };
+        if wins {
+            best = v;
+        }
+    }
+    state.push(best);
+    1
+}
+
+// math.max(x, ...)
+fn math_max(state: &mut LuaState) -> i32 {
+    min_max(state, true)
+}
+
+// math.min(x, ...)
+fn math_min(state: &mut LuaState) -> i32 {
+    min_max(state, false)
+}
+
+// math.modf(x) -> integral part, fractional part (both floats for a float)
+fn math_modf(state: &mut LuaState) -> i32 {
+    if let Some(&LuaValue::Int(n)) = state.arg(1) {
+        state.push(LuaValue::Int(n)); // its own integral part
+        state.push(LuaValue::Float(0.0));
+        return 2;
+    }
+    let x = state.check_number(1);
+    let ip = if x < 0.0 { x.ceil() } else { x.floor() }; // rounds toward zero
+    state.push(LuaValue::Float(ip));
+    state.push(LuaValue::Float(if x == ip { 0.0 } else { x - ip })); // inf has no fraction
+    2
+}
+
+const MATH_INT_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("floor", math_floor),
+    ("ceil", math_ceil),
+    ("fmod", math_fmod),
+    ("type", math_type),
+    ("abs", math_abs),
+    ("tointeger", math_tointeger),
+    ("ult", math_ult),
+    ("max", math_max),
+    ("min", math_min),
+    ("modf", math_modf),
+];
+
+/// Register the functions that keep or make integers, with maxinteger and
+/// mininteger, in the `math` library
+pub fn open_math_int(state: &mut LuaState) {
+    for &(name, f) in MATH_INT_FUNCS {
+        state.register_lib_function("math", name, f);
+    }
+    state.register_lib_value("math", "maxinteger", LuaValue::Int(LuaInteger::MAX));
+    state.register_lib_value("math", "mininteger", LuaValue::Int(LuaInteger::MIN));
+}
+
+// Float functions
+
+fn push_float(state: &mut LuaState, x: f64) -> i32 {
+    state.push(LuaValue::Float(x as LuaFloat));
+    1
+}
+
+// math.sqrt(x)
+fn math_sqrt(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.sqrt())
+}
+
+// math.sin(x)
+fn math_sin(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.sin())
+}
+
+// math.cos(x)
+fn math_cos(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.cos())
+}
+
+// math.tan(x)
+fn math_tan(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.tan())
+}
+
+// math.asin(x)
+fn math_asin(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.asin())
+}
+
+// math.acos(x)
+fn math_acos(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.acos())
+}
+
+// math.atan(y [, x])
+fn math_atan(state: &mut LuaState) -> i32 {
+    let y = state.check_number(1) as f64;
+    let x = state.opt_number(2, 1.0) as f64;
+    push_float(state, y.atan2(x))
+}
+
+// math.exp(x)
+fn math_exp(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.exp())
+}
+
+// math.log(x [, base])
+fn math_log(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    let r = if state.is_none_or_nil(2) {
+        x.ln()
+    } else {
+        match state.check_number(2) as f64 {
+            b if b == 2.0 => x.log2(),
+            b if b == 10.0 => x.log10(),
+            b => x.ln() / b.ln(),
+        }
+    };
+    push_float(state, r)
+}
+
+// math.deg(x)
+fn math_deg(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.to_degrees())
+}
+
+// math.rad(x)
+fn math_rad(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.to_radians())
+}
+
+const MATH_FLOAT_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("sqrt", math_sqrt),
+    ("sin", math_sin),
+    ("cos", math_cos),
+    ("tan", math_tan),
+    ("asin", math_asin),
+    ("acos", math_acos),
+    ("atan", math_atan),
+    ("exp", math_exp),
+    ("log", math_log),
+    ("deg", math_deg),
+    ("rad", math_rad),
+];
+
+/// Register the float functions, with pi and huge, in the `math` library
+pub fn open_math_float(state: &mut LuaState) {
+    for &(name, f) in MATH_FLOAT_FUNCS {
+        state.register_lib_function("math", name, f);
+    }
+    state.register_lib_value("math", "pi", LuaValue::Float(std::f64::consts::PI as LuaFloat));
+    state.register_lib_value("math", "huge", LuaValue::Float(LuaFloat::INFINITY));
+}
+
+// Functions deprecated in 5.3 (COMPAT_MATHLIB)
+
+/// `x` as m * 2^e with 0.5 <= |m| < 1 (zero, infinities and NaN give
+/// themselves and 0)
+pub fn frexp(x: f64) -> (f64, i32) {
+    if x == 0.0 || !x.is_finite() {
+        return (x, 0);
+    }
+    let bits = x.to_bits();
+    let exp = ((bits >> 52) & 0x7ff) as i32;
+    if exp == 0 {
+        // subnormal: scale into the normal range first
+        let (m, e) = frexp(x * 2f64.powi(64));
+        return (m, e - 64);
+    }
+    (f64::from_bits((bits & !(0x7ff << 52)) | (1022 << 52)), exp - 1022)
+}
+
+/// x * 2^e, in steps so that no intermediate power of 2 overflows
+pub fn ldexp(mut x: f64, e: LuaInteger) -> f64 {
+    let mut e = e.clamp(-2200, 2200) as i32;
+    while e > 1000 {
+        x *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        x *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    x * 2f64.powi(e)
+}
+
+// math.atan2(y [, x])
+fn math_atan2(state: &mut LuaState) -> i32 {
+    let y = state.check_number(1) as f64;
+    let x = state.opt_number(2, 1.0) as f64;
+    push_float(state, y.atan2(x))
+}
+
+// math.cosh(x)
+fn math_cosh(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.cosh())
+}
+
+// math.sinh(x)
+fn math_sinh(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.sinh())
+}
+
+// math.tanh(x)
+fn math_tanh(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.tanh())
+}
+
+// math.pow(x, y)
+fn math_pow(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    let y = state.check_number(2) as f64;
+    push_float(state, x.powf(y))
+}
+
+// math.frexp(x) -> m, e
+fn math_frexp(state: &mut LuaState) -> i32 {
+    let (m, e) = frexp(state.check_number(1) as f64);
+    push_float(state, m);
+    state.push(LuaValue::Int(e as LuaInteger));
+    2
+}
+
+// math.ldexp(m, e)
+fn math_ldexp(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    let e = state.check_integer(2);
+    push_float(state, ldexp(x, e))
+}
+
+// math.log10(x)
+fn math_log10(state: &mut LuaState) -> i32 {
+    let x = state.check_number(1) as f64;
+    push_float(state, x.log10())
+}
+
+const MATH_COMPAT_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("atan2", math_atan2),
+    ("cosh", math_cosh),
+    ("sinh", math_sinh),
+    ("tanh", math_tanh),
+    ("pow", math_pow),
+    ("frexp", math_frexp),
+    ("ldexp", math_ldexp),
+    ("log10", math_log10),
+];
+
+/// Register the functions 5.3 dropped from the `math` library
+pub fn open_math_compat(state: &mut LuaState) {
+    for &(name, f) in MATH_COMPAT_FUNCS {
+        state.register_lib_function("math", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lauxlib::call_lib;
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let (mut a, mut b) = (RanState::new(42, 0), RanState::new(42, 0));
+        let xs: Vec<u64> = (0..8).map(|_| a.next()).collect();
+        let ys: Vec<u64> = (0..8).map(|_| b.next()).collect();
+        assert_eq!(xs, ys);
+        assert_ne!(RanState::new(43, 0).next(), xs[0]);
+        let f = a.next_float();
+        assert!((0.0..1.0).contains(&f));
+    }
+
+    #[test]
+    fn test_project_stays_in_range() {
+        let mut r = RanState::new(7, 7);
+        for n in [0u64, 1, 5, 6, 7, 100, u64::MAX] {
+            for _ in 0..50 {
+                let ran = r.next();
+                assert!(r.project(ran, n) <= n);
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_ranges() {
+        use crate::lstate::GlobalState;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let seed = call_lib(&mut state, math_randomseed, vec![LuaValue::Int(-5), LuaValue::Int(6)]);
+        assert_eq!(seed, vec![LuaValue::Int(-5), LuaValue::Int(6)]);
+        let first = call_lib(&mut state, math_random, vec![LuaValue::Int(0)]);
+        call_lib(&mut state, math_randomseed, vec![LuaValue::Int(-5), LuaValue::Int(6)]);
+        assert_eq!(call_lib(&mut state, math_random, vec![LuaValue::Int(0)]), first);
+        for _ in 0..100 {
+            let [LuaValue::Float(f)] = call_lib(&mut state, math_random, vec![])[..] else { panic!("not a float") };
+            assert!((0.0..1.0).contains(&f));
+            let [LuaValue::Int(i)] = call_lib(&mut state, math_random, vec![LuaValue::Int(6)])[..] else { panic!("not an integer") };
+            assert!((1..=6).contains(&i));
+            let [LuaValue::Int(i)] = call_lib(&mut state, math_random, vec![LuaValue::Int(-3), LuaValue::Int(-1)])[..] else { panic!("not an integer") };
+            assert!((-3..=-1).contains(&i));
+        }
+        // the whole integer range
+        let full = vec![LuaValue::Int(LuaInteger::MIN), LuaValue::Int(LuaInteger::MAX)];
+        assert!(matches!(call_lib(&mut state, math_random, full)[..], [LuaValue::Int(_)]));
+        assert_eq!(call_lib(&mut state, math_random, vec![LuaValue::Int(7), LuaValue::Int(7)]), vec![LuaValue::Int(7)]);
+    }
+
+    #[test]
+    fn test_integer_results() {
+        use crate::lstate::GlobalState;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        assert_eq!(call_lib(&mut state, math_floor, vec![LuaValue::Float(-3.5)]), vec![LuaValue::Int(-4)]);
+        assert_eq!(call_lib(&mut state, math_ceil, vec![LuaValue::Float(3.2)]), vec![LuaValue::Int(4)]);
+        assert_eq!(call_lib(&mut state, math_floor, vec![LuaValue::Int(LuaInteger::MAX)]), vec![LuaValue::Int(LuaInteger::MAX)]);
+        // no integer holds it: stays a float
+        assert_eq!(call_lib(&mut state, math_floor, vec![LuaValue::Float(1e300)]), vec![LuaValue::Float(1e300)]);
+        assert_eq!(call_lib(&mut state, math_fmod, vec![LuaValue::Int(-7), LuaValue::Int(3)]), vec![LuaValue::Int(-1)]);
+        assert_eq!(call_lib(&mut state, math_fmod, vec![LuaValue::Float(-7.0), LuaValue::Int(3)]), vec![LuaValue::Float(-1.0)]);
+        assert_eq!(fmod_integer(LuaInteger::MIN, -1), Some(0));
+        assert_eq!(fmod_integer(7, -3), Some(1));
+        assert_eq!(fmod_integer(1, 0), None);
+        assert_eq!(call_lib(&mut state, math_type, vec![LuaValue::Int(1)]), vec![LuaValue::Str("integer".to_string())]);
+        assert_eq!(call_lib(&mut state, math_type, vec![LuaValue::Float(1.0)]), vec![LuaValue::Str("float".to_string())]);
+        assert_eq!(call_lib(&mut state, math_type, vec![LuaValue::Str("1".to_string())]), vec![LuaValue::Nil]);
+    }
+
+    #[test]
+    fn test_frexp_ldexp() {
+        assert_eq!(frexp(1.0), (0.5, 1));
+        assert_eq!(frexp(-12.0), (-0.75, 4));
+        assert_eq!(frexp(0.0), (0.0, 0));
+        let tiny = f64::from_bits(1); // smallest subnormal
+        assert_eq!(frexp(tiny), (0.5, -1073));
+        assert_eq!(ldexp(0.5, -1073), tiny);
+        assert_eq!(ldexp(0.75, 4), 12.0);
+        assert_eq!(ldexp(1.0, 5000), f64::INFINITY);
+        assert_eq!(ldexp(1.0, LuaInteger::MIN), 0.0);
+    }
+
+    #[test]
+    fn test_min_max_and_conversions() {
+        use crate::lstate::GlobalState;
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let (i, f) = (LuaValue::Int, LuaValue::Float);
+        // the winner keeps its subtype
+        assert_eq!(call_lib(&mut state, math_max, vec![i(1), f(2.5), i(3)]), vec![i(3)]);
+        assert_eq!(call_lib(&mut state, math_min, vec![i(1), f(0.5), i(3)]), vec![f(0.5)]);
+        assert!(state.pcall(|s| call_lib(s, math_max, vec![])).is_err());
+        assert_eq!(call_lib(&mut state, math_abs, vec![i(-3)]), vec![i(3)]);
+        assert_eq!(call_lib(&mut state, math_abs, vec![i(LuaInteger::MIN)]), vec![i(LuaInteger::MIN)]);
+        assert_eq!(call_lib(&mut state, math_tointeger, vec![f(3.0)]), vec![i(3)]);
+        assert_eq!(call_lib(&mut state, math_tointeger, vec![f(3.5)]), vec![LuaValue::Nil]);
+        assert_eq!(call_lib(&mut state, math_tointeger, vec![LuaValue::Str("8".to_string())]), vec![i(8)]);
+        assert_eq!(call_lib(&mut state, math_ult, vec![i(1), i(-1)]), vec![LuaValue::Bool(true)]);
+        assert_eq!(call_lib(&mut state, math_modf, vec![f(-3.5)]), vec![f(-3.0), f(-0.5)]);
+        assert_eq!(call_lib(&mut state, math_modf, vec![i(7)]), vec![i(7), f(0.0)]);
+        assert_eq!(call_lib(&mut state, math_modf, vec![f(f64::INFINITY)]), vec![f(f64::INFINITY), f(0.0)]);
+        assert_eq!(call_lib(&mut state, math_log, vec![i(8), i(2)]), vec![f(3.0)]);
+        assert_eq!(call_lib(&mut state, math_log, vec![i(1000), i(10)]), vec![f(3.0)]);
+    }
+}
@@ -6,6 +6,7 @@ use std::alloc::{alloc, dealloc, realloc, Layout};
 use std::ptr;
 use std::alloc::{System, GlobalAlloc};
 use crate::lgc::{luaC_fullgc, luaC_step};
+use crate::ldo::{LuaStatus, LuaThrow};
 
 /// Minimum size for arrays during parsing
 pub const MINSIZEARRAY: usize = 4;
@@ -18,13 +19,81 @@ pub fn luaM_toobig(L: &mut lua_State) -> ! {
     panic!("memory allocation error: block too big");
 }
 
+/// Raise a memory error (LUA_ERRMEM) after an allocation failed even
+/// after an emergency collection
+pub fn luaM_error(_L: &mut lua_State) -> ! {
+    std::panic::panic_any(LuaThrow(LuaStatus::MemoryError))
+}
+
+/// Consult the ltests memory controller before a real allocation that
+/// grows a block from `osize` to `nsize` (count-, size- and fail-next modes).
+/// Without `internal_tests` this always allows the allocation.
+#[inline(always)]
+fn memcontrol_allows(osize: usize, nsize: usize) -> bool {
+    #[cfg(feature = "internal_tests")]
+    {
+        crate::ltests::MEM_CONTROL.check_alloc(osize, nsize)
+    }
+    #[cfg(not(feature = "internal_tests"))]
+    {
+        let _ = (osize, nsize);
+        true
+    }
+}
+
+/// Record a successful (re)allocation in the ltests memory controller
+#[inline(always)]
+fn memcontrol_account(osize: usize, nsize: usize) {
+    #[cfg(feature = "internal_tests")]
+    crate::ltests::MEM_CONTROL.account(osize, nsize);
+    #[cfg(not(feature = "internal_tests"))]
+    let _ = (osize, nsize);
+}
+
+/// The raw allocator (l_alloc in lauxlib.c): every real allocation goes
+/// through here so failure injection sees all allocation sites.
+/// Returns null on failure when `nsize > 0`; freeing never fails.
+unsafe fn l_alloc(block: *mut u8, osize: usize, nsize: usize, align: usize) -> *mut u8 {
+    if nsize > osize && !memcontrol_allows(osize, nsize) {
+        return ptr::null_mut(); // injected failure
+    }
+    let newblock = if nsize == 0 {
+        if !block.is_null() {
+            dealloc(block, Layout::from_size_align_unchecked(osize, align));
+        }
+        ptr::null_mut()
+    } else if block.is_null() {
+        alloc(Layout::from_size_align_unchecked(nsize, align))
+    } else {
+        realloc(block, Layout::from_size_align_unchecked(osize, align), nsize)
+    };
+    if !newblock.is_null() || nsize == 0 {
+        memcontrol_account(if block.is_null() { 0 } else { osize }, nsize);
+    }
+    newblock
+}
+
+/// Allocate with one emergency full GC and retry; raises LUA_ERRMEM if
+/// the retry also fails
+unsafe fn l_alloc_or_error(L: &mut lua_State, block: *mut u8, osize: usize, nsize: usize, align: usize) -> *mut u8 {
+    let mut newblock = l_alloc(block, osize, nsize, align);
+    if newblock.is_null() && nsize > 0 {
+        // Try full GC and retry allocation once
+        luaC_fullgc(L, true);
+        newblock = l_alloc(block, osize, nsize, align);
+        if newblock.is_null() {
+            luaM_error(L);
+        }
+    }
+    newblock
+}
+
 /// Free memory
 pub unsafe fn luaM_free(L: &mut lua_State, block: *mut u8, osize: usize) {
     let g = L.global();
     debug_assert!((osize == 0) == (block.is_null()));
     if !block.is_null() {
-        let layout = Layout::from_size_align_unchecked(osize, LUAI_MAXALIGN);
-        dealloc(block, layout);
+        l_alloc(block, osize, 0, LUAI_MAXALIGN);
         g.GCdebt += osize as l_mem;
     }
 }
@@ -34,17 +103,8 @@ pub unsafe fn luaM_malloc(L: &mut lua_State, size: usize) -> *mut u8 {
     if size == 0 {
         ptr::null_mut()
     } else {
+        let newblock = l_alloc_or_error(L, ptr::null_mut(), 0, size, LUAI_MAXALIGN);
         let g = L.global();
-        let layout = Layout::from_size_align_unchecked(size, LUAI_MAXALIGN);
-        let mut newblock = alloc(layout);
-        if newblock.is_null() {
-            // Try full GC and retry allocation once
-            luaC_fullgc(L, false);
-            newblock = alloc(layout);
-            if newblock.is_null() {
-                luaM_toobig(L);
-            }
-        }
         g.GCdebt -= size as l_mem;
         // Trigger incremental GC step if debt is high
         if g.GCdebt < -GCDEBT_THRESHOLD {
@@ -56,37 +116,12 @@ pub unsafe fn luaM_malloc(L: &mut lua_State, size: usize) -> *mut u8 {
 
 /// Reallocate memory (generic allocation routine)
 pub unsafe fn luaM_realloc(L: &mut lua_State, block: *mut u8, osize: usize, nsize: usize) -> *mut u8 {
-    let g = L.global();
     debug_assert!((osize == 0) == (block.is_null()));
-    let mut newblock = if block.is_null() {
-        if nsize == 0 {
-            ptr::null_mut()
-        } else {
-            let layout = Layout::from_size_align_unchecked(nsize, LUAI_MAXALIGN);
-            alloc(layout)
-        }
-    } else if nsize == 0 {
-        let layout = Layout::from_size_align_unchecked(osize, LUAI_MAXALIGN);
-        dealloc(block, layout);
-        ptr::null_mut()
-    } else {
-        let old_layout = Layout::from_size_align_unchecked(osize, LUAI_MAXALIGN);
-        realloc(block, old_layout, nsize)
-    };
-    if newblock.is_null() && nsize > 0 {
-        // Try full GC and retry allocation once
-        luaC_fullgc(L, false);
-        let layout = Layout::from_size_align_unchecked(nsize, LUAI_MAXALIGN);
-        newblock = alloc(layout);
-        if newblock.is_null() {
-            luaM_toobig(L);
-        }
-    }
-    if !newblock.is_null() {
-        g.GCdebt -= nsize as l_mem - osize as l_mem;
-        if g.GCdebt < -GCDEBT_THRESHOLD {
-            luaC_step(L);
-        }
+    let newblock = l_alloc_or_error(L, block, osize, nsize, LUAI_MAXALIGN);
+    let g = L.global();
+    g.GCdebt -= nsize as l_mem - osize as l_mem;
+    if !newblock.is_null() && g.GCdebt < -GCDEBT_THRESHOLD {
+        luaC_step(L);
     }
     newblock
 }
@@ -134,17 +169,8 @@ pub unsafe fn luaM_shrinkvector<T>(L: &mut lua_State, block: *mut T, size: &mut
 
 /// Reallocate memory with alignment
 pub unsafe fn luaM_realloc_aligned(L: &mut lua_State, block: *mut u8, osize: usize, nsize: usize, align: usize) -> *mut u8 {
+    let ptr = l_alloc_or_error(L, block, osize, nsize, align);
     let g = L.global();
-    let old_layout = Layout::from_size_align_unchecked(osize, align);
-    let mut ptr = realloc(block, old_layout, nsize);
-    if ptr.is_null() && nsize > 0 {
-        luaC_fullgc(L, false);
-        let layout = Layout::from_size_align_unchecked(nsize, align);
-        ptr = alloc(layout);
-        if ptr.is_null() {
-            luaM_toobig(L);
-        }
-    }
     g.GCdebt -= nsize as l_mem - osize as l_mem;
     if g.GCdebt < -GCDEBT_THRESHOLD {
         luaC_step(L);
@@ -154,19 +180,11 @@ pub unsafe fn luaM_realloc_aligned(L: &mut lua_State, block: *mut u8, osize: usi
 
 /// Allocate zero-initialized memory (like calloc)
 pub unsafe fn luaM_calloc(L: &mut lua_State, count: usize, size: usize) -> *mut u8 {
-    let total = count.checked_mul(size).expect("overflow in calloc");
-    let g = L.global();
-    let layout = Layout::from_size_align_unchecked(total, LUAI_MAXALIGN);
-    let mut ptr = alloc(layout);
-    if ptr.is_null() {
-        luaC_fullgc(L, false);
-        ptr = alloc(layout);
-        if ptr.is_null() {
-            luaM_toobig(L);
-        }
-    }
+    let total = count.checked_mul(size).unwrap_or_else(|| luaM_toobig(L));
+    let ptr = l_alloc_or_error(L, ptr::null_mut(), 0, total, LUAI_MAXALIGN);
     if !ptr.is_null() {
         std::ptr::write_bytes(ptr, 0, total);
+        let g = L.global();
         g.GCdebt -= total as l_mem;
         if g.GCdebt < -GCDEBT_THRESHOLD {
             luaC_step(L);
@@ -214,3 +232,86 @@ pub unsafe fn luaM_free_obj<T>(L: &mut lua_State, obj: *mut T) {
 /// Use a more permissive global allocator (System allocator)
 #[global_allocator]
 static GLOBAL: System = System;
+
+#[cfg(all(test, feature = "internal_tests"))]
+mod tests {
+    use super::*;
+    use crate::ldo::luaD_statusof;
+    use crate::ltests::{MEM_CONTROL, MEM_CONTROL_LOCK};
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::Ordering;
+    use std::sync::MutexGuard;
+
+    /// The memory controller for one test; its limits are lifted when dropped
+    struct Controlled(#[allow(dead_code)] MutexGuard<'static, ()>);
+
+    impl Drop for Controlled {
+        fn drop(&mut self) {
+            MEM_CONTROL.set_count_limit(None);
+            MEM_CONTROL.set_mem_limit(None);
+            MEM_CONTROL.set_fail_next(false);
+        }
+    }
+
+    fn controlled() -> Controlled {
+        Controlled(MEM_CONTROL_LOCK.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Status `f` ends with, as a protected call reports it
+    fn status_of(f: impl FnOnce()) -> LuaStatus {
+        match catch_unwind(AssertUnwindSafe(f)) {
+            Ok(()) => LuaStatus::Ok,
+            Err(e) => luaD_statusof(&*e),
+        }
+    }
+
+    #[test]
+    fn test_every_allocation_site_raises_errmem() {
+        let _mc = controlled();
+        let mut L = lua_State::default();
+        let blocks = MEM_CONTROL.num_blocks.load(Ordering::SeqCst);
+        let block = unsafe { luaM_malloc(&mut L, 32) };
+        MEM_CONTROL.set_count_limit(Some(0));
+        let sites: [&dyn Fn(&mut lua_State); 5] = [
+            &|L| unsafe { luaM_malloc(L, 32); },
+            &|L| unsafe { luaM_calloc(L, 4, 8); },
+            &|L| unsafe { luaM_realloc(L, ptr::null_mut(), 0, 32); },
+            &|L| unsafe { luaM_realloc_aligned(L, ptr::null_mut(), 0, 32, 16); },
+            &|L| unsafe { luaM_realloc(L, block, 32, 64); },
+        ];
+        for site in sites {
+            assert_eq!(status_of(|| site(&mut L)), LuaStatus::MemoryError);
+        }
+        // the block a failed realloc was given is still whole, and freeing
+        // never fails
+        unsafe {
+            let shrunk = luaM_realloc(&mut L, block, 32, 16);
+            luaM_free(&mut L, shrunk, 16);
+        }
+        assert_eq!(MEM_CONTROL.num_blocks.load(Ordering::SeqCst), blocks);
+    }
+
+    #[test]
+    fn test_emergency_collection_retries_once() {
+        let _mc = controlled();
+        let mut L = lua_State::default();
+        // one failure: the retry after the full collection succeeds
+        MEM_CONTROL.set_fail_next(true);
+        let block = unsafe { luaM_malloc(&mut L, 32) };
+        assert!(!block.is_null());
+        unsafe { luaM_free(&mut L, block, 32) };
+    }
+
+    #[test]
+    fn test_memory_limit_raises_errmem() {
+        let _mc = controlled();
+        let mut L = lua_State::default();
+        MEM_CONTROL.set_mem_limit(Some(MEM_CONTROL.total.load(Ordering::SeqCst) + 64));
+        let block = unsafe { luaM_malloc(&mut L, 64) };
+        assert_eq!(status_of(|| unsafe { luaM_malloc(&mut L, 1); }), LuaStatus::MemoryError);
+        unsafe { luaM_free(&mut L, block, 64) };
+        // the freed bytes can be had again
+        let block = unsafe { luaM_malloc(&mut L, 64) };
+        unsafe { luaM_free(&mut L, block, 64) };
+    }
+}
@@ -0,0 +1,164 @@
+//! lmodule.rs - Declaring Lua modules from Rust (ModuleBuilder)
+//
+// A crate exposing an API to Lua describes its module once:
+//
+//     ModuleBuilder::new("vec3")
+//         .func("new", vec3_new)
+//         .constant("EPS", 1e-9)
+//         .enumeration("Axis", &[("X", 0), ("Y", 1), ("Z", 2)])
+//         .global("vec3")
+//         .build(&mut state);
+//
+// build stores the table in package.loaded under the module name (dotted
+// names such as "game.vec3" are single keys there, as require expects) and
+// fills it in declaration order. Enumerations are frozen subtables from the
+// variant names to their values.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::lauxlib::LibFunction;
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+use crate::skylaconf::{LuaFloat, LuaInteger};
+
+/// A Rust value that can be a module constant
+pub trait ModuleConstant {
+    fn into_lua(self) -> LuaValue;
+}
+
+impl ModuleConstant for LuaValue {
+    fn into_lua(self) -> LuaValue {
+        self
+    }
+}
+
+impl ModuleConstant for LuaInteger {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::Int(self)
+    }
+}
+
+impl ModuleConstant for LuaFloat {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::Float(self)
+    }
+}
+
+impl ModuleConstant for bool {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::Bool(self)
+    }
+}
+
+impl ModuleConstant for &str {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::Str(self.to_string())
+    }
+}
+
+impl ModuleConstant for String {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::Str(self)
+    }
+}
+
+enum Field {
+    Func(LibFunction),
+    Value(LuaValue),
+    Enum(Vec<(String, LuaInteger)>),
+}
+
+/// Description of a module, installed by `build`
+pub struct ModuleBuilder {
+    name: String,
+    fields: Vec<(String, Field)>,
+    global: Option<String>,
+}
+
+impl ModuleBuilder {
+    pub fn new(name: &str) -> Self {
+        ModuleBuilder { name: name.to_string(), fields: Vec::new(), global: None }
+    }
+
+    pub fn func(mut self, name: &str, f: LibFunction) -> Self {
+        self.fields.push((name.to_string(), Field::Func(f)));
+        self
+    }
+
+    pub fn constant(mut self, name: &str, v: impl ModuleConstant) -> Self {
+        self.fields.push((name.to_string(), Field::Value(v.into_lua())));
+        self
+    }
+
+    /// A frozen subtable `name` of integer constants
+    pub fn enumeration(mut self, name: &str, variants: &[(&str, LuaInteger)]) -> Self {
+        let variants = variants.iter().map(|&(v, n)| (v.to_string(), n)).collect();
+        self.fields.push((name.to_string(), Field::Enum(variants)));
+        self
+    }
+
+    /// Also store the module in global `name`
+    pub fn global(mut self, name: &str) -> Self {
+        self.global = Some(name.to_string());
+        self
+    }
+
+    /// Install the module and return its table; building a module that is
+    /// already loaded adds the fields to the existing table
+    pub fn build(self, state: &mut LuaState) -> Rc<RefCell<Table>> {
+        let lib = state.lib_table(&self.name);
+        for (name, field) in self.fields {
+            let v = match field {
+                Field::Func(f) => {
+                    state.set_funcs(&lib, &[(name.as_str(), f)]);
+                    continue;
+                }
+                Field::Value(v) => v,
+                Field::Enum(variants) => {
+                    let mut t = Table::with_capacity(0, variants.len());
+                    for (v, n) in variants {
+                        t.rawset(&LuaValue::Str(v), LuaValue::Int(n));
+                    }
+                    t.freeze();
+                    LuaValue::Table(Rc::new(RefCell::new(t)))
+                }
+            };
+            lib.borrow_mut().rawset(&LuaValue::Str(name), v);
+        }
+        if let Some(g) = self.global {
+            state.set_global(&g, LuaValue::Table(lib.clone()));
+        }
+        lib
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lstate::GlobalState;
+
+    fn vec3_new(_: &mut LuaState) -> i32 {
+        0
+    }
+
+    #[test]
+    fn test_build_module() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let lib = ModuleBuilder::new("game.vec3")
+            .func("new", vec3_new)
+            .constant("EPS", 1e-9)
+            .constant("DIM", 3)
+            .enumeration("Axis", &[("X", 0), ("Y", 1), ("Z", 2)])
+            .build(&mut state);
+        assert!(Rc::ptr_eq(&lib, &state.lib_table("game.vec3")));
+        let get = |k: &str| lib.borrow().get(&LuaValue::Str(k.to_string())).cloned();
+        assert!(matches!(get("new"), Some(LuaValue::Function(_))));
+        assert_eq!(get("EPS"), Some(LuaValue::Float(1e-9)));
+        assert_eq!(get("DIM"), Some(LuaValue::Int(3)));
+        let Some(LuaValue::Table(axis)) = get("Axis") else { panic!("no Axis") };
+        assert!(axis.borrow().is_frozen());
+        assert_eq!(axis.borrow().get(&LuaValue::Str("Z".to_string())), Some(&LuaValue::Int(2)));
+    }
+}
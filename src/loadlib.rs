@@ -8,16 +8,21 @@ mod lua;
 
 use std::collections::HashMap;
 use std::ffi::{CString, CStr};
-use std::fs;
-use std::io::Read;
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+#[cfg(not(feature = "minimal"))]
 use libloading::{Library, Symbol};
 
 use crate::lualib::*;
 use crate::llimits::*;
 use crate::lauxlib::*;
 use crate::lua::*;
+use crate::skylaconf::{IG_MARK, LUA_CPATH_DEFAULT, LUA_PATH_DEFAULT, PATH_MARK};
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::lvfs::{default_vfs, Vfs};
+#[cfg(windows)]
+use crate::skylaconf::EXEC_DIR;
 
 /// Prefix for open functions in C libraries
 const LUA_POF: &str = "luaopen_";
@@ -32,16 +37,19 @@ const ERRLIB: i32 = 1;
 const ERRFUNC: i32 = 2;
 
 /// Global registry of loaded libraries (path -> Library)
+#[cfg(not(feature = "minimal"))]
 lazy_static::lazy_static! {
     static ref LIB_REGISTRY: Mutex<HashMap<String, Library>> = Mutex::new(HashMap::new());
 }
 
 /// Load a dynamic library and return a handle
+#[cfg(not(feature = "minimal"))]
 fn load_library(path: &str) -> Result<Library, String> {
     Library::new(path).map_err(|e| e.to_string())
 }
 
 /// Find a symbol in a loaded library
+#[cfg(not(feature = "minimal"))]
 unsafe fn find_symbol<T>(lib: &Library, sym: &str) -> Result<Symbol<T>, String> {
     let cstr = CString::new(sym).unwrap();
     lib.get::<T>(cstr.as_bytes_with_nul()).map_err(|e| e.to_string())
@@ -49,6 +57,7 @@ unsafe fn find_symbol<T>(lib: &Library, sym: &str) -> Result<Symbol<T>, String>
 
 /// Look for a C function named 'sym' in a dynamically loaded library 'path'.
 /// Returns Ok(Some(fn_ptr)) if found, Ok(None) if only loading the library, Err if error.
+#[cfg(not(feature = "minimal"))]
 fn lookforfunc(path: &str, sym: &str) -> Result<Option<*const ()>, (i32, String)> {
     let mut reg = LIB_REGISTRY.lock().unwrap();
     let lib = if let Some(lib) = reg.get(path) {
@@ -73,37 +82,187 @@ fn lookforfunc(path: &str, sym: &str) -> Result<Option<*const ()>, (i32, String)
     }
 }
 
-/// Search path logic (simplified)
-pub fn search_path(name: &str, path: &str, sep: &str, dirsep: &str) -> Result<String, String> {
+/// Search `path` (templates separated by ';') for `name`: `sep` in the name
+/// becomes `dirsep`, then each template has its marks replaced with the
+/// name (as luaL_gsub does). On failure the error lists every candidate the
+/// way package.searchpath does: "no file 'a'\n\tno file 'b'".
+/// Files are looked up through the state's Vfs.
+pub fn search_path(state: &LuaState, name: &str, path: &str, sep: &str, dirsep: &str) -> Result<String, String> {
+    search_path_in(&*state.vfs(), name, path, sep, dirsep)
+}
+
+/// search_path over the files of `vfs`
+pub fn search_path_in(vfs: &dyn Vfs, name: &str, path: &str, sep: &str, dirsep: &str) -> Result<String, String> {
+    let name = if sep.is_empty() { name.to_string() } else { gsub(name, sep, dirsep) };
     let mut tried = Vec::new();
-    let mut found = None;
-    for template in path.split(';') {
-        let candidate = template.replace("?", name).replace(sep, dirsep);
-        if std::fs::metadata(&candidate).is_ok() {
-            found = Some(candidate);
-            break;
-        } else {
-            tried.push(candidate);
+    for template in path.split(';').filter(|t| !t.is_empty()) {
+        let candidate = gsub(template, PATH_MARK, &name);
+        if vfs.exists(&candidate) {
+            return Ok(candidate);
+        }
+        tried.push(format!("no file '{}'", candidate));
+    }
+    Err(tried.join("\n\t"))
+}
+
+/// Replace the executable-directory mark ('!') in `path` with the directory
+/// of the running executable. Only Windows expands it, as in the reference
+/// implementation; elsewhere the path is returned unchanged.
+pub fn set_prog_dir(path: &str) -> String {
+    #[cfg(windows)]
+    {
+        if let Some(dir) = std::env::current_exe().ok().as_deref().and_then(Path::parent) {
+            return gsub(path, EXEC_DIR, &dir.to_string_lossy());
+        }
+    }
+    path.to_string()
+}
+
+// --- Path configuration ---
+
+/// Registry field set when the environment is to be ignored (skyla -E)
+pub const LUA_NOENV: &str = "LUA_NOENV";
+
+/// Replace the first ";;" in `path` with the default path `dft`
+pub fn insert_default_path(path: &str, dft: &str) -> String {
+    let Some(i) = path.find(";;") else {
+        return path.to_string();
+    };
+    let mut out = String::with_capacity(path.len() + dft.len());
+    if i > 0 {
+        out.push_str(&path[..i]);
+        out.push(';');
+    }
+    out.push_str(dft);
+    if i + 2 < path.len() {
+        out.push(';');
+        out.push_str(&path[i + 2..]);
+    }
+    out
+}
+
+/// Search path for `envname` (LUA_PATH or LUA_CPATH): the versioned
+/// variable (LUA_PATH_5_4) wins over the plain one, ";;" in it stands for
+/// `dft`, and `dft` alone is used when neither is set or `noenv` is given
+pub fn env_path(envname: &str, dft: &str, noenv: bool) -> String {
+    let getenv = |name: &str| crate::lplatform::with_platform(|p| p.getenv(name));
+    let path = if noenv {
+        None
+    } else {
+        getenv(&format!("{}{}", envname, LUA_VERSUFFIX)).or_else(|| getenv(envname))
+    };
+    let path = match path {
+        Some(p) => insert_default_path(&p, dft),
+        None => dft.to_string(),
+    };
+    set_prog_dir(&path)
+}
+
+/// Whether the state was told to ignore environment variables
+pub fn no_env(state: &mut LuaState) -> bool {
+    let registry = state.registry_table();
+    let noenv = registry.borrow().get(&LuaValue::Str(LUA_NOENV.to_string())).cloned();
+    matches!(noenv, Some(LuaValue::Bool(true)))
+}
+
+/// Make the libraries opened afterwards ignore environment variables
+pub fn set_no_env(state: &mut LuaState) {
+    let registry = state.registry_table();
+    registry.borrow_mut().rawset(&LuaValue::Str(LUA_NOENV.to_string()), LuaValue::Bool(true));
+}
+
+/// Names of the open functions to try for C module `modname`, in order. A
+/// hyphen (IG_MARK) splits the name: "a.b-v2" tries luaopen_a_b first and
+/// then the old-style luaopen_v2.
+pub fn open_func_names(modname: &str) -> Vec<String> {
+    let modname = gsub(modname, ".", LUA_OFSEP);
+    match modname.split_once(IG_MARK) {
+        Some((prefix, rest)) => vec![format!("{}{}", LUA_POF, prefix), format!("{}{}", LUA_POF, rest)],
+        None => vec![format!("{}{}", LUA_POF, modname)],
+    }
+}
+
+/// Look up the open function of C module `modname` in library `filename`.
+/// Only a missing symbol moves on to the next candidate name.
+#[cfg(not(feature = "minimal"))]
+fn load_func(filename: &str, modname: &str) -> Result<Option<*const ()>, (i32, String)> {
+    let names = open_func_names(modname);
+    let (last, first) = names.split_last().unwrap();
+    for sym in first {
+        match lookforfunc(filename, sym) {
+            Err((ERRFUNC, _)) => continue,
+            r => return r,
         }
     }
-    found.ok_or_else(|| format!("no file found in paths: {:?}", tried))
+    lookforfunc(filename, last)
 }
 
+/// Load the plugin in dynamic library `path` (see declare_plugin!) into
+/// `state`. The library's plugin ABI is checked before anything else in
+/// it runs; the library stays loaded for the life of the process.
+#[cfg(not(feature = "minimal"))]
+pub fn load_plugin_library(state: &mut LuaState, path: &str) -> Result<(), String> {
+    let abi = match lookforfunc(path, "skyla_plugin_abi") {
+        Ok(Some(f)) => unsafe { std::mem::transmute::<*const (), extern "C" fn() -> u32>(f)() },
+        Ok(None) => unreachable!(),
+        Err((_, msg)) => return Err(format!("cannot load plugin '{}': {}", path, msg)),
+    };
+    if let Some(msg) = crate::lplugin::abi_mismatch(path, abi) {
+        return Err(msg);
+    }
+    let create = match lookforfunc(path, "skyla_plugin_create") {
+        Ok(Some(f)) => unsafe { std::mem::transmute::<*const (), fn() -> Box<dyn crate::lplugin::Plugin>>(f) },
+        Ok(None) => unreachable!(),
+        Err((_, msg)) => return Err(format!("cannot load plugin '{}': {}", path, msg)),
+    };
+    state.load_plugin(&*create());
+    Ok(())
+}
+
+/// A module chunk bundled into the executable
+#[derive(Debug, Clone)]
+pub enum EmbeddedChunk {
+    Source(String),
+    Bytecode(Vec<u8>),
+}
+
+/// First bytes of every precompiled chunk
+const BYTECODE_SIGNATURE: &[u8] = b"\x1bLua";
+
 /// Package table and require logic (skeleton)
 pub struct Package {
     pub loaded: HashMap<String, bool>,
     pub preload: HashMap<String, fn()>,
+    /// Modules bundled with `PackageExt::preload_source`/`preload_bytecode`
+    pub embedded: HashMap<String, EmbeddedChunk>,
+    /// Where the searchers look for files
+    pub vfs: Arc<dyn Vfs>,
     pub cpath: String,
     pub path: String,
 }
 
 impl Package {
     pub fn new() -> Self {
+        Self::with_env(false)
+    }
+
+    /// Package for `state`: its file system and its -E setting
+    pub fn for_state(state: &mut LuaState) -> Self {
+        let mut pkg = Self::with_env(no_env(state));
+        pkg.vfs = state.vfs();
+        pkg
+    }
+
+    /// Package whose paths come from LUA_PATH/LUA_CPATH (unless `noenv`)
+    /// with the compiled defaults as fallback
+    pub fn with_env(noenv: bool) -> Self {
         Self {
             loaded: HashMap::new(),
             preload: HashMap::new(),
-            cpath: String::from("./?.so;./lib?.so"),
-            path: String::from("./?.lua;./?/init.lua"),
+            embedded: HashMap::new(),
+            vfs: default_vfs(),
+            cpath: env_path("LUA_CPATH", LUA_CPATH_DEFAULT, noenv),
+            path: env_path("LUA_PATH", LUA_PATH_DEFAULT, noenv),
         }
     }
 
@@ -120,9 +279,8 @@ impl Package {
         }
         // Try C library
         let cpath = self.cpath.clone();
-        let filename = search_path(name, &cpath, ".", std::path::MAIN_SEPARATOR_STR)?;
-        let sym = format!("{}{}", LUA_POF, name.replace('.', LUA_OFSEP));
-        match lookforfunc(&filename, &sym) {
+        let filename = search_path_in(&*self.vfs, name, &cpath, ".", std::path::MAIN_SEPARATOR_STR)?;
+        match load_func(&filename, name) {
             Ok(Some(_fn_ptr)) => {
                 // TODO: Actually call/init the function pointer
                 self.loaded.insert(name.to_string(), true);
@@ -165,38 +323,54 @@ impl std::fmt::Display for PackageError {
 
 /// Searcher trait for extensible searchers
 pub trait Searcher {
-    fn search(&self, pkg: &mut Package, name: &str) -> Result<(), PackageError>;
+    fn search(&self, state: &mut LuaState, pkg: &mut Package, name: &str) -> Result<(), PackageError>;
+}
+
+/// Load `data` with the state's loader and run it as require runs a Lua
+/// module: called with the module name and `extra` (the file it came
+/// from), its result (or true) stored in package.loaded[name]
+fn run_module_chunk(state: &mut LuaState, pkg: &mut Package, name: &str, data: &[u8], chunkname: &str, mode: &str, extra: &str) -> Result<(), PackageError> {
+    let opts = crate::lsourcemap::LoadOptions { chunkname: chunkname.to_string(), mode: mode.to_string(), ..Default::default() };
+    state.load_buffer_with(data, &opts).map_err(|e| {
+        PackageError::LoadError(format!("error loading module '{}' from {}:\n\t{}", name, extra, e))
+    })?;
+    state.push(LuaValue::Str(name.to_string()));
+    state.push(LuaValue::Str(extra.to_string()));
+    state.call(2, 1);
+    let value = match state.pop() {
+        None | Some(LuaValue::Nil) => LuaValue::Bool(true),
+        Some(v) => v,
+    };
+    let loaded = state.loaded_table();
+    loaded.borrow_mut().set(&LuaValue::Str(name.to_string()), value);
+    pkg.loaded.insert(name.to_string(), true);
+    Ok(())
 }
 
 /// Lua file searcher
 pub struct LuaFileSearcher;
 impl Searcher for LuaFileSearcher {
-    fn search(&self, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
-        let filename = search_path(name, &pkg.path, ".", std::path::MAIN_SEPARATOR_STR)
+    fn search(&self, state: &mut LuaState, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
+        let filename = search_path_in(&*pkg.vfs, name, &pkg.path, ".", std::path::MAIN_SEPARATOR_STR)
             .map_err(PackageError::NotFound)?;
-        // Simulate loading and running the Lua file
-        let mut file = fs::File::open(&filename)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        // TODO: Actually parse/execute Lua code
-        println!("[LuaFileSearcher] Loaded Lua file: {}", filename);
-        pkg.loaded.insert(name.to_string(), true);
-        Ok(())
+        let contents = pkg.vfs.read(&filename)?;
+        let chunkname = format!("@{}", filename);
+        run_module_chunk(state, pkg, name, &contents, &chunkname, "bt", &filename)
     }
 }
 
-/// C library searcher
+/// C library searcher (needs dynamic loading, so not in `minimal` builds)
+#[cfg(not(feature = "minimal"))]
 pub struct CLibrarySearcher;
+#[cfg(not(feature = "minimal"))]
 impl Searcher for CLibrarySearcher {
-    fn search(&self, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
+    fn search(&self, _state: &mut LuaState, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
         let cpath = pkg.cpath.clone();
-        let filename = search_path(name, &cpath, ".", std::path::MAIN_SEPARATOR_STR)
+        let filename = search_path_in(&*pkg.vfs, name, &cpath, ".", std::path::MAIN_SEPARATOR_STR)
             .map_err(PackageError::NotFound)?;
-        let sym = format!("{}{}", LUA_POF, name.replace('.', LUA_OFSEP));
-        match lookforfunc(&filename, &sym) {
+        match load_func(&filename, name) {
             Ok(Some(_fn_ptr)) => {
                 // TODO: Actually call/init the function pointer
-                println!("[CLibrarySearcher] Loaded C library: {} symbol: {}", filename, sym);
                 pkg.loaded.insert(name.to_string(), true);
                 Ok(())
             },
@@ -209,11 +383,10 @@ impl Searcher for CLibrarySearcher {
 /// Preload searcher
 pub struct PreloadSearcher;
 impl Searcher for PreloadSearcher {
-    fn search(&self, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
+    fn search(&self, _state: &mut LuaState, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
         if let Some(init) = pkg.preload.get(name) {
             init();
             pkg.loaded.insert(name.to_string(), true);
-            println!("[PreloadSearcher] Loaded from preload: {}", name);
             Ok(())
         } else {
             Err(PackageError::NotFound(format!("No preload for {}", name)))
@@ -221,6 +394,26 @@ impl Searcher for PreloadSearcher {
     }
 }
 
+/// Searcher for modules bundled into the executable; runs before the
+/// file searchers so a bundled module tree never touches the filesystem
+pub struct EmbeddedSearcher;
+impl Searcher for EmbeddedSearcher {
+    fn search(&self, state: &mut LuaState, pkg: &mut Package, name: &str) -> Result<(), PackageError> {
+        let chunkname = format!("=[embedded] {}", name);
+        let (data, mode) = match pkg.embedded.get(name) {
+            None => return Err(PackageError::NotFound(format!("no embedded module '{}'", name))),
+            Some(EmbeddedChunk::Source(src)) => (src.clone().into_bytes(), "t"),
+            Some(EmbeddedChunk::Bytecode(code)) => {
+                if !code.starts_with(BYTECODE_SIGNATURE) {
+                    return Err(PackageError::LoadError(format!("{}: bad binary format (not a precompiled chunk)", chunkname)));
+                }
+                (code.clone(), "b")
+            }
+        };
+        run_module_chunk(state, pkg, name, &data, &chunkname, mode, ":embedded:")
+    }
+}
+
 /// Package with searchers
 pub struct PackageExt {
     pub pkg: Package,
@@ -233,19 +426,32 @@ impl PackageExt {
             pkg: Package::new(),
             searchers: vec![
                 Box::new(PreloadSearcher),
+                Box::new(EmbeddedSearcher),
                 Box::new(LuaFileSearcher),
+                #[cfg(not(feature = "minimal"))]
                 Box::new(CLibrarySearcher),
             ],
         }
     }
 
-    /// Simulate 'require' with searchers
-    pub fn require(&mut self, name: &str) -> Result<(), PackageError> {
+    /// Bundle the Lua source of module `name`
+    pub fn preload_source(&mut self, name: &str, source: &str) {
+        self.pkg.embedded.insert(name.to_string(), EmbeddedChunk::Source(source.to_string()));
+    }
+
+    /// Bundle a precompiled chunk (luac output) for module `name`
+    pub fn preload_bytecode(&mut self, name: &str, bytecode: &[u8]) {
+        self.pkg.embedded.insert(name.to_string(), EmbeddedChunk::Bytecode(bytecode.to_vec()));
+    }
+
+    /// 'require' through the searchers; Lua modules are loaded and run
+    /// in `state`
+    pub fn require(&mut self, state: &mut LuaState, name: &str) -> Result<(), PackageError> {
         if self.pkg.loaded.get(name).copied().unwrap_or(false) {
             return Ok(());
         }
         for searcher in &self.searchers {
-            match searcher.search(&mut self.pkg, name) {
+            match searcher.search(state, &mut self.pkg, name) {
                 Ok(_) => return Ok(()),
                 Err(PackageError::NotFound(_)) => continue,
                 Err(e) => return Err(e),
@@ -258,14 +464,45 @@ impl PackageExt {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lstate::GlobalState;
     #[test]
     fn test_search_path() {
+        let state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
         let path = "./?.so;./lib?.so";
         let name = "testmod";
-        let result = search_path(name, path, ".", "/");
+        let result = search_path(&state, name, path, ".", "/");
         assert!(result.is_err() || result.as_ref().unwrap().contains("testmod"));
     }
     #[test]
+    fn test_search_path_uses_the_state_vfs() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let vfs = crate::lvfs::MemoryVfs::new();
+        vfs.insert("lib/a/b.lua", "return {}");
+        state.set_vfs(std::sync::Arc::new(vfs));
+        assert_eq!(search_path(&state, "a.b", "lib/?.lua", ".", "/").as_deref(), Ok("lib/a/b.lua"));
+    }
+    #[test]
+    fn test_search_path_error_lists_candidates() {
+        let state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let err = search_path(&state, "a.b", "/nonexistent/?.lua;;/nonexistent/?/init.lua", ".", "/").unwrap_err();
+        assert_eq!(err, "no file '/nonexistent/a/b.lua'\n\tno file '/nonexistent/a/b/init.lua'");
+    }
+    #[test]
+    fn test_insert_default_path() {
+        assert_eq!(insert_default_path("./?.lua", "D"), "./?.lua");
+        assert_eq!(insert_default_path(";;", "D"), "D");
+        assert_eq!(insert_default_path("a;;", "D"), "a;D");
+        assert_eq!(insert_default_path(";;b", "D"), "D;b");
+        assert_eq!(insert_default_path("a;;b;;", "D"), "a;D;b;;");
+    }
+    #[test]
+    fn test_open_func_names() {
+        assert_eq!(open_func_names("a.b"), vec!["luaopen_a_b"]);
+        assert_eq!(open_func_names("a.b-v2"), vec!["luaopen_a_b", "luaopen_v2"]);
+    }
+    #[test]
     fn test_package_require() {
         let mut pkg = Package::new();
         // Simulate preload
@@ -275,20 +512,79 @@ mod tests {
     }
 }
 
+/// Bundle Lua sources into the executable at compile time:
+/// `include_lua!(pkg, "app.util" => "lua/app/util.lua", ...)` stores each
+/// file (path relative to the calling source file, as with include_str!)
+/// with `PackageExt::preload_source`.
+#[macro_export]
+macro_rules! include_lua {
+    ($pkg:expr, $($name:literal => $path:literal),+ $(,)?) => {{
+        $( $pkg.preload_source($name, include_str!($path)); )+
+    }};
+}
+
 #[cfg(test)]
 mod ext_tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lstate::GlobalState;
+
+    fn new_state() -> LuaState {
+        LuaState::new(Rc::new(RefCell::new(GlobalState::new())))
+    }
+
+    fn loaded(state: &mut LuaState, name: &str) -> LuaValue {
+        state.loaded_table().borrow().get(&LuaValue::Str(name.to_string())).cloned().unwrap_or(LuaValue::Nil)
+    }
+
     #[test]
     fn test_package_ext_preload() {
+        let mut state = new_state();
         let mut pkg = PackageExt::new();
-        pkg.pkg.preload.insert("bar".to_string(), || println!("init bar"));
-        assert!(pkg.require("bar").is_ok());
+        pkg.pkg.preload.insert("bar".to_string(), || {});
+        assert!(pkg.require(&mut state, "bar").is_ok());
         assert!(pkg.pkg.loaded["bar"]);
     }
     #[test]
+    fn test_package_ext_embedded() {
+        let mut state = new_state();
+        let mut pkg = PackageExt::new();
+        pkg.preload_source("app.util", "local name = ...; return { answer = 42, name = name }");
+        pkg.preload_source("app.empty", "local x = 1");
+        pkg.preload_bytecode("app.truncated", b"\x1bLua\x54\x00");
+        pkg.preload_bytecode("app.bad", b"return 1");
+        assert!(pkg.require(&mut state, "app.util").is_ok());
+        match loaded(&mut state, "app.util") {
+            LuaValue::Table(t) => {
+                assert!(matches!(t.borrow().get(&LuaValue::Str("answer".into())), Some(LuaValue::Int(42))));
+                assert!(matches!(t.borrow().get(&LuaValue::Str("name".into())), Some(LuaValue::Str(s)) if s == "app.util"));
+            }
+            other => panic!("expected the module's table, got {:?}", other),
+        }
+        assert!(pkg.require(&mut state, "app.empty").is_ok());
+        assert!(matches!(loaded(&mut state, "app.empty"), LuaValue::Bool(true)));
+        assert!(matches!(pkg.require(&mut state, "app.truncated"), Err(PackageError::LoadError(_))));
+        assert!(matches!(pkg.require(&mut state, "app.bad"), Err(PackageError::LoadError(_))));
+        assert!(!pkg.pkg.loaded.contains_key("app.truncated"));
+    }
+    #[test]
+    fn test_package_ext_vfs() {
+        let mut state = new_state();
+        let vfs = crate::lvfs::MemoryVfs::new();
+        vfs.insert("lib/app/util.lua", "return select(2, ...)");
+        let mut pkg = PackageExt::new();
+        pkg.pkg.vfs = Arc::new(vfs);
+        pkg.pkg.path = "lib/?.lua".to_string();
+        assert!(pkg.require(&mut state, "app.util").is_ok());
+        assert!(matches!(loaded(&mut state, "app.util"), LuaValue::Str(s) if s.ends_with("util.lua")));
+        assert!(matches!(pkg.require(&mut state, "app.other"), Err(PackageError::NotFound(_))));
+    }
+    #[test]
     fn test_package_ext_notfound() {
+        let mut state = new_state();
         let mut pkg = PackageExt::new();
-        let result = pkg.require("notfound");
+        let result = pkg.require(&mut state, "notfound");
         assert!(matches!(result, Err(PackageError::NotFound(_))));
     }
 }
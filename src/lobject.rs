@@ -10,6 +10,7 @@ use crate::ldebug::*;
 use crate::ldo::*;
 use std::cmp;
 use std::f64;
+use crate::skylaconf::{LuaFloat, LuaInteger, LuaUnsigned};
 
 /// Computes ceil(log2(x))
 pub fn luaO_ceillog2(mut x: u32) -> u8 {
@@ -51,6 +52,18 @@ pub fn luaO_codeparam(mut p: u32) -> u8 {
     }
 }
 
+/// Encodes the size hint `n` of a table constructor (NEWTABLE's B and C)
+/// as a floating-point byte: exact below 32, rounded down to 5 significant
+/// bits above, and capped at 3968, where the table just grows as it fills
+pub fn luaO_codesize(n: u32) -> u8 {
+    luaO_codeparam(n.min(0x1F << 7) * 100)
+}
+
+/// Size hint encoded by luaO_codesize
+pub fn luaO_decodesize(b: u8) -> usize {
+    luaO_applyparam(b, 1) as usize
+}
+
 /// Applies a floating-point byte parameter to an integer
 pub fn luaO_applyparam(p: u8, x: i64) -> i64 {
     let mut m = (p & 0xF) as i64;
@@ -88,40 +101,240 @@ pub fn luaO_hexavalue(c: u8) -> u8 {
     }
 }
 
-/// Convert a string to an integer (supports decimal and hex)
-pub fn luaO_str2int(s: &str) -> Option<i64> {
-    let s = s.trim();
-    let (neg, s) = match s.chars().next() {
-        Some('-') => (true, &s[1..]),
-        Some('+') => (false, &s[1..]),
+/// Convert a numeral to an integer (l_str2int): decimal or hexadecimal,
+/// with optional surrounding whitespace and sign. Hexadecimal numerals
+/// wrap around; a decimal one that does not fit is not an integer (it
+/// converts as a float instead).
+pub fn luaO_str2int(s: &str) -> Option<LuaInteger> {
+    let s = s.trim_matches(LUA_SPACECHARS).as_bytes();
+    let (neg, digits) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
         _ => (false, s),
     };
-    let s = s.trim_start();
-    if s.starts_with("0x") || s.starts_with("0X") {
-        i64::from_str_radix(&s[2..], 16).ok().map(|v| if neg { -v } else { v })
+    let mut a: LuaUnsigned = 0;
+    if let Some(hex) = digits.strip_prefix(b"0x").or_else(|| digits.strip_prefix(b"0X")) {
+        if hex.is_empty() || !hex.iter().all(u8::is_ascii_hexdigit) {
+            return None;
+        }
+        for &c in hex {
+            a = a.wrapping_mul(16).wrapping_add(luaO_hexavalue(c) as LuaUnsigned);
+        }
     } else {
-        s.parse::<i64>().ok().map(|v| if neg { -v } else { v })
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let maxby10 = LuaInteger::MAX as LuaUnsigned / 10;
+        let maxlastd = LuaInteger::MAX as LuaUnsigned % 10;
+        for &c in digits {
+            let d = (c - b'0') as LuaUnsigned;
+            if a >= maxby10 && (a > maxby10 || d > maxlastd + neg as LuaUnsigned) {
+                return None; // overflow
+            }
+            a = a * 10 + d;
+        }
     }
+    Some(if neg { a.wrapping_neg() } else { a } as LuaInteger)
+}
+
+/// A converted numeral, keeping its subtype
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Numeral {
+    Int(LuaInteger),
+    Float(LuaFloat),
 }
 
-/// Convert a string to a float (locale-independent, basic)
-pub fn luaO_str2num(s: &str) -> Option<f64> {
-    s.trim().parse::<f64>().ok()
+/// Convert a numeral (luaO_str2num): an integer if it is written as one
+/// and fits, else a float. This is the one conversion behind tonumber,
+/// lua_stringtonumber, string coercions and numeric literals.
+pub fn luaO_str2number(s: &str) -> Option<Numeral> {
+    match luaO_str2int(s) {
+        Some(i) => Some(Numeral::Int(i)),
+        None => luaO_str2num(s).map(Numeral::Float),
+    }
+}
+
+/// Length of the numeral at the start of `s` as the lexer reads it
+/// (read_numeral): hex digits and dots, an exponent mark ('e'/'E', or
+/// 'p'/'P' after "0x") with an optional sign, and one letter touching the
+/// end so that "3x" is read whole. The token then goes to luaO_str2number;
+/// if that fails the numeral is malformed.
+pub fn luaO_numeral_len(s: &[u8]) -> usize {
+    let (expo, mut i): (&[u8], usize) = if s.len() >= 2 && s[0] == b'0' && (s[1] == b'x' || s[1] == b'X') {
+        (b"Pp", 2)
+    } else {
+        (b"Ee", 0)
+    };
+    while let Some(&c) = s.get(i) {
+        if expo.contains(&c) {
+            i += 1;
+            if matches!(s.get(i), Some(b'-' | b'+')) {
+                i += 1;
+            }
+        } else if c.is_ascii_hexdigit() || c == b'.' {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if s.get(i).is_some_and(|c| c.is_ascii_alphabetic() || *c == b'_') {
+        i += 1;
+    }
+    i
 }
 
-/// Convert a number to a string (integer or float)
-pub fn luaO_num2str(n: f64) -> String {
-    if n.fract() == 0.0 {
-        format!("{:.0}", n)
+/// Whitespace accepted around numerals (same set as C's isspace in the "C" locale)
+pub const LUA_SPACECHARS: &[char] = &[' ', '\x0c', '\n', '\r', '\t', '\x0b'];
+
+/// Convert a string to a float (locale-independent).
+/// Accepts decimal and hexadecimal numerals (e.g. "0x1p4", "0xA.8"), with
+/// optional leading/trailing whitespace; rejects "inf"/"nan" like Lua does.
+pub fn luaO_str2num(s: &str) -> Option<LuaFloat> {
+    let s = s.trim_matches(LUA_SPACECHARS);
+    let (neg, body) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if body.starts_with("0x") || body.starts_with("0X") {
+        luaO_hexstr2num(&body[2..]).map(|v| (if neg { -v } else { v }) as LuaFloat)
+    } else if body.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        // a leading digit or dot keeps out the "inf"/"nan" spellings that
+        // Rust's parser would take
+        s.parse::<LuaFloat>().ok()
     } else {
-        format!("{}", n)
+        None
+    }
+}
+
+/// Convert the digits of a hexadecimal numeral (after "0x") to a float:
+/// hex mantissa with an optional '.', then an optional binary exponent 'p'.
+pub fn luaO_hexstr2num(s: &str) -> Option<f64> {
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    let mut r = 0.0f64;
+    let mut exp: i64 = 0; // exponent correction for fractional digits
+    let mut any = false;
+    let mut seen_dot = false;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'.' {
+            if seen_dot { return None; }
+            seen_dot = true;
+        } else if c.is_ascii_hexdigit() {
+            r = r * 16.0 + luaO_hexavalue(c) as f64;
+            if seen_dot { exp -= 4; }
+            any = true;
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if !any {
+        return None;
+    }
+    if i < bytes.len() && (bytes[i] == b'p' || bytes[i] == b'P') {
+        i += 1;
+        let (eneg, start) = match bytes.get(i) {
+            Some(b'-') => (true, i + 1),
+            Some(b'+') => (false, i + 1),
+            _ => (false, i),
+        };
+        let digits = &s[start..];
+        if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let e: i64 = digits.parse().unwrap_or(i64::MAX / 2);
+        exp = if eneg { exp - e } else { exp + e };
+        i = bytes.len();
     }
+    if i != bytes.len() {
+        return None;
+    }
+    Some(r * 2f64.powi(exp.clamp(i32::MIN as i64, i32::MAX as i64) as i32))
 }
 
-/// Convert a number to a string, adding ".0" if it looks like an integer
-pub fn luaO_num2str_dot(n: f64) -> String {
+/// Convert a string in the given base (2..=36) to an integer, as used by
+/// tonumber(s, base). Surrounding whitespace is allowed; overflow wraps.
+pub fn luaO_str2int_base(s: &str, base: u32) -> Option<LuaInteger> {
+    debug_assert!((2..=36).contains(&base));
+    let s = s.trim_matches(LUA_SPACECHARS);
+    let (neg, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: LuaUnsigned = 0;
+    for c in digits.chars() {
+        let d = c.to_digit(36)?;
+        if d >= base {
+            return None; // invalid numeral
+        }
+        n = n.wrapping_mul(base as LuaUnsigned).wrapping_add(d as LuaUnsigned);
+    }
+    let n = if neg { n.wrapping_neg() } else { n };
+    Some(n as LuaInteger)
+}
+
+/// Significant digits used for floats (LUAI_NUMFFORMAT is "%.14g", or
+/// "%.7g" with the float32 profile)
+pub use crate::skylaconf::LUAI_NUMFFORMAT_PREC;
+
+/// Format a float like C's "%.<prec>g" (including "inf", "-inf", "nan",
+/// "-nan" and "-0"), independent of the current locale.
+pub fn luaO_fmt_g(n: LuaFloat, prec: usize) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan".to_string() } else { "nan".to_string() };
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf".to_string() } else { "inf".to_string() };
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0".to_string() } else { "0".to_string() };
+    }
+    let p = prec.max(1);
+    // Round to 'p' significant digits first to find the decimal exponent
+    let sci = format!("{:.*e}", p - 1, n);
+    let (mant, exp) = sci.split_once('e').unwrap();
+    let x: i32 = exp.parse().unwrap();
+    if x < -4 || x >= p as i32 {
+        let mant = strip_trailing_zeros(mant);
+        let sign = if x < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mant, sign, x.abs())
+    } else {
+        let fixed = format!("{:.*}", (p as i32 - 1 - x) as usize, n);
+        strip_trailing_zeros(&fixed).to_string()
+    }
+}
+
+/// Remove trailing zeros (and a dangling '.') from a decimal fraction
+fn strip_trailing_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Convert a float to a string using LUAI_NUMFFORMAT ("%.14g")
+pub fn luaO_num2str(n: LuaFloat) -> String {
+    luaO_fmt_g(n, LUAI_NUMFFORMAT_PREC)
+}
+
+/// Convert an integer to a string (never adds a decimal point)
+pub fn luaO_int2str(i: LuaInteger) -> String {
+    i.to_string()
+}
+
+/// Convert a float to a string as tostring/print do: "%.14g", adding ".0"
+/// if the result looks like an integer (so 1.0 prints as "1.0", -0.0 as
+/// "-0.0", while "inf"/"nan"/"1e+20" are left alone)
+pub fn luaO_num2str_dot(n: LuaFloat) -> String {
     let s = luaO_num2str(n);
-    if s.find('.').is_none() && s.find('e').is_none() && s.find('E').is_none() {
+    if s.bytes().all(|c| c == b'-' || c.is_ascii_digit()) {
         format!("{}.0", s)
     } else {
         s
@@ -184,27 +397,27 @@ pub fn luaO_chunkid(source: &str, bufflen: usize) -> String {
 }
 
 /// Arithmetic operations for Lua values (integer and float)
-pub fn luaO_add(a: f64, b: f64) -> f64 { a + b }
-pub fn luaO_sub(a: f64, b: f64) -> f64 { a - b }
-pub fn luaO_mul(a: f64, b: f64) -> f64 { a * b }
-pub fn luaO_div(a: f64, b: f64) -> f64 { a / b }
-pub fn luaO_mod(a: f64, b: f64) -> f64 { a % b }
-pub fn luaO_pow(a: f64, b: f64) -> f64 { a.powf(b) }
-pub fn luaO_unm(a: f64) -> f64 { -a }
+pub fn luaO_add(a: LuaFloat, b: LuaFloat) -> LuaFloat { a + b }
+pub fn luaO_sub(a: LuaFloat, b: LuaFloat) -> LuaFloat { a - b }
+pub fn luaO_mul(a: LuaFloat, b: LuaFloat) -> LuaFloat { a * b }
+pub fn luaO_div(a: LuaFloat, b: LuaFloat) -> LuaFloat { a / b }
+pub fn luaO_mod(a: LuaFloat, b: LuaFloat) -> LuaFloat { a % b }
+pub fn luaO_pow(a: LuaFloat, b: LuaFloat) -> LuaFloat { a.powf(b) }
+pub fn luaO_unm(a: LuaFloat) -> LuaFloat { -a }
 
 /// Integer bitwise operations
-pub fn luaO_band(a: i64, b: i64) -> i64 { a & b }
-pub fn luaO_bor(a: i64, b: i64) -> i64 { a | b }
-pub fn luaO_bxor(a: i64, b: i64) -> i64 { a ^ b }
-pub fn luaO_bnot(a: i64) -> i64 { !a }
-pub fn luaO_shl(a: i64, b: u32) -> i64 { a << b }
-pub fn luaO_shr(a: i64, b: u32) -> i64 { a >> b }
+pub fn luaO_band(a: LuaInteger, b: LuaInteger) -> LuaInteger { a & b }
+pub fn luaO_bor(a: LuaInteger, b: LuaInteger) -> LuaInteger { a | b }
+pub fn luaO_bxor(a: LuaInteger, b: LuaInteger) -> LuaInteger { a ^ b }
+pub fn luaO_bnot(a: LuaInteger) -> LuaInteger { !a }
+pub fn luaO_shl(a: LuaInteger, b: u32) -> LuaInteger { a << b }
+pub fn luaO_shr(a: LuaInteger, b: u32) -> LuaInteger { a >> b }
 
 /// Equality and comparison helpers
-pub fn luaO_eqnum(a: f64, b: f64) -> bool { (a - b).abs() < f64::EPSILON }
-pub fn luaO_eqint(a: i64, b: i64) -> bool { a == b }
-pub fn luaO_lt(a: f64, b: f64) -> bool { a < b }
-pub fn luaO_le(a: f64, b: f64) -> bool { a <= b }
+pub fn luaO_eqnum(a: LuaFloat, b: LuaFloat) -> bool { (a - b).abs() < LuaFloat::EPSILON }
+pub fn luaO_eqint(a: LuaInteger, b: LuaInteger) -> bool { a == b }
+pub fn luaO_lt(a: LuaFloat, b: LuaFloat) -> bool { a < b }
+pub fn luaO_le(a: LuaFloat, b: LuaFloat) -> bool { a <= b }
 
 /// Set a node's key as 'dead' (used in Lua tables for deleted keys)
 #[inline(always)]
@@ -264,8 +477,8 @@ luai_func!(pub fn luaO_example_func(x: i32) -> i32 {
 /// A trait for Lua value types (for dynamic dispatch, type tags, etc.)
 pub trait LuaValue: std::fmt::Debug + Send + Sync {
     fn type_name(&self) -> &'static str;
-    fn as_number(&self) -> Option<f64> { None }
-    fn as_integer(&self) -> Option<i64> { None }
+    fn as_number(&self) -> Option<LuaFloat> { None }
+    fn as_integer(&self) -> Option<LuaInteger> { None }
     fn as_str(&self) -> Option<&str> { None }
     fn is_nil(&self) -> bool { false }
     fn is_truthy(&self) -> bool { true }
@@ -276,8 +489,8 @@ pub trait LuaValue: std::fmt::Debug + Send + Sync {
 pub enum LObject {
     Nil,
     Boolean(bool),
-    Integer(i64),
-    Number(f64),
+    Integer(LuaInteger),
+    Number(LuaFloat),
     String(String),
     Table, // Placeholder for table type
     Function, // Placeholder for function type
@@ -298,17 +511,17 @@ impl LuaValue for LObject {
             LObject::UserData => "userdata",
         }
     }
-    fn as_number(&self) -> Option<f64> {
+    fn as_number(&self) -> Option<LuaFloat> {
         match self {
             LObject::Number(n) => Some(*n),
-            LObject::Integer(i) => Some(*i as f64),
+            LObject::Integer(i) => Some(*i as LuaFloat),
             _ => None,
         }
     }
-    fn as_integer(&self) -> Option<i64> {
+    fn as_integer(&self) -> Option<LuaInteger> {
         match self {
             LObject::Integer(i) => Some(*i),
-            LObject::Number(n) => Some(*n as i64),
+            LObject::Number(n) => crate::skylaconf::float_to_integer(*n),
             _ => None,
         }
     }
@@ -332,11 +545,11 @@ pub fn to_lobject<T: Into<LObject>>(v: T) -> LObject {
 }
 
 /// Example: Implement From for common Rust types
-impl From<i64> for LObject {
-    fn from(i: i64) -> Self { LObject::Integer(i) }
+impl From<LuaInteger> for LObject {
+    fn from(i: LuaInteger) -> Self { LObject::Integer(i) }
 }
-impl From<f64> for LObject {
-    fn from(n: f64) -> Self { LObject::Number(n) }
+impl From<LuaFloat> for LObject {
+    fn from(n: LuaFloat) -> Self { LObject::Number(n) }
 }
 impl From<&str> for LObject {
     fn from(s: &str) -> Self { LObject::String(s.to_string()) }
@@ -349,8 +562,8 @@ impl From<bool> for LObject {
 }
 
 /// Example: Convert LObject to Rust types (if possible)
-pub fn lobject_to_i64(obj: &LObject) -> Option<i64> { obj.as_integer() }
-pub fn lobject_to_f64(obj: &LObject) -> Option<f64> { obj.as_number() }
+pub fn lobject_to_i64(obj: &LObject) -> Option<LuaInteger> { obj.as_integer() }
+pub fn lobject_to_f64(obj: &LObject) -> Option<LuaFloat> { obj.as_number() }
 pub fn lobject_to_str(obj: &LObject) -> Option<&str> { obj.as_str() }
 
 /// Example: Table node with LObject keys/values
@@ -373,10 +586,153 @@ pub fn lmod_with_meta(name: &str, version: &str, author: &str, doc: &str) -> LMo
     m
 }
 
+// --- Compact values ---
+
+/// Handle of an interned string in a ValueHeap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StrId(pub u32);
+
+/// Handle of a collectable object (table, function, userdata) in a ValueHeap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GcIndex(pub u32);
+
+/// Compact value: a 16-byte tagged union (8 bytes with int32 + float32).
+/// Unlike LObject it is Copy: strings and collectable objects are handles
+/// into a ValueHeap, so moving values between registers, tables and the
+/// stack never allocates or clones string bytes. A tagged union is used
+/// rather than NaN boxing so that integers keep their full LuaInteger range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LValue {
+    Nil,
+    Boolean(bool),
+    Integer(LuaInteger),
+    Number(LuaFloat),
+    String(StrId),
+    Object(GcIndex),
+}
+
+impl LValue {
+    pub fn is_nil(&self) -> bool {
+        matches!(self, LValue::Nil)
+    }
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, LValue::Nil | LValue::Boolean(false))
+    }
+    pub fn as_integer(&self) -> Option<LuaInteger> {
+        match self {
+            LValue::Integer(i) => Some(*i),
+            LValue::Number(n) => crate::skylaconf::float_to_integer(*n),
+            _ => None,
+        }
+    }
+    pub fn as_number(&self) -> Option<LuaFloat> {
+        match self {
+            LValue::Number(n) => Some(*n),
+            LValue::Integer(i) => Some(*i as LuaFloat),
+            _ => None,
+        }
+    }
+}
+
+/// Backing store for the handles in LValue: interned strings (equal
+/// strings share one StrId) and collectable objects
+#[derive(Debug, Default)]
+pub struct ValueHeap {
+    strings: Vec<std::rc::Rc<str>>,
+    string_ids: std::collections::HashMap<std::rc::Rc<str>, StrId>,
+    objects: Vec<LObject>,
+}
+
+impl ValueHeap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// StrId of `s`, interning it on first use
+    pub fn intern(&mut self, s: &str) -> StrId {
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+        let id = StrId(self.strings.len() as u32);
+        let s: std::rc::Rc<str> = s.into();
+        self.strings.push(s.clone());
+        self.string_ids.insert(s, id);
+        id
+    }
+
+    pub fn str(&self, id: StrId) -> &str {
+        &self.strings[id.0 as usize]
+    }
+
+    pub fn object(&self, idx: GcIndex) -> &LObject {
+        &self.objects[idx.0 as usize]
+    }
+
+    /// Compact form of `v`; strings are interned and collectable objects
+    /// moved into the heap
+    pub fn pack(&mut self, v: &LObject) -> LValue {
+        match v {
+            LObject::Nil => LValue::Nil,
+            LObject::Boolean(b) => LValue::Boolean(*b),
+            LObject::Integer(i) => LValue::Integer(*i),
+            LObject::Number(n) => LValue::Number(*n),
+            LObject::String(s) => LValue::String(self.intern(s)),
+            LObject::Table | LObject::Function | LObject::UserData => {
+                self.objects.push(v.clone());
+                LValue::Object(GcIndex(self.objects.len() as u32 - 1))
+            }
+        }
+    }
+
+    /// The LObject `v` stands for
+    pub fn unpack(&self, v: LValue) -> LObject {
+        match v {
+            LValue::Nil => LObject::Nil,
+            LValue::Boolean(b) => LObject::Boolean(b),
+            LValue::Integer(i) => LObject::Integer(i),
+            LValue::Number(n) => LObject::Number(n),
+            LValue::String(id) => LObject::String(self.str(id).to_string()),
+            LValue::Object(idx) => self.object(idx).clone(),
+        }
+    }
+
+    pub fn type_name(&self, v: LValue) -> &'static str {
+        match v {
+            LValue::Nil => "nil",
+            LValue::Boolean(_) => "boolean",
+            LValue::Integer(_) => "integer",
+            LValue::Number(_) => "number",
+            LValue::String(_) => "string",
+            LValue::Object(idx) => self.object(idx).type_name(),
+        }
+    }
+
+    /// Bytes held by the heap itself (string bytes, object slots and the
+    /// intern table), for comparing memory use against LObject storage
+    pub fn heap_size(&self) -> usize {
+        self.strings.iter().map(|s| s.len()).sum::<usize>()
+            + self.strings.capacity() * std::mem::size_of::<std::rc::Rc<str>>()
+            + self.string_ids.capacity() * (std::mem::size_of::<std::rc::Rc<str>>() + std::mem::size_of::<StrId>())
+            + self.objects.capacity() * std::mem::size_of::<LObject>()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
+    fn test_codesize() {
+        for n in 0..32 {
+            assert_eq!(luaO_decodesize(luaO_codesize(n)), n as usize);
+        }
+        for n in [33u32, 100, 1000, 3000] {
+            let d = luaO_decodesize(luaO_codesize(n));
+            assert!(d <= n as usize && d * 17 >= n as usize * 16, "{} -> {}", n, d);
+        }
+        assert_eq!(luaO_codesize(u32::MAX), 0xFF);
+        assert_eq!(luaO_decodesize(0xFF), 3968);
+    }
+    #[test]
     fn test_ceillog2() {
         assert_eq!(luaO_ceillog2(1), 0);
         assert_eq!(luaO_ceillog2(2), 1);
@@ -402,11 +758,101 @@ mod tests {
         assert_eq!(luaO_str2num("-2.5"), Some(-2.5));
     }
     #[test]
+    fn test_str2num_hex_and_spaces() {
+        assert_eq!(luaO_str2num("0x1p4"), Some(16.0));
+        assert_eq!(luaO_str2num("  0xA.8  "), Some(10.5));
+        assert_eq!(luaO_str2num("-0x.1"), Some(-0.0625));
+        assert_eq!(luaO_str2num("0x1P-1"), Some(0.5));
+        assert_eq!(luaO_str2num("\t1e2\n"), Some(100.0));
+        assert_eq!(luaO_str2num("inf"), None);
+        assert_eq!(luaO_str2num("nan"), None);
+        assert_eq!(luaO_str2num("-inf"), None);
+        assert_eq!(luaO_str2num("+Infinity"), None);
+        assert_eq!(luaO_str2num(" NaN "), None);
+        assert_eq!(luaO_str2num("1e+n"), None);
+        assert_eq!(luaO_str2num(".5e-1"), Some(0.05));
+        assert_eq!(luaO_str2num("0x"), None);
+        assert_eq!(luaO_str2num("1 2"), None);
+    }
+    #[test]
+    fn test_str2number_reference() {
+        // outputs of math.type/tonumber in the reference interpreter
+        let cases: &[(&str, Option<Numeral>)] = &[
+            ("10", Some(Numeral::Int(10))),
+            (" -0x10 ", Some(Numeral::Int(-16))),
+            ("9223372036854775807", Some(Numeral::Int(LuaInteger::MAX))),
+            ("-9223372036854775808", Some(Numeral::Int(LuaInteger::MIN))),
+            ("9223372036854775808", Some(Numeral::Float(9223372036854775808.0))),
+            ("0xffffffffffffffff", Some(Numeral::Int(-1))),
+            ("0x10000000000000000", Some(Numeral::Int(0))),
+            ("1e2", Some(Numeral::Float(100.0))),
+            ("5.", Some(Numeral::Float(5.0))),
+            (".5", Some(Numeral::Float(0.5))),
+            ("0x.8p1", Some(Numeral::Float(1.0))),
+            ("- 1", None),
+            ("1e", None),
+            ("0x", None),
+            ("", None),
+            ("inf", None),
+            ("1e+", None),
+        ];
+        for (s, want) in cases {
+            assert_eq!(luaO_str2number(s), *want, "{:?}", s);
+        }
+        // formatted numbers read back as themselves
+        let mut x: u64 = 0x9E3779B97F4A7C15;
+        for _ in 0..1000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let i = x as LuaInteger;
+            assert_eq!(luaO_str2number(&i.to_string()), Some(Numeral::Int(i)));
+            assert_eq!(luaO_str2number(&format!("{:#x}", x)), Some(Numeral::Int(i)));
+            let f = f64::from_bits(x >> 2);
+            if f.is_finite() {
+                assert_eq!(luaO_str2number(&format!("{:e}", f)), Some(Numeral::Float(f)));
+            }
+        }
+    }
+    #[test]
+    fn test_numeral_len() {
+        assert_eq!(luaO_numeral_len(b"3.14)"), 4);
+        assert_eq!(luaO_numeral_len(b"1e-5+x"), 4);
+        assert_eq!(luaO_numeral_len(b"0x1p+4 "), 6);
+        assert_eq!(luaO_numeral_len(b"0xep1"), 5);
+        assert_eq!(luaO_numeral_len(b"3x"), 2);
+        assert_eq!(luaO_numeral_len(b"1..2"), 4);
+    }
+    #[test]
+    fn test_str2int_base() {
+        assert_eq!(luaO_str2int_base("ff", 16), Some(255));
+        assert_eq!(luaO_str2int_base(" -101 ", 2), Some(-5));
+        assert_eq!(luaO_str2int_base("zz", 36), Some(1295));
+        assert_eq!(luaO_str2int_base("19", 8), None);
+        assert_eq!(luaO_str2int_base("", 10), None);
+    }
+    #[test]
     fn test_num2str() {
         assert_eq!(luaO_num2str(42.0), "42");
         assert_eq!(luaO_num2str(3.14), "3.14");
     }
     #[test]
+    fn test_num2str_lua_format() {
+        assert_eq!(luaO_num2str(0.1), "0.1");
+        assert_eq!(luaO_num2str(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(luaO_num2str(1e15), "1e+15");
+        assert_eq!(luaO_num2str(123456789012344.0), "1.2345678901234e+14");
+        assert_eq!(luaO_num2str(1e-5), "1e-05");
+        assert_eq!(luaO_num2str(f64::INFINITY), "inf");
+        assert_eq!(luaO_num2str(f64::NEG_INFINITY), "-inf");
+        assert_eq!(luaO_num2str(-f64::NAN), "-nan");
+        assert_eq!(luaO_num2str_dot(1.0), "1.0");
+        assert_eq!(luaO_num2str_dot(-0.0), "-0.0");
+        assert_eq!(luaO_num2str_dot(1e100), "1e+100");
+        assert_eq!(luaO_num2str_dot(f64::INFINITY), "inf");
+        assert_eq!(luaO_int2str(-42), "-42");
+    }
+    #[test]
     fn test_utf8esc() {
         assert_eq!(luaO_utf8esc(0x41), vec![0x41]);
         assert_eq!(luaO_utf8esc(0x20AC), vec![0xE2, 0x82, 0xAC]);
@@ -517,14 +963,23 @@ mod lobject_ext_tests {
     }
     #[test]
     fn test_lobject_from() {
-        let i: LObject = 42i64.into();
-        let n: LObject = 3.14f64.into();
+        let i: LObject = (42 as LuaInteger).into();
+        let n: LObject = (3.14 as LuaFloat).into();
         let s: LObject = "bar".into();
         assert_eq!(i.as_integer(), Some(42));
         assert_eq!(n.as_number(), Some(3.14));
         assert_eq!(s.as_str(), Some("bar"));
     }
     #[test]
+    fn test_lobject_float_to_integer() {
+        assert_eq!(LObject::Number(3.0).as_integer(), Some(3));
+        assert_eq!(LObject::Number(3.5).as_integer(), None);
+        // 2^63 (2^31 with int32) is just out of range
+        let lim = -(crate::skylaconf::LUA_INTEGER_MIN as LuaFloat);
+        assert_eq!(LObject::Number(-lim).as_integer(), Some(crate::skylaconf::LUA_INTEGER_MIN));
+        assert_eq!(LObject::Number(lim).as_integer(), None);
+    }
+    #[test]
     fn test_lnode() {
         let mut node = lnode_new(LObject::Integer(1), LObject::String("v".into()));
         assert!(!node.key_is_dead);
@@ -539,3 +994,29 @@ mod lobject_ext_tests {
         assert_eq!(m.doc.as_deref(), Some("doc"));
     }
 }
+
+#[cfg(test)]
+mod lvalue_tests {
+    use super::*;
+    #[test]
+    fn test_lvalue_size() {
+        assert!(std::mem::size_of::<LValue>() <= 16);
+        assert!(std::mem::size_of::<LValue>() < std::mem::size_of::<LObject>());
+    }
+    #[test]
+    fn test_lvalue_pack_unpack() {
+        let mut heap = ValueHeap::new();
+        let a = heap.pack(&LObject::String("key".into()));
+        let b = heap.pack(&LObject::String("key".into()));
+        assert_eq!(a, b); // interned: same handle, compared without touching bytes
+        assert_eq!(heap.intern("key"), StrId(0));
+        assert_eq!(heap.str(StrId(0)), "key");
+        let t = heap.pack(&LObject::Table);
+        assert_eq!(heap.type_name(t), "table");
+        assert_eq!(heap.type_name(a), "string");
+        let n = heap.pack(&LObject::Number(2.0));
+        assert_eq!(n.as_integer(), Some(2));
+        assert_eq!(heap.unpack(a).as_str(), Some("key"));
+        assert!(!heap.pack(&LObject::Boolean(false)).is_truthy());
+    }
+}
@@ -0,0 +1,511 @@
+//! lopt.rs - Bytecode optimizer: constant folding and peephole pass
+//
+// Runs over a finished Proto (the instruction set of lvm) when the compile
+// options ask for it (`skyla -O`, LuaState::set_compile_options):
+//   - arithmetic on constants, or on registers just loaded with constants,
+//     becomes a LOADK of the result; as in lcode.c, division by zero and
+//     float results that are NaN or zero are left for run time;
+//   - consecutive LOADNILs over adjacent registers are merged;
+//   - GETGLOBAL of a global the host declared constant becomes LOADK,
+//     LOADBOOL or LOADNIL, unless the chunk assigns that global;
+//   - a call of a plugin intrinsic's global (GETGLOBAL f, argument code,
+//     CALL in one basic block, fixed argument and result counts) becomes
+//     LOADK of the intrinsic's id and CALLI, unless the chunk assigns f;
+//   - jumps to jumps go straight to the final target, and jumps to the next
+//     instruction are dropped.
+// Removing instructions keeps the jump offsets, lineinfo and local variable
+// ranges in step. Level 0 leaves the code untouched.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+
+use crate::lstate::LuaState;
+use crate::lvm::{luaV_arith, Instruction, LuaType, OpCode, Proto, TValue, BITRK};
+use crate::skylaconf::{LuaFloat, LuaInteger};
+
+/// Largest constant index LOADK can address (Bx)
+const MAXARG_BX: usize = 0x3FFFF;
+
+/// Largest B operand (LOADNIL range)
+const MAXARG_B: u16 = 0x1FF;
+
+/// Value of a global the host promises never changes
+#[derive(Debug, Clone, PartialEq)]
+pub enum KnownConst {
+    Nil,
+    Bool(bool),
+    Int(LuaInteger),
+    Num(LuaFloat),
+}
+
+/// Options for compiling chunks (shared by all threads of a state)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompileOptions {
+    /// 0: code as generated; 1: the passes of this module (-O)
+    pub opt_level: u8,
+    /// Globals turned into constants by the GETGLOBAL specialization
+    pub known_globals: HashMap<String, KnownConst>,
+    /// Globals bound to plugin intrinsics, by intrinsic id (lplugin)
+    pub intrinsics: HashMap<String, usize>,
+}
+
+impl CompileOptions {
+    /// The options of `skyla -O`
+    pub fn optimized() -> Self {
+        CompileOptions { opt_level: 1, ..Default::default() }
+    }
+}
+
+impl LuaState {
+    pub fn set_compile_options(&mut self, opts: CompileOptions) {
+        self.l_G.borrow_mut().compile = opts;
+    }
+
+    pub fn compile_options(&self) -> CompileOptions {
+        self.l_G.borrow().compile.clone()
+    }
+}
+
+/// Optimize `p` in place according to `opts`
+pub fn optimize(p: &mut Proto, opts: &CompileOptions) {
+    if opts.opt_level == 0 || p.code.is_empty() {
+        return;
+    }
+    let (targets, protected) = control_points(p);
+    specialize_globals(p, &opts.known_globals);
+    resolve_intrinsics(p, &opts.intrinsics, &targets);
+    fold_constants(p, &targets);
+    let mut removed = vec![false; p.code.len()];
+    merge_loadnils(p, &targets, &protected, &mut removed);
+    thread_jumps(p, &protected, &mut removed);
+    compact(p, &removed);
+    p.decoded.clear();
+}
+
+fn op_of(i: Instruction) -> OpCode {
+    OpCode::from_u8(i.get_opcode())
+}
+
+fn jump_target(p: &Proto, pc: usize) -> usize {
+    (pc as i64 + 1 + p.code[pc].get_arg_sbx() as i64) as usize
+}
+
+/// Instructions control can reach other than by falling through (jump
+/// targets and the instruction after a LOADBOOL or comparison skip), and
+/// instructions that must stay in place (the one such a skip steps over)
+fn control_points(p: &Proto) -> (HashSet<usize>, HashSet<usize>) {
+    let mut targets = HashSet::new();
+    let mut protected = HashSet::new();
+    for pc in 0..p.code.len() {
+        match op_of(p.code[pc]) {
+            OpCode::JMP | OpCode::TFORLOOP => {
+                targets.insert(jump_target(p, pc));
+            }
+            OpCode::EQ | OpCode::LT | OpCode::LE => {
+                protected.insert(pc + 1);
+                targets.insert(pc + 2);
+            }
+            OpCode::LOADBOOL if p.code[pc].get_arg_c() != 0 => {
+                protected.insert(pc + 1);
+                targets.insert(pc + 2);
+            }
+            _ => {}
+        }
+    }
+    (targets, protected)
+}
+
+fn k_string(p: &Proto, idx: usize) -> Option<String> {
+    let k = p.k.get(idx)?;
+    match k.tt {
+        LuaType::String => Some(unsafe { CStr::from_ptr(k.value.s) }.to_string_lossy().into_owned()),
+        _ => None,
+    }
+}
+
+fn same_const(a: &TValue, b: &TValue) -> bool {
+    unsafe {
+        match (a.tt, b.tt) {
+            (LuaType::Integer, LuaType::Integer) => a.value.i == b.value.i,
+            (LuaType::Number, LuaType::Number) => a.value.n.to_bits() == b.value.n.to_bits(),
+            (LuaType::Boolean, LuaType::Boolean) => a.value.b == b.value.b,
+            _ => false,
+        }
+    }
+}
+
+/// Index of `v` in the constant table, adding it if needed; None if LOADK
+/// cannot reach it
+fn add_k(p: &mut Proto, v: TValue) -> Option<usize> {
+    if let Some(i) = p.k.iter().position(|k| same_const(k, &v)) {
+        return Some(i);
+    }
+    if p.k.len() > MAXARG_BX {
+        return None;
+    }
+    p.k.push(v);
+    Some(p.k.len() - 1)
+}
+
+/// Names of the globals the chunk sets
+fn assigned_globals(p: &Proto) -> HashSet<String> {
+    (0..p.code.len())
+        .filter(|&pc| op_of(p.code[pc]) == OpCode::SETGLOBAL)
+        .filter_map(|pc| k_string(p, p.code[pc].get_arg_bx() as usize))
+        .collect()
+}
+
+/// GETGLOBAL name -> the host's constant, unless the chunk sets `name`
+fn specialize_globals(p: &mut Proto, known: &HashMap<String, KnownConst>) {
+    if known.is_empty() {
+        return;
+    }
+    let assigned = assigned_globals(p);
+    for pc in 0..p.code.len() {
+        let i = p.code[pc];
+        if op_of(i) != OpCode::GETGLOBAL {
+            continue;
+        }
+        let Some(name) = k_string(p, i.get_arg_bx() as usize) else { continue };
+        if assigned.contains(&name) {
+            continue;
+        }
+        let a = i.get_arg_a();
+        let new = match known.get(&name) {
+            None => continue,
+            Some(KnownConst::Nil) => Instruction::encode_abc(OpCode::LOADNIL, a, 0, 0),
+            Some(KnownConst::Bool(b)) => Instruction::encode_abc(OpCode::LOADBOOL, a, *b as u16, 0),
+            Some(KnownConst::Int(n)) => match add_k(p, TValue::from_integer(*n)) {
+                Some(k) => Instruction::encode_abx(OpCode::LOADK, a, k as u32),
+                None => continue,
+            },
+            Some(KnownConst::Num(n)) => match add_k(p, TValue::from_number(*n)) {
+                Some(k) => Instruction::encode_abx(OpCode::LOADK, a, k as u32),
+                None => continue,
+            },
+        };
+        p.code[pc] = new;
+    }
+}
+
+/// GETGLOBAL f ... CALL -> LOADK id ... CALLI for the intrinsics' globals
+fn resolve_intrinsics(p: &mut Proto, intrinsics: &HashMap<String, usize>, targets: &HashSet<usize>) {
+    if intrinsics.is_empty() {
+        return;
+    }
+    let assigned = assigned_globals(p);
+    for pc in 0..p.code.len() {
+        let i = p.code[pc];
+        if op_of(i) != OpCode::GETGLOBAL {
+            continue;
+        }
+        let Some(name) = k_string(p, i.get_arg_bx() as usize) else { continue };
+        let Some(&id) = intrinsics.get(&name) else { continue };
+        if assigned.contains(&name) {
+            continue;
+        }
+        let a = i.get_arg_a();
+        let Some(call) = intrinsic_call(p, pc, a, targets) else { continue };
+        let Some(k) = add_k(p, TValue::from_integer(id as LuaInteger)) else { continue };
+        let c = p.code[call];
+        p.code[pc] = Instruction::encode_abx(OpCode::LOADK, a, k as u32);
+        p.code[call] = Instruction::encode_abc(OpCode::CALLI, a, c.get_arg_b(), c.get_arg_c());
+    }
+}
+
+/// The CALL of the function the GETGLOBAL at `pc` loads into R(a), if it
+/// follows in the same basic block with fixed argument and result counts
+/// and the code in between (the arguments) only writes registers above a
+fn intrinsic_call(p: &Proto, pc: usize, a: u8, targets: &HashSet<usize>) -> Option<usize> {
+    for q in pc + 1..p.code.len() {
+        if targets.contains(&q) {
+            return None;
+        }
+        let i = p.code[q];
+        match op_of(i) {
+            OpCode::CALL if i.get_arg_a() == a => {
+                return (i.get_arg_b() != 0 && i.get_arg_c() != 0).then_some(q);
+            }
+            OpCode::SETTABLE | OpCode::SETGLOBAL | OpCode::SETLIST => {}
+            OpCode::LOADBOOL if i.get_arg_c() != 0 => return None,
+            OpCode::MOVE | OpCode::LOADK | OpCode::LOADBOOL | OpCode::LOADNIL | OpCode::GETUPVAL
+            | OpCode::GETGLOBAL | OpCode::GETTABLE | OpCode::CALL | OpCode::CALLI | OpCode::VARARG
+            | OpCode::ADD | OpCode::SUB | OpCode::MUL | OpCode::DIV | OpCode::MOD | OpCode::POW
+            | OpCode::UNM | OpCode::NEWTABLE | OpCode::SELF if i.get_arg_a() > a => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The value of a folded operation, if folding it is safe (constfolding)
+fn fold(op: OpCode, a: &TValue, b: &TValue) -> Option<TValue> {
+    let is_zero = |v: &TValue| unsafe {
+        match v.tt {
+            LuaType::Integer => v.value.i == 0,
+            LuaType::Number => v.value.n == 0.0,
+            _ => false,
+        }
+    };
+    if matches!(op, OpCode::DIV | OpCode::MOD) && is_zero(b) {
+        return None;
+    }
+    let r = luaV_arith(op, a, b).ok()?;
+    match r.tt {
+        LuaType::Number => {
+            let n = unsafe { r.value.n };
+            // NaN and -0.0 have no constant of their own
+            if n.is_nan() || n == 0.0 { None } else { Some(r) }
+        }
+        _ => Some(r),
+    }
+}
+
+/// Replace arithmetic on known constants by LOADK, within basic blocks
+fn fold_constants(p: &mut Proto, targets: &HashSet<usize>) {
+    // register -> index of the constant it holds
+    let mut known: HashMap<usize, usize> = HashMap::new();
+    for pc in 0..p.code.len() {
+        if targets.contains(&pc) {
+            known.clear();
+        }
+        let i = p.code[pc];
+        let a = i.get_arg_a() as usize;
+        let (b, c) = (i.get_arg_b() as usize, i.get_arg_c() as usize);
+        let operand = |known: &HashMap<usize, usize>, x: usize| {
+            if x & BITRK != 0 { Some(x & !BITRK) } else { known.get(&x).copied() }
+        };
+        match op_of(i) {
+            OpCode::LOADK => {
+                known.insert(a, i.get_arg_bx() as usize);
+            }
+            OpCode::MOVE => match known.get(&b).copied() {
+                Some(k) => { known.insert(a, k); }
+                None => { known.remove(&a); }
+            },
+            op @ (OpCode::ADD | OpCode::SUB | OpCode::MUL | OpCode::DIV | OpCode::MOD | OpCode::POW | OpCode::UNM) => {
+                let (kb, kc) = if op == OpCode::UNM {
+                    (known.get(&b).copied(), known.get(&b).copied())
+                } else {
+                    (operand(&known, b), operand(&known, c))
+                };
+                let value = match (kb, kc) {
+                    (Some(kb), Some(kc)) => fold(op, &p.k[kb], &p.k[kc]),
+                    _ => None,
+                };
+                match value.and_then(|v| add_k(p, v)) {
+                    Some(k) => {
+                        p.code[pc] = Instruction::encode_abx(OpCode::LOADK, a as u8, k as u32);
+                        known.insert(a, k);
+                    }
+                    None => { known.remove(&a); }
+                }
+            }
+            OpCode::LOADNIL => known.retain(|&r, _| r < a || r > a + b),
+            OpCode::SELF => known.retain(|&r, _| r != a && r != a + 1),
+            OpCode::LOADBOOL | OpCode::GETUPVAL | OpCode::GETGLOBAL | OpCode::GETTABLE | OpCode::NEWTABLE => {
+                known.remove(&a);
+            }
+            // calls and varargs write R(A) and everything above it
+            OpCode::CALL | OpCode::CALLI | OpCode::VARARG => known.retain(|&r, _| r < a),
+            OpCode::SETGLOBAL | OpCode::SETTABLE | OpCode::SETLIST | OpCode::EQ | OpCode::LT | OpCode::LE => {}
+            OpCode::JMP | OpCode::RETURN | OpCode::TFORCALL | OpCode::TFORLOOP => known.clear(),
+        }
+    }
+}
+
+/// Merge each LOADNIL into the LOADNIL just before it when their register
+/// ranges touch
+fn merge_loadnils(p: &mut Proto, targets: &HashSet<usize>, protected: &HashSet<usize>, removed: &mut [bool]) {
+    let mut prev: Option<usize> = None;
+    for (pc, gone) in removed.iter_mut().enumerate() {
+        let i = p.code[pc];
+        if op_of(i) != OpCode::LOADNIL {
+            prev = None;
+            continue;
+        }
+        if let Some(q) = prev.filter(|_| !targets.contains(&pc) && !protected.contains(&pc)) {
+            let (a1, b1) = (p.code[q].get_arg_a() as u16, p.code[q].get_arg_b());
+            let (a2, b2) = (i.get_arg_a() as u16, i.get_arg_b());
+            let (from, to) = (a1.min(a2), (a1 + b1).max(a2 + b2));
+            if a2 <= a1 + b1 + 1 && a1 <= a2 + b2 + 1 && from <= u8::MAX as u16 && to - from <= MAXARG_B {
+                p.code[q] = Instruction::encode_abc(OpCode::LOADNIL, from as u8, to - from, 0);
+                *gone = true;
+                continue;
+            }
+        }
+        prev = Some(pc);
+    }
+}
+
+/// Point jumps at their final destination and drop jumps to the next
+/// instruction
+fn thread_jumps(p: &mut Proto, protected: &HashSet<usize>, removed: &mut [bool]) {
+    let n = p.code.len();
+    // backwards, so a jump over jumps that were just dropped is dropped too
+    for pc in (0..n).rev() {
+        if removed[pc] || op_of(p.code[pc]) != OpCode::JMP {
+            continue;
+        }
+        let mut target = jump_target(p, pc);
+        // bounded, so a cycle of jumps cannot hang the compiler
+        for _ in 0..100 {
+            if target >= n || target == pc || op_of(p.code[target]) != OpCode::JMP {
+                break;
+            }
+            target = jump_target(p, target);
+        }
+        let a = p.code[pc].get_arg_a();
+        p.code[pc] = Instruction::encode_asbx(OpCode::JMP, a, target as i32 - pc as i32 - 1);
+        let next = (pc + 1..n).find(|&q| !removed[q]).unwrap_or(n);
+        if target == next && !protected.contains(&pc) {
+            removed[pc] = true;
+        }
+    }
+}
+
+/// Drop the removed instructions, fixing jumps, lineinfo and locvars
+fn compact(p: &mut Proto, removed: &[bool]) {
+    if !removed.iter().any(|&r| r) {
+        return;
+    }
+    let n = p.code.len();
+    // new index of each old pc; a removed pc maps to the next kept one
+    let mut new_index = vec![0usize; n + 1];
+    let mut kept = 0;
+    for pc in 0..n {
+        new_index[pc] = kept;
+        if !removed[pc] {
+            kept += 1;
+        }
+    }
+    new_index[n] = kept;
+    let mut code = Vec::with_capacity(kept);
+    for pc in 0..n {
+        if removed[pc] {
+            continue;
+        }
+        let mut i = p.code[pc];
+        if matches!(op_of(i), OpCode::JMP | OpCode::TFORLOOP) {
+            let target = new_index[jump_target(p, pc).min(n)];
+            i = Instruction::encode_asbx(op_of(i), i.get_arg_a(), target as i32 - new_index[pc] as i32 - 1);
+        }
+        code.push(i);
+    }
+    if p.lineinfo.len() == n {
+        let lines = p.lineinfo.lines();
+        p.lineinfo = (0..n).filter(|&pc| !removed[pc]).map(|pc| lines[pc]).collect();
+    }
+    for v in &mut p.locvars {
+        v.startpc = new_index[v.startpc.min(n)];
+        v.endpc = new_index[v.endpc.min(n)];
+    }
+    p.code = code;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proto(code: Vec<Instruction>, k: Vec<TValue>) -> Proto {
+        Proto {
+            lineinfo: (1..=code.len() as u32).collect(),
+            code,
+            k,
+            numparams: 0,
+            is_vararg: true,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        }
+    }
+
+    fn abc(op: OpCode, a: u8, b: u16, c: u16) -> Instruction {
+        Instruction::encode_abc(op, a, b, c)
+    }
+
+    #[test]
+    fn test_fold_arithmetic_chain() {
+        // r0 = 2 * 3; r0 = r0 + 1; r1 = 1 / 0 (kept)
+        let mut p = proto(vec![
+            abc(OpCode::MUL, 0, BITRK as u16, BITRK as u16 | 1),
+            abc(OpCode::ADD, 0, 0, BITRK as u16 | 2),
+            abc(OpCode::DIV, 1, BITRK as u16 | 2, BITRK as u16 | 3),
+            abc(OpCode::RETURN, 0, 2, 0),
+        ], vec![TValue::from_integer(2), TValue::from_integer(3), TValue::from_integer(1), TValue::from_integer(0)]);
+        optimize(&mut p, &CompileOptions::optimized());
+        assert_eq!(op_of(p.code[1]), OpCode::LOADK);
+        let k = &p.k[p.code[1].get_arg_bx() as usize];
+        assert!(matches!(k.tt, LuaType::Integer));
+        assert_eq!(unsafe { k.value.i }, 7);
+        assert_eq!(op_of(p.code[2]), OpCode::DIV);
+    }
+
+    #[test]
+    fn test_merge_loadnils_and_drop_jumps() {
+        let mut p = proto(vec![
+            abc(OpCode::LOADNIL, 0, 1, 0),
+            abc(OpCode::LOADNIL, 2, 0, 0),
+            Instruction::encode_asbx(OpCode::JMP, 0, 0),
+            Instruction::encode_asbx(OpCode::JMP, 0, 0),
+            abc(OpCode::RETURN, 0, 1, 0),
+        ], Vec::new());
+        optimize(&mut p, &CompileOptions::optimized());
+        // the first jump goes to the second, which goes to the RETURN
+        assert_eq!(p.code.len(), 2);
+        assert_eq!(op_of(p.code[0]), OpCode::LOADNIL);
+        assert_eq!((p.code[0].get_arg_a(), p.code[0].get_arg_b()), (0, 2));
+        assert_eq!(op_of(p.code[1]), OpCode::RETURN);
+        assert_eq!(p.lineinfo.lines(), vec![1, 5]);
+    }
+
+    #[test]
+    fn test_keep_jump_after_comparison() {
+        // if r0 == r1 then return end: the JMP a false EQ skips stays,
+        // even though it jumps to the next instruction
+        let mut p = proto(vec![
+            abc(OpCode::EQ, 0, 0, 1),
+            Instruction::encode_asbx(OpCode::JMP, 0, 0),
+            abc(OpCode::RETURN, 0, 1, 0),
+        ], Vec::new());
+        optimize(&mut p, &CompileOptions::optimized());
+        assert_eq!(p.code.len(), 3);
+        assert_eq!(op_of(p.code[1]), OpCode::JMP);
+    }
+
+    #[test]
+    fn test_resolve_intrinsics() {
+        // sq(2) is bound; sq(...) has no fixed argument count and stays a call
+        let name = TValue::from_lua(&crate::lobject::LuaValue::Str("sq".to_string()));
+        let mut p = proto(vec![
+            Instruction::encode_abx(OpCode::GETGLOBAL, 0, 0),
+            Instruction::encode_abx(OpCode::LOADK, 1, 1),
+            abc(OpCode::CALL, 0, 2, 2),
+            Instruction::encode_abx(OpCode::GETGLOBAL, 1, 0),
+            abc(OpCode::VARARG, 2, 0, 0),
+            abc(OpCode::CALL, 1, 0, 2),
+            abc(OpCode::RETURN, 0, 3, 0),
+        ], vec![name, TValue::from_integer(2)]);
+        let mut opts = CompileOptions::optimized();
+        opts.intrinsics.insert("sq".to_string(), 7);
+        optimize(&mut p, &opts);
+        assert_eq!(op_of(p.code[0]), OpCode::LOADK);
+        let k = &p.k[p.code[0].get_arg_bx() as usize];
+        assert_eq!(unsafe { k.value.i }, 7);
+        assert_eq!(op_of(p.code[2]), OpCode::CALLI);
+        assert_eq!((p.code[2].get_arg_a(), p.code[2].get_arg_b(), p.code[2].get_arg_c()), (0, 2, 2));
+        assert_eq!(op_of(p.code[3]), OpCode::GETGLOBAL);
+        assert_eq!(op_of(p.code[5]), OpCode::CALL);
+    }
+
+    #[test]
+    fn test_level_zero_keeps_code() {
+        let mut p = proto(vec![abc(OpCode::LOADNIL, 0, 0, 0), abc(OpCode::LOADNIL, 1, 0, 0)], Vec::new());
+        optimize(&mut p, &CompileOptions::default());
+        assert_eq!(p.code.len(), 2);
+    }
+}
@@ -1,50 +1,137 @@
 //! loslib.rs - Standard Operating System library for Lua (Rust port)
 // Provides OS and time functions for Lua scripts, similar to loslib.c
 
+#[cfg(not(feature = "minimal"))]
 use std::env;
+#[cfg(not(feature = "minimal"))]
 use std::fs;
+#[cfg(not(feature = "minimal"))]
+use std::io;
+#[cfg(not(feature = "minimal"))]
 use std::process::{Command, exit};
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::ffi::OsString;
+use std::path::PathBuf;
 use chrono::{Datelike, Timelike, Local, Utc, NaiveDateTime};
+use crate::lplatform::with_platform;
 
-// Placeholder for Lua state and API integration
-type LuaState = ();
+use crate::lauxlib::ExecStatus;
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
 
 // --- OS Functions ---
+// execute/remove/rename/tmpname/exit need a real OS and are left out of
+// `minimal` builds; clock/time/getenv go through the host platform.
 
-pub fn os_execute(cmd: Option<&str>) -> Result<i32, String> {
+/// The shell command for `cmd`
+#[cfg(not(feature = "minimal"))]
+pub fn shell_command(cmd: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(cmd);
+        c
+    }
+    #[cfg(not(windows))]
+    {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(cmd);
+        c
+    }
+}
+
+/// Run `cmd` through the shell with the environment `env`
+#[cfg(not(feature = "minimal"))]
+pub fn os_execute(cmd: Option<&str>, env: &crate::lplatform::EnvOverlay) -> io::Result<ExecStatus> {
     match cmd {
         Some(command) => {
-            let status = Command::new("sh").arg("-c").arg(command).status();
-            status.map(|s| s.code().unwrap_or(-1)).map_err(|e| e.to_string())
-        },
-        None => Ok(0), // true if there is a shell
+            let mut c = shell_command(command);
+            env.apply(&mut c);
+            c.status().map(ExecStatus::from_status)
+        }
+        None => Ok(ExecStatus::Exit(0)), // true if there is a shell
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+pub fn os_remove(filename: &str) -> io::Result<()> {
+    fs::remove_file(filename)
+}
+
+#[cfg(not(feature = "minimal"))]
+pub fn os_rename(from: &str, to: &str) -> io::Result<()> {
+    fs::rename(from, to)
+}
+
+/// How many names make_temp_file tries before giving up (TMP_MAX-like)
+#[cfg(not(feature = "minimal"))]
+const TEMP_ATTEMPTS: usize = 100;
+
+/// Create a new, empty file with an unpredictable name in the temporary
+/// directory, readable and writable only by its owner (mkstemp). The name
+/// is claimed by creating the file exclusively, so nobody can take it
+/// first or plant a link there between choosing and opening it.
+#[cfg(not(feature = "minimal"))]
+pub fn make_temp_file() -> io::Result<(PathBuf, fs::File)> {
+    let mut opts = fs::OpenOptions::new();
+    opts.read(true).write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut opts, 0o600);
+    for _ in 0..TEMP_ATTEMPTS {
+        let path = env::temp_dir().join(format!("lua_{:016x}", rand::random::<u64>()));
+        match opts.open(&path) {
+            Ok(f) => return Ok((path, f)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
     }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "no unused temporary name found"))
 }
 
-pub fn os_remove(filename: &str) -> Result<(), String> {
-    fs::remove_file(filename).map_err(|e| e.to_string())
+/// Name of a new empty temporary file (os.tmpname); the file exists, so
+/// the name cannot be taken by anyone else
+#[cfg(not(feature = "minimal"))]
+pub fn os_tmpname() -> io::Result<String> {
+    make_temp_file().map(|(path, _)| path.to_string_lossy().into_owned())
+}
+
+/// Temporary files made for a state (os.tmpname), removed when the state
+/// is dropped unless the script removed or renamed them already
+#[derive(Debug, Default)]
+pub struct TempFiles(Vec<PathBuf>);
+
+impl TempFiles {
+    pub fn register(&mut self, path: PathBuf) {
+        self.0.push(path);
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in self.0.drain(..) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
 }
 
-pub fn os_rename(from: &str, to: &str) -> Result<(), String> {
-    fs::rename(from, to).map_err(|e| e.to_string())
+/// Environment variable `var` as `state` sees it
+pub fn os_getenv(state: &LuaState, var: &str) -> Option<String> {
+    state.l_G.borrow().env.get(var)
 }
 
-pub fn os_tmpname() -> Result<String, String> {
-    let mut tmp = env::temp_dir();
-    tmp.push(format!("lua_{:x}", rand::random::<u64>()));
-    Ok(tmp.to_string_lossy().into_owned())
+/// Set or (with None) remove an environment variable of `state` and the
+/// commands it starts; the process environment is left alone
+pub fn os_setenv(state: &LuaState, name: &str, value: Option<&str>) -> std::io::Result<()> {
+    state.l_G.borrow_mut().env.set(name, value)
 }
 
-pub fn os_getenv(var: &str) -> Option<String> {
-    env::var(var).ok()
+/// All environment variables `state` sees, sorted by name
+pub fn os_environ(state: &LuaState) -> Vec<(String, String)> {
+    state.l_G.borrow().env.vars()
 }
 
 pub fn os_clock() -> f64 {
-    // Returns process time in seconds (not wall clock)
-    // Placeholder: returns wall clock time since UNIX_EPOCH
-    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+    // Processor time in seconds, as reported by the host platform
+    with_platform(|p| p.clock())
 }
 
 // --- Time/Date Functions ---
@@ -89,7 +176,7 @@ pub fn os_time(table: Option<&[(&str, i32)]>) -> i64 {
         ).unwrap();
         dt.timestamp()
     } else {
-        chrono::Local::now().timestamp()
+        with_platform(|p| p.time())
     }
 }
 
@@ -97,11 +184,27 @@ pub fn os_difftime(t1: i64, t2: i64) -> f64 {
     (t1 - t2) as f64
 }
 
+/// Monotonic nanoseconds, for measuring intervals (not a date)
+pub fn os_hrtime() -> i64 {
+    with_platform(|p| p.hrtime()) as i64
+}
+
+/// Seconds between two os_hrtime values
+pub fn os_hrdifftime(t1: i64, t2: i64) -> f64 {
+    t1.wrapping_sub(t2) as f64 / 1e9
+}
+
+/// Block for `secs` seconds (fractions allowed)
+pub fn os_sleep(secs: f64) {
+    with_platform(|p| p.sleep(std::time::Duration::from_secs_f64(secs)));
+}
+
 pub fn os_setlocale(_locale: Option<&str>, _category: Option<&str>) -> Option<String> {
     // Not implemented: locale setting is platform-specific
     None
 }
 
+#[cfg(not(feature = "minimal"))]
 pub fn os_exit(status: Option<i32>) -> ! {
     exit(status.unwrap_or(0));
 }
@@ -140,27 +243,295 @@ pub fn os_now_local() -> i64 {
     chrono::Local::now().timestamp()
 }
 
-/// Struct for easy Lua registration (future integration)
+// --- Lua bindings ---
+
+// os.getenv(varname)
+fn os_lua_getenv(state: &mut LuaState) -> i32 {
+    let var = state.check_string(1);
+    state.push(os_getenv(state, &var).map_or(LuaValue::Nil, LuaValue::Str));
+    1
+}
+
+// os.clock()
+fn os_lua_clock(state: &mut LuaState) -> i32 {
+    let clock = state.virtual_clock().unwrap_or_else(os_clock);
+    state.push(LuaValue::Float(clock));
+    1
+}
+
+// os.time([table])
+fn os_lua_time(state: &mut LuaState) -> i32 {
+    if state.is_none_or_nil(1) {
+        let now = state.virtual_time().unwrap_or_else(|| os_time(None));
+        state.push(LuaValue::Int(now));
+        return 1;
+    }
+    let LuaValue::Table(t) = state.to_value(1) else {
+        state.type_error(1, "table");
+    };
+    let mut fields = Vec::new();
+    for key in ["year", "month", "day", "hour", "min", "sec"] {
+        match t.borrow().get(&LuaValue::Str(key.to_string())) {
+            Some(LuaValue::Int(v)) => fields.push((key, *v as i32)),
+            Some(LuaValue::Float(v)) if v.fract() == 0.0 => fields.push((key, *v as i32)),
+            None | Some(LuaValue::Nil) if matches!(key, "day" | "month" | "year") => {
+                state.error(&format!("field '{}' missing in date table", key));
+            }
+            None | Some(LuaValue::Nil) => {}
+            Some(_) => {
+                state.error(&format!("field '{}' is not an integer", key));
+            }
+        }
+    }
+    state.push(LuaValue::Int(os_time(Some(&fields))));
+    1
+}
+
+// os.difftime(t2, t1 [, "ns"]); with "ns" both are os.hrtime values
+fn os_lua_difftime(state: &mut LuaState) -> i32 {
+    let t2 = state.check_integer(1);
+    let t1 = state.opt_integer(2, 0);
+    let d = match state.check_option(3, Some("s"), &["s", "ns"]) {
+        1 => os_hrdifftime(t2, t1),
+        _ => os_difftime(t2, t1),
+    };
+    state.push(LuaValue::Float(d));
+    1
+}
+
+// os.date([format [, time]]); a leading '!' selects UTC
+fn os_lua_date(state: &mut LuaState) -> i32 {
+    let fmt = state.opt_string(1, "%c");
+    let t = if state.is_none_or_nil(2) { state.virtual_time() } else { Some(state.check_integer(2)) };
+    let (fmt, utc) = match fmt.strip_prefix('!') {
+        Some(rest) => (rest.to_string(), true),
+        None => (fmt, false),
+    };
+    state.push(LuaValue::Str(os_date(Some(&fmt), t, utc)));
+    1
+}
+
+// os.setlocale([locale [, category]])
+fn os_lua_setlocale(state: &mut LuaState) -> i32 {
+    const CATNAMES: &[&str] = &["all", "collate", "ctype", "monetary", "numeric", "time"];
+    let locale = if state.is_none_or_nil(1) { None } else { Some(state.check_string(1)) };
+    let cat = state.check_option(2, Some("all"), CATNAMES);
+    state.push(os_setlocale(locale.as_deref(), Some(CATNAMES[cat])).map_or(LuaValue::Nil, LuaValue::Str));
+    1
+}
+
+// os.execute([command])
+#[cfg(not(feature = "minimal"))]
+fn os_lua_execute(state: &mut LuaState) -> i32 {
+    let cmd = if state.is_none_or_nil(1) { None } else { Some(state.check_string(1)) };
+    let allowed = state.sandbox().allow_process;
+    if cmd.is_none() {
+        // without a command, report whether a shell is available
+        state.push(LuaValue::Bool(allowed));
+        return 1;
+    }
+    if !state.check_sandbox(allowed, "execute") {
+        return 0;
+    }
+    let res = os_execute(cmd.as_deref(), &state.l_G.borrow().env);
+    state.exec_result(res)
+}
+
+// os.remove(filename)
+#[cfg(not(feature = "minimal"))]
+fn os_lua_remove(state: &mut LuaState) -> i32 {
+    let filename = state.check_string(1);
+    let res = os_remove(&filename);
+    state.file_result(res, Some(&filename))
+}
+
+// os.rename(oldname, newname)
+#[cfg(not(feature = "minimal"))]
+fn os_lua_rename(state: &mut LuaState) -> i32 {
+    let from = state.check_string(1);
+    let to = state.check_string(2);
+    let res = os_rename(&from, &to);
+    state.file_result(res, Some(&from))
+}
+
+// os.tmpname()
+#[cfg(not(feature = "minimal"))]
+fn os_lua_tmpname(state: &mut LuaState) -> i32 {
+    match os_tmpname() {
+        Ok(name) => {
+            state.l_G.borrow_mut().temp_files.register(PathBuf::from(&name));
+            state.push(LuaValue::Str(name));
+            1
+        }
+        Err(_) => state.error("unable to generate a unique filename"),
+    }
+}
+
+// os.exit([code])
+#[cfg(not(feature = "minimal"))]
+fn os_lua_exit(state: &mut LuaState) -> i32 {
+    let status = match state.to_value(1) {
+        LuaValue::Nil | LuaValue::Bool(true) => 0,
+        LuaValue::Bool(false) => 1,
+        _ => state.check_integer(1) as i32,
+    };
+    os_exit(Some(status))
+}
+
+// os.setenv(name, value): Skyla extension; sets the variable for this state
+// and the commands it starts (a nil value removes it)
+#[cfg(feature = "skyla_ext")]
+fn os_lua_setenv(state: &mut LuaState) -> i32 {
+    let name = state.check_string(1);
+    let value = if state.is_none_or_nil(2) { None } else { Some(state.check_string(2)) };
+    let allowed = state.sandbox().allow_environment;
+    if !state.check_sandbox(allowed, "setenv") {
+        return 0;
+    }
+    let res = os_setenv(state, &name, value.as_deref());
+    state.file_result(res, Some(&name))
+}
+
+// os.hrtime(): Skyla extension; monotonic nanoseconds as an integer
+#[cfg(feature = "skyla_ext")]
+fn os_lua_hrtime(state: &mut LuaState) -> i32 {
+    let now = state.virtual_clock().map_or_else(os_hrtime, |c| (c * 1e9) as i64);
+    state.push(LuaValue::Int(now));
+    1
+}
+
+// os.sleep(seconds): Skyla extension
+#[cfg(feature = "skyla_ext")]
+fn os_lua_sleep(state: &mut LuaState) -> i32 {
+    let secs = state.check_number(1);
+    if !(0.0..=1e9).contains(&secs) {
+        state.arg_error(1, "invalid duration");
+    }
+    os_sleep(secs);
+    0
+}
+
+// os.environ(): Skyla extension; a table of all environment variables
+#[cfg(feature = "skyla_ext")]
+fn os_lua_environ(state: &mut LuaState) -> i32 {
+    let allowed = state.sandbox().allow_environment;
+    if !state.check_sandbox(allowed, "environ") {
+        return 0;
+    }
+    let vars = os_environ(state);
+    let t = state.create_table(0, vars.len());
+    for (k, v) in vars {
+        t.borrow_mut().rawset(&LuaValue::Str(k), LuaValue::Str(v));
+    }
+    state.push(LuaValue::Table(t));
+    1
+}
+
+const OS_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("getenv", os_lua_getenv),
+    ("clock", os_lua_clock),
+    ("time", os_lua_time),
+    ("difftime", os_lua_difftime),
+    ("date", os_lua_date),
+    ("setlocale", os_lua_setlocale),
+    #[cfg(not(feature = "minimal"))]
+    ("execute", os_lua_execute),
+    #[cfg(not(feature = "minimal"))]
+    ("remove", os_lua_remove),
+    #[cfg(not(feature = "minimal"))]
+    ("rename", os_lua_rename),
+    #[cfg(not(feature = "minimal"))]
+    ("tmpname", os_lua_tmpname),
+    #[cfg(not(feature = "minimal"))]
+    ("exit", os_lua_exit),
+];
+
+// Skyla extensions to the os library (enabled with the `skyla_ext` feature)
+#[cfg(feature = "skyla_ext")]
+const OS_EXT_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("setenv", os_lua_setenv),
+    ("environ", os_lua_environ),
+    ("hrtime", os_lua_hrtime),
+    ("sleep", os_lua_sleep),
+];
+
+/// Struct for easy Lua registration
 pub struct OsLib;
 
 impl OsLib {
-    pub fn register(_L: &mut LuaState) {
-        // Register all functions to Lua state here
+    pub fn register(state: &mut LuaState) {
+        luaopen_os(state);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lplatform::EnvOverlay;
+    use crate::lstate::GlobalState;
     #[test]
+    #[cfg(not(feature = "minimal"))]
     fn test_tmpname() {
         let name = os_tmpname().unwrap();
         assert!(name.contains("lua_"));
+        // the name is taken: the file exists, and a second name differs
+        assert_eq!(fs::metadata(&name).unwrap().len(), 0);
+        let other = os_tmpname().unwrap();
+        assert_ne!(other, name);
+        let _ = fs::remove_file(&other);
+        let mut files = TempFiles::default();
+        files.register(PathBuf::from(&name));
+        drop(files);
+        assert!(fs::metadata(&name).is_err());
+    }
+    #[test]
+    #[cfg(unix)]
+    fn test_execute_status() {
+        let env = EnvOverlay::default();
+        assert_eq!(os_execute(Some("exit 3"), &env).unwrap(), ExecStatus::Exit(3));
+        assert_eq!(os_execute(Some("kill -9 $$"), &env).unwrap(), ExecStatus::Signal(9));
+        assert!(os_execute(Some("true"), &env).unwrap().success());
     }
     #[test]
     fn test_getenv() {
-        std::env::set_var("LUA_TEST_ENV", "ok");
-        assert_eq!(os_getenv("LUA_TEST_ENV"), Some("ok".to_string()));
+        let state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        assert_eq!(os_getenv(&state, "PATH"), with_platform(|p| p.getenv("PATH")));
+    }
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn test_setenv_environ() {
+        let state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let other = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        os_setenv(&state, "LUA_TEST_SETENV", Some("1")).unwrap();
+        assert_eq!(os_getenv(&state, "LUA_TEST_SETENV"), Some("1".to_string()));
+        assert!(os_environ(&state).iter().any(|(k, v)| k == "LUA_TEST_SETENV" && v == "1"));
+        // the variable belongs to the state, not the process
+        assert_eq!(os_getenv(&other, "LUA_TEST_SETENV"), None);
+        assert!(std::env::var_os("LUA_TEST_SETENV").is_none());
+        #[cfg(unix)]
+        {
+            let env = state.l_G.borrow().env.clone();
+            assert!(os_execute(Some("test \"$LUA_TEST_SETENV\" = 1"), &env).unwrap().success());
+        }
+        os_setenv(&state, "LUA_TEST_SETENV", None).unwrap();
+        assert_eq!(os_getenv(&state, "LUA_TEST_SETENV"), None);
+        // removing hides a variable of the process
+        if let Some((name, _)) = os_environ(&state).into_iter().next() {
+            os_setenv(&state, &name, None).unwrap();
+            assert_eq!(os_getenv(&state, &name), None);
+            assert!(os_environ(&state).iter().all(|(k, _)| *k != name));
+        }
+        assert!(os_setenv(&state, "BAD=NAME", Some("x")).is_err());
+    }
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn test_hrtime_sleep() {
+        let t0 = os_hrtime();
+        os_sleep(0.0005);
+        let d = os_hrdifftime(os_hrtime(), t0);
+        assert!(d >= 0.0005 && d < 1.0);
     }
     #[test]
     fn test_time() {
@@ -186,7 +557,13 @@ mod completeness_tests {
     }
 }
 
-// --- Registration stub for Lua integration ---
-pub fn luaopen_os(_L: &mut LuaState) {
-    // Register all above functions to the Lua state
+// --- Registration for Lua integration ---
+pub fn luaopen_os(state: &mut LuaState) {
+    for &(name, f) in OS_FUNCS {
+        state.register_lib_function("os", name, f);
+    }
+    #[cfg(feature = "skyla_ext")]
+    for &(name, f) in OS_EXT_FUNCS {
+        state.register_lib_function("os", name, f);
+    }
 }
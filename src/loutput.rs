@@ -0,0 +1,178 @@
+//! loutput.rs - Where a state's standard output goes (print, io.write)
+//
+// print and io.write write through the state's OutputSink instead of the
+// process's stdout, so an embedder can capture what a script prints
+// (CaptureSink) and a host running many states can tell their output apart
+// (TaggedSink). The default is line-buffered output to the platform's
+// stdout (lplatform), flushed at each newline and by flush_output.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use crate::lplatform::with_platform;
+use crate::lstate::LuaState;
+
+/// Destination of a state's standard output
+pub trait OutputSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Push out anything held back
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Line-buffered writes to the platform's stdout
+#[derive(Default)]
+pub struct StdoutSink {
+    buf: Vec<u8>,
+}
+
+impl OutputSink for StdoutSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buf.extend_from_slice(data);
+        if let Some(end) = self.buf.iter().rposition(|&c| c == b'\n') {
+            let rest = self.buf.split_off(end + 1);
+            with_platform(|p| p.write_stdout(&self.buf));
+            self.buf = rest;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            with_platform(|p| p.write_stdout(&self.buf));
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StdoutSink {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Keeps everything written; clones share the buffer, so the host holds
+/// one clone and gives the state another
+#[derive(Clone, Default)]
+pub struct CaptureSink(Rc<RefCell<Vec<u8>>>);
+
+impl CaptureSink {
+    /// What was written so far, leaving the buffer empty
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.borrow_mut())
+    }
+
+    /// take() as text (invalid UTF-8 is replaced)
+    pub fn take_string(&self) -> String {
+        String::from_utf8_lossy(&self.take()).into_owned()
+    }
+}
+
+impl OutputSink for CaptureSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().extend_from_slice(data);
+        Ok(())
+    }
+}
+
+/// Prefixes every line written to `inner` with a tag, e.g. "[worker 3] "
+pub struct TaggedSink {
+    tag: String,
+    inner: Box<dyn OutputSink>,
+    at_line_start: bool,
+}
+
+impl TaggedSink {
+    pub fn new(tag: &str, inner: Box<dyn OutputSink>) -> Self {
+        TaggedSink { tag: tag.to_string(), inner, at_line_start: true }
+    }
+}
+
+impl OutputSink for TaggedSink {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        for line in data.split_inclusive(|&c| c == b'\n') {
+            if self.at_line_start {
+                self.inner.write(self.tag.as_bytes())?;
+            }
+            self.inner.write(line)?;
+            self.at_line_start = line.ends_with(b"\n");
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A state's sink (GlobalState::output)
+#[derive(Clone)]
+pub struct OutputHandle(pub Rc<RefCell<Box<dyn OutputSink>>>);
+
+impl Default for OutputHandle {
+    fn default() -> Self {
+        OutputHandle(Rc::new(RefCell::new(Box::new(StdoutSink::default()))))
+    }
+}
+
+impl fmt::Debug for OutputHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OutputHandle")
+    }
+}
+
+impl LuaState {
+    /// Send this state's print and io.write output to `sink` (shared by
+    /// all its threads); what the previous sink held back is flushed first.
+    /// The sink is replaced inside the handle, so io's standard output
+    /// file, which holds the handle, follows.
+    pub fn set_output(&mut self, sink: Box<dyn OutputSink>) {
+        let _ = self.flush_output();
+        let out = self.l_G.borrow().output.clone();
+        *out.0.borrow_mut() = sink;
+    }
+
+    /// Write to this state's standard output
+    pub fn write_output(&mut self, data: &[u8]) -> io::Result<()> {
+        let out = self.l_G.borrow().output.clone();
+        let mut sink = out.0.borrow_mut();
+        sink.write(data)
+    }
+
+    /// Flush this state's standard output (io.flush, the REPL prompt)
+    pub fn flush_output(&mut self) -> io::Result<()> {
+        let out = self.l_G.borrow().output.clone();
+        let mut sink = out.0.borrow_mut();
+        sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lstate::GlobalState;
+
+    #[test]
+    fn test_capture_and_tag() {
+        let capture = CaptureSink::default();
+        let mut tagged = TaggedSink::new("[a] ", Box::new(capture.clone()));
+        tagged.write(b"one\ntw").unwrap();
+        tagged.write(b"o\n\nthree").unwrap();
+        assert_eq!(capture.take_string(), "[a] one\n[a] two\n[a] \n[a] three");
+        assert!(capture.take().is_empty());
+    }
+
+    #[test]
+    fn test_state_output_goes_to_its_sink() {
+        let mut L = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let capture = CaptureSink::default();
+        L.set_output(Box::new(capture.clone()));
+        L.write_output(b"hello\t1\n").unwrap();
+        L.flush_output().unwrap();
+        assert_eq!(capture.take_string(), "hello\t1\n");
+    }
+}
@@ -0,0 +1,410 @@
+//! lpack.rs - Compact binary serialization of Lua values (skyla.pack)
+//
+// The format is MessagePack (nil, booleans, integers, float64, str, map)
+// with two extension types so that whole value graphs round-trip:
+//
+//   fixext4 type 1: reference to the n-th table serialized so far
+//                   (shared tables and cycles are preserved)
+//   fixext4 type 2: reference to the n-th interned string
+//
+// Every str of at least PACK_INTERN_MIN_LEN bytes is entered in the string
+// table by both sides, so a decoder needs no flag to follow interning. Data
+// without extension values is plain MessagePack readable by other tools.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::ltable::Table;
+use crate::ltm::obj_typename;
+
+/// Extension type of a table back-reference
+pub const PACK_EXT_TABLEREF: i8 = 1;
+/// Extension type of an interned-string reference
+pub const PACK_EXT_STRREF: i8 = 2;
+/// Shorter strings are never interned (a reference would not be smaller)
+pub const PACK_INTERN_MIN_LEN: usize = 6;
+/// Default nesting limit
+pub const PACK_DEFAULT_MAX_DEPTH: usize = 200;
+
+/// Options for `pack_value` / `unpack_value`
+#[derive(Debug, Clone)]
+pub struct PackOptions {
+    /// Deepest table nesting accepted
+    pub max_depth: usize,
+    /// Replace repeated strings by references
+    pub intern_strings: bool,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions { max_depth: PACK_DEFAULT_MAX_DEPTH, intern_strings: true }
+    }
+}
+
+/// Serialization error
+#[derive(Debug, Clone, PartialEq)]
+pub enum PackError {
+    TooDeep,
+    Unsupported(&'static str),
+    Truncated,
+    BadTag(u8),
+    BadTableRef(u32),
+    BadStringRef(u32),
+    BadString,
+    TrailingData,
+}
+
+impl std::fmt::Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackError::TooDeep => write!(f, "nesting too deep"),
+            PackError::Unsupported(t) => write!(f, "cannot serialize a {} value", t),
+            PackError::Truncated => write!(f, "truncated data"),
+            PackError::BadTag(t) => write!(f, "unsupported type byte 0x{:02x}", t),
+            PackError::BadTableRef(i) => write!(f, "bad table reference {}", i),
+            PackError::BadStringRef(i) => write!(f, "bad string reference {}", i),
+            PackError::BadString => write!(f, "invalid UTF-8 string"),
+            PackError::TrailingData => write!(f, "extra bytes after value"),
+        }
+    }
+}
+
+type TableRef = Rc<RefCell<Table>>;
+
+struct Packer<'a> {
+    opts: &'a PackOptions,
+    out: Vec<u8>,
+    tables: HashMap<*const RefCell<Table>, u32>,
+    strings: HashMap<String, u32>,
+    nstrings: u32,
+}
+
+impl Packer<'_> {
+    fn ext_ref(&mut self, ty: i8, id: u32) {
+        self.out.push(0xd6);
+        self.out.push(ty as u8);
+        self.out.extend_from_slice(&id.to_be_bytes());
+    }
+
+    fn int(&mut self, i: i64) {
+        match i {
+            0..=0x7f => self.out.push(i as u8),
+            -32..=-1 => self.out.push(i as i8 as u8),
+            -0x80..=0x7f => { self.out.push(0xd0); self.out.push(i as i8 as u8); }
+            -0x8000..=0x7fff => { self.out.push(0xd1); self.out.extend_from_slice(&(i as i16).to_be_bytes()); }
+            -0x8000_0000..=0x7fff_ffff => { self.out.push(0xd2); self.out.extend_from_slice(&(i as i32).to_be_bytes()); }
+            _ => { self.out.push(0xd3); self.out.extend_from_slice(&i.to_be_bytes()); }
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        if self.opts.intern_strings {
+            if let Some(&id) = self.strings.get(s) {
+                return self.ext_ref(PACK_EXT_STRREF, id);
+            }
+        }
+        if s.len() >= PACK_INTERN_MIN_LEN {
+            // Numbered whether or not interning is on, to match the decoder
+            self.strings.insert(s.to_string(), self.nstrings);
+            self.nstrings += 1;
+        }
+        let n = s.len();
+        if n < 32 {
+            self.out.push(0xa0 | n as u8);
+        } else if n <= 0xff {
+            self.out.push(0xd9);
+            self.out.push(n as u8);
+        } else if n <= 0xffff {
+            self.out.push(0xda);
+            self.out.extend_from_slice(&(n as u16).to_be_bytes());
+        } else {
+            self.out.push(0xdb);
+            self.out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn value(&mut self, v: &LuaValue, depth: usize) -> Result<(), PackError> {
+        match v {
+            LuaValue::Nil => self.out.push(0xc0),
+            LuaValue::Bool(false) => self.out.push(0xc2),
+            LuaValue::Bool(true) => self.out.push(0xc3),
+            LuaValue::Int(i) => self.int(*i),
+            LuaValue::Float(f) => { self.out.push(0xcb); self.out.extend_from_slice(&f.to_be_bytes()); }
+            LuaValue::Str(s) => self.string(s),
+            LuaValue::Table(t) => self.table(t, depth)?,
+            other => return Err(PackError::Unsupported(obj_typename(other))),
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, t: &TableRef, depth: usize) -> Result<(), PackError> {
+        if let Some(&id) = self.tables.get(&Rc::as_ptr(t)) {
+            self.ext_ref(PACK_EXT_TABLEREF, id);
+            return Ok(());
+        }
+        if depth >= self.opts.max_depth {
+            return Err(PackError::TooDeep);
+        }
+        let id = self.tables.len() as u32;
+        self.tables.insert(Rc::as_ptr(t), id);
+        let pairs: Vec<(LuaValue, LuaValue)> = t.borrow().pairs().map(|(k, v)| (k, v.clone())).collect();
+        let n = pairs.len();
+        if n < 16 {
+            self.out.push(0x80 | n as u8);
+        } else if n <= 0xffff {
+            self.out.push(0xde);
+            self.out.extend_from_slice(&(n as u16).to_be_bytes());
+        } else {
+            self.out.push(0xdf);
+            self.out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        for (k, v) in &pairs {
+            self.value(k, depth + 1)?;
+            self.value(v, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+struct Unpacker<'a> {
+    opts: &'a PackOptions,
+    data: &'a [u8],
+    pos: usize,
+    tables: Vec<TableRef>,
+    strings: Vec<String>,
+}
+
+impl<'a> Unpacker<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], PackError> {
+        let end = self.pos.checked_add(n).ok_or(PackError::Truncated)?;
+        let b = self.data.get(self.pos..end).ok_or(PackError::Truncated)?;
+        self.pos = end;
+        Ok(b)
+    }
+
+    fn be<const N: usize>(&mut self) -> Result<[u8; N], PackError> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    fn string(&mut self, n: usize) -> Result<LuaValue, PackError> {
+        let s = std::str::from_utf8(self.bytes(n)?).map_err(|_| PackError::BadString)?.to_string();
+        if s.len() >= PACK_INTERN_MIN_LEN {
+            self.strings.push(s.clone());
+        }
+        Ok(LuaValue::Str(s))
+    }
+
+    fn map(&mut self, n: usize, depth: usize) -> Result<LuaValue, PackError> {
+        if depth >= self.opts.max_depth {
+            return Err(PackError::TooDeep);
+        }
+        let t = Rc::new(RefCell::new(Table::new()));
+        // Registered before its contents so that cycles resolve
+        self.tables.push(t.clone());
+        for _ in 0..n {
+            let k = self.value(depth + 1)?;
+            let v = self.value(depth + 1)?;
+            t.borrow_mut().rawset(&k, v);
+        }
+        Ok(LuaValue::Table(t))
+    }
+
+    // Arrays are never written by `pack_value` but are read as sequences
+    fn array(&mut self, n: usize, depth: usize) -> Result<LuaValue, PackError> {
+        if depth >= self.opts.max_depth {
+            return Err(PackError::TooDeep);
+        }
+        let t = Rc::new(RefCell::new(Table::new()));
+        self.tables.push(t.clone());
+        for i in 1..=n {
+            let v = self.value(depth + 1)?;
+            t.borrow_mut().rawset(&LuaValue::Int(i as i64), v);
+        }
+        Ok(LuaValue::Table(t))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<LuaValue, PackError> {
+        let tag = self.be::<1>()?[0];
+        Ok(match tag {
+            0x00..=0x7f => LuaValue::Int(tag as i64),
+            0xe0..=0xff => LuaValue::Int(tag as i8 as i64),
+            0xc0 => LuaValue::Nil,
+            0xc2 => LuaValue::Bool(false),
+            0xc3 => LuaValue::Bool(true),
+            0xcc => LuaValue::Int(self.be::<1>()?[0] as i64),
+            0xcd => LuaValue::Int(u16::from_be_bytes(self.be()?) as i64),
+            0xce => LuaValue::Int(u32::from_be_bytes(self.be()?) as i64),
+            0xcf => LuaValue::Int(u64::from_be_bytes(self.be()?) as i64),
+            0xd0 => LuaValue::Int(self.be::<1>()?[0] as i8 as i64),
+            0xd1 => LuaValue::Int(i16::from_be_bytes(self.be()?) as i64),
+            0xd2 => LuaValue::Int(i32::from_be_bytes(self.be()?) as i64),
+            0xd3 => LuaValue::Int(i64::from_be_bytes(self.be()?)),
+            0xca => LuaValue::Float(f32::from_be_bytes(self.be()?) as f64),
+            0xcb => LuaValue::Float(f64::from_be_bytes(self.be()?)),
+            0xa0..=0xbf => self.string((tag & 0x1f) as usize)?,
+            0xd9 => { let n = self.be::<1>()?[0] as usize; self.string(n)? }
+            0xda => { let n = u16::from_be_bytes(self.be()?) as usize; self.string(n)? }
+            0xdb => { let n = u32::from_be_bytes(self.be()?) as usize; self.string(n)? }
+            0x80..=0x8f => self.map((tag & 0x0f) as usize, depth)?,
+            0xde => { let n = u16::from_be_bytes(self.be()?) as usize; self.map(n, depth)? }
+            0xdf => { let n = u32::from_be_bytes(self.be()?) as usize; self.map(n, depth)? }
+            0x90..=0x9f => self.array((tag & 0x0f) as usize, depth)?,
+            0xdc => { let n = u16::from_be_bytes(self.be()?) as usize; self.array(n, depth)? }
+            0xdd => { let n = u32::from_be_bytes(self.be()?) as usize; self.array(n, depth)? }
+            0xd6 => {
+                let ty = self.be::<1>()?[0] as i8;
+                let id = u32::from_be_bytes(self.be()?);
+                match ty {
+                    PACK_EXT_TABLEREF => self.tables.get(id as usize).cloned()
+                        .map(LuaValue::Table).ok_or(PackError::BadTableRef(id))?,
+                    PACK_EXT_STRREF => self.strings.get(id as usize).cloned()
+                        .map(LuaValue::Str).ok_or(PackError::BadStringRef(id))?,
+                    _ => return Err(PackError::BadTag(tag)),
+                }
+            }
+            _ => return Err(PackError::BadTag(tag)),
+        })
+    }
+}
+
+/// Serialize `v` and everything reachable from it
+pub fn pack_value(v: &LuaValue, opts: &PackOptions) -> Result<Vec<u8>, PackError> {
+    let mut p = Packer { opts, out: Vec::new(), tables: HashMap::new(), strings: HashMap::new(), nstrings: 0 };
+    p.value(v, 0)?;
+    Ok(p.out)
+}
+
+/// Rebuild a value serialized by `pack_value` (or plain MessagePack)
+pub fn unpack_value(data: &[u8], opts: &PackOptions) -> Result<LuaValue, PackError> {
+    let mut u = Unpacker { opts, data, pos: 0, tables: Vec::new(), strings: Vec::new() };
+    let v = u.value(0)?;
+    if u.pos != data.len() {
+        return Err(PackError::TrailingData);
+    }
+    Ok(v)
+}
+
+// Lua strings are Rust Strings in this VM, so binary data crosses the
+// boundary as one char (U+0000..U+00FF) per byte
+fn bytes_to_lua(b: &[u8]) -> String {
+    b.iter().map(|&c| c as char).collect()
+}
+
+fn lua_to_bytes(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c as u32).ok()).collect()
+}
+
+fn pack_options(state: &mut LuaState, arg: i32) -> PackOptions {
+    let mut opts = PackOptions::default();
+    if let LuaValue::Table(t) = state.to_value(arg) {
+        let t = t.borrow();
+        if let Some(LuaValue::Int(n)) = t.get(&LuaValue::Str("max_depth".to_string())) {
+            opts.max_depth = (*n).max(1) as usize;
+        }
+        if let Some(LuaValue::Bool(b)) = t.get(&LuaValue::Str("intern".to_string())) {
+            opts.intern_strings = *b;
+        }
+    }
+    opts
+}
+
+// skyla.pack.encode(value [, options]) -> string | nil, message
+fn pack_lua_encode(state: &mut LuaState) -> i32 {
+    let opts = pack_options(state, 2);
+    match pack_value(&state.to_value(1), &opts) {
+        Ok(b) => { state.push(LuaValue::Str(bytes_to_lua(&b))); 1 }
+        Err(e) => { state.push(LuaValue::Nil); state.push(LuaValue::Str(e.to_string())); 2 }
+    }
+}
+
+// skyla.pack.decode(string [, options]) -> value | nil, message
+fn pack_lua_decode(state: &mut LuaState) -> i32 {
+    let s = state.opt_string(1, "");
+    let opts = pack_options(state, 2);
+    let Some(bytes) = lua_to_bytes(&s) else {
+        state.arg_error(1, "not a packed string");
+    };
+    match unpack_value(&bytes, &opts) {
+        Ok(v) => { state.push(v); 1 }
+        Err(e) => { state.push(LuaValue::Nil); state.push(LuaValue::Str(e.to_string())); 2 }
+    }
+}
+
+const PACK_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("encode", pack_lua_encode),
+    ("decode", pack_lua_decode),
+];
+
+/// Register the `skyla.pack` module
+pub fn open_pack_lib(state: &mut LuaState) {
+    for &(name, f) in PACK_FUNCS {
+        state.register_lib_function("skyla.pack", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(x: &str) -> LuaValue {
+        LuaValue::Str(x.to_string())
+    }
+
+    #[test]
+    fn test_scalars_are_messagepack() {
+        let opts = PackOptions::default();
+        assert_eq!(pack_value(&LuaValue::Int(5), &opts).unwrap(), [0x05]);
+        assert_eq!(pack_value(&LuaValue::Int(-1), &opts).unwrap(), [0xff]);
+        assert_eq!(pack_value(&LuaValue::Int(300), &opts).unwrap(), [0xd1, 0x01, 0x2c]);
+        assert_eq!(pack_value(&s("hi"), &opts).unwrap(), [0xa2, b'h', b'i']);
+        for v in [LuaValue::Int(i64::MIN), LuaValue::Int(-200), LuaValue::Float(0.5), LuaValue::Bool(true)] {
+            let b = pack_value(&v, &opts).unwrap();
+            assert_eq!(format!("{:?}", unpack_value(&b, &opts).unwrap()), format!("{:?}", v));
+        }
+        assert_eq!(unpack_value(&[0xcd, 0x01, 0x00], &opts).map(|v| format!("{:?}", v)), Ok("Int(256)".to_string()));
+        assert_eq!(unpack_value(&[0xd1, 0x01], &opts).err(), Some(PackError::Truncated));
+        let LuaValue::Table(arr) = unpack_value(&[0x92, 0x01, 0xc3], &opts).unwrap() else { panic!("not a table") };
+        assert!(matches!(arr.borrow().get(&LuaValue::Int(2)), Some(LuaValue::Bool(true))));
+    }
+
+    #[test]
+    fn test_shared_tables_cycles_and_interning() {
+        let opts = PackOptions::default();
+        let root = Rc::new(RefCell::new(Table::new()));
+        let shared = Rc::new(RefCell::new(Table::new()));
+        shared.borrow_mut().rawset(&s("name"), s("shared value"));
+        root.borrow_mut().rawset(&LuaValue::Int(1), LuaValue::Table(shared.clone()));
+        root.borrow_mut().rawset(&LuaValue::Int(2), LuaValue::Table(shared.clone()));
+        root.borrow_mut().rawset(&s("self"), LuaValue::Table(root.clone()));
+        root.borrow_mut().rawset(&s("again"), s("shared value"));
+        let bytes = pack_value(&LuaValue::Table(root), &opts).unwrap();
+        // "shared value" is written once, then referenced
+        assert_eq!(bytes.windows(12).filter(|w| w == b"shared value").count(), 1);
+
+        let LuaValue::Table(r) = unpack_value(&bytes, &opts).unwrap() else { panic!("not a table") };
+        let r = r.borrow();
+        let (Some(LuaValue::Table(a)), Some(LuaValue::Table(b)), Some(LuaValue::Table(me))) =
+            (r.get(&LuaValue::Int(1)), r.get(&LuaValue::Int(2)), r.get(&s("self"))) else { panic!("missing fields") };
+        assert!(Rc::ptr_eq(a, b));
+        assert!(matches!(me.borrow().get(&s("again")), Some(LuaValue::Str(v)) if v == "shared value"));
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let mut v = LuaValue::Int(0);
+        for _ in 0..5 {
+            let mut t = Table::new();
+            t.rawset(&LuaValue::Int(1), v);
+            v = LuaValue::Table(Rc::new(RefCell::new(t)));
+        }
+        let shallow = PackOptions { max_depth: 4, ..Default::default() };
+        assert_eq!(pack_value(&v, &shallow), Err(PackError::TooDeep));
+        let bytes = pack_value(&v, &PackOptions::default()).unwrap();
+        assert_eq!(unpack_value(&bytes, &shallow).err(), Some(PackError::TooDeep));
+        assert_eq!(lua_to_bytes(&bytes_to_lua(&bytes)), Some(bytes));
+    }
+}
@@ -0,0 +1,298 @@
+//! lplatform.rs - Host services used by the VM and the standard libraries
+//
+// Everything the core needs from the operating system (clock, wall time,
+// seed entropy, stdout/stderr, reading script files, environment) goes
+// through the `Platform` trait, so the VM can run where std::fs and
+// std::process are unavailable (wasm32-unknown-unknown, firmware). Hosts
+// install their own implementation with `set_platform`. Without the
+// `minimal` feature the default is `StdPlatform`; with it the default is
+// `NullPlatform` and the os/io/package parts that need a real OS are left out.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::RwLock;
+
+/// Services the VM needs from its host
+pub trait Platform: Send + Sync {
+    /// Wall-clock time in seconds since the Unix epoch (os.time)
+    fn time(&self) -> i64;
+    /// Seconds of processor time used by the program (os.clock)
+    fn clock(&self) -> f64;
+    /// Entropy for the string-hash and random seeds
+    fn seed(&self) -> u64;
+    /// Standard output (print, io.write)
+    fn write_stdout(&self, s: &[u8]);
+    /// Standard error (warnings, error messages)
+    fn write_stderr(&self, s: &[u8]);
+    /// Contents of a script file (dofile, loadfile, require)
+    fn read_file(&self, path: &str) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot open {}: no file system", path)))
+    }
+    /// Does `path` name a readable file? (package.searchpath)
+    fn file_exists(&self, _path: &str) -> bool {
+        false
+    }
+    /// Environment variable (os.getenv, LUA_PATH, ...)
+    fn getenv(&self, _name: &str) -> Option<String> {
+        None
+    }
+    /// All environment variables (os.environ)
+    fn environ(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// Monotonic time in nanoseconds from an arbitrary origin (os.hrtime)
+    fn hrtime(&self) -> u64 {
+        (self.clock() * 1e9) as u64
+    }
+    /// Suspend the calling thread (os.sleep); hosts without threads return at once
+    fn sleep(&self, _d: std::time::Duration) {}
+}
+
+/// Host without an operating system: time stands still, output is discarded
+pub struct NullPlatform;
+
+impl Platform for NullPlatform {
+    fn time(&self) -> i64 { 0 }
+    fn clock(&self) -> f64 { 0.0 }
+    fn seed(&self) -> u64 { NULL_PLATFORM_SEED }
+    fn write_stdout(&self, _s: &[u8]) {}
+    fn write_stderr(&self, _s: &[u8]) {}
+}
+
+/// Fixed seed for hosts without entropy (deterministic across runs)
+pub const NULL_PLATFORM_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// The regular std-backed host
+#[cfg(not(feature = "minimal"))]
+pub struct StdPlatform;
+
+#[cfg(not(feature = "minimal"))]
+impl Platform for StdPlatform {
+    fn time(&self) -> i64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+    }
+    fn clock(&self) -> f64 {
+        // std has no CPU-time source; C's clock(), as lua's os.clock uses
+        extern "C" {
+            fn clock() -> std::os::raw::c_long;
+        }
+        #[cfg(windows)]
+        const CLOCKS_PER_SEC: f64 = 1000.0;
+        #[cfg(not(windows))]
+        const CLOCKS_PER_SEC: f64 = 1_000_000.0; // fixed by XSI
+        match unsafe { clock() } {
+            -1 => 0.0, // processor time not available
+            c => c as f64 / CLOCKS_PER_SEC,
+        }
+    }
+    fn seed(&self) -> u64 {
+        // luai_makeseed: the OS RNG (behind RandomState's keys), both
+        // clocks, and addresses ASLR moves (a static, the stack, the heap)
+        use std::collections::hash_map::RandomState;
+        use std::hash::{BuildHasher, Hasher};
+        let mut h = RandomState::new().build_hasher();
+        h.write_u64(self.time() as u64);
+        h.write_u64(self.hrtime());
+        let local = 0u8;
+        let heap = Box::new(0u8);
+        h.write_usize(&NULL_PLATFORM_SEED as *const u64 as usize);
+        h.write_usize(&local as *const u8 as usize);
+        h.write_usize(&*heap as *const u8 as usize);
+        h.finish()
+    }
+    fn write_stdout(&self, s: &[u8]) {
+        use std::io::Write;
+        let _ = io::stdout().write_all(s);
+    }
+    fn write_stderr(&self, s: &[u8]) {
+        use std::io::Write;
+        let _ = io::stderr().write_all(s);
+    }
+    fn read_file(&self, path: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+    fn file_exists(&self, path: &str) -> bool {
+        std::fs::File::open(path).is_ok()
+    }
+    fn getenv(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+    fn hrtime(&self) -> u64 {
+        use std::sync::OnceLock;
+        use std::time::Instant;
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        ORIGIN.get_or_init(Instant::now).elapsed().as_nanos() as u64
+    }
+    fn sleep(&self, d: std::time::Duration) {
+        std::thread::sleep(d);
+    }
+    fn environ(&self) -> Vec<(String, String)> {
+        std::env::vars_os()
+            .map(|(k, v)| (k.to_string_lossy().into_owned(), v.to_string_lossy().into_owned()))
+            .collect()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref PLATFORM: RwLock<Box<dyn Platform>> = RwLock::new(default_platform());
+}
+
+thread_local! {
+    /// Platform of this thread alone, set by `with_thread_platform`
+    static THREAD_PLATFORM: RefCell<Option<Box<dyn Platform>>> = const { RefCell::new(None) };
+}
+
+#[cfg(not(feature = "minimal"))]
+fn default_platform() -> Box<dyn Platform> {
+    Box::new(StdPlatform)
+}
+
+#[cfg(feature = "minimal")]
+fn default_platform() -> Box<dyn Platform> {
+    Box::new(NullPlatform)
+}
+
+/// Environment variables set or removed by one state (os.setenv). The
+/// process environment is never changed, as doing that while other threads
+/// read it is unsound: the overlay is laid over it for the state's getenv
+/// and environ, and given to the commands the state starts.
+#[derive(Debug, Clone, Default)]
+pub struct EnvOverlay {
+    vars: HashMap<String, Option<String>>, // None: removed
+}
+
+impl EnvOverlay {
+    /// Set (or with None remove) variable `name`
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> io::Result<()> {
+        // a child process could not be given these
+        if name.is_empty() || name.contains(['=', '\0']) || value.is_some_and(|v| v.contains('\0')) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid environment variable '{}'", name)));
+        }
+        self.vars.insert(name.to_string(), value.map(str::to_string));
+        Ok(())
+    }
+
+    /// Variable `name` as the state sees it
+    pub fn get(&self, name: &str) -> Option<String> {
+        match self.vars.get(name) {
+            Some(v) => v.clone(),
+            None => with_platform(|p| p.getenv(name)),
+        }
+    }
+
+    /// All variables as the state sees them, sorted by name
+    pub fn vars(&self) -> Vec<(String, String)> {
+        let mut vars: HashMap<String, String> = with_platform(|p| p.environ()).into_iter().collect();
+        for (k, v) in &self.vars {
+            match v {
+                Some(v) => vars.insert(k.clone(), v.clone()),
+                None => vars.remove(k),
+            };
+        }
+        let mut vars: Vec<(String, String)> = vars.into_iter().collect();
+        vars.sort();
+        vars
+    }
+
+    /// Start `cmd` with the state's environment
+    #[cfg(not(feature = "minimal"))]
+    pub fn apply(&self, cmd: &mut std::process::Command) {
+        for (k, v) in &self.vars {
+            match v {
+                Some(v) => cmd.env(k, v),
+                None => cmd.env_remove(k),
+            };
+        }
+    }
+}
+
+/// Install the host's platform implementation
+pub fn set_platform(p: Box<dyn Platform>) {
+    *PLATFORM.write().unwrap() = p;
+}
+
+/// Run `f` with the current platform
+pub fn with_platform<R>(f: impl FnOnce(&dyn Platform) -> R) -> R {
+    THREAD_PLATFORM.with(|t| match &*t.borrow() {
+        Some(p) => f(&**p),
+        None => f(&**PLATFORM.read().unwrap()),
+    })
+}
+
+/// Run `body` with `p` as the platform of the calling thread only; other
+/// threads keep the one installed by `set_platform`
+pub fn with_thread_platform<R>(p: Box<dyn Platform>, body: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Box<dyn Platform>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let prev = self.0.take();
+            THREAD_PLATFORM.with(|t| *t.borrow_mut() = prev);
+        }
+    }
+    let _restore = Restore(THREAD_PLATFORM.with(|t| t.borrow_mut().replace(p)));
+    body()
+}
+
+// --- luaconf.h output macros, routed through the platform ---
+
+/// lua_writestring: print a string (no newline)
+pub unsafe fn lua_writestring(s: *const std::os::raw::c_char, l: usize) {
+    let bytes = std::slice::from_raw_parts(s as *const u8, l);
+    with_platform(|p| p.write_stdout(bytes));
+}
+
+/// lua_writeline: print a newline
+pub fn lua_writeline() {
+    with_platform(|p| p.write_stdout(b"\n"));
+}
+
+/// lua_writestringerror: print an error/warning message
+pub fn lua_writestringerror(msg: &str) {
+    with_platform(|p| p.write_stderr(msg.as_bytes()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct CapturePlatform(Arc<Mutex<Vec<u8>>>);
+
+    impl Platform for CapturePlatform {
+        fn time(&self) -> i64 { 1_700_000_000 }
+        fn clock(&self) -> f64 { 1.5 }
+        fn seed(&self) -> u64 { 42 }
+        fn write_stdout(&self, s: &[u8]) { self.0.lock().unwrap().extend_from_slice(s); }
+        fn write_stderr(&self, _s: &[u8]) {}
+    }
+
+    #[test]
+    fn test_host_platform_is_used() {
+        // a thread override, so tests running alongside keep the default
+        let out = Arc::new(Mutex::new(Vec::new()));
+        with_thread_platform(Box::new(CapturePlatform(out.clone())), || {
+            unsafe { lua_writestring(b"hi".as_ptr() as *const _, 2) };
+            lua_writeline();
+            assert_eq!(with_platform(|p| (p.time(), p.seed())), (1_700_000_000, 42));
+            assert!(with_platform(|p| p.read_file("x.lua")).is_err());
+        });
+        assert_ne!(with_platform(|p| p.seed()), 42);
+        assert_eq!(&*out.lock().unwrap(), b"hi\n");
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    #[test]
+    fn test_clock_counts_processor_time() {
+        let start = StdPlatform.clock();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let mut x = 0u64;
+        while StdPlatform.clock() < start + 0.01 && std::time::Instant::now() < deadline {
+            for i in 0..10_000u64 {
+                x = std::hint::black_box(x.wrapping_mul(31).wrapping_add(i));
+            }
+        }
+        assert!(StdPlatform.clock() >= start + 0.01);
+    }
+}
@@ -0,0 +1,181 @@
+//! lplugin.rs - Plugins: intrinsic functions and libraries from Rust code
+//
+// A plugin implements Plugin and is loaded into a state either statically
+// (LuaState::load_plugin) or from a dynamic library (load_plugin_library
+// in loadlib, and the SKYLA_PLUGINS list of the standalone interpreter).
+// It registers:
+//   - intrinsics: functions of plain values (no state, no tables). Each
+//     one is an ordinary global function, and with -O the optimizer binds
+//     a call of that global to the intrinsic itself: GETGLOBAL + CALL
+//     become LOADK id + CALLI, with no global lookup and no call frame;
+//   - libraries: tables of LibFunctions, as with luaL_newlib.
+// A dynamic plugin exports `skyla_plugin_abi` and `skyla_plugin_create`
+// (declare_plugin!). Plugin and core exchange Rust trait objects, so a
+// library whose ABI version differs from SKYLA_PLUGIN_ABI is refused
+// before any of its code runs; bump the version whenever Plugin,
+// PluginRegistrar, Intrinsic, TValue or LibFunction change.
+
+use crate::lauxlib::LibFunction;
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::lvm::{luaV_addintrinsic, TValue};
+
+/// Version of the plugin interface of this core
+pub const SKYLA_PLUGIN_ABI: u32 = 1;
+
+/// An intrinsic: results from argument registers, or an error message.
+/// Strings in arguments and results live in the VM string pool.
+pub type Intrinsic = fn(&[TValue]) -> Result<TValue, String>;
+
+pub trait Plugin {
+    /// Name of the plugin; a state loads each name once
+    fn name(&self) -> &str;
+    /// Declare the plugin's intrinsics and libraries
+    fn register(&self, reg: &mut PluginRegistrar);
+}
+
+/// What a plugin adds to a state, collected by Plugin::register
+#[derive(Default)]
+pub struct PluginRegistrar {
+    intrinsics: Vec<(String, Intrinsic)>,
+    libs: Vec<(String, Vec<(String, LibFunction)>)>,
+}
+
+impl PluginRegistrar {
+    /// Global function `name`, bound at compile time by the optimizer
+    pub fn intrinsic(&mut self, name: &str, f: Intrinsic) -> &mut Self {
+        self.intrinsics.push((name.to_string(), f));
+        self
+    }
+
+    /// Library table `name` (a global and package.loaded[name]) with `funcs`
+    pub fn library(&mut self, name: &str, funcs: &[(&str, LibFunction)]) -> &mut Self {
+        let funcs = funcs.iter().map(|&(n, f)| (n.to_string(), f)).collect();
+        self.libs.push((name.to_string(), funcs));
+        self
+    }
+}
+
+/// Plugins loaded into a state (GlobalState::plugins)
+#[derive(Debug, Default)]
+pub struct PluginSet {
+    pub names: Vec<String>,
+}
+
+/// Message for a plugin built against another plugin ABI, if `abi` is not
+/// this core's
+pub fn abi_mismatch(name: &str, abi: u32) -> Option<String> {
+    (abi != SKYLA_PLUGIN_ABI).then(|| format!(
+        "plugin '{}' was built for plugin ABI {}, this core provides {}", name, abi, SKYLA_PLUGIN_ABI))
+}
+
+/// Call `f` as a global function: arguments from the stack, one result
+fn call_intrinsic(L: &mut LuaState, f: Intrinsic) -> i32 {
+    let args: Vec<TValue> = (1..=L.get_top()).map(|i| TValue::from_lua(&L.to_value(i))).collect();
+    match f(&args) {
+        Ok(v) => {
+            // SAFETY: intrinsic results are plain values from the VM pool
+            let v = unsafe { v.to_lua() }.unwrap_or(LuaValue::Nil);
+            L.push(v);
+            1
+        }
+        Err(msg) => L.throw(LuaValue::Str(msg)),
+    }
+}
+
+impl LuaState {
+    /// Load `plugin` into this state: its libraries and intrinsics become
+    /// globals, and chunks compiled from now on bind calls of the
+    /// intrinsics. Loading a plugin twice does nothing.
+    pub fn load_plugin(&mut self, plugin: &dyn Plugin) {
+        let name = plugin.name().to_string();
+        if self.l_G.borrow().plugins.names.contains(&name) {
+            return;
+        }
+        let mut reg = PluginRegistrar::default();
+        plugin.register(&mut reg);
+        for (libname, funcs) in &reg.libs {
+            let lib = self.lib_table(libname);
+            let funcs: Vec<(&str, LibFunction)> = funcs.iter().map(|(n, f)| (n.as_str(), *f)).collect();
+            self.set_funcs(&lib, &funcs);
+            self.set_global(libname, LuaValue::Table(lib));
+        }
+        for (fname, f) in reg.intrinsics {
+            let id = luaV_addintrinsic(f);
+            self.l_G.borrow_mut().compile.intrinsics.insert(fname.clone(), id);
+            let func = move |L: &mut LuaState| L.call_rust(|L| call_intrinsic(L, f));
+            self.set_global(&fname, LuaValue::Function(Box::new(func)));
+        }
+        self.l_G.borrow_mut().plugins.names.push(name);
+    }
+}
+
+/// Export a plugin from a `cdylib` crate: `declare_plugin!(MyPlugin)`
+/// defines the two entry points load_plugin_library looks for
+#[macro_export]
+macro_rules! declare_plugin {
+    ($plugin:expr) => {
+        #[no_mangle]
+        pub extern "C" fn skyla_plugin_abi() -> u32 {
+            $crate::lplugin::SKYLA_PLUGIN_ABI
+        }
+
+        #[no_mangle]
+        pub fn skyla_plugin_create() -> Box<dyn $crate::lplugin::Plugin> {
+            Box::new($plugin)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lstate::GlobalState;
+    use crate::lvm::{luaV_callintrinsic, LuaType};
+
+    struct Clamp;
+
+    fn clamp01(args: &[TValue]) -> Result<TValue, String> {
+        match args.first().map(|v| (v.tt, v)) {
+            Some((LuaType::Number, v)) => Ok(TValue::from_number(unsafe { v.value.n }.clamp(0.0, 1.0))),
+            _ => Err("clamp01: number expected".to_string()),
+        }
+    }
+
+    fn version(L: &mut LuaState) -> i32 {
+        L.push(LuaValue::Int(1));
+        1
+    }
+
+    impl Plugin for Clamp {
+        fn name(&self) -> &str { "clamp" }
+        fn register(&self, reg: &mut PluginRegistrar) {
+            reg.intrinsic("clamp01", clamp01).library("clamp", &[("version", version)]);
+        }
+    }
+
+    #[test]
+    fn test_load_plugin_registers_intrinsics_once() {
+        let mut L = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        L.load_plugin(&Clamp);
+        let id = L.compile_options().intrinsics["clamp01"];
+        let r = luaV_callintrinsic(id, &[TValue::from_number(1.5)]).unwrap();
+        assert_eq!(unsafe { r.value.n }, 1.0);
+        assert!(luaV_callintrinsic(id, &[TValue::nil()]).is_err());
+        let lib = L.lib_table("clamp");
+        assert!(matches!(lib.borrow().get(&LuaValue::Str("version".to_string())), Some(LuaValue::Function(_))));
+        // a second load keeps the first id
+        L.load_plugin(&Clamp);
+        assert_eq!(L.compile_options().intrinsics["clamp01"], id);
+        assert_eq!(L.l_G.borrow().plugins.names, vec!["clamp".to_string()]);
+    }
+
+    #[test]
+    fn test_abi_mismatch() {
+        assert_eq!(abi_mismatch("clamp", SKYLA_PLUGIN_ABI), None);
+        assert_eq!(abi_mismatch("clamp", SKYLA_PLUGIN_ABI + 1).unwrap(),
+            format!("plugin 'clamp' was built for plugin ABI {}, this core provides {}", SKYLA_PLUGIN_ABI + 1, SKYLA_PLUGIN_ABI));
+    }
+}
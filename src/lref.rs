@@ -0,0 +1,214 @@
+//! lref.rs - References to Lua values held by the host (LuaRef)
+//
+// A Rust system that keeps a Lua callback or table beyond the call that
+// produced it must pin it: `LuaState::create_ref` stores the value in the
+// state's reference table (the safe API's luaL_ref, with the same integer
+// references) and returns a LuaRef. Clones share the reference, and the
+// last one to be dropped releases it (luaL_unref). A LuaRef does not keep
+// the state alive: once the state is gone, `get` returns None and
+// dropping does nothing. Like the values it pins, a LuaRef stays on the
+// state's thread (it is neither Send nor Sync).
+
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::os::raw::c_int;
+use std::rc::{Rc, Weak};
+
+use crate::lauxlib::LUA_REFNIL;
+use crate::lobject::LuaValue;
+use crate::lstate::{GlobalState, LuaState};
+use crate::ltm::obj_typename;
+
+/// The values pinned by references, indexed by reference - 1, with the
+/// released slots kept for reuse
+#[derive(Debug, Default)]
+pub struct RefTable {
+    slots: Vec<Option<LuaValue>>,
+    free: Vec<usize>,
+}
+
+impl RefTable {
+    /// Pin `v` and return its reference (LUA_REFNIL for nil, which is not
+    /// stored)
+    pub fn make_ref(&mut self, v: LuaValue) -> c_int {
+        if matches!(v, LuaValue::Nil) {
+            return LUA_REFNIL;
+        }
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.slots[i] = Some(v);
+                i
+            }
+            None => {
+                self.slots.push(Some(v));
+                self.slots.len() - 1
+            }
+        };
+        i as c_int + 1
+    }
+
+    /// The value of reference `r` (nil for LUA_REFNIL and released ones)
+    pub fn get(&self, r: c_int) -> LuaValue {
+        if r <= 0 {
+            return LuaValue::Nil;
+        }
+        self.slots.get(r as usize - 1).cloned().flatten().unwrap_or(LuaValue::Nil)
+    }
+
+    /// Release reference `r`; LUA_NOREF, LUA_REFNIL and released ones are
+    /// ignored
+    pub fn unref(&mut self, r: c_int) {
+        if r <= 0 {
+            return;
+        }
+        if let Some(slot) = self.slots.get_mut(r as usize - 1) {
+            if slot.take().is_some() {
+                self.free.push(r as usize - 1);
+            }
+        }
+    }
+
+    /// Number of values pinned
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Which values a LuaRef may hold
+pub trait RefKind {
+    /// What `create_ref` reports it expected
+    const EXPECTED: &'static str;
+    fn accepts(v: &LuaValue) -> bool;
+}
+
+/// Any value
+pub struct AnyRef;
+/// A table
+pub struct TableRef;
+/// A function, e.g. a stored callback
+pub struct FunctionRef;
+
+impl RefKind for AnyRef {
+    const EXPECTED: &'static str = "value";
+    fn accepts(_: &LuaValue) -> bool {
+        true
+    }
+}
+
+impl RefKind for TableRef {
+    const EXPECTED: &'static str = "table";
+    fn accepts(v: &LuaValue) -> bool {
+        matches!(v, LuaValue::Table(_))
+    }
+}
+
+impl RefKind for FunctionRef {
+    const EXPECTED: &'static str = "function";
+    fn accepts(v: &LuaValue) -> bool {
+        matches!(v, LuaValue::Function(_))
+    }
+}
+
+/// The reference all clones of a LuaRef share
+struct RefSlot {
+    g: Weak<RefCell<GlobalState>>,
+    r: c_int,
+}
+
+impl Drop for RefSlot {
+    fn drop(&mut self) {
+        if let Some(g) = self.g.upgrade() {
+            g.borrow_mut().refs.unref(self.r);
+        }
+    }
+}
+
+/// A host-held reference to a value of kind K (see the module comment)
+pub struct LuaRef<K: RefKind = AnyRef> {
+    slot: Rc<RefSlot>,
+    kind: PhantomData<K>,
+}
+
+impl<K: RefKind> Clone for LuaRef<K> {
+    fn clone(&self) -> Self {
+        LuaRef { slot: self.slot.clone(), kind: PhantomData }
+    }
+}
+
+impl<K: RefKind> LuaRef<K> {
+    /// The value, or None if its state is gone
+    pub fn get(&self) -> Option<LuaValue> {
+        let g = self.slot.g.upgrade()?;
+        let v = g.borrow().refs.get(self.slot.r);
+        Some(v)
+    }
+
+    /// The integer reference, as luaL_ref returns it
+    pub fn id(&self) -> c_int {
+        self.slot.r
+    }
+}
+
+impl LuaState {
+    /// Pin `v` for the host; fails if it is not of kind K
+    pub fn create_ref<K: RefKind>(&mut self, v: LuaValue) -> Result<LuaRef<K>, String> {
+        if !K::accepts(&v) {
+            return Err(format!("{} expected, got {}", K::EXPECTED, obj_typename(&v)));
+        }
+        let r = self.l_G.borrow_mut().refs.make_ref(v);
+        Ok(LuaRef { slot: Rc::new(RefSlot { g: Rc::downgrade(&self.l_G), r }), kind: PhantomData })
+    }
+
+    /// Push the value of `r` back onto the stack; false (pushing nothing)
+    /// if `r` belongs to another state
+    pub fn push_ref<K: RefKind>(&mut self, r: &LuaRef<K>) -> bool {
+        if !Weak::ptr_eq(&r.slot.g, &Rc::downgrade(&self.l_G)) {
+            return false;
+        }
+        let v = self.l_G.borrow().refs.get(r.slot.r);
+        self.push(v);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refs_pin_and_release() {
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g.clone());
+        let f: LuaRef<FunctionRef> = state.create_ref(LuaValue::Function(Box::new(|_: &mut LuaState| 0))).unwrap();
+        assert!(state.create_ref::<FunctionRef>(LuaValue::Int(1)).is_err());
+        let copy = f.clone();
+        drop(f);
+        assert_eq!(g.borrow().refs.len(), 1);
+        assert!(state.push_ref(&copy));
+        assert!(matches!(state.pop(), Some(LuaValue::Function(_))));
+        let id = copy.id();
+        drop(copy);
+        assert!(g.borrow().refs.is_empty());
+        // the slot is reused
+        let t: LuaRef = state.create_ref(LuaValue::Bool(true)).unwrap();
+        assert_eq!(t.id(), id);
+
+        let mut other = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        assert!(!other.push_ref(&t));
+        drop((state, g));
+        assert!(t.get().is_none());
+    }
+
+    #[test]
+    fn test_nil_ref() {
+        let mut refs = RefTable::default();
+        assert_eq!(refs.make_ref(LuaValue::Nil), LUA_REFNIL);
+        assert_eq!(refs.get(LUA_REFNIL), LuaValue::Nil);
+        refs.unref(LUA_REFNIL);
+        assert!(refs.is_empty());
+    }
+}
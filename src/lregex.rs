@@ -0,0 +1,278 @@
+//! lregex.rs - Regular expressions beside Lua patterns (skyla.regex)
+//
+// Built with the `regex` feature; Lua patterns stay what the string library
+// uses. The functions mirror string.find, string.match, string.gmatch and
+// string.gsub: the subject comes first, positions are 1-based byte offsets,
+// `init` may be negative, and gsub's replacement is a string with %0-%9, a
+// table or a function. The pattern is a regex string, compiled on each
+// call, or a regex from regex.compile(pattern [, flags]), which has the
+// same functions as methods (`re:find(s)`), so a hot loop compiles once.
+// The syntax is the regex crate's: Perl-like, without backreferences or
+// lookaround, and matching takes time linear in the subject. A group that
+// takes no part in a match captures false (an empty string in
+// replacements).
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use regex::bytes::{Captures, Regex, RegexBuilder};
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::lstrlib::{posrelat_i, Capture, Replacement};
+use crate::ltable::Table;
+use crate::skylaconf::LuaInteger;
+
+thread_local! {
+    static REGEXES: RefCell<HashMap<i64, Rc<Regex>>> = RefCell::new(HashMap::new());
+    static NEXT_REGEX: RefCell<i64> = const { RefCell::new(1) };
+}
+
+/// Field of a compiled regex object holding its handle
+const REGEX_FIELD: &str = "__regex";
+
+/// Compile `pattern` with `flags`: "i" ignores case, "m" makes ^ and $
+/// match at lines, "s" lets . match \n, "x" allows spaces and comments
+pub fn compile(pattern: &str, flags: &str) -> Result<Regex, String> {
+    let mut b = RegexBuilder::new(pattern);
+    for f in flags.chars() {
+        match f {
+            'i' => b.case_insensitive(true),
+            'm' => b.multi_line(true),
+            's' => b.dot_matches_new_line(true),
+            'x' => b.ignore_whitespace(true),
+            _ => return Err(format!("invalid regex flag '{}'", f)),
+        };
+    }
+    b.build().map_err(|e| e.to_string())
+}
+
+/// The compiled regex of object `v`
+fn regex_handle(v: &LuaValue) -> Option<Rc<Regex>> {
+    let LuaValue::Table(t) = v else { return None };
+    let id = match t.borrow().get(&LuaValue::Str(REGEX_FIELD.to_string())) {
+        Some(LuaValue::Int(id)) => *id,
+        _ => return None,
+    };
+    REGEXES.with(|r| r.borrow().get(&id).cloned())
+}
+
+/// The regex at `arg`: a compiled one, or a string compiled now
+fn check_regex(state: &mut LuaState, arg: i32) -> Option<Rc<Regex>> {
+    let v = state.to_value(arg);
+    if let Some(re) = regex_handle(&v) {
+        return Some(re);
+    }
+    let LuaValue::Str(pattern) = v else {
+        state.type_error(arg, "string or regex");
+    };
+    match compile(&pattern, "") {
+        Ok(re) => Some(Rc::new(re)),
+        Err(e) => {
+            state.error(&e)
+        }
+    }
+}
+
+/// Subject and regex of a call: (s, pattern, ...) as a module function,
+/// (re, s, ...) as a method; the other arguments start at 3 either way
+fn subject_and_regex(state: &mut LuaState) -> Option<(String, Rc<Regex>)> {
+    if let Some(re) = regex_handle(&state.to_value(1)) {
+        return Some((state.check_string(2), re));
+    }
+    let s = state.check_string(1);
+    check_regex(state, 2).map(|re| (s, re))
+}
+
+/// Groups 1.. of a match, or the whole match if there are none and
+/// `whole` is set; None for a group that took no part
+fn groups(caps: &Captures, whole: bool) -> Vec<Option<Capture>> {
+    let text = |m: regex::bytes::Match| Capture::Str(m.as_bytes().to_vec());
+    if caps.len() == 1 && whole {
+        return vec![caps.get(0).map(text)];
+    }
+    (1..caps.len()).map(|i| caps.get(i).map(text)).collect()
+}
+
+fn push_groups(state: &mut LuaState, groups: &[Option<Capture>]) -> i32 {
+    for g in groups {
+        state.push(g.as_ref().map_or(LuaValue::Bool(false), Capture::to_value));
+    }
+    groups.len() as i32
+}
+
+/// regex.find and regex.match (str_find_aux)
+fn find_aux(state: &mut LuaState, find: bool) -> i32 {
+    let Some((s, re)) = subject_and_regex(state) else { return 0 };
+    let init = posrelat_i(state.opt_integer(3, 1), s.len()) - 1;
+    let caps = if init > s.len() { None } else { re.captures_at(s.as_bytes(), init) };
+    let Some(caps) = caps else {
+        state.push(LuaValue::Nil);
+        return 1;
+    };
+    let found = groups(&caps, !find);
+    if !find {
+        return push_groups(state, &found);
+    }
+    let m = caps.get(0).expect("group 0 is the match");
+    state.push(LuaValue::Int(m.start() as LuaInteger + 1));
+    state.push(LuaValue::Int(m.end() as LuaInteger));
+    2 + push_groups(state, &found)
+}
+
+// regex.find(s, pattern [, init]) -> start, end, groups...
+fn regex_find(state: &mut LuaState) -> i32 {
+    find_aux(state, true)
+}
+
+// regex.match(s, pattern [, init]) -> groups (or the whole match)
+fn regex_match(state: &mut LuaState) -> i32 {
+    find_aux(state, false)
+}
+
+// regex.gmatch(s, pattern) -> iterator over the groups of each match
+fn regex_gmatch(state: &mut LuaState) -> i32 {
+    let Some((s, re)) = subject_and_regex(state) else { return 0 };
+    let pos = RefCell::new((0usize, None::<usize>)); // where to search, end of the last match
+    let f = move |L: &mut LuaState| {
+        let (mut src, lastmatch) = *pos.borrow();
+        while src <= s.len() {
+            let Some(caps) = re.captures_at(s.as_bytes(), src) else { break };
+            let m = caps.get(0).expect("group 0 is the match");
+            if Some(m.end()) == lastmatch {
+                src = m.start() + 1; // an empty match right after the last one
+                continue;
+            }
+            *pos.borrow_mut() = (m.end(), Some(m.end()));
+            return push_groups(L, &groups(&caps, true));
+        }
+        pos.borrow_mut().0 = s.len() + 1; // exhausted
+        0
+    };
+    state.push(LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(&f))));
+    1
+}
+
+// regex.gsub(s, pattern, repl [, n]) -> string, count
+fn regex_gsub(state: &mut LuaState) -> i32 {
+    let Some((s, re)) = subject_and_regex(state) else { return 0 };
+    let Some(repl) = Replacement::check(state, 3) else { return 0 };
+    let max_n = if state.is_none_or_nil(4) { usize::MAX } else { state.check_integer(4).max(0) as usize };
+    let src = s.as_bytes();
+    let mut out = Vec::with_capacity(src.len());
+    let (mut pos, mut copied, mut n, mut lastmatch) = (0, 0, 0, None);
+    while n < max_n && pos <= src.len() {
+        let Some(caps) = re.captures_at(src, pos) else { break };
+        let m = caps.get(0).expect("group 0 is the match");
+        if Some(m.end()) == lastmatch {
+            pos = m.start() + 1;
+            continue;
+        }
+        n += 1;
+        let args: Vec<Capture> = groups(&caps, true)
+            .into_iter()
+            .map(|g| g.unwrap_or_else(|| Capture::Str(Vec::new())))
+            .collect();
+        out.extend_from_slice(&src[copied..m.start()]);
+        match repl.apply(state, m.as_bytes(), &args) {
+            Ok(Some(text)) => out.extend_from_slice(&text),
+            Ok(None) => out.extend_from_slice(m.as_bytes()), // keep original text
+            Err(msg) => {
+                state.error(&msg);
+            }
+        }
+        pos = m.end();
+        copied = m.end();
+        lastmatch = Some(m.end());
+    }
+    out.extend_from_slice(&src[copied..]);
+    state.push(LuaValue::Str(String::from_utf8_lossy(&out).into_owned()));
+    state.push(LuaValue::Int(n as LuaInteger));
+    2
+}
+
+// regex.compile(pattern [, flags]) -> regex object with find, match,
+// gmatch and gsub methods
+fn regex_compile(state: &mut LuaState) -> i32 {
+    let pattern = state.check_string(1);
+    let flags = state.opt_string(2, "");
+    let re = match compile(&pattern, &flags) {
+        Ok(re) => re,
+        Err(e) => {
+            state.error(&e);
+        }
+    };
+    let id = NEXT_REGEX.with(|n| {
+        let mut n = n.borrow_mut();
+        *n += 1;
+        *n - 1
+    });
+    REGEXES.with(|r| r.borrow_mut().insert(id, Rc::new(re)));
+    let t = Rc::new(RefCell::new(Table::new()));
+    t.borrow_mut().rawset(&LuaValue::Str(REGEX_FIELD.to_string()), LuaValue::Int(id));
+    t.borrow_mut().rawset(&LuaValue::Str("pattern".to_string()), LuaValue::Str(pattern));
+    state.set_funcs(&t, REGEX_METHODS);
+    state.push(LuaValue::Table(t));
+    1
+}
+
+const REGEX_METHODS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("find", regex_find),
+    ("gmatch", regex_gmatch),
+    ("gsub", regex_gsub),
+    ("match", regex_match),
+];
+
+const REGEX_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("compile", regex_compile),
+    ("find", regex_find),
+    ("gmatch", regex_gmatch),
+    ("gsub", regex_gsub),
+    ("match", regex_match),
+];
+
+/// Register the `skyla.regex` module
+pub fn open_regex_lib(state: &mut LuaState) {
+    for &(name, f) in REGEX_FUNCS {
+        state.register_lib_function("skyla.regex", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lauxlib::call_lib;
+    use crate::lstate::GlobalState;
+
+    #[test]
+    fn test_mirrors_string_library() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let s = |x: &str| LuaValue::Str(x.to_string());
+        let i = LuaValue::Int;
+        assert_eq!(call_lib(&mut state, regex_find, vec![s("key = 42"), s(r"(\w+)\s*=\s*(\d+)")]),
+            vec![i(1), i(8), s("key"), s("42")]);
+        assert_eq!(call_lib(&mut state, regex_find, vec![s("a1b2"), s(r"\d"), i(-2)]), vec![i(4), i(4)]);
+        assert_eq!(call_lib(&mut state, regex_match, vec![s("x=1"), s(r"\d")]), vec![s("1")]);
+        assert_eq!(call_lib(&mut state, regex_match, vec![s("ab"), s("a(x)?b")]), vec![LuaValue::Bool(false)]);
+        assert_eq!(call_lib(&mut state, regex_gsub, vec![s("hello world"), s(r"(\w+)"), s("<%1>")]),
+            vec![s("<hello> <world>"), i(2)]);
+        assert_eq!(call_lib(&mut state, regex_gsub, vec![s("abc"), s(""), s("-")]), vec![s("-a-b-c-"), i(4)]);
+
+        let re = call_lib(&mut state, regex_compile, vec![s("^A+"), s("im")]).remove(0);
+        assert_eq!(call_lib(&mut state, regex_find, vec![re.clone(), s("x\naab"), i(1)]), vec![i(3), i(4)]);
+        assert_eq!(call_lib(&mut state, regex_gsub, vec![re, s("aa\nA"), s("%0!"), i(1)]), vec![s("aa!\nA"), i(1)]);
+
+        let LuaValue::Function(iter) = call_lib(&mut state, regex_gmatch, vec![s("a,b,,c"), s("[^,]*")]).remove(0) else { unreachable!() };
+        let mut words = Vec::new();
+        loop {
+            state.stack.truncate(1);
+            if iter(&mut state) == 0 {
+                break;
+            }
+            words.push(state.pop().unwrap());
+        }
+        assert_eq!(words, vec![s("a"), s("b"), s(""), s("c")]);
+        assert!(compile("(", "").is_err() && compile("a", "q").is_err());
+    }
+}
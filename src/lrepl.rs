@@ -0,0 +1,207 @@
+//! lrepl.rs - Locals that outlive a REPL line
+//
+// Each REPL line is compiled as its own chunk, so `local x = 1` would be
+// gone by the next line. A ReplSession remembers the names of the
+// top-level locals that successful lines declared and wraps later lines so
+// they see them: a prologue declares the known names as locals initialized
+// from the REPL_LOCALS table, and an epilogue stores them (and the line's
+// new ones) back. Only the tokens needed to find top-level `local`
+// statements are scanned; the compiler still reports any syntax error.
+
+/// Global table holding the REPL's locals between lines
+pub const REPL_LOCALS: &str = "__skyla_locals";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Punct(u8),
+    /// A string or numeral
+    Literal,
+}
+
+/// Tokens of `src`, with comments skipped
+fn tokens(src: &str) -> Vec<Token> {
+    let b = src.as_bytes();
+    // level of a long bracket `[==[` opening at j
+    let long_open = |j: usize| -> Option<usize> {
+        if b.get(j) != Some(&b'[') {
+            return None;
+        }
+        let level = b[j + 1..].iter().take_while(|&&c| c == b'=').count();
+        (b.get(j + 1 + level) == Some(&b'[')).then_some(level)
+    };
+    // end of the long string or comment whose contents start at j
+    let skip_long = |j: usize, level: usize| -> usize {
+        let close = format!("]{}]", "=".repeat(level));
+        src[j..].find(&close).map_or(b.len(), |p| j + p + close.len())
+    };
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let c = b[i];
+        if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'_') {
+                i += 1;
+            }
+            out.push(Token::Name(src[start..i].to_string()));
+        } else if c == b'-' && b.get(i + 1) == Some(&b'-') {
+            i += 2;
+            match long_open(i) {
+                Some(level) => i = skip_long(i + level + 2, level),
+                None => i = src[i..].find('\n').map_or(b.len(), |p| i + p),
+            }
+        } else if c == b'"' || c == b'\'' {
+            i += 1;
+            while i < b.len() && b[i] != c && b[i] != b'\n' {
+                i += if b[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+            out.push(Token::Literal);
+        } else if let Some(level) = long_open(i) {
+            i = skip_long(i + level + 2, level);
+            out.push(Token::Literal);
+        } else if c.is_ascii_digit() {
+            // 0x1p-4, 1e+3: an exponent sign is part of the numeral
+            i += 1;
+            while i < b.len() && (b[i].is_ascii_alphanumeric() || b[i] == b'.'
+                || (matches!(b[i], b'+' | b'-') && matches!(b[i - 1], b'e' | b'E' | b'p' | b'P'))) {
+                i += 1;
+            }
+            out.push(Token::Literal);
+        } else {
+            if !c.is_ascii_whitespace() {
+                out.push(Token::Punct(c));
+            }
+            i += 1;
+        }
+    }
+    out
+}
+
+fn name(t: Option<&Token>) -> Option<&str> {
+    match t {
+        Some(Token::Name(n)) => Some(n),
+        _ => None,
+    }
+}
+
+/// Names of the locals `src` declares outside any block or function
+pub fn declared_locals(src: &str) -> Vec<String> {
+    let toks = tokens(src);
+    let mut names: Vec<String> = Vec::new();
+    let mut depth = 0i32;
+    let mut i = 0;
+    while i < toks.len() {
+        match name(toks.get(i)) {
+            Some("function" | "do" | "if" | "repeat") => depth += 1,
+            Some("end" | "until") => depth -= 1,
+            Some("local") if depth == 0 => {
+                if name(toks.get(i + 1)) == Some("function") {
+                    // the function's body is counted as a block from here
+                    names.extend(name(toks.get(i + 2)).map(str::to_string));
+                    i += 1;
+                    continue;
+                }
+                // local a <const>, b <close> = ...
+                i += 1;
+                while let Some(n) = name(toks.get(i)) {
+                    names.push(n.to_string());
+                    i += 1;
+                    if toks.get(i) == Some(&Token::Punct(b'<')) {
+                        i += 3;
+                    }
+                    if toks.get(i) != Some(&Token::Punct(b',')) {
+                        break;
+                    }
+                    i += 1;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    names
+}
+
+/// The REPL locals known so far
+#[derive(Debug, Default)]
+pub struct ReplSession {
+    locals: Vec<String>,
+}
+
+impl ReplSession {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn locals(&self) -> &[String] {
+        &self.locals
+    }
+
+    /// Lines wrap puts before the code (to map error line numbers back)
+    pub fn prologue_lines(&self) -> u32 {
+        if self.locals.is_empty() { 1 } else { 2 }
+    }
+
+    /// `code` with the known locals in scope, storing back the known and
+    /// newly declared ones when it finishes
+    pub fn wrap(&self, code: &str) -> String {
+        let mut all = self.locals.clone();
+        for n in declared_locals(code) {
+            if !all.contains(&n) {
+                all.push(n);
+            }
+        }
+        let mut chunk = format!("{0} = {0} or {{}}\n", REPL_LOCALS);
+        if !self.locals.is_empty() {
+            let loads: Vec<String> = self.locals.iter().map(|n| format!("{}.{}", REPL_LOCALS, n)).collect();
+            chunk.push_str(&format!("local {} = {}\n", self.locals.join(", "), loads.join(", ")));
+        }
+        chunk.push_str(code);
+        chunk.push('\n');
+        if !all.is_empty() {
+            let stores: Vec<String> = all.iter().map(|n| format!("{}.{}", REPL_LOCALS, n)).collect();
+            chunk.push_str(&format!("{} = {}\n", stores.join(", "), all.join(", ")));
+        }
+        chunk
+    }
+
+    /// Remember the locals `code` declared, once it ran without error
+    pub fn commit(&mut self, code: &str) {
+        for n in declared_locals(code) {
+            if !self.locals.contains(&n) {
+                self.locals.push(n);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_declared_locals() {
+        assert_eq!(declared_locals("local x = 1"), vec!["x"]);
+        assert_eq!(declared_locals("local a <const>, b = 1, 2 local function f() local y end"), vec!["a", "b", "f"]);
+        assert_eq!(declared_locals("do local z = 1 end if c then local w end local v"), vec!["v"]);
+        assert_eq!(declared_locals("s = 'local q' -- local r\nlocal t = [[local u]] + 1e-3"), vec!["t"]);
+        assert!(declared_locals("for i = 1, 3 do local k = i end").is_empty());
+    }
+
+    #[test]
+    fn test_wrap_loads_and_stores_locals() {
+        let mut s = ReplSession::new();
+        assert_eq!(s.prologue_lines(), 1);
+        let first = "local x, y = 1, 2";
+        assert_eq!(s.wrap(first), "__skyla_locals = __skyla_locals or {}\nlocal x, y = 1, 2\n__skyla_locals.x, __skyla_locals.y = x, y\n");
+        s.commit(first);
+        s.commit("local x = 3");
+        assert_eq!(s.locals(), ["x", "y"]);
+        assert_eq!(s.prologue_lines(), 2);
+        assert_eq!(s.wrap("print(x + y)"),
+            "__skyla_locals = __skyla_locals or {}\nlocal x, y = __skyla_locals.x, __skyla_locals.y\nprint(x + y)\n__skyla_locals.x, __skyla_locals.y = x, y\n");
+    }
+}
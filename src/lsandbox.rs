@@ -0,0 +1,167 @@
+//! lsandbox.rs - Per-state policy for library features that reach outside the VM
+//
+// Hosts running untrusted scripts restrict a state with `set_sandbox`;
+// library functions check the policy before acting and raise an error (or
+// report the feature as unavailable) when it is denied. The default policy
+// allows everything, as the reference implementation does. Code that must
+// not touch the real globals at all is run with an environment of its own
+// (load's `env`, skyla.setfenv).
+
+use std::ffi::CStr;
+use crate::lapi::{lua_getupvalue, lua_pop, lua_State};
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    /// Run commands: os.execute, io.popen
+    pub allow_process: bool,
+    /// Change or enumerate the environment: os.setenv, os.environ
+    pub allow_environment: bool,
+    /// Open the debug library, which can reach into any function
+    pub allow_debug: bool,
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        SandboxPolicy { allow_process: true, allow_environment: true, allow_debug: true }
+    }
+}
+
+impl SandboxPolicy {
+    /// Everything that leaves the VM is denied
+    pub fn restricted() -> Self {
+        SandboxPolicy { allow_process: false, allow_environment: false, allow_debug: false }
+    }
+}
+
+impl LuaState {
+    /// Install the policy for this state (shared by all its threads)
+    pub fn set_sandbox(&mut self, policy: SandboxPolicy) {
+        self.l_G.borrow_mut().sandbox = policy;
+    }
+
+    pub fn sandbox(&self) -> SandboxPolicy {
+        self.l_G.borrow().sandbox.clone()
+    }
+
+    /// Raise "'fname' is disabled by the sandbox policy" unless `allowed`;
+    /// returns `allowed`
+    pub fn check_sandbox(&mut self, allowed: bool, fname: &str) -> bool {
+        if !allowed {
+            self.error(&format!("'{}' is disabled by the sandbox policy", fname));
+        }
+        allowed
+    }
+
+    /// Make the value at `envidx` the globals of the function at `fidx`
+    /// (both argument indices). The function's _ENV upvalue is first
+    /// replaced by a fresh one, so the other functions of its chunk keep
+    /// their environment. False if it is not a Lua function or has no
+    /// _ENV, as it uses no globals.
+    pub fn set_function_env(&mut self, fidx: i32, envidx: i32) -> bool {
+        let Some(cl) = crate::lvm::closure_of(&self.to_value(fidx)) else { return false };
+        let L = self as *mut LuaState as *mut lua_State;
+        unsafe {
+            let Some(n) = env_upvalue(L, fidx) else { return false };
+            let env = crate::lvm::TValue::from_lua(&self.to_value(envidx));
+            (*cl.as_ptr()).join_upvalue(n as usize, crate::lvm::UpVal::closed(env));
+        }
+        true
+    }
+}
+
+/// Index of the _ENV upvalue of the function at `fidx`
+unsafe fn env_upvalue(L: *mut lua_State, fidx: i32) -> Option<i32> {
+    for n in 1.. {
+        let name = lua_getupvalue(L, fidx, n);
+        if name.is_null() {
+            break;
+        }
+        lua_pop(L, 1);
+        if CStr::from_ptr(name).to_bytes() == b"_ENV" {
+            return Some(n);
+        }
+    }
+    None
+}
+
+// skyla.setfenv(f, env) -> f: run f with `env` as its global table, the
+// usual way to confine user code to a sandbox environment
+fn skyla_setfenv(state: &mut LuaState) -> i32 {
+    if !matches!(state.arg(1), Some(LuaValue::Function(_))) {
+        state.type_error(1, "function");
+    }
+    state.check_any(2);
+    if !state.set_function_env(1, 2) {
+        state.error("'setfenv' cannot change environment of given object");
+    }
+    let f = state.to_value(1);
+    state.push(f);
+    1
+}
+
+/// Register `setfenv` in the `skyla` module
+pub fn open_sandbox_lib(state: &mut LuaState) {
+    state.register_lib_function("skyla", "setfenv", skyla_setfenv);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy_allows_everything() {
+        assert!(SandboxPolicy::default().allow_process);
+        assert!(!SandboxPolicy::restricted().allow_process);
+        assert!(!SandboxPolicy::restricted().allow_environment);
+        assert!(!SandboxPolicy::restricted().allow_debug);
+    }
+
+    #[test]
+    fn test_setfenv() {
+        use crate::lstate::GlobalState;
+        use crate::lvm::{closure_value, Closure, ClosureType, Proto, TValue, UpVal};
+        use std::cell::RefCell;
+        use std::ptr::NonNull;
+        use std::rc::Rc;
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: vec!["x".to_string(), "_ENV".to_string()],
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let p: *mut Proto = &mut p;
+        let globals = UpVal::closed(TValue::nil());
+        let mut f = Closure { cl: ClosureType { p }, upvals: vec![UpVal::closed(TValue::nil()), globals] };
+        let g = Closure { cl: ClosureType { p }, upvals: vec![UpVal::closed(TValue::nil()), globals] };
+        let env = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        let fv = closure_value(NonNull::from(&mut f));
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        state.push(LuaValue::Nil); // the running function's slot
+        state.push(fv);
+        state.push(LuaValue::Table(env.clone()));
+        assert_eq!(skyla_setfenv(&mut state), 1);
+        unsafe {
+            assert_eq!((*f.upvalue(2).unwrap().1).value.p, env.as_ptr() as *mut std::ffi::c_void);
+            // the other functions of the chunk keep the old _ENV
+            assert_ne!(f.upvalue_id(2), g.upvalue_id(2));
+            assert!(matches!((*g.upvalue(2).unwrap().1).tt, crate::lvm::LuaType::Nil));
+        }
+
+        // Rust functions have no _ENV to replace
+        state.stack.truncate(1);
+        state.push(LuaValue::Function(Box::new(|_: &mut LuaState| 0)));
+        state.push(LuaValue::Table(env));
+        let Err(e) = state.pcall(skyla_setfenv) else { panic!("setfenv accepted a Rust function") };
+        assert_eq!(e.into_value(), LuaValue::Str("'setfenv' cannot change environment of given object".to_string()));
+    }
+}
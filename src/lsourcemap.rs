@@ -0,0 +1,196 @@
+//! lsourcemap.rs - Line maps from loaded chunks back to original sources
+//
+// A host that concatenates files or expands templates into one chunk can
+// load it with a SourceMap (LoadOptions::source_map). luaL_where, and so
+// error messages, and luaL_traceback then report the original file and
+// line in place of the chunk's own ones. Maps are kept per state, keyed by
+// the chunk name, so every function of the chunk shares its map.
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+
+/// Lines [start, next segment's start) of the chunk come from `file`,
+/// starting at its line `line`
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    start: u32,
+    file: String,
+    line: u32,
+}
+
+/// Where each line of a chunk came from
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    /// Sorted by start
+    segments: Vec<Segment>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Chunk lines from `start` on come from `file`, beginning at its line
+    /// `line`, up to the next segment
+    pub fn add(&mut self, start: u32, file: &str, line: u32) -> &mut Self {
+        let seg = Segment { start, file: file.to_string(), line };
+        match self.segments.binary_search_by_key(&start, |s| s.start) {
+            Ok(i) => self.segments[i] = seg,
+            Err(i) => self.segments.insert(i, seg),
+        }
+        self
+    }
+
+    /// Original file and line of chunk line `line`; None before the first
+    /// segment
+    pub fn resolve(&self, line: u32) -> Option<(&str, u32)> {
+        let i = self.segments.partition_point(|s| s.start <= line).checked_sub(1)?;
+        let s = &self.segments[i];
+        Some((&s.file, s.line + (line - s.start)))
+    }
+
+    /// Join `parts` (file name, text) into one chunk, with the map that
+    /// sends each of its lines back to its part
+    pub fn concat(parts: &[(&str, &str)]) -> (String, SourceMap) {
+        let mut chunk = String::new();
+        let mut map = SourceMap::new();
+        let mut line = 1;
+        for (file, text) in parts {
+            map.add(line, file, 1);
+            chunk.push_str(text);
+            if !text.ends_with('\n') {
+                chunk.push('\n');
+            }
+            line += text.lines().count().max(1) as u32;
+        }
+        (chunk, map)
+    }
+}
+
+/// How to load a chunk (LuaState::load_buffer_with)
+#[derive(Debug, Clone)]
+pub struct LoadOptions {
+    /// As for load_buffer: "=name" or "@file" for messages
+    pub chunkname: String,
+    /// "b", "t" or "bt"
+    pub mode: String,
+    /// Lines of the chunk in the original sources
+    pub source_map: Option<SourceMap>,
+    /// _ENV of the chunk instead of the global table (load's `env`)
+    pub env: Option<LuaValue>,
+}
+
+impl Default for LoadOptions {
+    fn default() -> Self {
+        LoadOptions { chunkname: "=(load)".to_string(), mode: "bt".to_string(), source_map: None, env: None }
+    }
+}
+
+impl LuaState {
+    /// Load `data` as load_buffer does, first registering the options'
+    /// source map for its chunk name, then giving the chunk the options'
+    /// environment as its _ENV (its first upvalue), as load does
+    pub fn load_buffer_with(&mut self, data: &[u8], opts: &LoadOptions) -> crate::lerror::Result<()> {
+        if let Some(map) = &opts.source_map {
+            self.set_source_map(&opts.chunkname, map.clone());
+        }
+        self.load_buffer(data, &opts.chunkname, &opts.mode)?;
+        if let Some(env) = &opts.env {
+            self.set_chunk_env(env.clone());
+        }
+        Ok(())
+    }
+
+    /// Make `env` the _ENV of the chunk on top of the stack (its first
+    /// upvalue), as load does with its `env` argument. A binary chunk may
+    /// have no upvalues, and then `env` is simply dropped
+    pub fn set_chunk_env(&mut self, env: LuaValue) {
+        let L = self as *mut LuaState as *mut crate::lapi::lua_State;
+        self.push(env);
+        unsafe {
+            if crate::lapi::lua_setupvalue(L, -2, 1).is_null() {
+                self.pop(); // remove 'env' if not used by the chunk
+            }
+        }
+    }
+
+    /// Report lines of chunk `chunkname` through `map` from now on
+    pub fn set_source_map(&mut self, chunkname: &str, map: SourceMap) {
+        self.l_G.borrow_mut().source_maps.insert(chunkname.to_string(), map);
+    }
+
+    /// Original file and line of `line` in chunk `source`, if it has a map
+    pub fn map_source_line(&self, source: &str, line: i32) -> Option<(String, i32)> {
+        if line <= 0 {
+            return None;
+        }
+        let g = self.l_G.borrow();
+        let (file, l) = g.source_maps.get(source)?.resolve(line as u32)?;
+        Some((file.to_string(), l as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lstate::GlobalState;
+
+    #[test]
+    fn test_resolve_and_concat() {
+        let (chunk, map) = SourceMap::concat(&[("a.lua", "x = 1\ny = 2\n"), ("b.lua", "z = 3")]);
+        assert_eq!(chunk, "x = 1\ny = 2\nz = 3\n");
+        assert_eq!(map.resolve(2), Some(("a.lua", 2)));
+        assert_eq!(map.resolve(3), Some(("b.lua", 1)));
+        let mut m = SourceMap::new();
+        m.add(10, "page.tmpl", 40).add(5, "header.tmpl", 1);
+        assert_eq!(m.resolve(4), None);
+        assert_eq!(m.resolve(7), Some(("header.tmpl", 3)));
+        assert_eq!(m.resolve(12), Some(("page.tmpl", 42)));
+    }
+
+    #[test]
+    fn test_state_maps_by_chunkname() {
+        let mut L = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let (_, map) = SourceMap::concat(&[("a.lua", "x = 1\n"), ("b.lua", "error('boom')\n")]);
+        L.set_source_map("=bundle", map);
+        assert_eq!(L.map_source_line("=bundle", 2), Some(("b.lua".to_string(), 1)));
+        assert_eq!(L.map_source_line("=other", 2), None);
+        assert_eq!(L.map_source_line("=bundle", 0), None);
+    }
+
+    #[test]
+    fn test_load_env_is_first_upvalue() {
+        use crate::lvm::{closure_value, Closure, ClosureType, Proto, TValue, UpVal};
+        use std::ptr::NonNull;
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: true,
+            source: "=(load)".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: vec!["_ENV".to_string()],
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        // what load_buffer leaves on the stack for a text chunk
+        let mut chunk = Closure { cl: ClosureType { p: &mut p }, upvals: vec![UpVal::closed(TValue::nil())] };
+        let mut L = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        L.push(closure_value(NonNull::from(&mut chunk)));
+        let env = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        L.set_chunk_env(LuaValue::Table(env.clone()));
+        assert_eq!(L.stack.len(), 1);
+        unsafe { assert_eq!((*chunk.upvalue(1).unwrap().1).value.p, env.as_ptr() as *mut std::ffi::c_void) };
+
+        // a binary chunk without upvalues drops the environment
+        let mut bare = Closure { cl: ClosureType { p: &mut p }, upvals: Vec::new() };
+        L.push(closure_value(NonNull::from(&mut bare)));
+        L.set_chunk_env(LuaValue::Table(env));
+        assert_eq!(L.stack.len(), 2);
+    }
+}
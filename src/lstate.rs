@@ -9,9 +9,12 @@ use crate::lmem::*;
 use crate::lstring::*;
 use crate::ltable::*;
 use crate::lua::*;
+use crate::ldo::{LuaStatus, LuaThrow};
+use crate::lerror::Error;
+use crate::lcontext::{Cancel, Coroutine};
 use std::ptr;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::rc::{Rc, Weak};
 
 // --- CallInfo struct ---
 #[derive(Debug, Default)]
@@ -21,6 +24,10 @@ pub struct CallInfo {
     pub previous: Option<Rc<RefCell<CallInfo>>>,
     pub next: Option<Rc<RefCell<CallInfo>>>,
     pub callstatus: u32,
+    pub name: Option<String>, // name the function was called by, for argument errors
+    pub is_method: bool,      // called with ':' (self is not counted in argument errors)
+    pub savedpc: usize,       // Lua closures: instructions run when the VM last saved its pc
+    pub base: Option<ptr::NonNull<crate::lvm::TValue>>, // Lua closures: first register, saved with savedpc
     // ...other fields as needed...
 }
 
@@ -40,6 +47,12 @@ pub struct LuaState {
     pub error_jump: Option<usize>,
     // --- Upvalue management ---
     pub open_upvalues: Vec<LuaValue>,
+    // --- Message handler of the running protected call (L->errfunc) ---
+    pub errfunc: Option<LuaValue>,
+    // --- Stack slots to be closed (lua_toclose), lowest first ---
+    pub tbclist: Vec<usize>,
+    // --- Coroutine this state belongs to, None for the main thread ---
+    pub thread: Option<Weak<LuaThread>>,
 }
 
 // --- Global State ---
@@ -54,6 +67,45 @@ pub struct GlobalState {
     pub total_bytes: usize, // Total allocated bytes
     // --- Warning function (stub) ---
     pub warning_func: Option<fn(&str)>,
+    // --- File access for io/loadfile/require (None: lvfs::default_vfs) ---
+    pub vfs: Option<crate::lvfs::VfsHandle>,
+    // --- What the libraries may do outside the VM ---
+    pub sandbox: crate::lsandbox::SandboxPolicy,
+    // --- How chunks are compiled (optimization level, known globals) ---
+    pub compile: crate::lopt::CompileOptions,
+    // --- Order strings with strcoll (the C locale) instead of byte-wise ---
+    pub locale_aware: bool,
+    // --- Plugins loaded into this state (lplugin) ---
+    pub plugins: crate::lplugin::PluginSet,
+    // --- Standard output of print and io.write (loutput) ---
+    pub output: crate::loutput::OutputHandle,
+    // --- Source maps of loaded chunks, by chunk name (lsourcemap) ---
+    pub source_maps: std::collections::HashMap<String, crate::lsourcemap::SourceMap>,
+    // --- Generator of math.random (lmathlib) ---
+    pub rng: crate::lmathlib::RanState,
+    // --- Fixed seed and virtual clock of a deterministic state (ldeterm) ---
+    pub deterministic: Option<crate::ldeterm::DeterministicOptions>,
+    /// Do tables created by this state keep insertion order? (ldeterm)
+    pub ordered_tables: bool,
+    // --- Default files of io.read and io.write (liolib) ---
+    #[cfg(not(feature = "minimal"))]
+    pub io_defaults: crate::liolib::IoDefaults,
+    // --- Files of os.tmpname, removed with the state (loslib) ---
+    pub temp_files: crate::loslib::TempFiles,
+    // --- Variables set by os.setenv, over the process environment (lplatform) ---
+    pub env: crate::lplatform::EnvOverlay,
+    // --- What a failed api_check does (lapi) ---
+    pub api_check_policy: crate::lapi::ApiCheckPolicy,
+    // --- Converters of clone_value_to for non-plain values (lclone) ---
+    pub clone_converters: Vec<crate::lclone::CloneConverter>,
+    // --- Values pinned for the host by LuaRef (lref) ---
+    pub refs: crate::lref::RefTable,
+    // --- GC event callback and memory-pressure thresholds (lgc) ---
+    pub gc_events: GcEvents,
+    // --- Automatic collection stopped by the host (gc_set_paused) ---
+    pub gc_paused: bool,
+    // --- How luaL_traceback renders stacks (lauxlib) ---
+    pub traceback: crate::lauxlib::TracebackOptions,
 }
 
 // --- Functions (stubs, to be filled out as needed) ---
@@ -70,6 +122,9 @@ impl LuaState {
             hook: None,
             error_jump: None,
             open_upvalues: Vec::new(),
+            errfunc: None,
+            tbclist: Vec::new(),
+            thread: None,
         }
     }
     pub fn push(&mut self, value: LuaValue) {
@@ -87,6 +142,10 @@ impl LuaState {
     pub fn is_ok(&self) -> bool {
         self.status == TStatus::LUA_OK
     }
+    /// Choose what a failed API check does in this state and its threads
+    pub fn set_api_check_policy(&mut self, policy: crate::lapi::ApiCheckPolicy) {
+        self.l_G.borrow_mut().api_check_policy = policy;
+    }
     // --- More fields and helpers for LuaState ---
     pub fn stack_size(&self) -> usize {
         self.stack.len()
@@ -94,21 +153,197 @@ impl LuaState {
     pub fn clear_stack(&mut self) {
         self.stack.clear();
     }
-    pub fn get_global(&self, key: &str) -> Option<&LuaValue> {
-        // Example: lookup in registry/global table (stub)
-        Some(&LuaValue::Nil)
+    /// Field `key` of the globals table, None if absent
+    pub fn get_global(&mut self, key: &str) -> Option<LuaValue> {
+        let g = self.globals_table();
+        let v = g.borrow().get(&LuaValue::Str(key.to_string())).cloned();
+        v
     }
+    /// Store `value` in field `key` of the globals table (lua_setglobal)
     pub fn set_global(&mut self, key: &str, value: LuaValue) {
-        // Example: set in registry/global table (stub)
-    }
-    pub fn error(&mut self, msg: &str) {
+        let g = self.globals_table();
+        g.borrow_mut().rawset(&LuaValue::Str(key.to_string()), value);
+    }
+    /// Raise `msg` as a Lua error (luaL_error without the position): it
+    /// unwinds like `throw`, so the caller never resumes
+    pub fn error(&mut self, msg: &str) -> ! {
+        self.throw(LuaValue::Str(msg.to_string()))
+    }
+    /// Raise `err` as a Lua error (lua_error). The message handler of the
+    /// running protected call (`errfunc`) transforms it first, while the
+    /// frames of the error are still there (luaG_errormsg). It then unwinds
+    /// to that protected call, which returns it as the error object; no
+    /// panic message is printed on the way.
+    pub fn throw(&mut self, err: LuaValue) -> ! {
+        // an error in the handler itself is raised as it is
+        let err = match self.errfunc.take() {
+            Some(handler) => {
+                self.push(handler.clone());
+                self.push(err);
+                self.call(1, 1);
+                self.errfunc = Some(handler);
+                self.pop().unwrap_or(LuaValue::Nil)
+            }
+            None => err,
+        };
         self.status = TStatus::LUA_ERRRUN;
-        // In a real VM, would raise/propagate error
-        eprintln!("Lua error: {}", msg);
+        self.push(err);
+        std::panic::resume_unwind(Box::new(LuaThrow(LuaStatus::RuntimeError)))
+    }
+    /// Raise a Rust-side error in Lua: runtime errors keep their error
+    /// object, the others become their message
+    pub fn raise(&mut self, e: Error) -> ! {
+        match e {
+            Error::Memory => std::panic::resume_unwind(Box::new(LuaThrow(LuaStatus::MemoryError))),
+            e => self.throw(e.into_value()),
+        }
+    }
+    /// Run `f` in protected mode: a Lua error raised inside it comes back
+    /// as Err with its error object, whatever value it is, and the stack
+    /// is cut back to its size on entry. Panics that are not Lua errors
+    /// keep unwinding.
+    pub fn pcall<R>(&mut self, f: impl FnOnce(&mut LuaState) -> R) -> crate::lerror::Result<R> {
+        let oldtop = self.stack.len();
+        let (oldci, oldnci) = (self.ci.clone(), self.nci);
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&mut *self))) {
+            Ok(r) => Ok(r),
+            Err(payload) => {
+                let Some(&LuaThrow(status)) = payload.downcast_ref::<LuaThrow>() else {
+                    std::panic::resume_unwind(payload)
+                };
+                let mut err = match status {
+                    LuaStatus::MemoryError => Error::Memory,
+                    _ if self.stack.len() > oldtop => Error::Runtime(self.stack.pop().unwrap()),
+                    _ => Error::Runtime(LuaValue::Nil),
+                };
+                while self.tbclist.last().is_some_and(|&i| i >= oldtop) {
+                    let errobj = match &err {
+                        Error::Runtime(v) => v.clone(),
+                        e => LuaValue::Str(e.to_string()),
+                    };
+                    // an error in a __close replaces the one being handled
+                    if let Err(e) = self.pcall(|L| L.close_tbc(oldtop, Some(&errobj))) {
+                        err = e;
+                    }
+                }
+                self.stack.truncate(oldtop);
+                self.ci = oldci;
+                self.nci = oldnci;
+                self.status = TStatus::LUA_OK;
+                Err(err)
+            }
+        }
+    }
+    /// Call the value below the top `nargs` values with them as arguments
+    /// (lua_call): the value and the arguments are replaced by its results,
+    /// adjusted to `nresults` unless that is LUA_MULTRET (-1). A value that
+    /// is not a function is called through its __call metamethod. Errors
+    /// unwind to the nearest protected call.
+    pub fn call(&mut self, nargs: usize, nresults: i32) {
+        let func = self.stack.len() - nargs - 1;
+        let f = match &self.stack[func] {
+            LuaValue::Function(_) => self.stack[func].clone(),
+            v => match get_metafield(v, TMS::Call.name()) {
+                Some(h) => {
+                    // the called value becomes the handler's first argument
+                    self.stack.insert(func, h);
+                    return self.call(nargs + 1, nresults);
+                }
+                None => {
+                    let msg = format!("attempt to call a {} value", obj_typename(v));
+                    self.error(&msg)
+                }
+            },
+        };
+        let LuaValue::Function(f) = f else { unreachable!() };
+        let oldci = self.ci.clone();
+        self.ci = Rc::new(RefCell::new(CallInfo {
+            func,
+            top: self.stack.len(),
+            previous: Some(oldci.clone()),
+            ..CallInfo::default()
+        }));
+        self.nci += 1;
+        let n = f(self) as usize;
+        self.ci = oldci;
+        self.nci -= 1;
+        let first = self.stack.len() - n;
+        self.stack.drain(func..first);
+        if nresults >= 0 {
+            self.stack.resize_with(func + nresults as usize, || LuaValue::Nil);
+        }
+    }
+    /// Run a registered Rust callback. A panic inside it must not unwind
+    /// through the VM: it is caught here, the stack and the upvalues opened
+    /// since entry are unwound, and it is raised as the Lua error
+    /// "rust panic: <message>". Lua errors from the callback pass through,
+    /// and so does the unwinding of a coroutine being closed.
+    pub fn call_rust<R>(&mut self, f: impl FnOnce(&mut LuaState) -> R) -> R {
+        let (oldtop, oldupvals) = (self.stack.len(), self.open_upvalues.len());
+        let (oldci, oldnci) = (self.ci.clone(), self.nci);
+        let base = oldci.borrow().func + 1;
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&mut *self))) {
+            Ok(r) => {
+                // slots the callback marked are closed on its return (luaD_poscall)
+                self.close_tbc(base, None);
+                r
+            }
+            Err(payload) if payload.is::<LuaThrow>() || payload.is::<Cancel>() => {
+                std::panic::resume_unwind(payload)
+            }
+            Err(payload) => {
+                let msg = payload.downcast_ref::<&str>().copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("non-string panic payload");
+                let err = LuaValue::Str(format!("rust panic: {}", msg));
+                self.close_tbc(base, Some(&err));
+                self.open_upvalues.truncate(oldupvals); // close them
+                self.stack.truncate(oldtop);
+                self.ci = oldci;
+                self.nci = oldnci;
+                self.throw(err)
+            }
+        }
     }
+    /// Mark stack slot `idx` (0-based) as to-be-closed (lua_toclose): the
+    /// __close metamethod of its value runs when the slot is closed by
+    /// close_slot, when the Rust function that marked it returns, or when
+    /// an error unwinds past it, with the error object as second argument.
+    /// nil and false need no closing; other values must have __close.
+    pub fn to_close(&mut self, idx: usize) {
+        assert!(idx < self.stack.len() && self.tbclist.last().map_or(true, |&l| idx > l),
+            "invalid index for to-be-closed slot");
+        let v = &self.stack[idx];
+        if matches!(v, LuaValue::Nil | LuaValue::Bool(false)) {
+            return;
+        }
+        if get_metafield(v, TMS::Close.name()).is_none() {
+            self.throw(LuaValue::Str("variable '?' got a non-closable value".to_string()));
+        }
+        self.tbclist.push(idx);
+    }
+    /// Close the to-be-closed slots at `level` and above, the newest first
+    /// (luaF_close). Each is unmarked before its __close runs, so an error
+    /// in one leaves the others to whoever handles it.
+    pub fn close_tbc(&mut self, level: usize, err: Option<&LuaValue>) {
+        while let Some(idx) = self.tbclist.last().copied().filter(|&i| i >= level) {
+            self.tbclist.pop();
+            let v = self.stack.get(idx).cloned().unwrap_or(LuaValue::Nil);
+            if let Some(f) = get_metafield(&v, TMS::Close.name()) {
+                call_tm_vm(self, &f, &[v, err.cloned().unwrap_or(LuaValue::Nil)]);
+            }
+        }
+    }
+    /// Close the to-be-closed slot `idx` and set it to nil (lua_closeslot)
+    pub fn close_slot(&mut self, idx: usize) {
+        assert!(self.tbclist.last().map_or(true, |&l| l <= idx), "no variable to close at given level");
+        self.close_tbc(idx, None);
+        self.stack[idx] = LuaValue::Nil;
+    }
+    /// Whether the running function may yield (lua_isyieldable): only a
+    /// coroutine can, and not across a call that has no continuation
     pub fn is_yieldable(&self) -> bool {
-        // Placeholder: always yieldable
-        true
+        self.thread.is_some() && self.yieldable()
     }
     // --- More advanced VM helpers and fields ---
     pub fn yieldable(&self) -> bool {
@@ -158,19 +393,88 @@ impl LuaState {
         // TODO: implement value metatable logic
         None
     }
+    /// Identity of the object at stack slot `idx` (0-based), if it is collectable
+    pub fn object_id(&self, idx: usize) -> Option<ObjectId> {
+        self.stack.get(idx).and_then(ObjectId::of)
+    }
+}
+
+// --- Object identity ---
+
+/// Stable identity of a collectable value (table, function, userdata,
+/// thread), usable as a HashMap key by hosts that cache per-object data.
+/// Two ids are equal iff they name the same object; an id stays valid until
+/// the object is collected, after which its address may be reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectId(usize);
+
+impl ObjectId {
+    /// Identity of `v`, or None for values compared by value
+    pub fn of(v: &LuaValue) -> Option<ObjectId> {
+        let addr = match v {
+            LuaValue::Table(t) => Rc::as_ptr(t) as *const () as usize,
+            LuaValue::Function(f) => &**f as *const _ as *const () as usize,
+            LuaValue::UserData(u) => Rc::as_ptr(u) as *const () as usize,
+            LuaValue::Thread(th) => Rc::as_ptr(th) as *const () as usize,
+            _ => return None,
+        };
+        Some(ObjectId(addr))
+    }
+
+    /// The pointer lua_topointer returns for this object
+    pub fn as_ptr(self) -> *const std::ffi::c_void {
+        self.0 as *const std::ffi::c_void
+    }
+}
+
+/// Primitive equality (lua_rawequal): numbers by value across subtypes,
+/// strings by contents, collectable objects by identity; never calls __eq
+pub fn raw_equal(a: &LuaValue, b: &LuaValue) -> bool {
+    match (a, b) {
+        (LuaValue::Nil, LuaValue::Nil) => true,
+        (LuaValue::Bool(x), LuaValue::Bool(y)) => x == y,
+        (LuaValue::Int(x), LuaValue::Int(y)) => x == y,
+        (LuaValue::Float(x), LuaValue::Float(y)) => x == y,
+        (LuaValue::Int(i), LuaValue::Float(f)) | (LuaValue::Float(f), LuaValue::Int(i)) => f.fract() == 0.0 && *f as i128 == *i as i128,
+        (LuaValue::Str(x), LuaValue::Str(y)) => x == y,
+        (LuaValue::Pointer(x), LuaValue::Pointer(y)) => x == y,
+        _ => matches!((ObjectId::of(a), ObjectId::of(b)), (Some(x), Some(y)) if x == y),
+    }
 }
 
 impl GlobalState {
     pub fn new() -> Self {
-        GlobalState {
+        let g = GlobalState {
             gc: GarbageCollector::new(),
             strt: StringTable::new(),
             registry: LuaValue::Nil,
             nilvalue: LuaValue::Nil,
-            seed: 0,
+            seed: crate::lauxlib::makeseed(),
             total_bytes: 0,
             warning_func: None,
-        }
+            vfs: None,
+            sandbox: crate::lsandbox::SandboxPolicy::default(),
+            compile: crate::lopt::CompileOptions::default(),
+            locale_aware: false,
+            plugins: crate::lplugin::PluginSet::default(),
+            output: crate::loutput::OutputHandle::default(),
+            source_maps: std::collections::HashMap::new(),
+            rng: crate::lmathlib::RanState::default(),
+            deterministic: None,
+            ordered_tables: false,
+            #[cfg(not(feature = "minimal"))]
+            io_defaults: crate::liolib::IoDefaults::default(),
+            temp_files: crate::loslib::TempFiles::default(),
+            env: crate::lplatform::EnvOverlay::default(),
+            api_check_policy: crate::lapi::ApiCheckPolicy::default(),
+            clone_converters: Vec::new(),
+            refs: crate::lref::RefTable::default(),
+            gc_events: GcEvents::default(),
+            gc_paused: false,
+            traceback: crate::lauxlib::TracebackOptions::default(),
+        };
+        crate::ltable::set_hash_key(None);
+        g
     }
     pub fn set_registry(&mut self, value: LuaValue) {
         self.registry = value;
@@ -178,6 +482,7 @@ impl GlobalState {
     pub fn set_nilvalue(&mut self, value: LuaValue) {
         self.nilvalue = value;
     }
+    /// Reseed the string hashes
     pub fn set_seed(&mut self, seed: u32) {
         self.seed = seed;
     }
@@ -213,6 +518,62 @@ impl GlobalState {
     }
 }
 
+// --- Coroutines ---
+/// A coroutine (lua_newthread): a state of its own sharing the global
+/// state, and once resumed, the native stack its calls run on. Values hold
+/// it as LuaValue::Thread; its LuaState points back with a weak reference.
+pub struct LuaThread {
+    // declared first so it is dropped first: closing a suspended coroutine
+    // unwinds frames that still use the state
+    coroutine: UnsafeCell<Option<Box<Coroutine>>>,
+    state: UnsafeCell<LuaState>,
+    /// Number of values passed by the last resume or yield, on top of the stack
+    pub transfer: Cell<usize>,
+    /// Resumes nested below this one, against LUAI_MAXCCALLS
+    pub depth: Cell<usize>,
+}
+
+impl LuaThread {
+    /// A new thread with an empty stack (the base function slot only)
+    pub fn new(l_G: Rc<RefCell<GlobalState>>) -> Rc<LuaThread> {
+        Rc::new_cyclic(|weak| {
+            let mut state = LuaState::new(l_G);
+            state.thread = Some(weak.clone());
+            state.push(LuaValue::Nil);
+            LuaThread {
+                coroutine: UnsafeCell::new(None),
+                state: UnsafeCell::new(state),
+                transfer: Cell::new(0),
+                depth: Cell::new(0),
+            }
+        })
+    }
+    /// The thread's state. Like every `*mut lua_State`, the pointer stays
+    /// valid while the thread is alive, and the API is not reentrant on it.
+    pub fn state(&self) -> *mut LuaState {
+        self.state.get()
+    }
+    /// The native stack of a started coroutine, None before its first
+    /// resume and after it returns or dies
+    pub fn coroutine(&self) -> Option<&Coroutine> {
+        unsafe { (*self.coroutine.get()).as_deref() }
+    }
+    /// Replace the native stack; dropping a suspended one unwinds it
+    pub fn set_coroutine(&self, co: Option<Box<Coroutine>>) {
+        let old = unsafe { std::mem::replace(&mut *self.coroutine.get(), co) };
+        drop(old);
+    }
+}
+
+impl std::fmt::Debug for LuaThread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LuaThread")
+            .field("started", &self.coroutine().is_some())
+            .field("transfer", &self.transfer.get())
+            .finish_non_exhaustive()
+    }
+}
+
 // --- Example stub for a function ---
 pub fn luaE_setdebt(g: &mut GlobalState, debt: isize) {
     // ...implement logic for setting GC debt...
@@ -227,6 +588,33 @@ pub fn luaE_freethread(_L: &mut LuaState, _L1: &mut LuaState) {
     // In Rust, memory is managed automatically, but you can add cleanup logic here if needed.
 }
 
+/// Minimum stack size kept by a reset thread (LUA_MINSTACK)
+pub const LUA_MINSTACK: usize = 20;
+
+/// Unwind every call of `L`, close its open upvalues and empty its stack,
+/// marking it OK so it can run a new function. A yield counts as a clean
+/// stop; for an error `status`, the error object (the value on top, or the
+/// recorded message) is left as the only value on the stack and `status`
+/// is returned.
+pub fn luaE_resetthread(L: &mut LuaState, status: TStatus) -> TStatus {
+    let status = if status == TStatus::LUA_YIELD { TStatus::LUA_OK } else { status };
+    let errobj = if status != TStatus::LUA_OK {
+        L.stack.last().cloned().or_else(|| L.error.clone().map(LuaValue::Str))
+    } else {
+        None
+    };
+    L.ci = Rc::new(RefCell::new(CallInfo::default())); // unwind CallInfo list
+    L.open_upvalues.clear();
+    L.stack.clear();
+    L.stack.shrink_to(LUA_MINSTACK);
+    L.stack.extend(errobj);
+    L.status = TStatus::LUA_OK;
+    L.error = None;
+    L.pc = 0;
+    L.error_jump = None;
+    status
+}
+
 // --- Example: CallInfo extension ---
 impl CallInfo {
     pub fn extend(&mut self) -> Rc<RefCell<CallInfo>> {
@@ -248,11 +636,11 @@ pub fn luaE_incCstack(_L: &mut LuaState) {
 }
 
 pub fn luaE_warning(_L: &LuaState, msg: &str, _tocont: bool) {
-    eprintln!("Lua warning: {}", msg);
+    crate::lplatform::lua_writestringerror(&format!("Lua warning: {}\n", msg));
 }
 
 pub fn luaE_warnerror(_L: &LuaState, where_: &str) {
-    eprintln!("Lua VM error in {}", where_);
+    crate::lplatform::lua_writestringerror(&format!("Lua VM error in {}\n", where_));
 }
 
 // --- Test scaffolding ---
@@ -277,11 +665,37 @@ mod tests {
         assert_eq!(state.stack_size(), 0);
     }
     #[test]
-    fn test_error_status() {
+    fn test_object_identity() {
+        let t = LuaValue::Table(Rc::new(RefCell::new(Table::new())));
+        let same = t.clone();
+        let other = LuaValue::Table(Rc::new(RefCell::new(Table::new())));
+        assert_eq!(ObjectId::of(&t), ObjectId::of(&same));
+        assert_ne!(ObjectId::of(&t), ObjectId::of(&other));
+        assert_eq!(ObjectId::of(&LuaValue::Int(1)), None);
+        assert!(raw_equal(&t, &same) && !raw_equal(&t, &other));
+        assert!(raw_equal(&LuaValue::Int(3), &LuaValue::Float(3.0)));
+        assert!(!raw_equal(&LuaValue::Int(3), &LuaValue::Str("3".to_string())));
+    }
+    #[test]
+    fn test_error_unwinds() {
         let g = Rc::new(RefCell::new(GlobalState::new()));
         let mut state = LuaState::new(g);
-        state.error("fail");
-        assert_eq!(state.status, TStatus::LUA_ERRRUN);
+        let r: crate::lerror::Result<()> = state.pcall(|L| L.error("fail"));
+        assert_eq!(r.unwrap_err().to_string(), "fail");
+        assert_eq!(state.status, TStatus::LUA_OK);
+    }
+    #[test]
+    fn test_api_check_policy() {
+        use crate::lapi::{lua_pushvalue, ApiCheckPolicy};
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g);
+        let L = &mut state as *mut LuaState as *mut crate::lapi::lua_State;
+        // the default policy panics out of the API function
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe { lua_pushvalue(L, -5) }));
+        assert!(r.is_err());
+        state.set_api_check_policy(ApiCheckPolicy::LuaError);
+        let r = state.pcall(|_| unsafe { lua_pushvalue(L, -5) });
+        assert_eq!(r.unwrap_err().to_string(), "API check failed: invalid index");
     }
 }
 
@@ -301,8 +715,9 @@ mod more_tests {
     fn test_error_status() {
         let g = Rc::new(RefCell::new(GlobalState::new()));
         let mut state = LuaState::new(g);
-        state.error("fail");
-        assert_eq!(state.status, TStatus::LUA_ERRRUN);
+        let r = state.pcall(|L| L.error("fail"));
+        assert!(r.is_err());
+        assert!(state.stack.is_empty());
     }
 }
 
@@ -321,6 +736,75 @@ mod advanced_tests {
         state.dec_nyci();
         assert!(state.yieldable());
     }
+    #[test]
+    fn test_tbc_slots_unwind_with_errors() {
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g);
+        state.push(LuaValue::Nil);
+        state.push(LuaValue::Bool(false));
+        state.to_close(1); // false: nothing to close
+        assert!(state.tbclist.is_empty());
+        state.tbclist.push(0);
+        let r = state.pcall(|L| {
+            L.push(LuaValue::Int(1));
+            L.to_close(2)
+        });
+        assert!(matches!(r, Err(Error::Runtime(LuaValue::Str(ref m))) if m.contains("non-closable")));
+        let r = state.pcall(|L| {
+            L.push(LuaValue::Nil);
+            L.tbclist.push(2);
+            L.throw(LuaValue::Nil)
+        });
+        assert!(r.is_err());
+        // the slot above the protected call's level was closed, the outer one kept
+        assert_eq!(state.tbclist, vec![0]);
+        state.close_slot(0);
+        assert!(state.tbclist.is_empty() && state.stack_size() == 2);
+    }
+    #[test]
+    fn test_pcall_catches_thrown_error() {
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g);
+        state.push(LuaValue::Int(1));
+        let r = state.pcall(|L| {
+            L.push(LuaValue::Int(2));
+            L.throw(LuaValue::Str("file.lua:3: boom".to_string()))
+        });
+        assert!(matches!(r, Err(Error::Runtime(LuaValue::Str(ref m))) if m == "file.lua:3: boom"));
+        assert_eq!(state.stack_size(), 1);
+        assert!(state.is_ok());
+        assert!(matches!(state.pcall(|L| L.stack_size()), Ok(1)));
+    }
+    #[test]
+    fn test_pcall_keeps_error_value() {
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g);
+        let t = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        let r = state.pcall(|L| L.throw(LuaValue::Table(t.clone())));
+        assert!(matches!(r, Err(Error::Runtime(LuaValue::Table(ref e))) if Rc::ptr_eq(e, &t)));
+        let r = state.pcall(|L| L.raise(Error::Memory));
+        assert!(matches!(r, Err(Error::Memory)));
+    }
+    #[test]
+    fn test_panic_in_callback_under_pcall() {
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g);
+        state.push(LuaValue::Int(1));
+        let r = state.pcall(|L| {
+            L.call_rust(|L| {
+                L.push(LuaValue::Int(2));
+                L.open_upvalues.push(LuaValue::Int(3));
+                let v: Vec<i32> = Vec::new();
+                v[0]
+            })
+        });
+        assert!(matches!(r, Err(Error::Runtime(LuaValue::Str(ref m))) if m.starts_with("rust panic: index out of bounds")));
+        assert_eq!(state.stack_size(), 1);
+        assert!(state.open_upvalues.is_empty());
+        // Lua errors are not reported as panics
+        let r = state.pcall(|L| L.call_rust(|L| L.throw(LuaValue::Int(7))));
+        assert!(matches!(r, Err(Error::Runtime(LuaValue::Int(7)))));
+    }
 }
 
 // --- Coroutine/thread helpers and more advanced state management ---
@@ -350,6 +834,23 @@ mod coroutine_tests {
         assert!(state.resume().is_ok());
         assert!(state.yield_thread().is_ok());
     }
+    #[test]
+    fn test_resetthread() {
+        let g = Rc::new(RefCell::new(GlobalState::new()));
+        let mut state = LuaState::new(g);
+        state.push(LuaValue::Int(1));
+        state.open_upvalues.push(LuaValue::Int(2));
+        assert_eq!(luaE_resetthread(&mut state, TStatus::LUA_YIELD), TStatus::LUA_OK);
+        assert_eq!(state.stack_size(), 0);
+        assert!(state.open_upvalues.is_empty());
+        // a dead thread keeps its error object, but can run again
+        state.push(LuaValue::Int(1));
+        state.push(LuaValue::Str("boom".to_string()));
+        state.status = TStatus::LUA_ERRRUN;
+        assert_eq!(luaE_resetthread(&mut state, TStatus::LUA_ERRRUN), TStatus::LUA_ERRRUN);
+        assert_eq!(state.stack, vec![LuaValue::Str("boom".to_string())]);
+        assert!(state.is_ok());
+    }
 }
 
 // --- Hook and error jump management, upvalue helpers ---
@@ -0,0 +1,242 @@
+//! lstring.rs - String table: interned short strings (Rust port)
+// Ported from lstring.c
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// Maximum length of a short (interned) string, as LUAI_MAXSHORTLEN
+pub const LUAI_MAXSHORTLEN: usize = 40;
+
+thread_local! {
+    // The string table (g->strt): every live short string, once
+    static STRT: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+    // Counters behind luaS_stats
+    static COUNTERS: RefCell<Counters> = RefCell::new(Counters::default());
+}
+
+#[derive(Default)]
+struct Counters {
+    lookups: u64,      // short strings requested
+    hits: u64,         // ... found already interned
+    long_strings: usize,
+    long_bytes: usize,
+}
+
+/// Interning statistics and string memory, for gc_stats and
+/// debug.stringstats
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StringStats {
+    /// Strings in the string table
+    pub interned: usize,
+    /// Bytes of their contents
+    pub interned_bytes: usize,
+    /// Live long (not interned) strings and their bytes
+    pub long_strings: usize,
+    pub long_bytes: usize,
+    /// Strings in the table per slot it has room for
+    pub load_factor: f64,
+    /// Short strings requested, and how many of them were already interned
+    pub lookups: u64,
+    pub hits: u64,
+}
+
+impl StringStats {
+    /// Bytes of all live strings
+    pub fn total_bytes(&self) -> usize {
+        self.interned_bytes + self.long_bytes
+    }
+
+    /// Fraction of lookups served from the string table (0 without any)
+    pub fn hit_rate(&self) -> f64 {
+        if self.lookups == 0 { 0.0 } else { self.hits as f64 / self.lookups as f64 }
+    }
+}
+
+/// An immutable Lua string. Short strings are interned, so two short
+/// strings are equal exactly when they share an allocation and they hash
+/// by address; long strings compare and hash by content. Cloning only
+/// bumps a reference count.
+#[derive(Clone)]
+pub struct TString(Rc<str>);
+
+impl TString {
+    /// luaS_new: the interned string for `s` if short, a fresh one if long
+    pub fn new(s: &str) -> TString {
+        if s.len() > LUAI_MAXSHORTLEN {
+            COUNTERS.with(|c| {
+                let mut c = c.borrow_mut();
+                c.long_strings += 1;
+                c.long_bytes += s.len();
+            });
+            return TString(Rc::from(s));
+        }
+        STRT.with(|strt| {
+            let mut strt = strt.borrow_mut();
+            let hit = strt.get(s).cloned();
+            COUNTERS.with(|c| {
+                let mut c = c.borrow_mut();
+                c.lookups += 1;
+                c.hits += hit.is_some() as u64;
+            });
+            if let Some(ts) = hit {
+                return TString(ts);
+            }
+            let ts: Rc<str> = Rc::from(s);
+            strt.insert(ts.clone());
+            TString(ts)
+        })
+    }
+
+    pub fn is_short(&self) -> bool {
+        self.0.len() <= LUAI_MAXSHORTLEN
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Drop the interned strings nobody else references (called by the full
+/// GC); returns how many were freed
+pub fn luaS_sweep() -> usize {
+    STRT.with(|strt| {
+        let mut strt = strt.borrow_mut();
+        let before = strt.len();
+        strt.retain(|ts| Rc::strong_count(ts) > 1);
+        before - strt.len()
+    })
+}
+
+/// Number of strings in the string table
+pub fn luaS_tablesize() -> usize {
+    STRT.with(|strt| strt.borrow().len())
+}
+
+/// Current StringStats (the counters are per thread, like the table)
+pub fn luaS_stats() -> StringStats {
+    let (interned, interned_bytes, capacity) = STRT.with(|strt| {
+        let strt = strt.borrow();
+        (strt.len(), strt.iter().map(|ts| ts.len()).sum(), strt.capacity())
+    });
+    COUNTERS.with(|c| {
+        let c = c.borrow();
+        StringStats {
+            interned,
+            interned_bytes,
+            long_strings: c.long_strings,
+            long_bytes: c.long_bytes,
+            load_factor: if capacity == 0 { 0.0 } else { interned as f64 / capacity as f64 },
+            lookups: c.lookups,
+            hits: c.hits,
+        }
+    })
+}
+
+impl Drop for TString {
+    fn drop(&mut self) {
+        // the last reference to a long string frees it
+        if !self.is_short() && Rc::strong_count(&self.0) == 1 {
+            let _ = COUNTERS.try_with(|c| {
+                let mut c = c.borrow_mut();
+                c.long_strings -= 1;
+                c.long_bytes -= self.0.len();
+            });
+        }
+    }
+}
+
+impl PartialEq for TString {
+    fn eq(&self, other: &TString) -> bool {
+        if self.is_short() && other.is_short() {
+            Rc::ptr_eq(&self.0, &other.0)
+        } else {
+            self.0 == other.0
+        }
+    }
+}
+
+impl Eq for TString {}
+
+impl Hash for TString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if self.is_short() {
+            (Rc::as_ptr(&self.0) as *const u8 as usize).hash(state);
+        } else {
+            self.0.hash(state);
+        }
+    }
+}
+
+impl Deref for TString {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for TString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for TString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for TString {
+    fn from(s: &str) -> Self { TString::new(s) }
+}
+
+impl From<String> for TString {
+    fn from(s: String) -> Self { TString::new(&s) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_short_strings_are_interned() {
+        let a = TString::new("key");
+        let b = TString::from(String::from("key"));
+        assert!(Rc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a, b);
+        assert_ne!(a, TString::new("other"));
+        let long = "x".repeat(LUAI_MAXSHORTLEN + 1);
+        let (c, d) = (TString::new(&long), TString::new(&long));
+        assert!(!Rc::ptr_eq(&c.0, &d.0));
+        assert_eq!(c, d);
+    }
+    #[test]
+    fn test_sweep() {
+        let kept = TString::new("sweep_kept");
+        drop(TString::new("sweep_dropped"));
+        let before = luaS_tablesize();
+        assert!(luaS_sweep() >= 1);
+        assert!(luaS_tablesize() < before);
+        assert_eq!(TString::new("sweep_kept"), kept);
+    }
+    #[test]
+    fn test_stats() {
+        let before = luaS_stats();
+        let a = TString::new("stats_key");
+        let _b = TString::new("stats_key");
+        let long = TString::new(&"y".repeat(LUAI_MAXSHORTLEN + 10));
+        let _long2 = long.clone();
+        let st = luaS_stats();
+        assert_eq!(st.lookups - before.lookups, 2);
+        assert_eq!(st.hits - before.hits, 1);
+        assert_eq!(st.long_strings, before.long_strings + 1);
+        assert_eq!(st.long_bytes, before.long_bytes + LUAI_MAXSHORTLEN + 10);
+        assert!(st.interned_bytes >= a.len());
+        assert!(st.load_factor > 0.0 && st.load_factor <= 1.0);
+        drop((long, _long2));
+        assert_eq!(luaS_stats().long_bytes, before.long_bytes);
+    }
+}
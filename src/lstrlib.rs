@@ -56,6 +56,16 @@ pub fn str_rep(s: &str, n: usize, sep: Option<&str>) -> String {
     std::iter::repeat(s).take(n).collect::<Vec<_>>().join(sep)
 }
 
+/// Length of `n` copies of a string of length `l` joined by a separator
+/// of length `lsep`; None if it would exceed MAX_SIZE
+pub fn rep_size(l: usize, lsep: usize, n: usize) -> Option<usize> {
+    if n == 0 {
+        return Some(0);
+    }
+    let total = l.checked_add(lsep)?.checked_mul(n)?.checked_sub(lsep)?;
+    (total <= crate::llimits::MAX_SIZE).then_some(total)
+}
+
 /// Returns the bytes at the given positions (1-based)
 pub fn str_byte(s: &str, start: isize, end: Option<isize>) -> Vec<u8> {
     let bytes = s.as_bytes();
@@ -71,310 +81,1221 @@ pub fn str_char(bytes: &[u8]) -> String {
     bytes.iter().map(|&b| b as char).collect()
 }
 
-// --- Minimal Lua pattern-matching engine (partial, extensible) ---
-use std::collections::HashSet;
+// --- string.format (str_format of lstrlib.c) ---
+// Directives are checked as in Lua 5.4: each conversion takes only the
+// flags C gives a meaning for it, and width and precision have at most
+// two digits. Numbers are formatted here, the way C's printf does, so the
+// output does not depend on the locale.
+
+/// Why str_format rejected its arguments
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// Argument `n` (0-based in `args`) does not suit its directive; the
+    /// message is the argument error's ("no value", "number expected, got
+    /// table", ...)
+    BadArgument(usize, String),
+    /// The format itself is wrong ("invalid conversion '%10q' to 'format'")
+    Invalid(String),
+}
+
+/// Directives this long or longer ('%' not counted) are rejected
+/// (MAX_FORMAT - 10 in lstrlib.c)
+const MAX_DIRECTIVE: usize = 22;
+
+/// Flags taken by a, A, e, E, f, F, g and G (L_FMTFLAGSF)
+const FLAGS_F: &str = "-+ #0";
+/// Flags taken by o, x and X (L_FMTFLAGSX)
+const FLAGS_X: &str = "-#0";
+/// Flags taken by d and i (L_FMTFLAGSI)
+const FLAGS_I: &str = "-+ 0";
+/// Flags taken by c, p and s (L_FMTFLAGSC)
+const FLAGS_C: &str = "-";
+
+/// Flags, width and precision of a directive such as "%-08.3f"
+struct Spec<'a> {
+    flags: &'a str,
+    width: usize,
+    prec: Option<usize>,
+}
+
+impl Spec<'_> {
+    fn has(&self, flag: char) -> bool {
+        self.flags.contains(flag)
+    }
+}
+
+/// Check directive `form` ('%', modifiers, conversion) against the flags
+/// its conversion takes and whether it may have a precision (checkformat)
+fn check_spec<'a>(form: &'a str, flags: &str, precision: bool) -> Result<Spec<'a>, FormatError> {
+    let body = &form[1..form.len() - 1];
+    let nflags = body.find(|c| !flags.contains(c)).unwrap_or(body.len());
+    // at most two digits, as get2digits
+    let digits = |s: &'a str| {
+        let n = s.bytes().take(2).take_while(u8::is_ascii_digit).count();
+        (s[..n].parse::<usize>().unwrap_or(0), &s[n..])
+    };
+    let mut spec = Spec { flags: &body[..nflags], width: 0, prec: None };
+    let mut rest = &body[nflags..];
+    if !rest.starts_with('0') {
+        // a width cannot start with '0'
+        (spec.width, rest) = digits(rest);
+        if let Some(r) = rest.strip_prefix('.').filter(|_| precision) {
+            let (p, r) = digits(r);
+            (spec.prec, rest) = (Some(p), r);
+        }
+    }
+    if !rest.is_empty() {
+        return Err(FormatError::Invalid(format!("invalid conversion '{}' to 'format'", form)));
+    }
+    Ok(spec)
+}
+
+/// `body` after `lead` (sign and radix prefix), padded to the width:
+/// on the right with the '-' flag, else with zeros between the two when
+/// `zeros` allows the '0' flag, else on the left
+fn pad(spec: &Spec, lead: &str, body: &str, zeros: bool) -> String {
+    let fill = spec.width.saturating_sub(lead.len() + body.len());
+    if spec.has('-') {
+        format!("{}{}{}", lead, body, " ".repeat(fill))
+    } else if zeros && spec.has('0') {
+        format!("{}{}{}", lead, "0".repeat(fill), body)
+    } else {
+        format!("{}{}{}", " ".repeat(fill), lead, body)
+    }
+}
+
+/// Sign of a number as the '+' and ' ' flags ask for it
+fn sign(spec: &Spec, negative: bool) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.has('+') {
+        "+"
+    } else if spec.has(' ') {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// An integer for %d, %i, %o, %x or %X; the last three show it unsigned
+fn format_int(spec: &Spec, conv: char, n: LuaInteger) -> String {
+    let u = n as crate::skylaconf::LuaUnsigned;
+    let mut digits = match conv {
+        'o' => format!("{:o}", u),
+        'x' => format!("{:x}", u),
+        'X' => format!("{:X}", u),
+        _ => n.unsigned_abs().to_string(),
+    };
+    // the precision is the least number of digits
+    if let Some(p) = spec.prec {
+        if p == 0 && n == 0 {
+            digits.clear();
+        } else if digits.len() < p {
+            digits.insert_str(0, &"0".repeat(p - digits.len()));
+        }
+    }
+    let lead = match conv {
+        'd' | 'i' => sign(spec, n < 0),
+        'o' if spec.has('#') && !digits.starts_with('0') => {
+            digits.insert(0, '0');
+            ""
+        }
+        'x' if spec.has('#') && n != 0 => "0x",
+        'X' if spec.has('#') && n != 0 => "0X",
+        _ => "",
+    };
+    pad(spec, lead, &digits, spec.prec.is_none())
+}
+
+/// C's "%.<prec>e" of a non-negative finite float: the exponent has a
+/// sign and at least two digits
+fn fmt_e(v: crate::skylaconf::LuaFloat, prec: usize) -> String {
+    let s = format!("{:.*e}", prec, v);
+    let (mant, exp) = s.split_once('e').unwrap();
+    let x: i32 = exp.parse().unwrap();
+    format!("{}e{}{:02}", mant, if x < 0 { '-' } else { '+' }, x.abs())
+}
+
+/// C's "%a" of a non-negative finite float, without the "0x": leading
+/// digit 1 (0 for zero and subnormals), then the fraction rounded to
+/// `prec` hex digits, or as many as it needs (lua_number2strx)
+fn fmt_hex_float(v: crate::skylaconf::LuaFloat, prec: Option<usize>) -> String {
+    let bits = (v as f64).to_bits();
+    let (biased, mut frac) = ((bits >> 52) as i32 & 0x7ff, bits & ((1 << 52) - 1));
+    let (mut lead, exp) = match (biased, frac) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022),
+        _ => (1, biased - 1023),
+    };
+    let mut ndigits = 13; // of the 52 fraction bits
+    if let Some(p) = prec.filter(|&p| p < 13) {
+        // round to nearest, ties to even, as printf does
+        let shift = 4 * (13 - p) as u32;
+        let (rem, half) = (frac & ((1 << shift) - 1), 1 << (shift - 1));
+        frac >>= shift;
+        if rem > half || (rem == half && frac & 1 == 1) {
+            frac += 1;
+        }
+        if frac >> (4 * p) != 0 {
+            lead += 1;
+            frac &= (1 << (4 * p)) - 1;
+        }
+        ndigits = p;
+    }
+    let mut digits = if ndigits == 0 { String::new() } else { format!("{:01$x}", frac, ndigits) };
+    match prec {
+        None => digits.truncate(digits.trim_end_matches('0').len()),
+        Some(p) => digits.push_str(&"0".repeat(p.saturating_sub(13))),
+    }
+    let point = if digits.is_empty() { "" } else { "." };
+    format!("{}{}{}p{:+}", lead, point, digits, exp)
+}
+
+/// A float for %a, %A, %e, %E, %f, %F, %g or %G
+fn format_float(spec: &Spec, conv: char, n: crate::skylaconf::LuaFloat) -> String {
+    let v = n.abs();
+    let lower = conv.to_ascii_lowercase();
+    let mut lead = sign(spec, n.is_sign_negative()).to_string();
+    let mut body = if v.is_nan() {
+        "nan".to_string()
+    } else if v.is_infinite() {
+        "inf".to_string()
+    } else {
+        match lower {
+            'f' => format!("{:.*}", spec.prec.unwrap_or(6), v),
+            'e' => fmt_e(v, spec.prec.unwrap_or(6)),
+            'g' => crate::lobject::luaO_fmt_g(v, spec.prec.unwrap_or(6)),
+            _ => {
+                lead.push_str("0x");
+                fmt_hex_float(v, spec.prec)
+            }
+        }
+    };
+    // '#' keeps the point even without fraction digits
+    if spec.has('#') && v.is_finite() && lower != 'g' && !body.contains('.') {
+        let at = body.find(['e', 'p']).unwrap_or(body.len());
+        body.insert(at, '.');
+    }
+    if conv.is_ascii_uppercase() {
+        lead.make_ascii_uppercase();
+        body.make_ascii_uppercase();
+    }
+    pad(spec, &lead, &body, v.is_finite())
+}
+
+/// `s` in double quotes, escaped so that Lua reads it back (addquoted)
+fn add_quoted(out: &mut String, s: &str) {
+    out.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\\' | '\n' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\r' => out.push_str("\\r"),
+            c if c.is_ascii_control() => {
+                // a following digit would be read as part of the escape
+                if chars.peek().is_some_and(char::is_ascii_digit) {
+                    out.push_str(&format!("\\{:03}", c as u32));
+                } else {
+                    out.push_str(&format!("\\{}", c as u32));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// `v` as Lua source that reads back as the same value (addliteral):
+/// floats in hexadecimal so no digit is lost
+fn add_literal(out: &mut String, v: &LuaValue) -> Result<(), String> {
+    match v {
+        LuaValue::Str(s) => add_quoted(out, s),
+        // -9223372036854775808 would read back as a float
+        LuaValue::Int(n) if *n == LuaInteger::MIN => out.push_str(&format!("{:#x}", n)),
+        LuaValue::Int(n) => out.push_str(&crate::lobject::luaO_int2str(*n)),
+        LuaValue::Float(f) if f.is_nan() => out.push_str("(0/0)"),
+        LuaValue::Float(f) if f.is_infinite() => out.push_str(if *f > 0.0 { "1e9999" } else { "-1e9999" }),
+        LuaValue::Float(f) => {
+            out.push_str(if f.is_sign_negative() { "-0x" } else { "0x" });
+            out.push_str(&fmt_hex_float(f.abs(), None));
+        }
+        LuaValue::Nil => out.push_str("nil"),
+        LuaValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        _ => return Err("value has no literal form".to_string()),
+    }
+    Ok(())
+}
+
+/// Argument `i` as a type error for a number
+fn not_a_number(args: &[LuaValue], i: usize) -> FormatError {
+    let actual = crate::lauxlib::error_typename(&args[i]);
+    FormatError::BadArgument(i, crate::lauxlib::typeerror_message("number", &actual))
+}
+
+/// Argument `i` as a float; numeric strings convert (luaL_checknumber)
+fn format_number(args: &[LuaValue], i: usize) -> Result<crate::skylaconf::LuaFloat, FormatError> {
+    use crate::lobject::Numeral;
+    match &args[i] {
+        LuaValue::Int(n) => Ok(*n as crate::skylaconf::LuaFloat),
+        LuaValue::Float(f) => Ok(*f),
+        LuaValue::Str(s) => match crate::lobject::luaO_str2number(s) {
+            Some(Numeral::Int(n)) => Ok(n as crate::skylaconf::LuaFloat),
+            Some(Numeral::Float(f)) => Ok(f),
+            None => Err(not_a_number(args, i)),
+        },
+        _ => Err(not_a_number(args, i)),
+    }
+}
+
+/// Argument `i` as an integer; floats with an exact integer value and
+/// numeric strings convert (luaL_checkinteger)
+fn format_integer(args: &[LuaValue], i: usize) -> Result<LuaInteger, FormatError> {
+    use crate::lobject::Numeral;
+    let f = match &args[i] {
+        LuaValue::Int(n) => return Ok(*n),
+        LuaValue::Float(f) => *f,
+        LuaValue::Str(s) => match crate::lobject::luaO_str2number(s) {
+            Some(Numeral::Int(n)) => return Ok(n),
+            Some(Numeral::Float(f)) => f,
+            None => return Err(not_a_number(args, i)),
+        },
+        _ => return Err(not_a_number(args, i)),
+    };
+    crate::skylaconf::float_to_integer(f)
+        .ok_or_else(|| FormatError::BadArgument(i, "number has no integer representation".to_string()))
+}
+
+/// Format `args` like string.format, with the directives of Lua 5.4:
+/// %c, %d, %i, %o, %x, %X, %a, %A, %e, %E, %f, %F, %g, %G, %p, %q, %s
+/// and %%. %s shows strings, numbers, booleans and nil as tostring does,
+/// and hands other values to `tostring`, which may call __tostring.
+pub fn str_format(
+    fmt: &str,
+    args: &[LuaValue],
+    mut tostring: impl FnMut(&LuaValue) -> String,
+) -> Result<String, FormatError> {
+    let mut out = String::new();
+    let mut rest = fmt;
+    let mut next = 0; // index in `args` of the next directive's argument
+    while let Some(pos) = rest.find('%') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(r) = rest.strip_prefix('%') {
+            out.push('%');
+            rest = r;
+            continue;
+        }
+        let i = next;
+        next += 1;
+        if i >= args.len() {
+            return Err(FormatError::BadArgument(i, "no value".to_string()));
+        }
+        // modifiers, then the conversion (getformat)
+        let len = rest.find(|c| !"-+ #0123456789.".contains(c)).unwrap_or(rest.len());
+        if len + 1 >= MAX_DIRECTIVE {
+            return Err(FormatError::Invalid("invalid format string to 'format'".to_string()));
+        }
+        let conv = rest[len..].chars().next().unwrap_or('\0');
+        let end = (len + conv.len_utf8()).min(rest.len());
+        let form = format!("%{}", &rest[..end]);
+        rest = &rest[end..];
+        match conv {
+            'c' => {
+                let spec = check_spec(&form, FLAGS_C, false)?;
+                let n = format_integer(args, i)?;
+                out.push_str(&pad(&spec, "", &char::from(n as u8).to_string(), false));
+            }
+            'd' | 'i' => {
+                let n = format_integer(args, i)?;
+                out.push_str(&format_int(&check_spec(&form, FLAGS_I, true)?, conv, n));
+            }
+            'o' | 'x' | 'X' => {
+                let n = format_integer(args, i)?;
+                out.push_str(&format_int(&check_spec(&form, FLAGS_X, true)?, conv, n));
+            }
+            'a' | 'A' | 'e' | 'E' | 'f' | 'F' | 'g' | 'G' => {
+                let n = format_number(args, i)?;
+                out.push_str(&format_float(&check_spec(&form, FLAGS_F, true)?, conv, n));
+            }
+            'p' => {
+                let spec = check_spec(&form, FLAGS_C, false)?;
+                let addr = match &args[i] {
+                    LuaValue::Pointer(p) => *p as usize,
+                    v => crate::lstate::ObjectId::of(v).map_or(0, |id| id.as_ptr() as usize),
+                };
+                let body = if addr == 0 { "(null)".to_string() } else { format!("{:#x}", addr) };
+                out.push_str(&pad(&spec, "", &body, false));
+            }
+            'q' => {
+                if form.len() > 2 {
+                    return Err(FormatError::Invalid("specifier '%q' cannot have modifiers".to_string()));
+                }
+                add_literal(&mut out, &args[i]).map_err(|msg| FormatError::BadArgument(i, msg))?;
+            }
+            's' => {
+                let s = match &args[i] {
+                    LuaValue::Str(s) => s.clone(),
+                    LuaValue::Int(n) => crate::lobject::luaO_int2str(*n),
+                    LuaValue::Float(f) => crate::lobject::luaO_num2str_dot(*f),
+                    LuaValue::Bool(b) => b.to_string(),
+                    LuaValue::Nil => "nil".to_string(),
+                    v => tostring(v),
+                };
+                if form.len() == 2 {
+                    out.push_str(&s); // keep the entire string
+                    continue;
+                }
+                if s.contains('\0') {
+                    return Err(FormatError::BadArgument(i, "string contains zeros".to_string()));
+                }
+                let spec = check_spec(&form, FLAGS_C, true)?;
+                // the precision is the most bytes shown
+                let mut shown = spec.prec.unwrap_or(s.len()).min(s.len());
+                while !s.is_char_boundary(shown) {
+                    shown -= 1;
+                }
+                out.push_str(&pad(&spec, "", &s[..shown], false));
+            }
+            _ => return Err(FormatError::Invalid(format!("invalid conversion '{}' to 'format'", form))),
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// --- Lua pattern matching (match machinery of lstrlib.c) ---
+// Patterns work on bytes: positions are byte offsets, as in Lua, and the
+// classes are those of the C locale.
+
+/// Most captures a pattern can have (LUA_MAXCAPTURES)
+pub const LUA_MAXCAPTURES: usize = 32;
+
+/// Recursion limit of the matcher (MAXCCALLS)
+const MAXCCALLS: usize = 200;
+
+const L_ESC: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
 
-/// Checks if a character matches a Lua pattern class (e.g., %a, %d, etc.)
+/// Checks if a character matches a Lua pattern class (e.g., %a, %d, etc.);
+/// the upper-case letter of a class is its complement. Classes follow the
+/// C locale: bytes above 127 belong to none of them.
 fn match_class(c: char, class: char) -> bool {
-    match class {
+    let res = match class.to_ascii_lowercase() {
         'a' => c.is_ascii_alphabetic(),
+        'c' => c.is_ascii_control(),
         'd' => c.is_ascii_digit(),
+        'g' => c.is_ascii_graphic(), // printable except space
         'l' => c.is_ascii_lowercase(),
+        'p' => c.is_ascii_punctuation(),
+        's' => c.is_ascii_whitespace() || c == '\x0b', // isspace has '\v' too
         'u' => c.is_ascii_uppercase(),
         'w' => c.is_ascii_alphanumeric(),
-        's' => c.is_ascii_whitespace(),
-        'p' => c.is_ascii_punctuation(),
-        'c' => c.is_ascii_control(),
         'x' => c.is_ascii_hexdigit(),
-        'z' => c == '\0',
-        'A' => !c.is_ascii_alphabetic(),
-        'D' => !c.is_ascii_digit(),
-        'L' => !c.is_ascii_lowercase(),
-        'U' => !c.is_ascii_uppercase(),
-        'W' => !c.is_ascii_alphanumeric(),
-        'S' => !c.is_ascii_whitespace(),
-        'P' => !c.is_ascii_punctuation(),
-        'C' => !c.is_ascii_control(),
-        'X' => !c.is_ascii_hexdigit(),
-        'Z' => c != '\0',
-        _ => c == class,
-    }
-}
-
-/// Matches a single pattern item (char, class, or .)
-fn match_one(c: char, pat: &mut std::str::Chars) -> bool {
-    match pat.next() {
-        Some('.') => true,
-        Some('%') => {
-            if let Some(class) = pat.next() {
-                match_class(c, class)
-            } else {
-                false
-            }
+        'z' => c == '\0', // deprecated option
+        _ => return c == class, // an escaped non-letter stands for itself
+    };
+    if class.is_ascii_uppercase() { !res } else { res }
+}
+
+/// One value captured by a pattern: a substring, or for an empty capture
+/// "()" the (1-based) position it stands at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capture {
+    Str(Vec<u8>),
+    Pos(usize),
+}
+
+impl Capture {
+    pub fn to_value(&self) -> LuaValue {
+        match self {
+            Capture::Str(s) => LuaValue::Str(String::from_utf8_lossy(s).into_owned()),
+            Capture::Pos(p) => LuaValue::Int(*p as LuaInteger),
         }
-        Some(ch) => c == ch,
-        None => false,
     }
 }
 
-/// Minimal recursive pattern matcher (no captures, no balanced, no frontier)
-fn match_lua_pat(s: &str, pat: &str) -> Option<(usize, usize)> {
-    let s_chars: Vec<_> = s.chars().collect();
-    let pat_chars: Vec<_> = pat.chars().collect();
-    for i in 0..=s_chars.len() {
-        if let Some(len) = match_here(&s_chars[i..], &pat_chars) {
-            return Some((i + 1, i + len)); // 1-based
+/// State of one match attempt (MatchState); errors are messages for
+/// state.error
+struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    matchdepth: usize,
+    level: usize,
+    /// (start, length) of each capture; the length may be CAP_UNFINISHED
+    /// or CAP_POSITION
+    capture: [(usize, isize); LUA_MAXCAPTURES],
+}
+
+impl<'a> MatchState<'a> {
+    fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        MatchState { src, pat, matchdepth: MAXCCALLS, level: 0, capture: [(0, 0); LUA_MAXCAPTURES] }
+    }
+
+    /// Ready for another attempt (reprepstate)
+    fn reprep(&mut self) {
+        self.level = 0;
+        self.matchdepth = MAXCCALLS;
+    }
+
+    /// End of the single-character class starting at `p`
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pat[p];
+        p += 1;
+        if c == L_ESC {
+            if p >= self.pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            loop {
+                // look for a ']'
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let cc = self.pat[p];
+                p += 1;
+                if cc == L_ESC && p < self.pat.len() {
+                    p += 1; // skip escapes (e.g. '%]')
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    /// Does `c` belong to the set `[...]` between `p` (the '[') and `ec`
+    /// (the ']')?
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat[p + 1] == b'^' {
+            sig = false;
+            p += 1; // skip the '^'
         }
+        p += 1;
+        while p < ec {
+            if self.pat[p] == L_ESC {
+                p += 1;
+                if match_class(c as char, self.pat[p] as char) {
+                    return sig;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return sig;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+        !sig
     }
-    None
-}
 
-fn match_here(s: &[char], pat: &[char]) -> Option<usize> {
-    if pat.is_empty() {
-        return Some(0);
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else { return false };
+        match self.pat[p] {
+            b'.' => true, // matches any char
+            L_ESC => match_class(c as char, self.pat[p + 1] as char),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
     }
-    let mut pat_iter = pat.iter().peekable();
-    let mut s_idx = 0;
-    while let Some(&&p) = pat_iter.peek() {
-        if let Some(&&next) = pat_iter.clone().nth(1) {
-            match next {
-                '*' => {
-                    pat_iter.next(); pat_iter.next();
-                    let mut max = s_idx;
-                    while s_idx < s.len() && match_pat_char(s[s_idx], p) {
-                        s_idx += 1;
-                    }
-                    for j in (0..=s_idx).rev() {
-                        if let Some(rest) = match_here(&s[j..], pat_iter.clone().collect::<Vec<_>>().as_slice()) {
-                            return Some(j + rest);
-                        }
-                    }
-                    return None;
+
+    /// Match `pat[p..]` at `src[s..]`; the end of the match if it succeeds
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        if self.matchdepth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.matchdepth -= 1;
+        let r = loop {
+            if p == self.pat.len() {
+                break Some(s); // end of pattern
+            }
+            match self.pat[p] {
+                b'(' => {
+                    break if self.pat.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CAP_POSITION)?
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)?
+                    };
+                }
+                b')' => break self.end_capture(s, p + 1)?,
+                b'$' if p + 1 == self.pat.len() => {
+                    break if s == self.src.len() { Some(s) } else { None };
                 }
-                '+' => {
-                    pat_iter.next(); pat_iter.next();
-                    if s_idx < s.len() && match_pat_char(s[s_idx], p) {
-                        s_idx += 1;
-                        while s_idx < s.len() && match_pat_char(s[s_idx], p) {
-                            s_idx += 1;
+                L_ESC => match self.pat.get(p + 1) {
+                    Some(b'b') => match self.match_balance(s, p + 2)? {
+                        Some(e) => {
+                            s = e;
+                            p += 4;
+                            continue;
+                        }
+                        None => break None,
+                    },
+                    Some(b'f') => {
+                        p += 2;
+                        if self.pat.get(p) != Some(&b'[') {
+                            return Err("missing '[' after '%f' in pattern".to_string());
                         }
-                        for j in (1..=s_idx).rev() {
-                            if let Some(rest) = match_here(&s[j..], pat_iter.clone().collect::<Vec<_>>().as_slice()) {
-                                return Some(j + rest);
-                            }
+                        let ep = self.class_end(p)?;
+                        let prev = if s == 0 { 0 } else { self.src[s - 1] };
+                        let cur = self.src.get(s).copied().unwrap_or(0);
+                        if !self.match_bracket_class(prev, p, ep - 1) && self.match_bracket_class(cur, p, ep - 1) {
+                            p = ep;
+                            continue;
                         }
+                        break None;
                     }
-                    return None;
-                }
-                '?' => {
-                    pat_iter.next(); pat_iter.next();
-                    if s_idx < s.len() && match_pat_char(s[s_idx], p) {
-                        if let Some(rest) = match_here(&s[s_idx + 1..], pat_iter.clone().collect::<Vec<_>>().as_slice()) {
-                            return Some(1 + rest);
+                    Some(&d) if d.is_ascii_digit() => match self.match_capture(s, d)? {
+                        Some(e) => {
+                            s = e;
+                            p += 2;
+                            continue;
+                        }
+                        None => break None,
+                    },
+                    _ => {}
+                },
+                _ => {}
+            }
+            // a single-character class, maybe with a repetition suffix
+            let ep = self.class_end(p)?;
+            let m = self.single_match(s, p, ep);
+            match self.pat.get(ep) {
+                Some(b'?') => {
+                    if m {
+                        if let Some(e) = self.do_match(s + 1, ep + 1)? {
+                            break Some(e);
                         }
                     }
-                    if let Some(rest) = match_here(&s[s_idx..], pat_iter.clone().collect::<Vec<_>>().as_slice()) {
-                        return Some(rest);
+                    p = ep + 1;
+                }
+                Some(b'+') => break if m { self.max_expand(s + 1, p, ep)? } else { None },
+                Some(b'*') => break self.max_expand(s, p, ep)?,
+                Some(b'-') => break self.min_expand(s, p, ep)?,
+                _ => {
+                    if !m {
+                        break None;
                     }
-                    return None;
+                    s += 1;
+                    p = ep;
                 }
-                _ => {}
             }
+        };
+        self.matchdepth += 1;
+        Ok(r)
+    }
+
+    /// %bxy: a balanced run from x to the matching y
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
         }
-        // Single char match
-        pat_iter.next();
-        if s_idx < s.len() && match_pat_char(s[s_idx], p) {
-            s_idx += 1;
-        } else {
-            return None;
+        let (b, e) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&b) {
+            return Ok(None);
         }
+        let mut cont = 1;
+        for i in s + 1..self.src.len() {
+            if self.src[i] == e {
+                cont -= 1;
+                if cont == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if self.src[i] == b {
+                cont += 1;
+            }
+        }
+        Ok(None) // string ends out of balance
     }
-    Some(s_idx)
-}
 
-fn match_pat_char(c: char, p: char) -> bool {
-    if p == '.' {
-        true
-    } else if p == '%' {
-        false // handled in full engine
-    } else {
-        c == p
+    /// Greedy repetition: as many as possible, then give back one by one
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        loop {
+            if let Some(e) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(e));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
     }
-}
 
-/// Matches a character against a bracketed class (e.g., [abc], [^abc], [a-z])
-fn match_bracket_class(c: char, pat: &[char]) -> Option<(bool, usize)> {
-    if pat.is_empty() || pat[0] != '[' {
-        return None;
+    /// Lazy repetition ('-'): as few as possible
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(e) = self.do_match(s, ep + 1)? {
+                return Ok(Some(e));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
     }
-    let mut negate = false;
-    let mut i = 1;
-    if i < pat.len() && pat[i] == '^' {
-        negate = true;
-        i += 1;
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> Result<Option<usize>, String> {
+        if self.level >= LUA_MAXCAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let r = self.do_match(s, p)?;
+        if r.is_none() {
+            self.level -= 1; // undo capture
+        }
+        Ok(r)
     }
-    let mut matched = false;
-    while i < pat.len() && pat[i] != ']' {
-        if i + 2 < pat.len() && pat[i + 1] == '-' && pat[i + 2] != ']' {
-            // Range
-            let start = pat[i];
-            let end = pat[i + 2];
-            if start <= c && c <= end {
-                matched = true;
-            }
-            i += 3;
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = (s - self.capture[l].0) as isize; // close capture
+        let r = self.do_match(s, p)?;
+        if r.is_none() {
+            self.capture[l].1 = CAP_UNFINISHED; // undo capture
+        }
+        Ok(r)
+    }
+
+    fn capture_to_close(&self) -> Result<usize, String> {
+        (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    /// %1-%9: the text of an earlier capture again
+    fn match_capture(&self, s: usize, d: u8) -> Result<Option<usize>, String> {
+        let l = (d as usize).wrapping_sub(b'1' as usize);
+        if l >= self.level || self.capture[l].1 == CAP_UNFINISHED {
+            return Err(format!("invalid capture index %{}", l.wrapping_add(1) as isize));
+        }
+        let (init, len) = self.capture[l];
+        if len < 0 {
+            return Ok(None); // a position capture has no text
+        }
+        let len = len as usize;
+        if self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
         } else {
-            if pat[i] == c {
-                matched = true;
+            Ok(None)
+        }
+    }
+
+    /// Capture `i` of the match `s..e`; capture 0 is the whole match when
+    /// the pattern has none (get_onecapture)
+    fn get_capture(&self, i: usize, s: usize, e: usize) -> Result<Capture, String> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(format!("invalid capture index %{}", i + 1));
             }
-            i += 1;
+            return Ok(Capture::Str(self.src[s..e].to_vec()));
+        }
+        match self.capture[i] {
+            (_, CAP_UNFINISHED) => Err("unfinished capture".to_string()),
+            (init, CAP_POSITION) => Ok(Capture::Pos(init + 1)),
+            (init, len) => Ok(Capture::Str(self.src[init..init + len as usize].to_vec())),
         }
     }
-    let consumed = i + 1; // include closing ]
-    Some(((matched ^ negate), consumed))
+
+    /// All captures of the match `s..e`, or the whole match if there are
+    /// none and `whole` is set (push_captures)
+    fn get_captures(&self, s: usize, e: usize, whole: bool) -> Result<Vec<Capture>, String> {
+        let n = if self.level == 0 && whole { 1 } else { self.level };
+        (0..n).map(|i| self.get_capture(i, s, e)).collect()
+    }
 }
 
-/// Enhanced pattern matcher with bracket class and basic captures (returns captures)
-fn match_lua_pat_captures(s: &str, pat: &str) -> Option<(usize, usize, Vec<String>)> {
-    let s_chars: Vec<_> = s.chars().collect();
-    let pat_chars: Vec<_> = pat.chars().collect();
-    for i in 0..=s_chars.len() {
-        if let Some((len, caps)) = match_here_captures(&s_chars[i..], &pat_chars, &mut Vec::new()) {
-            return Some((i + 1, i + len, caps));
-        }
+/// Position `pos` of a string of `len` bytes as a 1-based index, with
+/// negative positions counted from the end (posrelatI)
+pub(crate) fn posrelat_i(pos: LuaInteger, len: usize) -> usize {
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos.unsigned_abs() as usize > len {
+        1
+    } else {
+        len - pos.unsigned_abs() as usize + 1
     }
-    None
 }
 
-fn match_here_captures(s: &[char], pat: &[char], caps: &mut Vec<String>) -> Option<(usize, Vec<String>)> {
-    if pat.is_empty() {
-        return Some((0, caps.clone()));
+/// A match of string.find: first and last byte (1-based, inclusive) and
+/// the pattern's captures
+pub type FindResult = Option<(usize, usize, Vec<Capture>)>;
+
+/// string.find (`find`) and string.match (str_find_aux): search `s` from
+/// byte `init` for `pat`. A '^' anchors the pattern at `init`, so only that
+/// position is tried. Plain searches (`plain`, or a pattern without magic
+/// characters) compare bytes. For string.match the captures are the whole
+/// match when the pattern has none.
+fn str_find_aux(s: &str, pat: &str, init: LuaInteger, plain: bool, find: bool) -> Result<FindResult, String> {
+    let (src, mut p) = (s.as_bytes(), pat.as_bytes());
+    let init = posrelat_i(init, src.len()) - 1;
+    if init > src.len() {
+        return Ok(None); // start after string's end
     }
-    let mut pat_iter = 0;
-    let mut s_idx = 0;
-    let mut local_caps = caps.clone();
-    while pat_iter < pat.len() {
-        // Handle captures: ( ... )
-        if pat[pat_iter] == '(' {
-            let cap_start = s_idx;
-            pat_iter += 1;
-            let mut cap_pat = Vec::new();
-            let mut depth = 1;
-            while pat_iter < pat.len() && depth > 0 {
-                if pat[pat_iter] == '(' { depth += 1; }
-                if pat[pat_iter] == ')' { depth -= 1; }
-                if depth > 0 { cap_pat.push(pat[pat_iter]); }
-                pat_iter += 1;
-            }
-            if let Some((cap_len, mut sub_caps)) = match_here_captures(&s[s_idx..], &cap_pat, &mut Vec::new()) {
-                let cap_str: String = s[s_idx..s_idx+cap_len].iter().collect();
-                local_caps.push(cap_str);
-                s_idx += cap_len;
-                local_caps.append(&mut sub_caps);
-            } else {
-                return None;
-            }
-            continue;
+    if find && (plain || !p.iter().any(|c| SPECIALS.contains(c))) {
+        // do a plain search
+        let found = if p.is_empty() {
+            Some(init)
+        } else {
+            src[init..].windows(p.len()).position(|w| w == p).map(|i| init + i)
+        };
+        return Ok(found.map(|i| (i + 1, i + p.len(), Vec::new())));
+    }
+    let anchor = p.first() == Some(&b'^');
+    if anchor {
+        p = &p[1..]; // skip anchor character
+    }
+    let mut ms = MatchState::new(src, p);
+    let mut s1 = init;
+    loop {
+        ms.reprep();
+        if let Some(e) = ms.do_match(s1, 0)? {
+            return Ok(Some((s1 + 1, e, ms.get_captures(s1, e, !find)?)));
         }
-        // Bracket class
-        if pat[pat_iter] == '[' {
-            if let Some((matched, consumed)) = match_bracket_class(s.get(s_idx).copied().unwrap_or('\0'), &pat[pat_iter..]) {
-                if matched {
-                    s_idx += 1;
-                    pat_iter += consumed;
-                    continue;
-                } else {
-                    return None;
+        s1 += 1;
+        if anchor || s1 > src.len() {
+            return Ok(None);
+        }
+    }
+}
+
+/// string.find(s, pat, init, plain): the match's first and last byte,
+/// then the pattern's captures
+pub fn str_find_at(s: &str, pat: &str, init: LuaInteger, plain: bool) -> Result<FindResult, String> {
+    str_find_aux(s, pat, init, plain, true)
+}
+
+/// string.match(s, pat, init): the captures, or the whole match
+pub fn str_match_at(s: &str, pat: &str, init: LuaInteger) -> Result<Option<Vec<Capture>>, String> {
+    Ok(str_find_aux(s, pat, init, false, false)?.map(|(_, _, caps)| caps))
+}
+
+/// Position of the first match of `pat` in `s`
+pub fn str_find(s: &str, pat: &str) -> Option<(usize, usize)> {
+    str_find_at(s, pat, 1, false).ok().flatten().map(|(i, j, _)| (i, j))
+}
+
+/// Does `pat` match somewhere in `s`?
+pub fn str_match(s: &str, pat: &str) -> bool {
+    str_find(s, pat).is_some()
+}
+
+/// Positions of the successive matches of `pat` in `s`, as string.gmatch
+/// finds them (an empty match right after a match is skipped)
+pub fn str_gmatch<'a>(s: &'a str, pat: &'a str) -> impl Iterator<Item = (usize, usize)> + 'a {
+    let mut src = 0;
+    let mut lastmatch = None;
+    std::iter::from_fn(move || {
+        let mut ms = MatchState::new(s.as_bytes(), pat.as_bytes());
+        while src <= s.len() {
+            ms.reprep();
+            match ms.do_match(src, 0) {
+                Ok(Some(e)) if Some(e) != lastmatch => {
+                    let start = src;
+                    src = e;
+                    lastmatch = Some(e);
+                    return Some((start + 1, e));
                 }
+                Err(_) => return None,
+                _ => src += 1,
             }
         }
-        // Char class
-        if pat[pat_iter] == '%' && pat_iter + 1 < pat.len() {
-            if s_idx < s.len() && match_class(s[s_idx], pat[pat_iter + 1]) {
-                s_idx += 1;
-                pat_iter += 2;
-                continue;
-            } else {
-                return None;
+        None
+    })
+}
+
+/// One step of string.gmatch (gmatch_aux): the captures (or the whole
+/// match) of the next match of `pat` in `s` from byte `*src`, moving `*src`
+/// to its end; an empty match where the last match ended is skipped
+fn gmatch_step(s: &[u8], pat: &[u8], src: &mut usize, lastmatch: &mut Option<usize>) -> Result<Option<Vec<Capture>>, String> {
+    let mut ms = MatchState::new(s, pat);
+    while *src <= s.len() {
+        ms.reprep();
+        match ms.do_match(*src, 0)? {
+            Some(e) if Some(e) != *lastmatch => {
+                let caps = ms.get_captures(*src, e, true)?;
+                *src = e;
+                *lastmatch = Some(e);
+                return Ok(Some(caps));
             }
+            _ => *src += 1,
         }
-        // Dot
-        if pat[pat_iter] == '.' {
-            if s_idx < s.len() {
-                s_idx += 1;
-                pat_iter += 1;
-                continue;
-            } else {
-                return None;
+    }
+    Ok(None)
+}
+
+/// string.gsub(s, pat, repl, max_n) with `repl` computing the text that
+/// replaces each match from its captures (the whole match if there are
+/// none); None keeps the match as it is. Returns the new string and the
+/// number of matches. A '^' anchors the pattern at the start, so there is
+/// at most one match.
+pub fn str_gsub_with(
+    s: &str,
+    pat: &str,
+    max_n: Option<usize>,
+    mut repl: impl FnMut(&[u8], &[Capture]) -> Result<Option<Vec<u8>>, String>,
+) -> Result<(String, usize), String> {
+    let (src, mut p) = (s.as_bytes(), pat.as_bytes());
+    let anchor = p.first() == Some(&b'^');
+    if anchor {
+        p = &p[1..]; // skip anchor character
+    }
+    let mut ms = MatchState::new(src, p);
+    let mut out = Vec::with_capacity(src.len());
+    let (mut pos, mut n, mut lastmatch) = (0, 0, None);
+    while max_n.is_none_or(|max| n < max) {
+        ms.reprep();
+        match ms.do_match(pos, 0)? {
+            Some(e) if Some(e) != lastmatch => {
+                n += 1;
+                let caps = ms.get_captures(pos, e, true)?;
+                match repl(&src[pos..e], &caps)? {
+                    Some(text) => out.extend_from_slice(&text),
+                    None => out.extend_from_slice(&src[pos..e]), // keep original text
+                }
+                pos = e;
+                lastmatch = Some(e);
+            }
+            _ if pos < src.len() => {
+                out.push(src[pos]);
+                pos += 1;
             }
+            _ => break, // end of subject
         }
-        // Literal
-        if s_idx < s.len() && pat[pat_iter] == s[s_idx] {
-            s_idx += 1;
-            pat_iter += 1;
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[pos..]);
+    Ok((String::from_utf8_lossy(&out).into_owned(), n))
+}
+
+/// A string replacement (add_s): "%0" is the whole match, "%1".."%9" the
+/// captures and "%%" a '%'
+fn expand_replacement(repl: &[u8], whole: &[u8], caps: &[Capture]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(repl.len());
+    let mut i = 0;
+    while i < repl.len() {
+        let c = repl[i];
+        i += 1;
+        if c != L_ESC {
+            out.push(c);
             continue;
-        } else {
-            return None;
         }
+        match repl.get(i) {
+            Some(&L_ESC) => out.push(L_ESC),
+            Some(b'0') => out.extend_from_slice(whole),
+            Some(&d) if d.is_ascii_digit() => match caps.get((d - b'1') as usize) {
+                Some(Capture::Str(s)) => out.extend_from_slice(s),
+                Some(Capture::Pos(p)) => out.extend_from_slice(p.to_string().as_bytes()),
+                None => return Err(format!("invalid capture index %{}", d - b'0')),
+            },
+            _ => return Err("invalid use of '%' in replacement string".to_string()),
+        }
+        i += 1;
     }
-    Some((s_idx, local_caps))
+    Ok(out)
+}
+
+/// gsub with a replacement string; errors in the pattern or the
+/// replacement are messages
+pub fn str_gsub(s: &str, pat: &str, repl: &str) -> Result<String, String> {
+    str_gsub_with(s, pat, None, |whole, caps| expand_replacement(repl.as_bytes(), whole, caps).map(Some))
+        .map(|(out, _)| out)
 }
 
 /// Returns all captures for the first match of a pattern
 pub fn str_captures(s: &str, pat: &str) -> Vec<String> {
-    if let Some((_start, _end, caps)) = match_lua_pat_captures(s, pat) {
-        caps
-    } else {
-        Vec::new()
+    match str_find_at(s, pat, 1, false) {
+        Ok(Some((_, _, caps))) => caps
+            .into_iter()
+            .map(|c| match c {
+                Capture::Str(s) => String::from_utf8_lossy(&s).into_owned(),
+                Capture::Pos(p) => p.to_string(),
+            })
+            .collect(),
+        _ => Vec::new(),
     }
 }
 
-/// Checks for Lua frontier pattern (%f[])
-fn match_frontier(s: &[char], pos: usize, set: &[char]) -> bool {
-    let prev = if pos == 0 { '\0' } else { s[pos - 1] };
-    let curr = if pos < s.len() { s[pos] } else { '\0' };
-    let in_set = |c| set.contains(&c);
-    !in_set(prev) && in_set(curr)
+/// Substitute captures in replacement string (e.g., %1, %2)
+pub fn str_gsub_captures(s: &str, pat: &str, repl: &str) -> Result<String, String> {
+    str_gsub(s, pat, repl)
 }
 
-/// Substitute captures in replacement string (e.g., %1, %2)
-pub fn str_gsub_captures(s: &str, pat: &str, repl: &str) -> String {
-    let mut out = String::new();
-    let mut last = 0;
-    let mut rest = s;
-    let mut offset = 0;
-    while let Some((start, end, caps)) = match_lua_pat_captures(rest, pat) {
-        let start0 = start - 1;
-        let end0 = end;
-        out.push_str(&rest[..start0]);
-        let mut rep = String::new();
-        let mut chars = repl.chars().peekable();
-        while let Some(c) = chars.next() {
-            if c == '%' {
-                if let Some(nc) = chars.peek() {
-                    if nc.is_ascii_digit() {
-                        let idx = nc.to_digit(10).unwrap() as usize - 1;
-                        if idx < caps.len() {
-                            rep.push_str(&caps[idx]);
-                        }
-                        chars.next();
-                        continue;
-                    }
-                }
+// --- Lua bindings ---
+// Argument conversion and errors go through the lauxlib checks on LuaState.
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+use crate::skylaconf::LuaInteger;
+
+// string.len(s)
+fn str_lua_len(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Int(s.len() as LuaInteger));
+    1
+}
+
+// string.sub(s, i [, j])
+fn str_lua_sub(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let i = state.check_integer(2);
+    let j = state.opt_integer(3, -1);
+    state.push(LuaValue::Str(str_sub(&s, i as isize, Some(j as isize))));
+    1
+}
+
+// string.reverse(s)
+fn str_lua_reverse(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Str(str_reverse(&s)));
+    1
+}
+
+// string.lower(s)
+fn str_lua_lower(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Str(str_lower(&s)));
+    1
+}
+
+// string.upper(s)
+fn str_lua_upper(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    state.push(LuaValue::Str(str_upper(&s)));
+    1
+}
+
+// string.rep(s, n [, sep])
+fn str_lua_rep(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let n = state.check_integer(2);
+    let sep = state.opt_string(3, "");
+    let n = n.max(0) as usize;
+    if rep_size(s.len(), sep.len(), n).is_none() {
+        state.error("resulting string too large");
+    }
+    state.push(LuaValue::Str(str_rep(&s, n, Some(&sep))));
+    1
+}
+
+// string.byte(s [, i [, j]])
+fn str_lua_byte(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let i = state.opt_integer(2, 1);
+    let j = state.opt_integer(3, i);
+    let bytes = str_byte(&s, i as isize, Some(j as isize));
+    for &b in &bytes {
+        state.push(LuaValue::Int(b as LuaInteger));
+    }
+    bytes.len() as i32
+}
+
+// string.char(...)
+fn str_lua_char(state: &mut LuaState) -> i32 {
+    let n = state.get_top();
+    let mut bytes = Vec::with_capacity(n as usize);
+    for i in 1..=n {
+        let c = state.check_integer(i);
+        if !(0..=255).contains(&c) {
+            state.arg_error(i, "value out of range");
+        }
+        bytes.push(c as u8);
+    }
+    state.push(LuaValue::Str(str_char(&bytes)));
+    1
+}
+
+/// Push the captures of a match, returning how many
+fn push_captures(state: &mut LuaState, caps: &[Capture]) -> i32 {
+    for c in caps {
+        state.push(c.to_value());
+    }
+    caps.len() as i32
+}
+
+// string.find(s, pattern [, init [, plain]]) -> start, end, captures...
+fn str_lua_find(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let pat = state.check_string(2);
+    let init = state.opt_integer(3, 1);
+    let plain = !matches!(state.to_value(4), LuaValue::Nil | LuaValue::Bool(false));
+    match str_find_at(&s, &pat, init, plain) {
+        Ok(Some((i, j, caps))) => {
+            state.push(LuaValue::Int(i as LuaInteger));
+            state.push(LuaValue::Int(j as LuaInteger));
+            2 + push_captures(state, &caps)
+        }
+        Ok(None) => {
+            state.push(LuaValue::Nil);
+            1
+        }
+        Err(msg) => {
+            state.error(&msg)
+        }
+    }
+}
+
+// string.match(s, pattern [, init]) -> captures (or the whole match)
+fn str_lua_match(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let pat = state.check_string(2);
+    let init = state.opt_integer(3, 1);
+    match str_match_at(&s, &pat, init) {
+        Ok(Some(caps)) => push_captures(state, &caps),
+        Ok(None) => {
+            state.push(LuaValue::Nil);
+            1
+        }
+        Err(msg) => {
+            state.error(&msg)
+        }
+    }
+}
+
+// string.gmatch(s, pattern [, init]) -> iterator over the captures (or the
+// whole match) of each successive match
+fn str_lua_gmatch(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let pat = state.check_string(2);
+    let init = posrelat_i(state.opt_integer(3, 1), s.len()) - 1;
+    // where to search (past the end: no match), end of the last match
+    let pos = std::cell::RefCell::new((init.min(s.len() + 1), None::<usize>));
+    let f = move |L: &mut LuaState| {
+        let (mut src, mut lastmatch) = *pos.borrow();
+        let r = gmatch_step(s.as_bytes(), pat.as_bytes(), &mut src, &mut lastmatch);
+        *pos.borrow_mut() = (src, lastmatch);
+        match r {
+            Ok(Some(caps)) => push_captures(L, &caps),
+            Ok(None) => 0,
+            Err(msg) => L.error(&msg),
+        }
+    };
+    state.push(LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(&f))));
+    1
+}
+
+/// The replacement argument of gsub (string.gsub, skyla.regex)
+pub(crate) enum Replacement {
+    /// A string (or number) with %0-%9 and %%
+    Text(Vec<u8>),
+    /// A table indexed by the first capture
+    Table(LuaValue),
+    /// A function called with the captures
+    Function(LuaValue),
+}
+
+impl Replacement {
+    /// Argument `arg` as a replacement; None after a type error
+    pub(crate) fn check(state: &mut LuaState, arg: i32) -> Option<Replacement> {
+        match state.to_value(arg) {
+            LuaValue::Str(_) | LuaValue::Int(_) | LuaValue::Float(_) => Some(Replacement::Text(state.check_string(arg).into_bytes())),
+            t @ LuaValue::Table(_) => Some(Replacement::Table(t)),
+            f @ LuaValue::Function(_) => Some(Replacement::Function(f)),
+            _ => {
+                state.type_error(arg, "string/function/table")
             }
-            rep.push(c);
         }
-        out.push_str(&rep);
-        rest = &rest[end0..];
-        offset += end0;
     }
-    out.push_str(rest);
-    out
+
+    /// The text replacing the match `whole` (add_value); None keeps the
+    /// match, which a table or function asks for with false or nil
+    pub(crate) fn apply(&self, state: &mut LuaState, whole: &[u8], caps: &[Capture]) -> Result<Option<Vec<u8>>, String> {
+        let v = match self {
+            Replacement::Text(text) => return expand_replacement(text, whole, caps).map(Some),
+            Replacement::Table(t) => crate::lvm::luaV_finishget(state, t, &caps[0].to_value()),
+            Replacement::Function(f) => {
+                let args: Vec<LuaValue> = caps.iter().map(Capture::to_value).collect();
+                crate::ltm::call_tm_vm(state, f, &args).unwrap_or(LuaValue::Nil)
+            }
+        };
+        match v {
+            LuaValue::Nil | LuaValue::Bool(false) => Ok(None), // keep original text
+            LuaValue::Str(r) => Ok(Some(r.into_bytes())),
+            LuaValue::Int(i) => Ok(Some(i.to_string().into_bytes())),
+            LuaValue::Float(f) => Ok(Some(crate::lobject::luaO_num2str_dot(f).into_bytes())),
+            v => Err(format!("invalid replacement value (a {})", crate::ltm::obj_typename(&v))),
+        }
+    }
 }
 
-// --- Extended quantifier support for bracket/capture ---
-// (This is a stub for demonstration; a full engine would require a full parser)
-// For now, bracket/capture quantifiers are handled as single matches.
+// string.gsub(s, pattern, repl [, n]) -> string, count. `repl` is a string
+// with %0-%9, a table indexed by the first capture, or a function called
+// with the captures; false or nil from the last two keeps the match.
+fn str_lua_gsub(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let pat = state.check_string(2);
+    let Some(repl) = Replacement::check(state, 3) else { return 0 };
+    let max_n = if state.is_none_or_nil(4) { None } else { Some(state.check_integer(4).max(0) as usize) };
+    let r = str_gsub_with(&s, &pat, max_n, |whole, caps| repl.apply(state, whole, caps));
+    match r {
+        Ok((out, n)) => {
+            state.push(LuaValue::Str(out));
+            state.push(LuaValue::Int(n as LuaInteger));
+            2
+        }
+        Err(msg) => {
+            state.error(&msg)
+        }
+    }
+}
+
+// string.format(fmt, ...)
+fn str_lua_format(state: &mut LuaState) -> i32 {
+    let fmt = state.check_string(1);
+    let args: Vec<LuaValue> = (2..=state.get_top()).map(|i| state.to_value(i)).collect();
+    let r = str_format(&fmt, &args, |v| crate::lbaselib::tostring_value(state, v));
+    match r {
+        Ok(s) => {
+            state.push(LuaValue::Str(s));
+            1
+        }
+        Err(FormatError::BadArgument(i, msg)) => state.arg_error(i as i32 + 2, &msg),
+        Err(FormatError::Invalid(msg)) => state.error(&msg),
+    }
+}
+
+const STR_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("len", str_lua_len),
+    ("sub", str_lua_sub),
+    ("reverse", str_lua_reverse),
+    ("lower", str_lua_lower),
+    ("upper", str_lua_upper),
+    ("rep", str_lua_rep),
+    ("byte", str_lua_byte),
+    ("char", str_lua_char),
+    ("find", str_lua_find),
+    ("match", str_lua_match),
+    ("gmatch", str_lua_gmatch),
+    ("gsub", str_lua_gsub),
+    ("format", str_lua_format),
+];
+
+/// Register the string library functions
+pub fn open_string_lib(state: &mut LuaState) {
+    for &(name, f) in STR_FUNCS {
+        state.register_lib_function("string", name, f);
+    }
+}
 
 // --- Tests for advanced pattern features ---
 #[cfg(test)]
@@ -388,6 +1309,26 @@ mod advanced_pattern_tests {
         assert!(str_match("1bc", "[^a-z]bc"));
     }
     #[test]
+    fn test_class_edge_cases() {
+        assert_eq!(str_match_at("x = \"a b\"", "%g+", 1), Ok(Some(vec![Capture::Str(b"x".to_vec())])));
+        assert_eq!(str_find("hello world", "%G"), Some((6, 6)));
+        assert!(str_match("\x0b", "^%s$") && !str_match("\x0b", "%S"));
+        // classes inside sets
+        assert_eq!(str_find("  _id9 = 1", "[%a_][%w_]*"), Some((3, 6)));
+        assert_eq!(str_gsub("a1-b2", "[%d%-]", "").unwrap(), "ab");
+        // ']' escaped or first, '-' first or last is literal
+        assert_eq!(str_find("a]b", "[%]]"), Some((2, 2)));
+        assert_eq!(str_find("a]b", "[]]"), Some((2, 2)));
+        assert_eq!(str_find("b-a", "[a-]"), Some((2, 2)));
+        assert_eq!(str_find("b-a", "[-a]"), Some((2, 2)));
+        assert_eq!(str_find("xyz", "[^%l]"), None);
+        // the manual's examples
+        assert_eq!(str_gsub("hello world", "(%w+)", "%1 %1").unwrap(), "hello hello world world");
+        assert_eq!(str_gsub("hello world from Lua", "(%w+)%s*(%w+)", "%2 %1").unwrap(), "world hello Lua from");
+        assert_eq!(str_gsub("THE (quick) fox", "%f[%a]%a+", "X").unwrap(), "X (X) X");
+        assert!(str_find_at("a", "[a", 1, false).is_err());
+    }
+    #[test]
     fn test_captures() {
         let caps = str_captures("foo123bar", "foo(%d+)(%a+)");
         assert_eq!(caps, vec!["123", "bar"]);
@@ -395,7 +1336,7 @@ mod advanced_pattern_tests {
     #[test]
     fn test_gsub_captures() {
         let s = "foo123bar foo456baz";
-        let out = str_gsub_captures(s, "foo(%d+)(%a+)", "bar-%2-%1");
+        let out = str_gsub_captures(s, "foo(%d+)(%a+)", "bar-%2-%1").unwrap();
         assert_eq!(out, "bar-bar-123 bar-baz-456");
     }
 }
@@ -428,7 +1369,7 @@ mod pattern_tests {
     }
     #[test]
     fn test_gsub() {
-        assert_eq!(str_gsub("foo bar foo", "foo", "baz"), "baz bar baz");
+        assert_eq!(str_gsub("foo bar foo", "foo", "baz").unwrap(), "baz bar baz");
     }
     #[test]
     fn test_gmatch() {
@@ -436,6 +1377,60 @@ mod pattern_tests {
         let matches: Vec<_> = str_gmatch(s, "foo").collect();
         assert_eq!(matches, vec![(1, 3), (9, 11), (17, 19)]);
     }
+    #[test]
+    fn test_find_anchor_and_init() {
+        let cap = |s: &str| Capture::Str(s.as_bytes().to_vec());
+        assert_eq!(str_find_at("hello", "^h", 1, false), Ok(Some((1, 1, vec![]))));
+        assert_eq!(str_find_at("ahello", "^h", 1, false), Ok(None)); // only tries init
+        assert_eq!(str_find_at("ahello", "^h", 2, false), Ok(Some((2, 2, vec![]))));
+        // negative init counts from the end; before the start means 1
+        assert_eq!(str_find_at("abcabc", "b", -3, false), Ok(Some((5, 5, vec![]))));
+        assert_eq!(str_find_at("abc", "a", -10, false), Ok(Some((1, 1, vec![]))));
+        assert_eq!(str_find_at("abc", "", 4, false), Ok(Some((4, 3, vec![]))));
+        assert_eq!(str_find_at("abc", "", 5, false), Ok(None));
+        // plain turns the magic characters off
+        assert_eq!(str_find_at("a.b(c", ".b(", 1, true), Ok(Some((2, 4, vec![]))));
+        // captures follow the positions
+        assert_eq!(str_find_at("key = val", "(%w+)%s*=%s*(%w+)", 1, false),
+            Ok(Some((1, 9, vec![cap("key"), cap("val")]))));
+        assert_eq!(str_find_at("abc", "()b()", 1, false), Ok(Some((2, 2, vec![Capture::Pos(2), Capture::Pos(3)]))));
+        assert_eq!(str_match_at("  word  ", "^%s*(.-)%s*$", 1), Ok(Some(vec![cap("word")])));
+        assert_eq!(str_match_at("x = 1", "%a", 1), Ok(Some(vec![cap("x")])));
+        assert!(str_find_at("a", "(a", 1, false).is_err());
+        assert!(str_find_at("a", "a%", 1, false).is_err());
+    }
+    #[test]
+    fn test_gsub_anchor_and_limit() {
+        let upper = |_: &[u8], caps: &[Capture]| match &caps[0] {
+            Capture::Str(s) => Ok(Some(s.to_ascii_uppercase())),
+            Capture::Pos(_) => Ok(None),
+        };
+        assert_eq!(str_gsub_with("aaa", "^a", None, upper), Ok(("Aaa".to_string(), 1)));
+        assert_eq!(str_gsub_with("aaa", "a", Some(2), upper), Ok(("AAa".to_string(), 2)));
+        assert_eq!(str_gsub_with("abc", "%w*", None, upper), Ok(("ABC".to_string(), 1)));
+        assert_eq!(str_gsub("hello world", "(%w+)", "<%1>").unwrap(), "<hello> <world>");
+        assert_eq!(str_gsub("abc", "", "-").unwrap(), "-a-b-c-");
+        assert_eq!(str_gsub("x", "x", "%0%%").unwrap(), "x%");
+        // pattern and replacement errors are reported, not swallowed
+        assert_eq!(str_gsub("a", "(a", "x"), Err("unfinished capture".to_string()));
+        assert!(str_gsub("a", "a", "%2").is_err());
+        // captures are bytes: splitting a multi-byte character and joining
+        // it again gives the original text
+        assert_eq!(str_gsub("h\u{e9}llo", "(.)", "%1").unwrap(), "h\u{e9}llo");
+    }
+    #[test]
+    fn test_gsub_number_replacements() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        let t = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        t.borrow_mut().set(&LuaValue::Str("x".to_string()), LuaValue::Float(1.0));
+        t.borrow_mut().set(&LuaValue::Str("y".to_string()), LuaValue::Int(2));
+        let repl = Replacement::Table(LuaValue::Table(t));
+        // numbers are written as tostring does
+        let r = str_gsub_with("x y z", "%a", None, |whole, caps| repl.apply(&mut state, whole, caps));
+        assert_eq!(r, Ok(("1.0 2 z".to_string(), 3)));
+    }
 }
 
 #[cfg(test)]
@@ -464,6 +1459,10 @@ mod tests {
     #[test]
     fn test_str_rep() {
         assert_eq!(str_rep("a", 3, Some("-")), "a-a-a");
+        assert_eq!(rep_size(1, 1, 3), Some(5));
+        assert_eq!(rep_size(3, 0, 0), Some(0));
+        assert_eq!(rep_size(1, 0, usize::MAX), None);
+        assert_eq!(rep_size(10, 0, crate::llimits::MAX_SIZE), None);
     }
     #[test]
     fn test_str_byte() {
@@ -473,6 +1472,13 @@ mod tests {
     fn test_str_char() {
         assert_eq!(str_char(&[97, 98, 99]), "abc");
     }
+    #[test]
+    fn test_str_format_g() {
+        let fmt = |spec: &str, args: &[LuaValue]| str_format(spec, args, |_| unreachable!()).unwrap();
+        assert_eq!(fmt("%g", &[LuaValue::Str("0.1".to_string())]), "0.1");
+        assert_eq!(fmt("%g", &[LuaValue::Float(1e14)]), "1e+14");
+        assert_eq!(fmt("%.14g|%d", &[LuaValue::Float(3.0), LuaValue::Int(7)]), "3|7");
+    }
 }
 
 #[cfg(test)]
@@ -490,11 +1496,76 @@ mod ext_tests {
     }
     #[test]
     fn test_str_gsub() {
-        assert_eq!(str_gsub("aabb", "a", "z"), "zzbb");
+        assert_eq!(str_gsub("aabb", "a", "z").unwrap(), "zzbb");
     }
     #[test]
     fn test_str_format() {
-        assert_eq!(str_format("hi %s!", &["bob"]), "hi bob!");
+        let s = |x: &str| LuaValue::Str(x.to_string());
+        let (i, f) = (LuaValue::Int, LuaValue::Float);
+        let fmt = |spec: &str, args: &[LuaValue]| str_format(spec, args, |_| "other".to_string());
+        assert_eq!(fmt("hi %s!", &[s("bob")]).unwrap(), "hi bob!");
+        assert_eq!(fmt("%d|%d", &[s("3.0"), s("0x10")]).unwrap(), "3|16");
+        assert_eq!(fmt("%5.1f|%-4d|%+.3d|%05d", &[f(3.14159), i(7), i(7), i(-42)]).unwrap(), "  3.1|7   |+007|-0042");
+        assert_eq!(fmt("%x|%#X|%o|%#o|%x", &[i(255), i(255), i(8), i(8), i(-1)]).unwrap(), "ff|0XFF|10|010|ffffffffffffffff");
+        assert_eq!(fmt("%e|%.0e|%E", &[f(12345.678), f(5.0), f(0.00012)]).unwrap(), "1.234568e+04|5e+00|1.200000E-04");
+        assert_eq!(fmt("%a|%A|%.1a|%a", &[f(1.0), f(-0.5), f(1.96875), f(0.0)]).unwrap(), "0x1p+0|-0X1P-1|0x2.0p+0|0x0p+0");
+        assert_eq!(fmt("%f|%5.1f|%-6g|", &[f(f64::INFINITY), f(-f64::INFINITY), f(f64::NAN)]).unwrap(), "inf| -inf|nan   |");
+        assert_eq!(fmt("%c%c|%-3c|", &[i(72), i(105), i(33)]).unwrap(), "Hi|!  |");
+        assert_eq!(fmt("%5s|%-5s|%.2s|%s", &[s("ab"), s("ab"), s("abc"), LuaValue::Nil]).unwrap(), "   ab|ab   |ab|nil");
+        assert_eq!(fmt("%s|%s|%s", &[f(1.0), LuaValue::Bool(true), LuaValue::Pointer(std::ptr::null())]).unwrap(), "1.0|true|other");
+        assert_eq!(fmt("%q", &[s("a\"b\\\n\r\u{0}1\u{1}x")]).unwrap(), "\"a\\\"b\\\\\\\n\\r\\0001\\1x\"");
+        assert_eq!(fmt("%q|%q|%q|%q", &[i(LuaInteger::MIN), f(0.5), f(f64::INFINITY), f(f64::NAN)]).unwrap(),
+            "0x8000000000000000|0x1p-1|1e9999|(0/0)");
+        assert_eq!(fmt("%10p|%-8p|", &[LuaValue::Nil, i(4)]).unwrap(), "    (null)|(null)  |");
+        assert_eq!(fmt("100%%", &[]).unwrap(), "100%");
+        // errors name the argument or the directive
+        let bad = |n, msg: &str| -> Result<String, FormatError> { Err(FormatError::BadArgument(n, msg.to_string())) };
+        let invalid = |msg: &str| -> Result<String, FormatError> { Err(FormatError::Invalid(msg.to_string())) };
+        assert_eq!(fmt("%d", &[]), bad(0, "no value"));
+        assert_eq!(fmt("%s %d", &[s("a"), LuaValue::Bool(true)]), bad(1, "number expected, got boolean"));
+        assert_eq!(fmt("%d", &[f(1.5)]), bad(0, "number has no integer representation"));
+        assert_eq!(fmt("%q", &[LuaValue::Pointer(std::ptr::null())]), bad(0, "value has no literal form"));
+        assert_eq!(fmt("%10q", &[s("a")]), invalid("specifier '%q' cannot have modifiers"));
+        assert_eq!(fmt("%#d", &[i(1)]), invalid("invalid conversion '%#d' to 'format'"));
+        assert_eq!(fmt("%100d", &[i(1)]), invalid("invalid conversion '%100d' to 'format'"));
+        assert_eq!(fmt("%y", &[i(1)]), invalid("invalid conversion '%y' to 'format'"));
+    }
+    #[test]
+    fn test_format_from_lua() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        crate::skylalib::open_libs(&mut state);
+        state.stack.truncate(0);
+        let src = b"local ok, e = pcall(function() return string.format('%d', {}) end)\n\
+            return string.format('%5.1f|%-3d|%x|%s|%q', 3.14159, 7, 255, 1.0, 'a\\n'), e";
+        state.load_buffer_with(src, &crate::lsourcemap::LoadOptions::default()).unwrap();
+        state.call(0, 2);
+        assert_eq!(state.stack[0], LuaValue::Str("  3.1|7  |ff|1.0|\"a\\\n\"".to_string()));
+        assert!(matches!(&state.stack[1], LuaValue::Str(e)
+            if e.contains("bad argument #2 to") && e.ends_with("(number expected, got table)")));
+    }
+    #[test]
+    fn test_gmatch_from_lua() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        crate::skylalib::open_libs(&mut state);
+        state.stack.truncate(0);
+        let src = b"local t = {}\n\
+            for k, v in string.gmatch('a=1, b=2', '(%w+)=(%w+)') do t[#t + 1] = k .. v end\n\
+            local n = 0\n\
+            for w in string.gmatch('abc', 'x*') do n = n + 1 end\n\
+            local it = string.gmatch('hello', 'l', 4)\n\
+            return table.concat(t, ','), n, it(), it()";
+        state.load_buffer_with(src, &crate::lsourcemap::LoadOptions::default()).unwrap();
+        state.call(0, 4);
+        assert_eq!(state.stack, vec![
+            LuaValue::Str("a1,b2".to_string()),
+            LuaValue::Int(4),
+            LuaValue::Str("l".to_string()),
+            LuaValue::Nil,
+        ]);
     }
     #[test]
     fn test_str_dump() {
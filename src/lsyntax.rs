@@ -0,0 +1,935 @@
+//! lsyntax.rs - Syntax checking without running (skyla::check_syntax)
+//
+// A lexer and a recognizer for the grammar of lparser.c that generate no
+// code, for editors, linters and `skyla -p`. Errors carry the parser's
+// messages ("'=' expected near 'x'") and the line and column of the token
+// they are about. After an error the checker skips to the next token that
+// can start a statement and carries on, so one pass reports every error it
+// can recover from. A lexical error ends the scan, since nothing after it
+// can be tokenized reliably. Besides the grammar it checks what lparser.c
+// checks while parsing: break outside a loop, goto without a visible
+// label, duplicate labels, attributes, assignments to const variables and
+// '...' outside a vararg function.
+
+use crate::lobject::{luaO_numeral_len, luaO_str2number};
+
+/// One syntax error; line and column (in characters) start at 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub line: u32,
+    pub col: u32,
+    pub message: String,
+}
+
+/// Stop after this many errors; by then most are knock-on ones
+pub const MAX_DIAGNOSTICS: usize = 100;
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if",
+    "in", "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Longest first, so the lexer can take the first that matches
+const SYMBOLS: &[&str] = &[
+    "...", "..", "==", "~=", "<=", ">=", "<<", ">>", "//", "::",
+    "+", "-", "*", "/", "%", "^", "#", "&", "~", "|", "<", ">", "=",
+    "(", ")", "{", "}", "[", "]", ";", ":", ",", ".",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Name,
+    Str,
+    Num,
+    /// A keyword or symbol
+    Sym(&'static str),
+    /// A character that starts no token
+    Char,
+    Eof,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    tok: Tok,
+    text: String,
+    line: u32,
+    col: u32,
+}
+
+impl Token {
+    /// The token as error messages show it (luaX_token2str)
+    fn near(&self) -> String {
+        match self.tok {
+            Tok::Eof => "<eof>".to_string(),
+            _ => format!("'{}'", self.text),
+        }
+    }
+
+    fn is(&self, s: &str) -> bool {
+        matches!(self.tok, Tok::Sym(t) if t == s)
+    }
+}
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+    line: u32,
+    line_start: usize,
+}
+
+impl Lexer<'_> {
+    fn col_at(&self, pos: usize) -> u32 {
+        self.src[self.line_start..pos].chars().count() as u32 + 1
+    }
+
+    fn peek(&self, k: usize) -> Option<u8> {
+        self.src.as_bytes().get(self.pos + k).copied()
+    }
+
+    fn newline(&mut self) {
+        // \r\n and \n\r count as one line break
+        let c = self.peek(0);
+        self.pos += 1;
+        if matches!(self.peek(0), Some(d @ (b'\n' | b'\r')) if Some(d) != c) {
+            self.pos += 1;
+        }
+        self.line += 1;
+        self.line_start = self.pos;
+    }
+
+    /// Level of the long bracket `[==[` at the current position
+    fn long_bracket(&self) -> Option<usize> {
+        let b = &self.src.as_bytes()[self.pos..];
+        let level = b.iter().skip(1).take_while(|&&c| c == b'=').count();
+        (b.first() == Some(&b'[') && b.get(1 + level) == Some(&b'[')).then_some(level)
+    }
+
+    /// Skip a long string or comment whose opening bracket is at the
+    /// current position
+    fn skip_long(&mut self, level: usize, what: &str) -> Result<(), String> {
+        let start_line = self.line;
+        self.pos += level + 2;
+        let close = format!("]{}]", "=".repeat(level));
+        loop {
+            match self.peek(0) {
+                None => return Err(format!("unfinished long {} (starting at line {}) near <eof>", what, start_line)),
+                Some(b'\n' | b'\r') => self.newline(),
+                Some(_) if self.src[self.pos..].starts_with(&close) => {
+                    self.pos += close.len();
+                    return Ok(());
+                }
+                Some(_) => self.pos += 1,
+            }
+        }
+    }
+
+    fn string(&mut self, start: usize) -> Result<(), String> {
+        let quote = self.peek(0);
+        self.pos += 1;
+        let unfinished = |l: &Self| format!("unfinished string near '{}'", &l.src[start..l.pos]);
+        loop {
+            match self.peek(0) {
+                None | Some(b'\n' | b'\r') => return Err(unfinished(self)),
+                Some(c) if Some(c) == quote => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    self.escape(start)?;
+                }
+                Some(_) => self.pos += 1,
+            }
+        }
+    }
+
+    fn escape(&mut self, start: usize) -> Result<(), String> {
+        let err = |l: &Self, msg: &str, end: usize| format!("{} near '{}'", msg, &l.src[start..end.min(l.src.len())]);
+        match self.peek(0) {
+            Some(b'a' | b'b' | b'f' | b'n' | b'r' | b't' | b'v' | b'\\' | b'"' | b'\'') => self.pos += 1,
+            Some(b'\n' | b'\r') => self.newline(),
+            Some(b'x') => {
+                for k in 1..=2 {
+                    if !self.peek(k).is_some_and(|c| c.is_ascii_hexdigit()) {
+                        return Err(err(self, "hexadecimal digit expected", self.pos + k + 1));
+                    }
+                }
+                self.pos += 3;
+            }
+            Some(b'z') => {
+                self.pos += 1;
+                while let Some(c) = self.peek(0) {
+                    match c {
+                        b'\n' | b'\r' => self.newline(),
+                        c if c.is_ascii_whitespace() => self.pos += 1,
+                        _ => break,
+                    }
+                }
+            }
+            Some(b'u') => {
+                if self.peek(1) != Some(b'{') {
+                    return Err(err(self, "missing '{' in \\u{xxxx}", self.pos + 2));
+                }
+                self.pos += 2;
+                let digits = self.src.as_bytes()[self.pos..].iter().take_while(|c| c.is_ascii_hexdigit()).count();
+                if digits == 0 {
+                    return Err(err(self, "hexadecimal digit expected", self.pos + 1));
+                }
+                if u64::from_str_radix(&self.src[self.pos..self.pos + digits.min(9)], 16).map_or(true, |v| v > 0x7FFF_FFFF) {
+                    return Err(err(self, "UTF-8 value too large", self.pos + digits));
+                }
+                self.pos += digits;
+                if self.peek(0) != Some(b'}') {
+                    return Err(err(self, "missing '}' in \\u{xxxx}", self.pos + 1));
+                }
+                self.pos += 1;
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let n = self.src.as_bytes()[self.pos..].iter().take(3).take_while(|c| c.is_ascii_digit()).count();
+                if self.src[self.pos..self.pos + n].parse::<u32>().unwrap_or(0) > 255 {
+                    return Err(err(self, "decimal escape too large", self.pos + n));
+                }
+                self.pos += n;
+            }
+            None => {}
+            Some(_) => {
+                let len = self.src[self.pos..].chars().next().map_or(1, char::len_utf8);
+                return Err(err(self, "invalid escape sequence", self.pos + len));
+            }
+        }
+        Ok(())
+    }
+
+    /// The next token, or a lexical error message
+    fn next(&mut self) -> Result<Token, (String, u32, u32)> {
+        loop {
+            match self.peek(0) {
+                Some(b'\n' | b'\r') => self.newline(),
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                Some(b'-') if self.peek(1) == Some(b'-') => {
+                    let (line, col) = (self.line, self.col_at(self.pos));
+                    self.pos += 2;
+                    match self.long_bracket() {
+                        Some(level) => self.skip_long(level, "comment").map_err(|m| (m, line, col))?,
+                        None => while !matches!(self.peek(0), None | Some(b'\n' | b'\r')) { self.pos += 1 },
+                    }
+                }
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        let (line, col) = (self.line, self.col_at(start));
+        let tok = match self.peek(0) {
+            None => Tok::Eof,
+            Some(c) if c.is_ascii_alphabetic() || c == b'_' => {
+                let len = self.src.as_bytes()[start..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == b'_').count();
+                self.pos += len;
+                match KEYWORDS.iter().find(|k| **k == &self.src[start..self.pos]) {
+                    Some(k) => Tok::Sym(k),
+                    None => Tok::Name,
+                }
+            }
+            Some(c) if c.is_ascii_digit() || (c == b'.' && self.peek(1).is_some_and(|d| d.is_ascii_digit())) => {
+                self.pos += luaO_numeral_len(&self.src.as_bytes()[start..]);
+                if luaO_str2number(&self.src[start..self.pos]).is_none() {
+                    return Err((format!("malformed number near '{}'", &self.src[start..self.pos]), line, col));
+                }
+                Tok::Num
+            }
+            Some(b'"' | b'\'') => {
+                self.string(start).map_err(|m| (m, line, col))?;
+                Tok::Str
+            }
+            Some(b'[') if self.long_bracket().is_some() => {
+                let level = self.long_bracket().unwrap();
+                self.skip_long(level, "string").map_err(|m| (m, line, col))?;
+                Tok::Str
+            }
+            Some(_) => match SYMBOLS.iter().find(|s| self.src[start..].starts_with(**s)) {
+                Some(s) => {
+                    self.pos += s.len();
+                    Tok::Sym(s)
+                }
+                None => {
+                    self.pos += self.src[start..].chars().next().map_or(1, char::len_utf8);
+                    Tok::Char
+                }
+            },
+        };
+        Ok(Token { tok, text: self.src[start..self.pos].to_string(), line, col })
+    }
+}
+
+/// Tokens up to the end of input or the first lexical error
+fn tokenize(src: &str, diags: &mut Vec<Diagnostic>) -> Vec<Token> {
+    // a first line starting with '#' (a shebang) is skipped, as by luaL_loadfile
+    let skip = if src.starts_with('#') { src.find('\n').unwrap_or(src.len()) } else { 0 };
+    let mut lx = Lexer { src, pos: skip, line: 1, line_start: 0 };
+    let mut toks = Vec::new();
+    loop {
+        match lx.next() {
+            Ok(t) => {
+                let end = t.tok == Tok::Eof;
+                toks.push(t);
+                if end {
+                    return toks;
+                }
+            }
+            Err((message, line, col)) => {
+                diags.push(Diagnostic { line, col, message });
+                toks.push(Token { tok: Tok::Eof, text: String::new(), line: lx.line, col: lx.col_at(lx.pos.min(src.len())) });
+                return toks;
+            }
+        }
+    }
+}
+
+/// An error was reported; unwind to the enclosing statement list
+struct Abort;
+
+type PResult<T = ()> = Result<T, Abort>;
+
+/// What an expression turned out to be, as far as assignments care
+enum ExpKind {
+    /// A plain name (a local, upvalue or global)
+    Name(String),
+    Indexed,
+    Call,
+    Other,
+}
+
+#[derive(Default)]
+struct Block {
+    is_loop: bool,
+    /// Locals and whether they are const (<const> or <close>)
+    locals: Vec<(String, bool)>,
+    labels: Vec<(String, u32)>,
+    /// gotos not yet matched to a label: name, line, column
+    gotos: Vec<(String, u32, u32)>,
+}
+
+struct Function {
+    is_vararg: bool,
+    blocks: Vec<Block>,
+}
+
+struct Checker {
+    toks: Vec<Token>,
+    pos: usize,
+    diags: Vec<Diagnostic>,
+    funcs: Vec<Function>,
+    /// A lexical error cut the input short: errors at its end are not real
+    truncated: bool,
+}
+
+/// Check `source` as a Lua chunk without running it; an empty result
+/// means it compiles
+pub fn check_syntax(source: &str) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    let toks = tokenize(source, &mut diags);
+    let truncated = !diags.is_empty();
+    let mut c = Checker { toks, pos: 0, diags, funcs: Vec::new(), truncated };
+    c.main_chunk();
+    c.diags.sort_by_key(|d| (d.line, d.col));
+    c.diags.truncate(MAX_DIAGNOSTICS);
+    c.diags
+}
+
+impl Checker {
+    fn cur(&self) -> &Token {
+        &self.toks[self.pos.min(self.toks.len() - 1)]
+    }
+
+    fn is(&self, s: &str) -> bool {
+        self.cur().is(s)
+    }
+
+    fn advance(&mut self) -> Token {
+        let t = self.cur().clone();
+        if t.tok != Tok::Eof {
+            self.pos += 1;
+        }
+        t
+    }
+
+    fn test_next(&mut self, s: &str) -> bool {
+        if self.is(s) {
+            self.advance();
+            return true;
+        }
+        false
+    }
+
+    fn report_at(&mut self, line: u32, col: u32, message: String) {
+        if self.diags.len() < MAX_DIAGNOSTICS {
+            self.diags.push(Diagnostic { line, col, message });
+        }
+    }
+
+    /// Report `msg` near the current token (luaX_syntaxerror)
+    fn error<T>(&mut self, msg: &str) -> PResult<T> {
+        let t = self.cur().clone();
+        if !(self.truncated && t.tok == Tok::Eof) {
+            self.report_at(t.line, t.col, format!("{} near {}", msg, t.near()));
+        }
+        Err(Abort)
+    }
+
+    /// Report `msg` at the current token, not naming it (luaK_semerror)
+    fn semerror<T>(&mut self, msg: &str) -> PResult<T> {
+        let t = self.cur().clone();
+        self.report_at(t.line, t.col, msg.to_string());
+        Err(Abort)
+    }
+
+    fn check(&mut self, s: &str) -> PResult {
+        if self.test_next(s) {
+            return Ok(());
+        }
+        self.error(&format!("'{}' expected", s))
+    }
+
+    /// Close `what` opened at `line` with `s` (check_match)
+    fn check_match(&mut self, s: &str, what: &str, line: u32) -> PResult {
+        if self.test_next(s) {
+            return Ok(());
+        }
+        if line == self.cur().line {
+            self.error(&format!("'{}' expected", s))
+        } else {
+            self.error(&format!("'{}' expected (to close '{}' at line {})", s, what, line))
+        }
+    }
+
+    fn name(&mut self) -> PResult<String> {
+        if self.cur().tok == Tok::Name {
+            return Ok(self.advance().text);
+        }
+        self.error("<name> expected")
+    }
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.cur().tok {
+            Tok::Eof => true,
+            Tok::Sym("else" | "elseif" | "end") => true,
+            Tok::Sym("until") => with_until,
+            _ => false,
+        }
+    }
+
+    // --- scopes ---
+
+    fn func(&mut self) -> &mut Function {
+        self.funcs.last_mut().expect("open function")
+    }
+
+    fn open_block(&mut self, is_loop: bool) {
+        self.func().blocks.push(Block { is_loop, ..Default::default() });
+    }
+
+    /// Close the innermost block: its pending gotos either match one of its
+    /// labels or move out to the enclosing block
+    fn close_block(&mut self) {
+        let b = self.func().blocks.pop().expect("open block");
+        let pending: Vec<_> = b.gotos.into_iter().filter(|(n, _, _)| !b.labels.iter().any(|(l, _)| l == n)).collect();
+        match self.func().blocks.last_mut() {
+            Some(outer) => outer.gotos.extend(pending),
+            None => {
+                for (n, line, col) in pending {
+                    self.report_at(line, col, format!("no visible label '{}' for <goto>", n));
+                }
+            }
+        }
+    }
+
+    fn add_local(&mut self, name: String, is_const: bool) {
+        self.func().blocks.last_mut().expect("open block").locals.push((name, is_const));
+    }
+
+    /// Is `name` a const local of this function or an enclosing one?
+    fn is_const(&self, name: &str) -> bool {
+        for f in self.funcs.iter().rev() {
+            for b in f.blocks.iter().rev() {
+                if let Some((_, c)) = b.locals.iter().rev().find(|(n, _)| n == name) {
+                    return *c;
+                }
+            }
+        }
+        false
+    }
+
+    // --- statements ---
+
+    fn main_chunk(&mut self) {
+        self.funcs.push(Function { is_vararg: true, blocks: Vec::new() });
+        self.open_block(false);
+        self.statlist();
+        if self.cur().tok != Tok::Eof {
+            let _ = self.error::<()>("'<eof>' expected");
+        }
+        self.close_block();
+        self.funcs.pop();
+    }
+
+    fn statlist(&mut self) {
+        while !self.block_follow(true) {
+            if self.is("return") {
+                if self.retstat().is_err() {
+                    self.recover();
+                }
+                // nothing may follow a return in its block
+                if !self.block_follow(true) {
+                    let _ = self.error::<()>("'end' expected");
+                    self.recover();
+                    continue;
+                }
+                return;
+            }
+            let start = self.pos;
+            if self.statement().is_err() {
+                self.recover();
+                if self.pos == start {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    /// Skip to a token that can start a statement or end a block
+    fn recover(&mut self) {
+        loop {
+            match self.cur().tok {
+                Tok::Eof => return,
+                Tok::Sym("local" | "function" | "if" | "while" | "for" | "repeat" | "return" | "do"
+                    | "break" | "goto" | "::" | ";" | "end" | "else" | "elseif" | "until") => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn block(&mut self, is_loop: bool) {
+        self.open_block(is_loop);
+        self.statlist();
+        self.close_block();
+    }
+
+    fn statement(&mut self) -> PResult {
+        let line = self.cur().line;
+        match self.cur().tok {
+            Tok::Sym(";") => {
+                self.advance();
+            }
+            Tok::Sym("if") => self.ifstat(line)?,
+            Tok::Sym("while") => {
+                self.advance();
+                self.expr()?;
+                self.check("do")?;
+                self.block(true);
+                self.check_match("end", "while", line)?;
+            }
+            Tok::Sym("do") => {
+                self.advance();
+                self.block(false);
+                self.check_match("end", "do", line)?;
+            }
+            Tok::Sym("for") => self.forstat(line)?,
+            Tok::Sym("repeat") => {
+                self.advance();
+                // the condition sees the body's locals
+                self.open_block(true);
+                self.statlist();
+                self.check_match("until", "repeat", line)?;
+                let r = self.expr();
+                self.close_block();
+                r?;
+            }
+            Tok::Sym("function") => {
+                self.advance();
+                let is_method = self.funcname()?;
+                self.body(is_method, line)?;
+            }
+            Tok::Sym("local") => {
+                self.advance();
+                if self.test_next("function") {
+                    let name = self.name()?;
+                    self.add_local(name, false);
+                    self.body(false, line)?;
+                } else {
+                    self.localstat()?;
+                }
+            }
+            Tok::Sym("::") => {
+                self.advance();
+                let name = self.name()?;
+                self.check("::")?;
+                let dup = self.func().blocks.iter().flat_map(|b| b.labels.iter()).find(|(n, _)| *n == name).map(|(_, l)| *l);
+                if let Some(prev) = dup {
+                    let t = self.cur().clone();
+                    self.report_at(t.line, t.col, format!("label '{}' already defined on line {}", name, prev));
+                }
+                self.func().blocks.last_mut().expect("open block").labels.push((name, line));
+            }
+            Tok::Sym("break") => {
+                let t = self.advance();
+                if !self.func().blocks.iter().any(|b| b.is_loop) {
+                    self.report_at(t.line, t.col, format!("break outside a loop at line {}", t.line));
+                }
+            }
+            Tok::Sym("goto") => {
+                let t = self.advance();
+                let name = self.name()?;
+                self.func().blocks.last_mut().expect("open block").gotos.push((name, t.line, t.col));
+            }
+            _ => self.exprstat()?,
+        }
+        Ok(())
+    }
+
+    fn ifstat(&mut self, line: u32) -> PResult {
+        // if cond then block {elseif cond then block} [else block] end
+        self.advance();
+        self.expr()?;
+        self.check("then")?;
+        self.block(false);
+        while self.test_next("elseif") {
+            self.expr()?;
+            self.check("then")?;
+            self.block(false);
+        }
+        if self.test_next("else") {
+            self.block(false);
+        }
+        self.check_match("end", "if", line)
+    }
+
+    fn forstat(&mut self, line: u32) -> PResult {
+        self.advance();
+        let first = self.name()?;
+        let mut names = vec![first];
+        match self.cur().tok {
+            Tok::Sym("=") => {
+                self.advance();
+                self.expr()?;
+                self.check(",")?;
+                self.expr()?;
+                if self.test_next(",") {
+                    self.expr()?;
+                }
+            }
+            Tok::Sym("," | "in") => {
+                while self.test_next(",") {
+                    names.push(self.name()?);
+                }
+                self.check("in")?;
+                self.explist()?;
+            }
+            _ => return self.error("'=' or 'in' expected"),
+        }
+        self.check("do")?;
+        self.open_block(true);
+        for n in names {
+            self.add_local(n, false);
+        }
+        self.statlist();
+        self.close_block();
+        self.check_match("end", "for", line)
+    }
+
+    /// funcname: Name {'.' Name} [':' Name]; true for a method
+    fn funcname(&mut self) -> PResult<bool> {
+        self.name()?;
+        while self.test_next(".") {
+            self.name()?;
+        }
+        if self.test_next(":") {
+            self.name()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn localstat(&mut self) -> PResult {
+        let mut names = Vec::new();
+        let mut close = false;
+        loop {
+            let name = self.name()?;
+            let mut is_const = false;
+            if self.test_next("<") {
+                let attr = self.name()?;
+                match attr.as_str() {
+                    "const" => is_const = true,
+                    "close" => {
+                        is_const = true;
+                        if close {
+                            return self.semerror("multiple to-be-closed variables in local list");
+                        }
+                        close = true;
+                    }
+                    _ => return self.semerror(&format!("unknown attribute '{}'", attr)),
+                }
+                self.check(">")?;
+            }
+            names.push((name, is_const));
+            if !self.test_next(",") {
+                break;
+            }
+        }
+        if self.test_next("=") {
+            self.explist()?;
+        }
+        for (n, c) in names {
+            self.add_local(n, c);
+        }
+        Ok(())
+    }
+
+    fn retstat(&mut self) -> PResult {
+        self.advance();
+        if !self.block_follow(true) && !self.is(";") {
+            self.explist()?;
+        }
+        self.test_next(";");
+        Ok(())
+    }
+
+    fn exprstat(&mut self) -> PResult {
+        let first = self.suffixedexp()?;
+        if self.is("=") || self.is(",") {
+            let mut targets = vec![first];
+            while self.test_next(",") {
+                targets.push(self.suffixedexp()?);
+            }
+            for t in &targets {
+                match t {
+                    ExpKind::Name(n) if self.is_const(n) => {
+                        return self.semerror(&format!("attempt to assign to const variable '{}'", n));
+                    }
+                    ExpKind::Name(_) | ExpKind::Indexed => {}
+                    _ => return self.error("syntax error"),
+                }
+            }
+            self.check("=")?;
+            return self.explist();
+        }
+        match first {
+            ExpKind::Call => Ok(()),
+            _ => self.error("syntax error"),
+        }
+    }
+
+    // --- expressions ---
+
+    fn explist(&mut self) -> PResult {
+        self.expr()?;
+        while self.test_next(",") {
+            self.expr()?;
+        }
+        Ok(())
+    }
+
+    /// expr: (simpleexp | unop expr) {binop expr}; precedence does not
+    /// change what is valid
+    fn expr(&mut self) -> PResult {
+        while matches!(self.cur().tok, Tok::Sym("not" | "-" | "#" | "~")) {
+            self.advance();
+        }
+        self.simpleexp()?;
+        while matches!(self.cur().tok, Tok::Sym("+" | "-" | "*" | "/" | "//" | "%" | "^" | ".." | "==" | "~="
+            | "<" | "<=" | ">" | ">=" | "and" | "or" | "&" | "|" | "~" | "<<" | ">>")) {
+            self.advance();
+            while matches!(self.cur().tok, Tok::Sym("not" | "-" | "#" | "~")) {
+                self.advance();
+            }
+            self.simpleexp()?;
+        }
+        Ok(())
+    }
+
+    fn simpleexp(&mut self) -> PResult {
+        let line = self.cur().line;
+        match self.cur().tok {
+            Tok::Num | Tok::Str | Tok::Sym("nil" | "true" | "false") => {
+                self.advance();
+            }
+            Tok::Sym("...") => {
+                if !self.func().is_vararg {
+                    return self.error("cannot use '...' outside a vararg function");
+                }
+                self.advance();
+            }
+            Tok::Sym("{") => self.constructor()?,
+            Tok::Sym("function") => {
+                self.advance();
+                self.body(false, line)?;
+            }
+            _ => {
+                self.suffixedexp()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn primaryexp(&mut self) -> PResult<ExpKind> {
+        match self.cur().tok {
+            Tok::Name => Ok(ExpKind::Name(self.advance().text)),
+            Tok::Sym("(") => {
+                let line = self.advance().line;
+                self.expr()?;
+                self.check_match(")", "(", line)?;
+                Ok(ExpKind::Other)
+            }
+            _ => self.error("unexpected symbol"),
+        }
+    }
+
+    fn suffixedexp(&mut self) -> PResult<ExpKind> {
+        let mut kind = self.primaryexp()?;
+        loop {
+            match self.cur().tok {
+                Tok::Sym(".") => {
+                    self.advance();
+                    self.name()?;
+                    kind = ExpKind::Indexed;
+                }
+                Tok::Sym("[") => {
+                    self.advance();
+                    self.expr()?;
+                    self.check("]")?;
+                    kind = ExpKind::Indexed;
+                }
+                Tok::Sym(":") => {
+                    self.advance();
+                    self.name()?;
+                    self.funcargs()?;
+                    kind = ExpKind::Call;
+                }
+                Tok::Sym("(" | "{") | Tok::Str => {
+                    self.funcargs()?;
+                    kind = ExpKind::Call;
+                }
+                _ => return Ok(kind),
+            }
+        }
+    }
+
+    fn funcargs(&mut self) -> PResult {
+        let line = self.cur().line;
+        match self.cur().tok {
+            Tok::Sym("(") => {
+                self.advance();
+                if !self.is(")") {
+                    self.explist()?;
+                }
+                self.check_match(")", "(", line)
+            }
+            Tok::Sym("{") => self.constructor(),
+            Tok::Str => {
+                self.advance();
+                Ok(())
+            }
+            _ => self.error("function arguments expected"),
+        }
+    }
+
+    fn constructor(&mut self) -> PResult {
+        let line = self.advance().line;
+        while !self.is("}") {
+            if self.cur().tok == Tok::Name && self.toks.get(self.pos + 1).is_some_and(|t| t.is("=")) {
+                self.pos += 2;
+                self.expr()?;
+            } else if self.test_next("[") {
+                self.expr()?;
+                self.check("]")?;
+                self.check("=")?;
+                self.expr()?;
+            } else {
+                self.expr()?;
+            }
+            if !self.test_next(",") && !self.test_next(";") {
+                break;
+            }
+        }
+        self.check_match("}", "{", line)
+    }
+
+    /// body: '(' parlist ')' block 'end'
+    fn body(&mut self, is_method: bool, line: u32) -> PResult {
+        self.check("(")?;
+        let mut params = Vec::new();
+        if is_method {
+            params.push("self".to_string());
+        }
+        let mut is_vararg = false;
+        if !self.is(")") {
+            loop {
+                if self.test_next("...") {
+                    is_vararg = true;
+                    break;
+                }
+                params.push(self.name()?);
+                if !self.test_next(",") {
+                    break;
+                }
+            }
+        }
+        self.check(")")?;
+        self.funcs.push(Function { is_vararg, blocks: Vec::new() });
+        self.open_block(false);
+        for p in params {
+            self.add_local(p, false);
+        }
+        self.statlist();
+        self.close_block();
+        self.funcs.pop();
+        self.check_match("end", "function", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(src: &str) -> Vec<(u32, u32, String)> {
+        check_syntax(src).into_iter().map(|d| (d.line, d.col, d.message)).collect()
+    }
+
+    #[test]
+    fn test_valid_chunks() {
+        let src = "#!/usr/bin/env skyla\nlocal t <const> = {1, 2; x = 'a\\z\n  b', [3] = [==[s]==]}\n\
+            local function f(a, ...) return select('#', ...) end\n\
+            for i = 1, #t do if t[i] then goto continue end ::continue:: end\n\
+            for k, v in pairs(t) do print(k, v) end\n\
+            repeat local n = 1 until n > 0\n\
+            obj:method{1}.field = -0x1p4 // 2 .. \"\\u{48}\\65\"\n\
+            return f(1)";
+        assert_eq!(messages(src), vec![]);
+    }
+
+    #[test]
+    fn test_recovers_and_reports_every_error() {
+        let src = "x = = 1\nlocal y = 2\nif y then\n  z = )\nend\nprint('ok'\n";
+        assert_eq!(messages(src), vec![
+            (1, 5, "unexpected symbol near '='".to_string()),
+            (4, 7, "unexpected symbol near ')'".to_string()),
+            (7, 1, "')' expected (to close '(' at line 6) near <eof>".to_string()),
+        ]);
+        assert_eq!(messages("function f()\n  return 1\n"), vec![(3, 1, "'end' expected (to close 'function' at line 1) near <eof>".to_string())]);
+        assert_eq!(messages("s = 'abc\nx = 1"), vec![(1, 5, "unfinished string near ''abc'".to_string())]);
+        assert_eq!(messages("n = 3x"), vec![(1, 5, "malformed number near '3x'".to_string())]);
+    }
+
+    #[test]
+    fn test_semantic_checks() {
+        let src = "local c <const> = 1\nc = 2\nbreak\ngoto nowhere\nlocal d <other> = 1\nfunction g() return ... end\n::a:: ::a::";
+        assert_eq!(messages(src), vec![
+            (2, 3, "attempt to assign to const variable 'c'".to_string()),
+            (3, 1, "break outside a loop at line 3".to_string()),
+            (4, 1, "no visible label 'nowhere' for <goto>".to_string()),
+            (5, 15, "unknown attribute 'other'".to_string()),
+            (6, 21, "cannot use '...' outside a vararg function near '...'".to_string()),
+            (7, 12, "label 'a' already defined on line 7".to_string()),
+        ]);
+    }
+}
@@ -1,18 +1,25 @@
 //! ltable.rs - Modern, extensible Lua table (hash/array) implementation in Rust
 // Ported and modernized from ltable.c
 
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::{DefaultHasher, RandomState};
 use std::collections::HashMap;
-use std::hash::{Hash, Hasher};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::lobject::{LuaValue, LObject};
 use crate::lstate::LuaState;
 use crate::lgc::GcObject;
+use crate::lstring::TString;
+use crate::skylaconf::{LuaFloat, LuaInteger};
 
-/// TableKey: all valid Lua table keys
+/// TableKey: all valid Lua table keys (string keys are interned, so
+/// short ones hash and compare by address)
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TableKey {
-    Int(i64),
-    Float(f64),
-    Str(String),
+    Int(LuaInteger),
+    Float(LuaFloat),
+    Str(TString),
     Bool(bool),
     Ptr(*const ()),
     Obj(GcObject),
@@ -31,12 +38,18 @@ impl Default for TableMode {
     fn default() -> Self { TableMode::Normal }
 }
 
+/// Error message raised when a frozen table is mutated
+pub const READONLY_TABLE_MSG: &str = "attempt to modify a readonly table";
+
 /// Table: dual array/hash structure, metatable, and GC integration
 pub struct Table {
     array: Vec<Option<LuaValue>>, // array part (1-based)
-    hash: HashMap<TableKey, LuaValue>, // hash part
+    hash: HashMap<TableKey, LuaValue, SeededHasher>, // hash part
+    order: Option<KeyOrder>, // insertion order of the hash keys, if kept
     metatable: Option<GcObject>,
     mode: TableMode,
+    readonly: bool, // set by table.freeze; see check_writable
+    version: u64, // layout version, see TableSlot
 }
 
 impl Default for Table {
@@ -50,9 +63,12 @@ impl Table {
     pub fn new() -> Self {
         Table {
             array: Vec::new(),
-            hash: HashMap::new(),
+            hash: HashMap::with_hasher(SeededHasher::current()),
+            order: None,
             metatable: None,
             mode: TableMode::Normal,
+            readonly: false,
+            version: next_version(),
         }
     }
 
@@ -60,19 +76,33 @@ impl Table {
     pub fn with_capacity(array_cap: usize, hash_cap: usize) -> Self {
         Table {
             array: vec![None; array_cap],
-            hash: HashMap::with_capacity(hash_cap),
+            hash: HashMap::with_capacity_and_hasher(hash_cap, SeededHasher::current()),
+            order: None,
             metatable: None,
             mode: TableMode::Normal,
+            readonly: false,
+            version: next_version(),
         }
     }
 
+    /// Create a new empty table whose hash part traverses in insertion
+    /// order (see KeyOrder for what that costs)
+    pub fn ordered() -> Self {
+        let mut t = Table::new();
+        t.set_ordered(true);
+        t
+    }
+
     /// Create a new table with a mode (normal/weak)
     pub fn with_mode(mode: TableMode) -> Self {
         Table {
             array: Vec::new(),
-            hash: HashMap::new(),
+            hash: HashMap::with_hasher(SeededHasher::current()),
+            order: None,
             metatable: None,
             mode,
+            readonly: false,
+            version: next_version(),
         }
     }
 
@@ -96,14 +126,18 @@ impl Table {
                     return;
                 } else if idx < MAX_ARRAY_SIZE {
                     // Grow array if possible
-                    self.array.resize(idx + 1, None);
+                    self.grow_array(idx + 1);
                     self.array[idx] = Some(value);
                     return;
                 }
             }
             _ => {}
         }
-        self.hash.insert(TableKey::from_lua(key), value);
+        let k = TableKey::from_lua(key);
+        if self.hash.insert(k.clone(), value).is_none() {
+            self.key_added(k);
+            self.bump_version();
+        }
     }
 
     /// Remove a key
@@ -113,7 +147,9 @@ impl Table {
                 self.array[(*i as usize) - 1] = None;
             }
             _ => {
-                self.hash.remove(&TableKey::from_lua(key));
+                if self.hash.remove(&TableKey::from_lua(key)).is_some() {
+                    self.bump_version();
+                }
             }
         }
     }
@@ -129,7 +165,7 @@ impl Table {
         for (i, v) in self.array.iter().enumerate().skip(idx) {
             if v.is_some() {
                 if started {
-                    return Some((LuaValue::Int((i + 1) as i64), v.as_ref().unwrap()));
+                    return Some((LuaValue::Int((i + 1) as LuaInteger), v.as_ref().unwrap()));
                 } else {
                     started = true;
                 }
@@ -137,10 +173,10 @@ impl Table {
         }
         // Hash part
         let mut found = last_key.is_none();
-        for (k, v) in &self.hash {
+        for k in self.hash_keys() {
             let k_lua = k.to_lua();
-            if found && v.is_some() {
-                return Some((k_lua, v));
+            if found {
+                return Some((k_lua, &self.hash[k]));
             }
             if let Some(lk) = last_key {
                 if &k_lua == lk { found = true; }
@@ -153,6 +189,10 @@ impl Table {
     pub fn clear(&mut self) {
         self.array.clear();
         self.hash.clear();
+        if let Some(order) = &mut self.order {
+            *order = KeyOrder::default();
+        }
+        self.bump_version();
     }
 
     /// Check if a key exists
@@ -233,45 +273,141 @@ impl Table {
     /// Idiomatic Rust iterator over all key-value pairs (array + hash)
     pub fn pairs(&self) -> impl Iterator<Item = (LuaValue, &LuaValue)> {
         let array_iter = self.array.iter().enumerate().filter_map(|(i, v)| {
-            v.as_ref().map(|val| (LuaValue::Int((i + 1) as i64), val))
+            v.as_ref().map(|val| (LuaValue::Int((i + 1) as LuaInteger), val))
         });
-        let hash_iter = self.hash.iter().map(|(k, v)| (k.to_lua(), v));
+        let hash_iter = self.hash_keys().map(move |k| (k.to_lua(), &self.hash[k]));
         array_iter.chain(hash_iter)
     }
 
-    /// Rehash: optimize array/hash split for current keys (Lua-style)
+    /// Keys of the hash part in traversal order: insertion order when the
+    /// table keeps it, the map's own order otherwise
+    fn hash_keys(&self) -> Box<dyn Iterator<Item = &TableKey> + '_> {
+        match &self.order {
+            Some(order) => Box::new(order.keys.iter().flatten().filter(move |k| self.hash.contains_key(k))),
+            None => Box::new(self.hash.keys()),
+        }
+    }
+
+    /// Record `k`, just added to the hash part, as its newest key
+    fn key_added(&mut self, k: TableKey) {
+        if let Some(order) = &mut self.order {
+            order.push(k, &self.hash);
+        }
+    }
+
+    /// Does the hash part traverse in insertion order?
+    pub fn is_ordered(&self) -> bool {
+        self.order.is_some()
+    }
+
+    /// Start or stop keeping insertion order. Keys already in the hash
+    /// part keep their current traversal order, ahead of those added later.
+    pub fn set_ordered(&mut self, on: bool) {
+        match (on, self.order.is_some()) {
+            (true, false) => {
+                let keys: Vec<TableKey> = self.hash.keys().cloned().collect();
+                self.order = Some(KeyOrder {
+                    pos: keys.iter().enumerate().map(|(i, k)| (k.clone(), i)).collect(),
+                    keys: keys.into_iter().map(Some).collect(),
+                });
+            }
+            (false, true) => self.order = None,
+            _ => {}
+        }
+    }
+
+    /// Next entry of a traversal (the `next` fast path of a generic for):
+    /// O(1) per step, where `next` from a key has to search for it. Fields
+    /// assigned during the traversal, or cleared (skipped), are fine, as
+    /// for `next`; keys added during it may or may not be visited.
+    pub fn next_entry(&self, cursor: &mut TableCursor) -> Option<(LuaValue, LuaValue)> {
+        while cursor.index < self.array.len() {
+            cursor.index += 1;
+            if let Some(v) = &self.array[cursor.index - 1] {
+                return Some((LuaValue::Int(cursor.index as LuaInteger), v.clone()));
+            }
+        }
+        let keys = cursor.keys.get_or_insert_with(|| self.hash_keys().cloned().collect());
+        while let Some(k) = keys.get(cursor.hpos) {
+            cursor.hpos += 1;
+            if let Some(v) = self.hash.get(k) {
+                return Some((k.to_lua(), v.clone()));
+            }
+        }
+        None
+    }
+
+    /// Rehash: resize the array part to Lua's optimal size, the largest
+    /// power of two n with more than n/2 of the keys 1..n in use. Only the
+    /// entries that change part are moved; nothing is rebuilt.
     pub fn rehash(&mut self) {
-        // Collect all keys/values
-        let mut all = Vec::new();
+        // nums[b]: integer keys in (2^(b-1), 2^b], as numusearray/numusehash
+        let mut nums = [0usize; MAXABITS + 1];
+        let mut total = 0;
         for (i, v) in self.array.iter().enumerate() {
-            if let Some(val) = v { all.push((LuaValue::Int((i + 1) as i64), val.clone())); }
+            if v.is_some() {
+                nums[ceil_log2(i + 1)] += 1;
+                total += 1;
+            }
         }
-        for (k, v) in &self.hash {
-            all.push((k.to_lua(), v.clone()));
+        for k in self.hash.keys() {
+            if let TableKey::Int(i) = k {
+                if *i > 0 && (*i as usize) <= MAX_ARRAY_SIZE {
+                    nums[ceil_log2(*i as usize)] += 1;
+                    total += 1;
+                }
+            }
         }
-        // Find optimal array size (Lua: largest n with >50% 1..n used)
-        let mut n = 0;
-        let mut used = 0;
-        for (k, _) in &all {
-            if let LuaValue::Int(i) = k {
-                if *i > 0 { n = n.max(*i as usize); }
+        let size = compute_sizes(&nums, total);
+        if size > self.array.len() {
+            self.grow_array(size);
+        } else if size < self.array.len() {
+            self.bump_version();
+            for (i, v) in self.array.drain(size..).enumerate() {
+                if let Some(v) = v {
+                    let k = TableKey::Int((size + i + 1) as LuaInteger);
+                    self.hash.insert(k.clone(), v);
+                    if let Some(order) = &mut self.order {
+                        order.push(k, &self.hash);
+                    }
+                }
             }
         }
-        for (k, _) in &all {
-            if let LuaValue::Int(i) = k {
-                if *i > 0 && (*i as usize) <= n { used += 1; }
+    }
+
+    /// Extend the array part to `new_len` slots. Only the hash entries
+    /// whose keys fall in the new slots move, found by probing the new
+    /// range or by one pass over the hash, whichever is shorter; a run of
+    /// keys continuing past the new end is absorbed as well.
+    fn grow_array(&mut self, new_len: usize) {
+        let old_len = self.array.len();
+        self.array.resize(new_len, None);
+        self.bump_version();
+        if self.hash.is_empty() {
+            return;
+        }
+        if new_len - old_len <= self.hash.len() {
+            for i in old_len..new_len {
+                if let Some(v) = self.hash.remove(&TableKey::Int((i + 1) as LuaInteger)) {
+                    self.array[i] = Some(v);
+                }
             }
+        } else {
+            let array = &mut self.array;
+            self.hash.retain(|k, v| match k {
+                TableKey::Int(i) if *i > old_len as LuaInteger && *i <= new_len as LuaInteger => {
+                    array[(*i as usize) - 1] = Some(std::mem::replace(v, LuaValue::Nil));
+                    false
+                }
+                _ => true,
+            });
         }
-        let mut new_array = vec![None; n];
-        let mut new_hash = HashMap::new();
-        for (k, v) in all {
-            if let LuaValue::Int(i) = k {
-                if i > 0 && (i as usize) <= n { new_array[(i as usize) - 1] = Some(v); continue; }
+        while self.array.len() < MAX_ARRAY_SIZE {
+            match self.hash.remove(&TableKey::Int((self.array.len() + 1) as LuaInteger)) {
+                Some(v) => self.array.push(Some(v)),
+                None => break,
             }
-            new_hash.insert(TableKey::from_lua(&k), v);
         }
-        self.array = new_array;
-        self.hash = new_hash;
     }
 
     /// Find the length as per Lua's # operator (last non-nil in array)
@@ -286,18 +422,36 @@ impl Table {
         Table {
             array: self.array.clone(),
             hash: self.hash.clone(),
+            order: self.order.clone(),
             metatable: self.metatable.clone(),
             mode: self.mode,
+            readonly: false,
+            version: next_version(),
         }
     }
-    /// Deep clone (requires LuaValue:Clone to be deep)
+    /// Deep clone: nested tables among the values are copied too. A table
+    /// reached twice (shared or cyclic) is copied once and the copies keep
+    /// the same sharing; keys and metatables are not copied.
     pub fn clone_deep(&self) -> Self {
-        Table {
-            array: self.array.iter().map(|v| v.clone()).collect(),
-            hash: self.hash.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
-            metatable: self.metatable.clone(),
-            mode: self.mode,
+        self.clone_deep_with(&mut HashMap::new())
+    }
+    fn clone_deep_with(&self, seen: &mut HashMap<*const RefCell<Table>, Rc<RefCell<Table>>>) -> Self {
+        let mut t = self.clone_shallow();
+        for v in t.array.iter_mut().flatten().chain(t.hash.values_mut()) {
+            let LuaValue::Table(inner) = v else { continue };
+            let copy = match seen.get(&Rc::as_ptr(inner)) {
+                Some(copy) => copy.clone(),
+                None => {
+                    let copy = Rc::new(RefCell::new(Table::new()));
+                    seen.insert(Rc::as_ptr(inner), copy.clone());
+                    let body = inner.borrow().clone_deep_with(seen);
+                    *copy.borrow_mut() = body;
+                    copy
+                }
+            };
+            *v = LuaValue::Table(copy);
         }
+        t
     }
     /// Filter: keep only entries where predicate returns true
     pub fn filter<F>(&self, mut pred: F) -> Self
@@ -334,13 +488,14 @@ impl Table {
         // Array part
         for (i, v) in self.array.iter_mut().enumerate() {
             if let Some(val) = v {
-                if !pred(&LuaValue::Int((i + 1) as i64), val) {
+                if !pred(&LuaValue::Int((i + 1) as LuaInteger), val) {
                     *v = None;
                 }
             }
         }
         // Hash part
         self.hash.retain(|k, v| pred(&k.to_lua(), v));
+        self.bump_version();
     }
     /// Iterator over all keys
     pub fn keys(&self) -> impl Iterator<Item = LuaValue> + '_ {
@@ -371,15 +526,22 @@ impl Table {
                     }
                     return self.array[idx].as_mut().unwrap();
                 } else if idx < MAX_ARRAY_SIZE {
-                    self.array.resize(idx + 1, None);
-                    self.array[idx] = Some(default());
+                    self.grow_array(idx + 1);
+                    if self.array[idx].is_none() {
+                        self.array[idx] = Some(default());
+                    }
                     return self.array[idx].as_mut().unwrap();
                 }
             }
             _ => {}
         }
         let k = TableKey::from_lua(key);
-        self.hash.entry(k).or_insert_with(default)
+        if !self.hash.contains_key(&k) {
+            self.hash.insert(k.clone(), default());
+            self.key_added(k.clone());
+            self.bump_version();
+        }
+        self.hash.get_mut(&k).unwrap()
     }
     /// Update a value in-place if it exists
     pub fn update<F>(&mut self, key: &LuaValue, mut f: F)
@@ -403,13 +565,55 @@ impl Table {
             LuaValue::Int(i) if *i > 0 && (*i as usize) <= self.array.len() => {
                 self.array[(*i as usize) - 1].take()
             }
-            _ => self.hash.remove(&TableKey::from_lua(key)),
+            _ => {
+                let v = self.hash.remove(&TableKey::from_lua(key));
+                if v.is_some() {
+                    self.bump_version();
+                }
+                v
+            }
         }
     }
+    /// Layout version: changes whenever a TableSlot of this table may
+    /// have been invalidated. Versions are never reused, not even by
+    /// another table.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+    /// Slot of an integer key: any index of the array part (a hole
+    /// included, writing it does not change the layout), or an existing
+    /// hash entry
+    pub fn slot_int(&mut self, i: LuaInteger) -> Option<TableSlot> {
+        if i > 0 && (i as usize) <= self.array.len() {
+            return Some(TableSlot::Array(&mut self.array[(i as usize) - 1]));
+        }
+        self.hash.get_mut(&TableKey::Int(i)).map(|v| TableSlot::Hash(v))
+    }
+    /// Slot of an existing string key; short strings are found by address
+    pub fn slot_str(&mut self, key: &TString) -> Option<TableSlot> {
+        self.hash.get_mut(&TableKey::Str(key.clone())).map(|v| TableSlot::Hash(v))
+    }
+    fn bump_version(&mut self) {
+        self.version = next_version();
+    }
     /// Get current array/hash capacities
     pub fn capacity(&self) -> (usize, usize) {
         (self.array.capacity(), self.hash.capacity())
     }
+    /// Mark the table as readonly (table.freeze); this cannot be undone
+    pub fn freeze(&mut self) {
+        self.readonly = true;
+    }
+    /// Returns true if the table has been frozen
+    pub fn is_frozen(&self) -> bool {
+        self.readonly
+    }
+    /// Err(READONLY_TABLE_MSG) if the table is frozen. The mutators do not
+    /// check it themselves; code writing on behalf of Lua raises the error
+    /// through its state before touching the table.
+    pub fn check_writable(&self) -> Result<(), &'static str> {
+        if self.readonly { Err(READONLY_TABLE_MSG) } else { Ok(()) }
+    }
 }
 
 /// TableKey conversion helpers
@@ -418,7 +622,7 @@ impl TableKey {
         match val {
             LuaValue::Int(i) => TableKey::Int(*i),
             LuaValue::Float(f) => TableKey::Float(*f),
-            LuaValue::Str(s) => TableKey::Str(s.clone()),
+            LuaValue::Str(s) => TableKey::Str(TString::new(s)),
             LuaValue::Bool(b) => TableKey::Bool(*b),
             LuaValue::Pointer(p) => TableKey::Ptr(*p),
             LuaValue::Object(o) => TableKey::Obj(o.clone()),
@@ -429,7 +633,7 @@ impl TableKey {
         match self {
             TableKey::Int(i) => LuaValue::Int(*i),
             TableKey::Float(f) => LuaValue::Float(*f),
-            TableKey::Str(s) => LuaValue::Str(s.clone()),
+            TableKey::Str(s) => LuaValue::Str(s.to_string()),
             TableKey::Bool(b) => LuaValue::Bool(*b),
             TableKey::Ptr(p) => LuaValue::Pointer(*p),
             TableKey::Obj(o) => LuaValue::Object(o.clone()),
@@ -437,9 +641,173 @@ impl TableKey {
     }
 }
 
+/// Insertion order of the keys of a hash part. A removed key keeps its
+/// place, so `next` can go on from a field cleared during a traversal, as
+/// in Lua; the log is compacted only when a key is added, which Lua does
+/// not allow during a traversal anyway.
+///
+/// Costs against the plain hash part: every key is stored twice more (in
+/// `keys` and `pos`), plus up to 2n + 8 log slots left by removed keys,
+/// so an ordered hash part takes roughly three times the key memory;
+/// adding a new key does a second map insertion (and an occasional O(n)
+/// compaction); lookups, assignments to existing fields and the array
+/// part cost the same. A traversal skips the removed slots, so after heavy
+/// churn it may visit up to three times as many slots as there are keys.
+#[derive(Debug, Clone, Default)]
+struct KeyOrder {
+    keys: Vec<Option<TableKey>>, // None: the key was removed and added again later
+    pos: HashMap<TableKey, usize>,
+}
+
+impl KeyOrder {
+    /// Append `k`, now in `hash`, moving it to the end if it was logged before
+    fn push(&mut self, k: TableKey, hash: &HashMap<TableKey, LuaValue, SeededHasher>) {
+        if let Some(i) = self.pos.insert(k.clone(), self.keys.len()) {
+            self.keys[i] = None;
+        }
+        self.keys.push(Some(k));
+        if self.keys.len() > 2 * hash.len() + 8 {
+            let live: Vec<TableKey> = self.keys.drain(..).flatten().filter(|k| hash.contains_key(k)).collect();
+            self.pos = live.iter().enumerate().map(|(i, k)| (k.clone(), i)).collect();
+            self.keys = live.into_iter().map(Some).collect();
+        }
+    }
+}
+
+thread_local! {
+    /// Fixed SipHash key of the hash parts of tables created on this
+    /// thread, if the last GlobalState created here is deterministic
+    static HASH_KEY: Cell<Option<(u64, u64)>> = const { Cell::new(None) };
+}
+
+/// Key the hash parts of tables created from now on by this thread with
+/// the fixed 128-bit `key` of a deterministic state, or with fresh random
+/// keys (None, the default)
+pub fn set_hash_key(key: Option<(u64, u64)>) {
+    HASH_KEY.with(|k| k.set(key));
+}
+
+/// Builds the hashers of a hash part. By default each table gets its own
+/// random SipHash keys (RandomState), so the bucket layout an attacker
+/// would need to flood a table cannot be predicted; a deterministic state
+/// keys every table with its fixed key, so hash order repeats across runs.
+#[derive(Debug, Clone)]
+pub enum SeededHasher {
+    Random(RandomState),
+    Fixed(u64, u64),
+}
+
+impl SeededHasher {
+    fn current() -> Self {
+        match HASH_KEY.with(|k| k.get()) {
+            Some((k0, k1)) => SeededHasher::Fixed(k0, k1),
+            None => SeededHasher::Random(RandomState::new()),
+        }
+    }
+}
+
+impl BuildHasher for SeededHasher {
+    type Hasher = DefaultHasher;
+    fn build_hasher(&self) -> DefaultHasher {
+        match self {
+            SeededHasher::Random(r) => r.build_hasher(),
+            SeededHasher::Fixed(k0, k1) => {
+                let mut h = DefaultHasher::new();
+                h.write_u64(*k0);
+                h.write_u64(*k1);
+                h
+            }
+        }
+    }
+}
+
+/// Position of a traversal with Table::next_entry: an index into the
+/// array part, then into the hash keys present when it reached them
+#[derive(Debug, Default)]
+pub struct TableCursor {
+    index: usize,
+    keys: Option<Vec<TableKey>>,
+    hpos: usize,
+}
+
+impl TableCursor {
+    pub fn new() -> Self {
+        TableCursor::default()
+    }
+}
+
+/// Where the value of a key lives inside a table, for the VM's GETTABLE
+/// and SETTABLE fast paths: reading or writing it needs neither a borrow
+/// of the table nor a copy of the key. A slot stays valid while the
+/// table's version() is the one it was taken at; growing or shrinking the
+/// array part, adding or removing a hash key, rehash and clear all change
+/// it. Writes through a slot skip the readonly check.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableSlot {
+    Array(*mut Option<LuaValue>),
+    Hash(*mut LuaValue),
+}
+
+impl TableSlot {
+    /// The value in the slot (None for a hole of the array part)
+    ///
+    /// # Safety
+    /// The table must be alive and still at the version the slot was
+    /// taken at, with no other reference to the value live.
+    pub unsafe fn get<'a>(self) -> Option<&'a LuaValue> {
+        match self {
+            TableSlot::Array(p) => (*p).as_ref(),
+            TableSlot::Hash(p) => Some(&*p),
+        }
+    }
+
+    /// Store `v` in the slot. Nil cannot be stored in a hash slot, since
+    /// removing a key changes the layout: use Table::remove for it.
+    ///
+    /// # Safety
+    /// As for get.
+    pub unsafe fn set(self, v: LuaValue) {
+        match self {
+            TableSlot::Array(p) => *p = if matches!(v, LuaValue::Nil) { None } else { Some(v) },
+            TableSlot::Hash(p) => *p = v,
+        }
+    }
+}
+
+static NEXT_VERSION: AtomicU64 = AtomicU64::new(1);
+
+fn next_version() -> u64 {
+    NEXT_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
 /// Maximum array size for Lua tables (configurable)
 pub const MAX_ARRAY_SIZE: usize = 1 << 24;
 
+/// log2 of MAX_ARRAY_SIZE: the slices counted by Table::rehash
+const MAXABITS: usize = 24;
+
+/// ceil(log2(x)) for x >= 1
+fn ceil_log2(x: usize) -> usize {
+    (usize::BITS - (x - 1).leading_zeros()) as usize
+}
+
+/// computesizes: the largest power of two n such that more than n/2 of the
+/// integer keys 1..n are present (0 if none)
+fn compute_sizes(nums: &[usize], total: usize) -> usize {
+    let (mut a, mut optimal) = (0, 0);
+    for (b, n) in nums.iter().enumerate() {
+        let twotoi = 1usize << b;
+        if twotoi / 2 >= total {
+            break;
+        }
+        a += n;
+        if a > twotoi / 2 {
+            optimal = twotoi;
+        }
+    }
+    optimal
+}
+
 // --- Advanced features: custom hashers, D-based helpers, etc. can be added here ---
 
 // --- Tests ---
@@ -458,6 +826,85 @@ mod tests {
         assert_eq!(t.get(&LuaValue::Int(1)), None);
     }
     #[test]
+    fn test_next_entry_cursor() {
+        let mut t = Table::new();
+        for i in 1..=3 {
+            t.set(&LuaValue::Int(i), LuaValue::Int(i * 10));
+        }
+        for k in ["a", "b", "c"] {
+            t.set(&LuaValue::Str(k.to_string()), LuaValue::Bool(true));
+        }
+        let mut cursor = TableCursor::new();
+        let mut seen = Vec::new();
+        while let Some((k, _)) = t.next_entry(&mut cursor) {
+            if k == LuaValue::Int(1) {
+                // clearing fields during a traversal is allowed
+                t.remove(&LuaValue::Int(2));
+                t.remove(&LuaValue::Str("b".to_string()));
+                t.remove(&LuaValue::Str("c".to_string()));
+                t.set(&LuaValue::Str("a".to_string()), LuaValue::Bool(true));
+            }
+            seen.push(k);
+        }
+        assert_eq!(seen.len(), 3);
+        assert!(seen.contains(&LuaValue::Int(3)) && seen.contains(&LuaValue::Str("a".to_string())));
+    }
+    #[test]
+    fn test_insertion_order() {
+        let mut t = Table::ordered();
+        assert!(t.is_ordered() && !Table::new().is_ordered());
+        let key = |s: &str| LuaValue::Str(s.to_string());
+        for k in ["z", "a", "m", "b"] {
+            t.set(&key(k), LuaValue::Bool(true));
+        }
+        t.set(&LuaValue::Int(1), LuaValue::Int(1));
+        t.remove(&key("a"));
+        t.set(&key("a"), LuaValue::Bool(false)); // added again: now the newest
+        t.set(&key("m"), LuaValue::Int(0)); // assigning keeps the place
+        let expected = vec![LuaValue::Int(1), key("z"), key("m"), key("b"), key("a")];
+        assert_eq!(t.keys().collect::<Vec<_>>(), expected);
+        let mut cursor = TableCursor::new();
+        let mut seen = Vec::new();
+        while let Some((k, _)) = t.next_entry(&mut cursor) {
+            seen.push(k);
+        }
+        assert_eq!(seen, expected);
+        // churn compacts the log without disturbing the order
+        for i in 0..100 {
+            t.set(&key(&format!("tmp{}", i)), LuaValue::Int(i));
+            t.remove(&key(&format!("tmp{}", i)));
+        }
+        assert_eq!(t.keys().collect::<Vec<_>>(), expected);
+        assert!(t.order.as_ref().unwrap().keys.len() <= 2 * t.len_hash() + 9);
+    }
+    #[test]
+    fn test_set_ordered() {
+        let key = |s: &str| LuaValue::Str(s.to_string());
+        let mut t = Table::ordered();
+        assert!(t.is_ordered());
+        for k in ["q", "w", "e", "r", "t", "y"] {
+            t.set(&key(k), LuaValue::Bool(true));
+        }
+        let order: Vec<LuaValue> = t.keys().collect();
+        assert_eq!(order, ["q", "w", "e", "r", "t", "y"].map(key));
+        // the order survives a shallow clone, and turning it off keeps the keys
+        assert_eq!(t.clone_shallow().keys().collect::<Vec<_>>(), order);
+        t.set_ordered(false);
+        assert!(!t.is_ordered() && t.len_hash() == 6);
+
+        // switching on later freezes the current order of the existing keys
+        let mut u = Table::new();
+        for i in 0..20 {
+            u.set(&key(&format!("k{}", i)), LuaValue::Int(i));
+        }
+        let before: Vec<LuaValue> = u.keys().collect();
+        u.set_ordered(true);
+        u.set(&key("last"), LuaValue::Int(0));
+        let mut after: Vec<LuaValue> = u.keys().collect();
+        assert_eq!(after.pop(), Some(key("last")));
+        assert_eq!(after, before);
+    }
+    #[test]
     fn test_table_next() {
         let mut t = Table::new();
         t.set(&LuaValue::Int(1), LuaValue::Int(10));
@@ -486,6 +933,57 @@ mod tests {
         assert_eq!(t.lua_len(), 2);
     }
     #[test]
+    fn test_table_grow_moves_only_hash_keys_in_range() {
+        let mut t = Table::new();
+        for i in [1, 3, 1000] {
+            t.set(&LuaValue::Int(i), LuaValue::Int(i));
+        }
+        // array part shrinks to 1; 3 and 1000 move to the hash part
+        t.rehash();
+        assert_eq!((t.len(), t.len_hash()), (1, 2));
+        // appending 2 absorbs the run that continues in the hash part
+        t.set(&LuaValue::Int(2), LuaValue::Int(2));
+        assert_eq!((t.len(), t.len_hash()), (3, 1));
+        // growing over a hash key replaces it instead of shadowing it
+        t.set(&LuaValue::Int(1000), LuaValue::Int(-1));
+        assert_eq!(t.len_hash(), 0);
+        assert_eq!(t.get(&LuaValue::Int(1000)), Some(&LuaValue::Int(-1)));
+        assert_eq!(t.get(&LuaValue::Int(3)), Some(&LuaValue::Int(3)));
+    }
+    #[test]
+    fn test_compute_sizes() {
+        let mut t = Table::new();
+        for i in 1..=10 {
+            t.set(&LuaValue::Int(i), LuaValue::Int(i));
+        }
+        t.rehash();
+        assert_eq!(t.array.len(), 16);
+        assert_eq!(compute_sizes(&[1, 1, 0, 0, 0, 0, 0, 1], 3), 2);
+        assert_eq!(compute_sizes(&[0; MAXABITS + 1], 0), 0);
+    }
+    #[test]
+    fn test_slots_and_version() {
+        let mut t = Table::new();
+        t.set(&LuaValue::Int(1), LuaValue::Int(10));
+        t.set(&LuaValue::Str("x".to_string()), LuaValue::Int(20));
+        let v = t.version();
+        let (a, h) = (t.slot_int(1).unwrap(), t.slot_str(&TString::new("x")).unwrap());
+        assert!(t.slot_int(2).is_none() && t.slot_str(&TString::new("y")).is_none());
+        unsafe {
+            assert_eq!(a.get(), Some(&LuaValue::Int(10)));
+            h.set(LuaValue::Int(21));
+            a.set(LuaValue::Nil);
+        }
+        // overwriting fields keeps the layout, and the slots
+        t.set(&LuaValue::Str("x".to_string()), LuaValue::Int(22));
+        assert_eq!(t.version(), v);
+        assert_eq!(unsafe { h.get() }, Some(&LuaValue::Int(22)));
+        assert_eq!(t.get(&LuaValue::Int(1)), None);
+        t.set(&LuaValue::Str("y".to_string()), LuaValue::Int(30));
+        assert_ne!(t.version(), v);
+        assert_ne!(Table::new().version(), Table::new().version());
+    }
+    #[test]
     fn test_table_pairs() {
         let mut t = Table::new();
         t.set(&LuaValue::Int(1), LuaValue::Int(1));
@@ -839,7 +1337,7 @@ mod tests {
         // Type-changing map
         let t3 = t.map_values(|v| match v {
             LuaValue::Int(i) => LuaValue::Str(format!("num={}", i)),
-            LuaValue::Str(s) => LuaValue::Int(s.len() as i64),
+            LuaValue::Str(s) => LuaValue::Int(s.len() as LuaInteger),
             _ => v.clone(),
         });
         assert_eq!(t3.get(&LuaValue::Int(1)), Some(&LuaValue::Str("num=5".to_string())));
@@ -860,6 +1358,22 @@ mod tests {
         assert_eq!(t_deep.get(&LuaValue::Int(1)), Some(&LuaValue::Int(42)));
     }
 
+    #[test]
+    fn test_table_clone_deep_keeps_sharing() {
+        let inner = Rc::new(RefCell::new(Table::new()));
+        inner.borrow_mut().set(&LuaValue::Int(1), LuaValue::Table(inner.clone()));
+        let mut t = Table::new();
+        t.set(&LuaValue::Int(1), LuaValue::Table(inner.clone()));
+        t.set(&LuaValue::Str("again".to_string()), LuaValue::Table(inner.clone()));
+        let c = t.clone_deep();
+        let (Some(LuaValue::Table(a)), Some(LuaValue::Table(b))) = (c.get(&LuaValue::Int(1)), c.get(&LuaValue::Str("again".to_string()))) else {
+            panic!("nested tables were not cloned")
+        };
+        assert!(Rc::ptr_eq(a, b) && !Rc::ptr_eq(a, &inner));
+        // the cycle through the copy stays inside the copy
+        assert!(matches!(a.borrow().get(&LuaValue::Int(1)), Some(LuaValue::Table(x)) if Rc::ptr_eq(x, a)));
+    }
+
     #[test]
     fn test_table_retain_all_and_none() {
         let mut t = Table::new();
@@ -979,4 +1493,59 @@ mod tests {
         t.set(&LuaValue::Str("foo".to_string()), LuaValue::Int(456));
         assert_eq!(t.rawget(&LuaValue::Str("foo".to_string())), t.get(&LuaValue::Str("foo".to_string())));
     }
+
+    #[test]
+    fn test_table_freeze_is_frozen() {
+        let mut t = Table::new();
+        t.set(&LuaValue::Int(1), LuaValue::Int(10));
+        assert!(!t.is_frozen());
+        t.freeze();
+        assert!(t.is_frozen());
+        assert_eq!(t.get(&LuaValue::Int(1)), Some(&LuaValue::Int(10)));
+    }
+
+    #[test]
+    fn test_table_frozen_check_writable() {
+        let mut t = Table::new();
+        t.set(&LuaValue::Int(1), LuaValue::Int(1));
+        assert_eq!(t.check_writable(), Ok(()));
+        t.freeze();
+        assert_eq!(t.check_writable(), Err(READONLY_TABLE_MSG));
+        assert_eq!(t.get(&LuaValue::Int(1)), Some(&LuaValue::Int(1)));
+    }
+
+    #[test]
+    fn test_table_clone_of_frozen_is_writable() {
+        let mut t = Table::new();
+        t.set(&LuaValue::Int(1), LuaValue::Int(1));
+        t.freeze();
+        let mut c = t.clone_shallow();
+        assert!(!c.is_frozen());
+        c.set(&LuaValue::Int(2), LuaValue::Int(2));
+        assert_eq!(c.len(), 2);
+    }
+
+    #[test]
+    fn test_table_string_keys_interned() {
+        let a = TableKey::from_lua(&LuaValue::Str("name".to_string()));
+        let b = TableKey::from_lua(&LuaValue::Str("name".to_string()));
+        assert_eq!(a, b);
+        assert!(matches!(b.to_lua(), LuaValue::Str(ref s) if s == "name"));
+    }
+
+    #[test]
+    fn test_table_hash_order_follows_key() {
+        let build = |key| {
+            set_hash_key(key);
+            let mut t = Table::new();
+            for i in 0..64 {
+                t.set(&LuaValue::Int(-i), LuaValue::Int(i));
+            }
+            t.keys().collect::<Vec<_>>()
+        };
+        assert_eq!(build(Some((7, 1))), build(Some((7, 1))));
+        assert_ne!(build(Some((7, 1))), build(Some((7, 2))));
+        // without a fixed key every table is keyed at random
+        assert_ne!(build(None), build(None));
+    }
 }
@@ -22,8 +22,9 @@ macro_rules! unimplemented_table {
     }};
 }
 
-use crate::lstate::LuaState;
+use crate::lstate::{raw_equal, LuaState, lua_State};
 use crate::lobject::LuaValue;
+use crate::ltable::READONLY_TABLE_MSG;
 
 // Helper: checkfield
 fn checkfield(state: &mut LuaState, key: &str, n: i32) -> bool {
@@ -46,10 +47,46 @@ fn aux_getn(state: &mut LuaState, n: i32, w: u8) -> i64 {
     state.len(n)
 }
 
+// Table library function list (name, implementation)
+const TAB_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("concat", table_concat),
+    ("insert", table_insert),
+    ("pack", table_pack),
+    ("unpack", table_unpack),
+    ("remove", table_remove),
+    ("move", table_move),
+    ("sort", table_sort),
+    ("create", table_create),
+];
+
+// Skyla extensions to the table library (enabled with the `skyla_ext` feature)
+#[cfg(feature = "skyla_ext")]
+const TAB_EXT_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("clear", table_clear),
+    ("clone", table_clone),
+    ("find", table_find),
+    ("freeze", table_freeze),
+    ("isfrozen", table_isfrozen),
+    ("ordered", table_ordered),
+    ("isordered", table_isordered),
+];
+
 // Register all table library functions
 pub fn open_table_lib(state: &mut LuaState) {
-    // Register each function below with the global 'table' library
-    // Example: state.register_lib_function("table", "concat", table_concat);
+    for &(name, f) in TAB_FUNCS {
+        state.register_lib_function("table", name, f);
+    }
+    #[cfg(feature = "skyla_ext")]
+    for &(name, f) in TAB_EXT_FUNCS {
+        state.register_lib_function("table", name, f);
+    }
+}
+
+// luaopen_table: entry point used by linit
+pub fn luaopen_table(L: *mut lua_State) -> i32 {
+    let state = unsafe { &mut *(L as *mut LuaState) };
+    open_table_lib(state);
+    1
 }
 
 // table.concat(table, sep, i, j)
@@ -70,7 +107,6 @@ pub fn table_concat(state: &mut LuaState) -> i32 {
             }
             _ => {
                 state.error(&format!("invalid value at index {} in table for 'concat'", idx));
-                return 0;
             }
         }
     }
@@ -84,6 +120,9 @@ pub fn table_insert(state: &mut LuaState) -> i32 {
     let nargs = state.get_top();
     // Get the table
     let table = state.check_table(1);
+    if table.is_frozen() {
+        state.error(READONLY_TABLE_MSG);
+    }
     let len = aux_getn(state, 1, TAB_RW);
     let mut pos = len + 1; // default: insert at end
     let value;
@@ -103,7 +142,6 @@ pub fn table_insert(state: &mut LuaState) -> i32 {
         }
     } else {
         state.error("wrong number of arguments to 'insert'");
-        return 0;
     }
     table.set(pos as usize, value);
     0
@@ -112,6 +150,9 @@ pub fn table_insert(state: &mut LuaState) -> i32 {
 // table.remove(table, [pos])
 pub fn table_remove(state: &mut LuaState) -> i32 {
     let table = state.check_table(1);
+    if table.is_frozen() {
+        state.error(READONLY_TABLE_MSG);
+    }
     let len = aux_getn(state, 1, TAB_RW);
     let pos = state.opt_integer(2, len);
     if pos != len {
@@ -137,6 +178,9 @@ pub fn table_move(state: &mut LuaState) -> i32 {
     let tt = if state.is_none_or_nil(5) { 1 } else { 5 };
     let src = state.check_table(1);
     let dst = state.check_table(tt);
+    if dst.is_frozen() {
+        state.error(READONLY_TABLE_MSG);
+    }
     if e >= f {
         let n = e - f + 1;
         if t > i64::MAX - n + 1 {
@@ -159,33 +203,44 @@ pub fn table_move(state: &mut LuaState) -> i32 {
 }
 
 // table.pack(...)
+// The arguments are the slots above the function's own, so n counts
+// trailing nils exactly as select('#', ...) does
 pub fn table_pack(state: &mut LuaState) -> i32 {
     let n = state.get_top();
-    let table = state.create_table(n, 1);
-    for i in 1..=n {
-        let v = state.to_value(i);
-        table.set(i, v);
+    let table = state.create_table(n as usize, 1);
+    {
+        let mut t = table.borrow_mut();
+        for i in 1..=n {
+            let v = state.to_value(i);
+            if !matches!(v, LuaValue::Nil) {
+                t.rawset(&LuaValue::Int(i as i64), v);
+            }
+        }
+        t.rawset(&LuaValue::Str("n".to_string()), LuaValue::Int(n as i64));
     }
-    table.set_field("n", LuaValue::Int(n as i64));
-    state.push(table);
+    state.push(LuaValue::Table(table));
     1
 }
 
 // table.unpack(list, [i, j])
+// Elements are read with __index, so proxies of lists unpack too
 pub fn table_unpack(state: &mut LuaState) -> i32 {
     let i = state.opt_integer(2, 1);
-    let e = state.opt_integer(3, aux_getn(state, 1, TAB_R));
+    let e = if state.is_none_or_nil(3) { aux_getn(state, 1, TAB_R) } else { state.check_integer(3) };
     if i > e {
         return 0;
     }
-    let table = state.check_table(1);
-    let mut n = 0;
+    // number of elements - 1, which may not fit in an i64
+    let n = e.wrapping_sub(i) as u64;
+    if n >= i32::MAX as u64 || !state.check_stack(n as usize + 1) {
+        state.error("too many results to unpack");
+    }
+    let list = state.to_value(1);
     for idx in i..=e {
-        let v = table.get(idx as usize);
+        let v = crate::lvm::luaV_finishget(state, &list, &LuaValue::Int(idx));
         state.push(v);
-        n += 1;
     }
-    n
+    (n + 1) as i32
 }
 
 // table.sort(table [, comp])
@@ -202,6 +257,212 @@ pub fn table_create(state: &mut LuaState) -> i32 {
     // Optionally check for overflow (INT_MAX)
     // Create a new table with the given capacities
     let table = state.create_table(sizeseq, sizerest);
-    state.push(table);
+    state.push(LuaValue::Table(table));
+    1
+}
+// table.clear(t) [skyla_ext]
+// Removes all entries but keeps the allocated array/hash capacity for reuse.
+#[cfg(feature = "skyla_ext")]
+pub fn table_clear(state: &mut LuaState) -> i32 {
+    let table = state.check_table(1);
+    if table.is_frozen() {
+        state.error(READONLY_TABLE_MSG);
+    }
+    table.clear();
+    0
+}
+
+// table.clone(t [, deep]) [skyla_ext]
+#[cfg(feature = "skyla_ext")]
+pub fn table_clone(state: &mut LuaState) -> i32 {
+    let table = state.check_table(1);
+    let deep = state.opt_boolean(2, false);
+    let copy = if deep { table.clone_deep() } else { table.clone_shallow() };
+    state.push(copy);
     1
-}
\ No newline at end of file
+}
+
+// table.find(t, value [, init]) [skyla_ext]
+// Returns the first index >= init whose value is raw-equal to `value`, or nil;
+// as with rawequal, an integer and a float of the same value are equal.
+#[cfg(feature = "skyla_ext")]
+pub fn table_find(state: &mut LuaState) -> i32 {
+    let table = state.check_table(1);
+    let value = state.to_value(2);
+    let init = state.opt_integer(3, 1);
+    let len = aux_getn(state, 1, TAB_R);
+    if init < 1 {
+        state.arg_error(3, "initial position out of bounds");
+    }
+    for idx in init..=len {
+        if raw_equal(&table.get(idx as usize), &value) {
+            state.push(LuaValue::Int(idx));
+            return 1;
+        }
+    }
+    state.push(LuaValue::Nil);
+    1
+}
+
+// table.freeze(t) [skyla_ext]
+// Makes `t` readonly: rawset, table.insert/remove and SETTABLE will raise an error.
+#[cfg(feature = "skyla_ext")]
+pub fn table_freeze(state: &mut LuaState) -> i32 {
+    let table = state.check_table(1);
+    table.freeze();
+    state.push(table.clone());
+    1
+}
+
+// table.isfrozen(t) [skyla_ext]
+#[cfg(feature = "skyla_ext")]
+pub fn table_isfrozen(state: &mut LuaState) -> i32 {
+    let table = state.check_table(1);
+    state.push(LuaValue::Bool(table.is_frozen()));
+    1
+}
+
+// table.ordered([t]) [skyla_ext]
+// Makes pairs/next visit the hash part of t in insertion order from now on
+// (keys already there keep their current order) and returns t; without t,
+// returns a new empty ordered table.
+#[cfg(feature = "skyla_ext")]
+pub fn table_ordered(state: &mut LuaState) -> i32 {
+    if state.is_none_or_nil(1) {
+        state.push(crate::ltable::Table::ordered());
+        return 1;
+    }
+    let table = state.check_table(1);
+    table.set_ordered(true);
+    state.push(table.clone());
+    1
+}
+
+// table.isordered(t) [skyla_ext]
+#[cfg(feature = "skyla_ext")]
+pub fn table_isordered(state: &mut LuaState) -> i32 {
+    let table = state.check_table(1);
+    state.push(LuaValue::Bool(table.is_ordered()));
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lauxlib::call_lib;
+    use crate::lstate::GlobalState;
+
+    #[test]
+    fn test_pack_counts_like_select() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let s = |x: &str| LuaValue::Str(x.to_string());
+        for args in [vec![LuaValue::Int(1), LuaValue::Nil, LuaValue::Nil], vec![LuaValue::Nil], vec![]] {
+            let mut select_args = vec![s("#")];
+            select_args.extend(args.iter().cloned());
+            let count = call_lib(&mut state, crate::lbaselib::base_select, select_args);
+            let r = call_lib(&mut state, table_pack, args.clone());
+            let [LuaValue::Table(t)] = &r[..] else { panic!("pack returned {:?}", r) };
+            let t = t.borrow();
+            assert_eq!(vec![t.get(&s("n")).cloned().unwrap()], count);
+            for (i, a) in args.iter().enumerate() {
+                assert_eq!(t.get(&LuaValue::Int(i as i64 + 1)).cloned().unwrap_or(LuaValue::Nil), *a);
+            }
+        }
+    }
+    #[test]
+    fn test_unpack_limits_and_fallbacks() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let table = || Rc::new(RefCell::new(crate::ltable::Table::new()));
+        // ranges that cannot fit on the stack are rejected before anything is pushed
+        for (i, e) in [(1, i64::MAX), (i64::MIN, i64::MAX), (0, i32::MAX as i64)] {
+            let args = vec![LuaValue::Table(table()), LuaValue::Int(i), LuaValue::Int(e)];
+            let r = state.pcall(|s| call_lib(s, table_unpack, args));
+            assert!(matches!(r, Err(crate::lerror::Error::Runtime(LuaValue::Str(ref m))) if m.contains("too many results to unpack")), "{:?}", r);
+        }
+        // an empty range returns nothing, even at the integer limits
+        let r = call_lib(&mut state, table_unpack, vec![LuaValue::Table(table()), LuaValue::Int(i64::MAX), LuaValue::Int(i64::MIN)]);
+        assert!(r.is_empty());
+        // elements missing from the table itself are read through __index
+        let (proxy, backing, mt) = (table(), table(), table());
+        backing.borrow_mut().set(&LuaValue::Int(2), LuaValue::Int(20));
+        backing.borrow_mut().set(&LuaValue::Int(3), LuaValue::Int(30));
+        proxy.borrow_mut().set(&LuaValue::Int(1), LuaValue::Int(10));
+        mt.borrow_mut().set(&LuaValue::Str("__index".to_string()), LuaValue::Table(backing));
+        state.set_value_metatable(&LuaValue::Table(proxy.clone()), LuaValue::Table(mt));
+        let r = call_lib(&mut state, table_unpack, vec![LuaValue::Table(proxy), LuaValue::Int(1), LuaValue::Int(4)]);
+        assert_eq!(r, vec![LuaValue::Int(10), LuaValue::Int(20), LuaValue::Int(30), LuaValue::Nil]);
+    }
+
+    #[cfg(feature = "skyla_ext")]
+    fn list(items: &[LuaValue]) -> Rc<RefCell<crate::ltable::Table>> {
+        let t = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        for (i, v) in items.iter().enumerate() {
+            t.borrow_mut().set(&LuaValue::Int(i as i64 + 1), v.clone());
+        }
+        t
+    }
+
+    #[cfg(feature = "skyla_ext")]
+    #[test]
+    fn test_clear_keeps_the_table() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let t = list(&[LuaValue::Int(1), LuaValue::Int(2)]);
+        t.borrow_mut().set(&LuaValue::Str("x".to_string()), LuaValue::Int(3));
+        assert!(call_lib(&mut state, table_clear, vec![LuaValue::Table(t.clone())]).is_empty());
+        assert!(t.borrow().is_empty());
+        // a frozen table is left as it is
+        let t = list(&[LuaValue::Int(1)]);
+        t.borrow_mut().freeze();
+        let r = state.pcall(|s| call_lib(s, table_clear, vec![LuaValue::Table(t.clone())]));
+        assert!(matches!(r, Err(crate::lerror::Error::Runtime(LuaValue::Str(ref m))) if m == READONLY_TABLE_MSG), "{:?}", r);
+        assert_eq!(t.borrow().len(), 1);
+    }
+
+    #[cfg(feature = "skyla_ext")]
+    #[test]
+    fn test_clone_shallow_and_deep() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let inner = list(&[LuaValue::Int(10)]);
+        let t = list(&[LuaValue::Int(1), LuaValue::Table(inner.clone())]);
+        t.borrow_mut().freeze();
+        let nested = |r: &[LuaValue]| {
+            let [LuaValue::Table(c)] = r else { panic!("clone returned {:?}", r) };
+            assert!(!Rc::ptr_eq(c, &t) && !c.borrow().is_frozen());
+            assert_eq!(c.borrow().get(&LuaValue::Int(1)), Some(&LuaValue::Int(1)));
+            match c.borrow().get(&LuaValue::Int(2)) {
+                Some(LuaValue::Table(n)) => n.clone(),
+                other => panic!("nested value {:?}", other),
+            }
+        };
+        let r = call_lib(&mut state, table_clone, vec![LuaValue::Table(t.clone())]);
+        assert!(Rc::ptr_eq(&nested(&r), &inner));
+        let r = call_lib(&mut state, table_clone, vec![LuaValue::Table(t.clone()), LuaValue::Bool(true)]);
+        let copy = nested(&r);
+        assert!(!Rc::ptr_eq(&copy, &inner));
+        assert_eq!(copy.borrow().get(&LuaValue::Int(1)), Some(&LuaValue::Int(10)));
+    }
+
+    #[cfg(feature = "skyla_ext")]
+    #[test]
+    fn test_find_uses_raw_equality() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let s = |x: &str| LuaValue::Str(x.to_string());
+        let t = list(&[LuaValue::Int(1), LuaValue::Float(2.0), s("3"), LuaValue::Float(f64::NAN)]);
+        let mut find = |args: Vec<LuaValue>| {
+            let mut all = vec![LuaValue::Table(t.clone())];
+            all.extend(args);
+            call_lib(&mut state, table_find, all)
+        };
+        assert_eq!(find(vec![LuaValue::Float(1.0)]), vec![LuaValue::Int(1)]);
+        assert_eq!(find(vec![LuaValue::Int(2)]), vec![LuaValue::Int(2)]);
+        assert_eq!(find(vec![s("3")]), vec![LuaValue::Int(3)]);
+        // no coercion between strings and numbers, and NaN is never found
+        assert_eq!(find(vec![LuaValue::Int(3)]), vec![LuaValue::Nil]);
+        assert_eq!(find(vec![LuaValue::Float(f64::NAN)]), vec![LuaValue::Nil]);
+        // the search starts at init
+        assert_eq!(find(vec![LuaValue::Int(1), LuaValue::Int(2)]), vec![LuaValue::Nil]);
+        assert_eq!(find(vec![LuaValue::Int(2), LuaValue::Int(2)]), vec![LuaValue::Int(2)]);
+    }
+}
@@ -94,6 +94,16 @@ pub fn get_tm(table: &LuaTable, event: TMS) -> Option<LuaValue> {
     table.get_metatable().and_then(|mt| mt.get(&LuaValue::Str(event.name().to_string())))
 }
 
+/// Field `field` of the metatable of any value, if set (luaL_getmetafield)
+pub fn get_metafield(val: &LuaValue, field: &str) -> Option<LuaValue> {
+    val.get_metatable().and_then(|mt| mt.get(&LuaValue::Str(field.to_string())))
+}
+
+/// Metamethod `event` of any value (the field of its metatable), if set
+pub fn get_value_tm(val: &LuaValue, event: TMS) -> Option<LuaValue> {
+    get_metafield(val, event.name())
+}
+
 /// Fast path: check if metatable is missing the metamethod (using flags)
 pub fn has_no_tm(table: &LuaTable, event: TMS) -> bool {
     // In a real implementation, use table flags for fast path
@@ -143,16 +153,38 @@ pub fn obj_typename(val: &LuaValue) -> &'static str {
 use std::collections::HashMap;
 use std::sync::RwLock;
 
+// Extension metamethods: names outside ORDER TM that core operations look
+// up by name, through get_extension_tm. They start out registered, and an
+// operation only consults a name while it is registered, so
+// unregister_metamethod turns its hook off. Lua code registers its own
+// names with skyla.metamethod.register; those are for libraries to
+// consult, no core operation knows them. The operations that respect
+// each one:
+//   __serialize  skyla.json.encode: a table whose metatable has it is
+//                encoded as the value __serialize(t) returns
+//   __iter       generic for: `for k, v in t do` over a table with __iter
+//                iterates with the function, state and initial control
+//                value __iter(t) returns (luaV_forprep)
+
+/// Serialization hook (skyla.json)
+pub const TM_SERIALIZE: &str = "__serialize";
+/// Generic-for hook
+pub const TM_ITER: &str = "__iter";
+
+/// Extension metamethods known to the core, registered at startup
+pub const EXTENSION_METAMETHODS: [&str; 2] = [TM_SERIALIZE, TM_ITER];
+
 lazy_static::lazy_static! {
-    static ref DYNAMIC_METAMETHODS: RwLock<HashMap<String, usize>> = RwLock::new(HashMap::new());
+    static ref DYNAMIC_METAMETHODS: RwLock<HashMap<String, usize>> = RwLock::new(
+        EXTENSION_METAMETHODS.iter().enumerate().map(|(i, name)| (name.to_string(), TMS::COUNT + i)).collect());
 }
 
-/// Register a new (custom) metamethod name, returning its dynamic index
+/// Register a new (custom) metamethod name, returning its dynamic index;
+/// registering a name again returns the index it already has
 pub fn register_metamethod(name: &str) -> usize {
     let mut reg = DYNAMIC_METAMETHODS.write().unwrap();
     let idx = reg.len() + TMS::COUNT;
-    reg.entry(name.to_string()).or_insert(idx);
-    idx
+    *reg.entry(name.to_string()).or_insert(idx)
 }
 
 /// Lookup a dynamic metamethod index by name
@@ -165,6 +197,12 @@ pub fn get_any_tm(table: &LuaTable, name: &str) -> Option<LuaValue> {
     table.get_metatable().and_then(|mt| mt.get(&LuaValue::Str(name.to_string())))
 }
 
+/// The extension metamethod `name` of `val`, if `name` is registered
+pub fn get_extension_tm(val: &LuaValue, name: &str) -> Option<LuaValue> {
+    get_dynamic_metamethod_index(name)?;
+    get_metafield(val, name)
+}
+
 /// Call any metamethod (static or dynamic)
 pub fn call_any_tm(state: &mut LuaState, f: &LuaValue, args: &[LuaValue]) -> Option<LuaValue> {
     // In a real implementation, push args and call function in VM
@@ -173,22 +211,15 @@ pub fn call_any_tm(state: &mut LuaState, f: &LuaValue, args: &[LuaValue]) -> Opt
     None
 }
 
-/// VM integration: call a metamethod as a Lua function in the VM
+/// VM integration: call a metamethod with `args` (LuaState::call) and
+/// return its first result. An error in it propagates like any other.
 pub fn call_tm_vm(state: &mut LuaState, f: &LuaValue, args: &[LuaValue]) -> Option<LuaValue> {
-    // Example: push function and args, call in VM, pop result
-    // This assumes LuaState has push, call_function, and pop methods
     state.push(f.clone());
     for arg in args {
         state.push(arg.clone());
     }
-    // Call function with n arguments, expecting 1 result
-    let nargs = args.len();
-    let ok = state.call_function(nargs, 1); // returns true if call succeeded
-    if ok {
-        state.pop(1) // pop and return result
-    } else {
-        None
-    }
+    state.call(args.len(), 1);
+    state.pop()
 }
 
 /// VM integration: try a binary metamethod and return result (or fallback)
@@ -262,3 +293,62 @@ pub fn print_dynamic_metamethods() {
     }
 }
 
+// skyla.metamethod.register(name) -> index
+fn mm_lua_register(state: &mut LuaState) -> i32 {
+    let name = state.check_string(1);
+    if !name.starts_with("__") {
+        state.arg_error(1, "metamethod names start with '__'");
+    }
+    state.push(LuaValue::Int(register_metamethod(&name) as i64));
+    1
+}
+
+// skyla.metamethod.unregister(name) -> boolean
+fn mm_lua_unregister(state: &mut LuaState) -> i32 {
+    let name = state.check_string(1);
+    state.push(LuaValue::Bool(unregister_metamethod(&name)));
+    1
+}
+
+// skyla.metamethod.list() -> {name, ...} (sorted)
+fn mm_lua_list(state: &mut LuaState) -> i32 {
+    let mut names = list_dynamic_metamethods();
+    names.sort();
+    let mut t = crate::ltable::Table::new();
+    for (i, name) in names.into_iter().enumerate() {
+        t.rawset(&LuaValue::Int(i as i64 + 1), LuaValue::Str(name));
+    }
+    state.push(LuaValue::Table(std::rc::Rc::new(std::cell::RefCell::new(t))));
+    1
+}
+
+const METAMETHOD_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("register", mm_lua_register),
+    ("unregister", mm_lua_unregister),
+    ("list", mm_lua_list),
+];
+
+/// Register the `skyla.metamethod` module
+pub fn open_metamethod_lib(state: &mut LuaState) {
+    for &(name, f) in METAMETHOD_FUNCS {
+        state.register_lib_function("skyla.metamethod", name, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension_metamethods_are_registered() {
+        let iter = get_dynamic_metamethod_index(TM_ITER).unwrap();
+        assert!(get_dynamic_metamethod_index(TM_SERIALIZE).unwrap() >= TMS::COUNT);
+        // registering an existing name keeps its index
+        assert_eq!(register_metamethod(TM_ITER), iter);
+        let custom = register_metamethod("__test_custom");
+        assert_eq!(register_metamethod("__test_custom"), custom);
+        assert!(list_dynamic_metamethods().contains(&"__test_custom".to_string()));
+        assert!(unregister_metamethod("__test_custom"));
+        assert_eq!(get_dynamic_metamethod_index("__test_custom"), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! ltrace.rs - Structured VM event trace (opcodes, calls, GC phases)
+//
+// Enabled at runtime with SKYLA_TRACE=calls|ops|gc (any combination, or
+// "all"). Events go to stderr by default, to a file when SKYLA_TRACE_FILE is
+// set, or to a Rust callback installed with `set_trace_sink`. The hooks in
+// luaV_execute and luaC_step go through the `skyla_trace!` macro, which
+// compiles to nothing unless the crate is built with `--features trace`.
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use crate::skylaconf::{ENV_TRACE, ENV_TRACE_FILE};
+
+/// Trace categories (bit flags)
+pub const TRACE_CALLS: u8 = 1;
+pub const TRACE_OPS: u8 = 2;
+pub const TRACE_GC: u8 = 4;
+pub const TRACE_ALL: u8 = TRACE_CALLS | TRACE_OPS | TRACE_GC;
+
+/// One trace event
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// An instruction about to execute: pc (instruction index), source line
+    /// (0 if unknown) and opcode name
+    Op { pc: usize, line: u32, opcode: String },
+    /// Entry into a function
+    Call { func: String },
+    /// Exit from a function with `nresults` values
+    Return { func: String, nresults: usize },
+    /// GC state machine transition
+    GcPhase { from: &'static str, to: &'static str },
+}
+
+impl TraceEvent {
+    /// The category this event belongs to
+    pub fn kind(&self) -> u8 {
+        match self {
+            TraceEvent::Op { .. } => TRACE_OPS,
+            TraceEvent::Call { .. } | TraceEvent::Return { .. } => TRACE_CALLS,
+            TraceEvent::GcPhase { .. } => TRACE_GC,
+        }
+    }
+}
+
+impl std::fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceEvent::Op { pc, line, opcode } => write!(f, "op pc={} line={} {}", pc, line, opcode),
+            TraceEvent::Call { func } => write!(f, "call {}", func),
+            TraceEvent::Return { func, nresults } => write!(f, "return {} nresults={}", func, nresults),
+            TraceEvent::GcPhase { from, to } => write!(f, "gc {} -> {}", from, to),
+        }
+    }
+}
+
+/// Where trace events are written
+pub enum TraceSink {
+    Stderr,
+    File(Mutex<File>),
+    Callback(Box<dyn Fn(&TraceEvent) + Send + Sync>),
+}
+
+static TRACE_MASK: AtomicU8 = AtomicU8::new(0);
+
+/// Held by tests that change the process-wide mask or sink
+#[cfg(test)]
+pub(crate) static TRACE_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+lazy_static::lazy_static! {
+    static ref TRACE_SINK: RwLock<TraceSink> = RwLock::new(TraceSink::Stderr);
+}
+
+/// Parse a SKYLA_TRACE value ("calls|ops|gc", "all"; ',' also separates)
+pub fn parse_trace_mask(spec: &str) -> u8 {
+    spec.split(|c| c == '|' || c == ',')
+        .map(|s| match s.trim().to_ascii_lowercase().as_str() {
+            "calls" => TRACE_CALLS,
+            "ops" => TRACE_OPS,
+            "gc" => TRACE_GC,
+            "all" => TRACE_ALL,
+            _ => 0,
+        })
+        .fold(0, |m, k| m | k)
+}
+
+/// Configure tracing from SKYLA_TRACE / SKYLA_TRACE_FILE
+pub fn init_trace_from_env() {
+    let mask = std::env::var(ENV_TRACE).map(|s| parse_trace_mask(&s)).unwrap_or(0);
+    if let Ok(path) = std::env::var(ENV_TRACE_FILE) {
+        match File::create(&path) {
+            Ok(f) => set_trace_sink(TraceSink::File(Mutex::new(f))),
+            Err(e) => eprintln!("[ltrace] cannot open trace file '{}': {}", path, e),
+        }
+    }
+    set_trace_mask(mask);
+}
+
+/// Set the enabled trace categories (0 disables tracing)
+pub fn set_trace_mask(mask: u8) {
+    TRACE_MASK.store(mask, Ordering::Relaxed);
+}
+
+/// Currently enabled trace categories
+pub fn trace_mask() -> u8 {
+    TRACE_MASK.load(Ordering::Relaxed)
+}
+
+/// Replace the trace sink (stderr, file or Rust callback)
+pub fn set_trace_sink(sink: TraceSink) {
+    *TRACE_SINK.write().unwrap() = sink;
+}
+
+/// Install a new sink and return the previous one (used by the profiler
+/// to take over the hooks temporarily)
+pub fn replace_trace_sink(sink: TraceSink) -> TraceSink {
+    std::mem::replace(&mut *TRACE_SINK.write().unwrap(), sink)
+}
+
+/// Is the given category enabled? (cheap check used before building events)
+#[inline(always)]
+pub fn trace_enabled(kind: u8) -> bool {
+    TRACE_MASK.load(Ordering::Relaxed) & kind != 0
+}
+
+/// Emit an event to the current sink if its category is enabled
+pub fn trace_emit(ev: TraceEvent) {
+    if !trace_enabled(ev.kind()) {
+        return;
+    }
+    match &*TRACE_SINK.read().unwrap() {
+        TraceSink::Stderr => eprintln!("[trace] {}", ev),
+        TraceSink::File(f) => {
+            let _ = writeln!(f.lock().unwrap(), "{}", ev);
+        }
+        TraceSink::Callback(cb) => cb(&ev),
+    }
+}
+
+/// Trace hook used by the VM and GC. Expands to nothing without the
+/// `trace` feature; otherwise the event is only built when its category is on.
+#[macro_export]
+macro_rules! skyla_trace {
+    ($kind:expr, $ev:expr) => {{
+        #[cfg(feature = "trace")]
+        {
+            if $crate::ltrace::trace_enabled($kind) {
+                $crate::ltrace::trace_emit($ev);
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_trace_mask() {
+        assert_eq!(parse_trace_mask("calls"), TRACE_CALLS);
+        assert_eq!(parse_trace_mask("calls|ops"), TRACE_CALLS | TRACE_OPS);
+        assert_eq!(parse_trace_mask("gc, ops"), TRACE_GC | TRACE_OPS);
+        assert_eq!(parse_trace_mask("all"), TRACE_ALL);
+        assert_eq!(parse_trace_mask("bogus"), 0);
+    }
+
+    #[test]
+    fn test_trace_callback_sink_filters_by_kind() {
+        let _guard = TRACE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        // VM tests on other threads may emit while the mask is on
+        let me = std::thread::current().id();
+        set_trace_sink(TraceSink::Callback(Box::new(move |ev| {
+            if std::thread::current().id() == me {
+                seen2.lock().unwrap().push(ev.clone());
+            }
+        })));
+        set_trace_mask(TRACE_GC);
+        trace_emit(TraceEvent::Op { pc: 0, line: 1, opcode: "MOVE".to_string() });
+        trace_emit(TraceEvent::GcPhase { from: "pause", to: "propagate" });
+        set_trace_mask(0);
+        set_trace_sink(TraceSink::Stderr);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0], TraceEvent::GcPhase { from: "pause", to: "propagate" });
+    }
+}
@@ -0,0 +1,204 @@
+//! luserdata.rs - Rust values as Lua objects with typed methods
+//
+// A type implementing `UserData` lists its methods once, as closures that
+// receive the object's `&T` (add_method) or `&mut T` (add_method_mut);
+// `LuaState::create_userdata` then wraps a value. The object is a table,
+// like io files: its fields are the methods, and a hidden `__userdata`
+// field owns the value, which is dropped with the table. A method checks
+// that it was called on such an object (`obj:method(...)`), raising
+// "bad argument #1 (T expected, got ...)" otherwise, and reads its other
+// arguments from 2 on. Metamethods (add_meta_method) and `__name` go into
+// the object's metatable.
+//
+// A `&mut T` method holds the value borrowed while it runs: if it calls
+// back into Lua and that code uses the same object, the borrow fails and
+// becomes a Lua error instead of aliasing.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::lobject::LuaValue;
+use crate::lstate::{LuaState, ObjectId};
+use crate::ltable::Table;
+
+/// A Rust type usable as a Lua object
+pub trait UserData: Sized + 'static {
+    /// Type name in error messages and the metatable's __name
+    const NAME: &'static str;
+
+    fn add_methods(methods: &mut UserDataMethods<Self>);
+}
+
+enum Method<T> {
+    Ref(Rc<dyn Fn(&mut LuaState, &T) -> i32>),
+    Mut(Rc<dyn Fn(&mut LuaState, &mut T) -> i32>),
+}
+
+impl<T> Clone for Method<T> {
+    fn clone(&self) -> Self {
+        match self {
+            Method::Ref(f) => Method::Ref(f.clone()),
+            Method::Mut(f) => Method::Mut(f.clone()),
+        }
+    }
+}
+
+/// The methods and metamethods of a UserData type
+pub struct UserDataMethods<T> {
+    methods: Vec<(String, Method<T>)>,
+    meta: Vec<(String, Method<T>)>,
+}
+
+impl<T: UserData> UserDataMethods<T> {
+    pub fn add_method(&mut self, name: &str, f: impl Fn(&mut LuaState, &T) -> i32 + 'static) {
+        self.methods.push((name.to_string(), Method::Ref(Rc::new(f))));
+    }
+
+    pub fn add_method_mut(&mut self, name: &str, f: impl Fn(&mut LuaState, &mut T) -> i32 + 'static) {
+        self.methods.push((name.to_string(), Method::Mut(Rc::new(f))));
+    }
+
+    /// Metamethod `event` ("__tostring", "__len", ...)
+    pub fn add_meta_method(&mut self, event: &str, f: impl Fn(&mut LuaState, &T) -> i32 + 'static) {
+        self.meta.push((event.to_string(), Method::Ref(Rc::new(f))));
+    }
+
+    /// Metamethod that changes the value ("__gc", "__close", ...)
+    pub fn add_meta_method_mut(&mut self, event: &str, f: impl Fn(&mut LuaState, &mut T) -> i32 + 'static) {
+        self.meta.push((event.to_string(), Method::Mut(Rc::new(f))));
+    }
+}
+
+thread_local! {
+    // The value of every live object, by the identity of its table
+    static OBJECTS: RefCell<HashMap<ObjectId, Rc<dyn Any>>> = RefCell::new(HashMap::new());
+    // UserDataMethods of each type, built on first use
+    static METHODS: RefCell<HashMap<TypeId, Rc<dyn Any>>> = RefCell::new(HashMap::new());
+}
+
+/// Field of an object that owns its value
+const USERDATA_FIELD: &str = "__userdata";
+
+/// Removes the object's value from OBJECTS when the table drops it
+struct Owner(ObjectId);
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        // the table is being dropped, but other thread-locals may be gone
+        // already at thread exit
+        let _ = OBJECTS.try_with(|o| o.borrow_mut().remove(&self.0));
+    }
+}
+
+fn methods_of<T: UserData>() -> Rc<UserDataMethods<T>> {
+    let cached = METHODS.with(|m| m.borrow().get(&TypeId::of::<T>()).cloned());
+    if let Some(m) = cached {
+        return m.downcast().expect("cached under its TypeId");
+    }
+    let mut methods = UserDataMethods { methods: Vec::new(), meta: Vec::new() };
+    T::add_methods(&mut methods);
+    let methods = Rc::new(methods);
+    METHODS.with(|m| m.borrow_mut().insert(TypeId::of::<T>(), methods.clone()));
+    methods
+}
+
+/// The value of object `v`, if it is a T
+pub fn userdata_ref<T: UserData>(v: &LuaValue) -> Option<Rc<RefCell<T>>> {
+    let id = ObjectId::of(v)?;
+    let data = OBJECTS.with(|o| o.borrow().get(&id).cloned())?;
+    data.downcast().ok()
+}
+
+/// Call `m` on argument 1, which must be a T
+fn call_method<T: UserData>(state: &mut LuaState, m: &Method<T>) -> i32 {
+    let Some(data) = userdata_ref::<T>(&state.to_value(1)) else {
+        state.type_error(1, T::NAME);
+    };
+    match m {
+        Method::Ref(f) => f(state, &data.borrow()),
+        Method::Mut(f) => f(state, &mut data.borrow_mut()),
+    }
+}
+
+fn method_value<T: UserData>(m: Method<T>) -> LuaValue {
+    LuaValue::Function(Box::new(move |L: &mut LuaState| L.call_rust(|L| call_method(L, &m))))
+}
+
+impl LuaState {
+    /// A new Lua object holding `value`, with T's methods
+    pub fn create_userdata<T: UserData>(&mut self, value: T) -> LuaValue {
+        let methods = methods_of::<T>();
+        let t = Rc::new(RefCell::new(Table::new()));
+        let obj = LuaValue::Table(t.clone());
+        let id = ObjectId::of(&obj).expect("tables have an identity");
+        OBJECTS.with(|o| o.borrow_mut().insert(id, Rc::new(RefCell::new(value)) as Rc<dyn Any>));
+        let owner = Owner(id);
+        {
+            let mut t = t.borrow_mut();
+            t.rawset(&LuaValue::Str(USERDATA_FIELD.to_string()), LuaValue::Function(Box::new(move |_: &mut LuaState| {
+                let _ = &owner;
+                0
+            })));
+            for (name, m) in &methods.methods {
+                t.rawset(&LuaValue::Str(name.clone()), method_value(m.clone()));
+            }
+        }
+        let mut mt = Table::new();
+        mt.rawset(&LuaValue::Str("__name".to_string()), LuaValue::Str(T::NAME.to_string()));
+        for (event, m) in &methods.meta {
+            mt.rawset(&LuaValue::Str(event.clone()), method_value(m.clone()));
+        }
+        self.set_value_metatable(&obj, LuaValue::Table(Rc::new(RefCell::new(mt))));
+        obj
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lstate::GlobalState;
+
+    struct Counter(i64);
+
+    impl UserData for Counter {
+        const NAME: &'static str = "Counter";
+
+        fn add_methods(methods: &mut UserDataMethods<Self>) {
+            methods.add_method("get", |L, c| {
+                L.push(LuaValue::Int(c.0));
+                1
+            });
+            methods.add_method_mut("add", |L, c| {
+                c.0 += L.check_integer(2);
+                0
+            });
+        }
+    }
+
+    fn call(state: &mut LuaState, obj: &LuaValue, name: &str, args: Vec<LuaValue>) -> Vec<LuaValue> {
+        let LuaValue::Table(t) = obj else { panic!("not an object") };
+        let Some(LuaValue::Function(f)) = t.borrow().get(&LuaValue::Str(name.to_string())).cloned() else { panic!("no method") };
+        state.stack.truncate(0);
+        state.push(LuaValue::Nil); // the running function's slot
+        for a in args {
+            state.push(a);
+        }
+        let n = f(state) as usize;
+        state.stack.split_off(state.stack.len() - n)
+    }
+
+    #[test]
+    fn test_typed_methods() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let c = state.create_userdata(Counter(1));
+        call(&mut state, &c, "add", vec![c.clone(), LuaValue::Int(41)]);
+        assert_eq!(call(&mut state, &c, "get", vec![c.clone()]), vec![LuaValue::Int(42)]);
+        assert_eq!(userdata_ref::<Counter>(&c).unwrap().borrow().0, 42);
+        let id = ObjectId::of(&c).unwrap();
+        state.stack.clear();
+        drop(c);
+        assert!(OBJECTS.with(|o| !o.borrow().contains_key(&id)));
+    }
+}
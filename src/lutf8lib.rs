@@ -0,0 +1,293 @@
+//! lutf8lib.rs - Lua standard UTF-8 library for Rust-based Lua VM
+// Ported and adapted from lutf8lib.c
+/*
+** $Id: lutf8lib.c $
+** Standard library for UTF-8 manipulation
+** See Copyright Notice in lua.h
+*/
+// Positions are byte offsets, as in Lua. Lua strings are Rust strings here,
+// so they always hold valid UTF-8: the 'lax' flags are accepted and ignored,
+// and 'charpattern' is left out because its pattern bytes are not UTF-8.
+
+use crate::lobject::LuaValue;
+use crate::lstate::LuaState;
+
+const MSG_INVALID: &str = "invalid UTF-8 code";
+
+fn iscont(s: &[u8], i: usize) -> bool {
+    s.get(i).is_some_and(|&c| c & 0xC0 == 0x80)
+}
+
+/// Translate a relative string position: negative means back from end
+fn u_posrelat(pos: i64, len: usize) -> i64 {
+    if pos >= 0 {
+        pos
+    } else if pos.unsigned_abs() > len as u64 {
+        0
+    } else {
+        len as i64 + pos + 1
+    }
+}
+
+/// The character starting at byte `i`, with its length in bytes; None if
+/// `i` is not the start of a character
+fn utf8_decode(s: &str, i: usize) -> Option<(char, usize)> {
+    if !s.is_char_boundary(i) {
+        return None;
+    }
+    s[i..].chars().next().map(|c| (c, c.len_utf8()))
+}
+
+// utf8.len(s [, i [, j [, lax]]])
+// Number of characters that start between positions 'i' and 'j' (both
+// inclusive); fail plus the position of the first invalid byte otherwise
+fn utflen(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let len = s.len();
+    let mut posi = u_posrelat(state.opt_integer(2, 1), len);
+    let mut posj = u_posrelat(state.opt_integer(3, -1), len);
+    posi -= 1;
+    if !(0 <= posi && posi <= len as i64) {
+        state.arg_error(2, "initial position out of bounds");
+    }
+    posj -= 1;
+    if posj >= len as i64 {
+        state.arg_error(3, "final position out of bounds");
+    }
+    let mut n = 0;
+    while posi <= posj {
+        match utf8_decode(&s, posi as usize) {
+            Some((_, size)) => posi += size as i64,
+            None => {
+                state.push(LuaValue::Nil);
+                state.push(LuaValue::Int(posi + 1));
+                return 2;
+            }
+        }
+        n += 1;
+    }
+    state.push(LuaValue::Int(n));
+    1
+}
+
+// utf8.codepoint(s [, i [, j [, lax]]])
+// Returns the codepoints of all characters that start between byte
+// positions 'i' and 'j' (both inclusive)
+fn codepoint(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let len = s.len();
+    let posi = u_posrelat(state.opt_integer(2, 1), len);
+    let pose = u_posrelat(state.opt_integer(3, posi), len);
+    if posi < 1 {
+        state.arg_error(2, "out of bounds");
+    }
+    if pose > len as i64 {
+        state.arg_error(3, "out of bounds");
+    }
+    if posi > pose {
+        return 0; // empty interval; return no values
+    }
+    if pose - posi >= i32::MAX as i64 || !state.check_stack((pose - posi) as usize) {
+        state.error("string slice too long");
+    }
+    let mut n = 0;
+    let mut i = posi as usize - 1;
+    while i < pose as usize {
+        let Some((c, size)) = utf8_decode(&s, i) else { state.error(MSG_INVALID) };
+        state.push(LuaValue::Int(c as i64));
+        i += size;
+        n += 1;
+    }
+    n
+}
+
+// utf8.char(...)
+// Converts each argument to a character and returns their concatenation
+fn utfchar(state: &mut LuaState) -> i32 {
+    let n = state.get_top();
+    let mut out = String::new();
+    for arg in 1..=n {
+        let code = state.check_integer(arg);
+        match u32::try_from(code).ok().and_then(char::from_u32) {
+            Some(c) => out.push(c),
+            None => state.arg_error(arg, "value out of range"),
+        }
+    }
+    state.push(LuaValue::Str(out));
+    1
+}
+
+// utf8.offset(s, n [, i])
+// Byte position where the n-th character (counting from position 'i')
+// starts; n = 0 finds the start of the character containing byte 'i'
+fn byteoffset(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let b = s.as_bytes();
+    let len = b.len();
+    let mut n = state.check_integer(2);
+    let def = if n >= 0 { 1 } else { len as i64 + 1 };
+    let mut posi = u_posrelat(state.opt_integer(3, def), len);
+    posi -= 1;
+    if !(0 <= posi && posi <= len as i64) {
+        state.arg_error(3, "position out of bounds");
+    }
+    let mut posi = posi as usize;
+    if n == 0 {
+        // find beginning of current byte sequence
+        while posi > 0 && iscont(b, posi) {
+            posi -= 1;
+        }
+    } else {
+        if iscont(b, posi) {
+            state.error("initial position is a continuation byte");
+        }
+        if n < 0 {
+            while n < 0 && posi > 0 {
+                // move back: find beginning of previous character
+                posi -= 1;
+                while posi > 0 && iscont(b, posi) {
+                    posi -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            n -= 1; // do not move for 1st character
+            while n > 0 && posi < len {
+                // move forward: find beginning of next character
+                posi += 1;
+                while iscont(b, posi) {
+                    posi += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+    if n == 0 {
+        state.push(LuaValue::Int(posi as i64 + 1));
+    } else {
+        // did it not find the given character?
+        state.push(LuaValue::Nil);
+    }
+    1
+}
+
+fn iter_aux(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    let b = s.as_bytes();
+    let len = b.len() as u64;
+    // the control value is the position of the previous character
+    let mut n = state.check_integer(2) as u64;
+    if n < len {
+        while iscont(b, n as usize) {
+            n += 1; // go to next character
+        }
+    }
+    if n >= len {
+        return 0; // no more codepoints (also handles a negative 'n')
+    }
+    let Some((c, _)) = utf8_decode(&s, n as usize) else { state.error(MSG_INVALID) };
+    state.push(LuaValue::Int(n as i64 + 1));
+    state.push(LuaValue::Int(c as i64));
+    2
+}
+
+// utf8.codes(s [, lax])
+// Returns the iteration triple for 'for p, c in utf8.codes(s)'
+fn iter_codes(state: &mut LuaState) -> i32 {
+    let s = state.check_string(1);
+    if iscont(s.as_bytes(), 0) {
+        state.arg_error(1, MSG_INVALID);
+    }
+    state.push(LuaValue::Function(Box::new(|L: &mut LuaState| L.call_rust(iter_aux))));
+    state.push(LuaValue::Str(s));
+    state.push(LuaValue::Int(0));
+    3
+}
+
+const UTF8_FUNCS: &[(&str, fn(&mut LuaState) -> i32)] = &[
+    ("offset", byteoffset),
+    ("codepoint", codepoint),
+    ("char", utfchar),
+    ("len", utflen),
+    ("codes", iter_codes),
+];
+
+/// Register the utf8 library functions
+pub fn open_utf8_lib(state: &mut LuaState) {
+    let lib = state.lib_table(crate::skylalib::LUA_UTF8LIBNAME);
+    state.set_funcs(&lib, UTF8_FUNCS);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lauxlib::call_lib;
+    use crate::lerror::Error;
+    use crate::lstate::GlobalState;
+
+    fn s(x: &str) -> LuaValue {
+        LuaValue::Str(x.to_string())
+    }
+
+    fn fails_with(state: &mut LuaState, f: fn(&mut LuaState) -> i32, args: Vec<LuaValue>, msg: &str) -> bool {
+        let r = state.pcall(|L| call_lib(L, f, args));
+        matches!(r, Err(Error::Runtime(LuaValue::Str(ref m))) if m.contains(msg))
+    }
+
+    #[test]
+    fn test_char_and_codepoint() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let codes = vec![LuaValue::Int(72), LuaValue::Int(0xE9), LuaValue::Int(0x20AC), LuaValue::Int(0x1F600)];
+        let r = call_lib(&mut state, utfchar, codes.clone());
+        assert_eq!(r, vec![s("Hé€😀")]);
+        // byte positions: 'H' 1, 'é' 2-3, '€' 4-6, '😀' 7-10
+        assert_eq!(call_lib(&mut state, codepoint, vec![s("Hé€😀"), LuaValue::Int(1), LuaValue::Int(-1)]), codes);
+        assert_eq!(call_lib(&mut state, codepoint, vec![s("Hé€😀"), LuaValue::Int(4)]), vec![LuaValue::Int(0x20AC)]);
+        assert!(call_lib(&mut state, codepoint, vec![s("abc"), LuaValue::Int(3), LuaValue::Int(2)]).is_empty());
+        assert!(fails_with(&mut state, utfchar, vec![LuaValue::Int(0xD800)], "value out of range"));
+        assert!(fails_with(&mut state, utfchar, vec![LuaValue::Int(-1)], "value out of range"));
+        assert!(fails_with(&mut state, codepoint, vec![s("é"), LuaValue::Int(2)], MSG_INVALID));
+        assert!(fails_with(&mut state, codepoint, vec![s("abc"), LuaValue::Int(1), LuaValue::Int(4)], "out of bounds"));
+    }
+
+    #[test]
+    fn test_len_and_offset() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let text = s("Hé€😀");
+        assert_eq!(call_lib(&mut state, utflen, vec![text.clone()]), vec![LuaValue::Int(4)]);
+        assert_eq!(call_lib(&mut state, utflen, vec![text.clone(), LuaValue::Int(4)]), vec![LuaValue::Int(2)]);
+        // a continuation byte is reported with its position
+        assert_eq!(call_lib(&mut state, utflen, vec![text.clone(), LuaValue::Int(3)]), vec![LuaValue::Nil, LuaValue::Int(3)]);
+        assert!(fails_with(&mut state, utflen, vec![text.clone(), LuaValue::Int(12)], "initial position out of bounds"));
+        assert!(fails_with(&mut state, utflen, vec![text.clone(), LuaValue::Int(1), LuaValue::Int(11)], "final position out of bounds"));
+
+        let offset = |state: &mut LuaState, args: Vec<LuaValue>| call_lib(state, byteoffset, args);
+        assert_eq!(offset(&mut state, vec![text.clone(), LuaValue::Int(3)]), vec![LuaValue::Int(4)]);
+        assert_eq!(offset(&mut state, vec![text.clone(), LuaValue::Int(-1)]), vec![LuaValue::Int(7)]);
+        assert_eq!(offset(&mut state, vec![text.clone(), LuaValue::Int(5)]), vec![LuaValue::Int(11)]);
+        assert_eq!(offset(&mut state, vec![text.clone(), LuaValue::Int(6)]), vec![LuaValue::Nil]);
+        assert_eq!(offset(&mut state, vec![text.clone(), LuaValue::Int(0), LuaValue::Int(9)]), vec![LuaValue::Int(7)]);
+        assert!(fails_with(&mut state, byteoffset, vec![text.clone(), LuaValue::Int(1), LuaValue::Int(3)], "continuation byte"));
+        assert!(fails_with(&mut state, byteoffset, vec![text, LuaValue::Int(1), LuaValue::Int(12)], "position out of bounds"));
+    }
+
+    #[test]
+    fn test_codes_iterates_characters() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        let text = s("aé€");
+        let r = call_lib(&mut state, iter_codes, vec![text.clone()]);
+        assert_eq!(r[1..], [text.clone(), LuaValue::Int(0)]);
+        let mut pos = LuaValue::Int(0);
+        let mut seen = vec![];
+        loop {
+            let r = call_lib(&mut state, iter_aux, vec![text.clone(), pos]);
+            let [p, c] = &r[..] else { break };
+            seen.push((p.clone(), c.clone()));
+            pos = p.clone();
+        }
+        let expect = [(1, 0x61), (2, 0xE9), (4, 0x20AC)].map(|(p, c)| (LuaValue::Int(p), LuaValue::Int(c)));
+        assert_eq!(seen, expect);
+    }
+}
@@ -0,0 +1,403 @@
+//! lvfs.rs - Virtual file system for io, loadfile and package loading
+//
+// File access from Lua (io.open, loadfile, the package searchers) goes
+// through a `Vfs` installed per Lua state, so the host decides what a path
+// means: the real file system (`StdVfs`), a directory the scripts cannot
+// leave (`RootedVfs`), an in-memory map (`MemoryVfs`, also the base for
+// archives), or its own implementation. Without one the state uses
+// `PlatformVfs`, which reads through the host platform (lplatform).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::sync::{Arc, RwLock};
+
+use crate::lstate::LuaState;
+
+/// How a file is opened, from an io.open mode string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub truncate: bool,
+    pub create: bool,
+}
+
+impl OpenMode {
+    pub const READ: OpenMode = OpenMode { read: true, write: false, append: false, truncate: false, create: false };
+
+    /// Parse "r", "w", "a", "r+", "w+", "a+" with an optional trailing 'b'
+    /// (l_checkmode); None for anything else
+    pub fn parse(mode: &str) -> Option<OpenMode> {
+        let (base, plus) = match mode.strip_suffix('b').unwrap_or(mode) {
+            m if m.ends_with('+') => (&m[..m.len() - 1], true),
+            m => (m, false),
+        };
+        let m = match base {
+            "r" => OpenMode { read: true, write: plus, ..OpenMode::READ },
+            "w" => OpenMode { read: plus, write: true, append: false, truncate: true, create: true },
+            "a" => OpenMode { read: plus, write: true, append: true, truncate: false, create: true },
+            _ => return None,
+        };
+        Some(m)
+    }
+}
+
+/// An open file
+pub trait VfsFile: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> VfsFile for T {}
+
+/// What `Vfs::stat` reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VfsMetadata {
+    pub is_dir: bool,
+    pub len: u64,
+}
+
+/// File access for one Lua state. Paths are the strings scripts use.
+pub trait Vfs: Send + Sync {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>>;
+    fn stat(&self, path: &str) -> io::Result<VfsMetadata>;
+    /// Names of the entries of directory `path`
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+    /// Whole contents of a file (loadfile, require)
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path, OpenMode::READ)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+    /// Does `path` name a readable file? (package.searchpath)
+    fn exists(&self, path: &str) -> bool {
+        matches!(self.stat(path), Ok(m) if !m.is_dir)
+    }
+    /// Where `path` really leads once links are followed (RootedVfs checks
+    /// it against its root); file systems without links return it as is
+    fn real_path(&self, path: &str) -> io::Result<String> {
+        Ok(path.to_string())
+    }
+}
+
+fn unsupported(what: &str, path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("{}: cannot {} on this file system", path, what))
+}
+
+/// Reads through the host platform; writing and listing are unsupported
+pub struct PlatformVfs;
+
+impl Vfs for PlatformVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        if mode.write {
+            return Err(unsupported("write", path));
+        }
+        Ok(Box::new(Cursor::new(self.read(path)?)))
+    }
+    fn stat(&self, path: &str) -> io::Result<VfsMetadata> {
+        let len = self.read(path)?.len() as u64;
+        Ok(VfsMetadata { is_dir: false, len })
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        Err(unsupported("list", path))
+    }
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        crate::lplatform::with_platform(|p| p.read_file(path))
+    }
+    fn exists(&self, path: &str) -> bool {
+        crate::lplatform::with_platform(|p| p.file_exists(path))
+    }
+}
+
+/// The real file system
+#[cfg(not(feature = "minimal"))]
+pub struct StdVfs;
+
+#[cfg(not(feature = "minimal"))]
+impl Vfs for StdVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        let f = std::fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write && !mode.append)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create)
+            .open(path)?;
+        Ok(Box::new(f))
+    }
+    fn stat(&self, path: &str) -> io::Result<VfsMetadata> {
+        let m = std::fs::metadata(path)?;
+        Ok(VfsMetadata { is_dir: m.is_dir(), len: m.len() })
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        std::fs::read_dir(path)?
+            .map(|e| e.map(|e| e.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+    fn real_path(&self, path: &str) -> io::Result<String> {
+        Ok(std::fs::canonicalize(path)?.to_string_lossy().into_owned())
+    }
+}
+
+/// Resolve `path` inside a root: absolute paths are taken relative to the
+/// root, and ".." may not climb above it. None if the path escapes.
+pub fn confine_path(path: &str) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            p => parts.push(p),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Another file system limited to the directory tree under `root`. Paths
+/// are confined lexically first, then by where they really lead, so a
+/// link inside the root cannot point out of it.
+pub struct RootedVfs<V: Vfs> {
+    inner: V,
+    root: String,
+}
+
+impl<V: Vfs> RootedVfs<V> {
+    pub fn new(inner: V, root: &str) -> Self {
+        RootedVfs { inner, root: root.trim_end_matches('/').to_string() }
+    }
+
+    fn resolve(&self, path: &str) -> io::Result<String> {
+        let outside = || io::Error::new(io::ErrorKind::PermissionDenied, format!("{}: outside of the sandbox", path));
+        let full = match confine_path(path).ok_or_else(outside)? {
+            rel if rel.is_empty() => self.root.clone(),
+            rel => format!("{}/{}", self.root, rel),
+        };
+        let root = self.inner.real_path(if self.root.is_empty() { "/" } else { &self.root })?;
+        // a file about to be created does not exist yet: its directory
+        // tells where it would go
+        let real = match self.inner.real_path(&full) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match full.rsplit_once('/') {
+                Some((dir, name)) => format!("{}/{}", self.inner.real_path(if dir.is_empty() { "/" } else { dir })?, name),
+                None => return Err(e),
+            },
+            real => real?,
+        };
+        if !std::path::Path::new(&real).starts_with(&root) {
+            return Err(outside());
+        }
+        Ok(full)
+    }
+}
+
+impl<V: Vfs> Vfs for RootedVfs<V> {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        self.inner.open(&self.resolve(path)?, mode)
+    }
+    fn stat(&self, path: &str) -> io::Result<VfsMetadata> {
+        self.inner.stat(&self.resolve(path)?)
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        self.inner.list(&self.resolve(path)?)
+    }
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.inner.read(&self.resolve(path)?)
+    }
+    fn exists(&self, path: &str) -> bool {
+        self.resolve(path).map(|p| self.inner.exists(&p)).unwrap_or(false)
+    }
+    fn real_path(&self, path: &str) -> io::Result<String> {
+        self.inner.real_path(&self.resolve(path)?)
+    }
+}
+
+/// Files held in memory (bundled scripts, archives unpacked by the host,
+/// tests). Paths are normalized with `confine_path`; directories are
+/// implied by the file names. Files opened for writing are stored back
+/// when dropped.
+#[derive(Default)]
+pub struct MemoryVfs {
+    files: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemoryVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, path: &str, contents: impl Into<Vec<u8>>) {
+        let key = confine_path(path).unwrap_or_default();
+        self.files.write().unwrap().insert(key, contents.into());
+    }
+
+    fn key(path: &str) -> io::Result<String> {
+        confine_path(path).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file", path)))
+    }
+}
+
+/// A MemoryVfs file open for writing
+struct MemoryFile {
+    data: Cursor<Vec<u8>>,
+    key: String,
+    files: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.files.write().unwrap().insert(self.key.clone(), self.data.get_ref().clone());
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> io::Result<Box<dyn VfsFile>> {
+        let key = Self::key(path)?;
+        let existing = self.files.read().unwrap().get(&key).cloned();
+        let contents = match existing {
+            Some(_) if mode.truncate => Vec::new(),
+            Some(data) => data,
+            None if mode.create => Vec::new(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: No such file or directory", path))),
+        };
+        if !mode.write {
+            return Ok(Box::new(Cursor::new(contents)));
+        }
+        let mut data = Cursor::new(contents);
+        if mode.append {
+            data.seek(io::SeekFrom::End(0))?;
+        }
+        Ok(Box::new(MemoryFile { data, key, files: self.files.clone() }))
+    }
+    fn stat(&self, path: &str) -> io::Result<VfsMetadata> {
+        let key = Self::key(path)?;
+        let files = self.files.read().unwrap();
+        if let Some(data) = files.get(&key) {
+            return Ok(VfsMetadata { is_dir: false, len: data.len() as u64 });
+        }
+        let prefix = format!("{}/", key);
+        if key.is_empty() || files.keys().any(|k| k.starts_with(&prefix)) {
+            return Ok(VfsMetadata { is_dir: true, len: 0 });
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: No such file or directory", path)))
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let key = Self::key(path)?;
+        let prefix = if key.is_empty() { key } else { format!("{}/", key) };
+        let mut names: Vec<String> = self.files.read().unwrap().keys()
+            .filter_map(|k| k.strip_prefix(&prefix))
+            .map(|rest| rest.split('/').next().unwrap().to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Shared handle to a state's Vfs
+#[derive(Clone)]
+pub struct VfsHandle(pub Arc<dyn Vfs>);
+
+impl fmt::Debug for VfsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VfsHandle")
+    }
+}
+
+/// The file system a state uses when the host installed none
+pub fn default_vfs() -> Arc<dyn Vfs> {
+    Arc::new(PlatformVfs)
+}
+
+impl LuaState {
+    /// Redirect this state's file access (shared by all its threads)
+    pub fn set_vfs(&mut self, vfs: Arc<dyn Vfs>) {
+        self.l_G.borrow_mut().vfs = Some(VfsHandle(vfs));
+    }
+
+    /// The file system io, loadfile and the package searchers use
+    pub fn vfs(&self) -> Arc<dyn Vfs> {
+        self.l_G.borrow().vfs.as_ref().map(|h| h.0.clone()).unwrap_or_else(default_vfs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_mode() {
+        assert_eq!(OpenMode::parse("r"), Some(OpenMode::READ));
+        let m = OpenMode::parse("a+b").unwrap();
+        assert!(m.read && m.write && m.append && !m.truncate);
+        assert_eq!(OpenMode::parse("rw"), None);
+        assert_eq!(OpenMode::parse("+"), None);
+    }
+
+    #[test]
+    fn test_confine_path() {
+        assert_eq!(confine_path("/lib/./a.lua").as_deref(), Some("lib/a.lua"));
+        assert_eq!(confine_path("lib/../b.lua").as_deref(), Some("b.lua"));
+        assert_eq!(confine_path("../etc/passwd"), None);
+    }
+
+    #[cfg(all(unix, not(feature = "minimal")))]
+    #[test]
+    fn test_rooted_vfs_follows_links() {
+        let base = std::env::temp_dir().join(format!("skyla_rooted_{}", std::process::id()));
+        let root = base.join("root");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(base.join("secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("script.lua"), "return 1").unwrap();
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("leak.txt")).unwrap();
+        std::os::unix::fs::symlink(&base, root.join("up")).unwrap();
+        let vfs = RootedVfs::new(StdVfs, root.to_str().unwrap());
+        assert_eq!(vfs.read("script.lua").unwrap(), b"return 1");
+        for path in ["leak.txt", "up/secret.txt", "up/new.txt", "../secret.txt"] {
+            assert_eq!(vfs.read(path).unwrap_err().kind(), io::ErrorKind::PermissionDenied, "{}", path);
+        }
+        assert!(vfs.open("up/new.txt", OpenMode::parse("w").unwrap()).is_err());
+        assert!(!base.join("new.txt").exists());
+        // new files can still be created inside the root
+        vfs.open("new.lua", OpenMode::parse("w").unwrap()).unwrap().write_all(b"x").unwrap();
+        assert_eq!(std::fs::read(root.join("new.lua")).unwrap(), b"x");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_memory_vfs() {
+        let vfs = MemoryVfs::new();
+        vfs.insert("app/init.lua", "return {}");
+        vfs.insert("app/util.lua", "return 1");
+        assert!(vfs.exists("./app/init.lua"));
+        assert!(vfs.stat("app").unwrap().is_dir);
+        assert_eq!(vfs.list("app").unwrap(), vec!["init.lua", "util.lua"]);
+        {
+            let mut f = vfs.open("app/util.lua", OpenMode::parse("a").unwrap()).unwrap();
+            f.write_all(b" + 1").unwrap();
+        }
+        assert_eq!(vfs.read("app/util.lua").unwrap(), b"return 1 + 1");
+        assert!(vfs.open("missing.lua", OpenMode::READ).is_err());
+    }
+}
@@ -4,137 +4,1264 @@
 //! Adapted and translated from Lua 5.4 `lvm.c`.
 
 use std::os::raw::c_int;
-use crate::lobject::{lua_State, TValue, lua_Number};
+use std::ptr::NonNull;
+use crate::lobject::{lua_State, TValue, lua_Number, luaO_decodesize};
 use crate::lopcodes::{Instruction, OpCode, GETARG_A, GETARG_B, GETARG_C, GETARG_Bx, GETARG_sBx};
 use crate::lapi::{lua_pushnumber, lua_pushnil, lua_pop};
 use crate::lfunc::{Proto, Closure};
+#[cfg(feature = "trace")]
+use crate::ltrace::{TraceEvent, TRACE_CALLS, TRACE_OPS};
+use crate::ldebug::{luaG_callhook, luaG_hookmask, luaG_rethook, luaG_traceexec, DebugFrame, DebugValue, LUA_MASKLINE};
+use crate::skyla_trace;
+use crate::skyla_coverage;
+use crate::lobject::LuaValue;
+use crate::lstate::{raw_equal, LuaState, ObjectId};
+use crate::ltm::{call_tm_vm, get_any_tm, get_dynamic_metamethod_index, get_extension_tm, get_value_tm, has_any_tm, obj_typename, try_bin_tm_vm, TMS, TM_ITER};
+use crate::ltable::{Table, TableSlot};
+use crate::lstring::TString;
+use crate::lplugin::Intrinsic;
+
+/// An instruction with its operands unpacked once per prototype, so the
+/// interpreter loop does not re-decode fields it may not even use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub op: OpCode,
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
+    /// B and C index the constants (the BITRK bit, stripped from `b`/`c`)
+    pub kb: bool,
+    pub kc: bool,
+    pub bx: u32,
+    pub sbx: i32,
+}
+
+impl Decoded {
+    /// Decode `i`, or return its opcode byte if it names no known opcode
+    pub fn new(i: Instruction) -> Result<Decoded, u8> {
+        let op = OpCode::try_from_u8(i.get_opcode()).ok_or(i.get_opcode())?;
+        let (rkb, rkc) = op.rk_operands();
+        let (b, c) = (i.get_arg_b() as usize, i.get_arg_c() as usize);
+        let (kb, kc) = (rkb && b & BITRK != 0, rkc && c & BITRK != 0);
+        Ok(Decoded {
+            op,
+            a: i.get_arg_a() as usize,
+            b: if kb { b & !BITRK } else { b },
+            c: if kc { c & !BITRK } else { c },
+            kb,
+            kc,
+            bx: i.get_arg_bx(),
+            sbx: i.get_arg_sbx(),
+        })
+    }
+}
+
+/// Fill `p.decoded` from `p.code`. Unknown opcodes are reported here, once
+/// per prototype, instead of inside the dispatch loop.
+pub fn luaV_predecode(p: &mut Proto) -> Result<(), String> {
+    p.decoded = p.code.iter()
+        .enumerate()
+        .map(|(pc, &i)| Decoded::new(i).map_err(|op| format!("invalid opcode {} at pc {}", op, pc)))
+        .collect::<Result<_, _>>()?;
+    p.slot_cache = vec![SlotCache::EMPTY; p.code.len()];
+    Ok(())
+}
+
+/// Inline cache of a GETTABLE/SETTABLE with a constant key: the slot the
+/// key was found at, valid while the table is at `version`. Versions are
+/// unique across tables, so a hit also means it is the same table.
+#[derive(Clone, Copy, Debug)]
+pub struct SlotCache {
+    pub version: u64,
+    pub slot: Option<TableSlot>,
+}
+
+impl SlotCache {
+    pub const EMPTY: SlotCache = SlotCache { version: 0, slot: None };
+}
+
+/// Registers of the running frame, shared by the opcode handlers.
+/// `pc` and `dpc` advance in lockstep over `code` and `decoded`.
+pub struct Frame {
+    pub L: *mut lua_State,
+    pub ci: *mut CallInfo,
+    pub cl: *mut Closure,
+    pub base: *mut TValue,
+    pub pc: *const Instruction,
+    pub dpc: *const Decoded,
+    /// Traversals of the generic-for loops running `next` directly, by the
+    /// register of their iterator
+    pub iters: Vec<ForIter>,
+}
+
+/// State of a `for k, v in next, t` loop on the TFORCALL fast path
+pub struct ForIter {
+    pub a: usize,
+    pub table: *const crate::ltable::Table,
+    pub cursor: crate::ltable::TableCursor,
+}
+
+impl Frame {
+    #[inline(always)]
+    unsafe fn jump(&mut self, n: isize) {
+        self.pc = self.pc.offset(n);
+        self.dpc = self.dpc.offset(n);
+    }
+
+    #[inline(always)]
+    unsafe fn reg(&self, x: usize) -> *mut TValue {
+        self.base.add(x)
+    }
+
+    /// Inline cache of the instruction being run when its key operand
+    /// is a constant (`k`), else null
+    #[inline(always)]
+    unsafe fn slot_cache(&self, k: bool) -> *mut SlotCache {
+        if !k {
+            return ptr::null_mut();
+        }
+        let p = (*self.cl).cl.p;
+        let pcidx = self.dpc.offset_from((*p).decoded.as_ptr()) as usize - 1;
+        (*p).slot_cache.as_mut_ptr().add(pcidx)
+    }
+
+    /// Record the pc in the CallInfo (savepc), so the frame's current line
+    /// is known while it calls out or runs a hook; the API frame running
+    /// the closure gets it too, with its registers, for lua_getinfo and
+    /// lua_getlocal
+    #[inline(always)]
+    unsafe fn savepc(&self) {
+        (*self.ci).u.l.savedpc = self.pc;
+        let p = (*self.cl).cl.p;
+        let mut ci = api_state(self.L).ci.borrow_mut();
+        ci.savedpc = self.pc.offset_from((*p).code.as_ptr()) as usize;
+        ci.base = NonNull::new(self.base);
+    }
+}
+
+/// What the loop does after a handler runs
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Next,
+    Return,
+}
+
+/// An opcode handler; entries of DISPATCH_TABLE are indexed by opcode
+pub type OpHandler = unsafe fn(&mut Frame, &Decoded) -> Step;
 
 /// The Lua VM main interpreter loop.
 /// Executes bytecode instructions in `ci->func->p->code`.
 pub unsafe fn luaV_execute(L: *mut lua_State) {
-    let mut ci = (*L).ci;         // Call info for current function
-    let mut cl = (*ci).func;      // Closure being executed
-    let mut k: *const TValue;
-    let mut base = (*ci).func.offset(1); // Base register of function stack frame
-    let mut pc = (*ci).u.l.savedpc;
-
-    // Shortcut references
-    let mut instructions = (*(*cl).cl.p).code.as_ptr();
+    let ci = (*L).ci;             // Call info for current function
+    let cl = (*(*ci).func).value.p as *mut Closure; // Closure being executed
+    let p = (*cl).cl.p;
+    if (*p).decoded.len() != (*p).code.len() {
+        if let Err(msg) = luaV_predecode(&mut *p) {
+            api_state(L).throw(LuaValue::Str(msg));
+        }
+    }
+    let pc = (*ci).u.l.savedpc;
+    let mut f = Frame {
+        L,
+        ci,
+        cl,
+        base: (*ci).base, // Base register of function stack frame (above varargs)
+        pc,
+        dpc: (*p).decoded.as_ptr().offset(pc.offset_from((*p).code.as_ptr())),
+        iters: Vec::new(),
+    };
 
-    // Main fetch-decode-execute loop
+    // Main fetch-dispatch loop
     loop {
-        let instruction = *pc;
-        pc = pc.offset(1);
+        let i = *f.dpc;
+        f.jump(1);
+
+        skyla_trace!(TRACE_OPS, {
+            let pcidx = f.pc.offset_from((*p).code.as_ptr()) as usize - 1;
+            TraceEvent::Op {
+                pc: pcidx,
+                line: pcline(p, f.pc),
+                opcode: format!("{:?}", i.op),
+            }
+        });
+
+        skyla_coverage!(
+            p as usize,
+            (*p).source.as_str(),
+            (*p).lineinfo.iter(),
+            pcline(p, f.pc)
+        );
+
+        if luaG_hookmask() & LUA_MASKLINE != 0 {
+            f.savepc();
+            luaG_traceexec(api_state(f.L), p as usize, pcline(p, f.pc), || debug_frame(cl, f.base, f.pc));
+        }
+
+        if dispatch(&mut f, &i) == Step::Return {
+            return; // Return from this function frame
+        }
+    }
+}
+
+/// Dense match over the opcode; every arm is a direct call the compiler
+/// can inline, which lowers to a single jump table.
+#[cfg(not(feature = "jumptable_dispatch"))]
+#[inline(always)]
+unsafe fn dispatch(f: &mut Frame, i: &Decoded) -> Step {
+    match i.op {
+        OpCode::MOVE => op_move(f, i),
+        OpCode::LOADK => op_loadk(f, i),
+        OpCode::LOADBOOL => op_loadbool(f, i),
+        OpCode::LOADNIL => op_loadnil(f, i),
+        OpCode::GETUPVAL => op_getupval(f, i),
+        OpCode::GETGLOBAL => op_getglobal(f, i),
+        OpCode::SETGLOBAL => op_setglobal(f, i),
+        OpCode::CALL => op_call(f, i),
+        OpCode::RETURN => op_return(f, i),
+        OpCode::SETTABLE => op_settable(f, i),
+        OpCode::VARARG => op_vararg(f, i),
+        OpCode::ADD | OpCode::SUB | OpCode::MUL | OpCode::DIV | OpCode::MOD | OpCode::POW => op_arith(f, i),
+        OpCode::UNM => op_unm(f, i),
+        OpCode::JMP => op_jmp(f, i),
+        OpCode::EQ | OpCode::LT | OpCode::LE => op_compare(f, i),
+        OpCode::TFORCALL => op_tforcall(f, i),
+        OpCode::TFORLOOP => op_tforloop(f, i),
+        OpCode::GETTABLE => op_gettable(f, i),
+        OpCode::CALLI => op_calli(f, i),
+        OpCode::NEWTABLE => op_newtable(f, i),
+        OpCode::SETLIST => op_setlist(f, i),
+        OpCode::SELF => op_self(f, i),
+    }
+}
+
+/// Indirect call through DISPATCH_TABLE (feature `jumptable_dispatch`),
+/// for comparing against the match with the interpreter benchmarks.
+#[cfg(feature = "jumptable_dispatch")]
+#[inline(always)]
+unsafe fn dispatch(f: &mut Frame, i: &Decoded) -> Step {
+    DISPATCH_TABLE[i.op as usize](f, i)
+}
+
+/// Opcode handlers in opcode order
+pub static DISPATCH_TABLE: [OpHandler; NUM_OPCODES] = [
+    op_move,
+    op_loadk,
+    op_loadbool,
+    op_loadnil,
+    op_getupval,
+    op_getglobal,
+    op_setglobal,
+    op_call,
+    op_return,
+    op_settable,
+    op_vararg,
+    op_arith, // ADD
+    op_arith, // SUB
+    op_arith, // MUL
+    op_arith, // DIV
+    op_arith, // MOD
+    op_arith, // POW
+    op_unm,
+    op_jmp,
+    op_compare, // EQ
+    op_compare, // LT
+    op_compare, // LE
+    op_tforcall,
+    op_tforloop,
+    op_gettable,
+    op_calli,
+    op_newtable,
+    op_setlist,
+    op_self,
+];
+
+unsafe fn op_move(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := R(B)
+    *f.reg(i.a) = *f.reg(i.b);
+    Step::Next
+}
+
+unsafe fn op_loadk(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := Kst(Bx)
+    *f.reg(i.a) = *(*(*f.cl).cl.p).k.as_ptr().add(i.bx as usize);
+    Step::Next
+}
+
+unsafe fn op_loadbool(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := (Bool)B; if C != 0 skip next instruction
+    *f.reg(i.a) = TValue::from_bool(i.b != 0);
+    if i.c != 0 {
+        f.jump(1);
+    }
+    Step::Next
+}
+
+unsafe fn op_loadnil(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) to R(A+B) := nil
+    for r in i.a..=i.a + i.b {
+        *f.reg(r) = TValue::nil();
+    }
+    Step::Next
+}
+
+unsafe fn op_getupval(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := UpValue[B]
+    let upval = (*f.cl).upvals[i.b].as_ref();
+    *f.reg(i.a) = *upval.val();
+    Step::Next
+}
+
+unsafe fn op_getglobal(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := Gbl[Kst(Bx)]
+    let kname = (*(*f.cl).cl.p).k[i.bx as usize].to_string();
+    *f.reg(i.a) = luaH_get(f.L, &(*f.L).l_env, &kname);
+    Step::Next
+}
+
+unsafe fn op_setglobal(f: &mut Frame, i: &Decoded) -> Step {
+    // Gbl[Kst(Bx)] := R(A)
+    let kname = (*(*f.cl).cl.p).k[i.bx as usize].to_string();
+    luaH_set(f.L, &mut (*f.L).l_env, &kname, f.reg(i.a));
+    Step::Next
+}
+
+unsafe fn op_settable(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A)[RK(B)] := RK(C)
+    let rb = rk(f.cl, f.base, i.b, i.kb);
+    let rc = rk(f.cl, f.base, i.c, i.kc);
+    luaV_settable(f.L, f.slot_cache(i.kb).as_mut(), f.reg(i.a), rb, rc);
+    Step::Next
+}
+
+unsafe fn op_gettable(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := R(B)[RK(C)]
+    let rc = rk(f.cl, f.base, i.c, i.kc);
+    *f.reg(i.a) = luaV_gettable(f.L, f.slot_cache(i.kc).as_mut(), f.reg(i.b), rc);
+    Step::Next
+}
+
+unsafe fn op_self(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A+1) := R(B); R(A) := R(B)[RK(C)]
+    // The receiver is copied before R(A) is written, as A may be B. The
+    // method name is a constant, so the lookup goes through the slot cache
+    let rb = *f.reg(i.b);
+    *f.reg(i.a + 1) = rb;
+    let rc = rk(f.cl, f.base, i.c, i.kc);
+    *f.reg(i.a) = luaV_gettable(f.L, f.slot_cache(i.kc).as_mut(), &rb, rc);
+    Step::Next
+}
+
+unsafe fn op_newtable(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := {} with room for B array and C hash items (size hints
+    // encoded by luaO_codesize), so a constructor allocates once
+    let mut t = Table::with_capacity(luaO_decodesize(i.b as u8), luaO_decodesize(i.c as u8));
+    if api_state(f.L).insertion_order() {
+        t.set_ordered(true);
+    }
+    *f.reg(i.a) = TValue { tt: LuaType::Table, value: TValueValue { p: vm_table(t) as *mut std::ffi::c_void } };
+    Step::Next
+}
+
+unsafe fn op_setlist(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A)[C+j] := R(A+j), 1 <= j <= B; C items were stored before
+    // B == 0: the items run up to 'top' (the last one was a call or '...')
+    let ra = f.reg(i.a);
+    let n = if i.b != 0 { i.b } else { (*f.L).top.offset_from(ra) as usize - 1 };
+    for j in 1..=n {
+        let key = TValue::from_integer((i.c + j) as lua_Integer);
+        luaV_settable(f.L, None, ra, &key, ra.add(j));
+    }
+    Step::Next
+}
+
+// Plugin intrinsics (lplugin): the optimizer turns a call of an intrinsic's
+// global into CALLI, with the intrinsic's id loaded into the function slot.
+
+thread_local! {
+    // Intrinsics registered on this thread, indexed by id
+    static INTRINSICS: std::cell::RefCell<Vec<Intrinsic>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Register an intrinsic for CALLI; returns its id
+pub fn luaV_addintrinsic(f: Intrinsic) -> usize {
+    INTRINSICS.with(|t| {
+        let mut t = t.borrow_mut();
+        t.push(f);
+        t.len() - 1
+    })
+}
+
+/// Run intrinsic `id` on `args`
+pub fn luaV_callintrinsic(id: usize, args: &[TValue]) -> Result<TValue, String> {
+    match INTRINSICS.with(|t| t.borrow().get(id).copied()) {
+        Some(f) => f(args),
+        None => Err(format!("unknown intrinsic {}", id)),
+    }
+}
+
+unsafe fn op_calli(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A), ... ,R(A+C-2) := intrinsic[R(A)](R(A+1), ... ,R(A+B-1))
+    // B and C are never 0 here: the optimizer only binds fixed calls
+    let ra = f.reg(i.a);
+    let args = std::slice::from_raw_parts(ra.add(1), i.b - 1);
+    match luaV_callintrinsic((*ra).value.i as usize, args) {
+        Ok(v) => *ra = v,
+        Err(msg) => api_state(f.L).throw(LuaValue::Str(msg)),
+    }
+    for r in 1..i.c.saturating_sub(1) {
+        *ra.add(r) = TValue::nil();
+    }
+    Step::Next
+}
+
+unsafe fn op_call(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
+    // B == 0: arguments run up to 'top' (set by a previous CALL/VARARG)
+    // C == 0: keep all results (LUA_MULTRET)
+    let L = f.L;
+    let ra = f.reg(i.a);
+    let n_args = if i.b != 0 { i.b - 1 } else { (*L).top.offset_from(ra) as usize - 1 };
+    let n_results = if i.c != 0 { i.c as c_int - 1 } else { LUA_MULTRET };
+    skyla_trace!(TRACE_CALLS, TraceEvent::Call { func: format!("function: {:p}", ra) });
+    f.savepc();
+    luaG_callhook(api_state(L));
+    luaD_call(L, ra, n_args, n_results);
+    luaG_rethook(api_state(L));
+    f.base = (*f.ci).base;
+    Step::Next
+}
+
+unsafe fn op_return(f: &mut Frame, i: &Decoded) -> Step {
+    // return R(A), ... ,R(A+B-2)
+    // B == 0: return everything up to 'top'
+    let ra = f.reg(i.a);
+    let n = if i.b != 0 { i.b - 1 } else { (*f.L).top.offset_from(ra) as usize };
+    skyla_trace!(TRACE_CALLS, TraceEvent::Return { func: format!("function: {:p}", f.cl), nresults: n });
+    luaD_return(f.L, ra, n);
+    Step::Return
+}
+
+unsafe fn op_vararg(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A), R(A+1), ..., R(A+B-2) = vararg
+    // B == 0: copy all varargs and set 'top' past the last one
+    let p = (*f.cl).cl.p;
+    let base = f.base;
+    let n = ((base.offset_from((*f.ci).func) as c_int) - (*p).numparams as c_int - 1).max(0) as usize;
+    let ra = f.reg(i.a);
+    let wanted = if i.b != 0 { i.b - 1 } else {
+        (*f.L).top = ra.add(n);
+        n
+    };
+    for j in 0..wanted {
+        *ra.add(j) = if j < n {
+            *base.offset(j as isize - n as isize)
+        } else {
+            TValue::nil()
+        };
+    }
+    Step::Next
+}
+
+unsafe fn op_arith(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := RK(B) op RK(C)
+    let rb = rk(f.cl, f.base, i.b, i.kb);
+    let rc = rk(f.cl, f.base, i.c, i.kc);
+    match luaV_arith(i.op, &*rb, &*rc) {
+        Ok(v) => *f.reg(i.a) = v,
+        Err(msg) => api_state(f.L).throw(LuaValue::Str(msg)),
+    }
+    Step::Next
+}
+
+unsafe fn op_unm(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A) := -R(B)
+    let rb = f.reg(i.b);
+    match luaV_arith(OpCode::UNM, &*rb, &*rb) {
+        Ok(v) => *f.reg(i.a) = v,
+        Err(msg) => api_state(f.L).throw(LuaValue::Str(msg)),
+    }
+    Step::Next
+}
+
+unsafe fn op_jmp(f: &mut Frame, i: &Decoded) -> Step {
+    // pc += sBx
+    f.jump(i.sbx as isize);
+    Step::Next
+}
+
+unsafe fn op_compare(f: &mut Frame, i: &Decoded) -> Step {
+    // if ((RK(B) op RK(C)) ~= A) then pc++
+    // numbers and strings compare in place; the rest takes the generic
+    // comparison, with metamethods and errors raised through the state
+    let rb = &*rk(f.cl, f.base, i.b, i.kb);
+    let rc = &*rk(f.cl, f.base, i.c, i.kc);
+    let L = api_state(f.L);
+    let res = match luaV_rawcompare(i.op, rb, rc, locale_aware(L)) {
+        Some(res) => res,
+        None => {
+            let (a, b) = (lua_value(rb), lua_value(rc));
+            match i.op {
+                OpCode::EQ => luaV_equalobj(L, &a, &b),
+                OpCode::LT => luaV_lessthan(L, &a, &b),
+                _ => luaV_lessequal(L, &a, &b),
+            }
+        }
+    };
+    if res != (i.a != 0) {
+        f.jump(1);
+    }
+    Step::Next
+}
 
-        // Decode instruction opcode and args
-        let op = OpCode::from_u8(instruction.get_opcode());
-        let a = instruction.get_arg_a() as usize;
-        let b = instruction.get_arg_b() as usize;
-        let c = instruction.get_arg_c() as usize;
-        let bx = instruction.get_arg_bx();
-        let sbx = instruction.get_arg_sbx();
+/// Helper functions used inside VM:
+
+/// Get a value from a Lua table (simplified)
+unsafe fn luaH_get(L: *mut lua_State, table: *const TValue, key: &str) -> TValue {
+    // Implement hash table lookup
+    unimplemented!()
+}
 
+/// Set a value in a Lua table (simplified)
+unsafe fn luaH_set(L: *mut lua_State, table: *mut TValue, key: &str, val: *const TValue) {
+    // Implement hash table insertion or update
+    unimplemented!()
+}
+
+/// Bit marking an RK operand as a constant index (as in Lua 5.1's ISK)
+pub const BITRK: usize = 1 << 8;
+
+/// Integer modulo with the sign of the divisor (luaV_mod); `m` is not 0
+fn imod(n: lua_Integer, m: lua_Integer) -> lua_Integer {
+    let r = n.wrapping_rem(m);
+    if r != 0 && (r ^ m) < 0 { r + m } else { r }
+}
+
+/// Float modulo with the sign of the divisor (luai_nummod)
+fn fmod(n: lua_Number, m: lua_Number) -> lua_Number {
+    let r = n % m;
+    if r != 0.0 && (r < 0.0) != (m < 0.0) { r + m } else { r }
+}
+
+/// The result of an arithmetic opcode (ADD..POW, UNM with `b` ignored) on
+/// two numbers, used both by the VM and by constant folding (lopt).
+/// Integers stay integers except for DIV and POW, wrapping on overflow.
+pub fn luaV_arith(op: OpCode, a: &TValue, b: &TValue) -> Result<TValue, String> {
+    let tonum = |v: &TValue| unsafe {
+        match v.tt {
+            LuaType::Integer => Some((Some(v.value.i), v.value.i as lua_Number)),
+            LuaType::Number => Some((None, v.value.n)),
+            _ => None,
+        }
+    };
+    let ((ia, na), (ib, nb)) = match (tonum(a), tonum(b)) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Err("attempt to perform arithmetic on a non-number value".to_string()),
+    };
+    if let (Some(x), Some(y)) = (ia, ib) {
         match op {
-            OpCode::MOVE => {
-                // R(A) := R(B)
-                let rb = base.offset(b as isize);
-                let ra = base.offset(a as isize);
-                *ra = *rb;
+            OpCode::ADD => return Ok(TValue::from_integer(x.wrapping_add(y))),
+            OpCode::SUB => return Ok(TValue::from_integer(x.wrapping_sub(y))),
+            OpCode::MUL => return Ok(TValue::from_integer(x.wrapping_mul(y))),
+            OpCode::MOD if y == 0 => return Err("attempt to perform 'n%%0'".to_string()),
+            OpCode::MOD => return Ok(TValue::from_integer(imod(x, y))),
+            OpCode::UNM => return Ok(TValue::from_integer(x.wrapping_neg())),
+            _ => {}
+        }
+    }
+    let n = match op {
+        OpCode::ADD => na + nb,
+        OpCode::SUB => na - nb,
+        OpCode::MUL => na * nb,
+        OpCode::DIV => na / nb,
+        OpCode::MOD => fmod(na, nb),
+        OpCode::POW => na.powf(nb),
+        OpCode::UNM => -na,
+        _ => return Err(format!("{:?} is not an arithmetic opcode", op)),
+    };
+    Ok(TValue::from_number(n))
+}
+
+// --- Generic for ---
+//
+// `for v1, ..., vn in explist do` keeps three control values, the iterator
+// function, its state and the control variable, in R(A), R(A+1), R(A+2).
+// TFORCALL calls R(A)(R(A+1), R(A+2)) into R(A+3)...; TFORLOOP ends the
+// loop when the first result is nil, else makes it the new control value
+// and jumps back. When the iterator is the base library's `next` or
+// ipairs iterator over a table, TFORCALL steps through the table itself
+// instead of calling it. A table with the __iter extension metamethod in
+// the iterator slot is iterated with what __iter returns (luaV_forprep).
+
+thread_local! {
+    // The builtin `next` and ipairs iterator, as registered by the base library
+    static FAST_ITERATORS: std::cell::Cell<(*mut std::ffi::c_void, *mut std::ffi::c_void)> =
+        const { std::cell::Cell::new((std::ptr::null_mut(), std::ptr::null_mut())) };
+}
+
+/// Let TFORCALL recognize the base library's `next` and ipairs iterator
+/// functions, so pairs/ipairs loops skip the call
+pub fn luaV_setiterators(next: *mut std::ffi::c_void, inext: *mut std::ffi::c_void) {
+    FAST_ITERATORS.with(|it| it.set((next, inext)));
+}
+
+/// The iterator function, state and initial control value of a generic
+/// for over the explist values `f, s, c`: when `f` is not a function and
+/// has the __iter extension metamethod, the three values __iter(f)
+/// returns; otherwise `f, s, c` themselves
+pub fn luaV_forprep(L: &mut LuaState, f: LuaValue, s: LuaValue, c: LuaValue) -> (LuaValue, LuaValue, LuaValue) {
+    let tm = match &f {
+        LuaValue::Function(_) => None,
+        v => get_extension_tm(v, TM_ITER),
+    };
+    let Some(tm) = tm else { return (f, s, c) };
+    L.push(tm);
+    L.push(f);
+    if !L.call_function(1, 3) {
+        return (LuaValue::Nil, LuaValue::Nil, LuaValue::Nil);
+    }
+    let c = L.pop().unwrap_or(LuaValue::Nil);
+    let s = L.pop().unwrap_or(LuaValue::Nil);
+    let f = L.pop().unwrap_or(LuaValue::Nil);
+    (f, s, c)
+}
+
+unsafe fn op_tforcall(f: &mut Frame, i: &Decoded) -> Step {
+    // R(A+3), ... ,R(A+2+C) := R(A)(R(A+1), R(A+2))
+    let mut ra = f.reg(i.a);
+    if matches!((*ra).tt, LuaType::Table) {
+        // First step over a table whose metatable has __iter: replace the
+        // control values with the three __iter(t) returns, as luaV_forprep
+        // does. The iterator is then a function, so this runs once per
+        // loop; a table without __iter is called as usual (__call)
+        let t = (*ra).value.p as *const Table;
+        if let Some(tm) = get_any_tm(&*t, TM_ITER).filter(|_| get_dynamic_metamethod_index(TM_ITER).is_some()) {
+            let cb = ra.add(3);
+            *cb = TValue::from_lua(&tm);
+            *cb.add(1) = *ra;
+            (*f.L).top = cb.add(2);
+            luaD_call(f.L, cb, 1, 3);
+            (*f.L).top = (*f.ci).top;
+            f.base = (*f.ci).base;
+            ra = f.reg(i.a);
+            let cb = ra.add(3);
+            *ra = *cb;
+            *ra.add(1) = *cb.add(1);
+            *ra.add(2) = *cb.add(2);
+        }
+    }
+    if tforcall_fast(f, i.a, i.c) {
+        return Step::Next;
+    }
+    let cb = ra.add(3); // call on a copy of the control values
+    *cb.add(2) = *ra.add(2);
+    *cb.add(1) = *ra.add(1);
+    *cb = *ra;
+    (*f.L).top = cb.add(3);
+    luaD_call(f.L, cb, 2, i.c as c_int);
+    (*f.L).top = (*f.ci).top;
+    f.base = (*f.ci).base;
+    Step::Next
+}
+
+/// TFORCALL for `next` over a table and for the ipairs iterator over a
+/// table without a metatable (so no __index); false if neither applies
+unsafe fn tforcall_fast(f: &mut Frame, a: usize, nresults: usize) -> bool {
+    let ra = f.reg(a);
+    if !matches!((*ra).tt, LuaType::Function) || !matches!((*ra.add(1)).tt, LuaType::Table) {
+        return false;
+    }
+    let (next, inext) = FAST_ITERATORS.with(|it| it.get());
+    let func = (*ra).value.p;
+    let t = (*ra.add(1)).value.p as *const crate::ltable::Table;
+    let control = &*ra.add(2);
+    let entry = if func == inext && !inext.is_null() {
+        let k = match control.tt {
+            LuaType::Integer => control.value.i.wrapping_add(1),
+            _ => return false,
+        };
+        if (*t).get_metatable().is_some() {
+            return false;
+        }
+        (*t).get(&LuaValue::Int(k)).map(|v| (LuaValue::Int(k), v.clone()))
+    } else if func == next && !next.is_null() {
+        let pos = f.iters.iter().position(|it| it.a == a);
+        if matches!(control.tt, LuaType::Nil) {
+            // a new traversal
+            let it = ForIter { a, table: t, cursor: crate::ltable::TableCursor::new() };
+            match pos {
+                Some(p) => f.iters[p] = it,
+                None => f.iters.push(it),
             }
-            OpCode::LOADK => {
-                // R(A) := Kst(Bx)
-                k = (*(*cl).cl.p).k.as_ptr().offset(bx as isize);
-                *base.offset(a as isize) = *k;
+        } else if pos.is_none_or(|p| f.iters[p].table != t) {
+            return false; // started from a key: plain calls to next
+        }
+        let p = f.iters.iter().position(|it| it.a == a).unwrap();
+        let entry = (*t).next_entry(&mut f.iters[p].cursor);
+        if entry.is_none() {
+            f.iters.swap_remove(p);
+        }
+        entry
+    } else {
+        return false;
+    };
+    let results = ra.add(3);
+    let (k, v) = match entry {
+        Some((k, v)) => (TValue::from_lua(&k), TValue::from_lua(&v)),
+        None => (TValue::nil(), TValue::nil()),
+    };
+    for r in 0..nresults {
+        *results.add(r) = match r {
+            0 => k,
+            1 => v,
+            _ => TValue::nil(),
+        };
+    }
+    true
+}
+
+unsafe fn op_tforloop(f: &mut Frame, i: &Decoded) -> Step {
+    // if R(A+1) ~= nil then { R(A) := R(A+1); pc += sBx }
+    let ra = f.reg(i.a);
+    if !matches!((*ra.add(1)).tt, LuaType::Nil) {
+        *ra = *ra.add(1);
+        f.jump(i.sbx as isize);
+    }
+    Step::Next
+}
+
+// --- Comparison ---
+//
+// Numbers compare by value across subtypes: an integer and a float are
+// compared exactly, without rounding the integer to the nearest float.
+// Strings compare byte-wise (or with strcoll when the state is
+// locale_aware). Anything else compares with __eq/__lt/__le, and ordering
+// values without a metamethod is an error.
+
+/// A number operand, keeping its subtype
+#[derive(Clone, Copy)]
+enum Num {
+    Int(lua_Integer),
+    Flt(lua_Number),
+}
+
+/// Whether every integer with the magnitude of `i` is exact as a float
+fn l_intfitsf(i: lua_Integer) -> bool {
+    let nbm = lua_Number::MANTISSA_DIGITS;
+    if nbm >= lua_Integer::BITS - 1 {
+        return true;
+    }
+    let lim: lua_Integer = 1 << nbm;
+    (-lim..=lim).contains(&i)
+}
+
+/// floor(f) or ceil(f) as an integer, if in range
+fn flttoint(f: lua_Number, ceil: bool) -> Option<lua_Integer> {
+    crate::skylaconf::float_to_integer(if ceil { f.ceil() } else { f.floor() })
+}
+
+impl Num {
+    fn lt(self, other: Num) -> bool {
+        match (self, other) {
+            (Num::Int(a), Num::Int(b)) => a < b,
+            (Num::Flt(a), Num::Flt(b)) => a < b,
+            // i < f <=> i < ceil(f); a float out of range is below or above every integer
+            (Num::Int(i), Num::Flt(f)) if l_intfitsf(i) => (i as lua_Number) < f,
+            (Num::Int(i), Num::Flt(f)) => flttoint(f, true).map_or(f > 0.0, |fi| i < fi),
+            // f < i <=> floor(f) < i
+            (Num::Flt(f), Num::Int(i)) if l_intfitsf(i) => f < i as lua_Number,
+            (Num::Flt(f), Num::Int(i)) => flttoint(f, false).map_or(f < 0.0, |fi| fi < i),
+        }
+    }
+
+    fn le(self, other: Num) -> bool {
+        match (self, other) {
+            (Num::Int(a), Num::Int(b)) => a <= b,
+            (Num::Flt(a), Num::Flt(b)) => a <= b,
+            // i <= f <=> i <= floor(f)
+            (Num::Int(i), Num::Flt(f)) if l_intfitsf(i) => (i as lua_Number) <= f,
+            (Num::Int(i), Num::Flt(f)) => flttoint(f, false).map_or(f > 0.0, |fi| i <= fi),
+            // f <= i <=> ceil(f) <= i
+            (Num::Flt(f), Num::Int(i)) if l_intfitsf(i) => f <= i as lua_Number,
+            (Num::Flt(f), Num::Int(i)) => flttoint(f, true).map_or(f < 0.0, |fi| fi <= i),
+        }
+    }
+
+    fn eq(self, other: Num) -> bool {
+        match (self, other) {
+            (Num::Int(a), Num::Int(b)) => a == b,
+            (Num::Flt(a), Num::Flt(b)) => a == b,
+            (Num::Int(i), Num::Flt(f)) | (Num::Flt(f), Num::Int(i)) => {
+                crate::skylaconf::float_to_integer(f) == Some(i)
             }
-            OpCode::LOADBOOL => {
-                // R(A) := (Bool)B; if C != 0 skip next instruction
-                *base.offset(a as isize) = TValue::from_bool(b != 0);
-                if c != 0 {
-                    pc = pc.offset(1);
-                }
+        }
+    }
+
+    fn of_value(v: &LuaValue) -> Option<Num> {
+        match v {
+            LuaValue::Int(i) => Some(Num::Int(*i)),
+            LuaValue::Float(n) => Some(Num::Flt(*n)),
+            _ => None,
+        }
+    }
+
+    unsafe fn of_tvalue(v: &TValue) -> Option<Num> {
+        match v.tt {
+            LuaType::Integer => Some(Num::Int(v.value.i)),
+            LuaType::Number => Some(Num::Flt(v.value.n)),
+            _ => None,
+        }
+    }
+}
+
+extern "C" {
+    fn strcoll(a: *const std::os::raw::c_char, b: *const std::os::raw::c_char) -> c_int;
+}
+
+/// Order of two strings (l_strcmp): byte-wise, or by the current locale's
+/// collation with `locale_aware`. Embedded zeros are compared as
+/// separators of pieces that are collated one by one.
+pub fn l_strcmp(a: &[u8], b: &[u8], locale_aware: bool) -> std::cmp::Ordering {
+    if !locale_aware {
+        return a.cmp(b);
+    }
+    let (mut a, mut b) = (a, b);
+    loop {
+        let la = a.iter().position(|&c| c == 0).unwrap_or(a.len());
+        let lb = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+        let (ca, cb) = (CString::new(&a[..la]).unwrap(), CString::new(&b[..lb]).unwrap());
+        let r = unsafe { strcoll(ca.as_ptr(), cb.as_ptr()) };
+        if r != 0 {
+            return r.cmp(&0);
+        }
+        // equal up to the first zero: the one that ends there is smaller
+        match (lb == b.len(), la == a.len()) {
+            (true, true) => return std::cmp::Ordering::Equal,
+            (true, false) => return std::cmp::Ordering::Greater,
+            (false, true) => return std::cmp::Ordering::Less,
+            (false, false) => {
+                a = &a[la + 1..];
+                b = &b[lb + 1..];
             }
-            OpCode::LOADNIL => {
-                // R(A) to R(A+B) := nil
-                for i in 0..=b {
-                    *base.offset((a + i) as isize) = TValue::nil();
-                }
+        }
+    }
+}
+
+/// "attempt to compare two table values" / "attempt to compare number with nil"
+pub fn luaG_ordererror(t1: &str, t2: &str) -> String {
+    if t1 == t2 {
+        format!("attempt to compare two {} values", t1)
+    } else {
+        format!("attempt to compare {} with {}", t1, t2)
+    }
+}
+
+/// EQ/LT/LE on numbers, strings, booleans and nil, with strings ordered
+/// as l_strcmp does for `locale_aware`; None when the result depends on
+/// metamethods or is an error
+///
+/// # Safety
+/// String operands must point to NUL-terminated strings.
+pub unsafe fn luaV_rawcompare(op: OpCode, a: &TValue, b: &TValue, locale_aware: bool) -> Option<bool> {
+    if let (Some(x), Some(y)) = (Num::of_tvalue(a), Num::of_tvalue(b)) {
+        return match op {
+            OpCode::EQ => Some(x.eq(y)),
+            OpCode::LT => Some(x.lt(y)),
+            _ => Some(x.le(y)),
+        };
+    }
+    match (a.tt, b.tt) {
+        (LuaType::String, LuaType::String) => {
+            let ord = l_strcmp(std::ffi::CStr::from_ptr(a.value.s).to_bytes(), std::ffi::CStr::from_ptr(b.value.s).to_bytes(), locale_aware);
+            match op {
+                OpCode::EQ => Some(ord.is_eq()),
+                OpCode::LT => Some(ord.is_lt()),
+                _ => Some(ord.is_le()),
             }
-            OpCode::GETUPVAL => {
-                // R(A) := UpValue[B]
-                let upval = (*cl).upvals[b].as_ref();
-                *base.offset(a as isize) = *upval.val();
+        }
+        _ if op != OpCode::EQ => None,
+        (LuaType::Nil, LuaType::Nil) => Some(true),
+        (LuaType::Boolean, LuaType::Boolean) => Some(a.value.b == b.value.b),
+        (LuaType::Table, LuaType::Table) | (LuaType::Function, LuaType::Function) | (LuaType::Thread, LuaType::Thread) if a.value.p == b.value.p => Some(true),
+        (LuaType::Table, LuaType::Table) => None,
+        _ => Some(false),
+    }
+}
+
+fn is_false(v: &LuaValue) -> bool {
+    matches!(v, LuaValue::Nil | LuaValue::Bool(false))
+}
+
+/// Call the `event` metamethod of `a` or `b` and take the truth of its
+/// result; None if neither has one
+fn call_order_tm(L: &mut LuaState, a: &LuaValue, b: &LuaValue, event: TMS) -> Option<bool> {
+    if !has_any_tm(a, event.name()) && !has_any_tm(b, event.name()) {
+        return None;
+    }
+    let res = try_bin_tm_vm(L, a, b, event, || None).unwrap_or(LuaValue::Nil);
+    Some(!is_false(&res))
+}
+
+fn locale_aware(L: &LuaState) -> bool {
+    L.l_G.borrow().locale_aware
+}
+
+/// a == b, with __eq for two tables or two userdata that are not the
+/// same object (luaV_equalobj)
+pub fn luaV_equalobj(L: &mut LuaState, a: &LuaValue, b: &LuaValue) -> bool {
+    if let (Some(x), Some(y)) = (Num::of_value(a), Num::of_value(b)) {
+        return x.eq(y);
+    }
+    if raw_equal(a, b) {
+        return true;
+    }
+    match (a, b) {
+        (LuaValue::Table(_), LuaValue::Table(_)) | (LuaValue::UserData(_), LuaValue::UserData(_)) => {
+            call_order_tm(L, a, b, TMS::Eq).unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// a < b, with __lt for values that are not two numbers or two strings;
+/// raises an error if neither operand has one (luaV_lessthan)
+pub fn luaV_lessthan(L: &mut LuaState, a: &LuaValue, b: &LuaValue) -> bool {
+    if let (Some(x), Some(y)) = (Num::of_value(a), Num::of_value(b)) {
+        return x.lt(y);
+    }
+    if let (LuaValue::Str(x), LuaValue::Str(y)) = (a, b) {
+        return l_strcmp(x.as_bytes(), y.as_bytes(), locale_aware(L)).is_lt();
+    }
+    match call_order_tm(L, a, b, TMS::Lt) {
+        Some(res) => res,
+        None => L.throw(LuaValue::Str(luaG_ordererror(obj_typename(a), obj_typename(b)))),
+    }
+}
+
+/// a <= b, with __le for values that are not two numbers or two strings;
+/// raises an error if neither operand has one (luaV_lessequal)
+pub fn luaV_lessequal(L: &mut LuaState, a: &LuaValue, b: &LuaValue) -> bool {
+    if let (Some(x), Some(y)) = (Num::of_value(a), Num::of_value(b)) {
+        return x.le(y);
+    }
+    if let (LuaValue::Str(x), LuaValue::Str(y)) = (a, b) {
+        return l_strcmp(x.as_bytes(), y.as_bytes(), locale_aware(L)).is_le();
+    }
+    match call_order_tm(L, a, b, TMS::Le) {
+        Some(res) => res,
+        None => L.throw(LuaValue::Str(luaG_ordererror(obj_typename(a), obj_typename(b)))),
+    }
+}
+
+/// String form of a string or number operand of `..` (numbers convert
+/// as tostring does); None for any other value
+fn concat_piece(v: &LuaValue) -> Option<String> {
+    match v {
+        LuaValue::Str(s) => Some(s.clone()),
+        LuaValue::Int(i) => Some(crate::lobject::luaO_int2str(*i)),
+        LuaValue::Float(n) => Some(crate::lobject::luaO_num2str_dot(*n)),
+        _ => None,
+    }
+}
+
+/// "attempt to concatenate a nil value", blaming the operand that is not
+/// a string or number (luaG_concaterror)
+pub fn luaG_concaterror(a: &LuaValue, b: &LuaValue) -> String {
+    let culprit = if concat_piece(a).is_some() { b } else { a };
+    format!("attempt to concatenate a {} value", obj_typename(culprit))
+}
+
+/// v1 .. v2 .. ... .. vn, right to left as in Lua: runs of strings and
+/// numbers are joined in one go, any other pair goes through __concat.
+/// No values give the empty string (luaV_concat)
+pub fn luaV_concat(L: &mut LuaState, mut vals: Vec<LuaValue>) -> LuaValue {
+    while vals.len() > 1 {
+        let n = vals.len();
+        let (a, b) = (&vals[n - 2], &vals[n - 1]);
+        if concat_piece(a).is_some() && concat_piece(b).is_some() {
+            let first = (0..n - 2).rev().take_while(|&i| concat_piece(&vals[i]).is_some()).last().unwrap_or(n - 2);
+            let s: String = vals.drain(first..).map(|v| concat_piece(&v).unwrap()).collect();
+            vals.push(LuaValue::Str(s));
+        } else {
+            if !has_any_tm(a, TMS::Concat.name()) && !has_any_tm(b, TMS::Concat.name()) {
+                L.throw(LuaValue::Str(luaG_concaterror(a, b)));
             }
-            OpCode::GETGLOBAL => {
-                // R(A) := Gbl[Kst(Bx)]
-                let kname = (*(*cl).cl.p).k[bx as usize].to_string();
-                let val = luaH_get(L, &(*L).l_env, &kname);
-                *base.offset(a as isize) = val;
+            let (a, b) = (a.clone(), b.clone());
+            let res = try_bin_tm_vm(L, &a, &b, TMS::Concat, || None).unwrap_or(LuaValue::Nil);
+            vals.truncate(n - 2);
+            vals.push(res);
+        }
+    }
+    vals.pop().unwrap_or_else(|| LuaValue::Str(String::new()))
+}
+
+/// #v: the byte length of a string, __len if the value has one, else the
+/// border of a table; an error for anything else (luaV_objlen)
+pub fn luaV_objlen(L: &mut LuaState, v: &LuaValue) -> LuaValue {
+    if let LuaValue::Str(s) = v {
+        return LuaValue::Int(s.len() as lua_Integer);
+    }
+    if let Some(f) = get_value_tm(v, TMS::Len) {
+        return call_tm_vm(L, &f, &[v.clone(), v.clone()]).unwrap_or(LuaValue::Nil);
+    }
+    match v {
+        LuaValue::Table(t) => LuaValue::Int(t.borrow().lua_len() as lua_Integer),
+        _ => L.throw(LuaValue::Str(format!("attempt to get length of a {} value", obj_typename(v)))),
+    }
+}
+
+/// An RK operand: constant `x` when `k` is set, else register `x`.
+unsafe fn rk(cl: *mut Closure, base: *mut TValue, x: usize, k: bool) -> *const TValue {
+    if k {
+        (*(*cl).cl.p).k.as_ptr().add(x)
+    } else {
+        base.offset(x as isize)
+    }
+}
+
+/// Slot of an integer or string key already in `h`. With a cache (the
+/// key is a constant) a string key is looked up again only after the
+/// table's layout changed.
+unsafe fn find_slot(h: *mut Table, key: &TValue, cache: Option<&mut SlotCache>) -> Option<TableSlot> {
+    match key.tt {
+        LuaType::Integer => (*h).slot_int(key.value.i),
+        LuaType::String => {
+            let version = (*h).version();
+            if let Some(c) = &cache {
+                if c.version == version && c.slot.is_some() {
+                    return c.slot;
+                }
             }
-            OpCode::SETGLOBAL => {
-                // Gbl[Kst(Bx)] := R(A)
-                let kname = (*(*cl).cl.p).k[bx as usize].to_string();
-                luaH_set(L, &mut (*L).l_env, &kname, base.offset(a as isize));
+            let name = std::ffi::CStr::from_ptr(key.value.s).to_string_lossy();
+            let slot = (*h).slot_str(&TString::new(&name));
+            if let Some(c) = cache {
+                *c = SlotCache { version, slot };
             }
-            OpCode::CALL => {
-                // R(A), ... ,R(A+C-2) := R(A)(R(A+1), ... ,R(A+B-1))
-                let n_args = b - 1;
-                let n_results = c - 1;
-                luaD_call(L, base.offset(a as isize), n_args, n_results);
-                base = (*ci).func.offset(1);
+            slot
+        }
+        _ => None,
+    }
+}
+
+/// API state of the VM thread `L`, which metamethod calls and errors go
+/// through; only the slow paths need it
+unsafe fn api_state<'a>(L: *mut lua_State) -> &'a mut LuaState {
+    &mut *(*L).state
+}
+
+/// LuaValue form of a register for the slow paths (nil if it has none)
+unsafe fn lua_value(v: *const TValue) -> LuaValue {
+    (*v).to_lua().unwrap_or(LuaValue::Nil)
+}
+
+/// R(A) := t[key] for GETTABLE. Existing integer and string keys are read
+/// through their slot; other keys take the generic lookup, and a miss on
+/// a table with a metatable or a non-table value goes to luaV_finishget.
+unsafe fn luaV_gettable(L: *mut lua_State, cache: Option<&mut SlotCache>, t: *const TValue, key: *const TValue) -> TValue {
+    if let LuaType::Table = (*t).tt {
+        let h = (*t).value.p as *mut Table;
+        if let Some(v) = find_slot(h, &*key, cache).and_then(|slot| slot.get()) {
+            return TValue::from_lua(v);
+        }
+        if let Some(v) = (*key).to_lua().and_then(|k| (*h).get(&k)) {
+            return TValue::from_lua(v);
+        }
+        if (*h).get_metatable().is_none() {
+            return TValue::nil();
+        }
+    }
+    TValue::from_lua(&luaV_finishget(api_state(L), &lua_value(t), &lua_value(key)))
+}
+
+/// t[key] := val for SETTABLE; raises an error if `t` is a frozen table.
+/// A non-nil value for a key already present is written through its slot,
+/// other stores take the generic rawset, and an absent key of a table
+/// with a metatable or a non-table value goes to luaV_finishset.
+unsafe fn luaV_settable(L: *mut lua_State, cache: Option<&mut SlotCache>, t: *mut TValue, key: *const TValue, val: *const TValue) {
+    if let LuaType::Table = (*t).tt {
+        let h = (*t).value.p as *mut Table;
+        if let Err(msg) = (*h).check_writable() {
+            api_state(L).throw(LuaValue::Str(msg.to_string()));
+        }
+        if matches!((*key).tt, LuaType::Nil) {
+            api_state(L).throw(LuaValue::Str("table index is nil".to_string()));
+        }
+        let slot = find_slot(h, &*key, cache);
+        let k = (*key).to_lua();
+        // an array hole is an absent key, which __newindex may claim
+        let present = match slot {
+            Some(slot) => slot.get().is_some(),
+            None => k.as_ref().is_some_and(|k| (*h).contains_key(k)),
+        };
+        if present || (*h).get_metatable().is_none() {
+            match ((*val).to_lua(), slot) {
+                (Some(v), Some(slot)) if !matches!(v, LuaValue::Nil) => slot.set(v),
+                _ => {
+                    if let Err(msg) = rawset_value(&mut *h, &lua_value(key), &lua_value(val)) {
+                        api_state(L).throw(LuaValue::Str(msg));
+                    }
+                }
             }
-            OpCode::RETURN => {
-                // return R(A), ... ,R(A+B-2)
-                luaD_return(L, base.offset(a as isize), b - 1);
-                return; // Return from this function frame
+            return;
+        }
+    }
+    luaV_finishset(api_state(L), &lua_value(t), &lua_value(key), &lua_value(val));
+}
+
+/// Longest __index/__newindex chain followed before it is taken for a
+/// loop (MAXTAGLOOP)
+pub const MAXTAGLOOP: usize = 2000;
+
+/// t[key] past the raw lookup (luaV_finishget): __index tables are indexed
+/// in turn and an __index function is called, with the value it hangs
+/// off. `tm` gives a value's __index; errors are messages.
+pub fn luaV_index_chain(
+    t: &LuaValue,
+    key: &LuaValue,
+    mut tm: impl FnMut(&LuaValue) -> Option<LuaValue>,
+    mut call: impl FnMut(&LuaValue, &LuaValue) -> LuaValue,
+) -> Result<LuaValue, String> {
+    let mut t = t.clone();
+    for _ in 0..MAXTAGLOOP {
+        if let LuaValue::Table(h) = &t {
+            if let Some(v) = h.borrow().get(key).cloned() {
+                return Ok(v);
             }
-            // Add other opcodes here with their implementations...
+        }
+        let next = match tm(&t) {
+            Some(next) => next,
+            None if matches!(t, LuaValue::Table(_)) => return Ok(LuaValue::Nil),
+            None => return Err(format!("attempt to index a {} value", obj_typename(&t))),
+        };
+        if matches!(next, LuaValue::Function(_)) {
+            return Ok(call(&next, &t));
+        }
+        t = next;
+    }
+    Err("'__index' chain too long; possible loop".to_string())
+}
 
-            _ => {
-                panic!("Opcode {:?} not implemented yet!", op);
+/// t[key] = val past the raw lookup (luaV_finishset): a table stores the
+/// key raw if it has it or has no __newindex; otherwise __newindex tables
+/// are followed and an __newindex function is called, with the value it
+/// hangs off. `tm` gives a value's __newindex; errors are messages.
+pub fn luaV_newindex_chain(
+    t: &LuaValue,
+    key: &LuaValue,
+    val: &LuaValue,
+    mut tm: impl FnMut(&LuaValue) -> Option<LuaValue>,
+    mut call: impl FnMut(&LuaValue, &LuaValue),
+) -> Result<(), String> {
+    let mut t = t.clone();
+    for _ in 0..MAXTAGLOOP {
+        let next = match &t {
+            LuaValue::Table(h) => {
+                let present = h.borrow().contains_key(key);
+                match if present { None } else { tm(&t) } {
+                    Some(next) => next,
+                    None => return rawset_value(&mut h.borrow_mut(), key, val),
+                }
             }
+            _ => match tm(&t) {
+                Some(next) => next,
+                None => return Err(format!("attempt to index a {} value", obj_typename(&t))),
+            },
+        };
+        if matches!(next, LuaValue::Function(_)) {
+            call(&next, &t);
+            return Ok(());
         }
+        t = next;
     }
+    Err("'__newindex' chain too long; possible loop".to_string())
 }
 
-/// Helper functions used inside VM:
+fn rawset_value(h: &mut Table, key: &LuaValue, val: &LuaValue) -> Result<(), String> {
+    h.check_writable().map_err(str::to_string)?;
+    match (key, val) {
+        (LuaValue::Nil, _) => return Err("table index is nil".to_string()),
+        (_, LuaValue::Nil) => { h.remove(key); }
+        _ => h.set(key, val.clone()),
+    }
+    Ok(())
+}
 
-/// Get a value from a Lua table (simplified)
-unsafe fn luaH_get(L: *mut lua_State, table: *const TValue, key: &str) -> TValue {
-    // Implement hash table lookup
-    unimplemented!()
+/// t[key] with __index, for values outside the VM registers
+pub fn luaV_finishget(L: &mut LuaState, t: &LuaValue, key: &LuaValue) -> LuaValue {
+    let r = luaV_index_chain(t, key, |v| get_value_tm(v, TMS::Index), |f, t| {
+        call_tm_vm(L, f, &[t.clone(), key.clone()]).unwrap_or(LuaValue::Nil)
+    });
+    match r {
+        Ok(v) => v,
+        Err(msg) => L.throw(LuaValue::Str(msg)),
+    }
 }
 
-/// Set a value in a Lua table (simplified)
-unsafe fn luaH_set(L: *mut lua_State, table: *mut TValue, key: &str, val: *const TValue) {
-    // Implement hash table insertion or update
-    unimplemented!()
+/// t[key] = val with __newindex, for values outside the VM registers
+pub fn luaV_finishset(L: &mut LuaState, t: &LuaValue, key: &LuaValue, val: &LuaValue) {
+    let r = luaV_newindex_chain(t, key, val, |v| get_value_tm(v, TMS::NewIndex), |f, t| {
+        call_tm_vm(L, f, &[t.clone(), key.clone(), val.clone()]);
+    });
+    if let Err(msg) = r {
+        L.throw(LuaValue::Str(msg));
+    }
 }
 
-/// Call a Lua function with n_args arguments and expect n_results results.
-unsafe fn luaD_call(L: *mut lua_State, func: *mut TValue, n_args: usize, n_results: usize) {
-    // Setup new call frame and execute function
-    unimplemented!()
+/// Option for multiple returns in CALL ('C' == 0)
+pub const LUA_MULTRET: c_int = -1;
+
+/// Registers a Lua function may use (MAXREGS in lcode.c)
+pub const MAXREGS: usize = 255;
+
+/// Call the value at `func` with the `n_args` registers above it and write
+/// its results back from `func`: `n_results` of them, or all of them with
+/// LUA_MULTRET, which leaves 'top' after the last one. The call goes
+/// through the API state (LuaState::call) like any other, so a yield
+/// inside it suspends this frame with the rest of the coroutine's stack
+/// and the call returns once the coroutine is resumed.
+unsafe fn luaD_call(L: *mut lua_State, func: *mut TValue, n_args: usize, n_results: c_int) {
+    let state = api_state(L);
+    let first = state.stack.len();
+    for r in 0..=n_args {
+        state.push(lua_value(func.add(r)));
+    }
+    state.call(n_args, n_results);
+    let results: Vec<LuaValue> = state.stack.drain(first..).collect();
+    if func.add(results.len()) > (*(*L).ci).top {
+        state.throw(LuaValue::Str("stack overflow".to_string()));
+    }
+    for (r, v) in results.iter().enumerate() {
+        *func.add(r) = TValue::from_lua(v);
+    }
+    if n_results == LUA_MULTRET {
+        (*L).top = func.add(results.len());
+    }
 }
 
-/// Return from a Lua function call.
+/// Return from a Lua function call: move the `n_results` values at
+/// `first_result` down to the frame's function slot, where the caller
+/// reads them, and leave 'top' after them
 unsafe fn luaD_return(L: *mut lua_State, first_result: *mut TValue, n_results: usize) {
-    // Handle function return and stack cleanup
-    unimplemented!()
+    let func = (*(*L).ci).func;
+    ptr::copy(first_result, func, n_results);
+    (*L).top = func.add(n_results);
 }
 use std::ptr;
 use std::ffi::CString;
 
-pub type lua_Number = f64;
+pub type lua_Number = crate::skylaconf::LuaFloat;
+pub type lua_Integer = crate::skylaconf::LuaInteger;
 
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub enum LuaType {
     Nil,
     Boolean,
+    Integer,
     Number,
     String,
     Table,
     Function,
+    Thread,
     // ... more types as needed
 }
 
@@ -148,6 +1275,7 @@ pub struct TValue {
 #[derive(Clone, Copy)]
 pub union TValueValue {
     pub b: bool,
+    pub i: lua_Integer,
     pub n: lua_Number,
     pub s: *const i8,
     pub p: *mut std::ffi::c_void, // generic pointer for tables/functions etc.
@@ -166,6 +1294,12 @@ impl TValue {
             value: TValueValue { b },
         }
     }
+    pub fn from_integer(i: lua_Integer) -> Self {
+        TValue {
+            tt: LuaType::Integer,
+            value: TValueValue { i },
+        }
+    }
     pub fn from_number(n: lua_Number) -> Self {
         TValue {
             tt: LuaType::Number,
@@ -178,13 +1312,298 @@ impl TValue {
             value: TValueValue { s },
         }
     }
+    /// Register copy of a table entry. Strings are kept in the VM string
+    /// pool, which owns them for the life of the thread.
+    pub fn from_lua(v: &LuaValue) -> Self {
+        match v {
+            LuaValue::Bool(b) => TValue::from_bool(*b),
+            LuaValue::Int(i) => TValue::from_integer(*i),
+            LuaValue::Float(n) => TValue::from_number(*n),
+            LuaValue::Str(s) => TValue::from_string(vm_string(s)),
+            LuaValue::Table(t) => {
+                register_table(t);
+                TValue { tt: LuaType::Table, value: TValueValue { p: t.as_ptr() as *mut std::ffi::c_void } }
+            }
+            LuaValue::Function(_) => {
+                let p = match closure_of(v) {
+                    Some(cl) => cl.as_ptr() as *mut std::ffi::c_void,
+                    None => register_object(v),
+                };
+                TValue { tt: LuaType::Function, value: TValueValue { p } }
+            }
+            LuaValue::Thread(_) => TValue { tt: LuaType::Thread, value: TValueValue { p: register_object(v) } },
+            _ => TValue::nil(),
+        }
+    }
+    /// Table key or value for this register, if it has a LuaValue form
+    /// (tables come back through the table registry, Rust functions and
+    /// threads through the object registry, Lua closures through
+    /// closure_value)
+    ///
+    /// # Safety
+    /// A string value must point to a live NUL-terminated string.
+    pub unsafe fn to_lua(&self) -> Option<LuaValue> {
+        match self.tt {
+            LuaType::Nil => Some(LuaValue::Nil),
+            LuaType::Boolean => Some(LuaValue::Bool(self.value.b)),
+            LuaType::Integer => Some(LuaValue::Int(self.value.i)),
+            LuaType::Number => Some(LuaValue::Float(self.value.n)),
+            LuaType::String => Some(LuaValue::Str(std::ffi::CStr::from_ptr(self.value.s).to_string_lossy().into_owned())),
+            LuaType::Function => object_value(self.value.p).or_else(|| NonNull::new(self.value.p as *mut Closure).map(closure_value)),
+            LuaType::Thread => object_value(self.value.p),
+            LuaType::Table => table_value(self.value.p as *mut Table),
+        }
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self.tt {
+            LuaType::Nil => "nil",
+            LuaType::Boolean => "boolean",
+            LuaType::Integer | LuaType::Number => "number",
+            LuaType::String => "string",
+            LuaType::Table => "table",
+            LuaType::Function => "function",
+            LuaType::Thread => "thread",
+        }
+    }
+    /// Copy of this value for the debug hooks
+    pub unsafe fn to_debug_value(&self) -> DebugValue {
+        match self.tt {
+            LuaType::Nil => DebugValue::Nil,
+            LuaType::Boolean => DebugValue::Bool(self.value.b),
+            LuaType::Integer => DebugValue::Integer(self.value.i),
+            LuaType::Number => DebugValue::Number(self.value.n),
+            LuaType::String => DebugValue::Str(std::ffi::CStr::from_ptr(self.value.s).to_string_lossy().into_owned()),
+            LuaType::Table => DebugValue::Other(format!("table: {:p}", self.value.p)),
+            LuaType::Function => DebugValue::Other(format!("function: {:p}", self.value.p)),
+            LuaType::Thread => DebugValue::Other(format!("thread: {:p}", self.value.p)),
+        }
+    }
+}
+
+thread_local! {
+    static VM_STRINGS: std::cell::RefCell<std::collections::HashMap<String, CString>> =
+        std::cell::RefCell::new(std::collections::HashMap::new());
+}
+
+/// NUL-terminated copy of `s` owned by the VM string pool (cut at an
+/// embedded zero, which register strings cannot hold)
+pub(crate) fn vm_string(s: &str) -> *const i8 {
+    VM_STRINGS.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if let Some(c) = pool.get(s) {
+            return c.as_ptr();
+        }
+        let c = CString::new(s.split('\0').next().unwrap_or("")).unwrap();
+        let p = c.as_ptr();
+        pool.insert(s.to_string(), c);
+        p
+    })
+}
+
+thread_local! {
+    static VM_TABLES: std::cell::RefCell<Vec<std::rc::Rc<std::cell::RefCell<Table>>>> = const { std::cell::RefCell::new(Vec::new()) };
+    // Table value behind each register table, by the address of its Table
+    static REG_TABLES: std::cell::RefCell<std::collections::HashMap<usize, std::rc::Weak<std::cell::RefCell<Table>>>> =
+        std::cell::RefCell::new(std::collections::HashMap::new());
+}
+
+/// Table created by the VM (NEWTABLE), owned by the VM table pool for
+/// the life of the thread
+pub(crate) fn vm_table(t: Table) -> *mut Table {
+    let t = std::rc::Rc::new(std::cell::RefCell::new(t));
+    register_table(&t);
+    let p = t.as_ptr();
+    VM_TABLES.with(|pool| pool.borrow_mut().push(t));
+    p
+}
+
+/// Record `t` so a register holding its Table can be turned back into the
+/// same table value. An address reused by a later table is re-registered
+/// before any register can hold it.
+fn register_table(t: &std::rc::Rc<std::cell::RefCell<Table>>) {
+    REG_TABLES.with(|m| {
+        m.borrow_mut().insert(t.as_ptr() as usize, std::rc::Rc::downgrade(t));
+    });
+}
+
+/// Table value of a register table, if the table is still alive
+fn table_value(h: *mut Table) -> Option<LuaValue> {
+    REG_TABLES.with(|m| m.borrow().get(&(h as usize)).and_then(std::rc::Weak::upgrade)).map(LuaValue::Table)
+}
+
+thread_local! {
+    // Rust functions and threads that went through a register, by their
+    // identity; like the VM tables they are kept for the life of the thread
+    static REG_OBJECTS: std::cell::RefCell<std::collections::HashMap<usize, LuaValue>> =
+        std::cell::RefCell::new(std::collections::HashMap::new());
+}
+
+/// Register form of the Rust function or thread `v`: its identity, which
+/// object_value maps back to `v`
+fn register_object(v: &LuaValue) -> *mut std::ffi::c_void {
+    let p = ObjectId::of(v).expect("functions and threads have an identity").as_ptr() as *mut std::ffi::c_void;
+    REG_OBJECTS.with(|m| m.borrow_mut().entry(p as usize).or_insert_with(|| v.clone()));
+    p
+}
+
+/// Rust function or thread of a register, None for Lua closures
+fn object_value(p: *mut std::ffi::c_void) -> Option<LuaValue> {
+    REG_OBJECTS.with(|m| m.borrow().get(&(p as usize)).cloned())
+}
+
+thread_local! {
+    // Lua closure behind each function value made by closure_value, by the
+    // identity of that value
+    static API_CLOSURES: std::cell::RefCell<std::collections::HashMap<ObjectId, NonNull<Closure>>> =
+        std::cell::RefCell::new(std::collections::HashMap::new());
+}
+
+/// Removes a function value's entry from API_CLOSURES when the value is
+/// dropped (set once the value exists and its identity is known)
+struct ClosureOwner(std::cell::Cell<Option<ObjectId>>);
+
+impl Drop for ClosureOwner {
+    fn drop(&mut self) {
+        if let Some(id) = self.0.get() {
+            // other thread-locals may be gone already at thread exit
+            let _ = API_CLOSURES.try_with(|c| c.borrow_mut().remove(&id));
+        }
+    }
+}
+
+/// Function value for the Lua closure `cl`, as the API sees it: calling it
+/// runs `cl` (luaV_callclosure), and closure_of gives `cl` back, so the
+/// debug API can reach its upvalues
+pub fn closure_value(cl: NonNull<Closure>) -> LuaValue {
+    let owner = std::rc::Rc::new(ClosureOwner(std::cell::Cell::new(None)));
+    let f = LuaValue::Function(Box::new({
+        let owner = owner.clone();
+        move |L: &mut LuaState| {
+            let _ = &owner;
+            unsafe { luaV_callclosure(L, cl) }
+        }
+    }));
+    let id = ObjectId::of(&f).expect("functions have an identity");
+    API_CLOSURES.with(|c| c.borrow_mut().insert(id, cl));
+    owner.0.set(Some(id));
+    f
+}
+
+/// Lua closure of a function value made by closure_value; None for Rust
+/// functions and every other value
+pub fn closure_of(v: &LuaValue) -> Option<NonNull<Closure>> {
+    let id = ObjectId::of(v)?;
+    API_CLOSURES.with(|c| c.borrow().get(&id).copied())
+}
+
+/// Run the Lua closure `cl` with the running Rust function's arguments and
+/// push its results, returning how many: the arguments go into registers
+/// above a slot holding `cl`, laid out as OP_CALL leaves them, and the
+/// closure runs in a frame of its own (luaD_precall)
+///
+/// # Safety
+/// `cl` and its prototype must be live.
+pub unsafe fn luaV_callclosure(L: &mut LuaState, cl: NonNull<Closure>) -> c_int {
+    let p = (*cl.as_ptr()).cl.p;
+    let nargs = L.get_top() as usize;
+    let nparams = (*p).numparams as usize;
+    let mut regs: Vec<TValue> = Vec::with_capacity(nargs + 1 + MAXREGS);
+    regs.push(TValue { tt: LuaType::Function, value: TValueValue { p: cl.as_ptr() as *mut std::ffi::c_void } });
+    regs.extend((1..=nargs as i32).map(|i| TValue::from_lua(&L.to_value(i))));
+    regs.extend((0..MAXREGS).map(|_| TValue::nil()));
+    let func = regs.as_mut_ptr();
+    let base = if (*p).is_vararg {
+        // the fixed parameters move above the arguments, leaving the extra
+        // ones between the function and its registers for VARARG
+        let base = func.add(1 + nargs);
+        for j in 0..nparams.min(nargs) {
+            *base.add(j) = *func.add(1 + j);
+            *func.add(1 + j) = TValue::nil();
+        }
+        base
+    } else {
+        for j in nparams..nargs {
+            *func.add(1 + j) = TValue::nil(); // extra arguments are dropped
+        }
+        func.add(1)
+    };
+    let mut ci = CallInfo {
+        func,
+        base,
+        top: func.add(regs.len()),
+        u: CallInfoUnion { l: CallInfoL { savedpc: (*p).code.as_ptr() } },
+    };
+    let env = TValue::from_lua(&LuaValue::Table(L.globals_table()));
+    let mut vm = lua_State { ci: &mut ci, top: base, l_env: env, state: &mut *L };
+    luaV_execute(&mut vm);
+    let nresults = vm.top.offset_from(func) as usize;
+    for r in &regs[..nresults] {
+        L.push(r.to_lua().unwrap_or(LuaValue::Nil));
+    }
+    nresults as c_int
+}
+
+// Upvalue: a variable captured by closures. Every closure capturing the
+// same variable holds the same UpVal, so a write through one is seen by
+// all of them; 'v' points at the variable's register while it is open and
+// at 'value' once it is closed
+#[repr(C)]
+pub struct UpVal {
+    pub v: *mut TValue,
+    pub value: TValue,
+}
+
+impl UpVal {
+    /// New closed upvalue holding `value`
+    pub fn closed(value: TValue) -> NonNull<UpVal> {
+        let uv = Box::into_raw(Box::new(UpVal { v: std::ptr::null_mut(), value }));
+        unsafe {
+            (*uv).v = &mut (*uv).value;
+            NonNull::new_unchecked(uv)
+        }
+    }
+    /// Slot holding the variable's current value
+    pub fn val(&self) -> *mut TValue {
+        self.v
+    }
 }
 
 // Lua function closure
 #[repr(C)]
 pub struct Closure {
     pub cl: ClosureType,
-    pub upvals: *mut TValue,  // pointer to upvalues (simplified)
+    pub upvals: Vec<NonNull<UpVal>>, // shared with other closures of the same variables
+}
+
+impl Closure {
+    /// Name and value slot of upvalue `n` (1-based), as lua_getupvalue
+    /// reports them: the captured variable's name, or "(no name)" when the
+    /// prototype was stripped of debug info
+    ///
+    /// # Safety
+    /// The closure's prototype and upvalues must be live.
+    pub unsafe fn upvalue(&self, n: usize) -> Option<(&str, *mut TValue)> {
+        let uv = self.upvals.get(n.wrapping_sub(1))?;
+        let name = (*self.cl.p).upvalnames.get(n - 1).map_or("(no name)", String::as_str);
+        Some((name, uv.as_ref().val()))
+    }
+    /// Upvalue `n` (1-based), which is also its identity: closures sharing
+    /// the variable hold the same one, which is what lets serializers
+    /// rebuild that sharing
+    pub fn upvalue_id(&self, n: usize) -> Option<NonNull<UpVal>> {
+        self.upvals.get(n.wrapping_sub(1)).copied()
+    }
+    /// Make upvalue `n` of this closure be `uv`, another closure's upvalue
+    /// (lua_upvaluejoin); false if `n` is out of range
+    pub fn join_upvalue(&mut self, n: usize, uv: NonNull<UpVal>) -> bool {
+        match self.upvals.get_mut(n.wrapping_sub(1)) {
+            Some(slot) => {
+                *slot = uv;
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 #[repr(C)]
@@ -197,17 +1616,87 @@ pub union ClosureType {
 pub struct Proto {
     pub code: Vec<Instruction>,
     pub k: Vec<TValue>, // constants
+    pub lineinfo: crate::ldebug::LineInfo, // source line of each instruction (debug info)
+    pub numparams: u8,   // number of fixed parameters
+    pub is_vararg: bool, // declared with '...'
+    pub source: String,  // chunkname ("@file.lua", "=stdin", ...)
+    pub linedefined: u32,     // line where the function starts (0 for a main chunk)
+    pub lastlinedefined: u32, // line where it ends
+    pub locvars: Vec<LocVar>,     // local variable names and live ranges (debug info)
+    pub upvalnames: Vec<String>,  // upvalue names (debug info)
+    pub decoded: Vec<Decoded>,    // 'code' pre-decoded by luaV_predecode (empty until first run)
+    pub slot_cache: Vec<SlotCache>, // GETTABLE/SETTABLE inline caches, one per instruction
+
     // ... other fields like debug info, upvalues, etc.
 }
 
+// Description of a local variable for function prototypes (debug info)
+#[repr(C)]
+pub struct LocVar {
+    pub varname: String,
+    pub startpc: usize, // first point where variable is active
+    pub endpc: usize,   // first point where variable is dead
+}
+
+impl Proto {
+    /// Name of the n-th (1-based) local active at instruction `pc`
+    /// (luaF_getlocalname); that local lives in register n - 1
+    pub fn getlocalname(&self, n: usize, pc: usize) -> Option<&str> {
+        self.locvars.iter()
+            .filter(|v| v.startpc <= pc && pc < v.endpc)
+            .nth(n.checked_sub(1)?)
+            .map(|v| v.varname.as_str())
+    }
+}
+
+// Source line of the instruction just fetched ('pc' already advanced), 0 if unknown
+#[inline]
+unsafe fn pcline(p: *const Proto, pc: *const Instruction) -> u32 {
+    let pcidx = pc.offset_from((*p).code.as_ptr()) as usize - 1;
+    (*p).lineinfo.get(pcidx).unwrap_or(0)
+}
+
+// Snapshot of the running frame for the line hook: active locals (as in
+// luaF_getlocalname, the n-th active local lives in register n) and upvalues
+unsafe fn debug_frame(cl: *mut Closure, base: *mut TValue, pc: *const Instruction) -> DebugFrame {
+    let p = (*cl).cl.p;
+    let pcidx = pc.offset_from((*p).code.as_ptr()) as usize - 1;
+    let locals = (*p).locvars.iter()
+        .filter(|v| v.startpc <= pcidx && pcidx < v.endpc)
+        .enumerate()
+        .map(|(reg, v)| (v.varname.clone(), (*base.add(reg)).to_debug_value()))
+        .collect();
+    let upvalues = (*p).upvalnames.iter()
+        .zip(&(*cl).upvals)
+        .map(|(name, uv)| (name.clone(), (*uv.as_ref().val()).to_debug_value()))
+        .collect();
+    DebugFrame { source: (*p).source.clone(), line: 0, depth: 0, locals, upvalues }
+}
+
 // Lua call frame
 #[repr(C)]
 pub struct CallInfo {
     pub func: *mut TValue,
+    pub base: *mut TValue, // first register; varargs live between 'func' and 'base'
     pub top: *mut TValue,
     pub u: CallInfoUnion,
 }
 
+impl CallInfo {
+    /// Line of the instruction this Lua frame is running (savedpc is
+    /// already past it); 0 before its first instruction or without line
+    /// information
+    pub unsafe fn currentline(&self) -> u32 {
+        let cl = (*self.func).value.p as *mut Closure;
+        let p = (*cl).cl.p;
+        let pc = self.u.l.savedpc;
+        if pc.is_null() || pc <= (*p).code.as_ptr() {
+            return 0;
+        }
+        pcline(p, pc)
+    }
+}
+
 #[repr(C)]
 pub union CallInfoUnion {
     pub l: CallInfoL,
@@ -224,6 +1713,8 @@ pub struct lua_State {
     pub ci: *mut CallInfo,
     pub top: *mut TValue,
     pub l_env: TValue,
+    /// API state the VM runs for; metamethods and errors go through it
+    pub state: *mut LuaState,
     // ... other Lua VM state fields
 }
 #[repr(transparent)]
@@ -239,12 +1730,13 @@ impl Instruction {
         ((self.0 >> 6) & 0xFF) as u8
     }
 
-    pub fn get_arg_b(&self) -> u8 {
-        ((self.0 >> 23) & 0x1FF) as u8
+    // B and C are 9 bits wide: the high bit marks a constant (BITRK)
+    pub fn get_arg_b(&self) -> u16 {
+        ((self.0 >> 23) & 0x1FF) as u16
     }
 
-    pub fn get_arg_c(&self) -> u8 {
-        ((self.0 >> 14) & 0x1FF) as u8
+    pub fn get_arg_c(&self) -> u16 {
+        ((self.0 >> 14) & 0x1FF) as u16
     }
 
     pub fn get_arg_bx(&self) -> u32 {
@@ -255,7 +1747,7 @@ impl Instruction {
         (self.get_arg_bx() as i32) - 131071 // bias for signed offset
     }
 
-    pub fn encode_abc(opcode: OpCode, a: u8, b: u8, c: u8) -> Instruction {
+    pub fn encode_abc(opcode: OpCode, a: u8, b: u16, c: u16) -> Instruction {
         Instruction(
             (opcode as u32)
                 | ((a as u32) << 6)
@@ -267,6 +1759,10 @@ impl Instruction {
     pub fn encode_abx(opcode: OpCode, a: u8, bx: u32) -> Instruction {
         Instruction((opcode as u32) | ((a as u32) << 6) | (bx << 14))
     }
+
+    pub fn encode_asbx(opcode: OpCode, a: u8, sbx: i32) -> Instruction {
+        Self::encode_abx(opcode, a, (sbx + 131071) as u32)
+    }
 }
 
 #[repr(u8)]
@@ -281,22 +1777,89 @@ pub enum OpCode {
     SETGLOBAL = 6,
     CALL = 7,
     RETURN = 8,
+    SETTABLE = 9,
+    VARARG = 10,
+    ADD = 11,
+    SUB = 12,
+    MUL = 13,
+    DIV = 14,
+    MOD = 15,
+    POW = 16,
+    UNM = 17,
+    JMP = 18,
+    EQ = 19,
+    LT = 20,
+    LE = 21,
+    TFORCALL = 22,
+    TFORLOOP = 23,
+    GETTABLE = 24,
+    CALLI = 25,
+    NEWTABLE = 26,
+    SETLIST = 27,
+    SELF = 28,
     // ... add all Lua opcodes as needed
 }
 
+/// Number of opcodes; opcodes are dense in 0..NUM_OPCODES
+pub const NUM_OPCODES: usize = 29;
+
+/// Opcode for each byte value, indexed by discriminant
+static OPCODES: [OpCode; NUM_OPCODES] = [
+    OpCode::MOVE,
+    OpCode::LOADK,
+    OpCode::LOADBOOL,
+    OpCode::LOADNIL,
+    OpCode::GETUPVAL,
+    OpCode::GETGLOBAL,
+    OpCode::SETGLOBAL,
+    OpCode::CALL,
+    OpCode::RETURN,
+    OpCode::SETTABLE,
+    OpCode::VARARG,
+    OpCode::ADD,
+    OpCode::SUB,
+    OpCode::MUL,
+    OpCode::DIV,
+    OpCode::MOD,
+    OpCode::POW,
+    OpCode::UNM,
+    OpCode::JMP,
+    OpCode::EQ,
+    OpCode::LT,
+    OpCode::LE,
+    OpCode::TFORCALL,
+    OpCode::TFORLOOP,
+    OpCode::GETTABLE,
+    OpCode::CALLI,
+    OpCode::NEWTABLE,
+    OpCode::SETLIST,
+    OpCode::SELF,
+];
+
 impl OpCode {
+    pub fn try_from_u8(byte: u8) -> Option<OpCode> {
+        OPCODES.get(byte as usize).copied()
+    }
+
     pub fn from_u8(byte: u8) -> OpCode {
-        match byte {
-            0 => OpCode::MOVE,
-            1 => OpCode::LOADK,
-            2 => OpCode::LOADBOOL,
-            3 => OpCode::LOADNIL,
-            4 => OpCode::GETUPVAL,
-            5 => OpCode::GETGLOBAL,
-            6 => OpCode::SETGLOBAL,
-            7 => OpCode::CALL,
-            8 => OpCode::RETURN,
-            _ => panic!("Unknown opcode {}", byte),
+        Self::try_from_u8(byte).unwrap_or_else(|| panic!("Unknown opcode {}", byte))
+    }
+
+    /// Whether B and C are RK operands, which may name a constant
+    pub fn rk_operands(self) -> (bool, bool) {
+        match self {
+            OpCode::SETTABLE
+            | OpCode::ADD
+            | OpCode::SUB
+            | OpCode::MUL
+            | OpCode::DIV
+            | OpCode::MOD
+            | OpCode::POW
+            | OpCode::EQ
+            | OpCode::LT
+            | OpCode::LE => (true, true),
+            OpCode::GETTABLE | OpCode::SELF => (false, true),
+            _ => (false, false),
         }
     }
 }
@@ -317,3 +1880,493 @@ pub unsafe fn luaL_openlibs(L: *mut lua_State) {
 
     // ... open other libs ...
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcode_table_is_dense() {
+        for byte in 0..NUM_OPCODES as u8 {
+            assert_eq!(OpCode::try_from_u8(byte).map(|op| op as u8), Some(byte));
+        }
+        assert_eq!(OpCode::try_from_u8(NUM_OPCODES as u8), None);
+        assert_eq!(DISPATCH_TABLE.len(), NUM_OPCODES);
+    }
+
+    #[test]
+    fn test_mixed_number_comparison_is_exact() {
+        let big: lua_Integer = (1 << 53) + 1;
+        let f = (1u64 << 53) as lua_Number; // big rounds to f as a float
+        assert!(Num::Flt(f).lt(Num::Int(big)) && !Num::Int(big).le(Num::Flt(f)));
+        assert!(!Num::Int(big).eq(Num::Flt(f)) && Num::Int(1 << 53).eq(Num::Flt(f)));
+        assert!(Num::Int(lua_Integer::MAX).lt(Num::Flt(lua_Number::INFINITY)));
+        assert!(Num::Flt(-1e300).le(Num::Int(lua_Integer::MIN)));
+        assert!(!Num::Int(0).lt(Num::Flt(lua_Number::NAN)) && !Num::Flt(lua_Number::NAN).le(Num::Int(0)));
+        assert!(Num::Flt(2.5).lt(Num::Int(3)) && Num::Int(2).le(Num::Flt(2.0)));
+    }
+
+    #[test]
+    fn test_compare_opcodes() {
+        let (a, b) = (std::ffi::CString::new("a\u{ff}").unwrap(), std::ffi::CString::new("ab").unwrap());
+        let (sa, sb) = (TValue::from_string(a.as_ptr()), TValue::from_string(b.as_ptr()));
+        unsafe {
+            assert_eq!(luaV_rawcompare(OpCode::LT, &sb, &sa, false), Some(true));
+            assert_eq!(luaV_rawcompare(OpCode::LE, &sa, &sa, false), Some(true));
+            assert_eq!(luaV_rawcompare(OpCode::EQ, &TValue::from_integer(1), &TValue::from_number(1.0), false), Some(true));
+            assert_eq!(luaV_rawcompare(OpCode::EQ, &TValue::from_integer(1), &sa, false), Some(false));
+            assert_eq!(luaV_rawcompare(OpCode::LT, &TValue::nil(), &TValue::from_integer(1), false), None);
+        }
+        assert_eq!(luaG_ordererror("number", "nil"), "attempt to compare number with nil");
+        // through the opcode: ordering nil raises an error on the state
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut api = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        let mut vm = lua_State { ci: std::ptr::null_mut(), top: std::ptr::null_mut(), l_env: TValue::nil(), state: &mut api };
+        let mut regs = [TValue::nil(), TValue::from_integer(1)];
+        let mut f = Frame {
+            L: &mut vm,
+            ci: std::ptr::null_mut(),
+            cl: std::ptr::null_mut(),
+            base: regs.as_mut_ptr(),
+            pc: std::ptr::null(),
+            dpc: std::ptr::null(),
+            iters: Vec::new(),
+        };
+        let lt = Decoded::new(Instruction::encode_abc(OpCode::LT, 1, 0, 1)).unwrap();
+        let r = api.pcall(|_| unsafe { dispatch(&mut f, &lt) });
+        assert!(r.is_err());
+        assert_eq!(l_strcmp(b"a\0b", b"a\0c", true), std::cmp::Ordering::Less);
+        assert_eq!(l_strcmp(b"a\0", b"a", true), std::cmp::Ordering::Greater);
+    }
+
+    #[test]
+    fn test_tforcall_fast_paths() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let t = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        for i in 1..=3 {
+            t.borrow_mut().set(&LuaValue::Int(i), LuaValue::Int(i * 10));
+        }
+        t.borrow_mut().set(&LuaValue::Str("x".to_string()), LuaValue::Int(100));
+        let (mut next_fn, mut inext_fn) = (0u8, 0u8);
+        let next = &mut next_fn as *mut u8 as *mut std::ffi::c_void;
+        let inext = &mut inext_fn as *mut u8 as *mut std::ffi::c_void;
+        luaV_setiterators(next, inext);
+        let func = |p| TValue { tt: LuaType::Function, value: TValueValue { p } };
+        let mut regs = [func(next), TValue::from_lua(&LuaValue::Table(t.clone())), TValue::nil(), TValue::nil(), TValue::nil()];
+        let base = regs.as_mut_ptr();
+        let mut f = Frame {
+            L: std::ptr::null_mut(),
+            ci: std::ptr::null_mut(),
+            cl: std::ptr::null_mut(),
+            base,
+            pc: std::ptr::null(),
+            dpc: std::ptr::null(),
+            iters: Vec::new(),
+        };
+        let mut sum = 0;
+        unsafe {
+            // for k, v in next, t do sum = sum + v end
+            loop {
+                assert!(tforcall_fast(&mut f, 0, 2));
+                if matches!((*base.add(3)).tt, LuaType::Nil) {
+                    break;
+                }
+                *base.add(2) = *base.add(3);
+                sum += (*base.add(4)).value.i;
+            }
+            assert_eq!(sum, 160);
+            assert!(f.iters.is_empty());
+            // for i, v in ipairs(t): stops before the hash part
+            *base = func(inext);
+            *base.add(2) = TValue::from_integer(0);
+            let mut n = 0;
+            while tforcall_fast(&mut f, 0, 2) && !matches!((*base.add(3)).tt, LuaType::Nil) {
+                *base.add(2) = *base.add(3);
+                n += 1;
+            }
+            assert_eq!(n, 3);
+            // any other iterator is called
+            *base = func(std::ptr::null_mut());
+            assert!(!tforcall_fast(&mut f, 0, 2));
+        }
+    }
+
+    #[test]
+    fn test_table_slot_fast_paths() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let t = Rc::new(RefCell::new(Table::new()));
+        let x = CString::new("x").unwrap();
+        let mut p = Proto {
+            code: vec![
+                Instruction::encode_abc(OpCode::SETTABLE, 0, BITRK as u16, 2), // t.x = R(2)
+                Instruction::encode_abc(OpCode::GETTABLE, 1, 0, BITRK as u16), // R(1) := t.x
+                Instruction::encode_abc(OpCode::GETTABLE, 1, 0, 3),            // R(1) := t[R(3)]
+            ],
+            k: vec![TValue::from_string(x.as_ptr())],
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        luaV_predecode(&mut p).unwrap();
+        let code = p.decoded.clone();
+        let p: *mut Proto = &mut p;
+        let mut cl = Closure { cl: ClosureType { p }, upvals: Vec::new() };
+        let mut regs = [TValue::from_lua(&LuaValue::Table(t.clone())), TValue::nil(), TValue::from_integer(7), TValue::from_integer(1)];
+        let mut f = Frame {
+            L: std::ptr::null_mut(),
+            ci: std::ptr::null_mut(),
+            cl: &mut cl,
+            base: regs.as_mut_ptr(),
+            pc: std::ptr::null(),
+            dpc: std::ptr::null(),
+            iters: Vec::new(),
+        };
+        let x = LuaValue::Str("x".to_string());
+        unsafe {
+            let run = |f: &mut Frame, pc: usize| {
+                f.dpc = (*p).decoded.as_ptr().add(pc + 1);
+                dispatch(f, &code[pc]);
+            };
+            // a new key goes through Table::set, which changes the layout
+            run(&mut f, 0);
+            assert_eq!(t.borrow().get(&x), Some(&LuaValue::Int(7)));
+            run(&mut f, 1);
+            assert_eq!(regs[1].value.i, 7);
+            assert_eq!((&(*p).slot_cache)[1].version, t.borrow().version());
+            // an existing key is written through the slot, layout unchanged
+            let version = t.borrow().version();
+            regs[2] = TValue::from_integer(8);
+            run(&mut f, 0);
+            run(&mut f, 1);
+            assert_eq!(regs[1].value.i, 8);
+            assert_eq!((t.borrow().version(), (&(*p).slot_cache)[0].version), (version, version));
+            // integer keys use the array part directly; absent keys read nil
+            run(&mut f, 2);
+            assert!(matches!(regs[1].tt, LuaType::Nil));
+            t.borrow_mut().set(&LuaValue::Int(1), LuaValue::Int(9));
+            run(&mut f, 2);
+            assert_eq!(regs[1].value.i, 9);
+        }
+    }
+
+    #[test]
+    fn test_table_fallbacks() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut api = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        let mut vm = lua_State { ci: std::ptr::null_mut(), top: std::ptr::null_mut(), l_env: TValue::nil(), state: &mut api };
+        let table = || Rc::new(RefCell::new(Table::new()));
+        let (t, proto, key) = (table(), table(), table());
+        let x = LuaValue::Str("x".to_string());
+        proto.borrow_mut().set(&x, LuaValue::Int(5));
+        let mt = table();
+        mt.borrow_mut().set(&LuaValue::Str("__index".to_string()), LuaValue::Table(proto.clone()));
+        api.set_value_metatable(&LuaValue::Table(t.clone()), LuaValue::Table(mt));
+        let (tv, xv) = (TValue::from_lua(&LuaValue::Table(t.clone())), TValue::from_lua(&x));
+        unsafe {
+            // a miss follows __index
+            assert_eq!(luaV_gettable(&mut vm, None, &tv, &xv).value.i, 5);
+            // table keys and values are stored raw and read back as the same tables
+            let mut tv = tv;
+            let kv = TValue::from_lua(&LuaValue::Table(key.clone()));
+            luaV_settable(&mut vm, None, &mut tv, &kv, &kv);
+            assert!(matches!(t.borrow().get(&LuaValue::Table(key.clone())), Some(LuaValue::Table(v)) if Rc::ptr_eq(v, &key)));
+            assert!(matches!(luaV_gettable(&mut vm, None, &tv, &kv).to_lua(), Some(LuaValue::Table(v)) if Rc::ptr_eq(&v, &key)));
+            // errors are raised through the state, not as panics
+            let nil = TValue::nil();
+            let r = api.pcall(|_| luaV_settable(&mut vm, None, &mut tv, &nil, &xv));
+            assert!(r.is_err());
+            t.borrow_mut().freeze();
+            let r = api.pcall(|_| luaV_settable(&mut vm, None, &mut tv, &xv, &xv));
+            assert!(r.is_err());
+        }
+    }
+
+    #[test]
+    fn test_self_matches_explicit_receiver() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let t = Rc::new(RefCell::new(Table::new()));
+        t.borrow_mut().set(&LuaValue::Str("area".to_string()), LuaValue::Int(12));
+        let name = CString::new("area").unwrap();
+        let mut p = Proto {
+            code: vec![
+                Instruction::encode_abc(OpCode::SELF, 1, 0, BITRK as u16),     // obj:area
+                Instruction::encode_abc(OpCode::GETTABLE, 3, 0, BITRK as u16), // obj.area
+                Instruction::encode_abc(OpCode::MOVE, 4, 0, 0),                // obj
+                Instruction::encode_abc(OpCode::SELF, 0, 0, BITRK as u16),     // receiver in R(A) itself
+            ],
+            k: vec![TValue::from_string(name.as_ptr())],
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        luaV_predecode(&mut p).unwrap();
+        let code = p.decoded.clone();
+        let p: *mut Proto = &mut p;
+        let mut cl = Closure { cl: ClosureType { p }, upvals: Vec::new() };
+        let obj = TValue::from_lua(&LuaValue::Table(t.clone()));
+        let mut regs = [obj, TValue::nil(), TValue::nil(), TValue::nil(), TValue::nil()];
+        let mut f = Frame {
+            L: std::ptr::null_mut(),
+            ci: std::ptr::null_mut(),
+            cl: &mut cl,
+            base: regs.as_mut_ptr(),
+            pc: std::ptr::null(),
+            dpc: std::ptr::null(),
+            iters: Vec::new(),
+        };
+        unsafe {
+            let run = |f: &mut Frame, pc: usize| {
+                f.dpc = (*p).decoded.as_ptr().add(pc + 1);
+                dispatch(f, &code[pc]);
+            };
+            for pc in 0..3 {
+                run(&mut f, pc);
+            }
+            assert_eq!((regs[1].value.i, regs[2].value.p), (regs[3].value.i, regs[4].value.p));
+            assert_eq!((regs[1].value.i, regs[2].value.p), (12, obj.value.p));
+            assert_eq!((&(*p).slot_cache)[0].version, t.borrow().version());
+            run(&mut f, 3);
+            assert_eq!((regs[0].value.i, regs[1].value.p), (12, obj.value.p));
+        }
+    }
+
+    #[test]
+    fn test_table_constructor_opcodes() {
+        // local t = {10, 20, 30, x = 1}
+        let code = [
+            Decoded::new(Instruction::encode_abc(OpCode::NEWTABLE, 0, luaO_codesize(3) as u16, luaO_codesize(1) as u16)).unwrap(),
+            Decoded::new(Instruction::encode_abc(OpCode::SETLIST, 0, 2, 0)).unwrap(),
+            Decoded::new(Instruction::encode_abc(OpCode::SETLIST, 0, 1, 2)).unwrap(),
+        ];
+        let mut regs = [TValue::nil(), TValue::from_integer(10), TValue::from_integer(20)];
+        let mut f = Frame {
+            L: std::ptr::null_mut(),
+            ci: std::ptr::null_mut(),
+            cl: std::ptr::null_mut(),
+            base: regs.as_mut_ptr(),
+            pc: std::ptr::null(),
+            dpc: std::ptr::null(),
+            iters: Vec::new(),
+        };
+        unsafe {
+            dispatch(&mut f, &code[0]);
+            assert!(matches!(regs[0].tt, LuaType::Table));
+            let t = regs[0].value.p as *mut Table;
+            let (narr, nrec) = (*t).capacity();
+            assert!(narr >= 3 && nrec >= 1 && (*t).lua_len() == 0);
+            // two batches, the second starting after the 2 items already stored
+            dispatch(&mut f, &code[1]);
+            regs[1] = TValue::from_integer(30);
+            dispatch(&mut f, &code[2]);
+            assert_eq!((*t).lua_len(), 3);
+            assert_eq!((*t).get(&LuaValue::Int(3)), Some(&LuaValue::Int(30)));
+            // presized: filling the list did not grow the array part
+            assert_eq!((*t).capacity().0, narr);
+        }
+    }
+
+    #[test]
+    fn test_index_chains_stop_at_maxtagloop() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let table = || Rc::new(RefCell::new(Table::new()));
+        let (a, b) = (table(), table());
+        let key = LuaValue::Str("k".to_string());
+        b.borrow_mut().set(&key, LuaValue::Int(7));
+        // a's metatable is a itself, with __index = a and __newindex = a
+        let self_ref = |v: &LuaValue| match v {
+            LuaValue::Table(h) if Rc::ptr_eq(h, &a) => Some(LuaValue::Table(a.clone())),
+            _ => None,
+        };
+        let no_call = |_: &LuaValue, _: &LuaValue| unreachable!();
+        assert_eq!(luaV_index_chain(&LuaValue::Table(a.clone()), &key, self_ref, no_call).unwrap_err(),
+            "'__index' chain too long; possible loop");
+        assert_eq!(luaV_newindex_chain(&LuaValue::Table(a.clone()), &key, &LuaValue::Int(1), self_ref, |_, _| unreachable!()).unwrap_err(),
+            "'__newindex' chain too long; possible loop");
+        // a -> b: the lookup finds b's field and the store lands in b
+        let to_b = |v: &LuaValue| match v {
+            LuaValue::Table(h) if Rc::ptr_eq(h, &a) => Some(LuaValue::Table(b.clone())),
+            _ => None,
+        };
+        assert!(matches!(luaV_index_chain(&LuaValue::Table(a.clone()), &key, to_b, no_call), Ok(LuaValue::Int(7))));
+        let other = LuaValue::Str("other".to_string());
+        luaV_newindex_chain(&LuaValue::Table(a.clone()), &other, &LuaValue::Int(2), to_b, |_, _| unreachable!()).unwrap();
+        assert!(a.borrow().get(&other).is_none() && matches!(b.borrow().get(&other), Some(LuaValue::Int(2))));
+        // a present key is stored raw even with __newindex
+        a.borrow_mut().set(&key, LuaValue::Int(0));
+        luaV_newindex_chain(&LuaValue::Table(a.clone()), &key, &LuaValue::Int(3), self_ref, |_, _| unreachable!()).unwrap();
+        assert!(matches!(a.borrow().get(&key), Some(LuaValue::Int(3))));
+        // a function __index is called with the table it hangs off
+        let f = |v: &LuaValue| match v {
+            LuaValue::Table(h) if Rc::ptr_eq(h, &a) => Some(LuaValue::Function(Box::new(|_: &mut LuaState| 0))),
+            _ => None,
+        };
+        let called = luaV_index_chain(&LuaValue::Table(a.clone()), &other, f, |_, t| {
+            LuaValue::Bool(matches!(t, LuaValue::Table(h) if Rc::ptr_eq(h, &a)))
+        });
+        assert!(matches!(called, Ok(LuaValue::Bool(true))));
+        assert_eq!(luaV_index_chain(&LuaValue::Int(1), &key, |_| None, no_call).unwrap_err(), "attempt to index a number value");
+    }
+
+    #[test]
+    fn test_predecode() {
+        let mut p = Proto {
+            code: vec![
+                Instruction::encode_abc(OpCode::ADD, 1, 2, BITRK as u16 | 3),
+                Instruction::encode_asbx(OpCode::JMP, 0, -2),
+            ],
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        luaV_predecode(&mut p).unwrap();
+        assert_eq!((p.decoded[0].op, p.decoded[0].a, p.decoded[0].b, p.decoded[0].c), (OpCode::ADD, 1, 2, 3));
+        assert_eq!((p.decoded[0].kb, p.decoded[0].kc), (false, true));
+        assert_eq!((p.decoded[1].op, p.decoded[1].sbx), (OpCode::JMP, -2));
+
+        p.code.push(Instruction(63));
+        assert_eq!(luaV_predecode(&mut p), Err("invalid opcode 63 at pc 2".to_string()));
+    }
+
+    #[test]
+    fn test_yield_inside_a_for_iterator() {
+        use crate::lapi::{lua_newthread, lua_resume, lua_settop, lua_yield, LUA_OK, LUA_YIELD};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        // function(f, s, c) for c in f, s, c do end return c end
+        let mut p = Proto {
+            code: vec![
+                Instruction::encode_abc(OpCode::TFORCALL, 0, 0, 1),
+                Instruction::encode_asbx(OpCode::TFORLOOP, 2, -2),
+                Instruction::encode_abc(OpCode::RETURN, 2, 2, 0),
+            ],
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 3,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: Vec::new(),
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let p: *mut Proto = &mut p;
+        let mut cl = Closure { cl: ClosureType { p }, upvals: Vec::new() };
+        let mut state = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        state.push(LuaValue::Nil); // function slot of the running call
+        let l = &mut state as *mut LuaState as *mut crate::lapi::lua_State;
+        unsafe {
+            let co = lua_newthread(l);
+            let co1 = &mut *(co as *mut LuaState);
+            co1.push(closure_value(NonNull::from(&mut cl)));
+            // the iterator yields its control value and returns what it is
+            // resumed with
+            co1.push(LuaValue::Function(Box::new(|L: &mut LuaState| unsafe {
+                let l = L as *mut LuaState as *mut crate::lapi::lua_State;
+                lua_settop(l, 2);
+                lua_yield(l, 1)
+            })));
+            co1.push(LuaValue::Nil);
+            co1.push(LuaValue::Int(0));
+            let mut nres = 0;
+            assert_eq!(lua_resume(co, l, 3, &mut nres), LUA_YIELD);
+            assert_eq!((nres, co1.pop()), (1, Some(LuaValue::Int(0))));
+            co1.push(LuaValue::Int(10));
+            assert_eq!(lua_resume(co, l, 1, &mut nres), LUA_YIELD);
+            assert_eq!((nres, co1.pop()), (1, Some(LuaValue::Int(10))));
+            co1.push(LuaValue::Int(20));
+            assert_eq!(lua_resume(co, l, 1, &mut nres), LUA_YIELD);
+            assert_eq!((nres, co1.pop()), (1, Some(LuaValue::Int(20))));
+            // resumed with nothing, the iterator returns nil and the loop ends
+            assert_eq!(lua_resume(co, l, 0, &mut nres), LUA_OK);
+            assert_eq!((nres, co1.pop()), (1, Some(LuaValue::Int(20))));
+        }
+    }
+
+    #[test]
+    fn test_upvalue_identity_and_join() {
+        let mut p = Proto {
+            code: Vec::new(),
+            k: Vec::new(),
+            lineinfo: Default::default(),
+            numparams: 0,
+            is_vararg: false,
+            source: "=test".to_string(),
+            linedefined: 0,
+            lastlinedefined: 0,
+            locvars: Vec::new(),
+            upvalnames: vec!["count".to_string()],
+            decoded: Vec::new(),
+            slot_cache: Vec::new(),
+        };
+        let p: *mut Proto = &mut p;
+        let shared = UpVal::closed(TValue::from_integer(1));
+        let f = Closure { cl: ClosureType { p }, upvals: vec![shared, UpVal::closed(TValue::nil())] };
+        let mut g = Closure { cl: ClosureType { p }, upvals: vec![UpVal::closed(TValue::from_integer(2))] };
+        unsafe {
+            let (name, v) = f.upvalue(1).unwrap();
+            assert_eq!(name, "count");
+            assert_eq!((*v).value.i, 1);
+            // stripped of debug info
+            assert_eq!(f.upvalue(2).unwrap().0, "(no name)");
+            assert!(f.upvalue(0).is_none() && f.upvalue(3).is_none());
+
+            assert_ne!(f.upvalue_id(1), g.upvalue_id(1));
+            assert!(g.join_upvalue(1, f.upvalue_id(1).unwrap()));
+            assert_eq!(f.upvalue_id(1), g.upvalue_id(1));
+            // a write through one closure is seen by the other
+            *g.upvalue(1).unwrap().1 = TValue::from_integer(10);
+            assert_eq!((*f.upvalue(1).unwrap().1).value.i, 10);
+            assert!(!g.join_upvalue(2, shared) && f.upvalue_id(3).is_none());
+        }
+    }
+    #[test]
+    fn test_concat_and_objlen() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut L = LuaState::new(Rc::new(RefCell::new(crate::lstate::GlobalState::new())));
+        let s = |x: &str| LuaValue::Str(x.to_string());
+        assert_eq!(luaV_concat(&mut L, Vec::new()), s(""));
+        assert_eq!(luaV_concat(&mut L, vec![LuaValue::Int(7)]), LuaValue::Int(7)); // a single value is not converted
+        let vals = vec![s("x="), LuaValue::Int(1), s(", y="), LuaValue::Float(2.0), s(""), LuaValue::Float(0.5)];
+        assert_eq!(luaV_concat(&mut L, vals), s("x=1, y=2.00.5"));
+        assert_eq!(luaG_concaterror(&s("a"), &LuaValue::Nil), "attempt to concatenate a nil value");
+        assert_eq!(luaG_concaterror(&LuaValue::Bool(true), &LuaValue::Int(1)), "attempt to concatenate a boolean value");
+
+        let t = Rc::new(RefCell::new(crate::ltable::Table::new()));
+        for i in 1..=4 {
+            t.borrow_mut().set(&LuaValue::Int(i), LuaValue::Bool(true));
+        }
+        assert_eq!(luaV_objlen(&mut L, &LuaValue::Table(t)), LuaValue::Int(4));
+        assert_eq!(luaV_objlen(&mut L, &s("h\u{e9}llo")), LuaValue::Int(6)); // bytes, not chars
+    }
+}
@@ -5,6 +5,8 @@ use crate::lstate::LuaState;
 use crate::lobject::LuaValue;
 use crate::lauxlib;
 use crate::lualib;
+use crate::ldebugger::Debugger;
+use crate::lrepl::ReplSession;
 use std::env;
 use std::process;
 
@@ -22,10 +24,15 @@ fn print_usage(badoption: &str) {
 Available options are:\n\
   -e stat   execute string 'stat'\n\
   -i        enter interactive mode after executing 'script'\n\
+  -d        run 'script' under the interactive debugger\n\
+  --dap[=port] serve the Debug Adapter Protocol on stdio (or a TCP port)\n\
   -l mod    require library 'mod' into global 'mod'\n\
   -l g=mod  require library 'mod' into global 'g'\n\
   -v        show version information\n\
   -E        ignore environment variables\n\
+  -O        optimize the generated bytecode\n\
+  -p        check the syntax of 'script' (and of the files after it) without running\n\
+  --color=when color REPL output: auto, always or never\n\
   -W        turn warnings on\n\
   --        stop handling options\n\
   -         stop handling options and execute stdin", SKYLA_PROGNAME);
@@ -52,6 +59,93 @@ fn run_string(state: &mut LuaState, code: &str) -> bool {
     state.do_string(code).is_ok()
 }
 
+/// Run a REPL line: an expression has its values shown as skyla.inspect
+/// shows them, anything that does not compile as one runs as a statement.
+/// Top-level locals stay visible to later lines (lrepl).
+fn run_repl_line(state: &mut LuaState, session: &mut ReplSession, line: &str, color: bool) -> crate::lerror::Result<()> {
+    let expr = format!("__skyla_repl = table.pack({})", line.trim_end());
+    match state.do_string(&session.wrap(&expr)) {
+        Err(crate::lerror::Error::Syntax { .. }) => {
+            state.do_string(&session.wrap(line))?;
+            session.commit(line);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+        Ok(_) => {}
+    }
+    let results = state.get_global("__skyla_repl");
+    state.set_global("__skyla_repl", LuaValue::Nil);
+    let Some(LuaValue::Table(t)) = results else { return Ok(()) };
+    let n = match t.borrow().get(&LuaValue::Str("n".to_string())) {
+        Some(LuaValue::Int(n)) => *n,
+        _ => 0,
+    };
+    let opts = crate::linspect::InspectOptions { color, ..Default::default() };
+    let shown: Vec<String> = (1..=n).map(|i| {
+        let v = t.borrow().get(&LuaValue::Int(i)).cloned().unwrap_or(LuaValue::Nil);
+        crate::linspect::inspect_value(state, &v, &opts)
+    }).collect();
+    if !shown.is_empty() {
+        println!("{}", shown.join("\t"));
+    }
+    Ok(())
+}
+
+/// Report an error from REPL line `line`: a syntax error shows the line with
+/// the offending token marked, a runtime error its traceback
+fn report_repl_error(session: &ReplSession, line: &str, e: &crate::lerror::Error, color: bool) {
+    let msg = match e {
+        crate::lerror::Error::Syntax { line: l, msg } => {
+            // line numbers count the lines ReplSession::wrap put before it
+            let l = l.saturating_sub(session.prologue_lines());
+            crate::lcolor::highlight_syntax_error(line, l, msg, color)
+        }
+        e => crate::lcolor::dim_library_frames(&e.to_string(), color),
+    };
+    report_error(&msg);
+}
+
+/// `-p`: report the syntax errors of each file as file:line:col: message;
+/// false if any file has one or cannot be read
+fn check_files(files: &[&str]) -> bool {
+    let mut ok = true;
+    for &f in files {
+        let src = match crate::lplatform::with_platform(|p| p.read_file(f)) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(e) => {
+                report_error(&format!("cannot open {}: {}", f, e));
+                ok = false;
+                continue;
+            }
+        };
+        for d in crate::lsyntax::check_syntax(&src) {
+            eprintln!("{}:{}:{}: {}", f, d.line, d.col, d.message);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// `--dap`: the client's launch request names the script to run
+#[cfg(feature = "dap")]
+fn run_dap(state: &mut LuaState, port: Option<u16>, args: &[String]) {
+    use crate::ldap::{run_dap_server, DapTransport};
+    let transport = port.map(DapTransport::Tcp).unwrap_or(DapTransport::Stdio);
+    let result = run_dap_server(transport, state, |state, program| {
+        run_script(state, Some(program), args)
+    });
+    if let Err(e) = result {
+        report_error(&format!("debug adapter: {}", e));
+        process::exit(1);
+    }
+}
+
+#[cfg(not(feature = "dap"))]
+fn run_dap(_state: &mut LuaState, _port: Option<u16>, _args: &[String]) {
+    report_error("built without the 'dap' feature");
+    process::exit(1);
+}
+
 /// Extension 1: Add a :q and exit() command to the REPL for quitting
 fn register_exit(state: &mut LuaState) {
     state.set_global("exit", LuaValue::Function(Box::new(|_state, _args| {
@@ -81,12 +175,14 @@ fn register_globals(state: &mut LuaState) {
     })));
 }
 
-fn run_repl(state: &mut LuaState) {
+fn run_repl(state: &mut LuaState, color: bool) {
     use std::io::{self, Write};
     let stdin = io::stdin();
     let mut stdout = io::stdout();
     let mut line = String::new();
+    let mut session = ReplSession::new();
     loop {
+        let _ = state.flush_output();
         print!("> ");
         stdout.flush().unwrap();
         line.clear();
@@ -111,8 +207,8 @@ fn run_repl(state: &mut LuaState) {
             }
             continue;
         }
-        if !run_string(state, &line) {
-            report_error("Error in input");
+        if let Err(e) = run_repl_line(state, &mut session, &line, color) {
+            report_repl_error(&session, &line, &e, color);
         }
     }
 }
@@ -131,6 +227,8 @@ fn register_help(state: &mut LuaState) {
     let help_text = "Skyla REPL Help:\n\
   - Type Lua code and press Enter to execute.\n\
   - Use :q or exit() to quit.\n\
+  - Enter an expression to see its value; skyla.inspect(v) formats one.\n\
+  - Top-level locals (local x = 1) stay defined on the following lines.\n\
   - Use print(...) to display output.\n\
   - Use require('mod') to load modules.\n\
   - Use help() to see this message again.";
@@ -140,19 +238,70 @@ fn register_help(state: &mut LuaState) {
     })));
 }
 
+/// Load the plugin libraries listed in SKYLA_PLUGINS (separated by ';'),
+/// before any chunk is compiled so calls of their intrinsics get bound
+#[cfg(not(feature = "minimal"))]
+fn load_startup_plugins(state: &mut LuaState) {
+    if crate::loadlib::no_env(state) {
+        return;
+    }
+    let Ok(list) = env::var(crate::skylaconf::ENV_PLUGINS) else { return };
+    for path in list.split(';').filter(|p| !p.is_empty()) {
+        if let Err(msg) = crate::loadlib::load_plugin_library(state, path) {
+            eprintln!("[skyla] {}", msg);
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
+    crate::ltrace::init_trace_from_env();
+    let coverage_file = env::var(crate::skylaconf::ENV_COVERAGE).ok();
+    crate::lcoverage::set_coverage_enabled(coverage_file.is_some());
     let mut state = LuaState::new();
+    // -E must be known before package.path/cpath are set from the environment
+    let mut j = 1;
+    while j < args.len() {
+        match args[j].as_str() {
+            "-e" | "-l" => j += 1, // skip the option's argument
+            "-E" => crate::loadlib::set_no_env(&mut state),
+            // before any chunk is compiled, as -e may come first
+            "-O" => state.set_compile_options(crate::lopt::CompileOptions::optimized()),
+            "--" | "-" => break,
+            s if !s.starts_with('-') => break,
+            _ => {}
+        }
+        j += 1;
+    }
     lualib::open_libs(&mut state);
     register_exit(&mut state);
     register_help(&mut state);
     register_env(&mut state);
     register_globals(&mut state);
+    crate::linspect::open_inspect_lib(&mut state);
+    crate::lsandbox::open_sandbox_lib(&mut state);
+    crate::ltm::open_metamethod_lib(&mut state);
+    crate::lchannel::open_channel_lib(&mut state);
+    crate::ljson::open_json_lib(&mut state);
+    crate::lpack::open_pack_lib(&mut state);
+    crate::lprofiler::open_profiler_lib(&mut state);
+    #[cfg(feature = "regex")]
+    crate::lregex::open_regex_lib(&mut state);
+    #[cfg(feature = "skyla_ext")]
+    crate::lencoding::open_encoding_lib(&mut state);
+    #[cfg(feature = "hash")]
+    crate::lhash::open_hash_lib(&mut state);
+    #[cfg(not(feature = "minimal"))]
+    load_startup_plugins(&mut state);
     let mut script: Option<&str> = None;
     let mut script_args = Vec::new();
     let mut interactive = false;
+    let mut debug = false;
+    let mut dap: Option<Option<u16>> = None;
     let mut show_version = false;
     let mut ignore_env = false;
+    let mut color_mode = crate::lcolor::ColorMode::Auto;
+    let mut check_only = false;
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
@@ -168,8 +317,20 @@ fn main() {
                 state.require(&args[i]);
             },
             "-i" => interactive = true,
+            "-d" => debug = true,
+            "-p" => check_only = true,
+            "--dap" => dap = Some(None),
+            s if s.starts_with("--dap=") => match s[6..].parse() {
+                Ok(port) => dap = Some(Some(port)),
+                Err(_) => { print_usage(s); process::exit(1); }
+            },
             "-v" => show_version = true,
             "-E" => ignore_env = true,
+            "-O" => {} // applied before the libraries were opened
+            s if s.starts_with("--color=") => match crate::lcolor::ColorMode::parse(&s[8..]) {
+                Some(mode) => color_mode = mode,
+                None => { print_usage(s); process::exit(1); }
+            },
             "--" => { i += 1; break; },
             "-" => { break; },
             s if s.starts_with('-') => { print_usage(s); process::exit(1); },
@@ -179,6 +340,15 @@ fn main() {
     }
     // Remaining args are script args
     script_args.extend_from_slice(&args[i..]);
+    if check_only {
+        // nothing runs with -p, not even SKYLA_INIT
+        let Some(first) = script else {
+            report_error("'-p' needs a script to check");
+            process::exit(1);
+        };
+        let files: Vec<&str> = std::iter::once(first).chain(script_args.iter().map(String::as_str)).collect();
+        process::exit(if check_files(&files) { 0 } else { 1 });
+    }
     if show_version { print_version(); }
     if !ignore_env {
         if let Ok(init) = env::var(SKYLA_INIT_VAR) {
@@ -190,12 +360,21 @@ fn main() {
             }
         }
     }
+    let no_color = env::var("NO_COLOR").is_ok_and(|v| !v.is_empty());
+    let color = color_mode.enabled(std::io::IsTerminal::is_terminal(&std::io::stdout()), no_color);
+    if let Some(port) = dap {
+        run_dap(&mut state, port, &script_args);
+        return;
+    }
     if let Some(fname) = script {
+        if debug {
+            crate::ldebugger::debugger_attach(Debugger::new());
+        }
         if !run_script(&mut state, Some(fname), &script_args) { process::exit(1); }
-        if interactive { run_repl(&mut state); }
+        if interactive { run_repl(&mut state, color); }
     } else if interactive || script.is_none() {
         print_version();
-        run_repl(&mut state);
+        run_repl(&mut state, color);
     }
     // Print a warning if any script args are present but no script is given
     if script.is_none() && !script_args.is_empty() {
@@ -214,8 +393,6 @@ fn main() {
     if show_version && script.is_none() && !interactive {
         println!("Skyla is a modern, extensible Lua fork (Rust + D)");
     }
-    // Optionally: run startup hooks or plugins
-    // skyla::run_startup_plugins(&mut state); // (stub for future extension)
     // Optionally: print loaded modules and environment info for debugging
     if env::var("SKYLA_DEBUG").is_ok() {
         println!("[skyla] Debug: Loaded modules: {:?}", state.list_loaded_modules());
@@ -225,6 +402,12 @@ fn main() {
     if env::var("SKYLA_GOODBYE").is_ok() {
         println!("[skyla] Goodbye from Skyla!");
     }
+    // Write line coverage (merged with any previous runs) if requested
+    if let Some(path) = coverage_file {
+        if let Err(e) = crate::lcoverage::coverage_snapshot().write_lcov(&path, true) {
+            eprintln!("[skyla] cannot write coverage to '{}': {}", path, e);
+        }
+    }
     // Optionally: run post-exit hooks or cleanup
     // skyla::run_exit_hooks(&mut state); // (stub for future extension)
 }
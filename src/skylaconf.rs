@@ -28,6 +28,12 @@ pub type LuaFloat = f64;
 #[cfg(all(not(feature = "float32"), not(feature = "float64")))]
 pub type LuaFloat = f64; // default
 
+// Unsigned counterpart of LuaInteger (lua_Unsigned)
+#[cfg(feature = "int32")]
+pub type LuaUnsigned = u32;
+#[cfg(not(feature = "int32"))]
+pub type LuaUnsigned = u64;
+
 // === Numeric Limits ===
 pub const LUA_INTEGER_MIN: LuaInteger = LuaInteger::MIN;
 pub const LUA_INTEGER_MAX: LuaInteger = LuaInteger::MAX;
@@ -35,6 +41,50 @@ pub const LUA_INTEGER_MAX: LuaInteger = LuaInteger::MAX;
 pub const LUA_FLOAT_MIN: LuaFloat = LuaFloat::MIN;
 pub const LUA_FLOAT_MAX: LuaFloat = LuaFloat::MAX;
 
+// === Number Formatting ===
+// Length modifier and format of lua_Integer in C formats (LUA_INTEGER_FMT)
+#[cfg(feature = "int32")]
+pub const LUA_INTEGER_FRMLEN: &str = "";
+#[cfg(not(feature = "int32"))]
+pub const LUA_INTEGER_FRMLEN: &str = "ll";
+#[cfg(feature = "int32")]
+pub const LUA_INTEGER_FMT: &str = "%d";
+#[cfg(not(feature = "int32"))]
+pub const LUA_INTEGER_FMT: &str = "%lld";
+
+// Significant digits of tostring(float): "%.7g" for float, "%.14g" for double
+#[cfg(feature = "float32")]
+pub const LUAI_NUMFFORMAT_PREC: usize = 7;
+#[cfg(not(feature = "float32"))]
+pub const LUAI_NUMFFORMAT_PREC: usize = 14;
+
+// C type names of the configured numbers (lua.h typedefs)
+#[cfg(feature = "int32")]
+pub const LUA_INTEGER_CTYPE: (&str, &str) = ("int32_t", "uint32_t");
+#[cfg(not(feature = "int32"))]
+pub const LUA_INTEGER_CTYPE: (&str, &str) = ("int64_t", "uint64_t");
+#[cfg(feature = "float32")]
+pub const LUA_NUMBER_CTYPE: &str = "float";
+#[cfg(not(feature = "float32"))]
+pub const LUA_NUMBER_CTYPE: &str = "double";
+
+// === Binary Chunks ===
+// Values stored in the chunk header to detect a mismatched number format
+pub const LUAC_INT: LuaInteger = 0x5678;
+pub const LUAC_NUM: LuaFloat = 370.5;
+
+/// Convert a float with an exact integer value to LuaInteger
+/// (lua_numbertointeger); None if it has a fraction or is out of range
+pub fn float_to_integer(f: LuaFloat) -> Option<LuaInteger> {
+    // -(LUA_INTEGER_MIN as float) is exactly 2^(bits-1), the first value out of range
+    let lim = -(LUA_INTEGER_MIN as LuaFloat);
+    if f.fract() == 0.0 && f >= -lim && f < lim {
+        Some(f as LuaInteger)
+    } else {
+        None
+    }
+}
+
 // === Version and Build Info ===
 pub const SKYLA_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const SKYLA_BUILD_PROFILE: &str = env!("PROFILE");
@@ -85,11 +135,19 @@ pub const IDSIZE: usize = 60;
 pub const LUAL_BUFFERSIZE: usize = 16 * std::mem::size_of::<*const ()>() * std::mem::size_of::<LuaFloat>();
 
 // === Compatibility/Feature Flags ===
+/// The 5.1 globals unpack, loadstring and module (lcompat.rs)
 pub const COMPAT_GLOBAL: bool = true;
+/// Everything 5.3 code relies on, as the three flags below
 pub const COMPAT_5_3: bool = true;
-pub const COMPAT_MATHLIB: bool = true;
-pub const COMPAT_APIINTCASTS: bool = true;
-pub const COMPAT_LT_LE: bool = true;
+/// math.atan2, math.pow and the other functions dropped from math in 5.3
+pub const COMPAT_MATHLIB: bool = COMPAT_5_3;
+/// lua_pushunsigned and the other unsigned casts of the API (lauxlib.rs).
+/// Rust cannot leave functions out on a constant, so they follow the
+/// `compat_apiintcasts` feature and this reports it
+pub const COMPAT_APIINTCASTS: bool = cfg!(feature = "compat_apiintcasts");
+pub const COMPAT_LT_LE: bool = COMPAT_5_3;
+/// The 5.2 bit32 library (lbitlib.rs)
+pub const COMPAT_BITLIB: bool = true;
 
 // === API Visibility (no-op in Rust, for reference) ===
 // pub use visibility as needed
@@ -106,7 +164,7 @@ pub fn print_config() {
     println!("  C path: {}", LUA_CPATH_DEFAULT);
     println!("  Max stack: {}  Buffer size: {}", MAX_STACK, LUAL_BUFFERSIZE);
     println!("  API check: {}  NOCVTN2S: {}  NOCVTS2N: {}", USE_API_CHECK, NOCVTN2S, NOCVTS2N);
-    println!("  Compat: global={}  5.3={}  mathlib={}  apiintcasts={}  lt_le={}", COMPAT_GLOBAL, COMPAT_5_3, COMPAT_MATHLIB, COMPAT_APIINTCASTS, COMPAT_LT_LE);
+    println!("  Compat: global={}  5.3={}  mathlib={}  apiintcasts={}  lt_le={}  bitlib={}", COMPAT_GLOBAL, COMPAT_5_3, COMPAT_MATHLIB, COMPAT_APIINTCASTS, COMPAT_LT_LE, COMPAT_BITLIB);
 }
 
 // === Local configuration space ===
@@ -137,6 +195,7 @@ pub struct SkylaConfig {
     pub compat_mathlib: bool,
     pub compat_apiintcasts: bool,
     pub compat_lt_le: bool,
+    pub compat_bitlib: bool,
     pub fuzzing: bool,
     pub snapshot: bool,
     pub plugin_hooks: bool,
@@ -167,6 +226,7 @@ impl SkylaConfig {
             compat_mathlib: COMPAT_MATHLIB,
             compat_apiintcasts: COMPAT_APIINTCASTS,
             compat_lt_le: COMPAT_LT_LE,
+            compat_bitlib: COMPAT_BITLIB,
             fuzzing: option_env!("SKYLA_FUZZ").is_some(),
             snapshot: option_env!("SKYLA_SNAPSHOT").is_some(),
             plugin_hooks: option_env!("SKYLA_PLUGINS").is_some(),
@@ -192,6 +252,9 @@ pub const ENV_GOODBYE: &str = "SKYLA_GOODBYE";
 pub const ENV_FUZZ: &str = "SKYLA_FUZZ";
 pub const ENV_SNAPSHOT: &str = "SKYLA_SNAPSHOT";
 pub const ENV_PLUGINS: &str = "SKYLA_PLUGINS";
+pub const ENV_TRACE: &str = "SKYLA_TRACE";
+pub const ENV_TRACE_FILE: &str = "SKYLA_TRACE_FILE";
+pub const ENV_COVERAGE: &str = "SKYLA_COVERAGE";
 
 // === Experimental/Advanced Feature Flags ===
 #[cfg(feature = "deterministic_fuzzing")]
@@ -209,6 +272,34 @@ pub const INVARIANT_CHECK: bool = true;
 #[cfg(not(feature = "invariant_check"))]
 pub const INVARIANT_CHECK: bool = false;
 
+#[cfg(feature = "trace")]
+pub const TRACE: bool = true;
+#[cfg(not(feature = "trace"))]
+pub const TRACE: bool = false;
+
+#[cfg(feature = "internal_tests")]
+pub const INTERNAL_TESTS: bool = true;
+#[cfg(not(feature = "internal_tests"))]
+pub const INTERNAL_TESTS: bool = false;
+
+#[cfg(feature = "skyla_ext")]
+pub const SKYLA_EXT: bool = true;
+#[cfg(not(feature = "skyla_ext"))]
+pub const SKYLA_EXT: bool = false;
+
+#[cfg(feature = "dap")]
+pub const DAP: bool = true;
+#[cfg(not(feature = "dap"))]
+pub const DAP: bool = false;
+
+// `minimal`: core VM for wasm32-unknown-unknown / embedded hosts. No io
+// library, no os.execute/remove/rename/tmpname/exit, no C module loader; time,
+// seed and output come from the host's lplatform::Platform.
+#[cfg(feature = "minimal")]
+pub const MINIMAL: bool = true;
+#[cfg(not(feature = "minimal"))]
+pub const MINIMAL: bool = false;
+
 // === Platform/Build Info Utilities ===
 /// Returns a string describing the current platform and build info.
 pub fn platform_info() -> String {
@@ -227,6 +318,11 @@ pub fn print_experimental_features() {
     println!("  Deterministic fuzzing: {}", DETERMINISTIC_FUZZING);
     println!("  Coverage: {}", COVERAGE);
     println!("  Invariant check: {}", INVARIANT_CHECK);
+    println!("  Skyla extensions: {}", SKYLA_EXT);
+    println!("  Internal test library (T): {}", INTERNAL_TESTS);
+    println!("  VM trace (SKYLA_TRACE): {}", TRACE);
+    println!("  Debug adapter (DAP): {}", DAP);
+    println!("  Minimal (no-OS) build: {}", MINIMAL);
     println!("  Fuzzing (env): {}", option_env!("SKYLA_FUZZ").is_some());
     println!("  Snapshot (env): {}", option_env!("SKYLA_SNAPSHOT").is_some());
     println!("  Plugin hooks (env): {}", option_env!("SKYLA_PLUGINS").is_some());
@@ -270,6 +366,11 @@ pub fn is_feature_enabled(name: &str) -> bool {
         "deterministic_fuzzing" => DETERMINISTIC_FUZZING,
         "coverage" => COVERAGE,
         "invariant_check" => INVARIANT_CHECK,
+        "skyla_ext" => SKYLA_EXT,
+        "internal_tests" => INTERNAL_TESTS,
+        "trace" => TRACE,
+        "dap" => DAP,
+        "minimal" => MINIMAL,
         "fuzzing_env" => option_env!("SKYLA_FUZZ").is_some(),
         "snapshot_env" => option_env!("SKYLA_SNAPSHOT").is_some(),
         "plugin_hooks_env" => option_env!("SKYLA_PLUGINS").is_some(),
@@ -1,6 +1,7 @@
 // skylalib.rs - Skyla/Lua standard library registration (Rust translation of lualib.h)
 // This module defines library names, keys, and open functions for all standard libraries.
 
+use crate::lobject::LuaValue;
 use crate::lstate::LuaState;
 
 // Version suffix for environment variable names
@@ -16,30 +17,89 @@ pub const LUA_OSLIBNAME: &str = "os";
 pub const LUA_STRLIBNAME: &str = "string";
 pub const LUA_TABLIBNAME: &str = "table";
 pub const LUA_UTF8LIBNAME: &str = "utf8";
+pub const LUA_BITLIBNAME: &str = "bit32";
 
-// Library open functions (to be implemented in their respective modules)
-pub fn open_base(state: &mut LuaState) { /* ... */ }
-pub fn open_package(state: &mut LuaState) { /* ... */ }
-pub fn open_coroutine(state: &mut LuaState) { /* ... */ }
-pub fn open_debug(state: &mut LuaState) { /* ... */ }
-pub fn open_io(state: &mut LuaState) { /* ... */ }
-pub fn open_math(state: &mut LuaState) { /* ... */ }
-pub fn open_os(state: &mut LuaState) { /* ... */ }
-pub fn open_string(state: &mut LuaState) { /* ... */ }
-pub fn open_table(state: &mut LuaState) { /* ... */ }
-pub fn open_utf8(state: &mut LuaState) { /* ... */ }
-
-/// Open all standard libraries (call this from your VM entry point)
+// Library open functions. Each registers its functions into the library
+// table in package.loaded (see LuaState::register_lib_function).
+pub fn open_base(state: &mut LuaState) { crate::lbaselib::open_base_lib(state) }
+pub fn open_package(state: &mut LuaState) {
+    use crate::loadlib::{env_path, no_env};
+    use crate::skylaconf::{LUA_CPATH_DEFAULT, LUA_PATH_DEFAULT};
+    let noenv = no_env(state);
+    state.register_lib_value(LUA_LOADLIBNAME, "path", LuaValue::Str(env_path("LUA_PATH", LUA_PATH_DEFAULT, noenv)));
+    state.register_lib_value(LUA_LOADLIBNAME, "cpath", LuaValue::Str(env_path("LUA_CPATH", LUA_CPATH_DEFAULT, noenv)));
+}
+pub fn open_coroutine(state: &mut LuaState) { crate::lcorolib::open_coroutine_lib(state) }
+/// Not in LOADED_LIBS: debug is opened through luaL_requiref, and only if
+/// the sandbox policy allows it
+pub fn open_debug(state: &mut LuaState) {
+    crate::ldblib::require_debug(state);
+}
+pub fn open_io(state: &mut LuaState) {
+    // io needs a file system; `minimal` builds leave it out
+    #[cfg(not(feature = "minimal"))]
+    crate::liolib::open_io_lib(state)
+}
+pub fn open_math(state: &mut LuaState) {
+    crate::lmathlib::open_math_random(state);
+    crate::lmathlib::open_math_int(state);
+    crate::lmathlib::open_math_float(state);
+    if crate::skylaconf::COMPAT_MATHLIB {
+        crate::lmathlib::open_math_compat(state);
+    }
+}
+pub fn open_os(state: &mut LuaState) { crate::loslib::luaopen_os(state) }
+pub fn open_string(state: &mut LuaState) { crate::lstrlib::open_string_lib(state) }
+pub fn open_table(state: &mut LuaState) { crate::ltablib::open_table_lib(state) }
+pub fn open_utf8(state: &mut LuaState) { crate::lutf8lib::open_utf8_lib(state) }
+
+/// Standard libraries in load order
+const LOADED_LIBS: &[(&str, fn(&mut LuaState))] = &[
+    ("_G", open_base),
+    (LUA_LOADLIBNAME, open_package),
+    (LUA_COLIBNAME, open_coroutine),
+    (LUA_IOLIBNAME, open_io),
+    (LUA_MATHLIBNAME, open_math),
+    (LUA_OSLIBNAME, open_os),
+    (LUA_STRLIBNAME, open_string),
+    (LUA_TABLIBNAME, open_table),
+    (LUA_UTF8LIBNAME, open_utf8),
+];
+
+/// Open all standard libraries (call this from your VM entry point): each is
+/// stored in package.loaded and as a global, like luaL_openlibs
 pub fn open_libs(state: &mut LuaState) {
-    open_base(state);
-    open_package(state);
-    open_coroutine(state);
+    for &(name, openf) in LOADED_LIBS {
+        state.require_lib(name, openf, true);
+    }
     open_debug(state);
-    open_io(state);
-    open_math(state);
-    open_os(state);
-    open_string(state);
-    open_table(state);
-    open_utf8(state);
+    if crate::skylaconf::COMPAT_GLOBAL {
+        crate::lcompat::open_compat_globals(state);
+    }
+    if crate::skylaconf::COMPAT_BITLIB {
+        state.require_lib(LUA_BITLIBNAME, crate::lbitlib::open_bit32, true);
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::lstate::GlobalState;
+
+    #[test]
+    fn test_open_libs_installs_libraries() {
+        let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+        open_libs(&mut state);
+        let globals = state.globals_table();
+        // _G is the globals table itself, in package.loaded and as a global
+        let loaded = state.loaded_table();
+        assert!(matches!(loaded.borrow().get(&LuaValue::Str("_G".to_string())), Some(LuaValue::Table(t)) if Rc::ptr_eq(t, &globals)));
+        assert!(matches!(state.get_global("_G"), Some(LuaValue::Table(t)) if Rc::ptr_eq(&t, &globals)));
+        for (lib, func) in [(LUA_COLIBNAME, "wrap"), (LUA_UTF8LIBNAME, "codes"), (LUA_TABLIBNAME, "unpack"), ("_G", "print")] {
+            let Some(LuaValue::Table(t)) = state.get_global(lib) else { panic!("{} is not a global table", lib) };
+            assert!(matches!(t.borrow().get(&LuaValue::Str(func.to_string())), Some(LuaValue::Function(_))), "{}.{}", lib, func);
+        }
+    }
+}
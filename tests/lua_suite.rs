@@ -0,0 +1,127 @@
+//! lua_suite.rs - Runs the official Lua test scripts (vendored in testes/) against the VM
+//
+// Only the ltests-free subset is run here: scripts that need the internal `T`
+// library, C modules (testes/libs) or the stand-alone interpreter are left out.
+// Scripts that exercise features the VM does not implement yet are listed in
+// SKIP with a reason; set SKYLA_SUITE_NOSKIP=1 to run them anyway and see how
+// far they get. Each script runs with `_soft` and `_port` set, as the
+// reference suite does for user test runs.
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use skyla::lobject::LuaValue;
+use skyla::lstate::{GlobalState, LuaState};
+
+/// Directory holding the vendored test scripts
+const SUITE_DIR: &str = "testes";
+
+/// Scripts of the reference suite that do not depend on ltests
+const SUITE: &[&str] = &[
+    "strings.lua",
+    "math.lua",
+    "nextvar.lua",
+    "sort.lua",
+    "closure.lua",
+    "vararg.lua",
+    "coroutine.lua",
+];
+
+/// Scripts skipped until the tree provides what they need (script, what
+/// stops it). Remove an entry as soon as its script passes.
+const SKIP: &[(&str, &str)] = &[
+    ("strings.lua", "the main thread is not a value, so coroutine.running() returns nil there and '%p' formats it as \"(null)\" (line 176)"),
+    ("math.lua", "string.pack, string.unpack and string.packsize are not in the string library"),
+    ("nextvar.lua", "debug.setmetatable is a stub and set_value_metatable does nothing, so non-table values get no metatable"),
+    ("sort.lua", "string.packsize is not in the string library (line 38)"),
+    ("closure.lua", "string.gmatch's iterator is a Rust closure without upvalues, so debug.upvalueid(iterator, 1) fails (line 261)"),
+    ("coroutine.lua", "debug.getlocal, debug.setlocal, debug.sethook and debug.traceback are stubs"),
+];
+
+/// Outcome of running one suite script
+#[derive(Debug, PartialEq, Eq)]
+enum SuiteResult {
+    Passed,
+    Skipped(&'static str),
+    Failed(String),
+}
+
+fn suite_path(script: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join(SUITE_DIR).join(script)
+}
+
+fn skip_reason(script: &str) -> Option<&'static str> {
+    if std::env::var_os("SKYLA_SUITE_NOSKIP").is_some() {
+        return None;
+    }
+    SKIP.iter().find(|(name, _)| *name == script).map(|(_, why)| *why)
+}
+
+fn new_suite_state() -> LuaState {
+    let mut state = LuaState::new(Rc::new(RefCell::new(GlobalState::new())));
+    skyla::skylalib::open_libs(&mut state);
+    state.set_global("_soft", LuaValue::Bool(true));
+    state.set_global("_port", LuaValue::Bool(true));
+    state.set_global("_nomsg", LuaValue::Bool(true));
+    state
+}
+
+fn run_suite_script(script: &str) -> SuiteResult {
+    if let Some(why) = skip_reason(script) {
+        return SuiteResult::Skipped(why);
+    }
+    let path = suite_path(script);
+    let mut state = new_suite_state();
+    match state.do_file(path.to_str().unwrap()) {
+        Ok(_) => SuiteResult::Passed,
+        Err(e) => SuiteResult::Failed(format!("{:?}", e)),
+    }
+}
+
+fn check(script: &str) {
+    match run_suite_script(script) {
+        SuiteResult::Passed => {}
+        SuiteResult::Skipped(why) => eprintln!("[lua_suite] {} skipped: {}", script, why),
+        SuiteResult::Failed(e) => panic!("[lua_suite] {} failed: {}", script, e),
+    }
+}
+
+#[test]
+fn suite_scripts_are_vendored() {
+    for script in SUITE {
+        assert!(suite_path(script).is_file(), "missing {}/{}", SUITE_DIR, script);
+    }
+    for (script, _) in SKIP {
+        assert!(SUITE.contains(script), "skip-list entry {} is not in SUITE", script);
+    }
+}
+
+#[test]
+fn suite_strings() { check("strings.lua"); }
+
+#[test]
+fn suite_math() { check("math.lua"); }
+
+#[test]
+fn suite_nextvar() { check("nextvar.lua"); }
+
+#[test]
+fn suite_sort() { check("sort.lua"); }
+
+#[test]
+fn suite_closure() { check("closure.lua"); }
+
+#[test]
+fn suite_vararg() { check("vararg.lua"); }
+
+#[test]
+fn suite_coroutine() { check("coroutine.lua"); }
+
+/// Prints how many suite scripts currently run (progress indicator)
+#[test]
+fn suite_progress() {
+    let skipped = SUITE.iter().filter(|s| skip_reason(s).is_some()).count();
+    eprintln!("[lua_suite] {}/{} scripts enabled ({} skipped)",
+        SUITE.len() - skipped, SUITE.len(), skipped);
+}